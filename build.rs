@@ -0,0 +1,55 @@
+use std::{
+    fs, path::Path, process::Command, time::{SystemTime, UNIX_EPOCH},
+};
+
+// Embeds the build metadata reported by GET /version, see swim::build_info
+
+fn git_commit(manifest_dir: &Path) -> Option<String> {
+    let output = Command::new("git")
+        .args(["rev-parse", "--short=12", "HEAD"])
+        .current_dir(manifest_dir)
+        .output()
+        .ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    let commit = String::from_utf8(output.stdout).ok()?.trim().to_owned();
+    (!commit.is_empty()).then_some(commit)
+}
+
+// the version of a package as resolved in Cargo.lock
+fn locked_version(manifest_dir: &Path, package: &str) -> Option<String> {
+    let lock = fs::read_to_string(manifest_dir.join("Cargo.lock")).ok()?;
+    let name = format!("name = \"{}\"", package);
+    let mut lines = lock.lines();
+    lines.by_ref().find(|line| line.trim() == name)?;
+    let version = lines.next()?.trim().strip_prefix("version = \"")?.strip_suffix('"')?;
+    Some(version.to_owned())
+}
+
+// unix seconds, SOURCE_DATE_EPOCH takes precedence for reproducible builds
+fn build_timestamp() -> u64 {
+    std::env::var("SOURCE_DATE_EPOCH").ok()
+        .and_then(|epoch| epoch.parse().ok())
+        .unwrap_or_else(|| SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs()))
+}
+
+fn main() {
+    let manifest_dir = std::env::var("CARGO_MANIFEST_DIR").expect("cargo sets CARGO_MANIFEST_DIR");
+    let manifest_dir = Path::new(&manifest_dir);
+
+    println!("cargo:rustc-env=HOLYDIVER_GIT_COMMIT={}", git_commit(manifest_dir).unwrap_or_else(|| "unknown".to_owned()));
+    println!("cargo:rustc-env=HOLYDIVER_BUILD_TIMESTAMP={}", build_timestamp());
+    println!("cargo:rustc-env=HOLYDIVER_AUTOMERGE_VERSION={}",
+        locked_version(manifest_dir, "automerge").unwrap_or_else(|| "unknown".to_owned()));
+
+    println!("cargo:rerun-if-changed=build.rs");
+    println!("cargo:rerun-if-changed=Cargo.lock");
+    println!("cargo:rerun-if-env-changed=SOURCE_DATE_EPOCH");
+    // a new commit moves HEAD or the branch it points to
+    for git_path in [".git/HEAD", ".git/refs", ".git/packed-refs"] {
+        if manifest_dir.join(git_path).exists() {
+            println!("cargo:rerun-if-changed={}", git_path);
+        }
+    }
+}
@@ -11,19 +11,18 @@ use std::io::Write;
 fn main() {
     let mut data = Automerge::new();
     let heads = data.get_heads();
-    let (memo1, memo2) = data.transact::<_,_,AutomergeError>(|tx| {
+    data.transact::<_,_,AutomergeError>(|tx| {
         let memos = tx.put_object(ROOT, "memos", ObjType::Map).unwrap();
-        let memo1 = tx.put(&memos, "Memo1", "Do the thing").unwrap();
-        let memo2 = tx.put(&memos, "Memo2", "Add automerge support").unwrap();
-        Ok((memo1, memo2))
+        tx.put(&memos, "Memo1", "Do the thing").unwrap();
+        tx.put(&memos, "Memo2", "Add automerge support").unwrap();
+        Ok(())
     })
-    .unwrap()
-    .result;
+    .unwrap();
 
 
     // let a = data.save();
     // println!("Memo1: {:?}, Memo2: {:?}", memo1, memo2);
-    let changes = data.get_changes(&heads).unwrap();
+    let _changes = data.get_changes(&heads).unwrap();
     // println!("Changes since start:");
     // changes.iter().for_each(|c| println!("{:?}", c));
 
@@ -1,35 +1,72 @@
 use std::{
-    net::SocketAddr, str::FromStr,
-    sync::{Arc, Mutex}, num::NonZeroU8, path::PathBuf,
+    net::SocketAddr, sync::{Arc, Mutex}, path::{Path, PathBuf}, str::FromStr, time::Duration,
 };
-use clap::{arg, Command, value_parser, builder::{NonEmptyStringValueParser, BoolValueParser, OsStr}};
-use foca::Config;
-use holydiver::swim::core::HolyDiverController;
-use log::info;
+use clap::{arg, Arg, ArgAction, ArgMatches, Command, value_parser, builder::{NonEmptyStringValueParser, BoolValueParser, OsStr}, parser::ValueSource};
+use holydiver::swim::core::{HolyDiverController, gossip_budget};
+use holydiver::swim::handle::ControllerHandle;
+use holydiver::swim::coalesce::BroadcastCoalescer;
+use log::{info, warn};
 use dotenv::dotenv;
 
-use holydiver::swim::types::ID;
+use holydiver::swim::types::{BumpStrategy, ID};
 
 use holydiver::swim::broadcast::{MessageType::FullSync, GossipMessage, Tag::SyncOperation};
 
 use automerge::{transaction::Transactable, ObjType, ROOT, AutoCommit};
 use uuid::Uuid;
 
-use holydiver::swim::{foca::FocaCommand::SendBroadcast, foca::setup_foca, core::FocaRuntimeConfig, server::host_server};
-use anyhow::Result;
+use holydiver::swim::{foca::FocaCommand::SendBroadcast, foca::setup_foca, core::FocaRuntimeConfig, server::{bind_server_with_config, ServerConfig, REST_PORT_FILE}};
+use anyhow::{Context, Result};
 
 use holydiver::swim::core::HolyDiverDataHandler;
+use holydiver::swim::seed::{apply_seed, read_seed_file, SeedOutcome};
+use holydiver::swim::validator::Validator;
+use holydiver::swim::containers::DocSchema;
+use holydiver::swim::inbound_limits::InboundLimits;
+use holydiver::swim::conflict_policy::{spawn_policy_broadcasts, ConflictPolicies};
+use holydiver::swim::write_quorum::{MemberCount, WriteQuorum, DEFAULT_MIN_MEMBERS_FOR_WRITES};
+use holydiver::swim::runtime_settings::RuntimeSettings;
+use holydiver::swim::migrations::migrate_data_dir;
+use holydiver::swim::manifest::claim_data_dir;
+use holydiver::swim::encryption::DataKey;
+use holydiver::swim::store::FileStore;
+use holydiver::swim::backup::{backup_data_dir, backup_dir, spawn_scheduled_backups};
+use holydiver::swim::tombstones::spawn_tombstone_gc;
+use holydiver::swim::persistence::spawn_persistence_retry;
+use holydiver::swim::history::spawn_history_monitor;
+use holydiver::swim::diff::diff_saved;
+use holydiver::swim::auth::AuthTokens;
+use holydiver::swim::duration::parse_duration;
+use holydiver::swim::hops::{parse_operation_prefix, HopTracing};
+use holydiver::swim::size::parse_size;
+use holydiver::swim::audit::{AuditLog, FsyncPolicy};
+use holydiver::swim::field_names::FieldSanitation;
+use holydiver::swim::settings::{CheckReport, NodeSettings, identity_warnings, validate};
+use holydiver::swim::capture::replay;
+use holydiver::swim::chaos::FaultInjector;
+use holydiver::swim::codec::SwimCodec;
+use holydiver::swim::envelope::{ENVELOPE_VERSION, MIN_ENVELOPE_VERSION};
+use holydiver::swim::profile::Profile;
+use holydiver::swim::config_file::{ConfigFile, CONFIG_FILE, SYSTEMD_UNIT_FILE, systemd_unit};
+use holydiver::client::{ClientConfig, Event, EventType, HolyDiverClient};
+use futures_util::{future::{join, join_all, try_join_all}, StreamExt};
+use holydiver::swim::shutdown::{self, ShutdownCoordinator, DEFAULT_SHUTDOWN_DEADLINE};
+use holydiver::swim::logging;
+use holydiver::swim::text::{TextServer, TextServerConfig};
+use holydiver::swim::events::{self, MemberEventKind};
 
-fn cli() -> Command {
-    Command::new("holy-diver")
-        .about("You expected SWIM but it was me DIO!")
-        .arg_required_else_help(false)
-        .args(&[
+// the flags of a node, shared by the node itself and serve
+fn node_args() -> Vec<Arg> {
+    vec![
+        arg!(--profile <PROFILE> "Defaults for the network between the members: local, lan or wan. Flags given explicitly override them, see --print-config")
+        .value_parser(Profile::from_str)
+        .id("profile"),
         arg!(--"bind-address" <BIND_ADDRESS> "Socket address to bind to. Example: 127.0.0.1:8080")
         .value_parser(NonEmptyStringValueParser::new())
         .id("bind-address"),
-        arg!(identity: -i --identity <IDENTITY> "The address cluster members will use to talk to you. Defaults to bind-address")
-        .value_parser(NonEmptyStringValueParser::new()),
+        arg!(identity: -i --identity <IDENTITY> "The address cluster members will use to talk to you, it has to be reachable by them. Defaults to bind-address")
+        .value_parser(NonEmptyStringValueParser::new())
+        .visible_alias("advertise-address"),
         arg!(-a --"announce-to" <ANNOUNCE_TO> "Address to another holy-diver instance to join with")
         .value_parser(NonEmptyStringValueParser::new())
         .id("announce-to"),
@@ -40,12 +77,375 @@ fn cli() -> Command {
         arg!(-b --broadcast <BROADCAST> "Flag that indicates whether a broadcast should be sent on startup or not")
         .value_parser(BoolValueParser::new())
         .id("broadcast"),
-        arg!(-p --port <REST_PORT> "Port for the REST endpoint")
-        .value_parser(value_parser!(u16).range(1..))
+        arg!(-p --port <REST_PORT> "Port for the REST endpoint, 0 picks any free port and writes it to <DATA_DIR>/rest.port")
+        .value_parser(value_parser!(u16))
         .default_value(OsStr::from("9090"))
-        .id("rest-port")
-        ])
-        
+        .visible_alias("rest-port")
+        .id("rest-port"),
+        arg!(--"text-port" <TEXT_PORT> "Port of a line based protocol (GET, SET, DEL, KEYS, MEMBERS) for clients that can't use HTTP")
+        .value_parser(value_parser!(u16).range(1..))
+        .id("text-port"),
+        arg!(--"text-idle-timeout" <TIMEOUT> "Text protocol connections without a command for this long are closed")
+        .value_parser(parse_duration)
+        .default_value(OsStr::from("60s"))
+        .id("text-idle-timeout"),
+        arg!(--"max-batch-keys" <MAX_BATCH_KEYS> "Maximum number of keys that can be requested with a single batch read")
+        .value_parser(value_parser!(usize))
+        .default_value(OsStr::from("100"))
+        .id("max-batch-keys"),
+        arg!(--"seen-ops-horizon" <SECONDS> "How long ids of already handled broadcasts are remembered, also across restarts")
+        .value_parser(value_parser!(u64))
+        .default_value(OsStr::from("600"))
+        .id("seen-ops-horizon"),
+        arg!(--"seed-file" <SEED_FILE> "JSON file with the initial values, only applied if the data dir contains no state yet")
+        .value_parser(value_parser!(PathBuf))
+        .id("seed-file"),
+        arg!(--"seed-force" "Apply the seed file even over existing state, merging it as individual writes")
+        .id("seed-force"),
+        arg!(--"no-replicate" <PATTERN> "Keep the fields matching this glob pattern, e.g. 'tmp.*', on this node instead of gossiping them. Can be given several times")
+        .value_parser(NonEmptyStringValueParser::new())
+        .action(ArgAction::Append)
+        .id("no-replicate"),
+        arg!(--"conflict-policy" <PATTERN_POLICY> "Resolve concurrent writes of the fields matching a glob pattern with a policy instead of the pick of automerge, e.g. 'version.*=numeric-max'. Policies: automerge-default, lexicographic-max, numeric-max. All members should use the same policies. Can be given several times, the first matching pattern applies")
+        .value_parser(NonEmptyStringValueParser::new())
+        .action(ArgAction::Append)
+        .id("conflict-policy"),
+        arg!(--"rest-auth-token" <TOKEN_SCOPES> "Require this bearer token on REST requests with the given scopes, e.g. 'secret=rw:app1.*,r:shared.*,admin'. A scope can limit its keys with ';max_keys=1000;max_bytes=256KB'. Can be given several times, without it the API is open")
+        .value_parser(NonEmptyStringValueParser::new())
+        .action(ArgAction::Append)
+        .id("rest-auth-token"),
+        arg!(--"schema-file" <SCHEMA_FILE> "JSON file mapping key patterns to constraints enforced on local writes")
+        .value_parser(value_parser!(PathBuf))
+        .id("schema-file"),
+        arg!(--container <NAME_TYPE> "Declare a top-level container of the document besides values, e.g. 'audit:list', served as /container/NAME/state. TYPE is map (default) or list. Can be given several times")
+        .value_parser(NonEmptyStringValueParser::new())
+        .action(ArgAction::Append)
+        .id("container"),
+        arg!(--"gossip-budget-percent" <PERCENT> "Share of the max packet size a state broadcast may use before only changes are gossiped")
+        .value_parser(value_parser!(u64).range(1..=100))
+        .default_value(OsStr::from("60"))
+        .id("gossip-budget-percent"),
+        arg!(--"backup-interval" <INTERVAL> "Write a backup of the state to <DATA_DIR>/backups at this interval, e.g. 1h")
+        .value_parser(parse_duration)
+        .id("backup-interval"),
+        arg!(--"backup-keep" <COUNT> "Number of scheduled backups to keep, older ones are removed")
+        .value_parser(value_parser!(u64).range(1..))
+        .default_value(OsStr::from("24"))
+        .id("backup-keep"),
+        arg!(--"journal-max-entries" <COUNT> "Number of recent local changes kept to catch up members that were down")
+        .value_parser(value_parser!(u64).range(1..))
+        .default_value(OsStr::from("1000"))
+        .id("journal-max-entries"),
+        arg!(--"journal-max-age" <AGE> "Age after which local changes are dropped from the journal, members down for longer get the whole document")
+        .value_parser(parse_duration)
+        .default_value(OsStr::from("1h"))
+        .id("journal-max-age"),
+        arg!(--"override-window" <WINDOW> "Local writes replaced by a merge within this time after writing them are reported as overridden")
+        .value_parser(parse_duration)
+        .default_value(OsStr::from("10s"))
+        .id("override-window"),
+        arg!(--"log-overrides" <LOG_OVERRIDES> "Whether overridden local writes are logged at warn level")
+        .value_parser(BoolValueParser::new())
+        .default_value(OsStr::from("true"))
+        .id("log-overrides"),
+        arg!(--"sanitize-field-names" <MODE> "Check the names of merged fields against the rules of local writes: hide leaves fields with too long names or control characters out of listings and /events unless include_invalid=true, quarantine also reports them at /debug/invalid-fields. The fields stay in the document")
+        .value_parser(FieldSanitation::from_str)
+        .default_value(OsStr::from("off"))
+        .id("sanitize-field-names"),
+        arg!(--"wal-max-size" <BYTES> "Size of the write-ahead log after which the whole document is saved and the log truncated")
+        .value_parser(value_parser!(u64).range(1..))
+        .default_value(OsStr::from("16777216"))
+        .id("wal-max-size"),
+        arg!(--"strict-durability" "Reject writes with 507 while the state can't be written to disk instead of accepting them unpersisted")
+        .id("strict-durability"),
+        arg!(--"max-doc-size" <SIZE> "Reject local writes with 507 that would grow the document beyond this size, e.g. 4MB. Merges of remote changes are still applied")
+        .value_parser(parse_size)
+        .id("max-doc-size"),
+        arg!(--"min-members-for-writes" <MEMBERS> "Reject writes with 503 while fewer members than this are up, including this node, e.g. 2 so that an isolated node doesn't accept writes. Reads keep working and /healthz fails meanwhile")
+        .value_parser(value_parser!(u64).range(1..))
+        .default_value(OsStr::from("1"))
+        .id("min-members-for-writes"),
+        arg!(--"data-encryption-key-file" <FILE> "Encrypt the state in the data dir with the 32 byte AES key in this file, raw or hex or base64 encoded. Unencrypted state is encrypted with the next write")
+        .value_parser(value_parser!(PathBuf))
+        .id("data-encryption-key-file"),
+        arg!(--"unhealthy-after-failures" <FAILURES> "Report the node as unhealthy on /healthz after this many failed writes of the state in a row")
+        .value_parser(value_parser!(u32).range(1..))
+        .default_value(OsStr::from("3"))
+        .id("unhealthy-after-failures"),
+        arg!(--"event-queue-size" <EVENTS> "Number of events a subscriber of /events can fall behind before it loses the oldest ones. Defaults to the --profile")
+        .value_parser(value_parser!(u64).range(1..))
+        .id("event-queue-size"),
+        arg!(--"sse-heartbeat" <INTERVAL> "Interval of keep-alive comments sent on idle /events connections")
+        .value_parser(parse_duration)
+        .default_value(OsStr::from("15s"))
+        .id("sse-heartbeat"),
+        arg!(--"write-concern-timeout" <TIMEOUT> "How long writes with write_concern=replicated wait for another member to acknowledge them. Defaults to the --profile")
+        .value_parser(parse_duration)
+        .id("write-concern-timeout"),
+        arg!(--"rest-read-timeout" <TIMEOUT> "Answer GET requests taking longer with 503 and the phase they were in, /events and /export are not limited")
+        .value_parser(parse_duration)
+        .default_value(OsStr::from("2s"))
+        .id("rest-read-timeout"),
+        arg!(--"rest-write-timeout" <TIMEOUT> "Answer writes taking longer with 503 and the phase they were in, writes with write_concern=replicated get the --write-concern-timeout on top. /import is not limited")
+        .value_parser(parse_duration)
+        .default_value(OsStr::from("5s"))
+        .id("rest-write-timeout"),
+        arg!(--"slow-request-threshold" <DURATION> "Log REST requests taking longer with the time they spent waiting for the lock, applying, persisting and enqueuing the broadcast")
+        .value_parser(parse_duration)
+        .default_value(OsStr::from("1s"))
+        .id("slow-request-threshold"),
+        arg!(--"enable-swagger" "Serve a Swagger UI for /openapi.json at /swagger-ui")
+        .id("enable-swagger"),
+        arg!(--"rest-compression" <MODE> "Compress JSON and exported documents of at least 1 KiB sent over REST to clients accepting gzip, deflate, brotli or zstd")
+        .value_parser(["on", "off"])
+        .default_value(OsStr::from("on"))
+        .id("rest-compression"),
+        arg!(--chaos "Serve /admin/chaos to drop, delay and partition the gossip traffic of this node. For testing only")
+        .id("chaos"),
+        arg!(--"chaos-seed" <SEED> "Seed of the faults injected with --chaos when /admin/chaos sets none, so that runs can be repeated")
+        .value_parser(value_parser!(u64))
+        .requires("chaos")
+        .id("chaos-seed"),
+        arg!(--"debug-endpoints" "Serve DELETE /debug/seen-ops/{id} and POST /debug/seen-ops/clear to make this node apply operations again. For debugging only")
+        .id("debug-endpoints"),
+        arg!(--"broadcast-backlog-warning" <BROADCASTS> "Log a warning when more broadcasts than this are pending for over a minute. Defaults to the --profile")
+        .value_parser(value_parser!(u64))
+        .id("broadcast-backlog-warning"),
+        arg!(--"broadcast-interval" <INTERVAL> "Minimum time between two state broadcasts, writes in between are sent together. Defaults to the --profile")
+        .value_parser(parse_duration)
+        .id("broadcast-interval"),
+        arg!(--"tombstone-horizon" <AGE> "Age after which tombstones of deleted fields are purged, has to exceed the longest partition of a node")
+        .value_parser(parse_duration)
+        .default_value(OsStr::from("24h"))
+        .id("tombstone-horizon"),
+        arg!(--"history-check-interval" <INTERVAL> "Interval at which the history of the document is compared to its values to tell whether compacting it would pay off")
+        .value_parser(parse_duration)
+        .default_value(OsStr::from("10m"))
+        .id("history-check-interval"),
+        arg!(--"startup-reply-window" <WINDOW> "Answer the startup messages of a node with the full state at most once within this window")
+        .value_parser(parse_duration)
+        .default_value(OsStr::from("60s"))
+        .id("startup-reply-window"),
+        arg!(--"merge-queue-size" <BROADCASTS> "Number of received broadcasts that can wait to be merged, beyond it FullSyncs of the same member replace each other and others are dropped and caught up later")
+        .value_parser(value_parser!(u64).range(1..))
+        .default_value(OsStr::from("64"))
+        .id("merge-queue-size"),
+        arg!(--"peer-packet-rate" <PER_SEC> "Drop the datagrams of a peer sending more than this many per second, with bursts of twice as many. Should be well above --peer-broadcast-rate, so that failure detection keeps working. Unlimited by default")
+        .value_parser(value_parser!(u32).range(1..))
+        .id("peer-packet-rate"),
+        arg!(--"peer-broadcast-rate" <PER_SEC> "Drop the broadcasts and direct messages of a peer sending more than this many per second, with bursts of twice as many. Unlimited by default")
+        .value_parser(value_parser!(u32).range(1..))
+        .id("peer-broadcast-rate"),
+        arg!(--"swim-codec" <CODEC> "Serialization of the SWIM messages, postcard or bincode. All members have to use the same codec")
+        .value_parser(SwimCodec::from_str)
+        .default_value(OsStr::from("postcard"))
+        .id("swim-codec"),
+        arg!(--"wire-version" <VERSION> "Protocol version of the datagrams this node sends. Set it to the version of the other members while upgrading them one at a time, and remove it once all of them run the new version")
+        .value_parser(value_parser!(u8).range(MIN_ENVELOPE_VERSION as i64..=ENVELOPE_VERSION as i64))
+        .default_value(OsStr::from(ENVELOPE_VERSION.to_string()))
+        .id("wire-version"),
+        arg!(--"identity-bump" <STRATEGY> "How the identity picks its bump when it is renewed after being declared down: sequential, random-nonrepeating or timestamp. The bumps used are remembered in the data dir and not picked again")
+        .value_parser(BumpStrategy::from_str)
+        .default_value(OsStr::from("sequential"))
+        .id("identity-bump"),
+        arg!(--"trace-hops" "Debugging: send the broadcasts of this node with a trail of the members relaying them and extend the trails of received ones. Adds a few dozen bytes per broadcast, all members have to run a version supporting it")
+        .id("trace-hops"),
+        arg!(--"trace-operation" <PREFIX> "Debugging: log the hop trail of received operations whose id starts with this, requires --trace-hops")
+        .value_parser(parse_operation_prefix)
+        .requires("trace-hops")
+        .id("trace-operation"),
+        arg!(--"join-timeout" <TIMEOUT> "Exit with code 3 if no other member is up this long after announcing, disabled by default")
+        .value_parser(parse_duration)
+        .id("join-timeout"),
+        arg!(--"checksum-interval" <INTERVAL> "Gossip a checksum of the values this often so that members can detect diverged state, see /cluster/consistency. Defaults to the --profile, disabled without one")
+        .value_parser(parse_duration)
+        .id("checksum-interval"),
+        arg!(--"swim-probe-period" <INTERVAL> "How often foca probes a member to detect failures. Defaults to the --profile")
+        .value_parser(parse_duration)
+        .id("swim-probe-period"),
+        arg!(--"swim-probe-rtt" <TIMEOUT> "How long foca waits for the answer to a probe before asking other members to probe indirectly. Defaults to the --profile")
+        .value_parser(parse_duration)
+        .id("swim-probe-rtt"),
+        arg!(--"swim-suspect-timeout" <TIMEOUT> "How long a member that failed a probe is suspected before it is declared down. Defaults to the --profile")
+        .value_parser(parse_duration)
+        .id("swim-suspect-timeout"),
+        arg!(--"swim-max-transmissions" <COUNT> "How often a single broadcast is piggybacked onto SWIM messages. Defaults to the --profile")
+        .value_parser(value_parser!(u8).range(1..))
+        .id("swim-max-transmissions"),
+        arg!(--"forget-peers" "Don't announce to the members remembered from before a restart when there is no --announce-to or it can't be reached")
+        .id("forget-peers"),
+        arg!(--"adopt-data-dir" "Start on a data dir recorded for another identity or node id and record this node as its owner")
+        .id("adopt-data-dir"),
+        arg!(--"strict-identity" "Only treat a member coming up again at an address as the same member once it proved it holds the session token seen there first, signed with the --cluster-key-file. Members failing the check are logged and listed as unverified by /members")
+        .id("strict-identity"),
+        arg!(--"cluster-key-file" <FILE> "Secret of at least 16 bytes shared by all members, used by --strict-identity, e.g. written by `openssl rand -base64 32`")
+        .value_parser(value_parser!(PathBuf))
+        .id("cluster-key-file"),
+        arg!(--"probe-interval" <INTERVAL> "Measure the round trip time to every member this often, reported by /members?probe=true and /metrics. Disabled by default")
+        .value_parser(parse_duration)
+        .id("probe-interval"),
+        arg!(--"capture-dir" <CAPTURE_DIR> "Write all gossip traffic of this node to capture files in this directory for debugging")
+        .value_parser(value_parser!(PathBuf))
+        .id("capture-dir"),
+        arg!(--"capture-max-file-size" <BYTES> "Size after which a new capture file is started")
+        .value_parser(value_parser!(u64).range(1..))
+        .default_value(OsStr::from("67108864"))
+        .id("capture-max-file-size"),
+        arg!(--"audit-log" <PATH> "Append every field change applied on this node to this file as a JSON line")
+        .value_parser(value_parser!(PathBuf))
+        .id("audit-log"),
+        arg!(--"audit-log-max-size" <SIZE> "Size after which the audit log is rotated, e.g. 64MB")
+        .value_parser(parse_size)
+        .default_value(OsStr::from("64MB"))
+        .id("audit-log-max-size"),
+        arg!(--"audit-log-keep" <FILES> "Number of rotated audit logs kept besides the current one")
+        .value_parser(value_parser!(u64))
+        .default_value(OsStr::from("5"))
+        .id("audit-log-keep"),
+        arg!(--"audit-fsync" <POLICY> "When the audit log is synced to disk: always after every record, batch after the records written at once or never")
+        .value_parser(FsyncPolicy::from_str)
+        .default_value(OsStr::from("batch"))
+        .id("audit-fsync"),
+        arg!(--"audit-queue-size" <RECORDS> "Number of records waiting for the audit log after which further ones are dropped")
+        .value_parser(value_parser!(u64).range(1..))
+        .default_value(OsStr::from("4096"))
+        .id("audit-queue-size"),
+        arg!(--"otlp-endpoint" <URL> "Export tracing spans to this OTLP/HTTP collector, e.g. http://localhost:4318 (requires the otlp feature)")
+        .value_parser(NonEmptyStringValueParser::new())
+        .id("otlp-endpoint"),
+        arg!(--config <FILE> "holy-diver.toml written by init, its settings apply to the flags not given on the command line")
+        .value_parser(value_parser!(PathBuf))
+        .id("config"),
+        arg!(--"shutdown-deadline" <DURATION> "On SIGTERM or SIGINT, writes are rejected with 503, the members told about the leave and the state written. A node not done within this time exits with code 75")
+        .value_parser(parse_duration)
+        .default_value(OsStr::from(format!("{}s", DEFAULT_SHUTDOWN_DEADLINE.as_secs())))
+        .id("shutdown-deadline"),
+        arg!(--"print-config" "Print the effective configuration as JSON, including the profile and the settings overriding it, and exit")
+        .id("print-config"),
+        arg!(--check "Validate the configuration, data dir and state file, print the effective configuration as JSON and exit")
+        .id("check"),
+    ]
+}
+
+fn cli() -> Command {
+    Command::new("holy-diver")
+        .about("You expected SWIM but it was me DIO!")
+        .arg_required_else_help(false)
+        .args(node_args())
+        .subcommand(Command::new("serve")
+            .about("Runs a local cluster of several nodes in this process, e.g. for testing. Takes the same flags as a single node")
+            .args(node_args())
+            .args(&[
+            arg!(--instances <COUNT> "Number of nodes to run. Node i binds to --base-port + i, uses <DATA_DIR>/node-i and serves REST on --port + i, all but the first announce to the first")
+            .value_parser(value_parser!(u16).range(1..))
+            .default_value(OsStr::from("3"))
+            .id("instances"),
+            arg!(--"base-port" <PORT> "SWIM port of the first node, defaults to the port of --bind-address")
+            .value_parser(value_parser!(u16).range(1..))
+            .id("base-port"),
+            ]))
+        .subcommand(Command::new("init")
+            .about("Asks for the settings of a new node, creates its data dir and writes holy-diver.toml and a systemd unit for it, checked like --check")
+            .args(&[
+            arg!(--"bind-address" <BIND_ADDRESS> "Socket address the node binds to")
+            .value_parser(NonEmptyStringValueParser::new())
+            .default_value(OsStr::from("127.0.0.1:9000"))
+            .id("bind-address"),
+            arg!(-d --"data-dir" <DATA_DIR> "Data dir of the node, created if missing")
+            .value_parser(value_parser!(PathBuf))
+            .default_value(OsStr::from("./data"))
+            .id("data-dir"),
+            arg!(-a --"announce-to" <SEED> "Address of a member of the cluster to join, none for the first node")
+            .value_parser(NonEmptyStringValueParser::new())
+            .id("announce-to"),
+            arg!(-p --port <REST_PORT> "Port of the REST endpoint")
+            .value_parser(value_parser!(u16))
+            .default_value(OsStr::from("9090"))
+            .visible_alias("rest-port")
+            .id("rest-port"),
+            arg!(-o --"out-dir" <DIR> "Directory holy-diver.toml and holy-diver.service are written to")
+            .value_parser(value_parser!(PathBuf))
+            .default_value(OsStr::from("."))
+            .id("out-dir"),
+            arg!(-y --yes "Don't ask, take the flags and the defaults as they are, e.g. in provisioning scripts")
+            .id("yes"),
+            arg!(--force "Overwrite an existing holy-diver.toml and holy-diver.service")
+            .id("force"),
+            ]))
+        .subcommand(Command::new("backup")
+            .about("Writes a timestamped copy of the persisted state of a data dir. Restore by using a copy as automerge.dat of a data dir without wal.dat or by POSTing it to /import")
+            .args(&[
+            arg!(-d --"data-dir" <DATA_DIR> "Data dir containing the state to back up")
+            .value_parser(value_parser!(PathBuf))
+            .default_value(OsStr::from("./data"))
+            .id("data-dir"),
+            arg!(-o --out <OUT_DIR> "Directory the backup is written to")
+            .value_parser(value_parser!(PathBuf))
+            .default_value(OsStr::from("./backups"))
+            .id("out"),
+            arg!(--"data-encryption-key-file" <FILE> "Key the state of the data dir is encrypted with, the backup is encrypted with it as well")
+            .value_parser(value_parser!(PathBuf))
+            .id("data-encryption-key-file"),
+            ]))
+        .subcommand(Command::new("members")
+            .about("Prints the cluster members known to a running node")
+            .args(&[
+            arg!(--endpoint <URL> "REST endpoint of the node")
+            .value_parser(NonEmptyStringValueParser::new())
+            .default_value(OsStr::from("http://127.0.0.1:9090"))
+            .id("endpoint"),
+            arg!(--watch "Keep running and print a line whenever a member goes up or down")
+            .id("watch"),
+            arg!(--interval <INTERVAL> "How long to wait before reconnecting when the event stream of the node breaks")
+            .value_parser(parse_duration)
+            .default_value(OsStr::from("2s"))
+            .id("interval"),
+            arg!(--json "Print JSON instead of plain text")
+            .id("json"),
+            arg!(--token <TOKEN> "Bearer token for nodes started with --rest-auth-token")
+            .value_parser(NonEmptyStringValueParser::new())
+            .id("token"),
+            ]))
+        .subcommand(Command::new("diff")
+            .about("Compares the fields and histories of the documents of two running nodes")
+            .args(&[
+            arg!(--"endpoint-a" <URL> "REST endpoint of the first node")
+            .value_parser(NonEmptyStringValueParser::new())
+            .required(true)
+            .id("endpoint-a"),
+            arg!(--"endpoint-b" <URL> "REST endpoint of the second node")
+            .value_parser(NonEmptyStringValueParser::new())
+            .required(true)
+            .id("endpoint-b"),
+            arg!(--json "Print JSON instead of plain text")
+            .id("json"),
+            arg!(--token <TOKEN> "Bearer token with the admin scope for nodes started with --rest-auth-token")
+            .value_parser(NonEmptyStringValueParser::new())
+            .id("token"),
+            ]))
+        .subcommand(Command::new("replay")
+            .about("Feeds the inbound traffic of a capture file through a fresh node without any sockets and prints the resulting document and what happened to each message")
+            .args(&[
+            arg!(<CAPTURE_FILE> "Capture file written by a node started with --capture-dir")
+            .value_parser(value_parser!(PathBuf))
+            .id("capture-file"),
+            arg!(-d --"data-dir" <DATA_DIR> "Scratch data dir for the replayed state, should not contain state of a real node")
+            .value_parser(value_parser!(PathBuf))
+            .default_value(OsStr::from("./scratch"))
+            .id("data-dir"),
+            arg!(--"swim-codec" <CODEC> "Codec of the node that wrote the capture file, postcard or bincode")
+            .value_parser(SwimCodec::from_str)
+            .default_value(OsStr::from("postcard"))
+            .id("swim-codec"),
+            ]))
+        .subcommand(Command::new("completions")
+            .about("Prints the completions of all flags and subcommands for a shell (requires the completions feature)")
+            .arg(arg!(<SHELL> "Shell to complete in")
+            .value_parser(["bash", "zsh", "fish", "powershell"])
+            .id("shell")))
+        .subcommand(Command::new("manpage")
+            .about("Prints the man page of holy-diver in roff (requires the completions feature)"))
+
 }
 
 fn get_broadcast_data() -> Vec<u8> {
@@ -58,62 +458,280 @@ fn get_broadcast_data() -> Vec<u8> {
 #[tokio::main(flavor = "current_thread")]
 async fn main() -> Result<(), anyhow::Error> {
     dotenv().ok();
-    env_logger::init();
+    holydiver::swim::logging::init();
     let matches = cli().get_matches();
-    info!("Starting with matches: {:?}", matches);
+    if let Some(("init", init_matches)) = matches.subcommand() {
+        return run_init(init_matches);
+    }
+    if let Some(("backup", backup_matches)) = matches.subcommand() {
+        return run_backup(backup_matches);
+    }
+    if let Some(("members", members_matches)) = matches.subcommand() {
+        return run_members(members_matches);
+    }
+    if let Some(("diff", diff_matches)) = matches.subcommand() {
+        return run_diff(diff_matches);
+    }
+    if let Some(("replay", replay_matches)) = matches.subcommand() {
+        return run_replay(replay_matches).await;
+    }
+    if let Some(("completions", completions_matches)) = matches.subcommand() {
+        let shell = completions_matches.get_one::<String>("shell")
+        .expect("clap requires the shell");
+        return print_completions(shell);
+    }
+    if let Some(("manpage", _)) = matches.subcommand() {
+        return print_manpage();
+    }
     
-    let bind_addr = matches.get_one::<String>("bind-address")
-    .map(|ba| SocketAddr::from_str(ba.as_str()).unwrap_or_else(|_| panic!("could not parse binding address as SocketAddr '{}'", ba)))
-    .unwrap_or(SocketAddr::from_str("127.0.0.1:9000").unwrap());
-    info!("Binding to {}", bind_addr);
-
-    let identity = matches.get_one::<String>("identity")
-    .map(|id| SocketAddr::from_str(id.as_str()).unwrap_or_else(|_| panic!("could not parse identity as SocketAddr '{}'", id)))
-    .map(ID::new)
-    .unwrap_or(ID::new(bind_addr));
-    info!("Using identity {}", bind_addr); 
-
-    let announce_to = matches.get_one::<String>("announce-to")
-    .map(|a| SocketAddr::from_str(a.as_str()).unwrap_or_else(|_| panic!("could not parse announce-to as SocketAddr '{}'", a)))
-    .map(ID::new);
-    if announce_to.is_some() {
-        info!("Announcing to {:?}", announce_to.clone().unwrap());
+    // serve runs several nodes, each with the flags given to serve adjusted by NodeSettings::for_instance
+    let (matches, serve) = match matches.subcommand() {
+        Some(("serve", serve_matches)) => (serve_matches, true),
+        _ => (&matches, false),
+    };
+    let mut settings = node_settings(matches);
+    let config_path = matches.get_one::<PathBuf>("config").cloned();
+    if let Some(config_path) = &config_path {
+        let config = ConfigFile::load(config_path)?;
+        if let Some(log_level) = &config.log_level {
+            logging::set_filter(log_level)?;
+        }
+        config.apply(&mut settings, |flag| matches.value_source(flag) == Some(ValueSource::CommandLine));
+    }
+    let all_settings = if serve {
+        let base_port = match matches.get_one::<u16>("base-port") {
+            Some(base_port) => *base_port,
+            None => settings.bind_addr()?.port(),
+        };
+        let instances = *matches.get_one::<u16>("instances")
+        .expect("clap should have provided a default value for instances");
+        (0..instances).map(|index| settings.for_instance(index, base_port)).collect::<Result<Vec<_>>>()?
     } else {
-        info!("Starting up as single swimmer");
+        vec![settings]
+    };
+    for settings in &all_settings {
+        // not the matches themselves, they contain the auth tokens
+        info!("Starting with settings: {}", serde_json::to_string(settings)?);
+    }
+    if matches.get_flag("print-config") {
+        match all_settings.as_slice() {
+            [settings] => println!("{}", serde_json::to_string_pretty(settings)?),
+            all_settings => println!("{}", serde_json::to_string_pretty(all_settings)?),
+        }
+        return Ok(());
+    }
+    if matches.get_flag("check") {
+        // validates the configuration without binding any sockets or starting foca
+        let reports: Vec<CheckReport> = all_settings.iter().map(validate).collect();
+        let passed = reports.iter().all(CheckReport::passed);
+        match reports.as_slice() {
+            [report] => println!("{}", serde_json::to_string_pretty(report)?),
+            reports => println!("{}", serde_json::to_string_pretty(reports)?),
+        }
+        std::process::exit(if passed { 0 } else { 1 });
     }
 
-    let data_dir = matches.get_one::<PathBuf>("data-dir")
-    .expect("clap should have provided a default value for data-dir");
-    info!("Using {} as data dir", data_dir.display());
-
-    let rest_port = matches.get_one::<u16>("rest-port")
-    .expect("clap should have provided a default value for rest-port");
-    info!("Using {} as rest port", rest_port);
+    if let Some(endpoint) = matches.get_one::<String>("otlp-endpoint") {
+        init_tracing(endpoint)?;
+    }
 
     let should_broadcast = matches.get_one::<bool>("broadcast")
     .unwrap_or(&false)
     .to_owned();
-    
-    let foca_config = {
-        let mut c = Config::simple();
-        // With this setting you can suspend (^Z) one process,
-        // wait for it the member to be declared down then resume
-        // it (fg) and foca should recover by itself
-        c.notify_down_members = true;
-        // limits the number of broadcasts of a single message
-        c.max_transmissions = NonZeroU8::new(2).unwrap();
-        c
+    let mut nodes = Vec::new();
+    for settings in &all_settings {
+        match start_node(settings, should_broadcast).await {
+            Ok(node) => nodes.push(node),
+            Err(e) => {
+                for node in &nodes {
+                    node.controller.shutdown().await;
+                }
+                return Err(e);
+            },
+        }
+    }
+    let shutdown_deadline = *matches.get_one::<Duration>("shutdown-deadline")
+    .expect("clap should have provided a default value for shutdown-deadline");
+    let shutdown = ShutdownCoordinator::new();
+    let controllers: Vec<_> = nodes.iter().map(|node| node.controller.clone()).collect();
+    let mut servers = Vec::new();
+    for node in nodes {
+        let server_config = ServerConfig {
+            shutdown: shutdown.clone(),
+            handle_signals: false,
+            // the other half of the deadline is left to write the state
+            shutdown_timeout: shutdown_deadline / 2,
+            ..node.server_config
+        };
+        match bind_server_with_config(server_config, node.controller).await {
+            Ok((_, server)) => servers.push(server),
+            Err(e) => {
+                for controller in &controllers {
+                    controller.shutdown().await;
+                }
+                return Err(e);
+            },
+        }
+    }
+    shutdown::spawn_reload_on_hangup(move || match &config_path {
+        Some(config_path) => reload_log_level(config_path),
+        None => Err(anyhow::anyhow!("there is nothing to reload without --config")),
+    })?;
+    // the REST servers stop on SIGTERM and SIGINT, the nodes are shut down once all of them stopped, see shutdown
+    let handles: Vec<_> = servers.iter().map(|server| server.handle()).collect();
+    let mut running = std::pin::pin!(try_join_all(servers));
+    let served = tokio::select! {
+        served = &mut running => served,
+        signal = shutdown::stop_signal() => {
+            info!("Received {}, shutting down within {:?}", signal?, shutdown_deadline);
+            shutdown.begin();
+            shutdown::exit_after(shutdown_deadline);
+            join(running, join_all(handles.iter().map(|handle| handle.stop(true)))).await.0
+        },
     };
+    for controller in controllers {
+        controller.shutdown().await;
+    }
+    info!("Shut down");
+    served.map(|_| ()).map_err(anyhow::Error::from)
+}
+
+// applies the log level of the config file again, called on SIGHUP
+fn reload_log_level(config_path: &Path) -> Result<()> {
+    match ConfigFile::load(config_path)?.log_level {
+        Some(log_level) => {
+            logging::set_filter(&log_level)?;
+            info!("Log level set to {}", log_level);
+        },
+        None => info!("{} sets no log level, keeping {}", config_path.display(), logging::current_filter().unwrap_or_default()),
+    }
+    Ok(())
+}
+
+// a node whose REST server still has to be hosted
+struct StartedNode {
+    controller: ControllerHandle,
+    server_config: ServerConfig,
+}
+
+async fn start_node(settings: &NodeSettings, should_broadcast: bool) -> Result<StartedNode> {
+    let bind_addr = settings.bind_addr()?;
+    info!("Binding to {}", bind_addr);
+
+    let identity = ID::new(settings.identity_addr()?);
+    info!("Using identity {:?}", identity);
+
+    let announce_to: Vec<SocketAddr> = settings.announce_to_addr()?.into_iter().collect();
+    for warning in identity_warnings(identity.addr, announce_to.first()) {
+        warn!("{}", warning);
+    }
+    if !announce_to.is_empty() {
+        info!("Announcing to {:?}", announce_to);
+    } else {
+        info!("Starting up as single swimmer");
+    }
+
+    let data_dir = &settings.data_dir;
+    info!("Using {} as data dir", data_dir.display());
+    match settings.rest_port {
+        0 => info!("Using any free port as rest port"),
+        port => info!("Using {} as rest port", port),
+    }
+
+    let foca_config = settings.foca_config();
+    // shared between foca and the REST server so that /admin/settings changes reach both
+    let runtime_settings = settings.runtime.clone().shared();
+    // foca publishes member events that are streamed by /events
+    let member_events = events::member_channel(settings.event_queue_size);
+    // shared between foca and the REST server so that /admin/chaos changes the faults of the transport
+    let fault_injector = settings.chaos.then(|| {
+        warn!("Started with --chaos, network faults can be injected with /admin/chaos");
+        FaultInjector::shared(identity.addr, settings.chaos_seed)
+    });
+    // kept by foca, writes are checked against it by the data handler and /healthz by the REST server
+    let member_count = MemberCount::new();
+    let write_quorum = (settings.min_members_for_writes > DEFAULT_MIN_MEMBERS_FOR_WRITES)
+        .then(|| WriteQuorum::new(settings.min_members_for_writes, member_count.clone()));
     let runtime_config = FocaRuntimeConfig {
-        identity: identity.clone(),
-        data_dir: data_dir.to_owned(),
-        bind_addr,
-        announce_to,
-        foca_config,
+        runtime_settings: runtime_settings.clone(),
+        member_count,
+        fault_injector: fault_injector.clone(),
+        member_events: member_events.clone(),
+        capture_dir: settings.capture_dir.clone(),
+        capture_max_file_size: settings.capture_max_file_size,
+        broadcast_backlog_warning: settings.broadcast_backlog_warning,
+        startup_reply_window: Duration::from_secs(settings.startup_reply_window_secs),
+        merge_queue_size: settings.merge_queue_size,
+        inbound_limits: InboundLimits {
+            packets_per_sec: settings.peer_packet_rate,
+            broadcasts_per_sec: settings.peer_broadcast_rate,
+        },
+        swim_codec: settings.swim_codec,
+        wire_version: settings.wire_version,
+        bump_strategy: settings.identity_bump,
+        hop_tracing: settings.trace_hops.then(|| HopTracing { operation_prefix: settings.trace_operation.clone() }),
+        join_timeout: settings.join_timeout_secs.map(Duration::from_secs),
+        probe_interval: settings.probe_interval_secs.map(Duration::from_secs),
+        strict_identity: settings.cluster_key()?,
+        forget_peers: settings.forget_peers,
+        checksum_interval: settings.checksum_interval_secs.map(Duration::from_secs),
+        doc_schema: DocSchema::parse(&settings.containers)?,
+        ..FocaRuntimeConfig::new(identity.clone(), data_dir.to_owned(), bind_addr, announce_to, foca_config)
     };
+    // malformed seed files abort the startup before anything is running
+    let seed = settings.seed_file.as_ref()
+    .map(|seed_file| read_seed_file(seed_file))
+    .transpose()?;
+    let seed_force = settings.seed_force;
+    let validator = settings.schema_file.as_ref()
+    .map(|schema_file| Validator::from_file(schema_file))
+    .transpose()?;
+    let auth_tokens = AuthTokens::parse(&settings.rest_auth_tokens)?;
+    // before anything reads the data dir, a dir written by a newer version aborts the startup here
+    migrate_data_dir(data_dir)?;
+    // a dir recorded for another node aborts the startup before its history is merged into ours
+    claim_data_dir(&FileStore::new(data_dir), identity.addr, settings.adopt_data_dir)?;
+    let has_persisted_state = HolyDiverDataHandler::has_persisted_state(&runtime_config.data_dir);
     // let state = read_state_from_disk(data_dir);
     // let state_ref = Arc::from(Mutex::from(state));
-    let data_handler = Arc::from(Mutex::from(HolyDiverDataHandler::new(&runtime_config.data_dir, identity.clone())));
+    let data_key = settings.data_key()?;
+    let data_handler = Arc::from(Mutex::from(HolyDiverDataHandler::open_with_schema(FileStore::shared(data_dir), identity.clone(), data_key.clone(), runtime_config.doc_schema.clone())?));
+    if let Some(validator) = validator {
+        data_handler.lock().unwrap().set_validator(Arc::new(validator));
+    }
+    data_handler.lock().unwrap().set_journal_bounds(settings.journal_max_entries, Duration::from_secs(settings.journal_max_age_secs));
+    data_handler.lock().unwrap().set_override_window(Duration::from_secs(settings.override_window_secs));
+    data_handler.lock().unwrap().set_log_overrides(settings.log_overrides);
+    data_handler.lock().unwrap().set_field_sanitation(settings.sanitize_field_names);
+    data_handler.lock().unwrap().set_event_queue_size(settings.event_queue_size);
+    data_handler.lock().unwrap().set_wal_max_size(settings.wal_max_size);
+    data_handler.lock().unwrap().set_strict_durability(settings.strict_durability);
+    data_handler.lock().unwrap().set_max_doc_size(settings.max_doc_size);
+    data_handler.lock().unwrap().set_write_quorum(write_quorum.clone());
+    let conflict_policies = ConflictPolicies::parse(&settings.conflict_policies)?;
+    let resolves_conflicts = !conflict_policies.is_empty();
+    data_handler.lock().unwrap().set_conflict_policies(conflict_policies);
+    if let Some(audit_config) = settings.audit_config() {
+        data_handler.lock().unwrap().set_audit_log(AuditLog::spawn(audit_config)?);
+    }
+    // also run without patterns, so that fields that were local before are replicated again
+    let migrated_fields = data_handler.lock().unwrap().set_no_replicate(settings.no_replicate.clone())?;
+    // containers declared since the document was written
+    let created_containers = data_handler.lock().unwrap().create_declared_containers()?;
+    let seeded_over_existing_state = match seed {
+        Some(seed) => apply_seed(&mut data_handler.lock().unwrap(), seed, has_persisted_state, seed_force)? == SeedOutcome::AppliedOverExistingState,
+        None => false,
+    };
+    // set after the seed, which is the operator's and not limited
+    data_handler.lock().unwrap().set_quotas(auth_tokens.quotas()?);
+    if let Some(backup_interval) = settings.backup_interval_secs.map(Duration::from_secs) {
+        info!("Writing a backup every {:?}, keeping {}", backup_interval, settings.backup_keep);
+        spawn_scheduled_backups(data_handler.clone(), backup_dir(data_dir), backup_interval, settings.backup_keep, data_key.clone());
+    }
+    spawn_tombstone_gc(data_handler.clone(), Duration::from_secs(settings.tombstone_horizon_secs));
+    spawn_persistence_retry(data_handler.clone());
+    spawn_history_monitor(data_handler.clone(), Duration::from_secs(settings.history_check_interval_secs));
+    let gossip_budget = gossip_budget(&runtime_config.foca_config, settings.gossip_budget_percent);
     let foca_command_sender = setup_foca(runtime_config, Box::new(data_handler.clone())).await?;
     if should_broadcast {
         let broadcast_data = get_broadcast_data();
@@ -121,10 +739,457 @@ async fn main() -> Result<(), anyhow::Error> {
             operation_id: Uuid::new_v4()
         }, GossipMessage::new(FullSync, broadcast_data)))).await?;
     }
-    let rest_controller = Arc::from(Mutex::from(HolyDiverController{
+    let broadcasts = BroadcastCoalescer::spawn(foca_command_sender.clone(), data_handler.clone(), gossip_budget
+        , Duration::from_millis(settings.broadcast_interval_ms));
+    if resolves_conflicts {
+        spawn_policy_broadcasts(data_handler.clone(), broadcasts.clone());
+    }
+    let rest_controller = ControllerHandle::spawn(HolyDiverController{
         foca_command_sender: foca_command_sender.clone(),
-        data_handler: data_handler,
-    }));
-    host_server(rest_port.to_owned(), rest_controller).await?;
+        data_handler,
+        broadcasts,
+    });
+    if seeded_over_existing_state || migrated_fields > 0 || created_containers > 0 {
+        rest_controller.broadcast_current_state().await?;
+    }
+    if let Some(text_port) = settings.text_port {
+        let text_config = TextServerConfig {
+            idle_timeout: Duration::from_secs(settings.text_idle_timeout_secs),
+            hide_invalid_fields: settings.sanitize_field_names != FieldSanitation::Off,
+            ..TextServerConfig::new(text_port)
+        };
+        let text_server = match TextServer::bind(text_config).await {
+            Ok(text_server) => text_server,
+            Err(e) => {
+                rest_controller.shutdown().await;
+                return Err(e);
+            },
+        };
+        tokio::spawn(text_server.run(rest_controller.clone()));
+    }
+    let server_config = ServerConfig {
+        runtime_settings,
+        member_events,
+        sse_heartbeat: Duration::from_secs(settings.sse_heartbeat_secs),
+        enable_swagger: settings.enable_swagger,
+        compression: settings.rest_compression,
+        write_concern_timeout: Duration::from_secs(settings.write_concern_timeout_secs),
+        read_timeout: Duration::from_millis(settings.rest_read_timeout_ms),
+        write_timeout: Duration::from_millis(settings.rest_write_timeout_ms),
+        slow_request_threshold: Duration::from_millis(settings.slow_request_ms),
+        unhealthy_after_failures: settings.unhealthy_after_failures,
+        auth_tokens,
+        fault_injector,
+        debug_endpoints: settings.debug_endpoints,
+        field_sanitation: settings.sanitize_field_names,
+        write_quorum,
+        port_file: Some(data_dir.join(REST_PORT_FILE)),
+        ..ServerConfig::new(settings.rest_port)
+    };
+    Ok(StartedNode {
+        controller: rest_controller,
+        server_config,
+    })
+}
+
+// the flags whose defaults come from the --profile, by the settings they set
+const PROFILE_FLAGS: [(&str, &str); 9] = [
+    ("swim-probe-period", "swim_probe_period_ms"),
+    ("swim-probe-rtt", "swim_probe_rtt_ms"),
+    ("swim-suspect-timeout", "swim_suspect_timeout_ms"),
+    ("swim-max-transmissions", "swim_max_transmissions"),
+    ("broadcast-interval", "broadcast_interval_ms"),
+    ("checksum-interval", "checksum_interval_secs"),
+    ("event-queue-size", "event_queue_size"),
+    ("broadcast-backlog-warning", "broadcast_backlog_warning"),
+    ("write-concern-timeout", "write_concern_timeout_secs"),
+];
+
+fn node_settings(matches: &ArgMatches) -> NodeSettings {
+    let profile = matches.get_one::<Profile>("profile").copied();
+    let defaults = Profile::defaults(profile);
+    NodeSettings {
+        profile,
+        overridden: PROFILE_FLAGS.iter()
+        .filter(|(flag, _)| matches.value_source(flag) == Some(ValueSource::CommandLine))
+        .map(|(_, setting)| setting.to_string())
+        .collect(),
+        bind_address: matches.get_one::<String>("bind-address")
+        .cloned()
+        .unwrap_or("127.0.0.1:9000".to_owned()),
+        identity: matches.get_one::<String>("identity").cloned(),
+        announce_to: matches.get_one::<String>("announce-to").cloned(),
+        data_dir: matches.get_one::<PathBuf>("data-dir")
+        .expect("clap should have provided a default value for data-dir")
+        .to_owned(),
+        rest_port: matches.get_one::<u16>("rest-port")
+        .expect("clap should have provided a default value for rest-port")
+        .to_owned(),
+        text_port: matches.get_one::<u16>("text-port").copied(),
+        text_idle_timeout_secs: matches.get_one::<Duration>("text-idle-timeout")
+        .expect("clap should have provided a default value for text-idle-timeout")
+        .as_secs(),
+        seed_file: matches.get_one::<PathBuf>("seed-file").cloned(),
+        seed_force: matches.get_flag("seed-force"),
+        enable_swagger: matches.get_flag("enable-swagger"),
+        rest_compression: matches.get_one::<String>("rest-compression")
+        .expect("clap should have provided a default value for rest-compression") == "on",
+        chaos: matches.get_flag("chaos"),
+        chaos_seed: matches.get_one::<u64>("chaos-seed").copied(),
+        debug_endpoints: matches.get_flag("debug-endpoints"),
+        schema_file: matches.get_one::<PathBuf>("schema-file").cloned(),
+        no_replicate: matches.get_many::<String>("no-replicate").map_or_else(Vec::new, |patterns| patterns.cloned().collect()),
+        conflict_policies: matches.get_many::<String>("conflict-policy").map_or_else(Vec::new, |specs| specs.cloned().collect()),
+        containers: matches.get_many::<String>("container").map_or_else(Vec::new, |containers| containers.cloned().collect()),
+        rest_auth_tokens: matches.get_many::<String>("rest-auth-token").map_or_else(Vec::new, |specs| specs.cloned().collect()),
+        gossip_budget_percent: matches.get_one::<u64>("gossip-budget-percent")
+        .expect("clap should have provided a default value for gossip-budget-percent")
+        .to_owned() as usize,
+        backup_interval_secs: matches.get_one::<Duration>("backup-interval").map(|d| d.as_secs()),
+        backup_keep: matches.get_one::<u64>("backup-keep")
+        .expect("clap should have provided a default value for backup-keep")
+        .to_owned() as usize,
+        journal_max_entries: matches.get_one::<u64>("journal-max-entries")
+        .expect("clap should have provided a default value for journal-max-entries")
+        .to_owned() as usize,
+        journal_max_age_secs: matches.get_one::<Duration>("journal-max-age")
+        .expect("clap should have provided a default value for journal-max-age")
+        .as_secs(),
+        override_window_secs: matches.get_one::<Duration>("override-window")
+        .expect("clap should have provided a default value for override-window")
+        .as_secs(),
+        log_overrides: matches.get_one::<bool>("log-overrides")
+        .expect("clap should have provided a default value for log-overrides")
+        .to_owned(),
+        sanitize_field_names: matches.get_one::<FieldSanitation>("sanitize-field-names")
+        .expect("clap should have provided a default value for sanitize-field-names")
+        .to_owned(),
+        wal_max_size: matches.get_one::<u64>("wal-max-size")
+        .expect("clap should have provided a default value for wal-max-size")
+        .to_owned() as usize,
+        strict_durability: matches.get_flag("strict-durability"),
+        max_doc_size: matches.get_one::<u64>("max-doc-size").map(|size| *size as usize),
+        min_members_for_writes: matches.get_one::<u64>("min-members-for-writes")
+        .expect("clap should have provided a default value for min-members-for-writes")
+        .to_owned() as usize,
+        data_encryption_key_file: matches.get_one::<PathBuf>("data-encryption-key-file").cloned(),
+        cluster_key_file: matches.get_one::<PathBuf>("cluster-key-file").cloned(),
+        strict_identity: matches.get_flag("strict-identity"),
+        unhealthy_after_failures: matches.get_one::<u32>("unhealthy-after-failures")
+        .expect("clap should have provided a default value for unhealthy-after-failures")
+        .to_owned(),
+        event_queue_size: matches.get_one::<u64>("event-queue-size")
+        .map_or(defaults.event_queue_size, |size| *size as usize),
+        sse_heartbeat_secs: matches.get_one::<Duration>("sse-heartbeat")
+        .expect("clap should have provided a default value for sse-heartbeat")
+        .as_secs(),
+        write_concern_timeout_secs: matches.get_one::<Duration>("write-concern-timeout")
+        .map_or(defaults.write_concern_timeout_secs, Duration::as_secs),
+        rest_read_timeout_ms: matches.get_one::<Duration>("rest-read-timeout")
+        .expect("clap should have provided a default value for rest-read-timeout")
+        .as_millis() as u64,
+        rest_write_timeout_ms: matches.get_one::<Duration>("rest-write-timeout")
+        .expect("clap should have provided a default value for rest-write-timeout")
+        .as_millis() as u64,
+        slow_request_ms: matches.get_one::<Duration>("slow-request-threshold")
+        .expect("clap should have provided a default value for slow-request-threshold")
+        .as_millis() as u64,
+        broadcast_interval_ms: matches.get_one::<Duration>("broadcast-interval")
+        .map_or(defaults.broadcast_interval_ms, |d| d.as_millis() as u64),
+        broadcast_backlog_warning: matches.get_one::<u64>("broadcast-backlog-warning")
+        .map_or(defaults.broadcast_backlog_warning, |backlog| *backlog as usize),
+        tombstone_horizon_secs: matches.get_one::<Duration>("tombstone-horizon")
+        .expect("clap should have provided a default value for tombstone-horizon")
+        .as_secs(),
+        history_check_interval_secs: matches.get_one::<Duration>("history-check-interval")
+        .expect("clap should have provided a default value for history-check-interval")
+        .as_secs(),
+        startup_reply_window_secs: matches.get_one::<Duration>("startup-reply-window")
+        .expect("clap should have provided a default value for startup-reply-window")
+        .as_secs(),
+        merge_queue_size: *matches.get_one::<u64>("merge-queue-size")
+        .expect("clap should have provided a default value for merge-queue-size") as usize,
+        peer_packet_rate: matches.get_one::<u32>("peer-packet-rate").copied(),
+        peer_broadcast_rate: matches.get_one::<u32>("peer-broadcast-rate").copied(),
+        swim_codec: *matches.get_one::<SwimCodec>("swim-codec")
+        .expect("clap should have provided a default value for swim-codec"),
+        wire_version: *matches.get_one::<u8>("wire-version")
+        .expect("clap should have provided a default value for wire-version"),
+        identity_bump: *matches.get_one::<BumpStrategy>("identity-bump")
+        .expect("clap should have provided a default value for identity-bump"),
+        trace_hops: matches.get_flag("trace-hops"),
+        trace_operation: matches.get_one::<String>("trace-operation").cloned(),
+        join_timeout_secs: matches.get_one::<Duration>("join-timeout").map(|d| d.as_secs()),
+        probe_interval_secs: matches.get_one::<Duration>("probe-interval").map(|d| d.as_secs()),
+        forget_peers: matches.get_flag("forget-peers"),
+        adopt_data_dir: matches.get_flag("adopt-data-dir"),
+        checksum_interval_secs: matches.get_one::<Duration>("checksum-interval")
+        .map_or(defaults.checksum_interval_secs, |d| Some(d.as_secs())),
+        swim_probe_period_ms: matches.get_one::<Duration>("swim-probe-period")
+        .map_or(defaults.swim_probe_period_ms, |d| d.as_millis() as u64),
+        swim_probe_rtt_ms: matches.get_one::<Duration>("swim-probe-rtt")
+        .map_or(defaults.swim_probe_rtt_ms, |d| d.as_millis() as u64),
+        swim_suspect_timeout_ms: matches.get_one::<Duration>("swim-suspect-timeout")
+        .map_or(defaults.swim_suspect_timeout_ms, |d| d.as_millis() as u64),
+        swim_max_transmissions: matches.get_one::<u8>("swim-max-transmissions")
+        .copied()
+        .unwrap_or(defaults.swim_max_transmissions),
+        capture_dir: matches.get_one::<PathBuf>("capture-dir").cloned(),
+        capture_max_file_size: matches.get_one::<u64>("capture-max-file-size")
+        .expect("clap should have provided a default value for capture-max-file-size")
+        .to_owned(),
+        audit_log: matches.get_one::<PathBuf>("audit-log").cloned(),
+        audit_log_max_size: matches.get_one::<u64>("audit-log-max-size")
+        .expect("clap should have provided a default value for audit-log-max-size")
+        .to_owned(),
+        audit_log_keep: matches.get_one::<u64>("audit-log-keep")
+        .expect("clap should have provided a default value for audit-log-keep")
+        .to_owned() as usize,
+        audit_fsync: matches.get_one::<FsyncPolicy>("audit-fsync")
+        .expect("clap should have provided a default value for audit-fsync")
+        .to_owned(),
+        audit_queue_size: matches.get_one::<u64>("audit-queue-size")
+        .expect("clap should have provided a default value for audit-queue-size")
+        .to_owned() as usize,
+        runtime: RuntimeSettings {
+            max_batch_keys: matches.get_one::<usize>("max-batch-keys")
+            .expect("clap should have provided a default value for max-batch-keys")
+            .to_owned(),
+            seen_ops_horizon_secs: matches.get_one::<u64>("seen-ops-horizon")
+            .expect("clap should have provided a default value for seen-ops-horizon")
+            .to_owned(),
+            seen_ops_flush_interval_secs: defaults.seen_ops_flush_interval_secs,
+        },
+    }
+}
+
+#[cfg(feature = "otlp")]
+fn init_tracing(endpoint: &str) -> Result<()> {
+    holydiver::swim::telemetry::init_otlp(endpoint)?;
+    info!("Exporting spans to {}", endpoint);
+    Ok(())
+}
+
+#[cfg(not(feature = "otlp"))]
+fn init_tracing(_endpoint: &str) -> Result<()> {
+    anyhow::bail!("--otlp-endpoint requires building with the otlp feature")
+}
+
+// both are generated from cli(), so new flags and subcommands show up without further changes
+#[cfg(feature = "completions")]
+fn print_completions(shell: &str) -> Result<()> {
+    let shell = clap_complete::Shell::from_str(shell).map_err(|e| anyhow::anyhow!(e))?;
+    // generate panics on write errors, e.g. of a closed pipe
+    let mut completions = Vec::new();
+    clap_complete::generate(shell, &mut cli(), "holy-diver", &mut completions);
+    std::io::Write::write_all(&mut std::io::stdout(), &completions)?;
+    Ok(())
+}
+
+#[cfg(feature = "completions")]
+fn print_manpage() -> Result<()> {
+    clap_mangen::Man::new(cli()).render(&mut std::io::stdout())?;
+    Ok(())
+}
+
+#[cfg(not(feature = "completions"))]
+fn print_completions(_shell: &str) -> Result<()> {
+    anyhow::bail!("completions requires building with the completions feature")
+}
+
+#[cfg(not(feature = "completions"))]
+fn print_manpage() -> Result<()> {
+    anyhow::bail!("manpage requires building with the completions feature")
+}
+
+// the client needs a tokio runtime, which is the one of actix here
+fn run_diff(matches: &ArgMatches) -> Result<()> {
+    actix_web::rt::System::new().block_on(diff(matches))
+}
+
+async fn diff(matches: &ArgMatches) -> Result<()> {
+    let export = |id: &str| {
+        let endpoint = matches.get_one::<String>(id).expect("clap requires the endpoints").clone();
+        let token = matches.get_one::<String>("token").cloned();
+        async move {
+            let client = HolyDiverClient::new(ClientConfig { token, ..ClientConfig::new(&endpoint) })?;
+            client.export().await.with_context(|| format!("could not export the document of {}", endpoint))
+        }
+    };
+    let (a, b) = futures_util::future::try_join(export("endpoint-a"), export("endpoint-b")).await?;
+    let diff = diff_saved(&a, &b)?;
+    if matches.get_flag("json") {
+        println!("{}", serde_json::to_string_pretty(&diff)?);
+    } else {
+        print!("{}", diff);
+    }
+    Ok(())
+}
+
+fn run_members(matches: &ArgMatches) -> Result<()> {
+    actix_web::rt::System::new().block_on(members(matches))
+}
+
+async fn members(matches: &ArgMatches) -> Result<()> {
+    let endpoint = matches.get_one::<String>("endpoint")
+    .expect("clap should have provided a default value for endpoint");
+    let json = matches.get_flag("json");
+    let client = HolyDiverClient::new(ClientConfig {
+        token: matches.get_one::<String>("token").cloned(),
+        ..ClientConfig::new(endpoint)
+    })?;
+    if !matches.get_flag("watch") {
+        let list = client.members().await?;
+        if json {
+            println!("{}", serde_json::to_string_pretty(&list.members)?);
+        } else {
+            for member in list.members {
+                match list.labels.get(&member).and_then(|labels| labels.get("version")) {
+                    Some(version) => println!("{} {}", member, version),
+                    None => println!("{}", member),
+                }
+            }
+        }
+        return Ok(());
+    }
+    let interval = matches.get_one::<Duration>("interval")
+    .expect("clap should have provided a default value for interval");
+    loop {
+        // the client already retries connecting with a backoff
+        let events = match client.watch(&[EventType::Member]).await {
+            Ok(events) => events,
+            Err(e) => {
+                warn!("Could not watch the members of {}, retrying in {:?}: {}", endpoint, interval, e);
+                actix_web::rt::time::sleep(*interval).await;
+                continue;
+            },
+        };
+        let mut events = std::pin::pin!(events);
+        while let Some(event) = events.next().await {
+            match event {
+                Ok(Event::Member(event)) if json => println!("{}", serde_json::to_string(&event)?),
+                Ok(Event::Member(event)) => {
+                    let kind = match event.event {
+                        MemberEventKind::Up => "up",
+                        MemberEventKind::Down => "down",
+                        MemberEventKind::SelfRenewed => "self-renewed",
+                    };
+                    match event.reason {
+                        Some(reason) => println!("{} {} {} members={} reason={}", event.timestamp.to_rfc3339(), kind, event.address, event.member_count,
+                            serde_json::to_value(reason)?.as_str().unwrap_or_default()),
+                        None => println!("{} {} {} members={}", event.timestamp.to_rfc3339(), kind, event.address, event.member_count),
+                    }
+                },
+                Ok(Event::LostEvents { count, .. }) => warn!("Fell behind and missed {} member events", count),
+                Ok(Event::Field(_)) => {},
+                Err(e) => warn!("Watching the members of {} failed: {}", endpoint, e),
+            }
+        }
+        warn!("The event stream of {} ended, reconnecting in {:?}", endpoint, interval);
+        actix_web::rt::time::sleep(*interval).await;
+    }
+}
+
+async fn run_replay(matches: &ArgMatches) -> Result<()> {
+    let capture_file = matches.get_one::<PathBuf>("capture-file")
+    .expect("clap requires the capture file");
+    let data_dir = matches.get_one::<PathBuf>("data-dir")
+    .expect("clap should have provided a default value for data-dir");
+    let codec = *matches.get_one::<SwimCodec>("swim-codec")
+    .expect("clap should have provided a default value for swim-codec");
+    let report = replay(capture_file, data_dir, codec).await?;
+    println!("{}", serde_json::to_string_pretty(&report)?);
+    Ok(())
+}
+
+fn run_init(matches: &ArgMatches) -> Result<()> {
+    let flag = |id: &str| matches.get_one::<String>(id).cloned();
+    let mut config = ConfigFile {
+        bind_address: flag("bind-address"),
+        identity: None,
+        announce_to: flag("announce-to"),
+        data_dir: matches.get_one::<PathBuf>("data-dir").cloned(),
+        rest_port: matches.get_one::<u16>("rest-port").copied(),
+        log_level: None,
+    };
+    if !matches.get_flag("yes") {
+        config.bind_address = prompt("Bind address", config.bind_address.as_deref())?;
+        config.data_dir = prompt("Data dir", config.data_dir.as_ref().and_then(|dir| dir.to_str()))?.map(PathBuf::from);
+        config.announce_to = prompt("Seed node to announce to", config.announce_to.as_deref())?;
+        config.rest_port = loop {
+            let default = config.rest_port.map(|port| port.to_string());
+            match prompt("REST port", default.as_deref())?.map(|port| port.parse::<u16>()).transpose() {
+                Ok(port) => break port,
+                Err(e) => println!("Not a port: {}", e),
+            }
+        };
+    }
+    // absolute, so that the node finds it from the working directory of the unit
+    let data_dir = config.data_dir.clone().unwrap_or_else(|| PathBuf::from("./data"));
+    std::fs::create_dir_all(&data_dir)
+    .with_context(|| format!("could not create data dir {}", data_dir.display()))?;
+    config.data_dir = Some(data_dir.canonicalize()?);
+
+    // the settings a node started with --config would run with, checked like --check
+    let mut settings = node_settings(&cli().get_matches_from(["holy-diver"]));
+    config.apply(&mut settings, |_| false);
+    let report = validate(&settings);
+    for warning in &report.warnings {
+        warn!("{}", warning);
+    }
+    if !report.passed() {
+        anyhow::bail!("the settings are not valid: {}", report.problems.join("; "));
+    }
+
+    let out_dir = matches.get_one::<PathBuf>("out-dir")
+    .expect("clap should have provided a default value for out-dir");
+    std::fs::create_dir_all(out_dir)
+    .with_context(|| format!("could not create {}", out_dir.display()))?;
+    let config_path = out_dir.canonicalize()?.join(CONFIG_FILE);
+    let unit_path = out_dir.canonicalize()?.join(SYSTEMD_UNIT_FILE);
+    if !matches.get_flag("force") {
+        if let Some(existing) = [&config_path, &unit_path].into_iter().find(|path| path.exists()) {
+            anyhow::bail!("{} already exists, pass --force to overwrite it", existing.display());
+        }
+    }
+    config.save(&config_path)?;
+    if ConfigFile::load(&config_path)? != config {
+        anyhow::bail!("{} does not read back as written", config_path.display());
+    }
+    let unit = systemd_unit(&std::env::current_exe()?, &config_path, &settings.data_dir);
+    std::fs::write(&unit_path, unit)
+    .with_context(|| format!("could not write {}", unit_path.display()))?;
+    println!("{}", config_path.display());
+    println!("{}", unit_path.display());
+    Ok(())
+}
+
+// Asks on stdin, an empty answer keeps the default
+fn prompt(question: &str, default: Option<&str>) -> Result<Option<String>> {
+    match default {
+        Some(default) => print!("{} [{}]: ", question, default),
+        None => print!("{} (empty for none): ", question),
+    }
+    std::io::Write::flush(&mut std::io::stdout())?;
+    let mut answer = String::new();
+    if std::io::stdin().read_line(&mut answer)? == 0 {
+        anyhow::bail!("stdin was closed, pass --yes to init without asking");
+    }
+    Ok(match answer.trim() {
+        "" => default.map(str::to_owned),
+        answer => Some(answer.to_owned()),
+    })
+}
+
+fn run_backup(matches: &ArgMatches) -> Result<()> {
+    let data_dir = matches.get_one::<PathBuf>("data-dir")
+    .expect("clap should have provided a default value for data-dir");
+    let out = matches.get_one::<PathBuf>("out")
+    .expect("clap should have provided a default value for out");
+    let key = matches.get_one::<PathBuf>("data-encryption-key-file")
+    .map(|path| DataKey::from_file(path))
+    .transpose()?;
+    let backup = backup_data_dir(data_dir, out, key.as_ref())?;
+    println!("{}", backup.display());
     Ok(())
 }
@@ -4,7 +4,7 @@ use std::{
     sync::{Arc, Mutex}, num::NonZeroU8, path::PathBuf,
 };
 use foca::Config;
-use holydiver::swim::{core::{FocaRuntimeConfig, HolyDiverDataHandler, HolyDiverController}, types::ID, foca::setup_foca, server::host_server};
+use holydiver::swim::{core::{FocaRuntimeConfig, HolyDiverDataHandler, HolyDiverController, gossip_budget, DEFAULT_GOSSIP_BUDGET_PERCENT}, coalesce::{BroadcastCoalescer, DEFAULT_BROADCAST_INTERVAL}, handle::ControllerHandle, types::ID, foca::setup_foca, server::host_server};
 use dotenv::dotenv;
 
 use anyhow::Result;
@@ -27,21 +27,18 @@ async fn main() -> Result<(), anyhow::Error> {
     data_dir.push("./examples/data2");
     let bind_addr = SocketAddr::from_str("127.0.0.1:9001")?;
     let identity = ID::new(bind_addr);
-    let announce_to = Some(ID::new(SocketAddr::from_str("127.0.0.1:9000")?));
-    let runtime_config = FocaRuntimeConfig {
-        identity: identity.clone(),
-        data_dir: data_dir,
-        bind_addr: bind_addr,
-        announce_to: announce_to,
-        foca_config: foca_config,
-    };
+    let announce_to = vec![SocketAddr::from_str("127.0.0.1:9000")?];
+    let runtime_config = FocaRuntimeConfig::new(identity.clone(), data_dir, bind_addr, announce_to, foca_config);
     let data_handler = Arc::from(Mutex::from(HolyDiverDataHandler::new(&runtime_config.data_dir, identity.clone())));
+    let gossip_budget = gossip_budget(&runtime_config.foca_config, DEFAULT_GOSSIP_BUDGET_PERCENT);
     let foca_command_sender = setup_foca(runtime_config, Box::new(data_handler.clone())).await?;
+    let broadcasts = BroadcastCoalescer::spawn(foca_command_sender.clone(), data_handler.clone(), gossip_budget, DEFAULT_BROADCAST_INTERVAL);
 
-    let rest_controller = Arc::from(Mutex::from(HolyDiverController{
+    let rest_controller = ControllerHandle::spawn(HolyDiverController{
         foca_command_sender: foca_command_sender.clone(),
-        data_handler: data_handler,
-    }));
+        data_handler,
+        broadcasts,
+    });
     host_server(9091, rest_controller).await?;
     Ok(())
 }
\ No newline at end of file
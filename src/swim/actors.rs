@@ -0,0 +1,187 @@
+use std::{
+    collections::BTreeMap, net::SocketAddr,
+};
+use anyhow::Result;
+use automerge::ActorId;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+
+use super::{store::SharedStateStore, types::ID};
+
+// Maps the automerge actor ids of the document to the members writing with them, stored as JSON in
+// <DATA_DIR>/actors.json. A fresh document uses the Debug format of the identity as actor id, so the
+// writers of merged changes are resolved from their actor id. Documents loaded from disk write with a
+// random actor id, which only the node itself can map to its identity.
+// The identity gets a random bump on every start, so a document created by an earlier run of the node holds the
+// actor id of an identity it doesn't have anymore, which the table may not know, e.g. when the document is older
+// than the table. Such actor ids are recorded as legacy/self when the document is loaded, see adopt_legacy, so
+// that the history and the audit log attribute their changes to this node, also once it writes under another
+// actor id scheme.
+
+/// The last known writer of an actor id.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(feature = "rest", derive(utoipa::ToSchema))]
+pub struct ActorInfo {
+    // Debug format of the identity, e.g. ID(127.0.0.1:9000, 4711)
+    pub identity: String,
+    #[cfg_attr(feature = "rest", schema(value_type = String, example = "127.0.0.1:9000"))]
+    pub addr: SocketAddr,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tag: Option<ActorTag>,
+}
+
+impl ActorInfo {
+    fn of(identity: &ID) -> Self {
+        ActorInfo {
+            identity: format!("{:?}", identity),
+            addr: identity.addr,
+            tag: None,
+        }
+    }
+}
+
+/// How an actor id came to be attributed to its writer, untagged if it was written or merged while the node ran.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "rest", derive(utoipa::ToSchema))]
+pub enum ActorTag {
+    // derived from an earlier identity of this node and found in the loaded document
+    #[serde(rename = "legacy/self")]
+    LegacySelf,
+}
+
+/// An actor id of the document with its writer, if known, and the number of its changes.
+#[derive(Debug, Clone, Serialize)]
+#[cfg_attr(feature = "rest", derive(utoipa::ToSchema))]
+pub struct ActorSummary {
+    #[serde(flatten)]
+    pub info: Option<ActorInfo>,
+    pub changes: usize,
+}
+
+pub const ACTORS_KEY: &str = "actors.json";
+
+// the identity an actor id was derived from by get_initial_state
+fn parse_actor(actor: &ActorId) -> Option<ID> {
+    let text = std::str::from_utf8(actor.to_bytes()).ok()?;
+    let (addr, bump) = text.strip_prefix("ID(")?.strip_suffix(')')?.rsplit_once(", ")?;
+    Some(ID::with_bump(addr.parse().ok()?, bump.parse().ok()?))
+}
+
+pub struct ActorTable {
+    store: SharedStateStore,
+    // keyed by the hex encoded actor id as reported for field values
+    actors: BTreeMap<String, ActorInfo>,
+}
+
+impl ActorTable {
+    // an unreadable table is replaced, it is rebuilt from the merged changes
+    pub fn load(store: SharedStateStore) -> Self {
+        let actors = match store.load(ACTORS_KEY) {
+            Ok(Some(data)) => serde_json::from_slice(&data).unwrap_or_else(|e| {
+                warn!("Replacing invalid actor table {}: {}", store.describe(ACTORS_KEY), e);
+                BTreeMap::new()
+            }),
+            Ok(None) => BTreeMap::new(),
+            Err(e) => {
+                warn!("Could not read actor table: {:#}", e);
+                BTreeMap::new()
+            },
+        };
+        ActorTable { store, actors }
+    }
+
+    fn insert(&mut self, actor: &ActorId, info: ActorInfo) -> bool {
+        let actor = actor.to_string();
+        if self.actors.get(&actor) == Some(&info) {
+            return false;
+        }
+        info!("Actor {} belongs to {}", actor, info.identity);
+        self.actors.insert(actor, info);
+        true
+    }
+
+    // the actor this node writes with, returns whether the table changed
+    pub fn record_own(&mut self, actor: &ActorId, identity: &ID) -> bool {
+        self.insert(actor, ActorInfo::of(identity))
+    }
+
+    // Records the actor ids of a loaded document that were derived from an identity with the address of this
+    // node as legacy/self. Returns how many of them the table did not hold as such yet.
+    pub fn adopt_legacy<'a>(&mut self, actors: impl IntoIterator<Item = &'a ActorId>, addr: SocketAddr) -> usize {
+        let mut adopted = 0;
+        for actor in actors {
+            if let Some(identity) = parse_actor(actor).filter(|identity| identity.addr == addr) {
+                let info = ActorInfo { tag: Some(ActorTag::LegacySelf), ..ActorInfo::of(&identity) };
+                if self.insert(actor, info) {
+                    adopted += 1;
+                }
+            }
+        }
+        adopted
+    }
+
+    // actors of merged changes, those not derived from an identity stay unknown
+    pub fn record_merged<'a>(&mut self, actors: impl IntoIterator<Item = &'a ActorId>) -> bool {
+        let mut changed = false;
+        for actor in actors {
+            if let Some(identity) = parse_actor(actor) {
+                changed |= self.insert(actor, ActorInfo::of(&identity));
+            }
+        }
+        changed
+    }
+
+    pub fn resolve(&self, actor: &str) -> Option<&ActorInfo> {
+        self.actors.get(actor)
+    }
+
+    // address of the writer of an actor id as rendered for clients
+    pub fn writer(&self, actor: Option<&String>) -> Option<String> {
+        actor.and_then(|a| self.resolve(a)).map(|info| info.addr.to_string())
+    }
+
+    pub fn save(&self) -> Result<()> {
+        let data = serde_json::to_vec_pretty(&self.actors)?;
+        self.store.save(ACTORS_KEY, &data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::TempDir;
+    use super::*;
+    use crate::swim::store::FileStore;
+
+    fn actor_of(identity: &ID) -> ActorId {
+        ActorId::from(format!("{:?}", identity).as_bytes())
+    }
+
+    #[test]
+    fn merged_actors_are_resolved_and_kept_across_restarts() {
+        let dir = TempDir::new().unwrap();
+        let identity = ID::with_bump(SocketAddr::from(([127, 0, 0, 1], 9002)), 7);
+        let mut table = ActorTable::load(FileStore::shared(dir.path()));
+        assert!(table.record_merged([&actor_of(&identity), &ActorId::random()]));
+        assert!(!table.record_merged([&actor_of(&identity)]));
+        table.save().unwrap();
+
+        let table = ActorTable::load(FileStore::shared(dir.path()));
+        let actor = actor_of(&identity).to_string();
+        assert_eq!(table.resolve(&actor), Some(&ActorInfo::of(&identity)));
+        assert_eq!(table.writer(Some(&actor)).as_deref(), Some("127.0.0.1:9002"));
+        assert_eq!(table.actors.len(), 1);
+    }
+
+    #[test]
+    fn only_earlier_identities_of_this_node_are_adopted() {
+        let dir = TempDir::new().unwrap();
+        let addr = SocketAddr::from(([127, 0, 0, 1], 9001));
+        let earlier = actor_of(&ID::with_bump(addr, 1));
+        let other = actor_of(&ID::with_bump(SocketAddr::from(([127, 0, 0, 1], 9002)), 1));
+        let mut table = ActorTable::load(FileStore::shared(dir.path()));
+        assert_eq!(table.adopt_legacy([&earlier, &other], addr), 1);
+        assert_eq!(table.adopt_legacy([&earlier], addr), 0);
+        assert_eq!(table.resolve(&earlier.to_string()).and_then(|info| info.tag), Some(ActorTag::LegacySelf));
+        assert_eq!(table.resolve(&other.to_string()), None);
+    }
+}
@@ -0,0 +1,158 @@
+use std::{
+    collections::{BTreeMap, HashMap, VecDeque}, net::SocketAddr, sync::{Arc, Mutex}, time::{Duration, Instant},
+};
+use serde::{Deserialize, Serialize};
+
+use super::{clock::WireTimestamp, metrics};
+
+// How long it takes from a local write on its origin until a member applied it. State broadcasts carry their
+// origin and the wall clock time of the oldest write they hold, see OperationOrigin and BroadcastCoalescer.
+// Members record the time until they applied them per origin, in the apply_latency_seconds histogram and as
+// p50, p95 and max over the last SUMMARY_WINDOW in /cluster/consistency. The wall clocks of two nodes are
+// compared, so the latencies are off by the skew between them and can even be negative. The broadcasts of a
+// node reach its own handler right after they were added, which is recorded as its loopback latency: the time
+// spent coalescing and queued without any skew or network, the baseline for the latencies of the others.
+
+pub const SUMMARY_WINDOW: Duration = Duration::from_secs(5 * 60);
+// samples kept per origin within the window, further ones replace the oldest
+const MAX_SAMPLES: usize = 1024;
+
+/// Where and when the changes of a state broadcast were written.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct OperationOrigin {
+    pub node: SocketAddr,
+    // wall clock time of the oldest write in the broadcast, on node
+    pub written: WireTimestamp,
+}
+
+/// Apply latencies in millis over the last SUMMARY_WINDOW.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(feature = "rest", derive(utoipa::ToSchema))]
+pub struct LatencySummary {
+    pub samples: usize,
+    pub p50_ms: i64,
+    pub p95_ms: i64,
+    pub max_ms: i64,
+}
+
+/// The apply latencies of the broadcasts of each origin, see apply_latency.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[cfg_attr(feature = "rest", derive(utoipa::ToSchema))]
+pub struct ApplyLatencyReport {
+    // of the broadcasts of this node, without clock skew
+    pub loopback: Option<LatencySummary>,
+    #[cfg_attr(feature = "rest", schema(value_type = Object))]
+    pub origins: BTreeMap<SocketAddr, LatencySummary>,
+}
+
+/// The recent apply latencies by origin. Clones share the samples.
+#[derive(Clone)]
+pub struct ApplyLatencies {
+    identity: SocketAddr,
+    // when a latency was recorded and the latency in millis, oldest first
+    origins: Arc<Mutex<HashMap<SocketAddr, VecDeque<(Instant, i64)>>>>,
+}
+
+impl ApplyLatencies {
+    pub fn new(identity: SocketAddr) -> Self {
+        ApplyLatencies { identity, origins: Arc::new(Mutex::new(HashMap::new())) }
+    }
+
+    // A broadcast of origin was applied at the wall clock time applied, now is when for the summary window.
+    // Negative latencies only count as 0 in the histogram, it can't hold them.
+    pub fn record(&self, origin: OperationOrigin, applied: WireTimestamp, now: Instant) {
+        let latency_ms = applied.millis() - origin.written.millis();
+        metrics::APPLY_LATENCY_SECONDS.with_label_values(&[&origin.node.to_string()])
+            .observe(latency_ms.max(0) as f64 / 1000.0);
+        let mut origins = self.origins.lock().unwrap();
+        let samples = origins.entry(origin.node).or_default();
+        prune(samples, now);
+        if samples.len() >= MAX_SAMPLES {
+            samples.pop_front();
+        }
+        samples.push_back((now, latency_ms));
+    }
+
+    pub fn report(&self, now: Instant) -> ApplyLatencyReport {
+        let mut origins = self.origins.lock().unwrap();
+        origins.retain(|_, samples| {
+            prune(samples, now);
+            !samples.is_empty()
+        });
+        let mut report = ApplyLatencyReport::default();
+        for (node, samples) in origins.iter() {
+            let summary = summarize(samples);
+            match *node == self.identity {
+                true => report.loopback = Some(summary),
+                false => {
+                    report.origins.insert(*node, summary);
+                },
+            }
+        }
+        report
+    }
+
+    // forgets the origins that are no members anymore, this node is always kept
+    pub fn retain(&self, members: &[SocketAddr]) {
+        self.origins.lock().unwrap().retain(|node, _| {
+            let keep = *node == self.identity || members.contains(node);
+            if !keep {
+                let _ = metrics::APPLY_LATENCY_SECONDS.remove_label_values(&[&node.to_string()]);
+            }
+            keep
+        });
+    }
+}
+
+fn prune(samples: &mut VecDeque<(Instant, i64)>, now: Instant) {
+    while samples.front().is_some_and(|(recorded, _)| now.saturating_duration_since(*recorded) > SUMMARY_WINDOW) {
+        samples.pop_front();
+    }
+}
+
+fn summarize(samples: &VecDeque<(Instant, i64)>) -> LatencySummary {
+    let mut latencies: Vec<i64> = samples.iter().map(|(_, latency)| *latency).collect();
+    latencies.sort_unstable();
+    let percentile = |p: f64| latencies[((latencies.len() - 1) as f64 * p).round() as usize];
+    LatencySummary {
+        samples: latencies.len(),
+        p50_ms: percentile(0.5),
+        p95_ms: percentile(0.95),
+        max_ms: latencies[latencies.len() - 1],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn origin(port: u16, written: i64) -> OperationOrigin {
+        OperationOrigin { node: SocketAddr::from(([127, 0, 0, 1], port)), written: WireTimestamp::from_millis(written) }
+    }
+
+    #[test]
+    fn latencies_are_summarized_by_origin_over_the_window() {
+        let latencies = ApplyLatencies::new(origin(9001, 0).node);
+        let start = Instant::now();
+        for latency in 1..=100 {
+            latencies.record(origin(9002, 1_000), WireTimestamp::from_millis(1_000 + latency), start);
+        }
+        latencies.record(origin(9001, 1_000), WireTimestamp::from_millis(1_003), start);
+        // the clock of 9003 is ahead of this one
+        latencies.record(origin(9003, 1_000), WireTimestamp::from_millis(990), start);
+
+        let report = latencies.report(start);
+        assert_eq!(report.origins[&origin(9002, 0).node], LatencySummary { samples: 100, p50_ms: 51, p95_ms: 95, max_ms: 100 });
+        assert_eq!(report.origins[&origin(9003, 0).node].max_ms, -10);
+        assert_eq!(report.loopback, Some(LatencySummary { samples: 1, p50_ms: 3, p95_ms: 3, max_ms: 3 }));
+
+        // this node is kept without being a member
+        latencies.retain(&[origin(9002, 0).node]);
+        let report = latencies.report(start);
+        assert_eq!(report.origins.keys().copied().collect::<Vec<_>>(), vec![origin(9002, 0).node]);
+        assert!(report.loopback.is_some());
+
+        let report = latencies.report(start + SUMMARY_WINDOW + Duration::from_secs(1));
+        assert!(report.origins.is_empty() && report.loopback.is_none());
+    }
+}
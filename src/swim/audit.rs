@@ -0,0 +1,273 @@
+use std::{
+    fmt::{Display, Formatter}, fs::{self, File, OpenOptions}, io::{BufWriter, Write}, path::{Path, PathBuf}, str::FromStr,
+    sync::{atomic::{AtomicU64, Ordering}, Arc},
+};
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc;
+
+use super::{events::{ChangeKind, FieldChange}, metrics};
+
+// --audit-log keeps an append-only record of every field change applied on this node, local writes as well
+// as merged remote changes, one JSON object per line. Unlike the automerge history it is never compacted.
+// The handler hands the records to a writer task through a bounded queue so that writes and merges never
+// wait for the disk, records that don't fit into the queue are dropped and counted. Once the log reaches
+// its maximum size it is renamed to <path>.1, older logs are shifted to <path>.2 and so on.
+
+pub const DEFAULT_QUEUE_SIZE: usize = 4096;
+pub const DEFAULT_MAX_FILE_SIZE: u64 = 64 * 1024 * 1024;
+pub const DEFAULT_KEEP: usize = 5;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AuditSource {
+    Local,
+    Remote,
+}
+
+/// A line of the audit log.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AuditRecord {
+    pub ts: DateTime<Utc>,
+    pub field: String,
+    // None if the field was absent or deleted before
+    pub old: Option<String>,
+    // None for deletes
+    pub new: Option<String>,
+    pub source: AuditSource,
+    // automerge actor of the new value, None for deletes and local fields
+    pub actor: Option<String>,
+    // X-Request-Id of the REST request of a local change
+    pub request_id: Option<String>,
+}
+
+impl AuditRecord {
+    // None for overridden local writes, the change of the merge replacing them is recorded instead
+    pub fn from_change(change: &FieldChange, ts: DateTime<Utc>) -> Option<Self> {
+        if change.kind == ChangeKind::Overridden {
+            return None;
+        }
+        Some(AuditRecord {
+            ts,
+            field: change.field.clone(),
+            old: change.previous_value.clone(),
+            new: change.winning_value.clone(),
+            source: if change.remote { AuditSource::Remote } else { AuditSource::Local },
+            actor: change.winning_actor.clone(),
+            request_id: change.request_id.clone(),
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FsyncPolicy {
+    // every record is synced before the next one is written
+    Always,
+    // the records taken from the queue at once are synced together
+    Batch,
+    // syncing is left to the OS
+    Never,
+}
+
+impl Display for FsyncPolicy {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FsyncPolicy::Always => write!(f, "always"),
+            FsyncPolicy::Batch => write!(f, "batch"),
+            FsyncPolicy::Never => write!(f, "never"),
+        }
+    }
+}
+
+impl FromStr for FsyncPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "always" => Ok(FsyncPolicy::Always),
+            "batch" => Ok(FsyncPolicy::Batch),
+            "never" => Ok(FsyncPolicy::Never),
+            other => Err(format!("unknown fsync policy {:?}, expected always, batch or never", other)),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct AuditConfig {
+    pub path: PathBuf,
+    // size in bytes after which the log is rotated
+    pub max_file_size: u64,
+    // rotated logs kept besides the current one
+    pub keep: usize,
+    pub fsync: FsyncPolicy,
+    // records that can wait for the writer before new ones are dropped
+    pub queue_size: usize,
+}
+
+impl AuditConfig {
+    pub fn new(path: PathBuf) -> Self {
+        AuditConfig {
+            path,
+            max_file_size: DEFAULT_MAX_FILE_SIZE,
+            keep: DEFAULT_KEEP,
+            fsync: FsyncPolicy::Batch,
+            queue_size: DEFAULT_QUEUE_SIZE,
+        }
+    }
+}
+
+/// Hands audit records to the writer task, see HolyDiverDataHandler::set_audit_log.
+#[derive(Clone)]
+pub struct AuditLog {
+    sender: mpsc::Sender<AuditRecord>,
+    dropped: Arc<AtomicU64>,
+}
+
+impl AuditLog {
+    // The log is opened before this returns, so that an unwritable path fails the startup.
+    // Needs to be called from within a tokio runtime since the writer task is spawned here.
+    pub fn spawn(config: AuditConfig) -> Result<Self> {
+        let mut file = AuditFile::open(config.clone())?;
+        info!("Writing the audit log to {}", config.path.display());
+        let (sender, mut receiver) = mpsc::channel::<AuditRecord>(config.queue_size.max(1));
+        tokio::spawn(async move {
+            while let Some(record) = receiver.recv().await {
+                let mut records = vec![record];
+                while let Ok(record) = receiver.try_recv() {
+                    records.push(record);
+                }
+                file = match tokio::task::spawn_blocking(move || {
+                    file.write(&records);
+                    file
+                }).await {
+                    Ok(file) => file,
+                    Err(e) => {
+                        error!("Writing the audit log did not finish, no further records are written: {}", e);
+                        return;
+                    },
+                };
+            }
+        });
+        Ok(AuditLog {
+            sender,
+            dropped: Arc::new(AtomicU64::new(0)),
+        })
+    }
+
+    // never waits, the record is dropped if the queue is full
+    pub fn record(&self, change: &FieldChange) {
+        let record = match AuditRecord::from_change(change, Utc::now()) {
+            Some(record) => record,
+            None => return,
+        };
+        if self.sender.try_send(record).is_err() {
+            if self.dropped.fetch_add(1, Ordering::Relaxed) == 0 {
+                warn!("The audit log can't keep up, records are dropped and counted in holydiver_audit_records_dropped_total");
+            }
+            metrics::AUDIT_RECORDS_DROPPED.inc();
+        }
+    }
+
+    // records dropped since the start because the queue was full
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
+
+struct AuditFile {
+    config: AuditConfig,
+    file: BufWriter<File>,
+    size: u64,
+}
+
+impl AuditFile {
+    fn open(config: AuditConfig) -> Result<Self> {
+        if let Some(dir) = config.path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            fs::create_dir_all(dir)
+                .with_context(|| format!("could not create the dir of the audit log {}", config.path.display()))?;
+        }
+        let (file, size) = open_append(&config.path)?;
+        Ok(AuditFile { config, file, size })
+    }
+
+    // failures are logged and lose the records, later records are still written
+    fn write(&mut self, records: &[AuditRecord]) {
+        match self.append(records) {
+            Ok(()) => metrics::AUDIT_RECORDS_WRITTEN.inc_by(records.len() as u64),
+            Err(e) => error!("Could not write {} records to the audit log {}: {:#}", records.len(), self.config.path.display(), e),
+        }
+    }
+
+    fn append(&mut self, records: &[AuditRecord]) -> Result<()> {
+        for record in records {
+            if self.size >= self.config.max_file_size {
+                self.rotate()?;
+            }
+            let mut line = serde_json::to_vec(record)?;
+            line.push(b'\n');
+            self.file.write_all(&line)?;
+            self.size += line.len() as u64;
+            if self.config.fsync == FsyncPolicy::Always {
+                self.file.flush()?;
+                self.file.get_ref().sync_data()?;
+            }
+        }
+        self.file.flush()?;
+        if self.config.fsync == FsyncPolicy::Batch {
+            self.file.get_ref().sync_data()?;
+        }
+        Ok(())
+    }
+
+    // shifts the rotated logs by one, dropping the oldest, and starts a new log
+    fn rotate(&mut self) -> Result<()> {
+        self.file.flush()?;
+        self.file.get_ref().sync_data()?;
+        let path = &self.config.path;
+        let keep = self.config.keep;
+        remove_if_exists(&rotated_path(path, keep.max(1)))?;
+        for index in (1..keep).rev() {
+            let from = rotated_path(path, index);
+            if from.exists() {
+                fs::rename(&from, rotated_path(path, index + 1))
+                    .with_context(|| format!("could not rotate {}", from.display()))?;
+            }
+        }
+        if keep > 0 {
+            fs::rename(path, rotated_path(path, 1))
+                .with_context(|| format!("could not rotate {}", path.display()))?;
+        } else {
+            remove_if_exists(path)?;
+        }
+        (self.file, self.size) = open_append(path)?;
+        info!("Rotated the audit log {}", path.display());
+        Ok(())
+    }
+}
+
+fn open_append(path: &Path) -> Result<(BufWriter<File>, u64)> {
+    let file = OpenOptions::new()
+        .append(true)
+        .create(true)
+        .open(path)
+        .with_context(|| format!("could not open the audit log {}", path.display()))?;
+    let size = file.metadata()?.len();
+    Ok((BufWriter::new(file), size))
+}
+
+fn remove_if_exists(path: &Path) -> Result<()> {
+    match fs::remove_file(path) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e).with_context(|| format!("could not remove {}", path.display())),
+        _ => Ok(()),
+    }
+}
+
+// <path>.<index>
+pub fn rotated_path(path: &Path, index: usize) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(format!(".{}", index));
+    PathBuf::from(name)
+}
@@ -0,0 +1,188 @@
+use std::{
+    collections::HashMap, fmt::{Display, Formatter}, sync::Arc,
+};
+use anyhow::{anyhow, bail, Result};
+
+use super::{pattern::glob_matches, quotas::{parse_quota, Quota}};
+
+// Coarse access control of the REST API by bearer tokens, configured with --rest-auth-token.
+// A token is declared as `token=scope,scope,...`, each scope is either `admin` or an access level and a
+// key pattern, e.g. `tokenA=rw:app1.*,r:shared.*`. `r` allows reading the matching keys, `rw` reading and
+// writing them. Of several patterns matching a key the one with the longest prefix before its first `*`
+// decides, so `rw:app1.*,r:app1.config.*` makes app1.config.* read-only. Keys matching no pattern are
+// not accessible at all. Without any token configured the API is open to everyone.
+// A scope can limit the fields matching its pattern with `;max_keys=N` and `;max_bytes=SIZE`, see quotas.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Access {
+    Read,
+    ReadWrite,
+}
+
+impl Display for Access {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Access::Read => write!(f, "r"),
+            Access::ReadWrite => write!(f, "rw"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Scope {
+    pub access: Access,
+    pub pattern: String,
+    pub quota: Option<Quota>,
+}
+
+impl Scope {
+    // the literal part of the pattern, longer ones are more specific
+    fn prefix_len(&self) -> usize {
+        self.pattern.find('*').unwrap_or(self.pattern.len())
+    }
+}
+
+impl Display for Scope {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}", self.access, self.pattern)
+    }
+}
+
+/// What a request is denied, the deciding scope is None if no scope matched.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Denied {
+    pub resource: String,
+    pub required: String,
+    pub scope: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TokenScopes {
+    pub admin: bool,
+    pub scopes: Vec<Scope>,
+}
+
+impl TokenScopes {
+    // the most specific scope matching key, on equal prefixes the longer pattern and then the first one wins
+    pub fn scope_for(&self, key: &str) -> Option<&Scope> {
+        self.scopes.iter()
+            .filter(|scope| glob_matches(&scope.pattern, key))
+            .fold(None, |best: Option<&Scope>, scope| match best {
+                Some(best) if (best.prefix_len(), best.pattern.len()) >= (scope.prefix_len(), scope.pattern.len()) => Some(best),
+                _ => Some(scope),
+            })
+    }
+
+    pub fn allows(&self, key: &str, access: Access) -> bool {
+        self.scope_for(key).is_some_and(|scope| scope.access >= access)
+    }
+
+    pub fn check(&self, key: &str, access: Access) -> std::result::Result<(), Denied> {
+        match self.scope_for(key) {
+            Some(scope) if scope.access >= access => Ok(()),
+            scope => Err(Denied {
+                resource: key.to_owned(),
+                required: access.to_string(),
+                scope: scope.map(Scope::to_string),
+            }),
+        }
+    }
+
+    pub fn check_admin(&self, resource: &str) -> std::result::Result<(), Denied> {
+        if self.admin {
+            return Ok(());
+        }
+        Err(Denied {
+            resource: resource.to_owned(),
+            required: "admin".to_owned(),
+            scope: None,
+        })
+    }
+}
+
+fn parse_scope(scope: &str) -> Result<Option<Scope>> {
+    if scope == "admin" {
+        return Ok(None);
+    }
+    let mut options: Vec<&str> = scope.split(';').map(str::trim).collect();
+    let scope = options.remove(0);
+    let (access, pattern) = scope.split_once(':')
+        .ok_or_else(|| anyhow!("scope {} is neither admin nor r:PATTERN or rw:PATTERN", scope))?;
+    let access = match access {
+        "r" => Access::Read,
+        "rw" => Access::ReadWrite,
+        other => bail!("unknown access {} in scope {}, expected r or rw", other, scope),
+    };
+    if pattern.is_empty() {
+        bail!("scope {} has no key pattern", scope);
+    }
+    let quota = parse_quota(pattern, &options)?;
+    Ok(Some(Scope { access, pattern: pattern.to_owned(), quota }))
+}
+
+// parses `token=scope,scope,...`
+pub fn parse_token_spec(spec: &str) -> Result<(String, TokenScopes)> {
+    let (token, scopes) = spec.split_once('=')
+        .ok_or_else(|| anyhow!("expected TOKEN=SCOPES in auth token {}", redact(spec)))?;
+    if token.is_empty() {
+        bail!("empty auth token in {}", redact(spec));
+    }
+    let mut token_scopes = TokenScopes::default();
+    for scope in scopes.split(',').map(str::trim).filter(|scope| !scope.is_empty()) {
+        match parse_scope(scope)? {
+            Some(scope) => token_scopes.scopes.push(scope),
+            None => token_scopes.admin = true,
+        }
+    }
+    Ok((token.to_owned(), token_scopes))
+}
+
+// the spec without the token itself, for logs and the --check report
+pub fn redact(spec: &str) -> String {
+    match spec.split_once('=') {
+        Some((_, scopes)) => format!("***={}", scopes),
+        None => "***".to_owned(),
+    }
+}
+
+/// Scopes by token, empty if the API is open.
+#[derive(Debug, Clone, Default)]
+pub struct AuthTokens(HashMap<String, Arc<TokenScopes>>);
+
+impl AuthTokens {
+    pub fn parse(specs: &[String]) -> Result<Self> {
+        let mut tokens = HashMap::new();
+        for spec in specs {
+            let (token, scopes) = parse_token_spec(spec)?;
+            if tokens.insert(token, Arc::new(scopes)).is_some() {
+                bail!("auth token {} is declared more than once", redact(spec));
+            }
+        }
+        let tokens = AuthTokens(tokens);
+        tokens.quotas()?;
+        Ok(tokens)
+    }
+
+    // The quotas of all scopes, a pattern may be limited by the scopes of several tokens as long as they
+    // declare the same limits.
+    pub fn quotas(&self) -> Result<Vec<Quota>> {
+        let mut quotas: Vec<Quota> = Vec::new();
+        for quota in self.0.values().flat_map(|scopes| &scopes.scopes).filter_map(|scope| scope.quota.as_ref()) {
+            match quotas.iter().find(|known| known.pattern == quota.pattern) {
+                Some(known) if known != quota => bail!("the scopes of {} declare different quotas", quota.pattern),
+                Some(_) => {},
+                None => quotas.push(quota.clone()),
+            }
+        }
+        quotas.sort_by(|a, b| a.pattern.cmp(&b.pattern));
+        Ok(quotas)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn get(&self, token: &str) -> Option<Arc<TokenScopes>> {
+        self.0.get(token).cloned()
+    }
+}
@@ -0,0 +1,144 @@
+use std::{
+    fs, path::{Path, PathBuf}, sync::{Arc, Mutex}, time::Duration,
+};
+use anyhow::{anyhow, Context, Result};
+use log::{info, error};
+
+use super::{core::HolyDiverDataHandler, broadcast::DataHandler, encryption::{self, DataKey}, persistence::load_persisted_state, store::{FileStore, write_atomically}};
+
+const BACKUP_PREFIX: &str = "automerge-";
+const BACKUP_EXTENSION: &str = "dat";
+
+// default location of scheduled backups
+pub fn backup_dir(data_dir: &Path) -> PathBuf {
+    data_dir.join("backups")
+}
+
+// Writes a timestamped copy of a serialized document into dir. Backups are written atomically,
+// so a backup that exists is always complete.
+pub fn write_backup(dir: &Path, data: &[u8]) -> Result<PathBuf> {
+    fs::create_dir_all(dir)
+        .with_context(|| format!("could not create backup dir {}", dir.display()))?;
+    // the timestamp format sorts lexicographically in chronological order
+    let name = format!("{}{}.{}", BACKUP_PREFIX, chrono::Utc::now().format("%Y%m%dT%H%M%S%.3fZ"), BACKUP_EXTENSION);
+    let path = dir.join(name);
+    write_atomically(&path, data)
+        .with_context(|| format!("could not write backup {}", path.display()))?;
+    Ok(path)
+}
+
+// Backups in dir, oldest first. Other files in dir are ignored.
+pub fn list_backups(dir: &Path) -> Result<Vec<PathBuf>> {
+    if !dir.exists() {
+        return Ok(Vec::new());
+    }
+    let mut backups = fs::read_dir(dir)
+        .with_context(|| format!("could not list backup dir {}", dir.display()))?
+        .filter_map(|entry| entry.ok().map(|e| e.path()))
+        .filter(|path| path.extension().is_some_and(|e| e == BACKUP_EXTENSION)
+            && path.file_name().and_then(|n| n.to_str()).is_some_and(|n| n.starts_with(BACKUP_PREFIX)))
+        .collect::<Vec<PathBuf>>();
+    backups.sort();
+    Ok(backups)
+}
+
+// Removes all but the newest `keep` backups, returns how many were removed
+pub fn prune_backups(dir: &Path, keep: usize) -> Result<usize> {
+    let backups = list_backups(dir)?;
+    let excess = backups.len().saturating_sub(keep);
+    for backup in &backups[..excess] {
+        fs::remove_file(backup)
+            .with_context(|| format!("could not remove old backup {}", backup.display()))?;
+        info!("Removed old backup {}", backup.display());
+    }
+    Ok(excess)
+}
+
+// Writes the persisted state of data_dir, the snapshot with the WAL applied, as a single document
+// into out_dir. The snapshot is only ever replaced by renaming a completely written file over it
+// and a partially appended WAL record is ignored, so a running node can be backed up as well.
+// With a key the state is decrypted with it and the backup is encrypted with it.
+pub fn backup_data_dir(data_dir: &Path, out_dir: &Path, key: Option<&DataKey>) -> Result<PathBuf> {
+    let (mut doc, _) = load_persisted_state(&FileStore::new(data_dir), key)?
+        .ok_or_else(|| anyhow!("no persisted state in {}", data_dir.display()))?;
+    write_backup(out_dir, &encryption::seal(&doc.save(), key))
+}
+
+// Periodically writes the in-memory document to dir and keeps the newest `keep` backups.
+// The document is serialized under the data handler lock, so every backup is a consistent snapshot.
+// Backups are encrypted with key if there is one. Needs to be called from within a tokio runtime.
+pub fn spawn_scheduled_backups(data_handler: Arc<Mutex<HolyDiverDataHandler>>, dir: PathBuf, interval: Duration, keep: usize,
+    key: Option<Arc<DataKey>>) {
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(interval).await;
+            let data = data_handler.lock().unwrap().get_state();
+            let target = dir.clone();
+            let key = key.clone();
+            let result = tokio::task::spawn_blocking(move || {
+                let path = write_backup(&target, &encryption::seal(&data, key.as_deref()))?;
+                prune_backups(&target, keep)?;
+                Ok::<PathBuf, anyhow::Error>(path)
+            }).await;
+            match result {
+                Ok(Ok(path)) => info!("Wrote scheduled backup {}", path.display()),
+                Ok(Err(e)) => error!("Scheduled backup failed: {:#}", e),
+                Err(e) => error!("Scheduled backup did not finish: {}", e),
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+    use automerge::AutoCommit;
+    use super::*;
+    use crate::swim::{persistence::STATE_KEY, types::ID};
+
+    fn open(data_dir: &Path) -> HolyDiverDataHandler {
+        HolyDiverDataHandler::new(data_dir, ID::with_bump(SocketAddr::from(([127, 0, 0, 1], 9001)), 0))
+    }
+
+    #[test]
+    fn pruning_keeps_the_newest_backups() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut written = Vec::new();
+        for generation in 0..5u8 {
+            written.push(write_backup(dir.path(), &[generation]).unwrap());
+            // backups are named by their timestamp in millis
+            std::thread::sleep(Duration::from_millis(5));
+        }
+        let unrelated = dir.path().join("notes.txt");
+        fs::write(&unrelated, "not a backup").unwrap();
+
+        assert_eq!(list_backups(dir.path()).unwrap(), written);
+        assert_eq!(prune_backups(dir.path(), 2).unwrap(), 3);
+        assert_eq!(list_backups(dir.path()).unwrap(), written[3..]);
+        assert_eq!(fs::read(&written[4]).unwrap(), vec![4]);
+        assert!(unrelated.exists());
+        assert_eq!(prune_backups(dir.path(), 2).unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn a_restored_backup_holds_the_same_document() {
+        let data_dir = tempfile::tempdir().unwrap();
+        let mut data_handler = open(data_dir.path());
+        data_handler.set_field("db.host".to_owned(), "primary".to_owned()).unwrap();
+        data_handler.set_field("db.port".to_owned(), "5432".to_owned()).unwrap();
+        data_handler.flush_handle().wait().await;
+
+        let backups = tempfile::tempdir().unwrap();
+        let backup = backup_data_dir(data_dir.path(), backups.path(), None).unwrap();
+        // restoring is pointing a node at a data dir holding the backup as its state
+        let restore_dir = tempfile::tempdir().unwrap();
+        fs::copy(&backup, restore_dir.path().join(STATE_KEY)).unwrap();
+        let mut restored = open(restore_dir.path());
+
+        assert_eq!(restored.get_field("db.host".to_owned()).unwrap().as_deref(), Some("primary"));
+        assert_eq!(restored.get_field("db.port".to_owned()).unwrap().as_deref(), Some("5432"));
+        let mut original = AutoCommit::load(&data_handler.get_state()).unwrap();
+        let mut restored = AutoCommit::load(&restored.get_state()).unwrap();
+        assert_eq!(restored.get_heads(), original.get_heads());
+    }
+}
@@ -0,0 +1,68 @@
+use std::collections::BTreeMap;
+use serde::{Deserialize, Serialize};
+
+// Several writes applied as a single automerge change with a single persist and broadcast, see
+// HolyDiverController::transaction. Either all operations are applied or none: everything that can
+// fail, e.g. schema validation or incrementing a field that is no integer, is checked before the
+// document is touched, and the pending change is rolled back if applying fails anyway.
+// Increments write automerge counters, so that concurrent increments on several nodes add up instead
+// of one of them winning.
+
+/// A single operation of a batch.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(feature = "rest", derive(utoipa::ToSchema))]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum BatchOp {
+    Set { key: String, value: String },
+    Delete { key: String },
+    // adds to the integer value of the field, absent fields count as 0
+    Increment { key: String, by: i64 },
+}
+
+impl BatchOp {
+    pub fn key(&self) -> &str {
+        match self {
+            BatchOp::Set { key, .. } | BatchOp::Delete { key } | BatchOp::Increment { key, .. } => key,
+        }
+    }
+}
+
+/// Operations staged by the closure passed to HolyDiverController::transaction, applied in order.
+#[derive(Debug, Default)]
+pub struct Transaction {
+    ops: Vec<BatchOp>,
+}
+
+impl Transaction {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn set(&mut self, key: impl Into<String>, value: impl Into<String>) -> &mut Self {
+        self.ops.push(BatchOp::Set { key: key.into(), value: value.into() });
+        self
+    }
+
+    pub fn delete(&mut self, key: impl Into<String>) -> &mut Self {
+        self.ops.push(BatchOp::Delete { key: key.into() });
+        self
+    }
+
+    pub fn increment(&mut self, key: impl Into<String>, by: i64) -> &mut Self {
+        self.ops.push(BatchOp::Increment { key: key.into(), by });
+        self
+    }
+
+    pub fn into_ops(self) -> Vec<BatchOp> {
+        self.ops
+    }
+}
+
+/// Outcome of an applied batch: the number of operations and the resulting value of every
+/// field they touched, null for deleted fields.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[cfg_attr(feature = "rest", derive(utoipa::ToSchema))]
+pub struct BatchSummary {
+    pub applied: usize,
+    pub values: BTreeMap<String, Option<String>>,
+}
@@ -1,22 +1,38 @@
 use std::{
-    collections::HashSet,
-    net::SocketAddr,
-    time::SystemTime, sync::{Mutex, Arc},
+    collections::HashMap, net::SocketAddr,
+    time::{Duration, Instant}, sync::{Mutex, Arc},
 };
 use bincode::Options;
 use bytes::{Bytes, BytesMut, BufMut,};
-use serde::{Deserialize, Serialize};
+use std::fmt::{Display, Formatter};
+use serde::{de::DeserializeOwned, Deserialize, Deserializer, Serialize, Serializer};
 use uuid::Uuid;
-use log::{debug, info};
-use chrono::NaiveDateTime;
+use log::{debug, info, warn, Level};
 
 use foca::{BroadcastHandler, Invalidates};
 
+use super::{apply_latency::{ApplyLatencies, OperationOrigin}, clock::{CLOCK, HybridTimestamp, WireTimestamp}, consistency::{Checksum, ChecksumTracker}, envelope::{self, MAX_DATAGRAM_SIZE}, inbound_limits::{Budget, InboundLimiter}, log_throttle::{self, ErrorCategory}, members::{MemberLabels, NodeLabels}, merge_queue::{MergeQueue, QueuedMerge, Source}, metrics, hops::{HopTrail, HopTracing}, types::ID, seen_ops::SeenOperations, startup_replies::{StartupReplies, StartupReply, DEFAULT_STARTUP_REPLY_WINDOW}, telemetry};
+
 // Broadcasts here will always have the following shape:
 //
 // 0. Tag describing the payload
 // 1. Payload (e.g. GossipMessage)
 //
+// The message type of a GossipMessage is encoded as its u16 code. Up to version 1 it was encoded as
+// the variant index of the enum, which bincode writes the same way for the built-in types, so members
+// of both versions understand each other as long as no custom types are sent. Members of version 1
+// drop messages of custom types as undecodable, members of version 2 count and drop messages of types
+// without a registered handler, see Handler::register. Version 3 adds the origin of state broadcasts, see
+// apply_latency, which is only encoded for members of envelope version 4, see versioned_origin. Version 4 adds the
+// trace context, encoded from envelope version 5 on, see versioned_trace_context.
+//
+pub const GOSSIP_MESSAGE_VERSION: u8 = 4;
+
+// Members of envelope version 1 may predate AckedOperations and drop them as undecodable, nodes sending
+// version 1 don't send them, see envelope
+pub const ACKED_OPERATION_VERSION: u8 = 2;
+// the same for the NodeLabels of NodeConfig broadcasts
+pub const NODE_LABELS_VERSION: u8 = 2;
 
 #[derive(Debug, Clone, Copy, Deserialize, Serialize)]
 pub enum Tag {
@@ -35,8 +51,10 @@ pub enum Tag {
         // need to do it yourself
     },
 
+    // A (re)started node asking the members for their state, see startup_message.
+    // node_id is the persisted id of the node, see node_id
     StartupMessage {
-        startup_time: NaiveDateTime,
+        startup_time: WireTimestamp,
         node_id: Uuid,
     },
 
@@ -48,14 +66,46 @@ pub enum Tag {
     // behaviour): we can simply use last-write wins
     NodeConfig {
         node: SocketAddr,
-        // XXX SystemTime does NOT guarantee monotonic growth.
-        //     It's good enough for an example, but it's an outage
-        //     waiting to happen. Use something you have better
-        //     control of.
-        version: SystemTime,
+        // from CLOCK, which doesn't go backwards with the wall clock
+        version: HybridTimestamp,
+    },
+
+    // A SyncOperation whose receivers acknowledge applying it with a direct Ack message to origin,
+    // see write concerns in HolyDiverController::set_field_replicated. Only sent from ACKED_OPERATION_VERSION on.
+    AckedOperation {
+        operation_id: Uuid,
+        origin: SocketAddr,
+    },
+
+    // The checksum of the values of node, see consistency. Like NodeConfig, newer ones replace older ones.
+    StateChecksum {
+        node: SocketAddr,
+        version: HybridTimestamp,
+    },
+
+    // Direct messages only: node is shutting down, so that its members report its MemberDown as a leave, see
+    // departures. The message only follows the tag so that the message has the usual shape.
+    Leave {
+        node: SocketAddr,
+    },
+
+    // A SyncOperation carrying the path it took, only sent with --trace-hops, see hops
+    TracedOperation {
+        operation_id: Uuid,
+        trail: HopTrail,
     },
 }
 
+impl Tag {
+    // the id of the operation for SyncOperations, whether traced or not
+    pub fn sync_operation_id(&self) -> Option<Uuid> {
+        match self {
+            Tag::SyncOperation { operation_id } | Tag::TracedOperation { operation_id, .. } => Some(*operation_id),
+            _ => None,
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct Broadcast {
     pub tag: Tag,
@@ -64,13 +114,26 @@ pub struct Broadcast {
 
 impl Invalidates for Broadcast {
     fn invalidates(&self, other: &Self) -> bool {
+        if let (Some(self_operation_id), Some(other_operation_id)) = (self.tag.sync_operation_id(), other.tag.sync_operation_id()) {
+            return self_operation_id.eq(&other_operation_id);
+        }
         match (self.tag, other.tag) {
-            (Tag::SyncOperation {
-                operation_id: self_operation_id
+            (Tag::NodeConfig {
+                node: self_node,
+                version: self_version,
             },
-            Tag::SyncOperation {
-                operation_id: other_operation_id
-            }) => self_operation_id.eq(&other_operation_id),
+            Tag::NodeConfig {
+                node: other_node,
+                version: other_version,
+            }) => self_node == other_node && self_version > other_version,
+            (Tag::StateChecksum {
+                node: self_node,
+                version: self_version,
+            },
+            Tag::StateChecksum {
+                node: other_node,
+                version: other_version,
+            }) => self_node == other_node && self_version > other_version,
             _ => false
         }
     }
@@ -85,127 +148,911 @@ impl AsRef<[u8]> for Broadcast {
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct GossipMessage {
     message_type: MessageType,
-    message_payload: Vec<u8>,
+    // encoded like a Vec<u8>, Bytes only makes received payloads cheap to hand around, see decode_item
+    message_payload: Bytes,
+    // W3C traceparent of the span the message was created in, so that receivers can continue the trace.
+    // bincode is not self-describing, so from envelope version 5 on the field is always encoded, as a single
+    // byte when absent, see versioned_trace_context.
+    #[serde(default, with = "versioned_trace_context")]
+    trace_context: Option<String>,
+    // where and when the changes of a state broadcast were written, see apply_latency. Always encoded as well,
+    // from envelope version 4 on, see versioned_origin.
+    #[serde(default, with = "versioned_origin")]
+    origin: Option<OperationOrigin>,
 }
 
 impl GossipMessage {
-    pub fn new(message_type: MessageType, message_payload: Vec<u8>) -> Self {
+    pub fn new(message_type: MessageType, message_payload: impl Into<Bytes>) -> Self {
         GossipMessage {
             message_type,
-            message_payload
+            message_payload: message_payload.into(),
+            trace_context: telemetry::current_trace_context(),
+            origin: None,
+        }
+    }
+
+    pub fn with_origin(mut self, origin: OperationOrigin) -> Self {
+        self.origin = Some(origin);
+        self
+    }
+
+    pub fn message_type(&self) -> MessageType {
+        self.message_type
+    }
+
+    pub fn message_payload(&self) -> &Bytes {
+        &self.message_payload
+    }
+
+    pub fn origin(&self) -> Option<OperationOrigin> {
+        self.origin
+    }
+
+    // a span for applying this message that continues the trace of the sender
+    fn apply_span(&self, operation_id: &Uuid) -> tracing::Span {
+        let span = tracing::info_span!("apply", %operation_id, message_type = ?self.message_type);
+        if let Some(trace_context) = &self.trace_context {
+            telemetry::set_remote_parent(&span, trace_context);
         }
+        span
     }
 }
 
-#[derive(Debug, Clone, Copy, Deserialize, Serialize)]
+// A GossipMessage borrowed from a received buffer, has to stay in sync with GossipMessage
+#[derive(Serialize, Deserialize)]
+struct GossipMessageRef<'a> {
+    message_type: MessageType,
+    #[serde(borrow)]
+    message_payload: &'a [u8],
+    #[serde(with = "versioned_trace_context")]
+    trace_context: Option<String>,
+    #[serde(with = "versioned_origin")]
+    origin: Option<OperationOrigin>,
+}
+
+// The origin of GossipMessages for the envelope version they are encoded in. Up to version 3 they had no
+// origin, it is left out of the messages of these versions and decoded as None.
+mod versioned_origin {
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+    use crate::swim::{apply_latency::OperationOrigin, envelope};
+
+    const FIRST_VERSION: u8 = 4;
+
+    pub fn serialize<S: Serializer>(origin: &Option<OperationOrigin>, serializer: S) -> Result<S::Ok, S::Error> {
+        if envelope::wire_version() < FIRST_VERSION {
+            // nothing at all in bincode
+            return serializer.serialize_unit();
+        }
+        origin.serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<OperationOrigin>, D::Error> {
+        if envelope::wire_version() < FIRST_VERSION {
+            return <()>::deserialize(deserializer).map(|_| None);
+        }
+        Option::deserialize(deserializer)
+    }
+}
+
+// The trace context of GossipMessages, left out up to version 4 the same way as the origin
+mod versioned_trace_context {
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+    use crate::swim::envelope;
+
+    const FIRST_VERSION: u8 = 5;
+
+    pub fn serialize<S: Serializer>(trace_context: &Option<String>, serializer: S) -> Result<S::Ok, S::Error> {
+        if envelope::wire_version() < FIRST_VERSION {
+            return serializer.serialize_unit();
+        }
+        trace_context.serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<String>, D::Error> {
+        if envelope::wire_version() < FIRST_VERSION {
+            return <()>::deserialize(deserializer).map(|_| None);
+        }
+        Option::deserialize(deserializer)
+    }
+}
+
+// Received items are never larger than the packet they arrived in, so lengths declared inside an item are
+// checked against a multiple of the packet size before anything is allocated for them. Without a limit bincode
+// allocates whatever length a crafted item declares. Broadcasts are bound to foca's max_packet_size, see
+// Handler::set_decode_limit, direct messages and payloads to MAX_DATAGRAM_SIZE.
+pub const DECODE_LIMIT_FACTOR: usize = 2;
+pub const DEFAULT_DECODE_LIMIT: u64 = (MAX_DATAGRAM_SIZE * DECODE_LIMIT_FACTOR) as u64;
+
+// the decode limit of broadcasts of a node with foca's max_packet_size
+pub fn decode_limit(max_packet_size: usize) -> u64 {
+    (max_packet_size * DECODE_LIMIT_FACTOR) as u64
+}
+
+// the options craft_broadcast encodes with and a limit, the limit doesn't change the encoding
+fn decode_options(limit: u64) -> impl Options + Copy {
+    bincode::DefaultOptions::new().with_limit(limit)
+}
+
+/// Why a received item was dropped.
+#[derive(Debug)]
+pub enum BroadcastError {
+    // a length declared in the item exceeds the decode limit, e.g. a crafted one
+    LimitExceeded { what: &'static str, limit: u64 },
+    // the item or its payload doesn't decode
+    Invalid(String),
+    // the tag can't arrive this way, e.g. a Leave that was broadcast
+    Unexpected(String),
+}
+
+impl BroadcastError {
+    fn decoding(what: &'static str, limit: u64, e: bincode::Error) -> Self {
+        match *e {
+            bincode::ErrorKind::SizeLimit => BroadcastError::LimitExceeded { what, limit },
+            e => BroadcastError::Invalid(format!("invalid {}: {}", what, e)),
+        }
+    }
+}
+
+impl Display for BroadcastError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BroadcastError::LimitExceeded { what, limit } => write!(f, "{} declares more than the limit of {} bytes", what, limit),
+            BroadcastError::Invalid(message) | BroadcastError::Unexpected(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+impl std::error::Error for BroadcastError {}
+
+// Decodes a payload encoded with bincode's default options, e.g. of custom message types, within DEFAULT_DECODE_LIMIT
+pub fn decode_payload<T: DeserializeOwned>(payload: &[u8]) -> Result<T, BroadcastError> {
+    decode_options(DEFAULT_DECODE_LIMIT).deserialize(payload)
+        .map_err(|e| BroadcastError::decoding("payload", DEFAULT_DECODE_LIMIT, e))
+}
+
+// Decodes the tag and message at the start of data without copying the payload,
+// returns the length of both as well since further items may follow
+fn decode_borrowed(data: &[u8], limit: u64) -> Result<(Tag, GossipMessageRef<'_>, usize), BroadcastError> {
+    let opts = decode_options(limit);
+    let mut reader = data;
+    let tag: Tag = opts.deserialize_from(&mut reader).map_err(|e| BroadcastError::decoding("tag", limit, e))?;
+    let tag_len = data.len() - reader.len();
+    let msg: GossipMessageRef = opts.allow_trailing_bytes().deserialize(reader)
+        .map_err(|e| BroadcastError::decoding("message", limit, e))?;
+    let msg_len = opts.serialized_size(&msg).map_err(|e| BroadcastError::decoding("message", limit, e))? as usize;
+    Ok((tag, msg, tag_len + msg_len))
+}
+
+// Decodes a single item as crafted by craft_broadcast, the payload of the message is a slice of item
+fn decode_item(item: &Bytes, limit: u64) -> Result<(Tag, GossipMessage), BroadcastError> {
+    let (tag, msg, _) = decode_borrowed(item, limit)?;
+    Ok((tag, GossipMessage {
+        message_type: msg.message_type,
+        message_payload: item.slice_ref(msg.message_payload),
+        trace_context: msg.trace_context,
+        origin: msg.origin,
+    }))
+}
+
+// Code of a message type. Codes below FIRST_CUSTOM_KIND are reserved for the built-in types.
+pub type MessageKind = u16;
+
+pub const FIRST_CUSTOM_KIND: MessageKind = 256;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum MessageType {
     FullSync,
     IncSync,
+    // direct messages only: asks the receiver for its heads, answered with Heads
+    HeadsRequest,
+    // direct messages only: bincode encoded Vec<ChangeHash>, answered with the changes the sender is missing
+    Heads,
+    // direct messages only: the 16 bytes of the operation id of an applied AckedOperation
+    Ack,
+    // bincode encoded NodeLabels of a NodeConfig broadcast, handled by the Handler itself
+    NodeLabels,
+    // the 16 bytes of the checksum of a StateChecksum broadcast, handled by the Handler itself
+    StateChecksum,
+    // direct messages only: the 16 bytes of a request id, answered with State
+    StateRequest,
+    // direct messages only: the request id followed by the saved document of the sender, only the request id
+    // if the document doesn't fit into a datagram
+    State,
+    // direct messages only: a bincode encoded SyncPayload of an automerge sync session, answered with the next
+    // message of the session until it is done, see sync_states
+    SyncMessage,
+    // direct messages only: the nonce of a challenge of --strict-identity, answered with IdentityProof by the caller
+    IdentityChallenge,
+    // direct messages only: the session token of the sender and its signature of the nonce, see strict_identity
+    IdentityProof,
+    // application messages handled by the handler registered for the code, see Handler::register.
+    // Also what unknown built-in codes of newer versions are decoded as.
+    Custom(MessageKind),
+}
+
+impl MessageType {
+    // the code on the wire, the built-in ones are the variant indexes of version 1
+    pub fn code(&self) -> MessageKind {
+        match self {
+            MessageType::FullSync => 0,
+            MessageType::IncSync => 1,
+            MessageType::HeadsRequest => 2,
+            MessageType::Heads => 3,
+            MessageType::Ack => 4,
+            MessageType::NodeLabels => 5,
+            MessageType::StateChecksum => 6,
+            MessageType::StateRequest => 7,
+            MessageType::State => 8,
+            MessageType::SyncMessage => 9,
+            MessageType::IdentityChallenge => 10,
+            MessageType::IdentityProof => 11,
+            MessageType::Custom(kind) => *kind,
+        }
+    }
+
+    pub fn from_code(code: MessageKind) -> Self {
+        match code {
+            0 => MessageType::FullSync,
+            1 => MessageType::IncSync,
+            2 => MessageType::HeadsRequest,
+            3 => MessageType::Heads,
+            4 => MessageType::Ack,
+            5 => MessageType::NodeLabels,
+            6 => MessageType::StateChecksum,
+            7 => MessageType::StateRequest,
+            8 => MessageType::State,
+            9 => MessageType::SyncMessage,
+            10 => MessageType::IdentityChallenge,
+            11 => MessageType::IdentityProof,
+            kind => MessageType::Custom(kind),
+        }
+    }
+}
+
+impl Serialize for MessageType {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_u16(self.code())
+    }
+}
+
+impl<'de> Deserialize<'de> for MessageType {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        MessageKind::deserialize(deserializer).map(MessageType::from_code)
+    }
 }
 
+/// What happened to a received SyncOperation.
+#[derive(Debug, Clone, Serialize)]
+pub enum ReceiveOutcome {
+    // the operation id was seen before, the message was ignored
+    Seen,
+    Applied,
+    Failed(String),
+    // an Ack of an AckedOperation sent by this node, to be handled by the caller
+    Acknowledged(Uuid),
+    // the answer to a StateRequest of this node with its request id, to be handled by the caller
+    State(Uuid, #[serde(skip_serializing)] Bytes),
+    // the member with this address announced its leave, to be handled by the caller
+    Leaving(SocketAddr),
+    // the nonce of an IdentityChallenge and the payload of an IdentityProof, to be handled by the caller
+    IdentityChallenge {
+        #[serde(skip_serializing)]
+        nonce: Bytes,
+    },
+    IdentityProof {
+        #[serde(skip_serializing)]
+        proof: Bytes,
+    },
+}
+
+// The broadcast a starting node sends to get the full state from the members. The message only
+// follows the tag so that the broadcast has the usual shape, its content is ignored.
+pub fn startup_message(node_id: Uuid, startup_time: WireTimestamp) -> (Tag, GossipMessage) {
+    (Tag::StartupMessage { startup_time, node_id }, GossipMessage::new(MessageType::HeadsRequest, Vec::new()))
+}
+
+// The broadcast announcing the labels of node, sent when it starts and whenever a member comes up
+pub fn node_config_message(node: SocketAddr, labels: &NodeLabels) -> (Tag, GossipMessage) {
+    let labels = bincode::DefaultOptions::new().serialize(labels).expect("error handling");
+    (Tag::NodeConfig { node, version: CLOCK.now() }, GossipMessage::new(MessageType::NodeLabels, labels))
+}
+
+// The direct message a node shutting down sends to every member
+pub fn leave_message(node: SocketAddr) -> (Tag, GossipMessage) {
+    (Tag::Leave { node }, GossipMessage::new(MessageType::HeadsRequest, Vec::new()))
+}
+
+// The broadcast carrying the checksum of the values of node, sent every --checksum-interval
+pub fn state_checksum_message(node: SocketAddr, checksum: &Checksum) -> (Tag, GossipMessage) {
+    (Tag::StateChecksum { node, version: CLOCK.now() }, GossipMessage::new(MessageType::StateChecksum, checksum.to_vec()))
+}
+
+pub type ReceiveObserver = Box<dyn FnMut(&Tag, &ReceiveOutcome) + Send>;
+
+// origin and operation id of applied AckedOperations that still have to be acknowledged
+pub type PendingAcks = Arc<Mutex<Vec<(SocketAddr, Uuid)>>>;
+
 pub struct Handler {
-    seen_op_ids: HashSet<Uuid>,
+    seen_op_ids: Arc<Mutex<SeenOperations>>,
     data_handler: Box<Arc<Mutex<dyn DataHandler + Send + Sync>>>,
+    observer: Option<ReceiveObserver>,
+    startup_replies: StartupReplies,
+    pending_acks: PendingAcks,
+    member_labels: MemberLabels,
+    // StateChecksum broadcasts are dropped without a tracker
+    checksums: Option<ChecksumTracker>,
+    // handlers of custom message types, see register
+    message_handlers: HashMap<MessageKind, Box<dyn DataHandler + Send>>,
+    // merges are applied inline without a queue
+    merge_queue: Option<MergeQueue>,
+    // address of this node and what to log of traced broadcasts, see set_hop_tracing
+    hop_tracing: Option<(SocketAddr, HopTracing)>,
+    // see DECODE_LIMIT_FACTOR
+    decode_limit: u64,
+    // received items take from the broadcast budget of their sender, not limited if None
+    inbound: Option<InboundLimiter>,
+    // the apply latencies of state broadcasts are only recorded with a tracker, see apply_latency
+    latencies: Option<ApplyLatencies>,
 }
 
 pub trait DataHandler {
-    fn handle_message(&mut self, msg_type:MessageType, data:Vec<u8>);
+    fn handle_message(&mut self, msg_type:MessageType, data:Bytes) -> anyhow::Result<()>;
+
+    // handles a message sent to this node only, the returned messages are sent back to the sender
+    fn handle_direct(&mut self, msg_type:MessageType, data:Bytes) -> anyhow::Result<Vec<GossipMessage>> {
+        self.handle_message(msg_type, data).map(|_| Vec::new())
+    }
 
-    fn get_state(&mut self) -> Vec<u8>;
+    fn get_state(&mut self) -> Bytes;
+
+    // checksum of the replicated values, compared to the ones gossiped by the members
+    fn state_checksum(&mut self) -> Checksum;
 }
 
+// Encodes in the version this node sends, see envelope, also while a received item of another version is handled
+#[tracing::instrument(skip_all)]
 pub fn craft_broadcast(tag: Tag, item: GossipMessage) -> Broadcast {
     let mut writer = BytesMut::new().writer();
     let opts = bincode::DefaultOptions::new();
-    opts.serialize_into(&mut writer, &tag).expect("error handling");
-    opts.serialize_into(&mut writer, &item).expect("error handling");
+    envelope::with_wire_version(envelope::send_version(), || {
+        opts.serialize_into(&mut writer, &tag).expect("error handling");
+        opts.serialize_into(&mut writer, &item).expect("error handling");
+    });
     Broadcast {
         tag,
         data: writer.into_inner().freeze()
     }
 }
 
+// size of the broadcast craft_broadcast would produce, without crafting it
+pub fn broadcast_size(tag: &Tag, item: &GossipMessage) -> usize {
+    let opts = bincode::DefaultOptions::new();
+    let size = envelope::with_wire_version(envelope::send_version(), || opts.serialized_size(tag).expect("error handling")
+        + opts.serialized_size(item).expect("error handling"));
+    size as usize
+}
+
+// A received item to pass on, as received if it is encoded in the version this node sends
+fn relayed(tag: Tag, msg: GossipMessage, item: Bytes) -> Broadcast {
+    if envelope::wire_version() == envelope::send_version() {
+        Broadcast { tag, data: item }
+    } else {
+        craft_broadcast(tag, msg)
+    }
+}
+
 impl Handler {
     pub fn new(
-        seen_op_ids: HashSet<Uuid>,
+        seen_op_ids: Arc<Mutex<SeenOperations>>,
         data_handler: Box<Arc<Mutex<dyn DataHandler + Send + Sync>>>,) -> Self {
         Self {
             seen_op_ids,
             data_handler,
+            observer: None,
+            startup_replies: StartupReplies::new(DEFAULT_STARTUP_REPLY_WINDOW),
+            pending_acks: PendingAcks::default(),
+            member_labels: MemberLabels::default(),
+            checksums: None,
+            message_handlers: HashMap::new(),
+            merge_queue: None,
+            hop_tracing: None,
+            decode_limit: DEFAULT_DECODE_LIMIT,
+            inbound: None,
+            latencies: None,
         }
     }
 
-    pub fn craft_broadcast(&mut self, tag: Tag, item: GossipMessage) -> Broadcast {
-        let mut writer = BytesMut::new().writer();
-        let opts = bincode::DefaultOptions::new();
-        opts.serialize_into(&mut writer, &tag).expect("error handling");
-        opts.serialize_into(&mut writer, &item).expect("error handling");
-        Broadcast {
-            tag,
-            data: writer.into_inner().freeze()
+    // Received broadcasts of the custom message type kind are handed to handler instead of the data handler
+    // and passed on like state broadcasts. Broadcasts of custom types without a handler are dropped.
+    pub fn register(&mut self, kind: MessageKind, handler: Box<dyn DataHandler + Send>) -> anyhow::Result<()> {
+        if kind < FIRST_CUSTOM_KIND {
+            anyhow::bail!("message kind {} is reserved for built-in message types, custom ones start at {}", kind, FIRST_CUSTOM_KIND);
+        }
+        if self.message_handlers.insert(kind, handler).is_some() {
+            anyhow::bail!("a handler for message kind {} is already registered", kind);
+        }
+        Ok(())
+    }
+
+    // labels of the members as received with their NodeConfig broadcasts
+    pub fn member_labels(&self) -> MemberLabels {
+        self.member_labels.clone()
+    }
+
+    // foca owns the handler, so the acks are sent by whoever drains this after handing data to foca
+    pub fn pending_acks(&self) -> PendingAcks {
+        self.pending_acks.clone()
+    }
+
+    // compares the checksums gossiped by the members to the local one
+    pub fn set_checksum_tracker(&mut self, checksums: ChecksumTracker) {
+        self.checksums = Some(checksums);
+    }
+
+    // items declaring larger lengths are dropped, see decode_limit
+    pub fn set_decode_limit(&mut self, limit: u64) {
+        self.decode_limit = limit;
+    }
+
+    // The items of received datagrams take from the broadcast budget of the sender foca handles them for,
+    // see InboundLimiter::set_source
+    pub fn set_inbound_limiter(&mut self, inbound: InboundLimiter) {
+        self.inbound = Some(inbound);
+    }
+
+    pub fn set_apply_latencies(&mut self, latencies: ApplyLatencies) {
+        self.latencies = Some(latencies);
+    }
+
+    fn record_latency(&self, origin: Option<OperationOrigin>) {
+        if let (Some(latencies), Some(origin)) = (&self.latencies, origin) {
+            latencies.record(origin, WireTimestamp::now(), Instant::now());
+        }
+    }
+
+    // minimum time between two full state replies to StartupMessages of the same node
+    pub fn set_startup_reply_window(&mut self, window: Duration) {
+        self.startup_replies.set_window(window);
+    }
+
+    // hands the changes of received broadcasts to the worker consuming queue instead of merging them here
+    pub fn set_merge_queue(&mut self, queue: MergeQueue) {
+        self.merge_queue = Some(queue);
+    }
+
+    // Relayed TracedOperations get node appended to their trail. Without hop tracing they are still applied
+    // and passed on, but their trails are neither extended nor logged.
+    pub fn set_hop_tracing(&mut self, node: SocketAddr, tracing: HopTracing) {
+        self.hop_tracing = Some((node, tracing));
+    }
+
+    // gets called with the outcome of every received SyncOperation that is not queued, e.g. to report replayed traffic
+    pub fn set_observer(&mut self, observer: ReceiveObserver) {
+        self.observer = Some(observer);
+    }
+
+    fn observe(&mut self, tag: &Tag, outcome: ReceiveOutcome) {
+        if let Some(observer) = self.observer.as_mut() {
+            observer(tag, &outcome);
         }
     }
+
+    pub fn craft_broadcast(&mut self, tag: Tag, item: GossipMessage) -> Broadcast {
+        craft_broadcast(tag, item)
+    }
+}
+
+// Handles a tag and GossipMessage that were sent to this node only. The operation id is remembered
+// so that the same operation arriving via gossip later is not applied twice, but direct messages
+// are never passed on to other members. Returns the messages to send back to the sender.
+pub fn receive_direct(
+    seen_op_ids: &Mutex<SeenOperations>,
+    data_handler: &Mutex<dyn DataHandler + Send + Sync>,
+    data: &Bytes) -> Result<(ReceiveOutcome, Vec<GossipMessage>), BroadcastError> {
+    let (tag, msg) = decode_item(data, DEFAULT_DECODE_LIMIT)?;
+    match tag {
+        Tag::SyncOperation {
+            operation_id
+        } => {
+            if let MessageType::Ack = msg.message_type {
+                let acked = Uuid::from_slice(&msg.message_payload).map_err(|e| BroadcastError::Invalid(format!("invalid ack: {}", e)))?;
+                return Ok((ReceiveOutcome::Acknowledged(acked), Vec::new()));
+            }
+            if let MessageType::State = msg.message_type {
+                if msg.message_payload.len() < 16 {
+                    return Err(BroadcastError::Invalid(format!("invalid state of {} bytes", msg.message_payload.len())));
+                }
+                let request_id = Uuid::from_slice(&msg.message_payload[..16]).expect("16 bytes are a uuid");
+                return Ok((ReceiveOutcome::State(request_id, msg.message_payload.slice(16..)), Vec::new()));
+            }
+            match msg.message_type {
+                MessageType::IdentityChallenge => return Ok((ReceiveOutcome::IdentityChallenge { nonce: msg.message_payload }, Vec::new())),
+                MessageType::IdentityProof => return Ok((ReceiveOutcome::IdentityProof { proof: msg.message_payload }, Vec::new())),
+                _ => {},
+            }
+            if !seen_op_ids.lock().unwrap().insert(operation_id) {
+                info!("Got already seen direct message with id {}", &operation_id);
+                return Ok((ReceiveOutcome::Seen, Vec::new()));
+            }
+            info!("Got direct message of type {:?} with id {}", msg.message_type, &operation_id);
+            let _span = msg.apply_span(&operation_id).entered();
+            Ok(match data_handler.lock().unwrap().handle_direct(msg.message_type, msg.message_payload) {
+                Ok(replies) => (ReceiveOutcome::Applied, replies),
+                Err(e) => (ReceiveOutcome::Failed(format!("{:#}", e)), Vec::new()),
+            })
+        },
+        Tag::Leave {
+            node
+        } => Ok((ReceiveOutcome::Leaving(node), Vec::new())),
+        other => Err(BroadcastError::Unexpected(format!("unexpected direct message with tag {:?}", other))),
+    }
 }
 
-impl<T> BroadcastHandler<T> for Handler {
+impl BroadcastHandler<ID> for Handler {
     type Broadcast = Broadcast;
-    type Error = String;
+    type Error = BroadcastError;
 
+    #[tracing::instrument(skip_all)]
     fn receive_item(
         &mut self,
-        data: impl bytes::Buf,
-        _sender: Option<&T>,
+        mut data: impl bytes::Buf,
+        sender: Option<&ID>,
     ) -> Result<Option<Self::Broadcast>, Self::Error> {
         info!("Receiving item ...");
-        let opts = bincode::DefaultOptions::new();
-        let mut reader = data.reader();
-
-        let tag: Tag = opts.deserialize_from(&mut reader).unwrap();
+        let _timer = metrics::RECEIVE_ITEM_SECONDS.start_timer();
+        // foca hands over contiguous buffers, so the first chunk holds all remaining items
+        let (_, _, item_len) = decode_borrowed(data.chunk(), self.decode_limit)?;
+        // the only copy of the item, the payload handed to the data handler and the relayed broadcast share it
+        let item = data.copy_to_bytes(item_len);
+        // checked before decoding, dropped items are not seen yet so that relays of other members still count
+        if sender.is_some() && self.inbound.as_ref().is_some_and(|inbound| !inbound.admit_from_source(Budget::Broadcast, Instant::now())) {
+            return Ok(None);
+        }
+        let (tag, msg) = decode_item(&item, self.decode_limit)?;
 
         match tag {
             Tag::SyncOperation {
                 operation_id
+            } | Tag::AckedOperation {
+                operation_id,
+                ..
+            } | Tag::TracedOperation {
+                operation_id,
+                ..
             } => {
-                if self.seen_op_ids.contains(&operation_id) {
+                // the broadcasts this node added reach it right away, their latency is the loopback baseline
+                if sender.is_none() {
+                    self.record_latency(msg.origin);
+                }
+                if self.seen_op_ids.lock().unwrap().contains(&operation_id) {
                     info!("Got already seen broadcast with id {}", &operation_id);
-                    // necessary to advance the reader cursor and not start reading a new broadcast from this partially read one
-                    // at the next invocation of receive_item
-                    let _msg: GossipMessage = opts.deserialize_from(&mut reader).expect("error handling");
                     // We've seen this data before, nothing to do
+                    self.observe(&tag, ReceiveOutcome::Seen);
                     return Ok(None);
                 }
                 info!("Got new broadcast with id {}", &operation_id);
-                self.seen_op_ids.insert(operation_id);
-
-                let msg: GossipMessage = opts.deserialize_from(&mut reader).expect("error handling");
+                self.seen_op_ids.lock().unwrap().insert(operation_id);
 
-                // let op: Operation = opts.deserialize_from(&mut reader).expect("error handling");
-                {
-                    // This is where foca stops caring
-                    // If it were me, I'd stuff the bytes as-is into a channel
-                    // and have a separate task/thread consuming it.
-                    self.data_handler.lock().unwrap().handle_message(msg.message_type, msg.message_payload.clone());
+                if let (Some(queue), MessageType::FullSync | MessageType::IncSync) = (&self.merge_queue, msg.message_type) {
+                    // This is where foca stops caring, the merge worker applies the changes, see merge_queue.
+                    // Broadcasts of this node come without a sender and only hold changes of its own state.
+                    if let Some(sender) = sender {
+                        queue.push(QueuedMerge {
+                            operation_id,
+                            message_type: msg.message_type,
+                            payload: msg.message_payload.clone(),
+                            span: msg.apply_span(&operation_id),
+                            source: Some(match tag {
+                                Tag::AckedOperation { origin, .. } => Source::Origin(origin),
+                                _ => Source::Relay(sender.addr),
+                            }),
+                            acks: match tag {
+                                Tag::AckedOperation { origin, .. } => vec![(origin, operation_id)],
+                                _ => Vec::new(),
+                            },
+                            origins: msg.origin.into_iter().collect(),
+                            enqueued: Instant::now(),
+                        });
+                    }
+                } else {
+                    let _span = msg.apply_span(&operation_id).entered();
+                    let result = match msg.message_type {
+                        // foca hands broadcasts added by this node to receive_item without a sender, custom ones
+                        // are only passed on, see HolyDiverController::broadcast_custom
+                        MessageType::Custom(_) if sender.is_none() => Ok(()),
+                        MessageType::Custom(kind) => match self.message_handlers.get_mut(&kind) {
+                            Some(handler) => handler.handle_message(msg.message_type, msg.message_payload.clone()),
+                            None => {
+                                warn!("Dropping broadcast with id {} of message kind {} without a registered handler", &operation_id, kind);
+                                metrics::UNKNOWN_MESSAGES_DROPPED.inc();
+                                return Ok(None);
+                            },
+                        },
+                        _ => self.data_handler.lock().unwrap().handle_message(msg.message_type, msg.message_payload.clone()),
+                    };
                     // self.data_handler.handle_message(msg.message_type, msg.message_payload.clone());
+                    let outcome = match result {
+                        Ok(_) => {
+                            if let Tag::AckedOperation { origin, .. } = tag {
+                                self.pending_acks.lock().unwrap().push((origin, operation_id));
+                            }
+                            if sender.is_some() {
+                                self.record_latency(msg.origin);
+                            }
+                            ReceiveOutcome::Applied
+                        },
+                        Err(e) => {
+                            log_throttle::report(ErrorCategory::MergeFailure, Level::Error,
+                                format_args!("Could not handle broadcast with id {}: {:#}", &operation_id, e));
+                            ReceiveOutcome::Failed(format!("{:#}", e))
+                        },
+                    };
+                    self.observe(&tag, outcome);
+                }
+
+                if let (Tag::TracedOperation { trail, .. }, Some(sender), Some((node, tracing))) = (tag, sender, &self.hop_tracing) {
+                    let trail = trail.relayed_by(*node);
+                    if tracing.logs(&operation_id) {
+                        let relays: Vec<String> = trail.relays().map(|relay| relay.to_string()).collect();
+                        info!("Trail of operation {} from {}: {} hop(s) via {} (last sent by {})",
+                            &operation_id, trail.origin, trail.hops, relays.join(" -> "), sender.addr);
+                    }
+                    debug!("Relaying traced broadcast with id {} after {} hop(s)", &operation_id, trail.hops);
+                    return Ok(Some(craft_broadcast(Tag::TracedOperation { operation_id, trail }, msg)));
                 }
 
-                // This WAS new information, so we signal it to foca
-                debug!("Crafting broadcast with msg {:?}", msg);
-                let broadcast = self.craft_broadcast(tag, msg);
-                Ok(Some(broadcast))
+                // This WAS new information, so we signal it to foca.
+                // The item is passed on as received instead of crafting it again, see relayed
+                debug!("Relaying broadcast with id {}", &operation_id);
+                Ok(Some(relayed(tag, msg, item)))
             },
             Tag::StartupMessage {
-                startup_time: _,
-                node_id: _,
+                startup_time,
+                node_id,
+            } => {
+                match self.startup_replies.check(node_id, startup_time, Instant::now()) {
+                    StartupReply::Reply => {
+                        info!("Sending full state to node {} started at {}", node_id, startup_time);
+                        let current_state = self.data_handler.lock().unwrap().get_state();
+                        let broadcast = self.craft_broadcast(Tag::SyncOperation {
+                            operation_id: Uuid::new_v4()
+                        }, GossipMessage::new(MessageType::FullSync, current_state));
+                        Ok(Some(broadcast))
+                    },
+                    StartupReply::Duplicate => {
+                        debug!("Got already seen startup of node {} at {}", node_id, startup_time);
+                        Ok(None)
+                    },
+                    StartupReply::RateLimited => {
+                        info!("Not sending full state to node {} started at {}, it was answered recently", node_id, startup_time);
+                        metrics::STARTUP_REPLIES_SUPPRESSED.inc();
+                        Ok(None)
+                    },
+                }
+            },
+            Tag::NodeConfig {
+                node,
+                version,
+            } => {
+                CLOCK.observe(version);
+                let labels: NodeLabels = decode_options(self.decode_limit).deserialize(&msg.message_payload)
+                    .map_err(|e| BroadcastError::decoding("node labels", self.decode_limit, e))?;
+                if !self.member_labels.update(node, version, labels) {
+                    return Ok(None);
+                }
+                debug!("Got new labels of {}", node);
+                if envelope::send_version() < NODE_LABELS_VERSION {
+                    return Ok(None);
+                }
+                Ok(Some(relayed(tag, msg, item)))
+            },
+            Tag::StateChecksum {
+                node,
+                version,
             } => {
-                //TODO check if node_id and startup_time combo was already seen and if not send full state update message
-                let current_state = self.data_handler.lock().unwrap().get_state();
-                let broadcast = self.craft_broadcast(Tag::SyncOperation {
-                    operation_id: Uuid::new_v4()
-                }, GossipMessage::new(MessageType::FullSync, current_state));
-                Ok(Some(broadcast))
+                CLOCK.observe(version);
+                let checksum: Checksum = msg.message_payload.as_ref().try_into()
+                    .map_err(|_| BroadcastError::Invalid(format!("invalid checksum of {} bytes", msg.message_payload.len())))?;
+                let Some(checksums) = &self.checksums else {
+                    return Ok(None);
+                };
+                let local = self.data_handler.lock().unwrap().state_checksum();
+                if !checksums.record(node, version, checksum, local) {
+                    return Ok(None);
+                }
+                debug!("Got new checksum of {}", node);
+                Ok(Some(relayed(tag, msg, item)))
             },
-          _ => Ok(None)
+            Tag::Leave {
+                node
+            } => Err(BroadcastError::Unexpected(format!("unexpected broadcast of the leave of {}, leaves are only sent directly", node))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TRACEPARENT: &str = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01";
+
+    fn sync_operation() -> Tag {
+        Tag::SyncOperation { operation_id: Uuid::new_v4() }
+    }
+
+    #[test]
+    fn the_trace_context_travels_with_the_message() {
+        let message = GossipMessage {
+            trace_context: Some(TRACEPARENT.to_owned()),
+            ..GossipMessage::new(MessageType::IncSync, &b"changes"[..])
+        };
+        let broadcast = craft_broadcast(sync_operation(), message);
+        let (_, received) = decode_item(&broadcast.data, DEFAULT_DECODE_LIMIT).unwrap();
+        assert_eq!(received.trace_context.as_deref(), Some(TRACEPARENT));
+        assert_eq!(received.message_payload(), &b"changes"[..]);
+        // the span applying it continues the trace of the sender, which is only visible with otlp
+        let _span = received.apply_span(&Uuid::new_v4());
+    }
+
+    #[test]
+    fn an_absent_trace_context_takes_a_single_byte() {
+        let tag = sync_operation();
+        let without = GossipMessage { trace_context: None, ..GossipMessage::new(MessageType::IncSync, &b"changes"[..]) };
+        let with = GossipMessage { trace_context: Some(TRACEPARENT.to_owned()), ..without.clone() };
+        // the length of the string and the string, Some takes a byte like None
+        assert_eq!(broadcast_size(&tag, &with) - broadcast_size(&tag, &without), 1 + TRACEPARENT.len());
+        let size = broadcast_size(&tag, &without);
+        let broadcast = craft_broadcast(tag, without);
+        assert_eq!(broadcast.data.len(), size);
+        let (_, received) = decode_item(&broadcast.data, DEFAULT_DECODE_LIMIT).unwrap();
+        assert_eq!(received.trace_context, None);
+    }
+
+    fn encode_item(version: u8, tag: Tag, message: &GossipMessage) -> Bytes {
+        let mut writer = BytesMut::new().writer();
+        let opts = bincode::DefaultOptions::new();
+        envelope::with_wire_version(version, || {
+            opts.serialize_into(&mut writer, &tag).unwrap();
+            opts.serialize_into(&mut writer, message).unwrap();
+        });
+        writer.into_inner().freeze()
+    }
+
+    #[test]
+    fn older_versions_leave_the_trace_context_out() {
+        let message = GossipMessage {
+            trace_context: Some(TRACEPARENT.to_owned()),
+            ..GossipMessage::new(MessageType::IncSync, &b"changes"[..])
+        };
+        let encode = |version| envelope::with_wire_version(version, || bincode::DefaultOptions::new().serialize(&message).unwrap());
+        // the code of the type and the payload with its length
+        let mut golden = vec![1, 7];
+        golden.extend_from_slice(b"changes");
+        let tag = sync_operation();
+        for version in 1..=3 {
+            assert_eq!(encode(version), golden, "version {}", version);
+            let item = encode_item(version, tag, &message);
+            let (_, received) = envelope::with_wire_version(version, || decode_item(&item, DEFAULT_DECODE_LIMIT)).unwrap();
+            assert_eq!(received.trace_context, None);
+            assert_eq!(encode_item(version, tag, &received), item, "version {}", version);
+        }
+        // version 4 adds the absent origin
+        golden.push(0);
+        assert_eq!(encode(4), golden);
+        // and version 5 the trace context before it
+        golden.pop();
+        golden.extend_from_slice(&[1, TRACEPARENT.len() as u8]);
+        golden.extend_from_slice(TRACEPARENT.as_bytes());
+        golden.push(0);
+        assert_eq!(encode(5), golden);
+    }
+
+    // keeps the payloads it is handed
+    struct Recorder(Arc<Mutex<Vec<Bytes>>>);
+
+    impl DataHandler for Recorder {
+        fn handle_message(&mut self, _msg_type: MessageType, data: Bytes) -> anyhow::Result<()> {
+            self.0.lock().unwrap().push(data);
+            Ok(())
+        }
+
+        fn get_state(&mut self) -> Bytes {
+            Bytes::new()
+        }
+
+        fn state_checksum(&mut self) -> Checksum {
+            [0; 16]
+        }
+    }
+
+    fn within(part: &[u8], whole: &[u8]) -> bool {
+        let range = whole.as_ptr_range();
+        range.start <= part.as_ptr() && part.as_ptr_range().end <= range.end
+    }
 
+    #[test]
+    fn a_received_payload_is_copied_once_and_relayed_as_received() {
+        let payloads = Arc::new(Mutex::new(Vec::new()));
+        let data_handler: Arc<Mutex<dyn DataHandler + Send + Sync>> = Arc::new(Mutex::new(Recorder(payloads.clone())));
+        let mut handler = Handler::new(Arc::new(Mutex::new(SeenOperations::new(Duration::from_secs(60), 100))), Box::new(data_handler));
+        handler.set_decode_limit(4 << 20);
+        let payload = vec![7u8; 1 << 20];
+        let sent = craft_broadcast(sync_operation(), GossipMessage::new(MessageType::FullSync, payload.clone()));
+        let sender = ID::new(SocketAddr::from(([127, 0, 0, 1], 9002)));
+
+        let relayed = handler.receive_item(&sent.data[..], Some(&sender)).unwrap().unwrap();
+        assert_eq!(relayed.data, sent.data);
+        let payloads = payloads.lock().unwrap();
+        assert_eq!(payloads.len(), 1);
+        assert_eq!(payloads[0], payload);
+        // the data handler got a view of the relayed item, not a copy of its own
+        assert!(within(&payloads[0], &relayed.data));
+    }
+
+    #[test]
+    fn a_decoded_payload_is_a_slice_of_the_item() {
+        let sent = craft_broadcast(sync_operation(), GossipMessage::new(MessageType::IncSync, vec![1u8; 4096]));
+        let (_, received) = decode_item(&sent.data, DEFAULT_DECODE_LIMIT).unwrap();
+        assert!(within(received.message_payload(), &sent.data));
+        assert!(within(&received.message_payload().clone(), &sent.data));
+    }
+
+    #[test]
+    fn custom_broadcasts_reach_their_handler_and_are_dropped_without_one() {
+        let applied = Arc::new(Mutex::new(Vec::new()));
+        let data_handler: Arc<Mutex<dyn DataHandler + Send + Sync>> = Arc::new(Mutex::new(Recorder(applied.clone())));
+        let mut handler = Handler::new(Arc::new(Mutex::new(SeenOperations::new(Duration::from_secs(60), 100))), Box::new(data_handler));
+        let custom = Arc::new(Mutex::new(Vec::new()));
+        handler.register(FIRST_CUSTOM_KIND + 1, Box::new(Recorder(custom.clone()))).unwrap();
+        assert!(handler.register(FIRST_CUSTOM_KIND + 1, Box::new(Recorder(custom.clone()))).is_err());
+        assert!(handler.register(FIRST_CUSTOM_KIND - 1, Box::new(Recorder(custom.clone()))).is_err());
+        let sender = ID::new(SocketAddr::from(([127, 0, 0, 1], 9002)));
+
+        let sent = craft_broadcast(sync_operation(), GossipMessage::new(MessageType::Custom(FIRST_CUSTOM_KIND + 1), b"hello".to_vec()));
+        let relayed = handler.receive_item(&sent.data[..], Some(&sender)).unwrap();
+        assert_eq!(relayed.unwrap().data, sent.data);
+        assert_eq!(*custom.lock().unwrap(), vec![Bytes::from_static(b"hello")]);
+
+        // added by this node, only passed on
+        let own = craft_broadcast(sync_operation(), GossipMessage::new(MessageType::Custom(FIRST_CUSTOM_KIND + 1), b"own".to_vec()));
+        assert!(handler.receive_item(&own.data[..], None).unwrap().is_some());
+        assert_eq!(custom.lock().unwrap().len(), 1);
+
+        let unknown = craft_broadcast(sync_operation(), GossipMessage::new(MessageType::Custom(FIRST_CUSTOM_KIND + 2), b"hello".to_vec()));
+        assert!(handler.receive_item(&unknown.data[..], Some(&sender)).unwrap().is_none());
+        assert_eq!(custom.lock().unwrap().len(), 1);
+        assert!(applied.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn relays_extend_the_trail_of_traced_broadcasts() {
+        let addr = |port| SocketAddr::from(([127, 0, 0, 1], port));
+        let relay = |node| {
+            let seen = Arc::new(Mutex::new(SeenOperations::new(Duration::from_secs(60), 100)));
+            let data_handler: Arc<Mutex<dyn DataHandler + Send + Sync>> = Arc::new(Mutex::new(Recorder(Arc::default())));
+            let mut handler = Handler::new(seen.clone(), Box::new(data_handler));
+            handler.set_hop_tracing(node, HopTracing::default());
+            (handler, seen)
+        };
+        let operation_id = Uuid::new_v4();
+        let sent = craft_broadcast(Tag::TracedOperation { operation_id, trail: HopTrail::new(addr(9001)) },
+            GossipMessage::new(MessageType::IncSync, b"changes".to_vec()));
+
+        let (mut first, first_seen) = relay(addr(9002));
+        let relayed = first.receive_item(&sent.data[..], Some(&ID::new(addr(9001)))).unwrap().unwrap();
+        assert!(first_seen.lock().unwrap().seen_at(&operation_id).is_some());
+        let (mut second, _) = relay(addr(9003));
+        let relayed = second.receive_item(&relayed.data[..], Some(&ID::new(addr(9002)))).unwrap().unwrap();
+
+        match decode_item(&relayed.data, DEFAULT_DECODE_LIMIT).unwrap() {
+            (Tag::TracedOperation { operation_id: relayed_id, trail }, message) => {
+                assert_eq!(relayed_id, operation_id);
+                assert_eq!(trail.origin, addr(9001));
+                assert_eq!(trail.hops, 2);
+                assert_eq!(trail.relays().collect::<Vec<_>>(), vec![addr(9002), addr(9003)]);
+                assert_eq!(&message.message_payload[..], b"changes");
+            },
+            (tag, _) => panic!("expected a traced operation, got {:?}", tag),
         }
     }
 }
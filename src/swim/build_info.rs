@@ -0,0 +1,61 @@
+use std::collections::BTreeMap;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+
+use super::{broadcast::GOSSIP_MESSAGE_VERSION, envelope::ENVELOPE_VERSION};
+
+// Build metadata embedded by build.rs, reported by GET /version, logged on startup and gossiped to the
+// other members as labels of the NodeConfig broadcast so that /members shows what each member runs.
+
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+pub const GIT_COMMIT: &str = env!("HOLYDIVER_GIT_COMMIT");
+pub const AUTOMERGE_VERSION: &str = env!("HOLYDIVER_AUTOMERGE_VERSION");
+// unix seconds
+const BUILD_TIMESTAMP: &str = env!("HOLYDIVER_BUILD_TIMESTAMP");
+
+/// What this node was built from.
+#[derive(Debug, Clone, Serialize)]
+#[cfg_attr(feature = "rest", derive(utoipa::ToSchema))]
+pub struct BuildInfo {
+    pub version: &'static str,
+    // "unknown" when built outside of a git checkout
+    pub git_commit: &'static str,
+    #[cfg_attr(feature = "rest", schema(value_type = String, example = "2024-05-01T12:00:00Z"))]
+    pub build_timestamp: Option<DateTime<Utc>>,
+    // version of the envelope of all datagrams, nodes only talk to nodes with the same one
+    pub protocol_version: u8,
+    // version of the encoding of gossiped messages, see broadcast
+    pub gossip_message_version: u8,
+    pub automerge_version: &'static str,
+}
+
+pub fn build_info() -> BuildInfo {
+    BuildInfo {
+        version: VERSION,
+        git_commit: GIT_COMMIT,
+        build_timestamp: BUILD_TIMESTAMP.parse().ok().and_then(|secs| DateTime::from_timestamp(secs, 0)),
+        protocol_version: ENVELOPE_VERSION,
+        gossip_message_version: GOSSIP_MESSAGE_VERSION,
+        automerge_version: AUTOMERGE_VERSION,
+    }
+}
+
+// the labels this node gossips about itself
+pub fn node_labels() -> BTreeMap<String, String> {
+    BTreeMap::from([
+        ("version".to_owned(), VERSION.to_owned()),
+        ("git_commit".to_owned(), GIT_COMMIT.to_owned()),
+        ("protocol_version".to_owned(), ENVELOPE_VERSION.to_string()),
+        ("gossip_message_version".to_owned(), GOSSIP_MESSAGE_VERSION.to_string()),
+    ])
+}
+
+impl std::fmt::Display for BuildInfo {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "holy-diver {} ({}", self.version, self.git_commit)?;
+        if let Some(build_timestamp) = self.build_timestamp {
+            write!(f, ", built {}", build_timestamp.to_rfc3339())?;
+        }
+        write!(f, "), protocol version {}, automerge {}", self.protocol_version, self.automerge_version)
+    }
+}
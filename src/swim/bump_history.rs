@@ -0,0 +1,30 @@
+use anyhow::Result;
+use log::warn;
+
+use super::store::StateStore;
+
+// The bumps the identity of this node used are remembered in <DATA_DIR>/identity_bumps.json whenever
+// foca renews it. A restarted node doesn't pick one of them again, see ID::with_renewals, since members
+// that still remember the identity from before the restart would refuse it.
+
+pub const BUMP_HISTORY_KEY: &str = "identity_bumps.json";
+
+// an unreadable file is treated as empty and replaced with the next renewal
+pub fn load_bump_history(store: &dyn StateStore) -> Vec<u16> {
+    match store.load(BUMP_HISTORY_KEY) {
+        Ok(Some(data)) => serde_json::from_slice(&data).unwrap_or_else(|e| {
+            warn!("Ignoring invalid identity bumps {}: {}", store.describe(BUMP_HISTORY_KEY), e);
+            Vec::new()
+        }),
+        Ok(None) => Vec::new(),
+        Err(e) => {
+            warn!("Could not read identity bumps: {:#}", e);
+            Vec::new()
+        },
+    }
+}
+
+pub fn save_bump_history(store: &dyn StateStore, bumps: &[u16]) -> Result<()> {
+    let data = serde_json::to_vec(bumps)?;
+    store.save(BUMP_HISTORY_KEY, &data)
+}
@@ -0,0 +1,243 @@
+use std::{
+    collections::BTreeMap, fs::{self, File}, io::{BufReader, BufWriter, Read, Write}, net::SocketAddr,
+    path::{Path, PathBuf}, sync::{Arc, Mutex}, time::Duration,
+};
+use anyhow::{bail, Context, Result};
+use bincode::Options;
+use foca::{Config, Foca};
+use log::{info, error};
+use rand::{rngs::StdRng, SeedableRng};
+use serde::{Deserialize, Serialize};
+
+use super::{
+    broadcast::{Handler, ReceiveOutcome, Tag, receive_direct}, codec::SwimCodec, core::{AccumulatingRuntime, HolyDiverDataHandler},
+    envelope::{self, IncomingFrame, Kind}, seen_ops::SeenOperations, types::ID,
+};
+
+// Layout of a capture file:
+//
+// 0. Magic bytes "HDCP"
+// 1. Format version (u8)
+// 2. u32 length + bincode encoded ID of the capturing node
+// 3. Records, each a u32 length + bincode encoded CaptureRecord
+//
+// All lengths are little endian.
+const MAGIC: &[u8; 4] = b"HDCP";
+// version 2 encodes the identity compactly, see ID
+const FORMAT_VERSION: u8 = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+pub enum Direction {
+    Inbound,
+    Outbound,
+}
+
+/// A datagram as it went over the wire, including the envelope.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct CaptureRecord {
+    pub direction: Direction,
+    // unix millis
+    pub timestamp: i64,
+    pub peer: SocketAddr,
+    pub data: Vec<u8>,
+}
+
+/// Appends datagrams to capture files in a directory, starting a new file once the current one
+/// exceeds `max_file_size` bytes.
+pub struct CaptureWriter {
+    dir: PathBuf,
+    identity: ID,
+    max_file_size: u64,
+    current: Option<(BufWriter<File>, u64)>,
+}
+
+impl CaptureWriter {
+    pub fn new(dir: PathBuf, identity: ID, max_file_size: u64) -> Result<Self> {
+        fs::create_dir_all(&dir)
+            .with_context(|| format!("could not create capture dir {}", dir.display()))?;
+        Ok(CaptureWriter {
+            dir,
+            identity,
+            max_file_size,
+            current: None,
+        })
+    }
+
+    pub fn shared(self) -> Arc<Mutex<CaptureWriter>> {
+        Arc::new(Mutex::new(self))
+    }
+
+    // capturing is best effort, failures are logged and never affect the traffic itself
+    pub fn record(&mut self, direction: Direction, peer: SocketAddr, data: &[u8]) {
+        let record = CaptureRecord {
+            direction,
+            timestamp: chrono::Utc::now().timestamp_millis(),
+            peer,
+            data: data.to_vec(),
+        };
+        if let Err(e) = self.append(&record) {
+            error!("Could not capture datagram: {:#}", e);
+            // starting over with a new file on the next record
+            self.current = None;
+        }
+    }
+
+    fn append(&mut self, record: &CaptureRecord) -> Result<()> {
+        if self.current.as_ref().is_none_or(|(_, size)| *size >= self.max_file_size) {
+            self.current = Some(self.create_file()?);
+        }
+        let (file, size) = self.current.as_mut().expect("a capture file was just created");
+        *size += write_length_prefixed(file, record)?;
+        file.flush()?;
+        Ok(())
+    }
+
+    fn create_file(&self) -> Result<(BufWriter<File>, u64)> {
+        let name = format!("capture-{}.bin", chrono::Utc::now().format("%Y%m%dT%H%M%S%.3fZ"));
+        let path = self.dir.join(name);
+        let mut file = BufWriter::new(File::create(&path)
+            .with_context(|| format!("could not create capture file {}", path.display()))?);
+        file.write_all(MAGIC)?;
+        file.write_all(&[FORMAT_VERSION])?;
+        let size = MAGIC.len() as u64 + 1 + write_length_prefixed(&mut file, &self.identity)?;
+        info!("Capturing gossip traffic to {}", path.display());
+        Ok((file, size))
+    }
+}
+
+fn write_length_prefixed<T: Serialize, W: Write>(writer: &mut W, value: &T) -> Result<u64> {
+    let bytes = bincode::DefaultOptions::new().serialize(value)?;
+    writer.write_all(&(bytes.len() as u32).to_le_bytes())?;
+    writer.write_all(&bytes)?;
+    Ok(4 + bytes.len() as u64)
+}
+
+// None at the end of the file
+fn read_length_prefixed<T: for<'de> Deserialize<'de>, R: Read>(reader: &mut R) -> Result<Option<T>> {
+    let mut length = [0u8; 4];
+    match reader.read_exact(&mut length) {
+        Ok(_) => {},
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e.into()),
+    }
+    let mut bytes = vec![0u8; u32::from_le_bytes(length) as usize];
+    reader.read_exact(&mut bytes).context("truncated record")?;
+    Ok(Some(bincode::DefaultOptions::new().deserialize(&bytes)?))
+}
+
+// Reads the identity of the capturing node and all records of a capture file
+pub fn read_capture(path: &Path) -> Result<(ID, Vec<CaptureRecord>)> {
+    let mut reader = BufReader::new(File::open(path)
+        .with_context(|| format!("could not open capture file {}", path.display()))?);
+    let mut header = [0u8; 5];
+    reader.read_exact(&mut header).context("not a capture file")?;
+    if &header[..MAGIC.len()] != MAGIC {
+        bail!("{} is not a capture file", path.display());
+    }
+    let identity: Option<ID> = match header[MAGIC.len()] {
+        // version 1 encoded the identity like the datagrams of envelope version 2
+        1 => envelope::with_wire_version(2, || read_length_prefixed(&mut reader))?,
+        FORMAT_VERSION => read_length_prefixed(&mut reader)?,
+        version => bail!("unsupported capture format version {}", version),
+    };
+    let identity = identity.context("capture file ends before the identity")?;
+    let mut records = Vec::new();
+    while let Some(record) = read_length_prefixed(&mut reader)? {
+        records.push(record);
+    }
+    Ok((identity, records))
+}
+
+/// What happened to one replayed inbound datagram.
+#[derive(Debug, Clone, Serialize)]
+pub struct ReplayDecision {
+    pub index: usize,
+    pub peer: SocketAddr,
+    pub tag: Option<Tag>,
+    pub outcome: ReceiveOutcome,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ReplayReport {
+    pub decisions: Vec<ReplayDecision>,
+    pub document: BTreeMap<String, String>,
+}
+
+// Feeds the inbound records of a capture file through a fresh foca, broadcast Handler and data handler
+// using data_dir for state. Nothing is sent, outbound records and everything foca wants to send are
+// ignored. The random generator is seeded so that replaying the same file gives the same result.
+// Needs to be called from within a tokio runtime since the data handler writes its state from a task.
+pub async fn replay(capture_file: &Path, data_dir: &Path, codec: SwimCodec) -> Result<ReplayReport> {
+    let (identity, records) = read_capture(capture_file)?;
+    fs::create_dir_all(data_dir)
+        .with_context(|| format!("could not create data dir {}", data_dir.display()))?;
+    let data_handler = Arc::new(Mutex::new(HolyDiverDataHandler::new(data_dir, identity.clone())));
+    let seen_ops = Arc::new(Mutex::new(SeenOperations::new(Duration::from_secs(u32::MAX as u64), usize::MAX)));
+    let decisions: Arc<Mutex<Vec<ReplayDecision>>> = Arc::new(Mutex::new(Vec::new()));
+    // index and peer of the record that is currently replayed, read by the observer
+    let current: Arc<Mutex<(usize, Option<SocketAddr>)>> = Arc::new(Mutex::new((0, None)));
+
+    let mut handler = Handler::new(seen_ops.clone(), Box::new(data_handler.clone()));
+    let observed_decisions = decisions.clone();
+    let observed_current = current.clone();
+    handler.set_observer(Box::new(move |tag, outcome| {
+        let (index, peer) = *observed_current.lock().unwrap();
+        if let Some(peer) = peer {
+            observed_decisions.lock().unwrap().push(ReplayDecision {
+                index,
+                peer,
+                tag: Some(*tag),
+                outcome: outcome.clone(),
+            });
+        }
+    }));
+    let mut foca = Foca::with_custom_broadcast(identity, Config::simple(), StdRng::seed_from_u64(0), codec, handler);
+    let mut runtime = AccumulatingRuntime::new();
+
+    for (index, record) in records.into_iter().enumerate().filter(|(_, r)| r.direction == Direction::Inbound) {
+        *current.lock().unwrap() = (index, Some(record.peer));
+        let failed = |message: String| ReplayDecision {
+            index,
+            peer: record.peer,
+            tag: None,
+            outcome: ReceiveOutcome::Failed(message),
+        };
+        match envelope::decode_datagram(&record.data) {
+            Ok(IncomingFrame { version, kind: Kind::Foca, payload: data }) => {
+                if let Err(e) = envelope::with_wire_version(version, || foca.handle_data(&data, &mut runtime)) {
+                    decisions.lock().unwrap().push(failed(e.to_string()));
+                }
+            },
+            Ok(IncomingFrame { version, kind: Kind::Direct, payload: data }) => {
+                let received = envelope::with_wire_version(version, || receive_direct(&seen_ops, &*data_handler, &data));
+                let decision = match received {
+                    // replies would go back to the original sender, which is not part of a replay
+                    Ok((outcome, _replies)) => ReplayDecision {
+                        index,
+                        peer: record.peer,
+                        tag: None,
+                        outcome,
+                    },
+                    Err(e) => failed(e.to_string()),
+                };
+                decisions.lock().unwrap().push(decision);
+            },
+            // latency probes don't change any state
+            Ok(IncomingFrame { kind: Kind::Ping | Kind::Pong, .. }) => {},
+            Err(e) => decisions.lock().unwrap().push(failed(e.to_string())),
+        }
+        // nothing is actually sent or scheduled during a replay
+        runtime.to_send.clear();
+        runtime.to_schedule.clear();
+        runtime.notifications.clear();
+    }
+
+    let flush_handle = data_handler.lock().unwrap().flush_handle();
+    flush_handle.wait().await;
+    let document = data_handler.lock().unwrap().get_all_fields()?;
+    let decisions = decisions.lock().unwrap().clone();
+    Ok(ReplayReport {
+        decisions,
+        document,
+    })
+}
@@ -0,0 +1,48 @@
+use std::collections::HashMap;
+use automerge::{ActorId, AutoCommit, ChangeHash};
+use log::warn;
+
+// The changes of the document by actor and op counter, so that the change holding an op, e.g. the current value
+// of a field, is found without scanning the history. Before a lookup the index is brought up to date with the
+// changes since its last update, which costs as much as the changes added in between.
+
+#[derive(Default)]
+pub struct ChangeIndex {
+    // heads of the document as of the last update
+    heads: Vec<ChangeHash>,
+    // the first and last op counter of the changes of each actor with their hash, by first op
+    changes: HashMap<ActorId, Vec<(u64, u64, ChangeHash)>>,
+}
+
+impl ChangeIndex {
+    pub fn update(&mut self, doc: &mut AutoCommit) {
+        let heads = doc.get_heads();
+        if heads == self.heads {
+            return;
+        }
+        let changes = match doc.get_changes(&self.heads) {
+            Ok(changes) => changes,
+            Err(e) => {
+                warn!("Rebuilding the change index: {}", e);
+                self.changes.clear();
+                doc.get_changes(&[]).unwrap_or_default()
+            },
+        };
+        for change in changes {
+            let entry = (change.start_op().get(), change.max_op(), change.hash());
+            let entries = self.changes.entry(change.actor_id().clone()).or_default();
+            if let Err(index) = entries.binary_search_by_key(&entry.0, |(start_op, _, _)| *start_op) {
+                entries.insert(index, entry);
+            }
+        }
+        self.heads = heads;
+    }
+
+    // the change of actor holding the op with counter, as of the last update
+    pub fn find(&self, actor: &ActorId, counter: u64) -> Option<ChangeHash> {
+        let entries = self.changes.get(actor)?;
+        let index = entries.partition_point(|(start_op, _, _)| *start_op <= counter).checked_sub(1)?;
+        let (start_op, max_op, hash) = entries[index];
+        (start_op <= counter && counter <= max_op).then_some(hash)
+    }
+}
@@ -0,0 +1,209 @@
+use std::{
+    collections::BTreeMap, net::SocketAddr, sync::{Arc, Mutex}, time::Duration,
+};
+use log::warn;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use serde::{Deserialize, Serialize};
+
+use super::metrics;
+
+// Network faults injected between foca and the UDP socket of a node started with --chaos, e.g. to watch the
+// anti-entropy features at work in staging. Every datagram this node sends or receives passes the injector:
+// partitions and the blackhole apply to both directions, drops and latency only to sent datagrams, so a lossy
+// link in both directions needs the config on both ends. The decisions are drawn from a random generator
+// seeded with the seed of the config, so the same seed and the same sequence of datagrams give the same faults.
+
+/// Faults to inject, changed with PUT /admin/chaos. The default injects none.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(feature = "rest", derive(utoipa::ToSchema))]
+#[serde(default, deny_unknown_fields)]
+pub struct ChaosConfig {
+    /// Probability between 0 and 1 that a datagram sent to any destination is dropped.
+    pub drop_probability: f64,
+    /// Drop probabilities of single destinations, replacing drop_probability for them.
+    #[cfg_attr(feature = "rest", schema(value_type = Object, example = json!({"127.0.0.1:9001": 0.5})))]
+    pub drop_by_destination: BTreeMap<SocketAddr, f64>,
+    /// Sent datagrams are delayed by a random time in this range.
+    pub latency: Option<LatencyRange>,
+    /// Groups of member addresses that can only reach the members of their own group. Addresses in none
+    /// of the groups reach everyone.
+    #[cfg_attr(feature = "rest", schema(value_type = Vec<Vec<String>>, example = json!([["127.0.0.1:9000"], ["127.0.0.1:9001", "127.0.0.1:9002"]])))]
+    pub partitions: Vec<Vec<SocketAddr>>,
+    /// Drops every datagram sent or received.
+    pub blackhole: bool,
+    /// Seed of the decisions, the random generator is reseeded whenever a config is set. The seed of
+    /// --chaos-seed if absent, random without one.
+    pub seed: Option<u64>,
+}
+
+/// Uniformly distributed delay in milliseconds.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "rest", derive(utoipa::ToSchema))]
+pub struct LatencyRange {
+    pub min_ms: u64,
+    pub max_ms: u64,
+}
+
+impl ChaosConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        let probabilities = std::iter::once(&self.drop_probability).chain(self.drop_by_destination.values());
+        if let Some(p) = probabilities.into_iter().find(|p| !(0.0..=1.0).contains(*p)) {
+            return Err(format!("drop probabilities have to be between 0 and 1, got {}", p));
+        }
+        if let Some(latency) = self.latency.filter(|latency| latency.min_ms > latency.max_ms) {
+            return Err(format!("the minimum latency of {}ms exceeds the maximum of {}ms", latency.min_ms, latency.max_ms));
+        }
+        let mut seen = std::collections::HashSet::new();
+        if let Some(addr) = self.partitions.iter().flatten().find(|addr| !seen.insert(**addr)) {
+            return Err(format!("{} is part of more than one partition", addr));
+        }
+        Ok(())
+    }
+
+    fn partition_of(&self, addr: &SocketAddr) -> Option<usize> {
+        self.partitions.iter().position(|group| group.contains(addr))
+    }
+
+    // whether the partitions separate a and b
+    fn separated(&self, a: &SocketAddr, b: &SocketAddr) -> bool {
+        matches!((self.partition_of(a), self.partition_of(b)), (Some(x), Some(y)) if x != y)
+    }
+}
+
+/// What happens to a datagram this node sends.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fate {
+    Drop,
+    // sent after the delay
+    Deliver(Duration),
+}
+
+pub struct FaultInjector {
+    // address of this node as used in the partitions
+    identity: SocketAddr,
+    // used for configs without a seed
+    default_seed: Option<u64>,
+    state: Mutex<(ChaosConfig, StdRng)>,
+}
+
+impl FaultInjector {
+    pub fn new(identity: SocketAddr, default_seed: Option<u64>) -> Self {
+        FaultInjector {
+            identity,
+            default_seed,
+            state: Mutex::new((ChaosConfig::default(), rng(default_seed))),
+        }
+    }
+
+    pub fn shared(identity: SocketAddr, default_seed: Option<u64>) -> Arc<Self> {
+        Arc::new(Self::new(identity, default_seed))
+    }
+
+    pub fn config(&self) -> ChaosConfig {
+        self.state.lock().unwrap().0.clone()
+    }
+
+    // replaces the config and reseeds the random generator
+    pub fn set(&self, config: ChaosConfig) -> Result<(), String> {
+        config.validate()?;
+        let rng = rng(config.seed.or(self.default_seed));
+        if config != ChaosConfig::default() {
+            warn!("Injecting network faults: {:?}", config);
+        }
+        *self.state.lock().unwrap() = (config, rng);
+        Ok(())
+    }
+
+    pub fn outbound(&self, destination: &SocketAddr) -> Fate {
+        let mut state = self.state.lock().unwrap();
+        let (config, rng) = &mut *state;
+        let drop_probability = config.drop_by_destination.get(destination).copied().unwrap_or(config.drop_probability);
+        // the random draws don't depend on the outcome of the checks before, so that the sequence stays the same
+        let dropped = rng.gen_bool(drop_probability);
+        let delay = config.latency.map_or(Duration::ZERO, |latency| Duration::from_millis(rng.gen_range(latency.min_ms..=latency.max_ms)));
+        if config.blackhole || config.separated(&self.identity, destination) || dropped {
+            metrics::CHAOS_DATAGRAMS_DROPPED.with_label_values(&["outbound"]).inc();
+            return Fate::Drop;
+        }
+        Fate::Deliver(delay)
+    }
+
+    // whether a datagram received from sender is dropped
+    pub fn drops_inbound(&self, sender: &SocketAddr) -> bool {
+        let state = self.state.lock().unwrap();
+        let dropped = state.0.blackhole || state.0.separated(&self.identity, sender);
+        if dropped {
+            metrics::CHAOS_DATAGRAMS_DROPPED.with_label_values(&["inbound"]).inc();
+        }
+        dropped
+    }
+}
+
+fn rng(seed: Option<u64>) -> StdRng {
+    seed.map_or_else(StdRng::from_entropy, StdRng::seed_from_u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], port))
+    }
+
+    fn fates(injector: &FaultInjector, datagrams: usize) -> Vec<Fate> {
+        (0..datagrams).map(|_| injector.outbound(&addr(9002))).collect()
+    }
+
+    #[test]
+    fn the_same_seed_gives_the_same_faults() {
+        let config = ChaosConfig {
+            drop_probability: 0.5,
+            latency: Some(LatencyRange { min_ms: 1, max_ms: 100 }),
+            seed: Some(42),
+            ..ChaosConfig::default()
+        };
+        let injector = FaultInjector::new(addr(9001), None);
+        injector.set(config.clone()).unwrap();
+        let first = fates(&injector, 100);
+        assert!(first.contains(&Fate::Drop));
+        assert!(first.iter().any(|fate| matches!(fate, Fate::Deliver(_))));
+
+        // setting the config again reseeds
+        injector.set(config.clone()).unwrap();
+        assert_eq!(fates(&injector, 100), first);
+        let other = FaultInjector::new(addr(9003), Some(7));
+        other.set(config).unwrap();
+        assert_eq!(fates(&other, 100), first);
+    }
+
+    #[test]
+    fn partitions_separate_both_directions() {
+        let injector = FaultInjector::new(addr(9001), Some(1));
+        injector.set(ChaosConfig {
+            partitions: vec![vec![addr(9001)], vec![addr(9002)]],
+            ..ChaosConfig::default()
+        }).unwrap();
+        assert_eq!(injector.outbound(&addr(9002)), Fate::Drop);
+        assert!(injector.drops_inbound(&addr(9002)));
+        // addresses in no partition reach everyone
+        assert_eq!(injector.outbound(&addr(9003)), Fate::Deliver(Duration::ZERO));
+        assert!(!injector.drops_inbound(&addr(9003)));
+
+        injector.set(ChaosConfig::default()).unwrap();
+        assert_eq!(injector.outbound(&addr(9002)), Fate::Deliver(Duration::ZERO));
+    }
+
+    #[test]
+    fn invalid_configs_are_refused() {
+        let injector = FaultInjector::new(addr(9001), None);
+        for config in [
+            ChaosConfig { drop_probability: 1.5, ..ChaosConfig::default() },
+            ChaosConfig { latency: Some(LatencyRange { min_ms: 10, max_ms: 5 }), ..ChaosConfig::default() },
+            ChaosConfig { partitions: vec![vec![addr(9001)], vec![addr(9001)]], ..ChaosConfig::default() },
+        ] {
+            assert!(injector.set(config).is_err());
+        }
+        assert_eq!(injector.config(), ChaosConfig::default());
+    }
+}
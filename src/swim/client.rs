@@ -0,0 +1,281 @@
+use std::time::Duration;
+
+use anyhow::anyhow;
+use bytes::Bytes;
+use futures_util::{Stream, StreamExt};
+use reqwest::{RequestBuilder, Response, Url};
+use serde::Deserialize;
+
+use super::{
+    core::{FieldMeta, FieldValues}, error::HolyDiverError, events::{FieldChange, MemberEvent},
+    server::MemberList,
+};
+
+// Typed async client for the REST API of a node. Errors returned by the node are mapped back into the
+// HolyDiverError it raised, failures to reach it become HolyDiverError::Internal.
+// Needs a tokio runtime, the CLI subcommands use the one of actix.
+
+// records of /events larger than this end the stream with an error
+const MAX_EVENT_SIZE: usize = 1024 * 1024;
+
+#[derive(Debug, Clone)]
+pub struct ClientConfig {
+    // REST endpoint of the node, e.g. http://127.0.0.1:9090
+    pub base_url: String,
+    // sent as bearer token with every request
+    pub token: Option<String>,
+    // per request, /events streams only time out while connecting
+    pub timeout: Duration,
+    // attempts after the first one for unreachable nodes, 429 and 503 responses
+    pub retries: u32,
+    // doubled after every failed attempt up to max_backoff
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+impl ClientConfig {
+    pub fn new(base_url: &str) -> Self {
+        ClientConfig {
+            base_url: base_url.to_owned(),
+            token: None,
+            timeout: Duration::from_secs(10),
+            retries: 3,
+            initial_backoff: Duration::from_millis(200),
+            max_backoff: Duration::from_secs(30),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EventType {
+    Field,
+    Member,
+}
+
+/// A record of the /events stream.
+#[derive(Debug, Clone)]
+pub enum Event {
+    Field(FieldChange),
+    Member(MemberEvent),
+    // the client fell behind and `count` events of `event_type` were dropped for it
+    LostEvents { event_type: String, count: u64 },
+}
+
+#[derive(Deserialize)]
+struct LostEvents {
+    #[serde(rename = "type")]
+    event_type: String,
+    count: u64,
+}
+
+#[derive(Deserialize)]
+struct KeyList {
+    keys: Vec<String>,
+}
+
+pub struct HolyDiverClient {
+    config: ClientConfig,
+    base_url: Url,
+    http: reqwest::Client,
+}
+
+fn internal<E: Into<anyhow::Error>>(error: E) -> HolyDiverError {
+    HolyDiverError::Internal(error.into())
+}
+
+// unreachable nodes are reported as Internal as well
+fn retryable(error: &HolyDiverError) -> bool {
+    matches!(error, HolyDiverError::BroadcastBackpressure | HolyDiverError::RateLimited | HolyDiverError::Internal(_))
+}
+
+async fn error_from_response(response: Response) -> HolyDiverError {
+    let status = response.status().as_u16();
+    match response.text().await {
+        Ok(body) => HolyDiverError::from_response(status, &body),
+        Err(e) => internal(e),
+    }
+}
+
+// Parses one record of a Server-Sent Events stream, None for comments and unknown events
+fn parse_record(record: &str) -> Option<Result<Event, HolyDiverError>> {
+    let mut event = "message";
+    let mut data = String::new();
+    for line in record.lines() {
+        if let Some(value) = line.strip_prefix("event:") {
+            event = value.trim();
+        } else if let Some(value) = line.strip_prefix("data:") {
+            if !data.is_empty() {
+                data.push('\n');
+            }
+            data.push_str(value.strip_prefix(' ').unwrap_or(value));
+        }
+    }
+    let parsed = match event {
+        "field" => serde_json::from_str(&data).map(Event::Field),
+        "member" => serde_json::from_str(&data).map(Event::Member),
+        "lost_events" => serde_json::from_str::<LostEvents>(&data)
+            .map(|lost| Event::LostEvents { event_type: lost.event_type, count: lost.count }),
+        _ => return None,
+    };
+    Some(parsed.map_err(internal))
+}
+
+impl HolyDiverClient {
+    pub fn new(config: ClientConfig) -> Result<Self, HolyDiverError> {
+        let base_url = Url::parse(&config.base_url)
+            .map_err(|e| internal(anyhow!("invalid base URL {}: {}", config.base_url, e)))?;
+        if base_url.cannot_be_a_base() {
+            return Err(internal(anyhow!("invalid base URL {}", config.base_url)));
+        }
+        // the timeout is set per request so that it does not cut off /events streams
+        let http = reqwest::Client::builder()
+            .connect_timeout(config.timeout)
+            .build()
+            .map_err(internal)?;
+        Ok(HolyDiverClient {
+            config,
+            base_url,
+            http,
+        })
+    }
+
+    // segments are percent-encoded, so keys may contain slashes and spaces
+    fn url(&self, segments: &[&str]) -> Url {
+        let mut url = self.base_url.clone();
+        url.path_segments_mut()
+            .expect("checked by new that the base URL has a path")
+            .pop_if_empty()
+            .extend(segments);
+        url
+    }
+
+    fn authorize(&self, request: RequestBuilder) -> RequestBuilder {
+        match &self.config.token {
+            Some(token) => request.bearer_auth(token),
+            None => request,
+        }
+    }
+
+    // Sends the request built by `request` until it succeeds or the retries are used up
+    async fn send<F: Fn() -> RequestBuilder>(&self, request: F) -> Result<Response, HolyDiverError> {
+        let mut backoff = self.config.initial_backoff;
+        let mut attempt = 0;
+        loop {
+            let error = match self.authorize(request()).send().await {
+                Ok(response) if response.status().is_success() => return Ok(response),
+                Ok(response) => error_from_response(response).await,
+                Err(e) if e.is_connect() || e.is_timeout() => internal(e),
+                Err(e) => return Err(internal(e)),
+            };
+            if !retryable(&error) || attempt >= self.config.retries {
+                return Err(error);
+            }
+            futures_timer::Delay::new(backoff).await;
+            backoff = (backoff * 2).min(self.config.max_backoff);
+            attempt += 1;
+        }
+    }
+
+    async fn json<T: for<'de> Deserialize<'de>>(response: Response) -> Result<T, HolyDiverError> {
+        let body = response.bytes().await.map_err(internal)?;
+        serde_json::from_slice(&body).map_err(internal)
+    }
+
+    /// Value of a field, None if it does not exist.
+    pub async fn get_field(&self, key: &str) -> Result<Option<String>, HolyDiverError> {
+        let url = self.url(&["state", key]);
+        let request = || self.http.get(url.clone())
+            .query(&[("meta", "true")])
+            .timeout(self.config.timeout);
+        match self.send(request).await {
+            Ok(response) => Ok(Some(Self::json::<FieldMeta>(response).await?.value)),
+            Err(HolyDiverError::FieldNotFound(_)) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    pub async fn get_fields(&self, keys: &[String]) -> Result<FieldValues, HolyDiverError> {
+        let url = self.url(&["state", "get"]);
+        let body = serde_json::json!({ "keys": keys });
+        let request = || self.http.post(url.clone())
+            .json(&body)
+            .timeout(self.config.timeout);
+        Self::json(self.send(request).await?).await
+    }
+
+    pub async fn set_field(&self, key: &str, value: &str) -> Result<(), HolyDiverError> {
+        let url = self.url(&["state", key]);
+        let body = serde_json::json!({ "value": value });
+        let request = || self.http.put(url.clone())
+            .json(&body)
+            .timeout(self.config.timeout);
+        self.send(request).await.map(|_| ())
+    }
+
+    pub async fn delete_field(&self, key: &str) -> Result<(), HolyDiverError> {
+        let url = self.url(&["state", key]);
+        let request = || self.http.delete(url.clone()).timeout(self.config.timeout);
+        self.send(request).await.map(|_| ())
+    }
+
+    /// Sorted names of the fields starting with `prefix`, all fields for an empty prefix.
+    pub async fn list(&self, prefix: &str) -> Result<Vec<String>, HolyDiverError> {
+        let url = self.url(&["state", "_keys"]);
+        let request = || self.http.get(url.clone())
+            .query(&[("prefix", prefix)])
+            .timeout(self.config.timeout);
+        Ok(Self::json::<KeyList>(self.send(request).await?).await?.keys)
+    }
+
+    pub async fn members(&self) -> Result<MemberList, HolyDiverError> {
+        let url = self.url(&["members"]);
+        let request = || self.http.get(url.clone()).timeout(self.config.timeout);
+        Self::json(self.send(request).await?).await
+    }
+
+    /// The whole document as saved automerge bytes, needs the admin scope.
+    pub async fn export(&self) -> Result<Bytes, HolyDiverError> {
+        let url = self.url(&["export"]);
+        let request = || self.http.get(url.clone()).timeout(self.config.timeout);
+        self.send(request).await?.bytes().await.map_err(internal)
+    }
+
+    /// Streams the events of `types` from /events. The stream ends when the node closes the
+    /// connection, after a failed read it yields the error and ends as well.
+    pub async fn watch(&self, types: &[EventType]) -> Result<impl Stream<Item = Result<Event, HolyDiverError>>, HolyDiverError> {
+        let url = self.url(&["events"]);
+        let types = types.iter()
+            .map(|t| match t {
+                EventType::Field => "field",
+                EventType::Member => "member",
+            })
+            .collect::<Vec<_>>()
+            .join(",");
+        let request = || self.http.get(url.clone()).query(&[("types", &types)]);
+        let response = self.send(request).await?;
+        let state = (response.bytes_stream().boxed(), Vec::new(), false);
+        Ok(futures_util::stream::unfold(state, |(mut body, mut buffer, failed)| async move {
+            if failed {
+                return None;
+            }
+            loop {
+                if let Some(end) = buffer.windows(2).position(|w| w == b"\n\n") {
+                    let record: Vec<u8> = buffer.drain(..end + 2).collect();
+                    match parse_record(&String::from_utf8_lossy(&record)) {
+                        Some(event) => return Some((event, (body, buffer, false))),
+                        None => continue,
+                    }
+                }
+                if buffer.len() > MAX_EVENT_SIZE {
+                    let error = internal(anyhow!("event exceeds {} bytes", MAX_EVENT_SIZE));
+                    return Some((Err(error), (body, buffer, true)));
+                }
+                match body.next().await {
+                    Some(Ok(chunk)) => buffer.extend_from_slice(&chunk),
+                    Some(Err(e)) => return Some((Err(internal(e)), (body, buffer, true))),
+                    None => return None,
+                }
+            }
+        }))
+    }
+}
@@ -0,0 +1,247 @@
+use std::{
+    fmt::{Display, Formatter}, sync::Mutex, time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use chrono::{DateTime, NaiveDateTime, Utc};
+use log::warn;
+use once_cell::sync::Lazy;
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+
+use super::envelope;
+
+// Times exchanged with other members are WireTimestamps, i.e. unix millis in UTC encoded as a plain i64, so that
+// their encoding doesn't depend on how chrono or std serialize their types. Versions of broadcasts that replace
+// older ones of the same member, like NodeConfig, and the timestamps in values_meta come from CLOCK, a hybrid
+// logical clock: the wall clock in millis plus a counter. It never goes backwards, not even when the wall clock
+// does, and it is moved past the versions received from other members. It is not persisted though, a node whose
+// wall clock went back across a restart sends versions older than its last ones until it received one of them.
+//
+// Datagrams of envelope versions before WIRE_TIME_VERSION encode WireTimestamps as chrono NaiveDateTimes and
+// HybridTimestamps as std SystemTimes, with the counter in the nanos below the milli, see envelope.
+const WIRE_TIME_VERSION: u8 = 2;
+// counters from this on don't fit into the nanos below a milli
+const LEGACY_COUNTER_LIMIT: u32 = 1_000_000;
+
+// remote timestamps further ahead of the wall clock than this don't move CLOCK, a member with a clock that far
+// off would otherwise drag the timestamps of every member along
+pub const MAX_CLOCK_DRIFT_MILLIS: i64 = 60 * 1000;
+
+pub static CLOCK: Lazy<ClusterClock> = Lazy::new(ClusterClock::default);
+
+/// Unix millis in UTC.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub struct WireTimestamp(i64);
+
+impl WireTimestamp {
+    pub fn now() -> Self {
+        WireTimestamp(Utc::now().timestamp_millis())
+    }
+
+    pub fn from_millis(millis: i64) -> Self {
+        WireTimestamp(millis)
+    }
+
+    pub fn millis(self) -> i64 {
+        self.0
+    }
+
+    // None if out of the range chrono can represent
+    pub fn to_datetime(self) -> Option<DateTime<Utc>> {
+        DateTime::from_timestamp_millis(self.0)
+    }
+}
+
+impl From<DateTime<Utc>> for WireTimestamp {
+    fn from(datetime: DateTime<Utc>) -> Self {
+        WireTimestamp(datetime.timestamp_millis())
+    }
+}
+
+impl Display for WireTimestamp {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self.to_datetime() {
+            Some(datetime) => write!(f, "{}", datetime.to_rfc3339_opts(chrono::SecondsFormat::Millis, true)),
+            None => write!(f, "{}ms", self.0),
+        }
+    }
+}
+
+impl Serialize for WireTimestamp {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        if envelope::wire_version() < WIRE_TIME_VERSION {
+            return self.to_datetime().unwrap_or_default().naive_utc().serialize(serializer);
+        }
+        serializer.serialize_i64(self.0)
+    }
+}
+
+impl<'de> Deserialize<'de> for WireTimestamp {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        if envelope::wire_version() < WIRE_TIME_VERSION {
+            return NaiveDateTime::deserialize(deserializer).map(|naive| WireTimestamp(naive.and_utc().timestamp_millis()));
+        }
+        i64::deserialize(deserializer).map(WireTimestamp)
+    }
+}
+
+/// A timestamp of CLOCK, ordered by millis and then by counter.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub struct HybridTimestamp {
+    pub millis: WireTimestamp,
+    // orders the timestamps within the same milli, and after the wall clock went back
+    pub counter: u32,
+}
+
+// the encoding of HybridTimestamps from envelope version 2 on
+#[derive(Serialize, Deserialize)]
+#[serde(rename = "HybridTimestamp")]
+struct HybridFields {
+    millis: WireTimestamp,
+    counter: u32,
+}
+
+impl HybridTimestamp {
+    // the SystemTime of envelope version 1, timestamps before the epoch are sent as the epoch
+    fn to_legacy(self) -> SystemTime {
+        let millis = u64::try_from(self.millis.0).unwrap_or(0);
+        UNIX_EPOCH + Duration::from_millis(millis) + Duration::from_nanos(self.counter.min(LEGACY_COUNTER_LIMIT - 1).into())
+    }
+
+    fn from_legacy(time: SystemTime) -> Option<Self> {
+        let since_epoch = time.duration_since(UNIX_EPOCH).ok()?;
+        Some(HybridTimestamp {
+            millis: WireTimestamp(i64::try_from(since_epoch.as_millis()).ok()?),
+            counter: since_epoch.subsec_nanos() % LEGACY_COUNTER_LIMIT,
+        })
+    }
+}
+
+impl Serialize for HybridTimestamp {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        if envelope::wire_version() < WIRE_TIME_VERSION {
+            return self.to_legacy().serialize(serializer);
+        }
+        HybridFields { millis: self.millis, counter: self.counter }.serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for HybridTimestamp {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        if envelope::wire_version() < WIRE_TIME_VERSION {
+            let time = SystemTime::deserialize(deserializer)?;
+            return HybridTimestamp::from_legacy(time).ok_or_else(|| de::Error::custom("time before the unix epoch"));
+        }
+        HybridFields::deserialize(deserializer).map(|fields| HybridTimestamp { millis: fields.millis, counter: fields.counter })
+    }
+}
+
+impl Display for HybridTimestamp {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}+{}", self.millis, self.counter)
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct ClusterClock {
+    last: Mutex<HybridTimestamp>,
+}
+
+impl ClusterClock {
+    // a timestamp greater than all the ones returned or observed before
+    pub fn now(&self) -> HybridTimestamp {
+        self.tick(WireTimestamp::now())
+    }
+
+    fn tick(&self, wall: WireTimestamp) -> HybridTimestamp {
+        let mut last = self.last.lock().unwrap();
+        *last = match last.counter.checked_add(1) {
+            _ if wall > last.millis => HybridTimestamp { millis: wall, counter: 0 },
+            Some(counter) => HybridTimestamp { millis: last.millis, counter },
+            // more than u32::MAX timestamps within a milli, borrowing the next one
+            None => HybridTimestamp { millis: WireTimestamp(last.millis.0 + 1), counter: 0 },
+        };
+        *last
+    }
+
+    // moves the clock past a timestamp received from another member
+    pub fn observe(&self, remote: HybridTimestamp) {
+        let wall = WireTimestamp::now();
+        if remote.millis.0 > wall.0 + MAX_CLOCK_DRIFT_MILLIS {
+            warn!("Ignoring timestamp {} of a member, it is more than {}ms ahead of the clock of this node",
+                remote, MAX_CLOCK_DRIFT_MILLIS);
+            return;
+        }
+        let mut last = self.last.lock().unwrap();
+        if remote > *last {
+            *last = remote;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bincode::Options;
+    use super::*;
+
+    // 2023-11-14T22:13:20.123Z
+    const MILLIS: i64 = 1_700_000_000_123;
+
+    fn encode<T: Serialize>(version: u8, value: &T) -> Vec<u8> {
+        envelope::with_wire_version(version, || bincode::DefaultOptions::new().serialize(value).unwrap())
+    }
+
+    fn decode<T: de::DeserializeOwned>(version: u8, bytes: &[u8]) -> T {
+        envelope::with_wire_version(version, || bincode::DefaultOptions::new().deserialize(bytes).unwrap())
+    }
+
+    #[test]
+    fn wire_timestamps_keep_their_encoding() {
+        let timestamp = WireTimestamp::from_millis(MILLIS);
+        // a zigzag varint, i.e. twice the millis as u64 after its marker
+        let golden: [u8; 9] = [253, 246, 208, 202, 159, 23, 3, 0, 0];
+        assert_eq!(encode(WIRE_TIME_VERSION, &timestamp), golden);
+        assert_eq!(decode::<WireTimestamp>(WIRE_TIME_VERSION, &golden), timestamp);
+
+        // the NaiveDateTime of version 1 is a string
+        let mut legacy: Vec<u8> = vec![23];
+        legacy.extend_from_slice(b"2023-11-14T22:13:20.123");
+        assert_eq!(encode(1, &timestamp), legacy);
+        assert_eq!(decode::<WireTimestamp>(1, &legacy), timestamp);
+    }
+
+    #[test]
+    fn hybrid_timestamps_keep_their_encoding() {
+        let timestamp = HybridTimestamp { millis: WireTimestamp::from_millis(MILLIS), counter: 7 };
+        let golden: [u8; 10] = [253, 246, 208, 202, 159, 23, 3, 0, 0, 7];
+        assert_eq!(encode(WIRE_TIME_VERSION, &timestamp), golden);
+        assert_eq!(decode::<HybridTimestamp>(WIRE_TIME_VERSION, &golden), timestamp);
+
+        // the SystemTime of version 1 holds the counter in the nanos below the milli
+        let legacy: [u8; 10] = [252, 0, 241, 83, 101, 252, 199, 212, 84, 7];
+        assert_eq!(encode(1, &timestamp), legacy);
+        assert_eq!(decode::<HybridTimestamp>(1, &legacy), timestamp);
+    }
+
+    #[test]
+    fn the_clock_never_goes_back() {
+        let clock = ClusterClock::default();
+        let first = clock.tick(WireTimestamp::from_millis(MILLIS));
+        let same_milli = clock.tick(WireTimestamp::from_millis(MILLIS));
+        let wall_went_back = clock.tick(WireTimestamp::from_millis(MILLIS - 5000));
+        assert_eq!(first, HybridTimestamp { millis: WireTimestamp::from_millis(MILLIS), counter: 0 });
+        assert!(first < same_milli && same_milli < wall_went_back);
+        assert_eq!(wall_went_back.millis, first.millis);
+    }
+
+    #[test]
+    fn remote_timestamps_move_the_clock_unless_too_far_ahead() {
+        let clock = ClusterClock::default();
+        let now = WireTimestamp::now().millis();
+        let remote = HybridTimestamp { millis: WireTimestamp::from_millis(now + 10_000), counter: 3 };
+        clock.observe(remote);
+        assert!(clock.now() > remote);
+
+        let far_ahead = HybridTimestamp { millis: WireTimestamp::from_millis(now + 2 * MAX_CLOCK_DRIFT_MILLIS), counter: 0 };
+        clock.observe(far_ahead);
+        assert!(clock.now() < far_ahead);
+    }
+}
@@ -0,0 +1,253 @@
+use std::{
+    net::SocketAddr, sync::{Arc, Mutex}, time::{Duration, Instant},
+};
+use automerge::ChangeHash;
+use anyhow::Result;
+use log::{debug, warn};
+use tokio::sync::{mpsc::{Sender, error::TrySendError}, Notify};
+use uuid::Uuid;
+
+use super::{apply_latency::OperationOrigin, clock::WireTimestamp, broadcast::{MessageType::FullSync, MessageType::IncSync, DataHandler, broadcast_size, GossipMessage, Tag, Tag::AckedOperation, Tag::SyncOperation}, core::HolyDiverDataHandler, error::HolyDiverError, foca::FocaCommand, metrics, phases::{self, Phase}};
+
+// Every state broadcast carries the whole document (or all changes since the first pending write),
+// so of a burst of writes only the last broadcast matters. Local writes are applied right away but
+// at most one broadcast per interval is handed to foca, always crafted from the latest state.
+
+pub const DEFAULT_BROADCAST_INTERVAL: Duration = Duration::from_millis(100);
+
+#[derive(Default)]
+struct CoalescerState {
+    // Some if a broadcast is pending: the heads before the first pending change,
+    // or None if only the full state may be sent
+    pending: Option<Option<Vec<ChangeHash>>>,
+    // wall clock time of the first pending change, the broadcast carries it, see apply_latency
+    first_write: Option<WireTimestamp>,
+    last_emitted: Option<Instant>,
+}
+
+pub struct BroadcastCoalescer {
+    foca_command_sender: Sender<FocaCommand>,
+    data_handler: Arc<Mutex<HolyDiverDataHandler>>,
+    // maximum size of a crafted state broadcast in bytes, see gossip_budget
+    gossip_budget: usize,
+    interval: Duration,
+    state: Mutex<CoalescerState>,
+    wake: Notify,
+}
+
+impl BroadcastCoalescer {
+    // must be called within the tokio runtime, the deferred broadcasts are sent by a spawned task
+    pub fn spawn(foca_command_sender: Sender<FocaCommand>, data_handler: Arc<Mutex<HolyDiverDataHandler>>
+        , gossip_budget: usize, interval: Duration) -> Arc<Self> {
+        let coalescer = Arc::new(BroadcastCoalescer {
+            foca_command_sender,
+            data_handler,
+            gossip_budget,
+            interval,
+            state: Mutex::new(CoalescerState::default()),
+            wake: Notify::new(),
+        });
+        let task_coalescer = coalescer.clone();
+        tokio::spawn(async move {
+            loop {
+                task_coalescer.wake.notified().await;
+                let due = task_coalescer.state.lock().unwrap().last_emitted
+                    .map(|last_emitted| last_emitted + task_coalescer.interval);
+                if let Some(due) = due {
+                    tokio::time::sleep_until(due.into()).await;
+                }
+                if let Err(e) = task_coalescer.flush() {
+                    warn!("Could not broadcast coalesced changes, retrying: {}", e);
+                }
+            }
+        });
+        coalescer
+    }
+
+    // maximum size of a broadcast in bytes
+    pub fn gossip_budget(&self) -> usize {
+        self.gossip_budget
+    }
+
+    /// Requests a broadcast of the current state. It is sent right away if the last one is older
+    /// than the interval, otherwise it is merged with other pending requests and sent once the
+    /// interval is over. `heads_before` are the heads before the change, None to only send the full state.
+    pub fn request(&self, heads_before: Option<Vec<ChangeHash>>) -> Result<()> {
+        phases::enter(Phase::BroadcastEnqueue);
+        let mut state = self.state.lock().unwrap();
+        Self::add_pending(&mut state, heads_before);
+        let idle = state.last_emitted.is_none_or(|last_emitted| last_emitted.elapsed() >= self.interval);
+        if idle {
+            return self.emit(&mut state, None).map(|_| ());
+        }
+        drop(state);
+        self.wake.notify_one();
+        Ok(())
+    }
+
+    /// Sends the broadcast of a change right away, together with all pending requests, tagged so that
+    /// the receivers acknowledge it to `origin`. Returns false if the broadcast exceeded the gossip
+    /// budget and was not sent.
+    pub fn request_acked(&self, heads_before: Option<Vec<ChangeHash>>, operation_id: Uuid, origin: SocketAddr) -> Result<bool> {
+        phases::enter(Phase::BroadcastEnqueue);
+        let mut state = self.state.lock().unwrap();
+        Self::add_pending(&mut state, heads_before);
+        self.emit(&mut state, Some(AckedOperation { operation_id, origin }))
+    }
+
+    fn add_pending(state: &mut CoalescerState, heads_before: Option<Vec<ChangeHash>>) {
+        if state.pending.is_some() {
+            metrics::BROADCASTS_COALESCED.inc();
+        }
+        state.first_write.get_or_insert_with(WireTimestamp::now);
+        state.pending = Some(match state.pending.take() {
+            // the changes since the earlier heads include this change as well
+            Some(Some(earlier)) => heads_before.map(|_| earlier),
+            Some(None) => None,
+            None => heads_before,
+        });
+    }
+
+    // sends a pending broadcast right away
+    pub fn flush(&self) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        self.emit(&mut state, None).map(|_| ())
+    }
+
+    // Sends the pending broadcast with tag, a new SyncOperation if None. Returns whether a broadcast was sent.
+    fn emit(&self, state: &mut CoalescerState, tag: Option<Tag>) -> Result<bool> {
+        let heads_before = match state.pending.take() {
+            Some(heads_before) => heads_before,
+            None => return Ok(false),
+        };
+        let written = state.first_write.take().unwrap_or_else(WireTimestamp::now);
+        state.last_emitted = Some(Instant::now());
+        let tag = tag.unwrap_or_else(|| SyncOperation {
+            operation_id: Uuid::new_v4()
+        });
+        let broadcast = {
+            let mut handler = self.data_handler.lock().unwrap();
+            let origin = OperationOrigin { node: handler.node(), written };
+            self.craft_sync(&mut handler, tag, origin, heads_before.as_deref())?
+        };
+        let broadcast = match broadcast {
+            Some(broadcast) => broadcast,
+            None => return Ok(false),
+        };
+        // broadcasting the change so that all nodes get this update
+        self.foca_command_sender.try_send(FocaCommand::SendBroadcast(broadcast)).map_err(|e| match e {
+            // the write is applied locally, the broadcast is retried after the interval
+            TrySendError::Full(_) => {
+                state.pending = Some(heads_before);
+                state.first_write = Some(written);
+                self.wake.notify_one();
+                anyhow::Error::from(HolyDiverError::BroadcastBackpressure)
+            },
+            TrySendError::Closed(_) => anyhow::anyhow!("foca is not running anymore"),
+        })?;
+        Ok(true)
+    }
+
+    // Picks the form of the sync broadcast that fits into the gossip budget: the full document if
+    // possible, otherwise only the changes made since `heads_before`. If neither fits the change is
+    // not gossiped at all and the document is marked as needing anti-entropy.
+    fn craft_sync(&self, handler: &mut HolyDiverDataHandler, tag: Tag, origin: OperationOrigin, heads_before: Option<&[ChangeHash]>) -> Result<Option<(Tag, GossipMessage)>> {
+        let full_sync = GossipMessage::new(FullSync, handler.get_state()).with_origin(origin);
+        let full_sync_size = broadcast_size(&tag, &full_sync);
+        if full_sync_size <= self.gossip_budget {
+            handler.clear_needs_anti_entropy();
+            return Ok(Some((tag, full_sync)));
+        }
+        let mut smallest_size = full_sync_size;
+        if let Some(heads_before) = heads_before {
+            let inc_sync = GossipMessage::new(IncSync, handler.changes_since(heads_before)?).with_origin(origin);
+            let inc_sync_size = broadcast_size(&tag, &inc_sync);
+            if inc_sync_size <= self.gossip_budget {
+                debug!("Full state of {} bytes exceeds the gossip budget, broadcasting {} bytes of changes instead", full_sync_size, inc_sync_size);
+                return Ok(Some((tag, inc_sync)));
+            }
+            smallest_size = inc_sync_size;
+        }
+        let reason = format!("a broadcast of {} bytes exceeded the gossip budget of {} bytes", smallest_size, self.gossip_budget);
+        warn!("Not gossiping change: {}", reason);
+        handler.mark_needs_anti_entropy(reason);
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::sync::mpsc::{self, Receiver};
+    use super::*;
+    use crate::swim::{broadcast::MessageType, types::ID};
+
+    const BUDGET: usize = 2000;
+
+    // a field value far over BUDGET even after compression
+    fn large_value() -> String {
+        (0..500).map(|_| Uuid::new_v4().simple().to_string()).collect()
+    }
+
+    fn sent(receiver: &mut Receiver<FocaCommand>) -> Option<MessageType> {
+        match receiver.try_recv() {
+            Ok(FocaCommand::SendBroadcast((_, message))) => Some(message.message_type()),
+            _ => None,
+        }
+    }
+
+    #[tokio::test]
+    async fn broadcasts_shrink_to_fit_the_budget_or_are_skipped() {
+        let dir = tempfile::tempdir().unwrap();
+        let data_handler = Arc::new(Mutex::new(HolyDiverDataHandler::new(dir.path(), ID::with_bump(SocketAddr::from(([127, 0, 0, 1], 9001)), 0))));
+        let (sender, mut receiver) = mpsc::channel(16);
+        let coalescer = BroadcastCoalescer::spawn(sender, data_handler.clone(), BUDGET, DEFAULT_BROADCAST_INTERVAL);
+        let write = |field_name: &str, value: String| {
+            let heads_before = data_handler.lock().unwrap().heads();
+            data_handler.lock().unwrap().set_field(field_name.to_owned(), value).unwrap();
+            coalescer.request(Some(heads_before)).unwrap();
+            coalescer.flush().unwrap();
+        };
+
+        // a small document is sent whole
+        write("small", "value".to_owned());
+        assert_eq!(sent(&mut receiver), Some(MessageType::FullSync));
+        assert!(!data_handler.lock().unwrap().stats().needs_anti_entropy);
+
+        // a change too large for any broadcast is not gossiped
+        write("large", large_value());
+        assert_eq!(sent(&mut receiver), None);
+        let stats = data_handler.lock().unwrap().stats();
+        assert!(stats.needs_anti_entropy);
+        assert!(stats.anti_entropy_reason.unwrap().contains("exceeded the gossip budget"));
+
+        // the document is too large now, a small change is sent on its own
+        write("small", "changed".to_owned());
+        assert_eq!(sent(&mut receiver), Some(MessageType::IncSync));
+    }
+
+    #[tokio::test]
+    async fn a_burst_of_writes_is_broadcast_a_few_times_and_still_converges() {
+        let (a_dir, b_dir) = (tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap());
+        let a = Arc::new(Mutex::new(HolyDiverDataHandler::new(a_dir.path(), ID::with_bump(SocketAddr::from(([127, 0, 0, 1], 9002)), 0))));
+        let mut b = HolyDiverDataHandler::new(b_dir.path(), ID::with_bump(SocketAddr::from(([127, 0, 0, 1], 9001)), 0));
+        let (sender, mut receiver) = mpsc::channel(128);
+        // longer than the burst takes, so that only the first write and the flush emit
+        let coalescer = BroadcastCoalescer::spawn(sender, a.clone(), BUDGET * 100, Duration::from_secs(10));
+        for i in 0..100 {
+            let heads_before = a.lock().unwrap().heads();
+            a.lock().unwrap().set_field(format!("key{}", i % 10), i.to_string()).unwrap();
+            coalescer.request(Some(heads_before)).unwrap();
+        }
+        coalescer.flush().unwrap();
+
+        let mut broadcasts = 0;
+        while let Ok(FocaCommand::SendBroadcast((_, message))) = receiver.try_recv() {
+            broadcasts += 1;
+            b.handle_message(message.message_type(), message.message_payload().clone()).unwrap();
+        }
+        assert!((1..=2).contains(&broadcasts), "{} broadcasts were sent", broadcasts);
+        for key in 0..10 {
+            assert_eq!(b.get_field(format!("key{}", key)).unwrap(), Some((90 + key).to_string()));
+        }
+    }
+}
@@ -0,0 +1,129 @@
+use std::{
+    fmt::{Display, Formatter}, str::FromStr,
+};
+use bytes::{Buf, BufMut};
+use foca::{BincodeCodec, Codec, Header, Member, PostcardCodec};
+use bincode::Options;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use super::{broadcast::DEFAULT_DECODE_LIMIT, envelope};
+
+/// Serialization format of foca's SWIM messages. All members of a cluster have to use the same one,
+/// messages of members using another codec fail to decode and are dropped.
+/// Broadcast payloads are always encoded with bincode, independent of this setting.
+/// Messages are encoded in the version this node sends, see envelope, and decoded in the one they arrived in.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SwimCodec {
+    #[default]
+    Postcard,
+    Bincode,
+}
+
+impl Display for SwimCodec {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SwimCodec::Postcard => write!(f, "postcard"),
+            SwimCodec::Bincode => write!(f, "bincode"),
+        }
+    }
+}
+
+impl FromStr for SwimCodec {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "postcard" => Ok(SwimCodec::Postcard),
+            "bincode" => Ok(SwimCodec::Bincode),
+            other => Err(format!("unknown codec {:?}, expected postcard or bincode", other)),
+        }
+    }
+}
+
+// same options as the broadcasts use, with the limit of direct messages since SWIM messages can't be larger
+fn bincode_codec() -> BincodeCodec<impl bincode::Options + Copy> {
+    BincodeCodec(bincode::DefaultOptions::new().with_limit(DEFAULT_DECODE_LIMIT))
+}
+
+impl<T: Serialize + DeserializeOwned> Codec<T> for SwimCodec {
+    type Error = anyhow::Error;
+
+    fn encode_header(&mut self, header: &Header<T>, buf: impl BufMut) -> Result<(), Self::Error> {
+        envelope::with_wire_version(envelope::send_version(), || match self {
+            SwimCodec::Postcard => PostcardCodec.encode_header(header, buf).map_err(anyhow::Error::from),
+            SwimCodec::Bincode => bincode_codec().encode_header(header, buf).map_err(anyhow::Error::from),
+        })
+    }
+
+    fn decode_header(&mut self, buf: impl Buf) -> Result<Header<T>, Self::Error> {
+        match self {
+            SwimCodec::Postcard => Ok(PostcardCodec.decode_header(buf)?),
+            SwimCodec::Bincode => Ok(bincode_codec().decode_header(buf)?),
+        }
+    }
+
+    fn encode_member(&mut self, member: &Member<T>, buf: impl BufMut) -> Result<(), Self::Error> {
+        envelope::with_wire_version(envelope::send_version(), || match self {
+            SwimCodec::Postcard => PostcardCodec.encode_member(member, buf).map_err(anyhow::Error::from),
+            SwimCodec::Bincode => bincode_codec().encode_member(member, buf).map_err(anyhow::Error::from),
+        })
+    }
+
+    fn decode_member(&mut self, buf: impl Buf) -> Result<Member<T>, Self::Error> {
+        match self {
+            SwimCodec::Postcard => Ok(PostcardCodec.decode_member(buf)?),
+            SwimCodec::Bincode => Ok(bincode_codec().decode_member(buf)?),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+    use foca::State;
+    use super::*;
+    use crate::swim::types::ID;
+
+    fn encode(mut codec: SwimCodec, member: &Member<ID>) -> Vec<u8> {
+        let mut buf = Vec::new();
+        codec.encode_member(member, &mut buf).unwrap();
+        buf
+    }
+
+    #[test]
+    fn members_round_trip_under_both_codecs() {
+        for codec in [SwimCodec::Postcard, SwimCodec::Bincode] {
+            for addr in ["127.0.0.1:9001", "[2001:db8::1]:65535"] {
+                let id = ID::with_bump(addr.parse::<SocketAddr>().unwrap(), 513);
+                let member = Member::new(id.clone(), 7, State::Suspect);
+                let buf = encode(codec, &member);
+
+                let mut decoding = codec;
+                let decoded: Member<ID> = decoding.decode_member(&buf[..]).unwrap();
+                assert_eq!(decoded.id(), &id, "{} {}", codec, addr);
+                assert_eq!(decoded.id().bump, 513);
+                assert_eq!(decoded.incarnation(), 7);
+                assert_eq!(decoded.state(), State::Suspect);
+            }
+        }
+    }
+
+    #[test]
+    fn members_take_the_same_size_under_both_codecs() {
+        // the ID takes a tag, the octets of the address, the port and the bump, incarnation and state a byte each
+        for (addr, size) in [("127.0.0.1:9001", 1 + 4 + 2 + 2 + 2), ("[2001:db8::1]:9001", 1 + 16 + 2 + 2 + 2)] {
+            let member = Member::alive(ID::with_bump(addr.parse::<SocketAddr>().unwrap(), 1));
+            assert_eq!(encode(SwimCodec::Postcard, &member).len(), size, "postcard {}", addr);
+            assert_eq!(encode(SwimCodec::Bincode, &member).len(), size, "bincode {}", addr);
+        }
+    }
+
+    #[test]
+    fn codecs_parse_from_their_names() {
+        for codec in [SwimCodec::Postcard, SwimCodec::Bincode] {
+            assert_eq!(codec.to_string().parse::<SwimCodec>(), Ok(codec));
+        }
+        assert!("json".parse::<SwimCodec>().is_err());
+    }
+}
@@ -0,0 +1,94 @@
+use std::{fs, path::{Path, PathBuf}};
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+use super::settings::NodeSettings;
+
+// holy-diver.toml, written by `init` and read with --config. It holds the settings a new node needs to
+// find its cluster, keyed like the flags setting them. Flags given on the command line win over the file.
+
+pub const CONFIG_FILE: &str = "holy-diver.toml";
+pub const SYSTEMD_UNIT_FILE: &str = "holy-diver.service";
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct ConfigFile {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bind_address: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub identity: Option<String>,
+    // the seed node joined on startup
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub announce_to: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub data_dir: Option<PathBuf>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rest_port: Option<u16>,
+    // log filter in RUST_LOG syntax, replaces RUST_LOG and is read again on SIGHUP
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub log_level: Option<String>,
+}
+
+impl ConfigFile {
+    pub fn load(path: &Path) -> Result<ConfigFile> {
+        let content = fs::read_to_string(path)
+            .with_context(|| format!("could not read config file {}", path.display()))?;
+        toml::from_str(&content)
+            .with_context(|| format!("config file {} is not valid", path.display()))
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let content = toml::to_string(self).context("could not serialize the config file")?;
+        fs::write(path, content)
+            .with_context(|| format!("could not write config file {}", path.display()))
+    }
+
+    // The config of the settings of a node, the inverse of apply
+    pub fn from_settings(settings: &NodeSettings) -> ConfigFile {
+        ConfigFile {
+            bind_address: Some(settings.bind_address.clone()),
+            identity: settings.identity.clone(),
+            announce_to: settings.announce_to.clone(),
+            data_dir: Some(settings.data_dir.clone()),
+            rest_port: Some(settings.rest_port),
+            log_level: None,
+        }
+    }
+
+    // Sets the settings of the file whose flag was not given explicitly, `explicit` tells by the name of the flag
+    pub fn apply(&self, settings: &mut NodeSettings, explicit: impl Fn(&str) -> bool) {
+        if let Some(bind_address) = self.bind_address.as_ref().filter(|_| !explicit("bind-address")) {
+            settings.bind_address = bind_address.clone();
+        }
+        if let Some(identity) = self.identity.as_ref().filter(|_| !explicit("identity")) {
+            settings.identity = Some(identity.clone());
+        }
+        if let Some(announce_to) = self.announce_to.as_ref().filter(|_| !explicit("announce-to")) {
+            settings.announce_to = Some(announce_to.clone());
+        }
+        if let Some(data_dir) = self.data_dir.as_ref().filter(|_| !explicit("data-dir")) {
+            settings.data_dir = data_dir.clone();
+        }
+        if let Some(rest_port) = self.rest_port.filter(|_| !explicit("rest-port")) {
+            settings.rest_port = rest_port;
+        }
+    }
+}
+
+// A systemd unit running `<executable> --config <config>`, to be copied to /etc/systemd/system
+pub fn systemd_unit(executable: &Path, config: &Path, data_dir: &Path) -> String {
+    format!("[Unit]\n\
+        Description=holy-diver node\n\
+        Wants=network-online.target\n\
+        After=network-online.target\n\
+        \n\
+        [Service]\n\
+        ExecStart={} --config {}\n\
+        WorkingDirectory={}\n\
+        Restart=on-failure\n\
+        RestartSec=5\n\
+        \n\
+        [Install]\n\
+        WantedBy=multi-user.target\n",
+        executable.display(), config.display(), data_dir.display())
+}
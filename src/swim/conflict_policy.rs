@@ -0,0 +1,202 @@
+use std::{borrow::Cow, sync::{Arc, Mutex}};
+use anyhow::{anyhow, Result};
+use automerge::{ActorId, ChangeHash, ScalarValue, Value};
+use log::warn;
+use tokio::sync::mpsc;
+
+use super::{coalesce::BroadcastCoalescer, core::HolyDiverDataHandler, pattern::glob_matches};
+
+// Automerge picks the winner of concurrent writes of a field deterministically, but by actor and op ids, which
+// mean nothing to the application. A ConflictPolicy registered for a key pattern on the data handler is asked
+// after every merge which of the values of a conflicted field should win. If it prefers another value than the
+// winner, the handler writes that value again, a normal local write that is gossiped like any other and
+// resolves the conflict on every member that merges it.
+// All members converge as long as they register the same policies, and every policy picks by the values alone,
+// whatever the order of the candidates and whichever node asks. Members resolving the same conflict at the same
+// time write the same value, which conflicts again, but the winner then already has the preferred value and
+// nothing is written anymore. A member without the policy keeps the winner of automerge until the overwrite of
+// another member arrives. Fields hidden by a tombstone are left alone, writing them would bring them back.
+
+/// What a policy wants for a conflicted field.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Resolution {
+    // leave the value automerge picked
+    KeepWinner,
+    // the candidate at this index should win
+    Prefer(usize),
+}
+
+/// Picks the value of a conflicted field that should win, see conflict_policy.
+pub trait ConflictPolicy: Send + Sync {
+    // candidates are the concurrently written values with the actor that wrote them, at least two
+    fn resolve(&self, field: &str, candidates: &[(ActorId, Value<'static>)]) -> Resolution;
+}
+
+/// Keeps whatever automerge picked, e.g. to exempt keys from a broader pattern by registering it first.
+pub struct AutomergeDefault;
+
+impl ConflictPolicy for AutomergeDefault {
+    fn resolve(&self, _field: &str, _candidates: &[(ActorId, Value<'static>)]) -> Resolution {
+        Resolution::KeepWinner
+    }
+}
+
+/// The greatest string wins, candidates that aren't strings are ignored.
+pub struct LexicographicMax;
+
+impl ConflictPolicy for LexicographicMax {
+    fn resolve(&self, _field: &str, candidates: &[(ActorId, Value<'static>)]) -> Resolution {
+        prefer_max(candidates, |value| match value {
+            Value::Scalar(scalar) => match scalar.as_ref() {
+                ScalarValue::Str(text) => Some(text.to_string()),
+                _ => None,
+            },
+            Value::Object(_) => None,
+        })
+    }
+}
+
+/// The greatest number wins, strings are parsed as numbers. Candidates that aren't numbers are ignored.
+pub struct NumericMax;
+
+impl ConflictPolicy for NumericMax {
+    fn resolve(&self, _field: &str, candidates: &[(ActorId, Value<'static>)]) -> Resolution {
+        prefer_max(candidates, |value| match value {
+            Value::Scalar(scalar) => match scalar.as_ref() {
+                ScalarValue::Int(n) => Some(*n as f64),
+                ScalarValue::Uint(n) => Some(*n as f64),
+                ScalarValue::F64(n) => Some(*n),
+                ScalarValue::Str(text) => text.trim().parse::<f64>().ok(),
+                _ => None,
+            },
+            Value::Object(_) => None,
+        }.filter(|n| !n.is_nan()))
+    }
+}
+
+// the index of the candidate with the greatest key, of equal ones the first
+fn prefer_max<K: PartialOrd>(candidates: &[(ActorId, Value<'static>)], key: impl Fn(&Value<'static>) -> Option<K>) -> Resolution {
+    let mut best: Option<(usize, K)> = None;
+    for (index, (_, value)) in candidates.iter().enumerate() {
+        let Some(key) = key(value) else {
+            continue;
+        };
+        if best.as_ref().is_none_or(|(_, best_key)| key > *best_key) {
+            best = Some((index, key));
+        }
+    }
+    match best {
+        Some((index, _)) => Resolution::Prefer(index),
+        None => Resolution::KeepWinner,
+    }
+}
+
+// the built-in policies by the names used in configuration, e.g. numeric-max
+pub fn builtin(name: &str) -> Option<Arc<dyn ConflictPolicy>> {
+    match name {
+        "automerge-default" => Some(Arc::new(AutomergeDefault)),
+        "lexicographic-max" => Some(Arc::new(LexicographicMax)),
+        "numeric-max" => Some(Arc::new(NumericMax)),
+        _ => None,
+    }
+}
+
+/// The policies by key pattern, the first pattern matching a field applies.
+#[derive(Clone, Default)]
+pub struct ConflictPolicies(Vec<(String, Arc<dyn ConflictPolicy>)>);
+
+impl ConflictPolicies {
+    pub fn add(&mut self, pattern: &str, policy: Arc<dyn ConflictPolicy>) {
+        self.0.push((pattern.to_owned(), policy));
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn policy_for(&self, field: &str) -> Option<&Arc<dyn ConflictPolicy>> {
+        self.0.iter()
+            .find(|(pattern, _)| glob_matches(pattern, field))
+            .map(|(_, policy)| policy)
+    }
+
+    // from PATTERN=POLICY specs of the built-in policies as given with --conflict-policy
+    pub fn parse(specs: &[String]) -> Result<Self> {
+        let mut policies = ConflictPolicies::default();
+        for spec in specs {
+            let (pattern, name) = spec.rsplit_once('=')
+                .ok_or_else(|| anyhow!("invalid conflict policy {}, expected PATTERN=POLICY", spec))?;
+            let policy = builtin(name)
+                .ok_or_else(|| anyhow!("unknown conflict policy {}, expected automerge-default, lexicographic-max or numeric-max", name))?;
+            policies.add(pattern, policy);
+        }
+        Ok(policies)
+    }
+}
+
+// candidates borrow from the document, policies get owned values
+pub fn owned_value(value: Value<'_>) -> Value<'static> {
+    match value {
+        Value::Object(obj_type) => Value::Object(obj_type),
+        Value::Scalar(scalar) => Value::Scalar(Cow::Owned(scalar.into_owned())),
+    }
+}
+
+// Broadcasts the overwrites of the policies. The data handler can't request the broadcasts itself, it is
+// locked by the merge when it writes them, so it passes the heads before the overwrites to this task.
+pub fn spawn_policy_broadcasts(data_handler: Arc<Mutex<HolyDiverDataHandler>>, broadcasts: Arc<BroadcastCoalescer>) {
+    let (sender, mut receiver) = mpsc::unbounded_channel::<Vec<ChangeHash>>();
+    data_handler.lock().unwrap().set_policy_broadcasts(sender);
+    tokio::spawn(async move {
+        while let Some(heads_before) = receiver.recv().await {
+            if let Err(e) = broadcasts.request(Some(heads_before)) {
+                warn!("Could not broadcast a conflict resolution: {}", e);
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn candidates(values: &[ScalarValue]) -> Vec<(ActorId, Value<'static>)> {
+        values.iter().map(|value| (ActorId::random(), Value::Scalar(Cow::Owned(value.clone())))).collect()
+    }
+
+    #[test]
+    fn builtin_policies_pick_by_value() {
+        let versions = candidates(&[ScalarValue::Str("9".into()), ScalarValue::Str("10".into()), ScalarValue::Null]);
+        assert_eq!(NumericMax.resolve("version", &versions), Resolution::Prefer(1));
+        assert_eq!(LexicographicMax.resolve("version", &versions), Resolution::Prefer(0));
+        assert_eq!(AutomergeDefault.resolve("version", &versions), Resolution::KeepWinner);
+
+        let mixed = candidates(&[ScalarValue::Int(-3), ScalarValue::F64(2.5), ScalarValue::Uint(2), ScalarValue::F64(f64::NAN)]);
+        assert_eq!(NumericMax.resolve("n", &mixed), Resolution::Prefer(1));
+        // nothing comparable
+        assert_eq!(NumericMax.resolve("n", &candidates(&[ScalarValue::Boolean(true), ScalarValue::Null])), Resolution::KeepWinner);
+    }
+
+    #[test]
+    fn the_pick_doesnt_depend_on_the_order_of_the_candidates() {
+        let values = [ScalarValue::Str("b".into()), ScalarValue::Str("c".into()), ScalarValue::Str("a".into())];
+        let forward = candidates(&values);
+        let backward: Vec<_> = forward.iter().rev().cloned().collect();
+        let picked = |candidates: &[(ActorId, Value<'static>)]| match LexicographicMax.resolve("f", candidates) {
+            Resolution::Prefer(index) => candidates[index].1.clone(),
+            Resolution::KeepWinner => panic!("expected a preferred candidate"),
+        };
+        assert_eq!(picked(&forward), picked(&backward));
+    }
+
+    #[test]
+    fn policies_are_parsed_and_the_first_matching_pattern_applies() {
+        let policies = ConflictPolicies::parse(&["version.pinned=automerge-default".to_owned(), "version.*=numeric-max".to_owned()]).unwrap();
+        let versions = candidates(&[ScalarValue::Str("9".into()), ScalarValue::Str("10".into())]);
+        assert_eq!(policies.policy_for("version.pinned").unwrap().resolve("version.pinned", &versions), Resolution::KeepWinner);
+        assert_eq!(policies.policy_for("version.app").unwrap().resolve("version.app", &versions), Resolution::Prefer(1));
+        assert!(policies.policy_for("other").is_none());
+        assert!(ConflictPolicies::parse(&["version.*=newest".to_owned()]).is_err());
+        assert!(ConflictPolicies::parse(&["numeric-max".to_owned()]).is_err());
+    }
+}
@@ -0,0 +1,155 @@
+use std::{
+    collections::{BTreeMap, HashMap}, net::SocketAddr, sync::{Arc, Mutex},
+};
+use chrono::{DateTime, Utc};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use super::{apply_latency::ApplyLatencyReport, clock::HybridTimestamp, metrics};
+
+// Cheap divergence detection: with --checksum-interval every node gossips a 16 byte checksum of its
+// replicated values, see state_checksum_message, and every receiver compares it to its own. Writes
+// still being gossiped make checksums differ for a moment, so a member is only reported as diverged
+// once two of its checksums in a row differed, and as agreeing again with its next matching one.
+// Local fields and tombstones are not part of the checksum.
+
+pub type Checksum = [u8; 16];
+
+// consecutive differing checksums of a member until it is reported as diverged
+const MISMATCHES_UNTIL_DIVERGED: u32 = 2;
+
+// The first 16 bytes of the SHA-256 of the values sorted by key, fed one value after the other so that
+// the values don't have to be collected first.
+#[derive(Default)]
+pub struct ValuesChecksum(Sha256);
+
+impl ValuesChecksum {
+    // values have to be added in key order
+    pub fn add(&mut self, key: &str, value: &str) {
+        // length prefixed so that moving bytes from the key to the value changes the checksum
+        self.0.update((key.len() as u64).to_be_bytes());
+        self.0.update(key.as_bytes());
+        self.0.update((value.len() as u64).to_be_bytes());
+        self.0.update(value.as_bytes());
+    }
+
+    pub fn finish(self) -> Checksum {
+        let digest = self.0.finalize();
+        digest[..16].try_into().expect("SHA-256 digests have 32 bytes")
+    }
+}
+
+pub fn to_hex(checksum: &Checksum) -> String {
+    checksum.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// The last checksum received from a member.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "rest", derive(utoipa::ToSchema))]
+pub struct PeerChecksum {
+    #[cfg_attr(feature = "rest", schema(example = "3f2a9c1b7d3e4f2a9c1b7d3e4f2a9c1b"))]
+    pub checksum: String,
+    #[cfg_attr(feature = "rest", schema(value_type = String))]
+    pub received_at: DateTime<Utc>,
+    // checksums in a row that differed from the one of this node when they were received
+    pub consecutive_mismatches: u32,
+    pub diverged: bool,
+}
+
+/// The checksum of this node and the last ones of the other members, see /cluster/consistency.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "rest", derive(utoipa::ToSchema))]
+pub struct ConsistencyReport {
+    // whether this node gossips its checksum, see --checksum-interval
+    pub enabled: bool,
+    pub checksum: String,
+    #[cfg_attr(feature = "rest", schema(value_type = Object))]
+    pub peers: BTreeMap<SocketAddr, PeerChecksum>,
+    pub diverged_peers: usize,
+    // how long the writes of each member took to be applied here, see apply_latency
+    #[serde(default)]
+    pub apply_latency: ApplyLatencyReport,
+}
+
+struct PeerState {
+    sent_at: HybridTimestamp,
+    checksum: Checksum,
+    received_at: DateTime<Utc>,
+    mismatches: u32,
+}
+
+impl PeerState {
+    fn diverged(&self) -> bool {
+        self.mismatches >= MISMATCHES_UNTIL_DIVERGED
+    }
+}
+
+#[derive(Clone)]
+pub struct ChecksumTracker {
+    identity: SocketAddr,
+    peers: Arc<Mutex<HashMap<SocketAddr, PeerState>>>,
+}
+
+impl ChecksumTracker {
+    pub fn new(identity: SocketAddr) -> Self {
+        ChecksumTracker {
+            identity,
+            peers: Arc::default(),
+        }
+    }
+
+    // Compares the checksum peer sent at sent_at to the local one. Returns false for checksums older than
+    // the last one of peer, which are not passed on either. The checksums of this node pass through here
+    // as well when they are handed to foca, they are only remembered to drop relayed copies.
+    pub fn record(&self, peer: SocketAddr, sent_at: HybridTimestamp, checksum: Checksum, local: Checksum) -> bool {
+        let mut peers = self.peers.lock().unwrap();
+        let mismatches = match peers.get(&peer) {
+            Some(known) if known.sent_at >= sent_at => return false,
+            Some(known) => known.mismatches,
+            None => 0,
+        };
+        let state = PeerState {
+            sent_at,
+            checksum,
+            received_at: Utc::now(),
+            mismatches: if checksum == local || peer == self.identity { 0 } else { mismatches + 1 },
+        };
+        if state.mismatches == MISMATCHES_UNTIL_DIVERGED {
+            warn!("{} disagrees with the state of this node, its checksum {} differed from the local one {} times in a row, \
+                the local one is {}", peer, to_hex(&checksum), state.mismatches, to_hex(&local));
+            metrics::STATE_DIVERGENCES.inc();
+        } else if state.mismatches == 0 && mismatches >= MISMATCHES_UNTIL_DIVERGED {
+            info!("{} agrees with the state of this node again", peer);
+        }
+        peers.insert(peer, state);
+        metrics::DIVERGED_PEERS.set(peers.values().filter(|state| state.diverged()).count() as i64);
+        true
+    }
+
+    // forgets the members that are gone
+    pub fn retain(&self, members: &[SocketAddr]) {
+        let mut peers = self.peers.lock().unwrap();
+        peers.retain(|peer, _| members.contains(peer));
+        metrics::DIVERGED_PEERS.set(peers.values().filter(|state| state.diverged()).count() as i64);
+    }
+
+    pub fn report(&self, enabled: bool, local: Checksum) -> ConsistencyReport {
+        let peers: BTreeMap<SocketAddr, PeerChecksum> = self.peers.lock().unwrap().iter()
+            .filter(|(peer, _)| **peer != self.identity)
+            .map(|(peer, state)| (*peer, PeerChecksum {
+                checksum: to_hex(&state.checksum),
+                received_at: state.received_at,
+                consecutive_mismatches: state.mismatches,
+                diverged: state.diverged(),
+            }))
+            .collect();
+        ConsistencyReport {
+            enabled,
+            checksum: to_hex(&local),
+            diverged_peers: peers.values().filter(|peer| peer.diverged).count(),
+            peers,
+            apply_latency: ApplyLatencyReport::default(),
+        }
+    }
+}
@@ -0,0 +1,204 @@
+use std::{fmt::{Display, Formatter}, str::FromStr};
+use anyhow::{anyhow, Result};
+use automerge::{AutoCommit, AutomergeError, ObjId, ObjType, ReadDoc, ROOT, transaction::Transactable};
+use log::warn;
+use serde::Serialize;
+
+use super::{metrics, structure::{describe, LEASES_KEY, VALUES_KEY, VALUES_META_KEY}};
+
+// The top-level objects of the document besides the internal values_meta and leases maps, see --container.
+// The values map is always declared, the fields of /state are its keys. Further maps and lists are declared by
+// name and created with the initial state, or on startup in documents that lack them. Their entries are read
+// and written with /container/{name}/state/{field}, by key in maps and by index in lists. They are plain
+// entries: no metadata, tombstones, local fields, quotas or events, which are all about the values map.
+// All members should declare the same containers, a container only known to some of them is an unexpected key
+// to the others.
+
+/// The type of a declared container.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ContainerKind {
+    Map,
+    List,
+}
+
+impl ContainerKind {
+    pub fn obj_type(&self) -> ObjType {
+        match self {
+            ContainerKind::Map => ObjType::Map,
+            ContainerKind::List => ObjType::List,
+        }
+    }
+
+    fn matches(&self, value: &automerge::Value) -> bool {
+        matches!(value, automerge::Value::Object(obj_type) if *obj_type == self.obj_type())
+    }
+}
+
+impl Display for ContainerKind {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ContainerKind::Map => write!(f, "map"),
+            ContainerKind::List => write!(f, "list"),
+        }
+    }
+}
+
+impl FromStr for ContainerKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "map" => Ok(ContainerKind::Map),
+            "list" => Ok(ContainerKind::List),
+            other => Err(format!("unknown container type {:?}, expected map or list", other)),
+        }
+    }
+}
+
+/// A top-level object of the document.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Container {
+    pub name: String,
+    pub kind: ContainerKind,
+}
+
+impl FromStr for Container {
+    type Err = String;
+
+    // NAME or NAME:TYPE, maps by default
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, kind) = match s.rsplit_once(':') {
+            Some((name, kind)) => (name, kind.parse()?),
+            None => (s, ContainerKind::Map),
+        };
+        Ok(Container { name: name.to_owned(), kind })
+    }
+}
+
+/// The containers the initial state is built with, in the order they are created.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct DocSchema {
+    containers: Vec<Container>,
+}
+
+impl Default for DocSchema {
+    fn default() -> Self {
+        DocSchema { containers: vec![Container { name: VALUES_KEY.to_owned(), kind: ContainerKind::Map }] }
+    }
+}
+
+impl DocSchema {
+    // The values map is added in front unless it is declared, it can't be anything but a map. The internal
+    // keys of the root can't be declared.
+    pub fn new(containers: Vec<Container>) -> Result<Self> {
+        let mut schema = DocSchema::default();
+        for container in containers {
+            if container.name.is_empty() {
+                return Err(anyhow!("container names must not be empty"));
+            }
+            if container.name == VALUES_META_KEY || container.name == LEASES_KEY {
+                return Err(anyhow!("{} is kept by the node itself and can't be declared as a container", container.name));
+            }
+            if container.name == VALUES_KEY {
+                if container.kind != ContainerKind::Map {
+                    return Err(anyhow!("the {} container is always a map", VALUES_KEY));
+                }
+                continue;
+            }
+            if schema.is_declared(&container.name) {
+                return Err(anyhow!("container {} is declared more than once", container.name));
+            }
+            schema.containers.push(container);
+        }
+        Ok(schema)
+    }
+
+    // from NAME[:TYPE] specs as given with --container
+    pub fn parse(specs: &[String]) -> Result<Self> {
+        let containers = specs.iter()
+            .map(|spec| spec.parse().map_err(|e| anyhow!("invalid container {}: {}", spec, e)))
+            .collect::<Result<Vec<Container>>>()?;
+        Self::new(containers)
+    }
+
+    pub fn containers(&self) -> &[Container] {
+        &self.containers
+    }
+
+    pub fn is_declared(&self, name: &str) -> bool {
+        self.containers.iter().any(|container| container.name == name)
+    }
+
+    pub fn get(&self, name: &str) -> Result<&Container, ContainerError> {
+        self.containers.iter()
+            .find(|container| container.name == name)
+            .ok_or_else(|| ContainerError::Undeclared(name.to_owned()))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ContainerError {
+    // not part of the DocSchema of this node
+    Undeclared(String),
+    // declared but not in the document, e.g. removed by a peer declaring other containers
+    Missing(String),
+    // the value in place of the container, e.g. "a str"
+    WrongType { name: String, expected: ContainerKind, found: String },
+}
+
+impl Display for ContainerError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ContainerError::Undeclared(name) => write!(f, "no container {} is declared", name),
+            ContainerError::Missing(name) => write!(f, "the document has no container {}", name),
+            ContainerError::WrongType { name, expected, found } =>
+                write!(f, "the container {} is {} instead of a {}", name, found, expected),
+        }
+    }
+}
+
+impl std::error::Error for ContainerError {}
+
+// The declared container for reads, like structure::values_map it prefers the winning value of the key but
+// falls back to a container of the declared type among the conflicting values.
+pub fn container(doc: &AutoCommit, schema: &DocSchema, name: &str) -> Result<(ObjId, ContainerKind), ContainerError> {
+    let kind = schema.get(name)?.kind;
+    let all = doc.get_all(ROOT, name).unwrap_or_default();
+    if let Some((_, obj)) = all.iter().rev().find(|(value, _)| kind.matches(value)) {
+        return Ok((obj.clone(), kind));
+    }
+    match all.last() {
+        Some((value, _)) => Err(ContainerError::WrongType { name: name.to_owned(), expected: kind, found: describe(value) }),
+        None => Err(ContainerError::Missing(name.to_owned())),
+    }
+}
+
+// The declared container for writes, recreated if it is missing. Entries of a container that was replaced by
+// another value are not recovered.
+pub fn container_mut(doc: &mut AutoCommit, schema: &DocSchema, name: &str) -> Result<(ObjId, ContainerKind)> {
+    match container(doc, schema, name) {
+        Ok(found) => Ok(found),
+        Err(e @ ContainerError::Undeclared(_)) => Err(e.into()),
+        Err(e) => {
+            warn!("Recreating the container {} on write: {}", name, e);
+            metrics::DOC_STRUCTURE_REPAIRS.inc();
+            let kind = schema.get(name)?.kind;
+            Ok((doc.put_object(ROOT, name, kind.obj_type())?, kind))
+        },
+    }
+}
+
+// Creates the declared containers the document has no key for and returns their names. Keys holding
+// something else are left alone, replacing them would be gossiped and could fight a peer.
+pub fn create_missing(doc: &mut AutoCommit, schema: &DocSchema) -> Result<Vec<String>, AutomergeError> {
+    let mut created = Vec::new();
+    for container in schema.containers() {
+        if doc.get(ROOT, container.name.as_str())?.is_some() {
+            continue;
+        }
+        doc.put_object(ROOT, container.name.as_str(), container.kind.obj_type())?;
+        created.push(container.name.clone());
+    }
+    Ok(created)
+}
@@ -0,0 +1,144 @@
+use automerge::{ChangeHash, ObjId, ObjType, ReadDoc, ScalarValue, Value};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use chrono::{SecondsFormat, TimeZone, Utc};
+use serde_json::{json, Map, Number};
+
+// Conversion of automerge values to JSON, replacing the Display implementation of automerge
+// which quotes strings and prefixes counters and timestamps.
+//
+// strings, ints, uints, bools and null map to their JSON counterparts
+// floats are numbers, NaN and infinities are not representable and become {"$type": "f64", "repr": ...}
+// counters are numbers holding their current value
+// bytes are base64 encoded strings
+// timestamps are RFC3339 strings with millisecond precision
+// maps, lists and texts are converted recursively
+// anything else becomes {"$type": ..., "repr": ...}
+
+fn unsupported(value_type: &str, repr: String) -> serde_json::Value {
+    json!({ "$type": value_type, "repr": repr })
+}
+
+pub fn scalar_to_json(value: &ScalarValue) -> serde_json::Value {
+    match value {
+        ScalarValue::Str(s) => serde_json::Value::String(s.to_string()),
+        ScalarValue::Int(i) => json!(i),
+        ScalarValue::Uint(u) => json!(u),
+        ScalarValue::F64(f) => Number::from_f64(*f)
+            .map(serde_json::Value::Number)
+            .unwrap_or_else(|| unsupported("f64", f.to_string())),
+        ScalarValue::Counter(c) => json!(i64::from(c)),
+        ScalarValue::Timestamp(millis) => match Utc.timestamp_millis_opt(*millis).single() {
+            Some(timestamp) => serde_json::Value::String(timestamp.to_rfc3339_opts(SecondsFormat::Millis, true)),
+            None => unsupported("timestamp", millis.to_string()),
+        },
+        ScalarValue::Boolean(b) => json!(b),
+        ScalarValue::Bytes(bytes) => serde_json::Value::String(STANDARD.encode(bytes)),
+        ScalarValue::Null => serde_json::Value::Null,
+        ScalarValue::Unknown { type_code, bytes } => unsupported(&format!("unknown:{}", type_code), STANDARD.encode(bytes)),
+    }
+}
+
+/// Converts the value with the given id, objects are read at `heads` if given.
+pub fn value_to_json<D: ReadDoc>(doc: &D, value: &Value, id: &ObjId, heads: Option<&[ChangeHash]>) -> serde_json::Value {
+    match value {
+        Value::Scalar(scalar) => scalar_to_json(scalar),
+        Value::Object(ObjType::Map) | Value::Object(ObjType::Table) => {
+            let entries: Vec<(String, Value, ObjId)> = match heads {
+                Some(heads) => doc.map_range_at(id, .., heads).map(|(k, v, id)| (k.to_owned(), v, id)).collect(),
+                None => doc.map_range(id, ..).map(|(k, v, id)| (k.to_owned(), v, id)).collect(),
+            };
+            let map: Map<String, serde_json::Value> = entries.into_iter()
+                .map(|(key, v, id)| (key, value_to_json(doc, &v, &id, heads)))
+                .collect();
+            serde_json::Value::Object(map)
+        },
+        Value::Object(ObjType::List) => {
+            let entries: Vec<(Value, ObjId)> = match heads {
+                Some(heads) => doc.list_range_at(id, .., heads).map(|(_, v, id)| (v, id)).collect(),
+                None => doc.list_range(id, ..).map(|(_, v, id)| (v, id)).collect(),
+            };
+            serde_json::Value::Array(entries.into_iter()
+                .map(|(v, id)| value_to_json(doc, &v, &id, heads))
+                .collect())
+        },
+        Value::Object(ObjType::Text) => {
+            let text = match heads {
+                Some(heads) => doc.text_at(id, heads),
+                None => doc.text(id),
+            };
+            match text {
+                Ok(text) => serde_json::Value::String(text),
+                Err(e) => unsupported("text", e.to_string()),
+            }
+        },
+    }
+}
+
+/// A scalar like value_to_string returns it.
+pub fn scalar_to_string(value: &ScalarValue) -> String {
+    match scalar_to_json(value) {
+        serde_json::Value::String(s) => s,
+        other => other.to_string(),
+    }
+}
+
+/// Field values as returned over REST: strings as they were written, everything else as JSON.
+pub fn value_to_string<D: ReadDoc>(doc: &D, value: &Value, id: &ObjId, heads: Option<&[ChangeHash]>) -> String {
+    match value_to_json(doc, value, id, heads) {
+        serde_json::Value::String(s) => s,
+        other => other.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use automerge::{transaction::Transactable, AutoCommit, ROOT};
+    use super::*;
+
+    #[test]
+    fn every_scalar_has_a_json_form() {
+        assert_eq!(scalar_to_json(&ScalarValue::Str("text".into())), json!("text"));
+        assert_eq!(scalar_to_json(&ScalarValue::Int(-3)), json!(-3));
+        assert_eq!(scalar_to_json(&ScalarValue::Uint(u64::MAX)), json!(u64::MAX));
+        assert_eq!(scalar_to_json(&ScalarValue::F64(1.5)), json!(1.5));
+        assert_eq!(scalar_to_json(&ScalarValue::F64(f64::NAN)), json!({"$type": "f64", "repr": "NaN"}));
+        assert_eq!(scalar_to_json(&ScalarValue::F64(f64::INFINITY)), json!({"$type": "f64", "repr": "inf"}));
+        assert_eq!(scalar_to_json(&ScalarValue::counter(7)), json!(7));
+        assert_eq!(scalar_to_json(&ScalarValue::Timestamp(1_700_000_000_123)), json!("2023-11-14T22:13:20.123Z"));
+        assert_eq!(scalar_to_json(&ScalarValue::Timestamp(i64::MAX)), json!({"$type": "timestamp", "repr": i64::MAX.to_string()}));
+        assert_eq!(scalar_to_json(&ScalarValue::Boolean(true)), json!(true));
+        assert_eq!(scalar_to_json(&ScalarValue::Bytes(vec![0, 1, 2, 255])), json!("AAEC/w=="));
+        assert_eq!(scalar_to_json(&ScalarValue::Null), json!(null));
+        assert_eq!(scalar_to_json(&ScalarValue::Unknown { type_code: 20, bytes: vec![1, 2] }), json!({"$type": "unknown:20", "repr": "AQI="}));
+    }
+
+    #[test]
+    fn strings_are_returned_unquoted_and_everything_else_as_json() {
+        assert_eq!(scalar_to_string(&ScalarValue::Str("text".into())), "text");
+        assert_eq!(scalar_to_string(&ScalarValue::counter(7)), "7");
+        assert_eq!(scalar_to_string(&ScalarValue::Boolean(false)), "false");
+        assert_eq!(scalar_to_string(&ScalarValue::Null), "null");
+    }
+
+    #[test]
+    fn objects_are_converted_recursively() {
+        let mut doc = AutoCommit::new();
+        let map = doc.put_object(ROOT, "map", ObjType::Map).unwrap();
+        doc.put(&map, "count", ScalarValue::counter(2)).unwrap();
+        let list = doc.put_object(&map, "list", ObjType::List).unwrap();
+        doc.insert(&list, 0, 1_i64).unwrap();
+        doc.insert(&list, 1, "two").unwrap();
+        let text = doc.put_object(&map, "text", ObjType::Text).unwrap();
+        doc.splice_text(&text, 0, 0, "hello").unwrap();
+        let heads = doc.get_heads();
+        doc.put(&map, "later", true).unwrap();
+
+        let (value, id) = doc.get(ROOT, "map").unwrap().unwrap();
+        assert_eq!(value_to_json(&doc, &value, &id, None),
+            json!({"count": 2, "list": [1, "two"], "text": "hello", "later": true}));
+        assert_eq!(value_to_json(&doc, &value, &id, Some(&heads)),
+            json!({"count": 2, "list": [1, "two"], "text": "hello"}));
+        assert_eq!(value_to_string(&doc, &value, &id, Some(&heads)),
+            r#"{"count":2,"list":[1,"two"],"text":"hello"}"#);
+    }
+}
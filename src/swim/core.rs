@@ -1,14 +1,16 @@
 use std::{
-    time::Duration, path::PathBuf, io::{BufReader, Read, Write}, fs::{File, self}, net::SocketAddr, sync::{Mutex, Arc}
+    borrow::Cow, collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque, hash_map::DefaultHasher}, ops::{Bound, ControlFlow}, hash::{Hash, Hasher}, time::{Duration, Instant}, path::{Path, PathBuf}, net::SocketAddr, sync::{Mutex, MutexGuard, Arc}
 };
-use automerge::{ActorId, AutoCommit, transaction::Transactable, ObjType, ROOT, ReadDoc};
+use automerge::{ActorId, AutoCommit, AutomergeError, ChangeHash, ObjId, transaction::Transactable, ObjType, ROOT, ReadDoc, ScalarValue, sync::{self, SyncDoc}};
+use serde::{Deserialize, Serialize};
 use bytes::{BufMut, Bytes, BytesMut};
 use foca::{Identity, Notification, Runtime, Timer, Config};
-use log::{info, error, trace};
-use tokio::sync::mpsc::Sender;
+use log::{debug, info, error, trace, warn};
+use tokio::sync::mpsc::{Sender, UnboundedSender, error::TrySendError};
 use uuid::Uuid;
-use super::{broadcast::{MessageType, MessageType::FullSync, DataHandler, GossipMessage, Tag::SyncOperation}, types::ID, foca::FocaCommand};
+use super::{actors::{ActorSummary, ActorTable}, audit::AuditLog, change_index::ChangeIndex, chaos::FaultInjector, clock::{CLOCK, WireTimestamp}, conflict_policy::{owned_value, ConflictPolicies, ConflictPolicy, Resolution}, quotas::{Quota, QuotaTracker, QuotaUsage}, batch::{BatchOp, BatchSummary, Transaction}, consistency::{Checksum, ConsistencyReport, ValuesChecksum}, containers::{self, ContainerKind, DocSchema}, departures::Departure, direct_sync::{SyncMode, SyncReport}, encryption::{DataKey, KeyError}, broadcast::{ACKED_OPERATION_VERSION, FIRST_CUSTOM_KIND, decode_payload, MessageKind, MessageType, broadcast_size, MessageType::FullSync, MessageType::IncSync, MessageType::HeadsRequest, MessageType::Heads, MessageType::Ack, MessageType::StateRequest, MessageType::State, MessageType::SyncMessage, DataHandler, GossipMessage, Tag::SyncOperation}, types::{BumpStrategy, ID}, foca::{FocaCommand, FocaStatus, direct_message_size}, envelope::{self, ENVELOPE_VERSION, MAX_DATAGRAM_SIZE}, error::HolyDiverError, touched_keys::TouchedKeys, field_names::{self, FieldSanitation, InvalidFieldReport, Quarantine}, persistence::{self, ChecksumMismatch, StateWriter, FlushHandle, PersistedInfo, PersistenceStatus, SnapshotVerification, REJECTED_STATE_KEY, STATE_KEY, load_persisted_state}, store::{FileStore, SharedStateStore, StateStore}, metrics, validator::{Validator, Violation}, runtime_settings::{RuntimeSettings, SharedRuntimeSettings}, journal::ChangeJournal, coalesce::BroadcastCoalescer, convert::{scalar_to_string, value_to_string}, tombstones::{self, Tombstone}, structure::{self, DocStructureError}, startup_replies::DEFAULT_STARTUP_REPLY_WINDOW, node_id::load_or_create_node_id, sync_states::{SyncPayload, SyncStateStats, SyncStates, DEFAULT_MAX_SYNC_STATES}, strict_identity::{ClusterKey, Unverified}, leases::{Lease, LeaseError, LeaseManager}, merge_queue::DEFAULT_MERGE_QUEUE_SIZE, history::HistoryAssessment, hops::HopTracing, inbound_limits::{InboundLimits, PeerInboundRate}, phases::{self, Phase}, local_fields::LocalFields, codec::SwimCodec, members::{NodeLabels, REST_URL_LABEL}, probes::PeerProbe, write_quorum::{MemberCount, WriteQuorum}, events::{self, ChangeKind, FieldChange, FieldChangeReceiver, FieldChangeSender, MemberEventSender}};
 use anyhow::Result;
+use bincode::Options;
 
 pub struct AccumulatingRuntime<T> {
     pub to_send: Vec<(T, Bytes)>,
@@ -41,6 +43,12 @@ impl<T: Identity> Runtime<T> for AccumulatingRuntime<T> {
     }
 }
 
+impl<T> Default for AccumulatingRuntime<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl<T> AccumulatingRuntime<T> {
     pub fn new() -> Self {
         Self {
@@ -53,167 +61,3541 @@ impl<T> AccumulatingRuntime<T> {
 
 pub struct HolyDiverDataHandler {
     data: Mutex<AutoCommit>,
-    data_path: PathBuf,
+    writer: StateWriter,
+    versions: VersionHistory,
+    conflicts_detected: u64,
+    last_snapshot: SnapshotInfo,
+    last_local_write: Option<i64>,
+    last_remote_merge: Option<i64>,
+    // history statistics are expensive to compute, they are cached until the next mutation
+    history_stats: Option<(usize, usize)>,
+    // the last assessment of the history by the history monitor, see history
+    history_assessment: Option<HistoryAssessment>,
+    validator: Option<Arc<Validator>>,
+    // set when a change could not be gossiped because it exceeded the gossip budget
+    anti_entropy_reason: Option<String>,
+    journal: ChangeJournal,
+    // local writes within the override window, a merge replacing one of them emits an Overridden event
+    recent_writes: HashMap<String, RecentWrite>,
+    override_window: Duration,
+    log_overrides: bool,
+    events: FieldChangeSender,
+    // writers of the actor ids in the document, see actors
+    actors: ActorTable,
+    // fields kept out of the document, see local_fields
+    local: LocalFields,
+    // rejects local writes while the state can't be written
+    strict_durability: bool,
+    // rejects local writes while too few members are up, see write_quorum
+    write_quorum: Option<WriteQuorum>,
+    // the checksum of the values is computed for every received StateChecksum, so it is cached by heads
+    checksum_cache: Option<(Vec<ChangeHash>, Checksum)>,
+    save_cache: SaveCache,
+    // local writes growing the document beyond this are rejected, see check_doc_size
+    max_doc_size: Option<usize>,
+    // merges that pushed the document beyond max_doc_size
+    merges_over_max_size: u64,
+    // every published change except overridden writes is recorded here, see audit
+    audit: Option<AuditLog>,
+    // usage of the namespaces with a quota, follows every published change, see quotas
+    quotas: Mutex<QuotaTracker>,
+    // how the snapshot compared to its checksum on startup, reported with the persistence status
+    snapshot_verification: Option<SnapshotVerification>,
+    // what happens to merged fields whose names break the rules, see field_names
+    field_sanitation: FieldSanitation,
+    quarantine: Quarantine,
+    // the containers of the document, see containers
+    schema: DocSchema,
+    // address of this node, its state broadcasts name it as their origin, see apply_latency
+    node: SocketAddr,
+    // the values preferred for conflicted fields by key pattern, see conflict_policy
+    conflict_policies: ConflictPolicies,
+    // gets the heads before the overwrites of the policies, which are broadcast by spawn_policy_broadcasts
+    policy_broadcasts: Option<UnboundedSender<Vec<ChangeHash>>>,
+    // finds the change of an op for the meta of fields and their previous values, updated on lookup
+    change_index: Mutex<ChangeIndex>,
+    // sent along with sync messages, the members keep their sync state for this node by it, see sync_states
+    node_id: Uuid,
+    sync_states: SyncStates,
 }
 
-pub fn read_state_from_disk(data_dir: &PathBuf, identity: ID) -> AutoCommit {
-    let automerge_doc_path = data_dir.join("automerge.dat");
-    let automerge_doc;
-    if automerge_doc_path.exists() {
-        let mut read_buffer = Vec::new();    
-        automerge_doc = match File::open(automerge_doc_path.clone())
-        .map(BufReader::new)
-        .map(|mut r| r.read_to_end(&mut read_buffer)) {
-            Ok(_) => {
-                match AutoCommit::load(&read_buffer) {
-                    Ok(doc) => {
-                        info!("Loaded state from {}", automerge_doc_path.display());
-                        doc
-                    },
-                    Err(e) => {
-                        error!("Could not load state from {}: {}", automerge_doc_path.display(), e);
-                        get_initial_state(identity)
-                    }
+// The last full save of the document, shared by get_state, snapshots, exports and full syncs, which
+// would otherwise each serialize the whole document. It is keyed by the heads, which every mutation
+// changes, merges included, so a save is never served for a document that changed since.
+#[derive(Default)]
+struct SaveCache {
+    saved: Option<(Vec<ChangeHash>, Bytes)>,
+    hits: u64,
+    misses: u64,
+}
+
+impl SaveCache {
+    fn save(&mut self, doc: &mut AutoCommit) -> Bytes {
+        let heads = doc.get_heads();
+        if let Some((saved_heads, data)) = &self.saved {
+            if *saved_heads == heads {
+                self.hits += 1;
+                return data.clone();
+            }
+        }
+        self.misses += 1;
+        let data = Bytes::from(doc.save());
+        self.saved = Some((heads, data.clone()));
+        data
+    }
+
+    // the last save even if the document changed since
+    fn last(&self) -> Option<Bytes> {
+        self.saved.as_ref().map(|(_, data)| data.clone())
+    }
+
+    // size of the save if it is still current
+    fn size_at(&self, heads: &[ChangeHash]) -> Option<usize> {
+        match &self.saved {
+            Some((saved_heads, data)) if saved_heads == heads => Some(data.len()),
+            _ => None,
+        }
+    }
+}
+
+// Estimated growth of the document by a write besides its key and value: the change header with its
+// dependencies and the values_meta entry, see put_field_meta.
+const WRITE_OVERHEAD: usize = 128;
+
+fn estimated_growth(field_name: &str, field_value: Option<&str>) -> usize {
+    // the key is written to values and values_meta
+    2 * field_name.len() + field_value.map_or(0, str::len) + WRITE_OVERHEAD
+}
+
+struct RecentWrite {
+    // None for deletes
+    value: Option<String>,
+    written_at: Instant,
+}
+
+/// Bounded mapping from short version tokens to the document heads they were computed from
+/// so that historical versions can be addressed by clients. The oldest entries are evicted first.
+pub struct VersionHistory {
+    heads_by_version: HashMap<String, Vec<ChangeHash>>,
+    order: VecDeque<String>,
+    capacity: usize,
+}
+
+impl VersionHistory {
+    pub fn new(capacity: usize) -> Self {
+        VersionHistory {
+            heads_by_version: HashMap::new(),
+            order: VecDeque::new(),
+            capacity,
+        }
+    }
+
+    pub fn record(&mut self, heads: Vec<ChangeHash>) -> String {
+        let mut hasher = DefaultHasher::new();
+        heads.hash(&mut hasher);
+        let version = format!("{:016x}", hasher.finish());
+        if !self.heads_by_version.contains_key(&version) {
+            self.order.push_back(version.clone());
+            self.heads_by_version.insert(version.clone(), heads);
+            while self.order.len() > self.capacity {
+                if let Some(evicted) = self.order.pop_front() {
+                    self.heads_by_version.remove(&evicted);
                 }
+            }
+        }
+        version
+    }
+
+    pub fn resolve(&self, version: &str) -> Option<Vec<ChangeHash>> {
+        self.heads_by_version.get(version).cloned()
+    }
+}
+
+/// Metadata about the last write of a field in the `values` map.
+/// `actor` and `change_hash` are derived from the automerge op that holds the
+/// current value, `modified_at` is the wall-clock time (unix millis) recorded
+/// by the writer in the `values_meta` map. Values written by nodes that don't
+/// maintain `values_meta` have no `modified_at`. `writer` is the address of the
+/// member the actor belongs to, if known.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "rest", derive(utoipa::ToSchema))]
+pub struct FieldMeta {
+    pub value: String,
+    pub actor: Option<String>,
+    #[serde(default)]
+    pub writer: Option<String>,
+    pub modified_at: Option<i64>,
+    pub change_hash: Option<String>,
+}
+
+/// One of the concurrently written values of a field. Automerge deterministically
+/// picks one of them as the `winner` that is returned by regular reads.
+#[derive(Debug, Clone, Serialize)]
+#[cfg_attr(feature = "rest", derive(utoipa::ToSchema))]
+pub struct FieldConflict {
+    pub actor: Option<String>,
+    // address of the member the actor belongs to, if known
+    pub writer: Option<String>,
+    pub value: String,
+    pub winner: bool,
+}
+
+/// Statistics about the replicated document. Sizes are in bytes, times are unix millis.
+#[derive(Debug, Clone, Default, Serialize)]
+#[cfg_attr(feature = "rest", derive(utoipa::ToSchema))]
+pub struct DocStats {
+    pub keys: usize,
+    pub serialized_size: usize,
+    pub incremental_size: usize,
+    pub changes: usize,
+    pub actors: usize,
+    pub merge_conflicts: u64,
+    pub last_local_write: Option<i64>,
+    pub last_remote_merge: Option<i64>,
+    // changes were left out of gossip since the last full state broadcast
+    pub needs_anti_entropy: bool,
+    pub anti_entropy_reason: Option<String>,
+    // bytes appended to the WAL since the last snapshot
+    pub wal_size: usize,
+    pub persistence: PersistenceStatus,
+    // full saves served from the cache and those that had to serialize the document
+    pub save_cache_hits: u64,
+    pub save_cache_misses: u64,
+    // size of the last full save plus the changes since, which local writes are checked against
+    pub estimated_size: usize,
+    pub max_size: Option<usize>,
+    // merges of remote changes that grew the document beyond max_size
+    pub merges_over_max_size: u64,
+    // None until the history monitor assessed the document
+    pub history: Option<HistoryAssessment>,
+    // the automerge sync states kept for SyncMode::Session
+    pub sync_states: SyncStateStats,
+}
+
+/// Result of a batch read: the present keys with their values and the keys
+/// that are not part of the `values` map.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[cfg_attr(feature = "rest", derive(utoipa::ToSchema))]
+pub struct FieldValues {
+    pub values: BTreeMap<String, String>,
+    pub missing: Vec<String>,
+}
+
+/// All fields read under a single lock of the data handler, with the version of the document they were read at.
+#[derive(Debug, Clone)]
+pub struct StateSnapshot {
+    pub values: BTreeMap<String, String>,
+    pub deleted: Option<BTreeMap<String, Tombstone>>,
+    pub local_fields: Option<BTreeSet<String>>,
+    pub version: String,
+}
+
+// Loads the snapshot and WAL of the store, falls back to a new document if there is none or it is unusable.
+// A document without a values map gets a new one, it is snapshotted with the next write.
+// Fails only if the state is encrypted and can't be decrypted with key, a new document would replace it.
+pub fn read_state_from_disk(store: &dyn StateStore, identity: ID, key: Option<&DataKey>, schema: &DocSchema) -> Result<(AutoCommit, PersistedInfo)> {
+    let location = store.describe(STATE_KEY);
+    Ok(match load_persisted_state(store, key) {
+        Ok(Some((doc, info))) if structure::values_map(&doc).is_ok() => {
+            info!("Loaded state from {} ({} bytes snapshot, {} WAL records)", location, info.snapshot_size, info.wal_records);
+            (doc, info)
+        },
+        // the other fields and the history are kept, only the values map is recreated
+        Ok(Some((mut doc, mut info))) => match structure::values_map_mut(&mut doc) {
+            Ok(_) => {
+                warn!("Loaded state from {} ({} bytes snapshot, {} WAL records) and recreated its values map",
+                    location, info.snapshot_size, info.wal_records);
+                info.repaired = true;
+                (doc, info)
             },
             Err(e) => {
-                error!("Could not read file at {}: {}", automerge_doc_path.display(), e);
-                get_initial_state(identity)
+                error!("State in {} has no values map and could not be repaired: {}, creating initial state ...", location, e);
+                // kept for inspection like a snapshot that doesn't match its checksum
+                if let Some(data) = store.load(STATE_KEY)? {
+                    store.save(REJECTED_STATE_KEY, &data)?;
+                    warn!("Kept the rejected snapshot as {}", store.describe(REJECTED_STATE_KEY));
+                }
+                (get_initial_state(identity, schema)?, info)
             },
-        };
-    } else {
-        info!("No state found at {}, creating initial state ...", automerge_doc_path.display());
-        automerge_doc = get_initial_state(identity);
-    }
-    automerge_doc
+        },
+        Ok(None) => {
+            info!("No state found in {}, creating initial state ...", location);
+            (get_initial_state(identity, schema)?, PersistedInfo::default())
+        },
+        Err(e) if e.downcast_ref::<KeyError>().is_some() => return Err(e),
+        Err(e) if e.downcast_ref::<ChecksumMismatch>().is_some() => {
+            error!("{:#}, creating initial state ...", e);
+            // kept for inspection, the next snapshot replaces it
+            if let Some(data) = store.load(STATE_KEY)? {
+                store.save(REJECTED_STATE_KEY, &data)?;
+                warn!("Kept the rejected snapshot as {}", store.describe(REJECTED_STATE_KEY));
+            }
+            (get_initial_state(identity, schema)?, PersistedInfo {
+                verification: Some(SnapshotVerification::Mismatch),
+                ..PersistedInfo::default()
+            })
+        },
+        Err(e) => {
+            error!("Could not load state from {}: {:#}", location, e);
+            (get_initial_state(identity, schema)?, PersistedInfo::default())
+        },
+    })
 }
 
 impl DataHandler for HolyDiverDataHandler {
 
-    fn handle_message(&mut self, msg_type:MessageType, msg_payload:Vec<u8>) {
-        info!("Received message of type {:?}: {:?}", msg_type, msg_payload);
+    fn handle_message(&mut self, msg_type:MessageType, msg_payload:Bytes) -> Result<()> {
+        // payloads hold field values, only their size is logged
+        debug!("Received message of type {:?} ({} bytes)", msg_type, msg_payload.len());
         match msg_type {
             FullSync => {
-                match AutoCommit::load(&msg_payload) {
-                    Ok(doc) => {
-                        info!("Received document: {:?}", doc);
-                        self.merge(doc);
-                    },
-                    Err(e) => error!("Could not parse FullSync message: {}", e),
+                let mut doc = AutoCommit::load(&msg_payload)
+                    .map_err(|e| anyhow::anyhow!("could not parse FullSync message: {}", e))?;
+                trace!("Received document with heads {:?}", doc.get_heads());
+                self.merge(doc)
+            },
+            IncSync => self.merge_incremental(&msg_payload),
+            HeadsRequest | Heads | Ack | StateRequest | State | SyncMessage | MessageType::IdentityChallenge | MessageType::IdentityProof => anyhow::bail!("{:?} is only valid as a direct message", msg_type),
+            MessageType::NodeLabels => anyhow::bail!("{:?} is only valid in NodeConfig broadcasts", msg_type),
+            MessageType::StateChecksum => anyhow::bail!("{:?} is only valid in StateChecksum broadcasts", msg_type),
+            MessageType::Custom(kind) => anyhow::bail!("no handler for message kind {} is registered", kind),
+        }
+    }
+
+    fn handle_direct(&mut self, msg_type:MessageType, msg_payload:Bytes) -> Result<Vec<GossipMessage>> {
+        match msg_type {
+            HeadsRequest => {
+                let heads = bincode::DefaultOptions::new().serialize(&self.heads())?;
+                Ok(vec![GossipMessage::new(Heads, heads)])
+            },
+            Heads => {
+                let peer_heads: Vec<ChangeHash> = decode_payload(&msg_payload)?;
+                Ok(self.catch_up_messages(&peer_heads))
+            },
+            StateRequest => {
+                let request_id = Uuid::from_slice(&msg_payload)?;
+                let mut state = BytesMut::from(request_id.as_bytes().as_slice());
+                state.put(self.get_state());
+                let reply = GossipMessage::new(State, state.freeze());
+                let size = direct_message_size(&SyncOperation { operation_id: request_id }, &reply);
+                if size > MAX_DATAGRAM_SIZE {
+                    warn!("Answering state request {} without the document, {} bytes don't fit into a datagram", request_id, size);
+                    return Ok(vec![GossipMessage::new(State, request_id.as_bytes().to_vec())]);
                 }
+                Ok(vec![reply])
             },
-            other => {
-                info!("Handling of message type {:?} currently not implemented", other);
-            }
+            SyncMessage => self.handle_sync_message(&msg_payload),
+            other => self.handle_message(other, msg_payload).map(|_| Vec::new()),
         }
     }
 
-    fn get_state(&mut self) -> Vec<u8> {
-        self.data.lock().unwrap().save()
+    fn get_state(&mut self) -> Bytes {
+        let mut state = self.data.lock().unwrap();
+        self.save_cache.save(&mut state)
+    }
+
+    fn state_checksum(&mut self) -> Checksum {
+        let heads = self.heads();
+        match &self.checksum_cache {
+            Some((cached_heads, checksum)) if *cached_heads == heads => *checksum,
+            _ => {
+                let state = self.data.lock().unwrap();
+                let mut checksum = ValuesChecksum::default();
+                // a document without values map is checksummed like an empty one
+                for (key, value) in replicated_range(&state, Bound::Unbounded, "", None).into_iter().flatten() {
+                    checksum.add(key, &value);
+                }
+                let checksum = checksum.finish();
+                drop(state);
+                self.checksum_cache = Some((heads, checksum));
+                checksum
+            },
+        }
     }
 }
 
 impl HolyDiverDataHandler {
-    pub fn new(data_dir: &PathBuf, identity: ID) -> Self {
-        let initial_state = Mutex::from(read_state_from_disk(data_dir, identity));
-        HolyDiverDataHandler {
-            data: initial_state,
-            data_path: data_dir.to_owned(),
+    // The state file is written by a task spawned here, so this needs to be called within a tokio runtime.
+    pub fn new(data_dir: &Path, identity: ID) -> Self {
+        Self::with_store(FileStore::shared(data_dir), identity)
+    }
+
+    // same as new, keeping everything in store instead of files of a data dir
+    pub fn with_store(store: SharedStateStore, identity: ID) -> Self {
+        Self::open(store, identity, None).unwrap_or_else(|e| panic!("{:#}", e))
+    }
+
+    // Same as with_store, with the snapshot and WAL encrypted with key if there is one, see encryption.
+    // Fails if the persisted state is encrypted and key is missing or a different one. Plain state is read
+    // and replaced by encrypted state with the next write.
+    pub fn open(store: SharedStateStore, identity: ID, key: Option<Arc<DataKey>>) -> Result<Self> {
+        Self::open_with_schema(store, identity, key, DocSchema::default())
+    }
+
+    // Same as open, a new document is created with the containers of schema. Persisted documents lacking
+    // some of them get them with create_declared_containers.
+    pub fn open_with_schema(store: SharedStateStore, identity: ID, key: Option<Arc<DataKey>>, schema: DocSchema) -> Result<Self> {
+        let (mut initial_state, persisted) = read_state_from_disk(&*store, identity.clone(), key.as_deref(), &schema)?;
+        let node_id = load_or_create_node_id(&*store)?;
+        let mut actors = ActorTable::load(store.clone());
+        let mut actors_changed = false;
+        if persisted.snapshot_size > 0 || persisted.wal_records > 0 {
+            let doc_actors: HashSet<ActorId> = initial_state.get_changes(&[]).unwrap_or_default()
+                .iter()
+                .map(|c| c.actor_id().clone())
+                .collect();
+            let adopted = actors.adopt_legacy(&doc_actors, identity.addr);
+            if adopted > 0 {
+                info!("The document holds changes of {} actor id(s) derived from earlier identities of this node, \
+                    recording them as legacy/self so that the history and the audit log attribute them to this node", adopted);
+                actors_changed = true;
+            }
         }
+        actors_changed |= actors.record_own(initial_state.get_actor(), &identity);
+        if actors_changed {
+            Self::save_actors(&actors);
+        }
+        let last_snapshot = SnapshotInfo {
+            heads: initial_state.get_heads(),
+            size: persisted.snapshot_size,
+            // a fresh document is not persisted yet, so its initial change goes to the WAL
+            wal_heads: if persisted.snapshot_size > 0 || persisted.wal_records > 0 { initial_state.get_heads() } else { Vec::new() },
+            wal_size: persisted.wal_size,
+            wal_max_size: DEFAULT_WAL_MAX_SIZE,
+            // the WAL has to be rewritten before anything can be appended after a torn record,
+            // and plain state with the first write once there is a key
+            force_snapshot: persisted.torn || (key.is_some() && persisted.unencrypted)
+                || persisted.verification == Some(SnapshotVerification::Mismatch) || persisted.repaired,
+        };
+        Ok(HolyDiverDataHandler {
+            data: Mutex::from(initial_state),
+            writer: StateWriter::spawn(store.clone(), key),
+            versions: VersionHistory::new(1000),
+            conflicts_detected: 0,
+            last_snapshot,
+            last_local_write: None,
+            last_remote_merge: None,
+            history_stats: None,
+            history_assessment: None,
+            validator: None,
+            anti_entropy_reason: None,
+            journal: ChangeJournal::new(1000, Duration::from_secs(60 * 60)),
+            recent_writes: HashMap::new(),
+            override_window: Duration::from_secs(10),
+            log_overrides: true,
+            events: events::channel(events::DEFAULT_QUEUE_SIZE),
+            actors,
+            sync_states: SyncStates::load(store.clone(), DEFAULT_MAX_SYNC_STATES),
+            local: LocalFields::load(store),
+            strict_durability: false,
+            write_quorum: None,
+            checksum_cache: None,
+            save_cache: SaveCache::default(),
+            max_doc_size: None,
+            merges_over_max_size: 0,
+            audit: None,
+            quotas: Mutex::new(QuotaTracker::default()),
+            snapshot_verification: persisted.verification,
+            field_sanitation: FieldSanitation::Off,
+            quarantine: Quarantine::default(),
+            schema,
+            node: identity.addr,
+            conflict_policies: ConflictPolicies::default(),
+            policy_broadcasts: None,
+            change_index: Mutex::new(ChangeIndex::default()),
+            node_id,
+        })
     }
 
-    fn store_data(mut data:AutoCommit, data_path:&PathBuf) {
-        let mut file = fs::OpenOptions::new()
-        .write(true)
-        .truncate(true)
-        .create(true)
-        .open(data_path.clone())
-        .unwrap();
+    fn save_actors(actors: &ActorTable) {
+        if let Err(e) = actors.save() {
+            warn!("Could not persist actor table: {:#}", e);
+        }
+    }
 
-        match file.write_all(&data.save()) {
-            Ok(_) => {
-                info!("Wrote current state to {}", data_path.display());
+    pub fn node(&self) -> SocketAddr {
+        self.node
+    }
+
+    pub fn set_override_window(&mut self, override_window: Duration) {
+        self.override_window = override_window;
+    }
+
+    pub fn set_log_overrides(&mut self, log_overrides: bool) {
+        self.log_overrides = log_overrides;
+    }
+
+    // Checks the names of merged fields after every merge, see field_names. The fields already in the document
+    // are checked right away, e.g. those merged before the sanitation was turned on.
+    pub fn set_field_sanitation(&mut self, field_sanitation: FieldSanitation) {
+        self.field_sanitation = field_sanitation;
+        if field_sanitation == FieldSanitation::Off {
+            return;
+        }
+        let state = self.data.lock().unwrap();
+        let Ok(values) = structure::values_map(&state) else {
+            return;
+        };
+        let invalid: Vec<(String, field_names::InvalidFieldName)> = state.map_range(&values, ..)
+            .filter_map(|(key, _, _)| field_names::check(key).err().map(|invalid| (key.to_owned(), invalid)))
+            .collect();
+        drop(state);
+        if !invalid.is_empty() {
+            warn!("The document contains {} fields whose names break the field name rules, they are left out of listings", invalid.len());
+        }
+        if field_sanitation == FieldSanitation::Quarantine {
+            for (field_name, invalid) in invalid {
+                self.quarantine.insert(&field_name, invalid, None);
+            }
+        }
+    }
+
+    pub fn field_sanitation(&self) -> FieldSanitation {
+        self.field_sanitation
+    }
+
+    // the merged fields breaking the field name rules, empty unless they are quarantined
+    pub fn invalid_fields(&self) -> InvalidFieldReport {
+        self.quarantine.report()
+    }
+
+    // counts and logs merged fields breaking the field name rules, they are left in the document since
+    // deleting them would be gossiped and fight the members writing them
+    fn sanitize_field_names(field_sanitation: FieldSanitation, quarantine: &mut Quarantine, changes: &[FieldChange]) {
+        if field_sanitation == FieldSanitation::Off {
+            return;
+        }
+        for change in changes {
+            let Err(invalid) = field_names::check(&change.field) else {
+                continue;
+            };
+            if change.kind == ChangeKind::Deleted {
+                quarantine.remove(&change.field);
+                continue;
+            }
+            let new = match field_sanitation {
+                FieldSanitation::Quarantine => quarantine.insert(&change.field, invalid.clone(), change.winning_actor.clone()),
+                // without the report only fields that were absent before are new
+                _ => change.previous_value.is_none(),
+            };
+            if new {
+                warn!("Merged field of actor {:?} breaks the field name rules: {}", change.winning_actor, invalid);
+                metrics::INVALID_FIELD_NAMES_MERGED.with_label_values(&[invalid.rule.as_str()]).inc();
+            }
+        }
+    }
+
+    // With strict durability local writes fail with InsufficientStorage while the last write of the state
+    // failed, instead of being accepted without being persisted. Merges of remote changes are always applied.
+    pub fn set_strict_durability(&mut self, strict_durability: bool) {
+        self.strict_durability = strict_durability;
+    }
+
+    // Registers the policy for the fields matching pattern, the first pattern matching a field applies. All members
+    // should register the same policies, see conflict_policy.
+    pub fn set_conflict_policy(&mut self, pattern: &str, policy: Arc<dyn ConflictPolicy>) {
+        self.conflict_policies.add(pattern, policy);
+    }
+
+    // replaces the registered policies, e.g. with those parsed from --conflict-policy
+    pub fn set_conflict_policies(&mut self, conflict_policies: ConflictPolicies) {
+        self.conflict_policies = conflict_policies;
+    }
+
+    // see spawn_policy_broadcasts
+    pub fn set_policy_broadcasts(&mut self, policy_broadcasts: UnboundedSender<Vec<ChangeHash>>) {
+        self.policy_broadcasts = Some(policy_broadcasts);
+    }
+
+    // Local writes fail with ReadOnly while fewer members than the quorum asks for are up. Merges of
+    // remote changes are always applied.
+    pub fn set_write_quorum(&mut self, write_quorum: Option<WriteQuorum>) {
+        self.write_quorum = write_quorum;
+    }
+
+    pub fn persistence_status(&self) -> PersistenceStatus {
+        PersistenceStatus {
+            snapshot_verification: self.snapshot_verification,
+            ..self.writer.status()
+        }
+    }
+
+    fn check_write_quorum(&self) -> Result<()> {
+        match &self.write_quorum {
+            Some(write_quorum) => Ok(write_quorum.check()?),
+            None => Ok(()),
+        }
+    }
+
+    fn check_durability(&self) -> Result<()> {
+        if !self.strict_durability {
+            return Ok(());
+        }
+        let status = self.writer.status();
+        if status.consecutive_failures > 0 {
+            return Err(HolyDiverError::InsufficientStorage(status.last_error.unwrap_or_default()).into());
+        }
+        Ok(())
+    }
+
+    // Local writes that would grow the document beyond max_doc_size bytes fail with DocumentTooLarge.
+    // Merges of remote changes are always applied, exceeding the size with them is only reported.
+    pub fn set_max_doc_size(&mut self, max_doc_size: Option<usize>) {
+        self.max_doc_size = max_doc_size;
+    }
+
+    // The size of the last full save plus the changes appended to the WAL since. The raw changes are
+    // larger than their share of a full save, so the estimate only shrinks back with the next save.
+    fn estimated_size(last_snapshot: &SnapshotInfo, save_cache: &SaveCache, heads: &[ChangeHash]) -> usize {
+        save_cache.size_at(heads)
+            .unwrap_or(last_snapshot.size + last_snapshot.wal_size)
+    }
+
+    fn doc_size(&self) -> usize {
+        let heads = self.heads();
+        Self::estimated_size(&self.last_snapshot, &self.save_cache, &heads)
+    }
+
+    // Estimates the growth by the written keys and values instead of serializing the document. Deletes
+    // are not checked so that fields can still be removed from a document at its maximum size.
+    fn check_doc_size(&self, growth: usize) -> Result<()> {
+        let max = match self.max_doc_size {
+            Some(max) if growth > 0 => max,
+            _ => return Ok(()),
+        };
+        let size = self.doc_size();
+        if size + growth > max {
+            return Err(HolyDiverError::DocumentTooLarge { size, growth, max }.into());
+        }
+        Ok(())
+    }
+
+    // Local writes that would take the fields matching a pattern beyond its quota fail with QuotaExceeded,
+    // see quotas. The usage is counted from the current fields, so that it is right after a restart.
+    pub fn set_quotas(&mut self, quotas: Vec<Quota>) {
+        *self.quotas.lock().unwrap() = QuotaTracker::new(quotas);
+        self.recount_quotas();
+    }
+
+    fn recount_quotas(&self) {
+        // taken out so that the document isn't locked while holding the quotas
+        let mut quotas = std::mem::take(&mut *self.quotas.lock().unwrap());
+        quotas.reset_usage();
+        let counted = self.for_each_field("", |field_name, field_value| {
+            quotas.record(field_name, None, Some(field_value));
+            ControlFlow::Continue(())
+        });
+        if let Err(e) = counted {
+            warn!("Could not count the usage of the quotas: {}", e);
+        }
+        *self.quotas.lock().unwrap() = quotas;
+    }
+
+    pub fn quota_usage(&self) -> Vec<QuotaUsage> {
+        self.quotas.lock().unwrap().usage()
+    }
+
+    // Checks the new values of written fields against the schema, deletes (None) are always allowed.
+    fn check_schema(&self, writes: &BTreeMap<String, Option<String>>) -> Result<()> {
+        for (field_name, field_value) in writes {
+            if let Some(field_value) = field_value {
+                self.validate(field_name, field_value).map_err(HolyDiverError::SchemaViolation)?;
+            }
+        }
+        Ok(())
+    }
+
+    // Checks the new values of written fields against the quotas, None for deletes.
+    fn check_quotas(&self, writes: &BTreeMap<String, Option<String>>) -> Result<()> {
+        if self.quotas.lock().unwrap().is_empty() {
+            return Ok(());
+        }
+        let previous_values: Vec<Option<String>> = writes.keys()
+            .map(|field_name| self.get_field(field_name.to_owned()))
+            .collect::<Result<_, _>>()?;
+        let writes: Vec<(&str, Option<&str>, Option<&str>)> = writes.iter()
+            .zip(&previous_values)
+            .map(|((field_name, field_value), previous_value)| (field_name.as_str(), previous_value.as_deref(), field_value.as_deref()))
+            .collect();
+        self.quotas.lock().unwrap().check(&writes).map_err(|exceeded| {
+            metrics::QUOTA_REJECTIONS.with_label_values(&[&exceeded.pattern]).inc();
+            HolyDiverError::QuotaExceeded(exceeded).into()
+        })
+    }
+
+    // writes a snapshot if the last write failed, see spawn_persistence_retry
+    pub fn retry_persist(&mut self) {
+        if self.writer.needs_snapshot() {
+            let mut state = self.data.lock().unwrap();
+            persist(&mut self.writer, &mut self.last_snapshot, &mut self.save_cache, &mut state);
+        }
+    }
+
+    pub fn set_audit_log(&mut self, audit: AuditLog) {
+        self.audit = Some(audit);
+    }
+
+    // number of events a subscriber can fall behind before losing the oldest ones, to be set before subscribing
+    pub fn set_event_queue_size(&mut self, queue_size: usize) {
+        self.events = events::channel(queue_size);
+    }
+
+    // receives the field change events of this handler, subscribers that fall behind lose events
+    pub fn subscribe(&self) -> FieldChangeReceiver {
+        self.events.subscribe()
+    }
+
+    fn record_local_write(&mut self, field_name: &str, field_value: Option<String>) {
+        let now = Instant::now();
+        let override_window = self.override_window;
+        self.recent_writes.retain(|_, w| now.duration_since(w.written_at) < override_window);
+        self.recent_writes.insert(field_name.to_owned(), RecentWrite { value: field_value, written_at: now });
+    }
+
+    // Picks the merged changes replacing a recent local write with a different value and forgets those writes.
+    // Takes the fields separately since the document is still locked by the merge.
+    fn overridden_writes(recent_writes: &mut HashMap<String, RecentWrite>, override_window: Duration
+        , changes: &[FieldChange]) -> Vec<FieldChange> {
+        let now = Instant::now();
+        recent_writes.retain(|_, w| now.duration_since(w.written_at) < override_window);
+        let mut overridden = Vec::new();
+        for change in changes {
+            let local_value = match recent_writes.get(&change.field) {
+                Some(write) if write.value != change.winning_value => write.value.clone(),
+                _ => continue,
+            };
+            recent_writes.remove(&change.field);
+            overridden.push(FieldChange {
+                kind: ChangeKind::Overridden,
+                local_value,
+                ..change.clone()
+            });
+        }
+        overridden
+    }
+
+    // the value currently in effect for the field as an Updated or Deleted event
+    fn current_field_change(data: &AutoCommit, field_name: &str) -> FieldChange {
+        let visible = structure::values_map(data).ok()
+            .and_then(|values| tombstones::visible_value(data, &values, field_name).ok().flatten());
+        match visible {
+            Some((value, id)) => FieldChange {
+                kind: ChangeKind::Updated,
+                field: field_name.to_owned(),
+                local_value: None,
+                winning_value: Some(value_to_string(data, &value, &id, None)),
+                winning_actor: match &id {
+                    ObjId::Id(_, actor, _) => Some(actor.to_string()),
+                    ObjId::Root => None,
+                },
+                remote: false,
+                previous_value: None,
+                request_id: None,
             },
-            Err(e) => {
-                error!("Could not write current state to {}: {}", data_path.display(), e);
+            None => FieldChange {
+                kind: ChangeKind::Deleted,
+                field: field_name.to_owned(),
+                local_value: None,
+                winning_value: None,
+                winning_actor: None,
+                remote: false,
+                previous_value: None,
+                request_id: None,
+            },
+        }
+    }
+
+    // the value in effect for the field, None if it is absent or deleted
+    fn current_value(data: &AutoCommit, field_name: &str) -> Option<String> {
+        Self::current_field_change(data, field_name).winning_value
+    }
+
+    // the value the field had with the given id as of heads
+    fn value_at(data: &AutoCommit, field_name: &str, id: &ObjId, heads: &[ChangeHash]) -> Option<String> {
+        let values = structure::values_map(data).ok()?;
+        data.get_all_at(&values, field_name, heads).ok()?
+            .into_iter()
+            .find(|(_, value_id)| value_id == id)
+            .map(|(value, value_id)| value_to_string(data, &value, &value_id, Some(heads)))
+    }
+
+    // the fields among candidates whose visible value differs from the one as of heads_before after a merge
+    fn changed_fields(data: &AutoCommit, candidates: &BTreeSet<String>, heads_before: &[ChangeHash]) -> Vec<FieldChange> {
+        let values = structure::values_map(data).ok();
+        let values_before = structure::values_map_at(data, heads_before).ok();
+        candidates.iter()
+            .filter_map(|field_name| {
+                let id = values.as_ref()
+                    .and_then(|values| tombstones::visible_value(data, values, field_name).ok().flatten())
+                    .map(|(_, id)| id);
+                let id_before = values_before.as_ref()
+                    .and_then(|values| tombstones::visible_id_at(data, values, field_name, heads_before));
+                (id != id_before).then(|| FieldChange {
+                    remote: true,
+                    previous_value: id_before.and_then(|id| Self::value_at(data, field_name, &id, heads_before)),
+                    ..Self::current_field_change(data, field_name)
+                })
+            })
+            .collect()
+    }
+
+    fn publish(&self, mut change: FieldChange) {
+        if !change.remote {
+            change.request_id = events::current_request_id();
+        }
+        if let Some(audit) = &self.audit {
+            audit.record(&change);
+        }
+        if change.kind != ChangeKind::Overridden {
+            let mut quotas = self.quotas.lock().unwrap();
+            let exceeded = quotas.record(&change.field, change.previous_value.as_deref(), change.winning_value.as_deref());
+            // local writes are checked before, only seeds and migrated fields get here
+            for pattern in exceeded.iter().filter(|_| change.remote) {
+                warn!("Merged change of {} took the fields matching {} beyond their quota", change.field, pattern);
+                quotas.flag_merge(pattern);
+                metrics::QUOTA_EXCEEDING_MERGES.with_label_values(&[pattern]).inc();
             }
-        }   
+        }
+        // sending only fails without subscribers
+        let _ = self.events.send(change);
+    }
+
+    pub fn set_journal_bounds(&mut self, max_entries: usize, max_age: Duration) {
+        self.journal.set_bounds(max_entries, max_age);
+    }
+
+    pub fn set_validator(&mut self, validator: Arc<Validator>) {
+        self.validator = Some(validator);
+    }
+
+    pub fn validator(&self) -> Option<Arc<Validator>> {
+        self.validator.clone()
     }
 
-    fn merge(&mut self, mut other:AutoCommit) {
-        let automerge_doc_path = Self::get_state_path(&self.data_path);
+    pub fn validate(&self, field_name: &str, field_value: &str) -> std::result::Result<(), Violation> {
+        match &self.validator {
+            Some(validator) => validator.validate(field_name, field_value),
+            None => Ok(()),
+        }
+    }
+
+    fn merge(&mut self, mut other:AutoCommit) -> Result<()> {
+        self.apply_remote(|data| data.merge(&mut other).map(|cs| cs.len()))
+    }
+
+    // Changes whose dependencies are not known yet are kept by automerge until they arrive
+    fn merge_incremental(&mut self, changes: &[u8]) -> Result<()> {
+        self.apply_remote(|data| data.load_incremental(changes))
+    }
+
+    #[tracing::instrument(skip_all)]
+    fn apply_remote<F: FnOnce(&mut AutoCommit) -> Result<usize, AutomergeError>>(&mut self, apply: F) -> Result<()> {
+        let _timer = metrics::MERGE_SECONDS.start_timer();
         let mut data = self.data.lock().unwrap();
-        match data.merge(&mut other) {
-            Ok(cs) => {
-                info!("Merged {} changes into local state", cs.len());
-                Self::store_data(data.to_owned(), &automerge_doc_path);
+        let heads_before = data.get_heads();
+        let size_before = Self::estimated_size(&self.last_snapshot, &self.save_cache, &heads_before);
+        match apply(&mut data) {
+            Ok(applied) => {
+                info!("Merged remote changes into local state ({})", applied);
+                let (merged_actors, touched) = {
+                    let merged = data.get_changes(&heads_before).unwrap_or_default();
+                    let actors: HashSet<ActorId> = merged.iter().map(|c| c.actor_id().clone()).collect();
+                    (actors, TouchedKeys::of(merged.iter().copied()))
+                };
+                if self.actors.record_merged(&merged_actors) {
+                    Self::save_actors(&self.actors);
+                }
+                // only changes writing to the root can change its structure
+                if touched.as_ref().map_or(true, |touched| touched.keys(&ROOT).next().is_some()) {
+                    let anomalies_before = structure::check_structure_at(&data, &self.schema, &heads_before);
+                    // anomalies are left in the document, repairing them would be gossiped and could fight a peer
+                    for anomaly in structure::check_structure(&data, &self.schema).into_iter().filter(|a| !anomalies_before.contains(a)) {
+                        warn!("Merged changes of {:?} broke the document structure: {}", merged_actors, anomaly);
+                        metrics::DOC_STRUCTURE_ANOMALIES.with_label_values(&[anomaly.kind()]).inc();
+                    }
+                }
+                let candidates = Self::merged_fields(&data, touched.as_ref(), &heads_before);
+                let values_before = structure::values_map_at(&data, &heads_before).ok();
+                let conflicted: BTreeSet<String> = match structure::values_map(&data) {
+                    Ok(values) => candidates.iter()
+                        .filter(|f| Self::is_conflicted(&data, &values, f, None))
+                        .cloned()
+                        .collect(),
+                    Err(_) => BTreeSet::new(),
+                };
+                let new_conflicts: Vec<String> = conflicted.iter()
+                    .filter(|f| !values_before.as_ref().is_some_and(|values| Self::is_conflicted(&data, values, f, Some(&heads_before))))
+                    .cloned()
+                    .collect();
+                if !new_conflicts.is_empty() {
+                    warn!("Merge introduced concurrent writes on fields {:?}", new_conflicts);
+                    self.conflicts_detected += new_conflicts.len() as u64;
+                    metrics::MERGE_CONFLICTS.inc_by(new_conflicts.len() as u64);
+                }
+                let changes = Self::changed_fields(&data, &candidates, &heads_before);
+                // remote values are never rejected so that all nodes converge, violations are only reported
+                if let Some(validator) = &self.validator {
+                    for change in changes.iter().filter(|c| c.kind == ChangeKind::Updated) {
+                        let field_value = change.winning_value.as_deref().unwrap_or_default();
+                        if let Err(violation) = validator.validate(&change.field, field_value) {
+                            warn!("Merged value violates the schema: {}", violation);
+                            metrics::SCHEMA_VIOLATIONS_MERGED.inc();
+                        }
+                    }
+                }
+                Self::sanitize_field_names(self.field_sanitation, &mut self.quarantine, &changes);
+                let overridden = Self::overridden_writes(&mut self.recent_writes, self.override_window, &changes);
+                for change in overridden {
+                    if self.log_overrides {
+                        warn!("Local write {:?} of field {} was overridden by {:?} of actor {:?}",
+                            change.local_value, change.field, change.winning_value, change.winning_actor);
+                    }
+                    metrics::FIELD_OVERRIDES.inc();
+                    self.publish(change);
+                }
+                for change in changes {
+                    self.publish(change);
+                }
+                let preferred = match Self::preferred_values(&self.conflict_policies, &data, &conflicted) {
+                    Ok(preferred) => preferred,
+                    Err(e) => {
+                        warn!("Could not apply the conflict policies: {:#}", e);
+                        Vec::new()
+                    },
+                };
+                persist(&mut self.writer, &mut self.last_snapshot, &mut self.save_cache, &mut data);
+                if let Some(max) = self.max_doc_size {
+                    let size = Self::estimated_size(&self.last_snapshot, &self.save_cache, &data.get_heads());
+                    if size > max && size_before <= max {
+                        warn!("Merged remote changes grew the document to about {} bytes, beyond the maximum of {} bytes", size, max);
+                        self.merges_over_max_size += 1;
+                        metrics::DOC_MAX_SIZE_EXCEEDED.inc();
+                    }
+                }
+                self.last_remote_merge = Some(chrono::Utc::now().timestamp_millis());
+                self.history_stats = None;
+                drop(data);
+                if !preferred.is_empty() {
+                    self.write_preferred_values(preferred);
+                }
+                Ok(())
             },
             Err(e) => {
                 error!("Could not merge changes into local state: {}", e);
+                Err(e.into())
             },
         }
     }
 
-    pub fn get_field(&self, field_name: String) -> Option<String> {
-        let state = self.data.lock().unwrap();
-        let values = match state.get(ROOT, "values").unwrap() {
-            Some((automerge::Value::Object(ObjType::Map), values)) => values,
-            _ => panic!("a map with name values is expected in the ROOT of the AutoMerge document"),
+    // The fields a merge since heads_before may have changed: the keys its changes wrote in the values and values_meta
+    // maps, deletes and tombstones write both. Changes replacing one of the maps, or that can't be expanded, make
+    // every field of the old and the new values map a candidate.
+    fn merged_fields(state: &AutoCommit, touched: Option<&TouchedKeys>, heads_before: &[ChangeHash]) -> BTreeSet<String> {
+        let values = structure::values_map(state).ok();
+        let values_meta = tombstones::values_meta_map(state);
+        match touched {
+            Some(touched) if !touched.keys(&ROOT).any(|key| key == structure::VALUES_KEY || key == structure::VALUES_META_KEY) =>
+                values.iter().chain(values_meta.iter())
+                    .flat_map(|obj| touched.keys(obj).cloned())
+                    .collect(),
+            _ => {
+                let mut fields: BTreeSet<String> = values.iter().flat_map(|values| state.keys(values)).collect();
+                if let Ok(values_before) = structure::values_map_at(state, heads_before) {
+                    fields.extend(state.keys_at(&values_before, heads_before));
+                }
+                fields
+            },
+        }
+    }
+
+    // whether the field holds values of concurrent writes, as of heads if given
+    fn is_conflicted(state: &AutoCommit, values: &ObjId, field_name: &str, heads: Option<&[ChangeHash]>) -> bool {
+        let all = match heads {
+            Some(heads) => state.get_all_at(values, field_name, heads),
+            None => state.get_all(values, field_name),
         };
-        state.get(&values, field_name).unwrap()
-            .map(|(v,_)| v)
-            .map(|v| v.to_string())
+        all.is_ok_and(|all| all.len() > 1)
     }
 
-    pub async fn set_field(&mut self, field_name: String, field_value: String) -> Result<()> {
+    // The values the conflict policies prefer over the winners automerge picked for the conflicted fields of a merge.
+    // Fields whose winner already has the preferred value are left out, so that nodes resolving the same conflict stop
+    // once their overwrites merged.
+    fn preferred_values(policies: &ConflictPolicies, data: &AutoCommit, conflicted: &BTreeSet<String>) -> Result<Vec<(String, ScalarValue)>> {
+        if policies.is_empty() {
+            return Ok(Vec::new());
+        }
+        let values = match structure::values_map(data) {
+            Ok(values) => values,
+            Err(_) => return Ok(Vec::new()),
+        };
+        let values_meta = tombstones::values_meta_map(data);
+        let mut preferred: Vec<(String, ScalarValue)> = Vec::new();
+        for field_name in conflicted.iter().cloned() {
+            let Some(policy) = policies.policy_for(&field_name) else {
+                continue;
+            };
+            let Some((winner, winner_id)) = data.get(&values, field_name.as_str())? else {
+                continue;
+            };
+            if tombstones::is_hidden(data, values_meta.as_ref(), &field_name, &winner_id) {
+                continue;
+            }
+            let candidates: Vec<(ActorId, automerge::Value<'static>)> = data.get_all(&values, field_name.as_str())?
+                .into_iter()
+                .filter_map(|(value, id)| match id {
+                    ObjId::Id(_, actor, _) => Some((actor, owned_value(value))),
+                    ObjId::Root => None,
+                })
+                .collect();
+            let Resolution::Prefer(index) = policy.resolve(&field_name, &candidates) else {
+                continue;
+            };
+            let winner = match &winner {
+                automerge::Value::Scalar(winner) => Some(winner.as_ref()),
+                automerge::Value::Object(_) => None,
+            };
+            match candidates.get(index) {
+                Some((_, automerge::Value::Scalar(value))) if winner != Some(value.as_ref()) =>
+                    preferred.push((field_name, value.clone().into_owned())),
+                Some((_, automerge::Value::Scalar(_))) => {},
+                Some((_, automerge::Value::Object(_))) => warn!("The conflict policy of {} prefers an object, which can't be written again", field_name),
+                None => warn!("The conflict policy of {} prefers candidate {} of {}", field_name, index, candidates.len()),
+            }
+        }
+        Ok(preferred)
+    }
+
+    // Writes the preferred values of the conflict policies once the merge released the document. They are local
+    // writes and pass the same checks, if one fails nothing is written and the conflict is left to the other members.
+    fn write_preferred_values(&mut self, preferred: Vec<(String, ScalarValue)>) {
+        let writes: BTreeMap<String, Option<String>> = preferred.iter()
+            .map(|(field_name, value)| (field_name.clone(), Some(scalar_to_string(value))))
+            .collect();
+        let checked = self.check_write_quorum()
+            .and_then(|_| self.check_durability())
+            .and_then(|_| self.check_quotas(&writes))
+            .and_then(|_| self.check_doc_size(writes.iter().map(|(field_name, value)| estimated_growth(field_name, value.as_deref())).sum()));
+        if let Err(e) = checked {
+            warn!("Not writing the values the conflict policies prefer for {:?}: {:#}", writes.keys(), e);
+            return;
+        }
         let mut state = self.data.lock().unwrap();
-        let values = match state.get(ROOT, "values").unwrap() {
-            Some((automerge::Value::Object(ObjType::Map), values)) => values,
-            _ => panic!("a map with name values is expected in the ROOT of the AutoMerge document"),
+        let heads_before = state.get_heads();
+        let written = (|| -> Result<Vec<FieldChange>> {
+            let values = structure::values_map(&state)?;
+            let mut overwrites = Vec::new();
+            for (field_name, value) in preferred {
+                let previous_value = Self::current_value(&state, &field_name);
+                state.put(&values, field_name.as_str(), value)?;
+                Self::put_field_meta(&mut state, &field_name, false)?;
+                overwrites.push(FieldChange { previous_value, ..Self::current_field_change(&state, &field_name) });
+            }
+            Ok(overwrites)
+        })();
+        let overwrites = match written {
+            Ok(overwrites) => overwrites,
+            Err(e) => {
+                warn!("Could not write the values the conflict policies prefer: {:#}", e);
+                return;
+            },
         };
-        state.put(&values, field_name, field_value)?;
-        let automerge_doc_path = Self::get_state_path(&self.data_path);
-        Self::store_data(state.to_owned(), &automerge_doc_path);
-        Ok(())
+        persist(&mut self.writer, &mut self.last_snapshot, &mut self.save_cache, &mut state);
+        self.journal.record(&mut state);
+        drop(state);
+        info!("Conflict policies overwrote the merged winners of {} fields", overwrites.len());
+        metrics::CONFLICT_POLICY_OVERWRITES.inc_by(overwrites.len() as u64);
+        for change in overwrites {
+            self.publish(change);
+        }
+        self.last_local_write = Some(chrono::Utc::now().timestamp_millis());
+        self.history_stats = None;
+        // without the task the overwrites go out with the next broadcast of this node
+        if let Some(policy_broadcasts) = &self.policy_broadcasts {
+            let _ = policy_broadcasts.send(heads_before);
+        }
     }
 
-    fn get_state_path(data_path: &PathBuf) -> PathBuf {
-        data_path.join("automerge.dat")
+    // number of fields that became conflicted through merges since startup
+    pub fn conflicts_detected(&self) -> u64 {
+        self.conflicts_detected
     }
-}
 
-fn get_initial_state(identity: ID) -> AutoCommit {
-    let mut state = AutoCommit::new()
-    .with_actor(ActorId::from(format!("{:?}", identity).as_bytes()));
-    state.put_object(ROOT, "values", ObjType::Map).unwrap();
-    state
-}
+    pub fn get_conflicts(&self, field_name: String) -> Result<Vec<FieldConflict>, DocStructureError> {
+        // local fields have a single writer
+        if self.local.contains(&field_name) {
+            return Ok(Vec::new());
+        }
+        let state = self.data.lock().unwrap();
+        let values = structure::values_map(&state)?;
+        // a deleted field has no conflicts, even if writes concurrent with the delete are left in the values map
+        let Some((_, winner_id)) = tombstones::visible_value(&state, &values, &field_name)? else {
+            return Ok(Vec::new());
+        };
+        let values_meta = tombstones::values_meta_map(&state);
+        Ok(state.get_all(&values, field_name.as_str())?
+            .into_iter()
+            .filter(|(_, id)| !tombstones::is_hidden(&state, values_meta.as_ref(), &field_name, id))
+            .map(|(v, id)| {
+                let actor = match &id {
+                    ObjId::Id(_, actor, _) => Some(actor.to_string()),
+                    ObjId::Root => None,
+                };
+                FieldConflict {
+                    value: value_to_string(&*state, &v, &id, None),
+                    writer: self.actors.writer(actor.as_ref()),
+                    actor,
+                    winner: winner_id == id,
+                }
+            })
+            .collect())
+    }
 
-pub struct FocaRuntimeConfig {
-    pub identity: ID,
-    pub data_dir: PathBuf,
-    pub bind_addr: SocketAddr,
-    pub announce_to: Option<ID>,
-    pub foca_config: Config
-}
+    pub fn get_field(&self, field_name: String) -> Result<Option<String>, DocStructureError> {
+        if let Some(field) = self.local.get(&field_name) {
+            return Ok(Some(field.value.clone()));
+        }
+        let state = self.data.lock().unwrap();
+        let values = structure::values_map(&state)?;
+        Ok(tombstones::visible_value(&state, &values, &field_name)?
+            .map(|(v, id)| value_to_string(&*state, &v, &id, None)))
+    }
 
-pub struct HolyDiverController {
-    pub foca_command_sender: Sender<FocaCommand>,
-    pub data_handler: Arc<Mutex<HolyDiverDataHandler>>,
-}
+    // Returns a short token identifying the current heads of the document.
+    // The token can later be resolved with resolve_version as long as it wasn't evicted.
+    pub fn version(&mut self) -> String {
+        let heads = self.data.lock().unwrap().get_heads();
+        self.versions.record(heads)
+    }
 
-impl HolyDiverController {
-    pub fn get_field(&self, field_name: String) -> Option<String> {
-        self.data_handler.lock().unwrap().get_field(field_name)
+    pub fn resolve_version(&self, version: &str) -> Option<Vec<ChangeHash>> {
+        self.versions.resolve(version)
     }
 
-    pub async fn set_field(&mut self, field_name: String, field_value: String) -> Result<()> {
-        let mut handler = self.data_handler.lock().unwrap();
-        handler.set_field(field_name, field_value).await?;
-        // broadcasting the change so that all nodes get this update
-        self.foca_command_sender.send(FocaCommand::SendBroadcast((SyncOperation {
-            operation_id: Uuid::new_v4()
-        }, GossipMessage::new(FullSync, handler.get_state())))).await?;
-        Ok(())
+    // local fields are not versioned, their current value is returned for all versions
+    pub fn get_field_at(&self, field_name: String, heads: &[ChangeHash]) -> Result<Option<String>> {
+        if let Some(field) = self.local.get(&field_name) {
+            return Ok(Some(field.value.clone()));
+        }
+        let state = self.data.lock().unwrap();
+        let values = structure::values_map(&state)?;
+        Ok(tombstones::visible_value_at(&state, &values, &field_name, heads)?
+            .map(|(v, id)| value_to_string(&*state, &v, &id, Some(heads))))
     }
-}
\ No newline at end of file
+
+    // reads all requested fields under a single lock acquisition, duplicate keys are only looked up once
+    pub fn get_fields(&self, field_names: &[String]) -> Result<FieldValues, DocStructureError> {
+        let state = self.data.lock().unwrap();
+        let values = structure::values_map(&state)?;
+        let mut result = FieldValues::default();
+        for field_name in field_names {
+            if result.values.contains_key(field_name) || result.missing.contains(field_name) {
+                continue;
+            }
+            if let Some(field) = self.local.get(field_name) {
+                result.values.insert(field_name.to_owned(), field.value.clone());
+                continue;
+            }
+            match tombstones::visible_value(&state, &values, field_name)? {
+                Some((v, id)) => {
+                    result.values.insert(field_name.to_owned(), value_to_string(&*state, &v, &id, None));
+                },
+                None => result.missing.push(field_name.to_owned()),
+            }
+        }
+        Ok(result)
+    }
+
+    // whether the fields of the document can be read, see structure
+    pub fn values_readable(&self) -> Result<(), DocStructureError> {
+        structure::values_map(&self.data.lock().unwrap()).map(|_| ())
+    }
+
+    // the fields of the document and the local fields, which take precedence
+    pub fn get_all_fields(&self) -> Result<BTreeMap<String, String>, DocStructureError> {
+        let mut fields = BTreeMap::new();
+        self.for_each_field("", |key, value| {
+            fields.insert(key.to_owned(), value.to_owned());
+            ControlFlow::Continue(())
+        })?;
+        Ok(fields)
+    }
+
+    // Calls f with the fields starting with prefix in key order, local fields taking precedence, while
+    // iterating the document, so that listings don't need a copy of all values. Stops once f breaks.
+    pub fn for_each_field(&self, prefix: &str, f: impl FnMut(&str, &str) -> ControlFlow<()>) -> Result<(), DocStructureError> {
+        self.for_each_field_at(prefix, None, None, f)
+    }
+
+    // Like for_each_field, but starts after the key after and reads the document as of heads, so that a
+    // listing can be continued where it stopped with the same heads. Tombstones and local fields are read
+    // as they are now.
+    pub fn for_each_field_at(&self, prefix: &str, after: Option<&str>, heads: Option<&[ChangeHash]>,
+        mut f: impl FnMut(&str, &str) -> ControlFlow<()>) -> Result<(), DocStructureError> {
+        let start = match after {
+            Some(after) if after >= prefix => Bound::Excluded(after.to_owned()),
+            _ => Bound::Included(prefix.to_owned()),
+        };
+        let mut state = self.data.lock().unwrap();
+        // reading at heads rebuilds the clock of the heads on every call, the current document is read directly
+        let current = state.get_heads();
+        let heads = heads.filter(|heads| *heads != current.as_slice());
+        let mut replicated = replicated_range(&state, start.clone(), prefix, heads)?.peekable();
+        let mut local = self.local.fields().range::<String, _>((start, Bound::Unbounded))
+            .take_while(|(key, _)| key.starts_with(prefix))
+            .peekable();
+        loop {
+            let next_is_local = match (replicated.peek(), local.peek()) {
+                (None, None) => break,
+                (Some(_), None) => false,
+                (None, Some(_)) => true,
+                (Some((key, _)), Some((local_key, _))) => local_key.as_str() <= *key,
+            };
+            let flow = if next_is_local {
+                let (key, field) = local.next().expect("peeked");
+                if replicated.peek().is_some_and(|(replicated_key, _)| *replicated_key == key.as_str()) {
+                    replicated.next();
+                }
+                f(key, &field.value)
+            } else {
+                let (key, value) = replicated.next().expect("peeked");
+                f(key, &value)
+            };
+            if flow.is_break() {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    fn replicated_fields(&self) -> Result<BTreeMap<String, String>, DocStructureError> {
+        let state = self.data.lock().unwrap();
+        let fields = replicated_range(&state, Bound::Unbounded, "", None)?
+            .map(|(key, value)| (key.to_owned(), value.into_owned()))
+            .collect();
+        Ok(fields)
+    }
+
+    // The fields, their tombstones and names of the local fields as of one version of the document, nothing
+    // is merged or written in between since the caller holds the data handler.
+    pub fn snapshot(&mut self, include_deleted: bool, meta: bool) -> Result<StateSnapshot, DocStructureError> {
+        let values = self.get_all_fields()?;
+        let deleted = if include_deleted { Some(self.tombstones()?) } else { None };
+        Ok(StateSnapshot {
+            values,
+            deleted,
+            local_fields: meta.then(|| self.local_field_names()),
+            version: self.version(),
+        })
+    }
+
+    // names of the fields only stored on this node
+    pub fn local_field_names(&self) -> BTreeSet<String> {
+        self.local.fields().keys().cloned().collect()
+    }
+
+    // whether writes of the field are gossiped, i.e. it matches no --no-replicate pattern
+    pub fn replicates(&self, field_name: &str) -> bool {
+        !self.local.matches(field_name)
+    }
+
+    // sorted names of the fields starting with prefix
+    pub fn keys_with_prefix(&self, prefix: &str) -> Result<Vec<String>, DocStructureError> {
+        let state = self.data.lock().unwrap();
+        let values = structure::values_map(&state)?;
+        let values_meta = tombstones::values_meta_map(&state);
+        let mut keys: BTreeSet<String> = state.map_range(&values, prefix.to_owned()..)
+            .take_while(|(key, _, _)| key.starts_with(prefix))
+            .filter(|(key, _, id)| !tombstones::is_hidden(&state, values_meta.as_ref(), key, id))
+            .map(|(key, _, _)| key.to_owned())
+            .collect();
+        keys.extend(self.local.fields().keys().filter(|key| key.starts_with(prefix)).cloned());
+        Ok(keys.into_iter().collect())
+    }
+
+    // the newest tombstones of the deleted fields, see tombstones
+    pub fn tombstones(&self) -> Result<BTreeMap<String, Tombstone>, DocStructureError> {
+        let state = self.data.lock().unwrap();
+        let values = structure::values_map(&state)?;
+        Ok(tombstones::tombstones(&state, &values))
+    }
+
+    // Removes up to limit tombstones older than horizon together with values they hide, returns how many were
+    // purged. Fewer than limit means none is left.
+    pub fn purge_tombstones(&mut self, horizon: Duration, limit: usize) -> Result<usize> {
+        let mut state = self.data.lock().unwrap();
+        let values = structure::values_map(&state)?;
+        let cutoff = chrono::Utc::now().timestamp_millis() - horizon.as_millis() as i64;
+        let expired = tombstones::expired_tombstones(&state, &values, cutoff, limit);
+        let values_meta = match tombstones::values_meta_map(&state) {
+            Some(values_meta) if !expired.is_empty() => values_meta,
+            _ => return Ok(0),
+        };
+        for (field_name, hidden_value) in &expired {
+            if *hidden_value {
+                state.delete(&values, field_name.as_str())?;
+            }
+            state.delete(&values_meta, field_name.as_str())?;
+        }
+        persist(&mut self.writer, &mut self.last_snapshot, &mut self.save_cache, &mut state);
+        self.journal.record(&mut state);
+        self.history_stats = None;
+        metrics::TOMBSTONES_PURGED.inc_by(expired.len() as u64);
+        Ok(expired.len())
+    }
+
+    pub fn get_field_with_meta(&self, field_name: String) -> Result<Option<FieldMeta>, DocStructureError> {
+        if let Some(field) = self.local.get(&field_name) {
+            return Ok(Some(FieldMeta {
+                value: field.value.clone(),
+                actor: None,
+                writer: None,
+                modified_at: Some(field.modified_at),
+                change_hash: None,
+            }));
+        }
+        let mut state = self.data.lock().unwrap();
+        let values = structure::values_map(&state)?;
+        let (value, value_id) = match tombstones::visible_value(&state, &values, &field_name)? {
+            Some((v, id)) => (value_to_string(&*state, &v, &id, None), id),
+            None => return Ok(None),
+        };
+        let (actor, change_hash) = match &value_id {
+            ObjId::Id(counter, actor, _) => {
+                let mut change_index = self.change_index.lock().unwrap();
+                change_index.update(&mut state);
+                (Some(actor.clone()), change_index.find(actor, *counter))
+            },
+            ObjId::Root => (None, None),
+        };
+        // the meta entry only belongs to the current value if it was written by the same actor,
+        // a node that doesn't maintain values_meta may have overwritten the value in the meantime
+        let modified_at = match (state.get(ROOT, "values_meta")?, &actor) {
+            (Some((automerge::Value::Object(ObjType::Map), values_meta)), Some(actor)) => {
+                match state.get(&values_meta, field_name.as_str())? {
+                    Some((automerge::Value::Object(ObjType::Map), field_meta)) => {
+                        let meta_actor = state.get(&field_meta, "actor")?
+                            .and_then(|(v, _)| v.into_string().ok());
+                        let timestamp = state.get(&field_meta, "timestamp")?
+                            .and_then(|(v, _)| v.to_i64());
+                        timestamp.filter(|_| meta_actor == Some(actor.to_string()))
+                    },
+                    _ => None,
+                }
+            },
+            _ => None,
+        };
+        let actor = actor.map(|a| a.to_string());
+        Ok(Some(FieldMeta {
+            value,
+            writer: self.actors.writer(actor.as_ref()),
+            actor,
+            modified_at,
+            change_hash: change_hash.map(|h| h.to_string()),
+        }))
+    }
+
+    // Derives the value the field had before its current one from the document as of the dependencies of the change
+    // that set it, stepping further back while that change only wrote the same value again. The dependencies are
+    // what the writer had seen, so concurrent writes it had merged count as previous values as well.
+    // Returns None if the field has no prior value and Some(None) if the previous state was a delete.
+    pub fn previous_value(&self, field_name: String) -> Result<Option<Option<String>>, DocStructureError> {
+        // local fields keep no history
+        if self.local.contains(&field_name) {
+            return Ok(None);
+        }
+        let mut state = self.data.lock().unwrap();
+        structure::values_map(&state)?;
+        let mut change_index = self.change_index.lock().unwrap();
+        change_index.update(&mut state);
+        let heads = state.get_heads();
+        let (mut value, mut op) = Self::field_state_at(&state, &field_name, &heads);
+        loop {
+            // the field did not exist before
+            let Some(ObjId::Id(counter, actor, _)) = op else {
+                return Ok(None);
+            };
+            let deps = match change_index.find(&actor, counter).and_then(|hash| state.get_change_by_hash(&hash)) {
+                Some(change) => change.deps().to_vec(),
+                None => return Ok(None),
+            };
+            let (previous_value, previous_op) = Self::field_state_at(&state, &field_name, &deps);
+            if previous_op.is_some() && previous_value != value {
+                return Ok(Some(previous_value));
+            }
+            (value, op) = (previous_value, previous_op);
+        }
+    }
+
+    // The value of the field as of heads, None if it is absent, with the op that gave the field this state: the
+    // op of the value or the meta entry of the delete. The op is None if the field did not exist up to heads.
+    fn field_state_at(data: &AutoCommit, field_name: &str, heads: &[ChangeHash]) -> (Option<String>, Option<ObjId>) {
+        let Ok(values) = structure::values_map(data) else {
+            return (None, None);
+        };
+        if let Some((value, id)) = data.get_at(&values, field_name, heads).ok().flatten() {
+            return (Some(value_to_string(data, &value, &id, Some(heads))), Some(id));
+        }
+        let deleted = |field_meta: &ObjId| data.get_at(field_meta, "deleted", heads).ok().flatten()
+            .and_then(|(deleted, _)| deleted.to_bool())
+            .unwrap_or(false);
+        let tombstone = tombstones::values_meta_map(data)
+            .and_then(|values_meta| data.get_at(&values_meta, field_name, heads).ok().flatten())
+            .filter(|(meta, field_meta)| matches!(meta, automerge::Value::Object(ObjType::Map)) && deleted(field_meta))
+            .map(|(_, field_meta)| field_meta);
+        (None, tombstone)
+    }
+
+    pub fn set_field(&mut self, field_name: String, field_value: String) -> Result<()> {
+        field_names::check(&field_name).map_err(HolyDiverError::InvalidFieldName)?;
+        let writes = BTreeMap::from([(field_name.clone(), Some(field_value.clone()))]);
+        self.check_schema(&writes)?;
+        self.check_write_quorum()?;
+        self.check_durability()?;
+        self.check_quotas(&writes)?;
+        if self.local.matches(&field_name) {
+            return self.set_local_field(&field_name, field_value);
+        }
+        self.check_doc_size(estimated_growth(&field_name, Some(&field_value)))?;
+        self.set_replicated_field(field_name, field_value)
+    }
+
+    fn set_local_field(&mut self, field_name: &str, field_value: String) -> Result<()> {
+        let previous_value = self.local.get(field_name).map(|field| field.value.clone());
+        self.local.insert(field_name, field_value.clone(), chrono::Utc::now().timestamp_millis())?;
+        self.publish(FieldChange {
+            kind: ChangeKind::Updated,
+            field: field_name.to_owned(),
+            local_value: None,
+            winning_value: Some(field_value),
+            winning_actor: None,
+            remote: false,
+            previous_value,
+            request_id: None,
+        });
+        Ok(())
+    }
+
+    fn set_replicated_field(&mut self, field_name: String, field_value: String) -> Result<()> {
+        // a field stored locally before its pattern was removed would otherwise shadow the write
+        let shadowed = self.local.remove(&field_name)?;
+        let mut state = self.data.lock().unwrap();
+        let values = structure::values_map_mut(&mut state)?;
+        let previous_value = shadowed.map(|field| field.value)
+            .or_else(|| Self::current_value(&state, &field_name));
+        state.put(&values, field_name.as_str(), field_value.clone())?;
+        // written in the same (auto)transaction as the value so that both replicate together
+        Self::put_field_meta(&mut state, &field_name, false)?;
+        persist(&mut self.writer, &mut self.last_snapshot, &mut self.save_cache, &mut state);
+        self.journal.record(&mut state);
+        let change = FieldChange { previous_value, ..Self::current_field_change(&state, &field_name) };
+        drop(state);
+        self.publish(change);
+        self.record_local_write(&field_name, Some(field_value));
+        self.last_local_write = Some(chrono::Utc::now().timestamp_millis());
+        self.history_stats = None;
+        Ok(())
+    }
+
+    // writes all replicated fields in a single change with a single persist
+    pub fn set_fields(&mut self, fields: Vec<(String, String)>) -> Result<()> {
+        for (field_name, _) in &fields {
+            field_names::check(field_name).map_err(HolyDiverError::InvalidFieldName)?;
+        }
+        // of a field written more than once the last value is kept
+        let writes = fields.iter().map(|(field_name, field_value)| (field_name.clone(), Some(field_value.clone()))).collect();
+        self.check_schema(&writes)?;
+        self.check_write_quorum()?;
+        self.check_durability()?;
+        self.check_quotas(&writes)?;
+        let (local_fields, fields): (Vec<_>, Vec<_>) = fields.into_iter()
+            .partition(|(field_name, _)| self.local.matches(field_name));
+        self.check_doc_size(fields.iter().map(|(field_name, field_value)| estimated_growth(field_name, Some(field_value))).sum())?;
+        for (field_name, field_value) in local_fields {
+            self.set_local_field(&field_name, field_value)?;
+        }
+        let mut shadowed = HashMap::new();
+        for (field_name, _) in &fields {
+            if let Some(field) = self.local.remove(field_name)? {
+                shadowed.insert(field_name.as_str(), field.value);
+            }
+        }
+        let mut state = self.data.lock().unwrap();
+        let values = structure::values_map_mut(&mut state)?;
+        let previous_values: Vec<Option<String>> = fields.iter()
+            .map(|(field_name, _)| shadowed.remove(field_name.as_str())
+                .or_else(|| Self::current_value(&state, field_name)))
+            .collect();
+        for (field_name, field_value) in &fields {
+            state.put(&values, field_name.as_str(), field_value.as_str())?;
+            Self::put_field_meta(&mut state, field_name, false)?;
+        }
+        persist(&mut self.writer, &mut self.last_snapshot, &mut self.save_cache, &mut state);
+        self.journal.record(&mut state);
+        let changes: Vec<FieldChange> = fields.iter()
+            .zip(previous_values)
+            .map(|((field_name, _), previous_value)| FieldChange { previous_value, ..Self::current_field_change(&state, field_name) })
+            .collect();
+        drop(state);
+        for change in changes {
+            self.publish(change);
+        }
+        for (field_name, field_value) in fields {
+            self.record_local_write(&field_name, Some(field_value));
+        }
+        self.last_local_write = Some(chrono::Utc::now().timestamp_millis());
+        self.history_stats = None;
+        Ok(())
+    }
+
+    // Resulting value of every field touched by the operations, checked before anything is written.
+    // Increments of absent fields start at 0.
+    fn batch_values(&self, ops: &[BatchOp]) -> Result<BTreeMap<String, Option<String>>> {
+        let mut values: BTreeMap<String, Option<String>> = BTreeMap::new();
+        for op in ops {
+            // deletes are allowed, they remove fields that were merged with an invalid name
+            if !matches!(op, BatchOp::Delete { .. }) {
+                field_names::check(op.key()).map_err(HolyDiverError::InvalidFieldName)?;
+            }
+            let value = match op {
+                BatchOp::Set { value, .. } => Some(value.to_owned()),
+                BatchOp::Delete { .. } => None,
+                BatchOp::Increment { key, by } => {
+                    let current = match values.get(key) {
+                        Some(value) => value.clone(),
+                        None => self.get_field(key.to_owned())?,
+                    };
+                    let current = match current {
+                        Some(value) => value.parse::<i64>().map_err(|_| HolyDiverError::NotAnInteger(key.to_owned()))?,
+                        None => 0,
+                    };
+                    let incremented = current.checked_add(*by).ok_or_else(|| HolyDiverError::NotAnInteger(key.to_owned()))?;
+                    Some(incremented.to_string())
+                },
+            };
+            values.insert(op.key().to_owned(), value);
+        }
+        Ok(values)
+    }
+
+    // Applies all operations or none of them. The replicated ones are written as a single change with a
+    // single persist, local fields get their final value.
+    pub fn apply_batch(&mut self, ops: Vec<BatchOp>) -> Result<BatchSummary> {
+        self.check_write_quorum()?;
+        self.check_durability()?;
+        let values = self.batch_values(&ops)?;
+        self.check_schema(&values)?;
+        self.check_quotas(&values)?;
+        let (local_ops, ops): (Vec<_>, Vec<_>) = ops.into_iter()
+            .partition(|op| self.local.matches(op.key()));
+        let applied = local_ops.len() + ops.len();
+        self.check_doc_size(ops.iter()
+            .filter(|op| !matches!(op, BatchOp::Delete { .. }))
+            .map(|op| estimated_growth(op.key(), values[op.key()].as_deref()))
+            .sum())?;
+        if !ops.is_empty() {
+            let mut state = self.data.lock().unwrap();
+            let replicated: BTreeSet<&str> = ops.iter().map(BatchOp::key).collect();
+            let mut previous_values: HashMap<&str, Option<String>> = replicated.iter()
+                .map(|field_name| (*field_name, Self::current_value(&state, field_name)))
+                .collect();
+            if let Err(e) = Self::apply_ops(&mut state, &ops) {
+                state.rollback();
+                return Err(e);
+            }
+            persist(&mut self.writer, &mut self.last_snapshot, &mut self.save_cache, &mut state);
+            self.journal.record(&mut state);
+            let changes: Vec<FieldChange> = replicated.iter()
+                .map(|field_name| FieldChange {
+                    previous_value: previous_values.remove(field_name).flatten(),
+                    ..Self::current_field_change(&state, field_name)
+                })
+                .collect();
+            drop(state);
+            for field_name in replicated {
+                // a field stored locally before its pattern was removed would otherwise shadow the write
+                self.local.remove(field_name)?;
+                self.record_local_write(field_name, values[field_name].clone());
+            }
+            for change in changes {
+                self.publish(change);
+            }
+            self.last_local_write = Some(chrono::Utc::now().timestamp_millis());
+            self.history_stats = None;
+        }
+        let local: BTreeSet<&str> = local_ops.iter().map(BatchOp::key).collect();
+        for field_name in local {
+            match &values[field_name] {
+                Some(value) => self.set_local_field(field_name, value.to_owned())?,
+                None => {
+                    self.delete_field(field_name.to_owned())?;
+                },
+            }
+        }
+        Ok(BatchSummary { applied, values })
+    }
+
+    fn apply_ops(state: &mut AutoCommit, ops: &[BatchOp]) -> Result<()> {
+        let values = structure::values_map_mut(state)?;
+        for op in ops {
+            match op {
+                BatchOp::Set { key, value } => {
+                    state.put(&values, key.as_str(), value.as_str())?;
+                    Self::put_field_meta(state, key, false)?;
+                },
+                BatchOp::Delete { key } => {
+                    state.delete(&values, key.as_str())?;
+                    Self::put_field_meta(state, key, true)?;
+                },
+                BatchOp::Increment { key, by } => {
+                    // counters add up concurrent increments of other nodes, other values are replaced by one
+                    let current = tombstones::visible_value(state, &values, key)?
+                        .map(|(value, id)| (value.to_owned(), id));
+                    match current {
+                        Some((automerge::Value::Scalar(scalar), _)) if matches!(scalar.as_ref(), ScalarValue::Counter(_)) =>
+                            state.increment(&values, key.as_str(), *by)?,
+                        Some((value, id)) => {
+                            let current: i64 = value_to_string(&*state, &value, &id, None).parse()
+                                .map_err(|_| HolyDiverError::NotAnInteger(key.to_owned()))?;
+                            state.put(&values, key.as_str(), ScalarValue::counter(current + by))?;
+                        },
+                        None => state.put(&values, key.as_str(), ScalarValue::counter(*by))?,
+                    }
+                    Self::put_field_meta(state, key, false)?;
+                },
+            }
+        }
+        Ok(())
+    }
+
+    pub fn delete_field(&mut self, field_name: String) -> Result<()> {
+        self.check_write_quorum()?;
+        self.check_durability()?;
+        if self.local.matches(&field_name) {
+            if let Some(field) = self.local.remove(&field_name)? {
+                self.publish(FieldChange {
+                    kind: ChangeKind::Deleted,
+                    field: field_name,
+                    local_value: None,
+                    winning_value: None,
+                    winning_actor: None,
+                    remote: false,
+                    previous_value: Some(field.value),
+                    request_id: None,
+                });
+            }
+            return Ok(());
+        }
+        // a field stored locally before its pattern was removed would otherwise shadow the delete
+        self.local.remove(&field_name)?;
+        self.delete_replicated_field(field_name)
+    }
+
+    fn delete_replicated_field(&mut self, field_name: String) -> Result<()> {
+        let mut state = self.data.lock().unwrap();
+        let values = structure::values_map_mut(&mut state)?;
+        let previous_value = Self::current_value(&state, &field_name);
+        state.delete(&values, field_name.as_str())?;
+        // the tombstone keeps a concurrent older write from resurrecting the field, see tombstones
+        Self::put_field_meta(&mut state, &field_name, true)?;
+        persist(&mut self.writer, &mut self.last_snapshot, &mut self.save_cache, &mut state);
+        self.journal.record(&mut state);
+        let change = FieldChange { previous_value, ..Self::current_field_change(&state, &field_name) };
+        drop(state);
+        self.publish(change);
+        self.record_local_write(&field_name, None);
+        self.last_local_write = Some(chrono::Utc::now().timestamp_millis());
+        self.history_stats = None;
+        Ok(())
+    }
+
+    // Applies a lease operation like a local write. Leases are not fields, so no field events are published
+    fn update_leases<T>(&mut self, update: impl FnOnce(&mut LeaseManager) -> Result<T, LeaseError>) -> Result<T> {
+        self.check_write_quorum()?;
+        self.check_durability()?;
+        self.check_doc_size(WRITE_OVERHEAD)?;
+        let mut state = self.data.lock().unwrap();
+        let result = update(&mut LeaseManager::new(&mut state)).map_err(HolyDiverError::from)?;
+        persist(&mut self.writer, &mut self.last_snapshot, &mut self.save_cache, &mut state);
+        self.journal.record(&mut state);
+        drop(state);
+        self.last_local_write = Some(chrono::Utc::now().timestamp_millis());
+        self.history_stats = None;
+        Ok(result)
+    }
+
+    pub fn acquire_lease(&mut self, name: &str, holder: &str, ttl: Duration) -> Result<Lease> {
+        let now = chrono::Utc::now().timestamp_millis();
+        self.update_leases(|leases| leases.acquire(name, holder, ttl, now))
+    }
+
+    pub fn renew_lease(&mut self, name: &str, holder: &str, ttl: Duration) -> Result<Lease> {
+        let now = chrono::Utc::now().timestamp_millis();
+        self.update_leases(|leases| leases.renew(name, holder, ttl, now))
+    }
+
+    pub fn release_lease(&mut self, name: &str, holder: &str) -> Result<()> {
+        self.update_leases(|leases| leases.release(name, holder))
+    }
+
+    // a new meta entry replaces the old one, so writing a deleted field drops its tombstone
+    fn put_field_meta(state: &mut AutoCommit, field_name: &str, deleted: bool) -> Result<()> {
+        let values_meta = match state.get(ROOT, "values_meta")? {
+            Some((automerge::Value::Object(ObjType::Map), values_meta)) => values_meta,
+            // documents created before values_meta existed get the map on their first write
+            _ => state.put_object(ROOT, "values_meta", ObjType::Map)?,
+        };
+        let actor = state.get_actor().to_string();
+        let field_meta = state.put_object(&values_meta, field_name, ObjType::Map)?;
+        state.put(&field_meta, "actor", actor)?;
+        // from CLOCK, so that a wall clock going back doesn't make later writes look older
+        state.put(&field_meta, "timestamp", ScalarValue::Timestamp(CLOCK.now().millis.millis()))?;
+        if deleted {
+            state.put(&field_meta, "deleted", true)?;
+        }
+        Ok(())
+    }
+
+    pub fn stats(&mut self) -> DocStats {
+        let mut state = self.data.lock().unwrap();
+        let keys = structure::values_map(&state).map_or(0, |values| state.length(&values));
+        let (changes, actors) = match self.history_stats {
+            Some(history_stats) => history_stats,
+            None => {
+                let all_changes = state.get_changes(&[]).unwrap_or_default();
+                let actors: HashSet<&ActorId> = all_changes.iter().map(|c| c.actor_id()).collect();
+                let history_stats = (all_changes.len(), actors.len());
+                self.history_stats = Some(history_stats);
+                history_stats
+            }
+        };
+        let incremental_size = state.get_changes(&self.last_snapshot.heads)
+            .map(|cs| cs.iter().map(|c| c.raw_bytes().len()).sum())
+            .unwrap_or(0);
+        let heads = state.get_heads();
+        let stats = DocStats {
+            keys,
+            serialized_size: self.last_snapshot.size,
+            incremental_size,
+            changes,
+            actors,
+            merge_conflicts: self.conflicts_detected,
+            last_local_write: self.last_local_write,
+            last_remote_merge: self.last_remote_merge,
+            needs_anti_entropy: self.anti_entropy_reason.is_some(),
+            anti_entropy_reason: self.anti_entropy_reason.clone(),
+            wal_size: self.last_snapshot.wal_size,
+            persistence: self.writer.status(),
+            save_cache_hits: self.save_cache.hits,
+            save_cache_misses: self.save_cache.misses,
+            estimated_size: Self::estimated_size(&self.last_snapshot, &self.save_cache, &heads),
+            max_size: self.max_doc_size,
+            merges_over_max_size: self.merges_over_max_size,
+            history: self.history_assessment.clone(),
+            sync_states: self.sync_states.stats(),
+        };
+        metrics::DOC_KEYS.set(stats.keys as i64);
+        metrics::DOC_SIZE_BYTES.set(stats.serialized_size as i64);
+        metrics::DOC_INCREMENTAL_SIZE_BYTES.set(stats.incremental_size as i64);
+        metrics::DOC_ESTIMATED_SIZE_BYTES.set(stats.estimated_size as i64);
+        metrics::DOC_MAX_SIZE_BYTES.set(stats.max_size.unwrap_or_default() as i64);
+        metrics::DOC_CHANGES.set(stats.changes as i64);
+        metrics::DOC_ACTORS.set(stats.actors as i64);
+        stats
+    }
+
+    pub fn heads(&self) -> Vec<ChangeHash> {
+        self.data.lock().unwrap().get_heads()
+    }
+
+    // the last full save of the document without saving it again, None if it was never saved
+    pub fn cached_save(&self) -> Option<Bytes> {
+        self.save_cache.last()
+    }
+
+    pub fn record_history_assessment(&mut self, assessment: HistoryAssessment) {
+        self.history_assessment = Some(assessment);
+    }
+
+    // all actor ids of the document with their writers and number of changes
+    pub fn actors(&self) -> BTreeMap<String, ActorSummary> {
+        let mut state = self.data.lock().unwrap();
+        let mut changes: BTreeMap<String, usize> = BTreeMap::new();
+        for change in state.get_changes(&[]).unwrap_or_default() {
+            *changes.entry(change.actor_id().to_string()).or_default() += 1;
+        }
+        changes.into_iter()
+            .map(|(actor, changes)| {
+                let info = self.actors.resolve(&actor).cloned();
+                (actor, ActorSummary { info, changes })
+            })
+            .collect()
+    }
+
+    // the changes made after `heads` in the format accepted by load_incremental
+    pub fn changes_since(&self, heads: &[ChangeHash]) -> Result<Vec<u8>> {
+        let mut state = self.data.lock().unwrap();
+        let changes = state.get_changes(heads)?;
+        Ok(changes.iter().flat_map(|c| c.raw_bytes().iter().copied()).collect())
+    }
+
+    // Messages bringing a member with the given heads up to date with the local changes of this node.
+    // Changes of other members are left to them. If any of the missing changes is no longer journaled
+    // the whole document is sent instead.
+    pub fn catch_up_messages(&mut self, peer_heads: &[ChangeHash]) -> Vec<GossipMessage> {
+        let mut state = self.data.lock().unwrap();
+        // heads unknown here are changes the peer made itself
+        let known_heads: Vec<ChangeHash> = peer_heads.iter()
+            .filter(|h| state.get_change_by_hash(h).is_some())
+            .copied()
+            .collect();
+        let actor = state.get_actor().clone();
+        let missing: Vec<ChangeHash> = match state.get_changes(&known_heads) {
+            Ok(changes) => changes.iter()
+                .filter(|c| c.actor_id() == &actor)
+                .map(|c| c.hash())
+                .collect(),
+            Err(e) => {
+                warn!("Could not determine missing changes, sending the whole document: {}", e);
+                return vec![GossipMessage::new(FullSync, self.save_cache.save(&mut state))];
+            },
+        };
+        if missing.is_empty() {
+            return Vec::new();
+        }
+        match self.journal.changes(&missing) {
+            Some(changes) => {
+                info!("Sending {} journaled changes to catch up", changes.len());
+                changes.into_iter().map(|c| GossipMessage::new(IncSync, c)).collect()
+            },
+            None => {
+                info!("{} changes are missing but not all of them are journaled, sending the whole document", missing.len());
+                vec![GossipMessage::new(FullSync, self.save_cache.save(&mut state))]
+            },
+        }
+    }
+
+    // The first message of an automerge sync session with the member at target, resumed from the sync state of
+    // the last session with it, see sync_states.
+    pub fn start_sync_session(&mut self, target: SocketAddr) -> Option<GossipMessage> {
+        let (peer, mut state) = self.sync_states.start(target);
+        let message = self.data.lock().unwrap().sync().generate_sync_message(&mut state)?;
+        if let Err(e) = self.sync_states.keep(peer, target, state, false, WireTimestamp::now()) {
+            warn!("Could not persist the sync state of {}: {:#}", target, e);
+        }
+        Some(self.sync_message(message, true))
+    }
+
+    fn sync_message(&self, message: sync::Message, first: bool) -> GossipMessage {
+        let payload = SyncPayload { node_id: self.node_id, addr: self.node, first, message: message.encode() };
+        let payload = bincode::DefaultOptions::new().serialize(&payload).expect("sync payloads can be encoded");
+        GossipMessage::new(SyncMessage, payload)
+    }
+
+    // Applies a message of a sync session and answers with the next one, none once the session is done. The
+    // changes it carries are merged like any other remote changes.
+    fn handle_sync_message(&mut self, payload: &[u8]) -> Result<Vec<GossipMessage>> {
+        let payload: SyncPayload = decode_payload(payload)?;
+        let message = sync::Message::decode(&payload.message)
+            .map_err(|e| anyhow::anyhow!("invalid sync message from {}: {}", payload.addr, e))?;
+        let mut state = self.sync_states.take(payload.node_id, payload.addr, payload.first);
+        match message.changes.len() {
+            0 => self.data.lock().unwrap().sync().receive_sync_message(&mut state, message)?,
+            changes => self.apply_remote(|data| data.sync().receive_sync_message(&mut state, message).map(|()| changes))?,
+        }
+        let reply = self.data.lock().unwrap().sync().generate_sync_message(&mut state);
+        if let Err(e) = self.sync_states.keep(Some(payload.node_id), payload.addr, state, reply.is_none(), WireTimestamp::now()) {
+            warn!("Could not persist the sync state of {}: {:#}", payload.addr, e);
+        }
+        Ok(reply.map(|message| self.sync_message(message, false)).into_iter().collect())
+    }
+
+    pub fn mark_needs_anti_entropy(&mut self, reason: String) {
+        self.anti_entropy_reason = Some(reason);
+    }
+
+    // a full state broadcast contains everything that was left out before
+    pub fn clear_needs_anti_entropy(&mut self) {
+        self.anti_entropy_reason = None;
+    }
+
+    // merges a serialized document, e.g. a backup, into the current state
+    pub fn import(&mut self, data: &[u8]) -> Result<()> {
+        self.check_write_quorum()?;
+        self.check_durability()?;
+        // the imported changes are at most as large as the document carrying them
+        self.check_doc_size(data.len())?;
+        let doc = AutoCommit::load(data).map_err(|e| HolyDiverError::InvalidDocument(e.to_string()))?;
+        self.merge(doc)
+    }
+
+    // resolves once everything written up to now is persisted
+    pub fn flush_handle(&self) -> FlushHandle {
+        self.writer.flush_handle()
+    }
+
+    // whether a document was already persisted in the data dir
+    pub fn has_persisted_state(data_dir: &Path) -> bool {
+        persistence::has_persisted_state(&FileStore::new(data_dir))
+    }
+
+    // size in bytes after which the WAL is folded into a new snapshot
+    pub fn set_wal_max_size(&mut self, wal_max_size: usize) {
+        self.last_snapshot.wal_max_size = wal_max_size;
+    }
+
+    // Keeps the fields matching one of the patterns on this node, see local_fields. Fields that changed
+    // category since the patterns were last set are migrated: local fields matching no pattern anymore
+    // are written to the document, fields of the document matching a pattern now are moved to the local
+    // store and deleted from the document, and so from the other members as well.
+    // Returns the number of migrated fields, the document changes still have to be gossiped.
+    pub fn set_no_replicate(&mut self, patterns: Vec<String>) -> Result<usize> {
+        self.local.set_patterns(patterns);
+        let mut migrated = 0;
+        for field_name in self.local.unmatched() {
+            if let Some(field) = self.local.get(&field_name).cloned() {
+                info!("Replicating the local field {}, it matches no no-replicate pattern anymore", field_name);
+                self.set_replicated_field(field_name, field.value)?;
+                migrated += 1;
+            }
+        }
+        let now_local: Vec<(String, String)> = self.replicated_fields()?.into_iter()
+            .filter(|(field_name, _)| self.local.matches(field_name))
+            .collect();
+        for (field_name, field_value) in now_local {
+            info!("Moving the field {} out of the document, it matches a no-replicate pattern", field_name);
+            // a value written locally wins over one that arrived from another member
+            if !self.local.contains(&field_name) {
+                self.local.insert(&field_name, field_value, chrono::Utc::now().timestamp_millis())?;
+            }
+            self.delete_replicated_field(field_name)?;
+            migrated += 1;
+        }
+        // moving a field out of the document publishes its deletion although it is kept
+        self.recount_quotas();
+        Ok(migrated)
+    }
+
+    pub fn doc_schema(&self) -> &DocSchema {
+        &self.schema
+    }
+
+    // Creates the declared containers a persisted document lacks, e.g. after --container was given for the
+    // first time. Returns how many were created, they have to be broadcast like a local write.
+    pub fn create_declared_containers(&mut self) -> Result<usize> {
+        let mut state = self.data.lock().unwrap();
+        let created = containers::create_missing(&mut state, &self.schema)?;
+        if created.is_empty() {
+            return Ok(0);
+        }
+        info!("Created the containers {:?} missing in the document", created);
+        persist(&mut self.writer, &mut self.last_snapshot, &mut self.save_cache, &mut state);
+        self.journal.record(&mut state);
+        drop(state);
+        self.history_stats = None;
+        Ok(created.len())
+    }
+
+    // An entry of a declared container, by key in maps and by index in lists. The fields of the values map
+    // are read like with get_field.
+    pub fn get_container_field(&self, container: &str, field_name: &str) -> Result<Option<String>> {
+        if container == structure::VALUES_KEY {
+            return Ok(self.get_field(field_name.to_owned())?);
+        }
+        let state = self.data.lock().unwrap();
+        let (obj, kind) = containers::container(&state, &self.schema, container)?;
+        let value = match kind {
+            ContainerKind::Map => state.get(&obj, field_name)?,
+            ContainerKind::List => state.get(&obj, list_index(container, field_name)?)?,
+        };
+        Ok(value.map(|(v, id)| value_to_string(&*state, &v, &id, None)))
+    }
+
+    // Writes an entry of a declared container. In lists the index right after the last entry appends one.
+    pub fn set_container_field(&mut self, container: &str, field_name: String, field_value: String) -> Result<()> {
+        if container == structure::VALUES_KEY {
+            return self.set_field(field_name, field_value);
+        }
+        // checked before the container could be recreated, which would leave a pending change behind
+        let index = match self.schema.get(container)?.kind {
+            ContainerKind::Map => {
+                field_names::check(&field_name).map_err(HolyDiverError::InvalidFieldName)?;
+                None
+            },
+            ContainerKind::List => Some(list_index(container, &field_name)?),
+        };
+        self.check_write_quorum()?;
+        self.check_durability()?;
+        self.check_doc_size(estimated_growth(&field_name, Some(&field_value)))?;
+        let mut state = self.data.lock().unwrap();
+        let (obj, _) = containers::container_mut(&mut state, &self.schema, container)?;
+        match index {
+            None => state.put(&obj, field_name.as_str(), field_value)?,
+            Some(index) => {
+                let length = state.length(&obj);
+                if index < length {
+                    state.put(&obj, index, field_value)?;
+                } else if index == length {
+                    state.insert(&obj, index, field_value)?;
+                } else {
+                    return Err(HolyDiverError::InvalidQuery(format!("the list {} has {} entries, {} is beyond its end", container, length, index)).into());
+                }
+            },
+        }
+        persist(&mut self.writer, &mut self.last_snapshot, &mut self.save_cache, &mut state);
+        self.journal.record(&mut state);
+        drop(state);
+        self.last_local_write = Some(chrono::Utc::now().timestamp_millis());
+        self.history_stats = None;
+        Ok(())
+    }
+
+    // Deletes an entry of a declared container, the following entries of a list move up by one.
+    pub fn delete_container_field(&mut self, container: &str, field_name: String) -> Result<()> {
+        if container == structure::VALUES_KEY {
+            return self.delete_field(field_name);
+        }
+        let index = match self.schema.get(container)?.kind {
+            ContainerKind::Map => None,
+            ContainerKind::List => Some(list_index(container, &field_name)?),
+        };
+        self.check_write_quorum()?;
+        self.check_durability()?;
+        let mut state = self.data.lock().unwrap();
+        let (obj, _) = containers::container_mut(&mut state, &self.schema, container)?;
+        match index {
+            None => state.delete(&obj, field_name.as_str())?,
+            Some(index) => {
+                if index >= state.length(&obj) {
+                    return Err(HolyDiverError::FieldNotFound(field_name).into());
+                }
+                state.delete(&obj, index)?;
+            },
+        }
+        persist(&mut self.writer, &mut self.last_snapshot, &mut self.save_cache, &mut state);
+        self.journal.record(&mut state);
+        drop(state);
+        self.last_local_write = Some(chrono::Utc::now().timestamp_millis());
+        self.history_stats = None;
+        Ok(())
+    }
+}
+
+// the entries of list containers are addressed by their index
+fn list_index(container: &str, field_name: &str) -> Result<usize, HolyDiverError> {
+    field_name.parse().map_err(|_| HolyDiverError::InvalidQuery(
+        format!("the entries of the list {} are addressed by index, got {}", container, field_name)))
+}
+
+pub const DEFAULT_WAL_MAX_SIZE: usize = 16 * 1024 * 1024;
+
+// heads and size of the last full save, used to tell how much changed since then,
+// and the state of the WAL written since
+struct SnapshotInfo {
+    heads: Vec<ChangeHash>,
+    size: usize,
+    // heads of the document as of the last WAL record
+    wal_heads: Vec<ChangeHash>,
+    wal_size: usize,
+    wal_max_size: usize,
+    force_snapshot: bool,
+}
+
+// The visible fields of the document starting with prefix from start on in key order, as of heads if given.
+// Strings are borrowed from the document, only other values are converted.
+fn replicated_range<'a>(state: &'a AutoCommit, start: Bound<String>, prefix: &'a str, heads: Option<&'a [ChangeHash]>)
+    -> Result<impl Iterator<Item = (&'a str, Cow<'a, str>)> + 'a, DocStructureError> {
+    let values = structure::values_map(state)?;
+    let values_meta = tombstones::values_meta_map(state);
+    let range: Box<dyn Iterator<Item = (&'a str, automerge::Value<'a>, ObjId)> + 'a> = match heads {
+        Some(heads) => Box::new(state.map_range_at(&values, (start, Bound::Unbounded), heads)),
+        None => Box::new(state.map_range(&values, (start, Bound::Unbounded))),
+    };
+    Ok(range.take_while(move |(key, _, _)| key.starts_with(prefix))
+        .filter(move |(key, _, id)| !tombstones::is_hidden(state, values_meta.as_ref(), key, id))
+        .map(move |(key, value, id)| match &value {
+            automerge::Value::Scalar(Cow::Borrowed(ScalarValue::Str(s))) => (key, Cow::Borrowed(s.as_str())),
+            _ => (key, Cow::Owned(value_to_string(state, &value, &id, heads))),
+        }))
+}
+
+// Appends the changes since the last persist to the WAL, or writes a full snapshot once the WAL
+// got too large or lost records.
+#[tracing::instrument(skip_all)]
+// the time spent here is the persistence phase of requests, see phases
+fn persist(writer: &mut StateWriter, snapshot: &mut SnapshotInfo, save_cache: &mut SaveCache, doc: &mut AutoCommit) {
+    phases::enter(Phase::Persistence);
+    write_changes(writer, snapshot, save_cache, doc);
+    phases::enter(Phase::Apply);
+}
+
+fn write_changes(writer: &mut StateWriter, snapshot: &mut SnapshotInfo, save_cache: &mut SaveCache, doc: &mut AutoCommit) {
+    if snapshot.force_snapshot || snapshot.wal_size >= snapshot.wal_max_size || writer.needs_snapshot() {
+        let data = save_cache.save(doc);
+        snapshot.heads = doc.get_heads();
+        snapshot.size = data.len();
+        snapshot.wal_size = 0;
+        snapshot.force_snapshot = false;
+        writer.write_snapshot(data);
+    } else {
+        let changes: Vec<u8> = doc.get_changes(&snapshot.wal_heads)
+            .map(|cs| cs.iter().flat_map(|c| c.raw_bytes().iter().copied()).collect())
+            .unwrap_or_default();
+        if changes.is_empty() {
+            return;
+        }
+        snapshot.wal_size += writer.append(&changes);
+    }
+    snapshot.wal_heads = doc.get_heads();
+}
+
+
+fn get_initial_state(identity: ID, schema: &DocSchema) -> Result<AutoCommit, AutomergeError> {
+    let mut state = AutoCommit::new()
+    .with_actor(ActorId::from(format!("{:?}", identity).as_bytes()));
+    for container in schema.containers() {
+        state.put_object(ROOT, container.name.as_str(), container.kind.obj_type())?;
+    }
+    state.put_object(ROOT, "values_meta", ObjType::Map)?;
+    Ok(state)
+}
+
+/// The seeds a node announces to. Only their addresses are kept: every announce is sent to an ID with a fresh
+/// bump, which foca accepts by address, see ID::has_same_prefix. A bump cached from before would be a distinct
+/// identity to foca once the seed renewed its own, e.g. while this node was partitioned from it.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct AnnounceTargets(pub Vec<SocketAddr>);
+
+impl From<Vec<SocketAddr>> for AnnounceTargets {
+    fn from(addrs: Vec<SocketAddr>) -> Self {
+        AnnounceTargets(addrs)
+    }
+}
+
+// the seed as an ID like FocaRuntimeConfig::new used to take it, only its address is kept
+impl From<Option<ID>> for AnnounceTargets {
+    fn from(seed: Option<ID>) -> Self {
+        AnnounceTargets(seed.into_iter().map(|seed| seed.addr).collect())
+    }
+}
+
+pub struct FocaRuntimeConfig {
+    pub identity: ID,
+    pub data_dir: PathBuf,
+    // where seen operation ids, the node id and the known peers are kept, the files of data_dir by default
+    pub store: SharedStateStore,
+    pub bind_addr: SocketAddr,
+    // the seeds announced to on startup and until the node joined, by address, see AnnounceTargets
+    pub announce_to: Vec<SocketAddr>,
+    pub foca_config: Config,
+    // upper bound of remembered operation ids, which also bounds the size of seen_ops.bin
+    pub seen_ops_max_entries: usize,
+    // settings that can be changed while running, shared with the REST server
+    pub runtime_settings: SharedRuntimeSettings,
+    // when set, all inbound and outbound datagrams are written to capture files in this dir
+    pub capture_dir: Option<PathBuf>,
+    // size in bytes after which a new capture file is started
+    pub capture_max_file_size: u64,
+    // members going up and down are published here, shared with the REST server
+    pub member_events: MemberEventSender,
+    // a warning is logged when more broadcasts than this are pending for over a minute
+    pub broadcast_backlog_warning: usize,
+    // StartupMessages of the same node are answered with the full state at most once within this window
+    pub startup_reply_window: Duration,
+    // serialization of the SWIM messages, has to be the same on all members
+    pub swim_codec: SwimCodec,
+    // envelope version of the datagrams this node sends, see envelope
+    pub wire_version: u8,
+    // with announce_to set, the process exits if no other member is up within this time
+    pub join_timeout: Option<Duration>,
+    // the latency of the members is probed this often, not at all if None
+    pub probe_interval: Option<Duration>,
+    // don't announce to the peers remembered from before the restart, see known_peers
+    pub forget_peers: bool,
+    // the checksum of the values is gossiped this often, not at all if None
+    pub checksum_interval: Option<Duration>,
+    // handlers of custom message types, see with_message_handler
+    pub message_handlers: Vec<(MessageKind, Box<dyn DataHandler + Send>)>,
+    // datagrams pass this on their way to and from the socket, see chaos; shared with the REST server
+    pub fault_injector: Option<Arc<FaultInjector>>,
+    // received broadcasts that can wait to be merged before further ones are coalesced or dropped, see merge_queue
+    pub merge_queue_size: usize,
+    // how the identity picks its bump when foca renews it, see bump_history
+    pub bump_strategy: BumpStrategy,
+    // the broadcasts of this node carry hop trails and received ones are extended, not at all if None, see hops
+    pub hop_tracing: Option<HopTracing>,
+    // the top-level containers of the document, only the values map by default, see containers. The data handler
+    // has to be opened with the same schema, see HolyDiverDataHandler::open_with_schema
+    pub doc_schema: DocSchema,
+    // per-peer budgets of received datagrams and broadcasts, not limited by default, see inbound_limits
+    pub inbound_limits: InboundLimits,
+    // the number of active members is kept here, shared with the WriteQuorum of the data handler
+    pub member_count: MemberCount,
+    // members coming up have to prove their identity with this key, not checked if None, see strict_identity
+    pub strict_identity: Option<ClusterKey>,
+}
+
+impl FocaRuntimeConfig {
+    pub fn new(identity: ID, data_dir: PathBuf, bind_addr: SocketAddr, announce_to: impl Into<AnnounceTargets>, foca_config: Config) -> Self {
+        FocaRuntimeConfig {
+            identity,
+            store: FileStore::shared(data_dir.clone()),
+            data_dir,
+            bind_addr,
+            announce_to: announce_to.into().0,
+            foca_config,
+            seen_ops_max_entries: 10_000,
+            runtime_settings: RuntimeSettings::default().shared(),
+            capture_dir: None,
+            capture_max_file_size: 64 * 1024 * 1024,
+            member_events: events::member_channel(events::DEFAULT_QUEUE_SIZE),
+            broadcast_backlog_warning: 100,
+            startup_reply_window: DEFAULT_STARTUP_REPLY_WINDOW,
+            swim_codec: SwimCodec::default(),
+            wire_version: ENVELOPE_VERSION,
+            join_timeout: None,
+            probe_interval: None,
+            forget_peers: false,
+            checksum_interval: None,
+            message_handlers: Vec::new(),
+            fault_injector: None,
+            merge_queue_size: DEFAULT_MERGE_QUEUE_SIZE,
+            bump_strategy: BumpStrategy::default(),
+            hop_tracing: None,
+            doc_schema: DocSchema::default(),
+            inbound_limits: InboundLimits::default(),
+            member_count: MemberCount::new(),
+            strict_identity: None,
+        }
+    }
+
+    // Hands received broadcasts of the custom message type kind to handler, see Handler::register and
+    // HolyDiverController::broadcast_custom. Kinds start at FIRST_CUSTOM_KIND, setup_foca fails otherwise.
+    pub fn with_message_handler(mut self, kind: MessageKind, handler: Box<dyn DataHandler + Send>) -> Self {
+        self.message_handlers.push((kind, handler));
+        self
+    }
+}
+
+// Share of foca's max_packet_size a single state broadcast may take up, the rest is left to SWIM messages
+pub const DEFAULT_GOSSIP_BUDGET_PERCENT: usize = 60;
+
+pub fn gossip_budget(foca_config: &Config, percent: usize) -> usize {
+    foca_config.max_packet_size.get() * percent / 100
+}
+
+#[derive(Clone)]
+pub struct HolyDiverController {
+    pub foca_command_sender: Sender<FocaCommand>,
+    pub data_handler: Arc<Mutex<HolyDiverDataHandler>>,
+    // state broadcasts of local changes go through here, see BroadcastCoalescer
+    pub broadcasts: Arc<BroadcastCoalescer>,
+}
+
+impl HolyDiverController {
+    // the data handler, locking it ends the lock acquisition phase of a request, see phases
+    fn data(&self) -> MutexGuard<'_, HolyDiverDataHandler> {
+        let handler = self.data_handler.lock().unwrap();
+        phases::enter(Phase::Apply);
+        handler
+    }
+
+    pub fn get_field(&self, field_name: String) -> Result<Option<String>, DocStructureError> {
+        self.data().get_field(field_name)
+    }
+
+    pub fn get_container_field(&self, container: &str, field_name: &str) -> Result<Option<String>> {
+        self.data().get_container_field(container, field_name)
+    }
+
+    pub fn version(&self) -> String {
+        self.data().version()
+    }
+
+    pub fn resolve_version(&self, version: &str) -> Option<Vec<ChangeHash>> {
+        self.data().resolve_version(version)
+    }
+
+    pub fn get_field_at(&self, field_name: String, heads: &[ChangeHash]) -> Result<Option<String>> {
+        self.data().get_field_at(field_name, heads)
+    }
+
+    pub fn heads(&self) -> Vec<ChangeHash> {
+        self.data().heads()
+    }
+
+    pub fn values_readable(&self) -> Result<(), DocStructureError> {
+        self.data().values_readable()
+    }
+
+    pub fn for_each_field_at(&self, prefix: &str, after: Option<&str>, heads: Option<&[ChangeHash]>,
+        f: impl FnMut(&str, &str) -> ControlFlow<()>) -> Result<(), DocStructureError> {
+        self.data().for_each_field_at(prefix, after, heads, f)
+    }
+
+    pub fn validator(&self) -> Option<Arc<Validator>> {
+        self.data().validator()
+    }
+
+    pub fn stats(&self) -> DocStats {
+        self.data().stats()
+    }
+
+    pub fn persistence_status(&self) -> PersistenceStatus {
+        self.data().persistence_status()
+    }
+
+    pub fn get_conflicts(&self, field_name: String) -> Result<Vec<FieldConflict>, DocStructureError> {
+        self.data().get_conflicts(field_name)
+    }
+
+    pub fn get_fields(&self, field_names: &[String]) -> Result<FieldValues, DocStructureError> {
+        self.data().get_fields(field_names)
+    }
+
+    pub fn keys_with_prefix(&self, prefix: &str) -> Result<Vec<String>, DocStructureError> {
+        self.data().keys_with_prefix(prefix)
+    }
+
+    pub fn get_all_fields(&self) -> Result<BTreeMap<String, String>, DocStructureError> {
+        self.data().get_all_fields()
+    }
+
+    pub fn tombstones(&self) -> Result<BTreeMap<String, Tombstone>, DocStructureError> {
+        self.data().tombstones()
+    }
+
+    // all fields as of one version, see HolyDiverDataHandler::snapshot
+    pub fn snapshot(&self, include_deleted: bool, meta: bool) -> Result<StateSnapshot, DocStructureError> {
+        self.data().snapshot(include_deleted, meta)
+    }
+
+    pub fn actors(&self) -> BTreeMap<String, ActorSummary> {
+        self.data().actors()
+    }
+
+    // merged fields breaking the field name rules, see field_names
+    pub fn invalid_fields(&self) -> InvalidFieldReport {
+        self.data().invalid_fields()
+    }
+
+    // field change events, e.g. local writes overridden by merges
+    pub fn subscribe(&self) -> FieldChangeReceiver {
+        self.data().subscribe()
+    }
+
+    pub fn get_field_with_meta(&self, field_name: String) -> Result<Option<FieldMeta>, DocStructureError> {
+        self.data().get_field_with_meta(field_name)
+    }
+
+    #[tracing::instrument(skip_all, fields(field = %field_name))]
+    pub async fn set_field(&mut self, field_name: String, field_value: String) -> Result<()> {
+        let started = Instant::now();
+        if let Some(heads_before) = self.write_field(field_name, field_value)? {
+            self.broadcasts.request(Some(heads_before))?;
+        }
+        // only successful writes are observed, rejected ones would skew the latencies
+        metrics::SET_FIELD_BROADCAST_SECONDS.observe(started.elapsed().as_secs_f64());
+        Ok(())
+    }
+
+    // Writes like set_field but broadcasts the change right away, asking the receivers to acknowledge it.
+    // The returned receiver resolves with the address of the first member that applied the change.
+    // None if the change exceeded the gossip budget and is left to anti-entropy.
+    // The receiver is awaited without holding the controller, so that other requests are not blocked.
+    pub async fn set_field_replicated(&mut self, field_name: String, field_value: String) -> Result<Option<tokio::sync::oneshot::Receiver<SocketAddr>>> {
+        if envelope::send_version() < ACKED_OPERATION_VERSION {
+            anyhow::bail!("acknowledged writes need protocol version {}, this node sends version {}", ACKED_OPERATION_VERSION, envelope::send_version());
+        }
+        let origin = self.status().await?.identity;
+        let heads_before = match self.write_field(field_name, field_value)? {
+            Some(heads_before) => heads_before,
+            // nobody else gets local fields to acknowledge
+            None => return Ok(None),
+        };
+        let operation_id = Uuid::new_v4();
+        let (waiter, ack) = tokio::sync::oneshot::channel();
+        // the waiter is registered first so that an ack can't arrive before it
+        self.foca_command_sender.try_send(FocaCommand::AwaitAck(operation_id, waiter)).map_err(|e| match e {
+            TrySendError::Full(_) => anyhow::Error::from(HolyDiverError::BroadcastBackpressure),
+            TrySendError::Closed(_) => anyhow::anyhow!("foca is not running anymore"),
+        })?;
+        let sent = self.broadcasts.request_acked(Some(heads_before), operation_id, origin)?;
+        Ok(sent.then_some(ack))
+    }
+
+    // Stages the operations of build and applies them like apply_batch. Nothing is applied if build fails.
+    // build runs synchronously with the data handler unlocked, the broadcast is only requested afterwards.
+    pub async fn transaction<F>(&mut self, build: F) -> Result<BatchSummary>
+    where F: FnOnce(&mut Transaction) -> Result<()> {
+        let mut transaction = Transaction::new();
+        build(&mut transaction)?;
+        self.apply_batch(transaction.into_ops()).await
+    }
+
+    // Applies the operations as a single change with a single persist and a single broadcast, see batch
+    #[tracing::instrument(skip_all, fields(ops = ops.len()))]
+    pub async fn apply_batch(&mut self, ops: Vec<BatchOp>) -> Result<BatchSummary> {
+        let (heads_before, summary) = {
+            let mut handler = self.data();
+            let heads_before = handler.heads();
+            let summary = handler.apply_batch(ops)?;
+            // batches of local fields only leave the document untouched
+            let changed = handler.heads() != heads_before;
+            (changed.then_some(heads_before), summary)
+        };
+        if let Some(heads_before) = heads_before {
+            self.broadcasts.request(Some(heads_before))?;
+        }
+        Ok(summary)
+    }
+
+    // applies a local write, returns the heads before it or None for local fields
+    fn write_field(&self, field_name: String, field_value: String) -> Result<Option<Vec<ChangeHash>>> {
+        let mut handler = self.data();
+        let heads_before = handler.replicates(&field_name).then(|| handler.heads());
+        handler.set_field(field_name, field_value)?;
+        Ok(heads_before)
+    }
+
+    // whether writes of the field are gossiped, see local_fields
+    pub fn replicates(&self, field_name: &str) -> bool {
+        self.data().replicates(field_name)
+    }
+
+    pub fn local_field_names(&self) -> BTreeSet<String> {
+        self.data().local_field_names()
+    }
+
+    pub fn quota_usage(&self) -> Vec<QuotaUsage> {
+        self.data().quota_usage()
+    }
+
+    #[tracing::instrument(skip_all, fields(field = %field_name))]
+    pub async fn delete_field(&mut self, field_name: String) -> Result<()> {
+        let heads_before = {
+            let mut handler = self.data();
+            let heads_before = handler.replicates(&field_name).then(|| handler.heads());
+            handler.delete_field(field_name)?;
+            heads_before
+        };
+        match heads_before {
+            Some(heads_before) => self.broadcasts.request(Some(heads_before)),
+            None => Ok(()),
+        }
+    }
+
+    // Writes an entry of a declared container and gossips it, the values map is written like with set_field
+    #[tracing::instrument(skip_all, fields(container = %container, field = %field_name))]
+    pub async fn set_container_field(&mut self, container: String, field_name: String, field_value: String) -> Result<()> {
+        if container == structure::VALUES_KEY {
+            return self.set_field(field_name, field_value).await;
+        }
+        let heads_before = {
+            let mut handler = self.data();
+            let heads_before = handler.heads();
+            handler.set_container_field(&container, field_name, field_value)?;
+            heads_before
+        };
+        self.broadcasts.request(Some(heads_before))
+    }
+
+    #[tracing::instrument(skip_all, fields(container = %container, field = %field_name))]
+    pub async fn delete_container_field(&mut self, container: String, field_name: String) -> Result<()> {
+        if container == structure::VALUES_KEY {
+            return self.delete_field(field_name).await;
+        }
+        let heads_before = {
+            let mut handler = self.data();
+            let heads_before = handler.heads();
+            handler.delete_container_field(&container, field_name)?;
+            heads_before
+        };
+        self.broadcasts.request(Some(heads_before))
+    }
+
+    // Sets the field back to its previous value (or deletes it if it was deleted before) using the regular
+    // write paths so the revert is gossiped like any other write. Returns None if there is nothing to revert to.
+    #[tracing::instrument(skip_all, fields(field = %field_name))]
+    pub async fn revert_field(&mut self, field_name: String) -> Result<Option<Option<String>>> {
+        let previous_value = self.data().previous_value(field_name.clone())?;
+        match &previous_value {
+            Some(Some(value)) => self.set_field(field_name, value.to_owned()).await?,
+            Some(None) => self.delete_field(field_name).await?,
+            None => {},
+        }
+        Ok(previous_value)
+    }
+
+    // Lease operations are written with the identity address of this node as holder and gossiped
+    // like writes, see leases for why they are advisory only
+    pub async fn acquire_lease(&mut self, name: &str, ttl: Duration) -> Result<Lease> {
+        let holder = self.status().await?.identity.to_string();
+        let (heads_before, lease) = {
+            let mut handler = self.data();
+            (handler.heads(), handler.acquire_lease(name, &holder, ttl)?)
+        };
+        self.broadcasts.request(Some(heads_before))?;
+        Ok(lease)
+    }
+
+    pub async fn renew_lease(&mut self, name: &str, ttl: Duration) -> Result<Lease> {
+        let holder = self.status().await?.identity.to_string();
+        let (heads_before, lease) = {
+            let mut handler = self.data();
+            (handler.heads(), handler.renew_lease(name, &holder, ttl)?)
+        };
+        self.broadcasts.request(Some(heads_before))?;
+        Ok(lease)
+    }
+
+    pub async fn release_lease(&mut self, name: &str) -> Result<()> {
+        let holder = self.status().await?.identity.to_string();
+        let heads_before = {
+            let mut handler = self.data();
+            let heads_before = handler.heads();
+            handler.release_lease(name, &holder)?;
+            heads_before
+        };
+        self.broadcasts.request(Some(heads_before))
+    }
+
+    // the whole current document as it would be written to disk
+    pub fn export(&self) -> Bytes {
+        self.data().get_state()
+    }
+
+    // Merges a serialized document and gossips the result. Being a merge, this adds the changes of
+    // the imported document but never discards changes made after it was exported.
+    #[tracing::instrument(skip_all)]
+    pub async fn import(&mut self, data: &[u8]) -> Result<()> {
+        let heads_before = {
+            let mut handler = self.data();
+            let heads_before = handler.heads();
+            handler.import(data)?;
+            heads_before
+        };
+        self.broadcasts.request(Some(heads_before))
+    }
+
+    // waits until all local and merged changes so far are written to disk
+    pub async fn flush(&self) {
+        let flush_handle = self.data().flush_handle();
+        flush_handle.wait().await;
+    }
+
+    // gossips the whole current document, e.g. after it was changed before foca was running
+    #[tracing::instrument(skip_all)]
+    pub async fn broadcast_current_state(&self) -> Result<()> {
+        self.broadcasts.request(None)
+    }
+
+    // sends a pending coalesced broadcast right away instead of waiting for the interval
+    pub fn flush_broadcast(&self) -> Result<()> {
+        self.broadcasts.flush()
+    }
+
+    // Sends the message to a single member instead of gossiping it. The receiver applies it but
+    // does not pass it on.
+    pub async fn send_to(&self, to: ID, message: GossipMessage) -> Result<()> {
+        self.foca_command_sender.try_send(FocaCommand::SendDirect(to, SyncOperation {
+            operation_id: Uuid::new_v4()
+        }, message)).map_err(|e| match e {
+            TrySendError::Full(_) => anyhow::Error::from(HolyDiverError::BroadcastBackpressure),
+            TrySendError::Closed(_) => anyhow::anyhow!("foca is not running anymore"),
+        })?;
+        Ok(())
+    }
+
+    // Gossips payload as a message of the custom type kind, received by the handlers registered for it
+    // on the other members, see FocaRuntimeConfig::with_message_handler. The message is not handed to the
+    // handler of this node.
+    pub async fn broadcast_custom(&self, kind: MessageKind, payload: impl Into<Bytes>) -> Result<()> {
+        if kind < FIRST_CUSTOM_KIND {
+            anyhow::bail!("message kind {} is reserved for built-in message types, custom ones start at {}", kind, FIRST_CUSTOM_KIND);
+        }
+        let tag = SyncOperation { operation_id: Uuid::new_v4() };
+        let message = GossipMessage::new(MessageType::Custom(kind), payload);
+        let size = broadcast_size(&tag, &message);
+        if size > self.broadcasts.gossip_budget() {
+            anyhow::bail!("the message of {} bytes exceeds the gossip budget of {} bytes", size, self.broadcasts.gossip_budget());
+        }
+        self.foca_command_sender.try_send(FocaCommand::SendBroadcast((tag, message))).map_err(|e| match e {
+            TrySendError::Full(_) => anyhow::Error::from(HolyDiverError::BroadcastBackpressure),
+            TrySendError::Closed(_) => anyhow::anyhow!("foca is not running anymore"),
+        })
+    }
+
+    // Syncs with a single member right away instead of waiting for gossip: Full sends the whole document,
+    // Changes asks the member for its heads so that it is sent the journaled changes it misses, like a member
+    // coming back. Targets that are no active member are rejected unless forced, e.g. for a member foca
+    // already declared down.
+    pub async fn sync_with(&self, target: SocketAddr, mode: SyncMode, force: bool) -> Result<SyncReport> {
+        if !force && !self.members().await?.contains(&target) {
+            return Err(HolyDiverError::UnknownMember(target.to_string()).into());
+        }
+        let message = match mode {
+            SyncMode::Full => GossipMessage::new(FullSync, self.export()),
+            SyncMode::Changes => GossipMessage::new(HeadsRequest, Vec::new()),
+            SyncMode::Session => match self.data().start_sync_session(target) {
+                Some(message) => message,
+                None => return Ok(SyncReport { target, mode, enqueued: false, bytes: 0 }),
+            },
+        };
+        let tag = SyncOperation { operation_id: Uuid::new_v4() };
+        let bytes = direct_message_size(&tag, &message);
+        let mut report = SyncReport { target, mode, enqueued: false, bytes };
+        if bytes > MAX_DATAGRAM_SIZE {
+            warn!("Not syncing with {}, the document of {} bytes does not fit into a datagram", target, bytes);
+            return Ok(report);
+        }
+        match self.foca_command_sender.try_send(FocaCommand::SendDirect(ID::new(target), tag, message)) {
+            Ok(()) => report.enqueued = true,
+            Err(TrySendError::Full(_)) => warn!("Not syncing with {}, the send queue is full", target),
+            Err(TrySendError::Closed(_)) => anyhow::bail!("foca is not running anymore"),
+        }
+        info!("Sync with {} ({:?}, {} bytes) enqueued: {}", target, mode, bytes, report.enqueued);
+        Ok(report)
+    }
+
+    // Asks a member for its saved document with a direct message, the returned receiver gets it. It is empty if
+    // the document doesn't fit into a datagram, and never completes if the member doesn't answer, e.g. because it
+    // is older and doesn't know StateRequest, so it has to be awaited with a timeout.
+    pub async fn request_peer_state(&self, peer: SocketAddr) -> Result<tokio::sync::oneshot::Receiver<Bytes>> {
+        if !self.members().await?.contains(&peer) {
+            return Err(HolyDiverError::UnknownMember(peer.to_string()).into());
+        }
+        let request_id = Uuid::new_v4();
+        let (waiter, state) = tokio::sync::oneshot::channel();
+        let request = GossipMessage::new(StateRequest, request_id.as_bytes().to_vec());
+        // the waiter is registered first so that the state can't arrive before it
+        for command in [FocaCommand::AwaitState(request_id, waiter),
+            FocaCommand::SendDirect(ID::new(peer), SyncOperation { operation_id: Uuid::new_v4() }, request)] {
+            self.foca_command_sender.try_send(command).map_err(|e| match e {
+                TrySendError::Full(_) => anyhow::Error::from(HolyDiverError::BroadcastBackpressure),
+                TrySendError::Closed(_) => anyhow::anyhow!("foca is not running anymore"),
+            })?;
+        }
+        Ok(state)
+    }
+
+    // Stops foca and its socket, then waits until the state written so far is on disk.
+    // Used to tear down a node whose startup failed after foca was set up.
+    pub async fn shutdown(&self) {
+        if let Err(e) = self.flush_broadcast() {
+            warn!("Could not broadcast pending changes before shutting down: {}", e);
+        }
+        let _ignored_send_error = self.foca_command_sender.send(FocaCommand::Shutdown).await;
+        self.flush().await;
+    }
+
+    // addresses of the active cluster members as known by foca, including this node
+    pub async fn members(&self) -> Result<Vec<SocketAddr>> {
+        let (reply, members) = tokio::sync::oneshot::channel();
+        self.foca_command_sender.try_send(FocaCommand::Members(reply)).map_err(|e| match e {
+            TrySendError::Full(_) => anyhow::Error::from(HolyDiverError::BroadcastBackpressure),
+            TrySendError::Closed(_) => anyhow::anyhow!("foca is not running anymore"),
+        })?;
+        Ok(members.await?)
+    }
+
+    // labels the active members gossiped about themselves, e.g. their version
+    pub async fn member_labels(&self) -> Result<BTreeMap<SocketAddr, NodeLabels>> {
+        let (reply, labels) = tokio::sync::oneshot::channel();
+        self.foca_command_sender.try_send(FocaCommand::MemberLabels(reply)).map_err(|e| match e {
+            TrySendError::Full(_) => anyhow::Error::from(HolyDiverError::BroadcastBackpressure),
+            TrySendError::Closed(_) => anyhow::anyhow!("foca is not running anymore"),
+        })?;
+        Ok(labels.await?)
+    }
+
+    // members that went down within the last day and why, see departures
+    pub async fn departures(&self) -> Result<BTreeMap<SocketAddr, Departure>> {
+        let (reply, departures) = tokio::sync::oneshot::channel();
+        self.foca_command_sender.try_send(FocaCommand::Departures(reply)).map_err(|e| match e {
+            TrySendError::Full(_) => anyhow::Error::from(HolyDiverError::BroadcastBackpressure),
+            TrySendError::Closed(_) => anyhow::anyhow!("foca is not running anymore"),
+        })?;
+        Ok(departures.await?)
+    }
+
+    // unix millis when this node first saw the operation, see SeenOperations
+    pub async fn seen_operation(&self, operation_id: Uuid) -> Result<Option<i64>> {
+        let (reply, seen_at) = tokio::sync::oneshot::channel();
+        self.foca_command_sender.try_send(FocaCommand::SeenOperation(operation_id, reply)).map_err(|e| match e {
+            TrySendError::Full(_) => anyhow::Error::from(HolyDiverError::BroadcastBackpressure),
+            TrySendError::Closed(_) => anyhow::anyhow!("foca is not running anymore"),
+        })?;
+        Ok(seen_at.await?)
+    }
+
+    // operation ids this node remembers with the unix millis when they were first seen, newest first
+    pub async fn seen_operations(&self) -> Result<Vec<(Uuid, i64)>> {
+        let (reply, entries) = tokio::sync::oneshot::channel();
+        self.foca_command_sender.try_send(FocaCommand::SeenOperations(reply)).map_err(|e| match e {
+            TrySendError::Full(_) => anyhow::Error::from(HolyDiverError::BroadcastBackpressure),
+            TrySendError::Closed(_) => anyhow::anyhow!("foca is not running anymore"),
+        })?;
+        Ok(entries.await?)
+    }
+
+    // Forgets the operation id, or all of them if None, so that the operations are applied again when they are
+    // received the next time. Returns the number of forgotten ids.
+    pub async fn forget_operations(&self, operation_id: Option<Uuid>) -> Result<usize> {
+        let (reply, forgotten) = tokio::sync::oneshot::channel();
+        let command = match operation_id {
+            Some(operation_id) => FocaCommand::ForgetOperation(operation_id, reply),
+            None => FocaCommand::ClearSeenOperations(reply),
+        };
+        self.foca_command_sender.try_send(command).map_err(|e| match e {
+            TrySendError::Full(_) => anyhow::Error::from(HolyDiverError::BroadcastBackpressure),
+            TrySendError::Closed(_) => anyhow::anyhow!("foca is not running anymore"),
+        })?;
+        Ok(forgotten.await?)
+    }
+
+    // Gossips the URL of the REST endpoint of this node as label, with the port it is actually bound to and the
+    // IP address of the identity, which is the one the members reach this node by.
+    pub async fn advertise_rest_port(&self, port: u16) -> Result<()> {
+        let identity = self.status().await?.identity;
+        let url = format!("http://{}", SocketAddr::new(identity.ip(), port));
+        self.foca_command_sender.send(FocaCommand::SetLabel(REST_URL_LABEL.to_owned(), url)).await
+            .map_err(|_| anyhow::anyhow!("foca is not running anymore"))
+    }
+
+    // latency probes of the members, None if probing is disabled
+    pub async fn probes(&self) -> Result<Option<BTreeMap<SocketAddr, PeerProbe>>> {
+        let (reply, probes) = tokio::sync::oneshot::channel();
+        self.foca_command_sender.try_send(FocaCommand::Probes(reply)).map_err(|e| match e {
+            TrySendError::Full(_) => anyhow::Error::from(HolyDiverError::BroadcastBackpressure),
+            TrySendError::Closed(_) => anyhow::anyhow!("foca is not running anymore"),
+        })?;
+        Ok(probes.await?)
+    }
+
+    // what the source addresses sent recently and how much of it was dropped, see inbound_limits
+    pub async fn inbound_rates(&self) -> Result<BTreeMap<SocketAddr, PeerInboundRate>> {
+        let (reply, rates) = tokio::sync::oneshot::channel();
+        self.foca_command_sender.try_send(FocaCommand::InboundRates(reply)).map_err(|e| match e {
+            TrySendError::Full(_) => anyhow::Error::from(HolyDiverError::BroadcastBackpressure),
+            TrySendError::Closed(_) => anyhow::anyhow!("foca is not running anymore"),
+        })?;
+        Ok(rates.await?)
+    }
+
+    // the members whose identity is not verified with why, empty without strict identity, see strict_identity
+    pub async fn unverified_members(&self) -> Result<BTreeMap<SocketAddr, Unverified>> {
+        let (reply, unverified) = tokio::sync::oneshot::channel();
+        self.foca_command_sender.try_send(FocaCommand::UnverifiedMembers(reply)).map_err(|e| match e {
+            TrySendError::Full(_) => anyhow::Error::from(HolyDiverError::BroadcastBackpressure),
+            TrySendError::Closed(_) => anyhow::anyhow!("foca is not running anymore"),
+        })?;
+        Ok(unverified.await?)
+    }
+
+    // the checksum of this node and the last ones gossiped by the members
+    pub async fn consistency(&self) -> Result<ConsistencyReport> {
+        let (reply, report) = tokio::sync::oneshot::channel();
+        self.foca_command_sender.try_send(FocaCommand::Consistency(reply)).map_err(|e| match e {
+            TrySendError::Full(_) => anyhow::Error::from(HolyDiverError::BroadcastBackpressure),
+            TrySendError::Closed(_) => anyhow::anyhow!("foca is not running anymore"),
+        })?;
+        Ok(report.await?)
+    }
+
+    pub async fn status(&self) -> Result<FocaStatus> {
+        let (reply, status) = tokio::sync::oneshot::channel();
+        self.foca_command_sender.try_send(FocaCommand::Status(reply)).map_err(|e| match e {
+            TrySendError::Full(_) => anyhow::Error::from(HolyDiverError::BroadcastBackpressure),
+            TrySendError::Closed(_) => anyhow::anyhow!("foca is not running anymore"),
+        })?;
+        Ok(status.await?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    // A handler with a fresh document in a temporary data dir, its actor id is derived from the port. Fresh documents
+    // create their maps concurrently, after a merge those of the handler with the higher port win.
+    fn open(port: u16) -> (TempDir, HolyDiverDataHandler) {
+        let dir = TempDir::new().unwrap();
+        let handler = HolyDiverDataHandler::new(dir.path(), ID::with_bump(SocketAddr::from(([127, 0, 0, 1], port)), 0));
+        (dir, handler)
+    }
+
+    fn merge_into(to: &mut HolyDiverDataHandler, from: &mut HolyDiverDataHandler) {
+        to.handle_message(FullSync, from.get_state()).unwrap();
+    }
+
+    fn actor_of(handler: &HolyDiverDataHandler) -> String {
+        handler.data.lock().unwrap().get_actor().to_string()
+    }
+
+    #[tokio::test]
+    async fn meta_of_a_merged_write_names_the_remote_writer() {
+        let (_remote_dir, mut remote) = open(9002);
+        let (_local_dir, mut local) = open(9001);
+        remote.set_field("color".to_owned(), "red".to_owned()).unwrap();
+        merge_into(&mut local, &mut remote);
+
+        let meta = local.get_field_with_meta("color".to_owned()).unwrap().unwrap();
+        assert_eq!(meta.value, "red");
+        assert_eq!(meta.actor, Some(actor_of(&remote)));
+        assert_eq!(meta.writer.as_deref(), Some("127.0.0.1:9002"));
+        assert!(meta.modified_at.is_some());
+        assert!(meta.change_hash.is_some());
+    }
+
+    #[tokio::test]
+    async fn batch_read_reports_missing_keys_and_reads_duplicates_once() {
+        let (_dir, mut handler) = open(9001);
+        handler.set_field("a".to_owned(), "1".to_owned()).unwrap();
+        handler.set_field("b".to_owned(), "2".to_owned()).unwrap();
+        handler.set_field("gone".to_owned(), "3".to_owned()).unwrap();
+        handler.delete_field("gone".to_owned()).unwrap();
+
+        let read = handler.get_fields(&["a", "missing", "b", "gone", "a", "missing"].map(str::to_owned)).unwrap();
+        assert_eq!(read.values, BTreeMap::from([("a".to_owned(), "1".to_owned()), ("b".to_owned(), "2".to_owned())]));
+        assert_eq!(read.missing, vec!["missing".to_owned(), "gone".to_owned()]);
+    }
+
+    #[tokio::test]
+    async fn concurrent_writes_are_reported_with_the_same_winner_on_both_sides() {
+        let (_a_dir, mut a) = open(9002);
+        let (_b_dir, mut b) = open(9001);
+        merge_into(&mut b, &mut a);
+        a.set_field("color".to_owned(), "red".to_owned()).unwrap();
+        b.set_field("color".to_owned(), "blue".to_owned()).unwrap();
+        merge_into(&mut a, &mut b);
+        merge_into(&mut b, &mut a);
+
+        let winner = |conflicts: &[FieldConflict]| -> Vec<String> {
+            conflicts.iter().filter(|c| c.winner).map(|c| c.value.clone()).collect()
+        };
+        let conflicts_a = a.get_conflicts("color".to_owned()).unwrap();
+        let conflicts_b = b.get_conflicts("color".to_owned()).unwrap();
+        let mut values: Vec<&str> = conflicts_a.iter().map(|c| c.value.as_str()).collect();
+        values.sort();
+        assert_eq!(values, ["blue", "red"]);
+        assert_eq!(winner(&conflicts_a).len(), 1);
+        assert_eq!(winner(&conflicts_a), winner(&conflicts_b));
+        assert_eq!(a.get_field("color".to_owned()).unwrap(), winner(&conflicts_a).pop());
+        assert_eq!(b.get_field("color".to_owned()).unwrap(), winner(&conflicts_b).pop());
+        assert_eq!((a.conflicts_detected(), b.conflicts_detected()), (1, 1));
+    }
+
+    #[tokio::test]
+    async fn a_returning_peer_is_sent_exactly_the_writes_it_missed() {
+        let (_a_dir, mut a) = open(9002);
+        let (_b_dir, mut b) = open(9001);
+        a.set_field("before".to_owned(), "0".to_owned()).unwrap();
+        merge_into(&mut b, &mut a);
+        // b is down while a writes
+        for (field_name, value) in [("one", "1"), ("two", "2"), ("three", "3")] {
+            a.set_field(field_name.to_owned(), value.to_owned()).unwrap();
+        }
+
+        let messages = a.catch_up_messages(&b.heads());
+        assert_eq!(messages.len(), 3);
+        for message in messages {
+            assert_eq!(message.message_type(), IncSync);
+            b.handle_message(IncSync, message.message_payload().clone()).unwrap();
+        }
+        for (field_name, value) in [("before", "0"), ("one", "1"), ("two", "2"), ("three", "3")] {
+            assert_eq!(b.get_field(field_name.to_owned()).unwrap().as_deref(), Some(value));
+        }
+        assert!(a.catch_up_messages(&b.heads()).is_empty());
+    }
+
+    #[tokio::test]
+    async fn a_peer_missing_more_than_the_journal_is_sent_the_document() {
+        let (_a_dir, mut a) = open(9002);
+        let (_b_dir, mut b) = open(9001);
+        a.set_journal_bounds(2, Duration::from_secs(60));
+        merge_into(&mut b, &mut a);
+        for (field_name, value) in [("one", "1"), ("two", "2"), ("three", "3")] {
+            a.set_field(field_name.to_owned(), value.to_owned()).unwrap();
+        }
+
+        let messages = a.catch_up_messages(&b.heads());
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].message_type(), FullSync);
+    }
+
+    #[tokio::test]
+    async fn only_the_losing_side_of_a_race_is_told_its_write_was_overridden() {
+        let (_a_dir, mut a) = open(9002);
+        let (_b_dir, mut b) = open(9001);
+        merge_into(&mut b, &mut a);
+        let a_events = a.subscribe();
+        let b_events = b.subscribe();
+        a.set_field("color".to_owned(), "red".to_owned()).unwrap();
+        b.set_field("color".to_owned(), "blue".to_owned()).unwrap();
+        merge_into(&mut a, &mut b);
+        merge_into(&mut b, &mut a);
+
+        let overrides = |events: &mut FieldChangeReceiver| -> Vec<FieldChange> {
+            std::iter::from_fn(|| events.try_recv().ok())
+                .filter(|change| change.kind == ChangeKind::Overridden)
+                .collect()
+        };
+        let winner = a.get_field("color".to_owned()).unwrap().unwrap();
+        let (mut loser_events, mut winner_events, loser_value) = if winner == "red" {
+            (b_events, a_events, "blue")
+        } else {
+            (a_events, b_events, "red")
+        };
+        let overridden = overrides(&mut loser_events);
+        assert_eq!(overridden.len(), 1);
+        assert_eq!(overridden[0].field, "color");
+        assert_eq!(overridden[0].local_value.as_deref(), Some(loser_value));
+        assert_eq!(overridden[0].winning_value.as_deref(), Some(winner.as_str()));
+        assert!(overrides(&mut winner_events).is_empty());
+    }
+
+    #[tokio::test]
+    async fn a_tombstone_hides_an_older_concurrent_write_until_it_is_purged() {
+        let (_a_dir, mut a) = open(9002);
+        let (_b_dir, mut b) = open(9001);
+        a.set_field("color".to_owned(), "red".to_owned()).unwrap();
+        merge_into(&mut b, &mut a);
+        // b writes before a deletes, neither sees the other
+        b.set_field("color".to_owned(), "blue".to_owned()).unwrap();
+        std::thread::sleep(Duration::from_millis(5));
+        a.delete_field("color".to_owned()).unwrap();
+        let (a_delete, b_write) = (a.get_state(), b.get_state());
+        merge_into(&mut a, &mut b);
+        merge_into(&mut b, &mut a);
+
+        assert_eq!(a.get_field("color".to_owned()).unwrap(), None);
+        assert_eq!(b.get_field("color".to_owned()).unwrap(), None);
+        assert!(a.tombstones().unwrap().contains_key("color"));
+
+        // without the tombstone the same write brings the field back
+        let (_c_dir, mut c) = open(9003);
+        c.handle_message(FullSync, a_delete).unwrap();
+        std::thread::sleep(Duration::from_millis(5));
+        assert_eq!(c.purge_tombstones(Duration::ZERO, 10).unwrap(), 1);
+        assert!(c.tombstones().unwrap().is_empty());
+        c.handle_message(FullSync, b_write).unwrap();
+        assert_eq!(c.get_field("color".to_owned()).unwrap().as_deref(), Some("blue"));
+    }
+
+    #[tokio::test]
+    async fn a_deleted_conflicted_field_reports_no_conflicts() {
+        let (_a_dir, mut a) = open(9002);
+        let (_b_dir, mut b) = open(9001);
+        let (_c_dir, mut c) = open(9003);
+        merge_into(&mut b, &mut a);
+        merge_into(&mut c, &mut a);
+        a.set_field("color".to_owned(), "red".to_owned()).unwrap();
+        b.set_field("color".to_owned(), "blue".to_owned()).unwrap();
+        c.set_field("color".to_owned(), "green".to_owned()).unwrap();
+        merge_into(&mut a, &mut b);
+        assert_eq!(a.get_conflicts("color".to_owned()).unwrap().len(), 2);
+
+        std::thread::sleep(Duration::from_millis(5));
+        a.delete_field("color".to_owned()).unwrap();
+        // the write of c was concurrent with the delete and stays in the values map, hidden by the tombstone
+        merge_into(&mut a, &mut c);
+        assert_eq!(a.get_field("color".to_owned()).unwrap(), None);
+        assert!(a.get_conflicts("color".to_owned()).unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn reads_at_earlier_heads_see_the_values_and_the_tombstone_of_then() {
+        let (_a_dir, mut a) = open(9002);
+        let (_b_dir, mut b) = open(9001);
+        let mut versions = Vec::new();
+        for value in ["v1", "v2", "v3"] {
+            a.set_field("color".to_owned(), value.to_owned()).unwrap();
+            versions.push((a.heads(), Some(value)));
+        }
+        merge_into(&mut b, &mut a);
+        // b writes before a deletes, the merged write stays hidden by the tombstone
+        b.set_field("color".to_owned(), "stale".to_owned()).unwrap();
+        std::thread::sleep(Duration::from_millis(5));
+        a.delete_field("color".to_owned()).unwrap();
+        versions.push((a.heads(), None));
+        merge_into(&mut a, &mut b);
+        versions.push((a.heads(), None));
+
+        for (heads, expected) in versions {
+            assert_eq!(a.get_field_at("color".to_owned(), &heads).unwrap().as_deref(), expected);
+        }
+    }
+
+    #[tokio::test]
+    async fn a_concurrent_write_newer_than_the_delete_wins() {
+        let (_a_dir, mut a) = open(9002);
+        let (_b_dir, mut b) = open(9001);
+        a.set_field("color".to_owned(), "red".to_owned()).unwrap();
+        merge_into(&mut b, &mut a);
+        a.delete_field("color".to_owned()).unwrap();
+        std::thread::sleep(Duration::from_millis(5));
+        b.set_field("color".to_owned(), "blue".to_owned()).unwrap();
+        merge_into(&mut a, &mut b);
+        merge_into(&mut b, &mut a);
+
+        assert_eq!(a.get_field("color".to_owned()).unwrap().as_deref(), Some("blue"));
+        assert_eq!(b.get_field("color".to_owned()).unwrap().as_deref(), Some("blue"));
+    }
+
+    #[tokio::test]
+    async fn writes_of_two_nodes_are_attributed_to_their_addresses() {
+        let (_a_dir, mut a) = open(9002);
+        let (_b_dir, mut b) = open(9001);
+        a.set_field("from_a".to_owned(), "1".to_owned()).unwrap();
+        b.set_field("from_b".to_owned(), "2".to_owned()).unwrap();
+        merge_into(&mut a, &mut b);
+        merge_into(&mut b, &mut a);
+
+        for handler in [&a, &b] {
+            let actors = handler.actors();
+            assert_eq!(actors.len(), 2);
+            let addrs: BTreeMap<String, String> = actors.iter()
+                .map(|(actor, summary)| (actor.clone(), summary.info.as_ref().expect("resolvable actor").addr.to_string()))
+                .collect();
+            assert_eq!(addrs.get(&actor_of(&a)).map(String::as_str), Some("127.0.0.1:9002"));
+            assert_eq!(addrs.get(&actor_of(&b)).map(String::as_str), Some("127.0.0.1:9001"));
+            assert!(actors.values().all(|summary| summary.changes > 0));
+        }
+        let meta = a.get_field_with_meta("from_b".to_owned()).unwrap().unwrap();
+        assert_eq!(meta.writer.as_deref(), Some("127.0.0.1:9001"));
+    }
+
+    #[tokio::test]
+    async fn filtered_fields_are_served_locally_and_never_reach_a_peer() {
+        let (_a_dir, mut a) = open(9002);
+        let (_b_dir, mut b) = open(9001);
+        a.set_no_replicate(vec!["scratch.*".to_owned()]).unwrap();
+        a.set_field("scratch.tmp".to_owned(), "local".to_owned()).unwrap();
+        a.set_field("shared".to_owned(), "replicated".to_owned()).unwrap();
+        merge_into(&mut b, &mut a);
+
+        assert_eq!(a.get_field("scratch.tmp".to_owned()).unwrap().as_deref(), Some("local"));
+        assert_eq!(b.get_field("shared".to_owned()).unwrap().as_deref(), Some("replicated"));
+        assert_eq!(b.get_field("scratch.tmp".to_owned()).unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn fields_changing_category_are_migrated() {
+        let (_a_dir, mut a) = open(9002);
+        let (_b_dir, mut b) = open(9001);
+        a.set_field("cache.hits".to_owned(), "1".to_owned()).unwrap();
+        assert_eq!(a.set_no_replicate(vec!["cache.*".to_owned()]).unwrap(), 1);
+        a.set_field("cache.misses".to_owned(), "2".to_owned()).unwrap();
+        merge_into(&mut b, &mut a);
+        assert_eq!(a.get_field("cache.hits".to_owned()).unwrap().as_deref(), Some("1"));
+        assert_eq!(b.get_field("cache.hits".to_owned()).unwrap(), None);
+
+        // without the pattern both are replicated again
+        assert_eq!(a.set_no_replicate(Vec::new()).unwrap(), 2);
+        merge_into(&mut b, &mut a);
+        assert_eq!(b.get_field("cache.hits".to_owned()).unwrap().as_deref(), Some("1"));
+        assert_eq!(b.get_field("cache.misses".to_owned()).unwrap().as_deref(), Some("2"));
+    }
+
+    #[tokio::test]
+    async fn a_failing_batch_applies_nothing() {
+        let (_dir, mut handler) = open(9001);
+        handler.set_field("count".to_owned(), "1".to_owned()).unwrap();
+        handler.set_field("word".to_owned(), "text".to_owned()).unwrap();
+        let heads = handler.heads();
+
+        let ops = vec![
+            BatchOp::Set { key: "count".to_owned(), value: "2".to_owned() },
+            BatchOp::Increment { key: "word".to_owned(), by: 1 },
+            BatchOp::Delete { key: "word".to_owned() },
+        ];
+        assert!(handler.apply_batch(ops).is_err());
+        assert_eq!(handler.heads(), heads);
+        assert_eq!(handler.get_field("count".to_owned()).unwrap().as_deref(), Some("1"));
+        assert_eq!(handler.get_field("word".to_owned()).unwrap().as_deref(), Some("text"));
+    }
+
+    #[tokio::test]
+    async fn every_write_path_of_the_handler_checks_the_schema() {
+        let (_dir, mut handler) = open(9001);
+        let schema = serde_json::from_value(serde_json::json!({"count": {"type": "int"}})).unwrap();
+        handler.set_validator(Arc::new(Validator::new(schema).unwrap()));
+        let violation = |result: Result<()>| matches!(result.unwrap_err().downcast_ref::<HolyDiverError>(), Some(HolyDiverError::SchemaViolation(_)));
+
+        assert!(violation(handler.set_field("count".to_owned(), "many".to_owned())));
+        assert!(violation(handler.set_fields(vec![
+            ("color".to_owned(), "red".to_owned()),
+            ("count".to_owned(), "many".to_owned()),
+        ])));
+        assert!(violation(handler.apply_batch(vec![
+            BatchOp::Set { key: "count".to_owned(), value: "many".to_owned() },
+        ]).map(|_| ())));
+        assert_eq!(handler.get_field("color".to_owned()).unwrap(), None);
+        assert_eq!(handler.get_field("count".to_owned()).unwrap(), None);
+
+        handler.set_field("count".to_owned(), "3".to_owned()).unwrap();
+        assert_eq!(handler.get_field("count".to_owned()).unwrap().as_deref(), Some("3"));
+    }
+
+    #[tokio::test]
+    async fn a_batch_reaches_a_peer_as_a_single_change() {
+        let (_a_dir, mut a) = open(9002);
+        let (_b_dir, mut b) = open(9001);
+        a.set_field("gone".to_owned(), "soon".to_owned()).unwrap();
+        merge_into(&mut b, &mut a);
+
+        let summary = a.apply_batch(vec![
+            BatchOp::Set { key: "color".to_owned(), value: "red".to_owned() },
+            BatchOp::Delete { key: "gone".to_owned() },
+            BatchOp::Increment { key: "visits".to_owned(), by: 3 },
+        ]).unwrap();
+        assert_eq!(summary.applied, 3);
+        assert_eq!(summary.values, BTreeMap::from([
+            ("color".to_owned(), Some("red".to_owned())),
+            ("gone".to_owned(), None),
+            ("visits".to_owned(), Some("3".to_owned())),
+        ]));
+
+        let messages = a.catch_up_messages(&b.heads());
+        assert_eq!(messages.len(), 1);
+        b.handle_message(IncSync, messages[0].message_payload().clone()).unwrap();
+        assert_eq!(b.get_field("color".to_owned()).unwrap().as_deref(), Some("red"));
+        assert_eq!(b.get_field("gone".to_owned()).unwrap(), None);
+        assert_eq!(b.get_field("visits".to_owned()).unwrap().as_deref(), Some("3"));
+    }
+
+    #[tokio::test]
+    async fn a_full_quota_rejects_new_keys_until_one_is_deleted() {
+        let (dir, mut handler) = open(9001);
+        let quota = Quota { pattern: "app.*".to_owned(), max_keys: Some(2), max_bytes: None };
+        handler.set_quotas(vec![quota.clone()]);
+        handler.set_field("app.a".to_owned(), "1".to_owned()).unwrap();
+        handler.set_field("app.b".to_owned(), "2".to_owned()).unwrap();
+        handler.set_field("other".to_owned(), "3".to_owned()).unwrap();
+
+        let rejected = handler.set_field("app.c".to_owned(), "3".to_owned()).unwrap_err();
+        match rejected.downcast_ref::<HolyDiverError>() {
+            Some(HolyDiverError::QuotaExceeded(exceeded)) => {
+                assert_eq!(exceeded.limit, crate::swim::quotas::QuotaLimit::MaxKeys);
+                assert_eq!((exceeded.usage.keys, exceeded.requested.keys), (2, 3));
+            },
+            other => panic!("expected the quota to be exceeded, got {:?}", other),
+        }
+        assert_eq!(handler.get_field("app.c".to_owned()).unwrap(), None);
+        // overwriting adds no key
+        handler.set_field("app.a".to_owned(), "11".to_owned()).unwrap();
+        handler.delete_field("app.b".to_owned()).unwrap();
+        handler.set_field("app.c".to_owned(), "3".to_owned()).unwrap();
+        let usage = handler.quota_usage();
+        assert_eq!(usage[0].usage.keys, 2);
+        assert_eq!(usage[0].usage.bytes, ("app.a11".len() + "app.c3".len()) as u64);
+
+        // the usage is counted again from the persisted state
+        handler.flush_handle().wait().await;
+        drop(handler);
+        let mut restarted = HolyDiverDataHandler::new(dir.path(), ID::with_bump(SocketAddr::from(([127, 0, 0, 1], 9001)), 1));
+        restarted.set_quotas(vec![quota]);
+        assert_eq!(restarted.quota_usage()[0].usage, usage[0].usage);
+        assert!(restarted.set_field("app.d".to_owned(), "4".to_owned()).is_err());
+    }
+
+    #[tokio::test]
+    async fn merging_a_document_without_values_map_keeps_the_fields_served() {
+        let (_dir, mut handler) = open(9001);
+        handler.set_field("color".to_owned(), "red".to_owned()).unwrap();
+        // a peer that never saw the values map of the node puts a scalar in its place
+        let mut malformed = AutoCommit::new();
+        malformed.put(ROOT, structure::VALUES_KEY, "oops").unwrap();
+        malformed.put(ROOT, "unexpected", 1).unwrap();
+        handler.handle_message(FullSync, malformed.save().into()).unwrap();
+
+        assert_eq!(handler.get_field("color".to_owned()).unwrap().as_deref(), Some("red"));
+        handler.set_field("size".to_owned(), "xl".to_owned()).unwrap();
+        assert_eq!(handler.get_field("size".to_owned()).unwrap().as_deref(), Some("xl"));
+    }
+
+    #[tokio::test]
+    async fn a_replaced_values_map_fails_reads_and_is_recreated_by_writes() {
+        let (_dir, mut handler) = open(9001);
+        handler.set_field("color".to_owned(), "red".to_owned()).unwrap();
+        let mut malformed = AutoCommit::load(&handler.get_state()).unwrap();
+        malformed.put(ROOT, structure::VALUES_KEY, "oops").unwrap();
+        handler.handle_message(FullSync, malformed.save().into()).unwrap();
+
+        assert!(matches!(handler.get_field("color".to_owned()), Err(DocStructureError::ValuesNotAMap(_))));
+        handler.set_field("size".to_owned(), "xl".to_owned()).unwrap();
+        assert_eq!(handler.get_field("size".to_owned()).unwrap().as_deref(), Some("xl"));
+        assert_eq!(handler.get_field("color".to_owned()).unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn the_value_a_policy_prefers_wins_on_every_node() {
+        let (_a_dir, mut a) = open(9002);
+        let (_b_dir, mut b) = open(9001);
+        let (_c_dir, mut c) = open(9003);
+        for handler in [&mut a, &mut b] {
+            handler.set_conflict_policy("version.*", Arc::new(crate::swim::conflict_policy::NumericMax));
+        }
+        merge_into(&mut b, &mut a);
+        // "9" beats "10" as a string, whichever automerge picks
+        a.set_field("version.app".to_owned(), "9".to_owned()).unwrap();
+        b.set_field("version.app".to_owned(), "10".to_owned()).unwrap();
+        merge_into(&mut a, &mut b);
+        merge_into(&mut b, &mut a);
+        merge_into(&mut a, &mut b);
+        // c has no policy and merges the outcome
+        merge_into(&mut c, &mut a);
+
+        for handler in [&a, &b, &c] {
+            assert_eq!(handler.get_field("version.app".to_owned()).unwrap().as_deref(), Some("10"));
+        }
+        assert_eq!(a.heads(), b.heads());
+    }
+
+    // A FileStore whose writes fail while failing is set, like a full disk
+    struct FailingStore {
+        inner: FileStore,
+        failing: Arc<std::sync::atomic::AtomicBool>,
+    }
+
+    impl FailingStore {
+        fn check(&self) -> Result<()> {
+            if self.failing.load(std::sync::atomic::Ordering::SeqCst) {
+                anyhow::bail!("no space left on device");
+            }
+            Ok(())
+        }
+    }
+
+    impl StateStore for FailingStore {
+        fn load(&self, key: &str) -> Result<Option<Vec<u8>>> {
+            self.inner.load(key)
+        }
+
+        fn save(&self, key: &str, bytes: &[u8]) -> Result<()> {
+            self.check()?;
+            self.inner.save(key, bytes)
+        }
+
+        fn append(&self, key: &str, bytes: &[u8]) -> Result<()> {
+            self.check()?;
+            self.inner.append(key, bytes)
+        }
+
+        fn contains(&self, key: &str) -> bool {
+            self.inner.contains(key)
+        }
+
+        fn describe(&self, key: &str) -> String {
+            self.inner.describe(key)
+        }
+    }
+
+    #[tokio::test]
+    async fn strict_durability_rejects_writes_until_the_state_is_written_again() {
+        let dir = TempDir::new().unwrap();
+        let failing = Arc::new(std::sync::atomic::AtomicBool::new(false));
+        let store = Arc::new(FailingStore { inner: FileStore::new(dir.path()), failing: failing.clone() });
+        let mut handler = HolyDiverDataHandler::open(store, ID::with_bump(SocketAddr::from(([127, 0, 0, 1], 9001)), 0), None).unwrap();
+        handler.set_strict_durability(true);
+        handler.set_field("color".to_owned(), "red".to_owned()).unwrap();
+        handler.flush_handle().wait().await;
+        assert_eq!(handler.persistence_status().consecutive_failures, 0);
+
+        failing.store(true, std::sync::atomic::Ordering::SeqCst);
+        // accepted, the failure only shows once the writer tried to write it
+        handler.set_field("color".to_owned(), "green".to_owned()).unwrap();
+        handler.flush_handle().wait().await;
+        let status = handler.persistence_status();
+        assert_eq!(status.consecutive_failures, 1);
+        assert!(status.last_error.as_deref().unwrap().contains("no space left on device"));
+        assert!(status.last_failure.is_some());
+
+        let rejected = handler.set_field("color".to_owned(), "blue".to_owned()).unwrap_err();
+        assert!(matches!(rejected.downcast_ref::<HolyDiverError>(), Some(HolyDiverError::InsufficientStorage(_))), "{:#}", rejected);
+        assert_eq!(handler.get_field("color".to_owned()).unwrap().as_deref(), Some("green"));
+        // still failing, the retry counts as another failure
+        handler.retry_persist();
+        handler.flush_handle().wait().await;
+        assert_eq!(handler.persistence_status().consecutive_failures, 2);
+
+        failing.store(false, std::sync::atomic::Ordering::SeqCst);
+        handler.retry_persist();
+        handler.flush_handle().wait().await;
+        let status = handler.persistence_status();
+        assert_eq!(status.consecutive_failures, 0);
+        // kept to tell what went wrong
+        assert!(status.last_error.is_some());
+        handler.set_field("color".to_owned(), "blue".to_owned()).unwrap();
+        handler.flush_handle().wait().await;
+
+        // the write that failed is part of the snapshot written by the retry
+        drop(handler);
+        let restarted = HolyDiverDataHandler::new(dir.path(), ID::with_bump(SocketAddr::from(([127, 0, 0, 1], 9001)), 1));
+        assert_eq!(restarted.get_field("color".to_owned()).unwrap().as_deref(), Some("blue"));
+    }
+
+    // random hex digits, a full save compresses them to about half their length
+    fn incompressible(len: usize) -> String {
+        let mut value = String::new();
+        while value.len() < len {
+            value.push_str(&Uuid::new_v4().simple().to_string());
+        }
+        value.truncate(len);
+        value
+    }
+
+    #[tokio::test]
+    async fn local_writes_beyond_the_maximum_size_are_rejected_while_merges_are_applied() {
+        let (_local_dir, mut local) = open(9001);
+        let (_remote_dir, mut remote) = open(9002);
+        merge_into(&mut local, &mut remote);
+        local.set_field("small".to_owned(), "1".to_owned()).unwrap();
+        let size = local.stats().estimated_size;
+        local.set_max_doc_size(Some(size + 1000));
+        let large = incompressible(4000);
+
+        let rejected = local.set_field("large".to_owned(), large.clone()).unwrap_err();
+        match rejected.downcast_ref::<HolyDiverError>() {
+            Some(HolyDiverError::DocumentTooLarge { size: reported, max, .. }) => {
+                assert_eq!((*reported, *max), (size, size + 1000));
+            },
+            other => panic!("expected the document to be too large, got {:?}", other),
+        }
+        assert_eq!(local.get_field("large".to_owned()).unwrap(), None);
+        local.set_field("small".to_owned(), "2".to_owned()).unwrap();
+        // deletes are never rejected
+        local.delete_field("small".to_owned()).unwrap();
+
+        remote.set_field("large".to_owned(), large.clone()).unwrap();
+        merge_into(&mut local, &mut remote);
+        assert_eq!(local.get_field("large".to_owned()).unwrap(), Some(large));
+        let stats = local.stats();
+        assert!(stats.estimated_size > size + 1000, "{} bytes", stats.estimated_size);
+        assert_eq!(stats.max_size, Some(size + 1000));
+        assert_eq!(stats.merges_over_max_size, 1);
+        assert!(local.set_field("small".to_owned(), "3".to_owned()).is_err());
+    }
+
+    #[tokio::test]
+    async fn the_audit_log_records_local_writes_and_merged_changes() {
+        use crate::swim::audit::{AuditConfig, AuditRecord, AuditSource};
+        let (_remote_dir, mut remote) = open(9002);
+        let (dir, mut local) = open(9001);
+        merge_into(&mut local, &mut remote);
+        let path = dir.path().join("audit").join("audit.log");
+        local.set_audit_log(AuditLog::spawn(AuditConfig::new(path.clone())).unwrap());
+
+        events::with_request_id("req-1".to_owned(), async {
+            local.set_field("color".to_owned(), "red".to_owned()).unwrap();
+        }).await;
+        merge_into(&mut remote, &mut local);
+        remote.set_field("color".to_owned(), "blue".to_owned()).unwrap();
+        merge_into(&mut local, &mut remote);
+        local.delete_field("color".to_owned()).unwrap();
+
+        let mut records: Vec<AuditRecord> = Vec::new();
+        for _ in 0..100 {
+            records = std::fs::read_to_string(&path).unwrap_or_default().lines()
+                .map(|line| serde_json::from_str(line).unwrap())
+                .collect();
+            if records.len() >= 3 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        let summary: Vec<_> = records.iter()
+            .map(|record| (record.field.as_str(), record.old.as_deref(), record.new.as_deref(), record.source, record.request_id.as_deref()))
+            .collect();
+        assert_eq!(summary, vec![
+            ("color", None, Some("red"), AuditSource::Local, Some("req-1")),
+            ("color", Some("red"), Some("blue"), AuditSource::Remote, None),
+            ("color", Some("blue"), None, AuditSource::Local, None),
+        ]);
+        assert_eq!(records[0].actor, Some(actor_of(&local)));
+        assert_eq!(records[1].actor, Some(actor_of(&remote)));
+        assert_eq!(records[2].actor, None);
+    }
+
+    #[tokio::test]
+    async fn encrypted_state_needs_its_key_and_replaces_plain_state() {
+        let dir = TempDir::new().unwrap();
+        let identity = |bump| ID::with_bump(SocketAddr::from(([127, 0, 0, 1], 9001)), bump);
+        let key = Arc::new(DataKey::from_bytes(&[7; crate::swim::encryption::KEY_SIZE]).unwrap());
+        let open_with = |bump, key: Option<Arc<DataKey>>| HolyDiverDataHandler::open(FileStore::shared(dir.path()), identity(bump), key);
+
+        let mut plain = open_with(0, None).unwrap();
+        plain.set_field("secret".to_owned(), "plain value".to_owned()).unwrap();
+        plain.flush_handle().wait().await;
+        drop(plain);
+
+        // the first write with a key migrates the plain state
+        let mut encrypted = open_with(1, Some(key.clone())).unwrap();
+        assert_eq!(encrypted.get_field("secret".to_owned()).unwrap().as_deref(), Some("plain value"));
+        encrypted.set_field("other".to_owned(), "sealed value".to_owned()).unwrap();
+        encrypted.flush_handle().wait().await;
+        drop(encrypted);
+        let store = FileStore::new(dir.path());
+        for file in [STATE_KEY, persistence::WAL_KEY] {
+            let data = store.load(file).unwrap().unwrap_or_default();
+            let text = String::from_utf8_lossy(&data);
+            assert!(!text.contains("plain value") && !text.contains("sealed value"), "{} holds plain values", file);
+        }
+
+        let missing = open_with(2, None).err().unwrap();
+        assert!(matches!(missing.downcast_ref::<KeyError>(), Some(KeyError::Missing(_))), "{:#}", missing);
+        let other_key = Arc::new(DataKey::from_bytes(&[8; crate::swim::encryption::KEY_SIZE]).unwrap());
+        let wrong = open_with(2, Some(other_key)).err().unwrap();
+        assert!(matches!(wrong.downcast_ref::<KeyError>(), Some(KeyError::Wrong(_))), "{:#}", wrong);
+        let reopened = open_with(2, Some(key)).unwrap();
+        assert_eq!(reopened.get_field("secret".to_owned()).unwrap().as_deref(), Some("plain value"));
+        assert_eq!(reopened.get_field("other".to_owned()).unwrap().as_deref(), Some("sealed value"));
+    }
+
+    #[tokio::test]
+    async fn invalid_field_names_are_rejected_locally_and_quarantined_when_merged() {
+        let (_remote_dir, mut remote) = open(9002);
+        let (_local_dir, mut local) = open(9001);
+        merge_into(&mut local, &mut remote);
+        local.set_field_sanitation(FieldSanitation::Quarantine);
+
+        let rejected = local.set_field("bad\nname".to_owned(), "1".to_owned()).unwrap_err();
+        match rejected.downcast_ref::<HolyDiverError>() {
+            Some(HolyDiverError::InvalidFieldName(invalid)) => assert_eq!(invalid.rule, field_names::FieldNameRule::ControlCharacter),
+            other => panic!("expected an invalid field name, got {:?}", other),
+        }
+        assert!(local.set_field(String::new(), "1".to_owned()).is_err());
+        assert!(local.set_field("x".repeat(field_names::MAX_FIELD_NAME_LEN + 1), "1".to_owned()).is_err());
+
+        // written by a member that doesn't check the names
+        {
+            let mut doc = remote.data.lock().unwrap();
+            let values = structure::values_map(&doc).unwrap();
+            doc.put(&values, "bad\nname", "1").unwrap();
+        }
+        merge_into(&mut local, &mut remote);
+        let report = local.invalid_fields();
+        assert_eq!(report.fields.len(), 1);
+        assert_eq!(report.fields[0].name.field, "bad\\nname");
+        assert_eq!(report.fields[0].name.rule, field_names::FieldNameRule::ControlCharacter);
+        assert_eq!(report.fields[0].actor, Some(actor_of(&remote)));
+        // left in the document, deleting it would fight the member writing it
+        assert_eq!(local.get_field("bad\nname".to_owned()).unwrap().as_deref(), Some("1"));
+    }
+
+    #[tokio::test]
+    async fn declared_containers_are_added_to_persisted_documents_and_addressed_by_key_or_index() {
+        use crate::swim::containers::ContainerError;
+        let dir = TempDir::new().unwrap();
+        let identity = |bump| ID::with_bump(SocketAddr::from(([127, 0, 0, 1], 9001)), bump);
+        let mut plain = HolyDiverDataHandler::new(dir.path(), identity(0));
+        plain.set_field("color".to_owned(), "red".to_owned()).unwrap();
+        plain.flush_handle().wait().await;
+        drop(plain);
+
+        let schema = DocSchema::parse(&["config".to_owned(), "queue:list".to_owned()]).unwrap();
+        let mut handler = HolyDiverDataHandler::open_with_schema(FileStore::shared(dir.path()), identity(1), None, schema).unwrap();
+        assert_eq!(handler.create_declared_containers().unwrap(), 2);
+        assert_eq!(handler.create_declared_containers().unwrap(), 0);
+        assert_eq!(handler.get_container_field("values", "color").unwrap().as_deref(), Some("red"));
+
+        handler.set_container_field("config", "mode".to_owned(), "fast".to_owned()).unwrap();
+        assert_eq!(handler.get_container_field("config", "mode").unwrap().as_deref(), Some("fast"));
+        // not a field of the values map
+        assert_eq!(handler.get_field("mode".to_owned()).unwrap(), None);
+
+        handler.set_container_field("queue", "0".to_owned(), "first".to_owned()).unwrap();
+        handler.set_container_field("queue", "1".to_owned(), "second".to_owned()).unwrap();
+        assert!(handler.set_container_field("queue", "3".to_owned(), "beyond".to_owned()).is_err());
+        assert!(handler.set_container_field("queue", "last".to_owned(), "third".to_owned()).is_err());
+        handler.delete_container_field("queue", "0".to_owned()).unwrap();
+        assert_eq!(handler.get_container_field("queue", "0").unwrap().as_deref(), Some("second"));
+        assert_eq!(handler.get_container_field("queue", "1").unwrap(), None);
+
+        let undeclared = handler.set_container_field("other", "key".to_owned(), "value".to_owned()).unwrap_err();
+        assert!(matches!(undeclared.downcast_ref::<ContainerError>(), Some(ContainerError::Undeclared(name)) if name == "other"), "{:#}", undeclared);
+        assert!(handler.get_container_field("other", "key").is_err());
+    }
+
+    #[tokio::test]
+    async fn actor_ids_of_an_earlier_identity_are_attributed_to_the_node_as_legacy_self() {
+        use crate::swim::actors::{ActorTag, ACTORS_KEY};
+
+        let (_remote_dir, mut remote) = open(9002);
+        let (dir, mut local) = open(9001);
+        remote.set_field("shape".to_owned(), "round".to_owned()).unwrap();
+        merge_into(&mut local, &mut remote);
+        local.set_field("color".to_owned(), "red".to_owned()).unwrap();
+        local.flush_handle().wait().await;
+        let (earlier, merged) = (actor_of(&local), actor_of(&remote));
+        drop(local);
+        // the document is older than the table
+        std::fs::remove_file(FileStore::new(dir.path()).path(ACTORS_KEY)).unwrap();
+
+        let mut restarted = HolyDiverDataHandler::new(dir.path(), ID::with_bump(SocketAddr::from(([127, 0, 0, 1], 9001)), 1));
+        restarted.set_field("size".to_owned(), "large".to_owned()).unwrap();
+        let actors = restarted.actors();
+        let legacy = actors[&earlier].info.clone().unwrap();
+        assert_eq!((legacy.addr, legacy.tag), (SocketAddr::from(([127, 0, 0, 1], 9001)), Some(ActorTag::LegacySelf)));
+        // only the actor ids derived from the address of this node are adopted
+        assert!(actors[&merged].info.is_none());
+        let current = actors[&actor_of(&restarted)].info.clone().unwrap();
+        assert_eq!(current.tag, None);
+
+        let meta = restarted.get_field_with_meta("color".to_owned()).unwrap().unwrap();
+        assert_eq!(meta.writer.as_deref(), Some("127.0.0.1:9001"));
+    }
+
+    // Runs an automerge sync session of from with to, returns the number of messages and their bytes
+    fn sync_session(from: &mut HolyDiverDataHandler, to: &mut HolyDiverDataHandler) -> (usize, usize) {
+        let (mut messages, mut bytes) = (0, 0);
+        let mut next = from.start_sync_session(to.node());
+        let mut towards_to = true;
+        while let Some(message) = next {
+            messages += 1;
+            bytes += message.message_payload().len();
+            let receiver = if towards_to { &mut *to } else { &mut *from };
+            next = receiver.handle_direct(message.message_type(), message.message_payload().clone()).unwrap().pop();
+            towards_to = !towards_to;
+        }
+        (messages, bytes)
+    }
+
+    #[tokio::test]
+    async fn sync_sessions_resume_from_the_sync_state_of_the_last_one() {
+        use crate::swim::sync_states::SYNC_STATES_KEY;
+        let reopen = |dir: &TempDir, port: u16, bump: u16| HolyDiverDataHandler::new(dir.path(), ID::with_bump(SocketAddr::from(([127, 0, 0, 1], port)), bump));
+
+        let (a_dir, mut a) = open(9002);
+        let (b_dir, mut b) = open(9001);
+        for i in 0..100 {
+            a.set_field(format!("key{}", i), i.to_string()).unwrap();
+        }
+        let first = sync_session(&mut a, &mut b);
+        assert_eq!(b.get_field("key99".to_owned()).unwrap().as_deref(), Some("99"));
+        assert_eq!(a.heads(), b.heads());
+        a.flush_handle().wait().await;
+        b.flush_handle().wait().await;
+        drop((a, b));
+
+        // both know that they are in sync and only exchange their heads
+        let (mut a, mut b) = (reopen(&a_dir, 9002, 1), reopen(&b_dir, 9001, 1));
+        let resumed = sync_session(&mut a, &mut b);
+        assert!(resumed.0 < first.0, "{:?} after {:?}", resumed, first);
+        assert_eq!(a.stats().sync_states.states, 1);
+        assert_eq!(b.stats().sync_states.states, 1);
+        a.flush_handle().wait().await;
+        b.flush_handle().wait().await;
+        drop((a, b));
+
+        // without the states their bloom filters cover the whole history
+        for dir in [&a_dir, &b_dir] {
+            std::fs::remove_file(FileStore::new(dir.path()).path(SYNC_STATES_KEY)).unwrap();
+        }
+        let (mut a, mut b) = (reopen(&a_dir, 9002, 2), reopen(&b_dir, 9001, 2));
+        let from_scratch = sync_session(&mut a, &mut b);
+        assert_eq!(from_scratch.0, resumed.0);
+        assert!(resumed.1 < from_scratch.1, "{:?} compared to {:?}", resumed, from_scratch);
+    }
+}
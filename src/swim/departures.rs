@@ -0,0 +1,112 @@
+use std::{
+    collections::{BTreeMap, HashMap}, net::SocketAddr, time::{Duration, Instant},
+};
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+use super::store::StateStore;
+
+// A node shutting down tells the members it is leaving with a direct Leave message before foca gossips it
+// down. A MemberDown of an address that announced its leave shortly before is reported as left, any other
+// as failed, so that alerts can ignore graceful leaves. The departures of the last day are remembered in
+// <DATA_DIR>/departures.json, so that /members still shows why a member went away after a restart.
+
+pub const DEPARTURES_KEY: &str = "departures.json";
+
+// a Leave counts for a MemberDown of the same address within this time
+pub const LEAVE_WINDOW: Duration = Duration::from_secs(60);
+
+// departures older than this or beyond the most recent MAX_DEPARTURES are forgotten
+const DEPARTURE_RETENTION: chrono::Duration = chrono::Duration::hours(24);
+const MAX_DEPARTURES: usize = 256;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[cfg_attr(feature = "rest", derive(utoipa::ToSchema))]
+#[serde(rename_all = "lowercase")]
+pub enum DownReason {
+    // the member announced its leave before it went down
+    Left,
+    // the member stopped answering probes
+    Failed,
+    // e.g. departures remembered by versions without reasons
+    #[default]
+    Unknown,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[cfg_attr(feature = "rest", derive(utoipa::ToSchema))]
+pub struct Departure {
+    #[cfg_attr(feature = "rest", schema(value_type = String))]
+    pub timestamp: DateTime<Utc>,
+    #[serde(default)]
+    pub reason: DownReason,
+}
+
+#[derive(Debug, Default)]
+pub struct Departures {
+    // when the members announced their leave, until their MemberDown or the LEAVE_WINDOW passed
+    announced: HashMap<SocketAddr, Instant>,
+    recent: BTreeMap<SocketAddr, Departure>,
+}
+
+impl Departures {
+    // an unreadable file is treated as empty and replaced with the next departure
+    pub fn load(store: &dyn StateStore) -> Self {
+        let recent = match store.load(DEPARTURES_KEY) {
+            Ok(Some(data)) => serde_json::from_slice(&data).unwrap_or_else(|e| {
+                warn!("Ignoring invalid departures {}: {}", store.describe(DEPARTURES_KEY), e);
+                BTreeMap::new()
+            }),
+            Ok(None) => BTreeMap::new(),
+            Err(e) => {
+                warn!("Could not read departures: {:#}", e);
+                BTreeMap::new()
+            },
+        };
+        Departures {
+            announced: HashMap::new(),
+            recent,
+        }
+    }
+
+    pub fn save(&self, store: &dyn StateStore) -> Result<()> {
+        let data = serde_json::to_vec_pretty(&self.recent)?;
+        store.save(DEPARTURES_KEY, &data)
+    }
+
+    // member sent a Leave
+    pub fn announce_leave(&mut self, member: SocketAddr, now: Instant) {
+        self.announced.retain(|_, announced_at| now.duration_since(*announced_at) <= LEAVE_WINDOW);
+        self.announced.insert(member, now);
+    }
+
+    // Records that member went down and returns why
+    pub fn record_down(&mut self, member: SocketAddr, now: Instant, timestamp: DateTime<Utc>) -> DownReason {
+        let reason = match self.announced.remove(&member) {
+            Some(announced_at) if now.duration_since(announced_at) <= LEAVE_WINDOW => DownReason::Left,
+            _ => DownReason::Failed,
+        };
+        self.recent.insert(member, Departure { timestamp, reason });
+        self.recent.retain(|_, departure| timestamp - departure.timestamp <= DEPARTURE_RETENTION);
+        while self.recent.len() > MAX_DEPARTURES {
+            let oldest = self.recent.iter()
+                .min_by_key(|(_, departure)| departure.timestamp)
+                .map(|(member, _)| *member)
+                .expect("more than MAX_DEPARTURES departures");
+            self.recent.remove(&oldest);
+        }
+        reason
+    }
+
+    // member is up again, returns whether a departure of it was forgotten
+    pub fn record_up(&mut self, member: &SocketAddr) -> bool {
+        self.announced.remove(member);
+        self.recent.remove(member).is_some()
+    }
+
+    pub fn recent(&self) -> BTreeMap<SocketAddr, Departure> {
+        self.recent.clone()
+    }
+}
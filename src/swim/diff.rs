@@ -0,0 +1,148 @@
+use std::{
+    collections::{BTreeMap, BTreeSet}, fmt::{Display, Formatter},
+};
+use anyhow::{Context, Result};
+use automerge::{AutoCommit, ChangeHash, ObjId, ReadDoc};
+use serde::Serialize;
+
+use super::{convert::value_to_string, structure, tombstones};
+
+// Key-level comparison of two documents for troubleshooting divergence, e.g. the exports of two nodes. Used by
+// the diff subcommand of the CLI and GET /debug/diff, which compares this node with the state of a member.
+// Deleted fields count as missing, local fields aren't part of the documents.
+
+/// How the histories of two documents relate.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[cfg_attr(feature = "rest", derive(utoipa::ToSchema))]
+#[serde(rename_all = "snake_case")]
+pub enum HistoryRelation {
+    // same heads
+    Equal,
+    // a has every change of b and more, merging b into a changes nothing
+    AContainsB,
+    BContainsA,
+    // each has changes the other is missing
+    Diverged,
+}
+
+/// A value of one side with the actor of the change that wrote it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[cfg_attr(feature = "rest", derive(utoipa::ToSchema))]
+pub struct SideValue {
+    pub value: String,
+    pub actor: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[cfg_attr(feature = "rest", derive(utoipa::ToSchema))]
+pub struct DifferentValues {
+    pub a: SideValue,
+    pub b: SideValue,
+}
+
+/// Fields that differ between the documents a and b, by name.
+#[derive(Debug, Clone, Serialize)]
+#[cfg_attr(feature = "rest", derive(utoipa::ToSchema))]
+pub struct StateDiff {
+    pub heads_a: Vec<String>,
+    pub heads_b: Vec<String>,
+    pub history: HistoryRelation,
+    pub only_in_a: BTreeMap<String, SideValue>,
+    pub only_in_b: BTreeMap<String, SideValue>,
+    pub different: BTreeMap<String, DifferentValues>,
+}
+
+impl StateDiff {
+    // whether the values are the same, the histories may still differ
+    pub fn values_equal(&self) -> bool {
+        self.only_in_a.is_empty() && self.only_in_b.is_empty() && self.different.is_empty()
+    }
+}
+
+// the diff of two saved documents, e.g. as returned by /export
+pub fn diff_saved(a: &[u8], b: &[u8]) -> Result<StateDiff> {
+    let mut a = AutoCommit::load(a).context("document a is no valid automerge document")?;
+    let mut b = AutoCommit::load(b).context("document b is no valid automerge document")?;
+    Ok(diff_documents(&mut a, &mut b))
+}
+
+// Needs the documents mutably to look up changes, neither of them is changed.
+pub fn diff_documents(a: &mut AutoCommit, b: &mut AutoCommit) -> StateDiff {
+    let (heads_a, heads_b) = (a.get_heads(), b.get_heads());
+    let a_contains_b = contains_all(a, &heads_b);
+    let b_contains_a = contains_all(b, &heads_a);
+    let history = match (a_contains_b, b_contains_a) {
+        (true, true) => HistoryRelation::Equal,
+        (true, false) => HistoryRelation::AContainsB,
+        (false, true) => HistoryRelation::BContainsA,
+        (false, false) => HistoryRelation::Diverged,
+    };
+    let (mut only_in_a, mut values_b) = (visible_values(a), visible_values(b));
+    let mut different = BTreeMap::new();
+    only_in_a.retain(|field, value_a| match values_b.remove(field) {
+        None => true,
+        Some(value_b) => {
+            if value_b.value != value_a.value {
+                different.insert(field.clone(), DifferentValues { a: value_a.clone(), b: value_b });
+            }
+            false
+        },
+    });
+    StateDiff {
+        heads_a: heads_a.iter().map(ChangeHash::to_string).collect(),
+        heads_b: heads_b.iter().map(ChangeHash::to_string).collect(),
+        history,
+        only_in_a,
+        only_in_b: values_b,
+        different,
+    }
+}
+
+fn contains_all(doc: &mut AutoCommit, heads: &[ChangeHash]) -> bool {
+    heads.iter().all(|head| doc.get_change_by_hash(head).is_some())
+}
+
+// a document without values map has no fields
+fn visible_values(doc: &AutoCommit) -> BTreeMap<String, SideValue> {
+    let Ok(values) = structure::values_map(doc) else {
+        return BTreeMap::new();
+    };
+    doc.keys(&values)
+        .filter_map(|field| {
+            let (value, id) = tombstones::visible_value(doc, &values, &field).ok().flatten()?;
+            let actor = match &id {
+                ObjId::Id(_, actor, _) => Some(actor.to_string()),
+                ObjId::Root => None,
+            };
+            Some((field, SideValue { value: value_to_string(doc, &value, &id, None), actor }))
+        })
+        .collect()
+}
+
+// The diff as text for operators, one line per field
+impl Display for StateDiff {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let side = |value: &SideValue| format!("{:?} (actor {})", value.value, value.actor.as_deref().unwrap_or("unknown"));
+        writeln!(f, "heads a: {}", self.heads_a.join(", "))?;
+        writeln!(f, "heads b: {}", self.heads_b.join(", "))?;
+        writeln!(f, "history: {}", match self.history {
+            HistoryRelation::Equal => "equal",
+            HistoryRelation::AContainsB => "a contains b",
+            HistoryRelation::BContainsA => "b contains a",
+            HistoryRelation::Diverged => "diverged",
+        })?;
+        let fields: BTreeSet<&String> = self.only_in_a.keys().chain(self.only_in_b.keys()).chain(self.different.keys()).collect();
+        if fields.is_empty() {
+            return writeln!(f, "values are equal");
+        }
+        for field in fields {
+            match (self.only_in_a.get(field), self.only_in_b.get(field), self.different.get(field)) {
+                (Some(a), _, _) => writeln!(f, "only in a: {} = {}", field, side(a))?,
+                (_, Some(b), _) => writeln!(f, "only in b: {} = {}", field, side(b))?,
+                (_, _, Some(values)) => writeln!(f, "different: {}: a = {}, b = {}", field, side(&values.a), side(&values.b))?,
+                _ => {},
+            }
+        }
+        writeln!(f, "{} only in a, {} only in b, {} different", self.only_in_a.len(), self.only_in_b.len(), self.different.len())
+    }
+}
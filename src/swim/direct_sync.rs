@@ -0,0 +1,32 @@
+use std::net::SocketAddr;
+use serde::{Deserialize, Serialize};
+
+// Syncs with a single member triggered by an operator, e.g. after noticing that the member diverged,
+// instead of waiting for gossip to converge. All modes are sent as direct messages, see
+// HolyDiverController::sync_with. A full sync has to fit into a single datagram, larger documents are
+// reported as not enqueued and can only be synced by their changes. A session takes as many round trips as
+// automerge needs, each message has to fit into a datagram as well.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "rest", derive(utoipa::ToSchema))]
+#[serde(rename_all = "snake_case")]
+pub enum SyncMode {
+    // the whole document, merged by the member
+    Full,
+    // asks the member for its heads, it is then sent the journaled changes it is missing
+    Changes,
+    // an automerge sync session resumed from the sync state of the last session with the member, see sync_states
+    Session,
+}
+
+/// Outcome of a sync, `bytes` is the size of the datagram sent to the member.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "rest", derive(utoipa::ToSchema))]
+pub struct SyncReport {
+    #[cfg_attr(feature = "rest", schema(value_type = String, example = "10.0.0.7:9000"))]
+    pub target: SocketAddr,
+    pub mode: SyncMode,
+    // false if the datagram is too large or the send queue is full
+    pub enqueued: bool,
+    pub bytes: usize,
+}
@@ -0,0 +1,25 @@
+use std::time::Duration;
+
+// Parses durations like `90`, `250ms`, `30s`, `15m`, `1h` or `7d`, plain numbers are seconds.
+// The error is a String so this can be used as a clap value parser.
+pub fn parse_duration(input: &str) -> Result<Duration, String> {
+    let input = input.trim();
+    if let Some(millis) = input.strip_suffix("ms") {
+        return millis.parse::<u64>()
+            .map(Duration::from_millis)
+            .map_err(|_| format!("invalid duration {:?}, expected e.g. 250ms, 30s, 15m, 1h or 7d", input));
+    }
+    let (number, unit_secs) = match input.char_indices().last() {
+        Some((i, 's')) => (&input[..i], 1),
+        Some((i, 'm')) => (&input[..i], 60),
+        Some((i, 'h')) => (&input[..i], 60 * 60),
+        Some((i, 'd')) => (&input[..i], 24 * 60 * 60),
+        Some(_) => (input, 1),
+        None => return Err("duration must not be empty".to_owned()),
+    };
+    number.parse::<u64>()
+        .ok()
+        .and_then(|n| n.checked_mul(unit_secs))
+        .map(Duration::from_secs)
+        .ok_or_else(|| format!("invalid duration {:?}, expected e.g. 250ms, 30s, 15m, 1h or 7d", input))
+}
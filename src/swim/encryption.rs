@@ -0,0 +1,136 @@
+use std::{
+    borrow::Cow, fmt::{Debug, Display, Formatter}, fs, path::Path,
+};
+use aes_gcm::{aead::{Aead, AeadCore, KeyInit, OsRng}, Aes256Gcm, Key, Nonce};
+use anyhow::{anyhow, Context, Result};
+use base64::Engine;
+use zeroize::Zeroizing;
+
+// With --data-encryption-key-file the snapshot and every WAL record are encrypted with AES-256-GCM before
+// they are written, see persistence. Encrypted data always starts with MAGIC, which neither automerge
+// documents nor changes do, so plain data written before encryption was enabled is still read and is
+// replaced by encrypted data with the next snapshot.
+//
+// Layout of encrypted data
+//
+// 0. MAGIC (8 bytes)
+// 1. random nonce of this write (12 bytes)
+// 2. ciphertext followed by the 16 bytes of the authentication tag
+//
+const MAGIC: &[u8; 8] = b"HDAESGCM";
+const NONCE_SIZE: usize = 12;
+pub const KEY_SIZE: usize = 32;
+
+/// Key of --data-encryption-key-file. Never shown in logs or errors, zeroized when dropped.
+pub struct DataKey(Zeroizing<[u8; KEY_SIZE]>);
+
+impl DataKey {
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        let mut key = Zeroizing::new([0u8; KEY_SIZE]);
+        if bytes.len() != KEY_SIZE {
+            return Err(anyhow!("a key has to have {} bytes, got {}", KEY_SIZE, bytes.len()));
+        }
+        key.copy_from_slice(bytes);
+        Ok(DataKey(key))
+    }
+
+    // The file holds the 32 bytes of the key, either raw or hex or base64 encoded, e.g. as
+    // written by `openssl rand -base64 32`.
+    pub fn from_file(path: &Path) -> Result<Self> {
+        let content = Zeroizing::new(fs::read(path)
+            .with_context(|| format!("could not read the data encryption key file {}", path.display()))?);
+        Self::decode(&content)
+            .with_context(|| format!("the data encryption key file {} does not hold a key of {} bytes, raw or hex or base64 encoded",
+                path.display(), KEY_SIZE))
+    }
+
+    fn decode(content: &[u8]) -> Result<Self> {
+        if content.len() == KEY_SIZE {
+            return Self::from_bytes(content);
+        }
+        let text = std::str::from_utf8(content).map_err(|_| anyhow!("not a raw key and not text"))?.trim();
+        if text.len() == KEY_SIZE * 2 && text.bytes().all(|b| b.is_ascii_hexdigit()) {
+            let bytes = Zeroizing::new((0..KEY_SIZE)
+                .map(|i| u8::from_str_radix(&text[i * 2..i * 2 + 2], 16).expect("checked to be hex digits"))
+                .collect::<Vec<u8>>());
+            return Self::from_bytes(&bytes);
+        }
+        let bytes = Zeroizing::new(base64::engine::general_purpose::STANDARD.decode(text)
+            .map_err(|_| anyhow!("neither hex nor base64"))?);
+        Self::from_bytes(&bytes)
+    }
+
+    // the AES round keys are zeroized when the cipher is dropped
+    fn cipher(&self) -> Aes256Gcm {
+        Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&*self.0))
+    }
+
+    pub fn seal(&self, plaintext: &[u8]) -> Vec<u8> {
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        let ciphertext = self.cipher().encrypt(&nonce, plaintext)
+            .expect("AES-GCM encryption only fails for inputs of many gigabytes");
+        let mut sealed = Vec::with_capacity(MAGIC.len() + NONCE_SIZE + ciphertext.len());
+        sealed.extend_from_slice(MAGIC);
+        sealed.extend_from_slice(&nonce);
+        sealed.extend_from_slice(&ciphertext);
+        sealed
+    }
+
+    // None if data was not encrypted with this key or was changed since
+    fn open(&self, sealed: &[u8]) -> Option<Vec<u8>> {
+        let nonce = sealed.get(MAGIC.len()..MAGIC.len() + NONCE_SIZE)?;
+        let ciphertext = &sealed[MAGIC.len() + NONCE_SIZE..];
+        self.cipher().decrypt(Nonce::from_slice(nonce), ciphertext).ok()
+    }
+}
+
+impl Debug for DataKey {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "DataKey(redacted)")
+    }
+}
+
+pub fn is_sealed(data: &[u8]) -> bool {
+    data.starts_with(MAGIC)
+}
+
+// Seals data if there is a key
+pub fn seal<'a>(data: &'a [u8], key: Option<&DataKey>) -> Cow<'a, [u8]> {
+    match key {
+        Some(key) => Cow::Owned(key.seal(data)),
+        None => Cow::Borrowed(data),
+    }
+}
+
+// The plaintext of data read from location, plain data is returned as it is. Fails with a KeyError if data is
+// encrypted and there is no key or a different one.
+pub fn unseal<'a>(data: &'a [u8], key: Option<&DataKey>, location: &str) -> Result<Cow<'a, [u8]>> {
+    if !is_sealed(data) {
+        return Ok(Cow::Borrowed(data));
+    }
+    let key = key.ok_or_else(|| KeyError::Missing(location.to_owned()))?;
+    key.open(data)
+        .map(Cow::Owned)
+        .ok_or_else(|| KeyError::Wrong(location.to_owned()).into())
+}
+
+// Raised inside anyhow errors, the persisted state can't be read without the right key, so callers
+// must not fall back to a new document.
+#[derive(Debug)]
+pub enum KeyError {
+    Missing(String),
+    Wrong(String),
+}
+
+impl Display for KeyError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            KeyError::Missing(location) => write!(f, "{} is encrypted, pass the key it was encrypted with \
+                using --data-encryption-key-file", location),
+            KeyError::Wrong(location) => write!(f, "could not decrypt {}, the data encryption key is not the one \
+                it was encrypted with or the file is corrupted", location),
+        }
+    }
+}
+
+impl std::error::Error for KeyError {}
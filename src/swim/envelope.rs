@@ -0,0 +1,139 @@
+use std::{cell::Cell, sync::atomic::{AtomicU8, Ordering}};
+use anyhow::{bail, Result};
+use bytes::{BufMut, Bytes, BytesMut};
+
+// Every datagram exchanged between holy-diver nodes is wrapped in a small envelope:
+//
+// 0. Magic bytes "HD"
+// 1. Envelope version (u8)
+// 2. Kind of the payload (u8), see Kind
+// 3. Payload
+//
+// Version 2 encodes the times in the tags of broadcasts as WireTimestamps and HybridTimestamps, see clock,
+// version 1 encoded them as chrono and std times. Version 3 encodes the identities in foca's messages compactly,
+// see ID. Version 4 adds the origin and write time to GossipMessages, see apply_latency, version 5 their trace
+// context, see telemetry.
+//
+// Datagrams of the versions from MIN_ENVELOPE_VERSION on are decoded in the format of their version, older and
+// newer ones are dropped. A node sends the version set with --wire-version, ENVELOPE_VERSION by default, so that
+// a cluster is upgraded node by node: the upgraded nodes keep sending the version of the others until all of
+// them understand the new one. The types whose encoding changed between versions look up the version they are
+// encoded in or decoded from with wire_version.
+//
+const MAGIC: &[u8; 2] = b"HD";
+pub const ENVELOPE_VERSION: u8 = 5;
+pub const MIN_ENVELOPE_VERSION: u8 = 1;
+pub const ENVELOPE_SIZE: usize = MAGIC.len() + 2;
+// largest payload of a UDP datagram
+pub const MAX_DATAGRAM_SIZE: usize = 65_507;
+// one byte more than a datagram may have, so that larger ones are noticed instead of silently truncated
+pub const RECEIVE_BUFFER_SIZE: usize = MAX_DATAGRAM_SIZE + 1;
+
+static SEND_VERSION: AtomicU8 = AtomicU8::new(ENVELOPE_VERSION);
+
+thread_local! {
+    // the version of what is encoded or decoded on this thread, see with_wire_version
+    static WIRE_VERSION: Cell<u8> = const { Cell::new(ENVELOPE_VERSION) };
+}
+
+// the version the datagrams of this node are sent in, see --wire-version
+pub fn set_send_version(version: u8) -> Result<()> {
+    if !(MIN_ENVELOPE_VERSION..=ENVELOPE_VERSION).contains(&version) {
+        bail!("unsupported wire version {}, this node speaks versions {} to {}", version, MIN_ENVELOPE_VERSION, ENVELOPE_VERSION);
+    }
+    SEND_VERSION.store(version, Ordering::Relaxed);
+    Ok(())
+}
+
+pub fn send_version() -> u8 {
+    SEND_VERSION.load(Ordering::Relaxed)
+}
+
+// The version messages are encoded in or decoded from, ENVELOPE_VERSION outside of with_wire_version,
+// e.g. for what is persisted
+pub fn wire_version() -> u8 {
+    WIRE_VERSION.with(Cell::get)
+}
+
+// Runs f with wire_version returning version, e.g. to decode a datagram in the version it was sent in
+pub fn with_wire_version<R>(version: u8, f: impl FnOnce() -> R) -> R {
+    let previous = WIRE_VERSION.with(|current| current.replace(version));
+    let result = f();
+    WIRE_VERSION.with(|current| current.set(previous));
+    result
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    // a packet produced by foca, handed to foca as is
+    Foca,
+    // a tag and GossipMessage sent to a single member, never re-gossiped
+    Direct,
+    // a latency probe, answered with a Pong carrying the same payload, see probes
+    Ping,
+    Pong,
+}
+
+impl Kind {
+    fn to_byte(self) -> u8 {
+        match self {
+            Kind::Foca => 0,
+            Kind::Direct => 1,
+            Kind::Ping => 2,
+            Kind::Pong => 3,
+        }
+    }
+
+    fn from_byte(byte: u8) -> Option<Self> {
+        match byte {
+            0 => Some(Kind::Foca),
+            1 => Some(Kind::Direct),
+            2 => Some(Kind::Ping),
+            3 => Some(Kind::Pong),
+            _ => None,
+        }
+    }
+}
+
+pub fn wrap(kind: Kind, payload: &[u8]) -> Bytes {
+    let mut data = BytesMut::with_capacity(ENVELOPE_SIZE + payload.len());
+    data.put_slice(MAGIC);
+    data.put_u8(send_version());
+    data.put_u8(kind.to_byte());
+    data.put_slice(payload);
+    data.freeze()
+}
+
+/// A received datagram without its envelope. The payload is a copy owned by the frame, so the
+/// receive buffer can be reused right away.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IncomingFrame {
+    // the envelope version the payload is encoded in
+    pub version: u8,
+    pub kind: Kind,
+    pub payload: Bytes,
+}
+
+// Everything done to a datagram before it is sent is undone here, currently only the envelope.
+// Decompression or decryption belong here as well once there is any.
+pub fn decode_datagram(datagram: &[u8]) -> Result<IncomingFrame> {
+    if datagram.len() > MAX_DATAGRAM_SIZE {
+        bail!("datagram of more than {} bytes", MAX_DATAGRAM_SIZE);
+    }
+    if datagram.len() < ENVELOPE_SIZE || &datagram[..MAGIC.len()] != MAGIC {
+        bail!("datagram without holy-diver envelope");
+    }
+    let version = datagram[MAGIC.len()];
+    if !(MIN_ENVELOPE_VERSION..=ENVELOPE_VERSION).contains(&version) {
+        bail!("unsupported envelope version {}, this node speaks versions {} to {}", version, MIN_ENVELOPE_VERSION, ENVELOPE_VERSION);
+    }
+    let kind = match Kind::from_byte(datagram[MAGIC.len() + 1]) {
+        Some(kind) => kind,
+        None => bail!("unknown envelope kind {}", datagram[MAGIC.len() + 1]),
+    };
+    Ok(IncomingFrame {
+        version,
+        kind,
+        payload: Bytes::copy_from_slice(&datagram[ENVELOPE_SIZE..]),
+    })
+}
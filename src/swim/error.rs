@@ -0,0 +1,436 @@
+use std::{fmt::{Display, Formatter}, time::Duration};
+
+#[cfg(feature = "rest")]
+use actix_web::{HttpResponse, ResponseError, http::{StatusCode, header}, error::JsonPayloadError, HttpRequest};
+use anyhow::anyhow;
+use serde::Serialize;
+use serde_json::Value;
+
+use super::{auth::Denied, containers::ContainerError, field_names::InvalidFieldName, leases::{Lease, LeaseError}, phases::Phase, quotas::{QuotaExceeded, QuotaLimit}, structure::DocStructureError, validator::Violation};
+
+// Errors surfaced to REST clients. Every variant maps to a stable `code` string
+// that client libraries can match on, the message is meant for humans.
+#[derive(Debug)]
+pub enum HolyDiverError {
+    FieldNotFound(String),
+    NoPreviousValue(String),
+    VersionGone(String),
+    TooManyKeys { requested: usize, max: usize },
+    ValueTooLarge(String),
+    InvalidJson(String),
+    InvalidDocument(String),
+    InvalidQuery(String),
+    RouteNotFound(String),
+    SchemaViolation(Violation),
+    // a local write of a field whose name breaks the rules of field_names
+    InvalidFieldName(InvalidFieldName),
+    // incrementing a field whose value is no integer
+    NotAnInteger(String),
+    BroadcastBackpressure,
+    // writes are rejected with --strict-durability while the state can't be written to disk
+    InsufficientStorage(String),
+    // a local write would grow the document beyond --max-doc-size, sizes are estimates in bytes
+    DocumentTooLarge { size: usize, growth: usize, max: usize },
+    // a local write would take a namespace beyond its quota, see quotas
+    QuotaExceeded(QuotaExceeded),
+    // not raised by a node itself, but by proxies rate limiting the requests to it
+    RateLimited,
+    InvalidSetting { setting: String, message: String },
+    // the document lacks the values map, e.g. after merging a malformed document, see structure
+    DocumentStructure(String),
+    LeaseHeld(Lease),
+    // renewing or releasing a lease held by another node, holder is None if the lease does not exist
+    NotLeaseHolder { name: String, holder: Option<String> },
+    // a container that is not declared with --container, see containers
+    UnknownContainer(String),
+    // a sync targeting an address that is no active member, see HolyDiverController::sync_with
+    UnknownMember(String),
+    // a member did not send what was requested from it, e.g. its document for /debug/diff
+    PeerUnavailable { member: String, reason: String },
+    // the request took longer than the timeout of its route, stage is the phase it was in, see phases
+    RequestTimeout { stage: Phase, timeout: Duration },
+    // writes are rejected while the node drains before shutting down, see shutdown
+    ShuttingDown,
+    // local writes are rejected while the node is read-only, reads keep working
+    ReadOnly(ReadOnlyReason),
+    // no or an unknown bearer token while --rest-auth-token is configured
+    Unauthorized,
+    // the token lacks the required scope for the field, lease or admin endpoint, see auth
+    Forbidden(Denied),
+    Internal(anyhow::Error),
+}
+
+/// Why a node rejects local writes with read_only.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ReadOnlyReason {
+    // fewer members than --min-members-for-writes are up, see write_quorum
+    InsufficientMembers { members: usize, min: usize },
+}
+
+impl ReadOnlyReason {
+    // the cause in the details of the error
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ReadOnlyReason::InsufficientMembers { .. } => "insufficient_members",
+        }
+    }
+}
+
+/// Body of every error response.
+#[derive(Serialize)]
+#[cfg_attr(feature = "rest", derive(utoipa::ToSchema))]
+pub struct ErrorEnvelope {
+    pub error: ErrorBody,
+}
+
+#[derive(Serialize)]
+#[cfg_attr(feature = "rest", derive(utoipa::ToSchema))]
+pub struct ErrorBody {
+    // one of the codes of HolyDiverError::code
+    #[cfg_attr(feature = "rest", schema(example = "field_not_found"))]
+    pub code: &'static str,
+    pub message: String,
+    // depends on the code, e.g. the field or the violated constraint
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[cfg_attr(feature = "rest", schema(value_type = Option<Object>))]
+    pub details: Option<Value>,
+}
+
+impl HolyDiverError {
+    pub fn code(&self) -> &'static str {
+        match self {
+            HolyDiverError::FieldNotFound(_) => "field_not_found",
+            HolyDiverError::NoPreviousValue(_) => "no_previous_value",
+            HolyDiverError::VersionGone(_) => "version_gone",
+            HolyDiverError::TooManyKeys { .. } => "too_many_keys",
+            HolyDiverError::ValueTooLarge(_) => "value_too_large",
+            HolyDiverError::InvalidJson(_) => "invalid_json",
+            HolyDiverError::InvalidDocument(_) => "invalid_document",
+            HolyDiverError::InvalidQuery(_) => "invalid_query",
+            HolyDiverError::RouteNotFound(_) => "route_not_found",
+            HolyDiverError::SchemaViolation(_) => "schema_violation",
+            HolyDiverError::InvalidFieldName(_) => "invalid_field_name",
+            HolyDiverError::NotAnInteger(_) => "not_an_integer",
+            HolyDiverError::BroadcastBackpressure => "broadcast_backpressure",
+            HolyDiverError::InsufficientStorage(_) => "insufficient_storage",
+            HolyDiverError::DocumentTooLarge { .. } => "document_too_large",
+            HolyDiverError::QuotaExceeded(_) => "quota_exceeded",
+            HolyDiverError::RateLimited => "rate_limited",
+            HolyDiverError::InvalidSetting { .. } => "invalid_setting",
+            HolyDiverError::DocumentStructure(_) => "document_structure",
+            HolyDiverError::LeaseHeld(_) => "lease_held",
+            HolyDiverError::NotLeaseHolder { .. } => "not_lease_holder",
+            HolyDiverError::UnknownContainer(_) => "unknown_container",
+            HolyDiverError::UnknownMember(_) => "unknown_member",
+            HolyDiverError::PeerUnavailable { .. } => "peer_unavailable",
+            HolyDiverError::RequestTimeout { .. } => "request_timeout",
+            HolyDiverError::ShuttingDown => "shutting_down",
+            HolyDiverError::ReadOnly(_) => "read_only",
+            HolyDiverError::Unauthorized => "unauthorized",
+            HolyDiverError::Forbidden(_) => "forbidden",
+            HolyDiverError::Internal(_) => "internal_error",
+        }
+    }
+
+    fn details(&self) -> Option<Value> {
+        match self {
+            HolyDiverError::FieldNotFound(field) | HolyDiverError::NoPreviousValue(field)
+            | HolyDiverError::NotAnInteger(field) => Some(serde_json::json!({ "field": field })),
+            HolyDiverError::VersionGone(version) => Some(serde_json::json!({ "version": version })),
+            HolyDiverError::TooManyKeys { requested, max } => Some(serde_json::json!({ "requested": requested, "max": max })),
+            HolyDiverError::RouteNotFound(path) => Some(serde_json::json!({ "path": path })),
+            HolyDiverError::DocumentTooLarge { size, growth, max } =>
+                Some(serde_json::json!({ "size": size, "growth": growth, "max": max })),
+            HolyDiverError::SchemaViolation(violation) => serde_json::to_value(violation).ok(),
+            HolyDiverError::InvalidFieldName(invalid) => serde_json::to_value(invalid).ok(),
+            HolyDiverError::QuotaExceeded(exceeded) => serde_json::to_value(exceeded).ok(),
+            HolyDiverError::InvalidSetting { setting, message } => Some(serde_json::json!({ "setting": setting, "reason": message })),
+            HolyDiverError::LeaseHeld(lease) => serde_json::to_value(lease).ok(),
+            HolyDiverError::NotLeaseHolder { name, holder } => Some(serde_json::json!({ "name": name, "holder": holder })),
+            HolyDiverError::UnknownContainer(container) => Some(serde_json::json!({ "container": container })),
+            HolyDiverError::UnknownMember(member) => Some(serde_json::json!({ "member": member })),
+            HolyDiverError::PeerUnavailable { member, reason } => Some(serde_json::json!({ "member": member, "reason": reason })),
+            HolyDiverError::RequestTimeout { stage, timeout } =>
+                Some(serde_json::json!({ "timeout_stage": stage, "timeout_ms": timeout.as_millis() as u64 })),
+            HolyDiverError::ReadOnly(reason @ ReadOnlyReason::InsufficientMembers { members, min }) =>
+                Some(serde_json::json!({ "cause": reason.as_str(), "members": members, "min_members": min })),
+            HolyDiverError::Forbidden(denied) => Some(serde_json::json!({
+                "resource": denied.resource, "required": denied.required, "scope": denied.scope })),
+            HolyDiverError::ValueTooLarge(reason) | HolyDiverError::InvalidJson(reason)
+            | HolyDiverError::InvalidDocument(reason) | HolyDiverError::InvalidQuery(reason)
+            | HolyDiverError::InsufficientStorage(reason)
+            | HolyDiverError::DocumentStructure(reason) => Some(serde_json::json!({ "reason": reason })),
+            HolyDiverError::Internal(e) => Some(serde_json::json!({ "reason": e.to_string() })),
+            HolyDiverError::BroadcastBackpressure | HolyDiverError::RateLimited | HolyDiverError::Unauthorized
+            | HolyDiverError::ShuttingDown => None,
+        }
+    }
+
+    /// Turns an error response of a node back into the error raised by it, the inverse of error_response.
+    /// Responses without an error envelope, e.g. from a proxy, become Internal unless they are a 429.
+    pub fn from_response(status: u16, body: &str) -> Self {
+        let error = serde_json::from_str::<Value>(body).ok()
+            .and_then(|envelope| envelope.get("error").cloned());
+        let error = match error {
+            Some(error) => error,
+            None if status == 429 => return HolyDiverError::RateLimited,
+            None => return HolyDiverError::Internal(anyhow!("unexpected response with status {}: {}", status, body)),
+        };
+        let code = error.get("code").and_then(Value::as_str).unwrap_or_default();
+        let message = error.get("message").and_then(Value::as_str).unwrap_or_default().to_owned();
+        let details = error.get("details").cloned().unwrap_or(Value::Null);
+        let detail = |key: &str| details.get(key).and_then(Value::as_str).map(str::to_owned);
+        let count = |key: &str| details.get(key).and_then(Value::as_u64).unwrap_or_default() as usize;
+        let reason = || detail("reason").unwrap_or_else(|| message.clone());
+        match code {
+            "field_not_found" => HolyDiverError::FieldNotFound(detail("field").unwrap_or_default()),
+            "no_previous_value" => HolyDiverError::NoPreviousValue(detail("field").unwrap_or_default()),
+            "version_gone" => HolyDiverError::VersionGone(detail("version").unwrap_or_default()),
+            "too_many_keys" => HolyDiverError::TooManyKeys { requested: count("requested"), max: count("max") },
+            "value_too_large" => HolyDiverError::ValueTooLarge(reason()),
+            "invalid_json" => HolyDiverError::InvalidJson(reason()),
+            "invalid_document" => HolyDiverError::InvalidDocument(reason()),
+            "invalid_query" => HolyDiverError::InvalidQuery(reason()),
+            "route_not_found" => HolyDiverError::RouteNotFound(detail("path").unwrap_or_default()),
+            "schema_violation" => match serde_json::from_value(details.clone()) {
+                Ok(violation) => HolyDiverError::SchemaViolation(violation),
+                Err(_) => HolyDiverError::Internal(anyhow!(message)),
+            },
+            "invalid_field_name" => match serde_json::from_value(details.clone()) {
+                Ok(invalid) => HolyDiverError::InvalidFieldName(invalid),
+                Err(_) => HolyDiverError::Internal(anyhow!(message)),
+            },
+            "not_an_integer" => HolyDiverError::NotAnInteger(detail("field").unwrap_or_default()),
+            "broadcast_backpressure" => HolyDiverError::BroadcastBackpressure,
+            "insufficient_storage" => HolyDiverError::InsufficientStorage(reason()),
+            "document_too_large" => HolyDiverError::DocumentTooLarge { size: count("size"), growth: count("growth"), max: count("max") },
+            "quota_exceeded" => match serde_json::from_value(details.clone()) {
+                Ok(exceeded) => HolyDiverError::QuotaExceeded(exceeded),
+                Err(_) => HolyDiverError::Internal(anyhow!(message)),
+            },
+            "rate_limited" => HolyDiverError::RateLimited,
+            "document_structure" => HolyDiverError::DocumentStructure(reason()),
+            "invalid_setting" => HolyDiverError::InvalidSetting { setting: detail("setting").unwrap_or_default(), message: reason() },
+            "lease_held" => match serde_json::from_value(details.clone()) {
+                Ok(lease) => HolyDiverError::LeaseHeld(lease),
+                Err(_) => HolyDiverError::Internal(anyhow!(message)),
+            },
+            "not_lease_holder" => HolyDiverError::NotLeaseHolder { name: detail("name").unwrap_or_default(), holder: detail("holder") },
+            "unknown_container" => HolyDiverError::UnknownContainer(detail("container").unwrap_or_default()),
+            "unknown_member" => HolyDiverError::UnknownMember(detail("member").unwrap_or_default()),
+            "peer_unavailable" => HolyDiverError::PeerUnavailable { member: detail("member").unwrap_or_default(), reason: reason() },
+            "request_timeout" => match details.get("timeout_stage").cloned().map(serde_json::from_value::<Phase>) {
+                Some(Ok(stage)) => HolyDiverError::RequestTimeout {
+                    stage,
+                    timeout: Duration::from_millis(details.get("timeout_ms").and_then(Value::as_u64).unwrap_or_default()),
+                },
+                _ => HolyDiverError::Internal(anyhow!(message)),
+            },
+            "shutting_down" => HolyDiverError::ShuttingDown,
+            // nodes before read_only answered with insufficient_members
+            "read_only" | "insufficient_members" =>
+                HolyDiverError::ReadOnly(ReadOnlyReason::InsufficientMembers { members: count("members"), min: count("min_members") }),
+            "unauthorized" => HolyDiverError::Unauthorized,
+            "forbidden" => HolyDiverError::Forbidden(Denied {
+                resource: detail("resource").unwrap_or_default(),
+                required: detail("required").unwrap_or_default(),
+                scope: detail("scope"),
+            }),
+            "internal_error" => HolyDiverError::Internal(anyhow!(reason())),
+            other => HolyDiverError::Internal(anyhow!("{} ({}): {}", other, status, message)),
+        }
+    }
+}
+
+impl Display for HolyDiverError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            HolyDiverError::FieldNotFound(field) => write!(f, "field {} does not exist", field),
+            HolyDiverError::NoPreviousValue(field) => write!(f, "{} has no previous value to revert to", field),
+            HolyDiverError::VersionGone(version) => write!(f, "version {} can no longer be resolved", version),
+            HolyDiverError::TooManyKeys { requested, max } => write!(f, "at most {} keys can be requested at once, got {}", max, requested),
+            HolyDiverError::ValueTooLarge(reason) => write!(f, "value too large: {}", reason),
+            HolyDiverError::InvalidJson(reason) => write!(f, "invalid JSON body: {}", reason),
+            HolyDiverError::InvalidDocument(reason) => write!(f, "not a valid automerge document: {}", reason),
+            HolyDiverError::InvalidQuery(reason) => write!(f, "invalid query: {}", reason),
+            HolyDiverError::RouteNotFound(path) => write!(f, "no route for {}", path),
+            HolyDiverError::SchemaViolation(violation) => write!(f, "{}", violation),
+            HolyDiverError::InvalidFieldName(invalid) => write!(f, "{}", invalid),
+            HolyDiverError::NotAnInteger(field) => write!(f, "{} can't be incremented, its value is no integer", field),
+            HolyDiverError::BroadcastBackpressure => write!(f, "the broadcast queue is full, try again later"),
+            HolyDiverError::InsufficientStorage(reason) => write!(f, "writes are rejected until the state can be persisted again: {}", reason),
+            HolyDiverError::DocumentTooLarge { size, growth, max } =>
+                write!(f, "the document would grow from about {} by {} bytes beyond the maximum of {} bytes", size, growth, max),
+            HolyDiverError::QuotaExceeded(exceeded) => write!(f, "{}", exceeded),
+            HolyDiverError::RateLimited => write!(f, "too many requests, try again later"),
+            HolyDiverError::InvalidSetting { setting, message } => write!(f, "invalid value for {}: {}", setting, message),
+            HolyDiverError::DocumentStructure(reason) => write!(f, "the document can't be read: {}", reason),
+            HolyDiverError::LeaseHeld(lease) => write!(f, "lease {} is held by {}", lease.name, lease.holder),
+            HolyDiverError::NotLeaseHolder { name, holder: Some(holder) } => write!(f, "lease {} is held by {}", name, holder),
+            HolyDiverError::NotLeaseHolder { name, holder: None } => write!(f, "lease {} is not held by anyone", name),
+            HolyDiverError::UnknownContainer(container) => write!(f, "no container {} is declared", container),
+            HolyDiverError::UnknownMember(member) => write!(f, "{} is no active member of the cluster", member),
+            HolyDiverError::PeerUnavailable { member, reason } => write!(f, "{} is unavailable: {}", member, reason),
+            HolyDiverError::RequestTimeout { stage, timeout } => write!(f, "the request did not finish within {:?}, it was in the {} phase", timeout, stage),
+            HolyDiverError::ShuttingDown => write!(f, "the node is shutting down, write to another member"),
+            HolyDiverError::ReadOnly(ReadOnlyReason::InsufficientMembers { members, min }) =>
+                write!(f, "the node is read-only: writes need at least {} members but only {} are up", min, members),
+            HolyDiverError::Unauthorized => write!(f, "a valid bearer token is required"),
+            HolyDiverError::Forbidden(Denied { resource, required, scope: Some(scope) }) =>
+                write!(f, "{} requires {} access but the token only has {}", resource, required, scope),
+            HolyDiverError::Forbidden(Denied { resource, required, scope: None }) =>
+                write!(f, "{} requires {} access which the token does not have", resource, required),
+            HolyDiverError::Internal(e) => write!(f, "internal error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for HolyDiverError {}
+
+impl From<anyhow::Error> for HolyDiverError {
+    fn from(error: anyhow::Error) -> Self {
+        // errors raised as HolyDiverError deeper down keep their specific code
+        match error.downcast::<HolyDiverError>() {
+            Ok(e) => e,
+            Err(e) => match e.downcast::<DocStructureError>() {
+                Ok(e) => e.into(),
+                Err(e) => match e.downcast::<ContainerError>() {
+                    Ok(e) => e.into(),
+                    Err(e) => HolyDiverError::Internal(e),
+                },
+            },
+        }
+    }
+}
+
+impl From<DocStructureError> for HolyDiverError {
+    fn from(error: DocStructureError) -> Self {
+        HolyDiverError::DocumentStructure(error.to_string())
+    }
+}
+
+impl From<ContainerError> for HolyDiverError {
+    fn from(error: ContainerError) -> Self {
+        match error {
+            ContainerError::Undeclared(container) => HolyDiverError::UnknownContainer(container),
+            other => HolyDiverError::DocumentStructure(other.to_string()),
+        }
+    }
+}
+
+impl From<LeaseError> for HolyDiverError {
+    fn from(error: LeaseError) -> Self {
+        match error {
+            LeaseError::Held(lease) => HolyDiverError::LeaseHeld(lease),
+            LeaseError::NotHolder { name, lease } => HolyDiverError::NotLeaseHolder { name, holder: lease.map(|l| l.holder) },
+            LeaseError::Automerge(e) => HolyDiverError::Internal(e.into()),
+        }
+    }
+}
+
+#[cfg(feature = "rest")]
+impl ResponseError for HolyDiverError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            HolyDiverError::FieldNotFound(_) | HolyDiverError::RouteNotFound(_)
+            | HolyDiverError::UnknownMember(_) | HolyDiverError::UnknownContainer(_) => StatusCode::NOT_FOUND,
+            HolyDiverError::NoPreviousValue(_) | HolyDiverError::LeaseHeld(_)
+            | HolyDiverError::NotLeaseHolder { .. } => StatusCode::CONFLICT,
+            HolyDiverError::VersionGone(_) => StatusCode::GONE,
+            HolyDiverError::Unauthorized => StatusCode::UNAUTHORIZED,
+            HolyDiverError::Forbidden(_) => StatusCode::FORBIDDEN,
+            HolyDiverError::TooManyKeys { .. } | HolyDiverError::InvalidJson(_)
+            | HolyDiverError::InvalidDocument(_) | HolyDiverError::InvalidQuery(_)
+            | HolyDiverError::InvalidSetting { .. } | HolyDiverError::InvalidFieldName(_) => StatusCode::BAD_REQUEST,
+            HolyDiverError::ValueTooLarge(_) => StatusCode::PAYLOAD_TOO_LARGE,
+            HolyDiverError::SchemaViolation(_) | HolyDiverError::NotAnInteger(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            HolyDiverError::BroadcastBackpressure | HolyDiverError::RequestTimeout { .. }
+            | HolyDiverError::ShuttingDown | HolyDiverError::ReadOnly(_) => StatusCode::SERVICE_UNAVAILABLE,
+            HolyDiverError::InsufficientStorage(_) | HolyDiverError::DocumentTooLarge { .. } => StatusCode::INSUFFICIENT_STORAGE,
+            HolyDiverError::RateLimited => StatusCode::TOO_MANY_REQUESTS,
+            HolyDiverError::PeerUnavailable { .. } => StatusCode::GATEWAY_TIMEOUT,
+            // too many keys are rejected like too many requests, too many bytes like a full disk
+            HolyDiverError::QuotaExceeded(QuotaExceeded { limit: QuotaLimit::MaxKeys, .. }) => StatusCode::TOO_MANY_REQUESTS,
+            HolyDiverError::QuotaExceeded(QuotaExceeded { limit: QuotaLimit::MaxBytes, .. }) => StatusCode::INSUFFICIENT_STORAGE,
+            HolyDiverError::Internal(_) | HolyDiverError::DocumentStructure(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        let mut response = HttpResponse::build(self.status_code());
+        if let HolyDiverError::ShuttingDown = self {
+            response.insert_header((header::RETRY_AFTER, super::shutdown::DRAIN_RETRY_AFTER_SECS.to_string()));
+        }
+        response.json(ErrorEnvelope {
+            error: ErrorBody {
+                code: self.code(),
+                message: self.to_string(),
+                details: self.details(),
+            }
+        })
+    }
+}
+
+// used as actix JsonConfig::error_handler so malformed bodies get the same envelope
+#[cfg(feature = "rest")]
+pub fn json_error_handler(error: JsonPayloadError, _req: &HttpRequest) -> actix_web::Error {
+    match error {
+        JsonPayloadError::Overflow { limit } | JsonPayloadError::OverflowKnownLength { limit, .. } =>
+            HolyDiverError::ValueTooLarge(format!("request body exceeds {} bytes", limit)).into(),
+        other => HolyDiverError::InvalidJson(other.to_string()).into(),
+    }
+}
+
+#[cfg(all(test, feature = "rest"))]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    // the status and body of the response, and the error a client reads back from them
+    async fn respond(error: HolyDiverError) -> (StatusCode, Value, HolyDiverError) {
+        let response = error.error_response();
+        let status = response.status();
+        let body = actix_web::body::to_bytes(response.into_body()).await.unwrap();
+        let body = std::str::from_utf8(&body).unwrap();
+        (status, serde_json::from_str(body).unwrap(), HolyDiverError::from_response(status.as_u16(), body))
+    }
+
+    #[actix_web::test]
+    async fn failures_are_answered_with_the_error_envelope() {
+        let cases = vec![
+            (HolyDiverError::FieldNotFound("color".to_owned()), StatusCode::NOT_FOUND, json!({ "field": "color" })),
+            (HolyDiverError::TooManyKeys { requested: 300, max: 256 }, StatusCode::BAD_REQUEST, json!({ "requested": 300, "max": 256 })),
+            (HolyDiverError::InvalidJson("expected value".to_owned()), StatusCode::BAD_REQUEST, json!({ "reason": "expected value" })),
+            (HolyDiverError::DocumentTooLarge { size: 900, growth: 200, max: 1000 }, StatusCode::INSUFFICIENT_STORAGE,
+                json!({ "size": 900, "growth": 200, "max": 1000 })),
+            (HolyDiverError::ReadOnly(ReadOnlyReason::InsufficientMembers { members: 1, min: 3 }), StatusCode::SERVICE_UNAVAILABLE,
+                json!({ "cause": "insufficient_members", "members": 1, "min_members": 3 })),
+            (HolyDiverError::Internal(anyhow!("disk on fire")), StatusCode::INTERNAL_SERVER_ERROR, json!({ "reason": "disk on fire" })),
+        ];
+        for (error, expected_status, details) in cases {
+            let code = error.code();
+            let message = error.to_string();
+            let (status, envelope, read_back) = respond(error).await;
+            assert_eq!(status, expected_status, "{}", code);
+            assert_eq!(envelope, json!({ "error": { "code": code, "message": message, "details": details } }));
+            assert_eq!(read_back.code(), code);
+        }
+    }
+
+    #[actix_web::test]
+    async fn errors_without_details_leave_them_out() {
+        let response = HolyDiverError::ShuttingDown.error_response();
+        assert!(response.headers().contains_key(header::RETRY_AFTER));
+        let (status, envelope, read_back) = respond(HolyDiverError::ShuttingDown).await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(envelope["error"]["code"], "shutting_down");
+        assert!(envelope["error"].get("details").is_none());
+        assert_eq!(read_back.code(), "shutting_down");
+    }
+
+    #[test]
+    fn responses_without_envelope_are_rate_limited_or_internal() {
+        assert_eq!(HolyDiverError::from_response(429, "Too Many Requests").code(), "rate_limited");
+        assert_eq!(HolyDiverError::from_response(502, "<html>Bad Gateway</html>").code(), "internal_error");
+    }
+}
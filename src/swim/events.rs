@@ -0,0 +1,95 @@
+use std::{future::Future, net::SocketAddr};
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast;
+
+use super::departures::DownReason;
+
+// Every subscriber of a channel can fall behind by this many events by default,
+// older events are dropped for it and reported as lagged on its next receive.
+pub const DEFAULT_QUEUE_SIZE: usize = 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ChangeKind {
+    // the field was written, locally or by a merge
+    Updated,
+    // the field was deleted, locally or by a merge
+    Deleted,
+    // a recent local write lost against a remote write during a merge
+    Overridden,
+}
+
+/// Notification about a change of a field in the `values` map.
+/// `winning_value` and `winning_actor` describe the value in effect after the change, `previous_value`
+/// the one before it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FieldChange {
+    pub kind: ChangeKind,
+    pub field: String,
+    // only set for Overridden, None if the local write was a delete
+    pub local_value: Option<String>,
+    // None if the field was deleted
+    pub winning_value: Option<String>,
+    pub winning_actor: Option<String>,
+    // whether the change was merged from another member rather than written on this node
+    #[serde(default)]
+    pub remote: bool,
+    // None if the field was absent or deleted before the change
+    #[serde(default)]
+    pub previous_value: Option<String>,
+    // X-Request-Id of the REST request that made a local change, see with_request_id
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
+}
+
+tokio::task_local! {
+    static REQUEST_ID: String;
+}
+
+// Runs future with request_id as the id of the request it serves, local changes made by it carry the id.
+pub async fn with_request_id<F: Future>(request_id: String, future: F) -> F::Output {
+    REQUEST_ID.scope(request_id, future).await
+}
+
+// the request id of the current task, see with_request_id
+pub fn current_request_id() -> Option<String> {
+    REQUEST_ID.try_with(Clone::clone).ok()
+}
+
+pub type FieldChangeSender = broadcast::Sender<FieldChange>;
+pub type FieldChangeReceiver = broadcast::Receiver<FieldChange>;
+
+pub fn channel(queue_size: usize) -> FieldChangeSender {
+    broadcast::channel(queue_size).0
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MemberEventKind {
+    Up,
+    Down,
+    // foca renewed the identity of this node after it was declared down, address is this node
+    #[serde(rename = "self-renewed")]
+    SelfRenewed,
+}
+
+/// A member joining or leaving the effective member list, or this node renewing its identity.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MemberEvent {
+    pub timestamp: chrono::DateTime<chrono::Utc>,
+    pub event: MemberEventKind,
+    pub address: SocketAddr,
+    // number of members after the change, including this node
+    pub member_count: usize,
+    // only for Down, whether the member left or failed, see departures
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reason: Option<DownReason>,
+}
+
+pub type MemberEventSender = broadcast::Sender<MemberEvent>;
+pub type MemberEventReceiver = broadcast::Receiver<MemberEvent>;
+
+pub fn member_channel(queue_size: usize) -> MemberEventSender {
+    broadcast::channel(queue_size).0
+}
@@ -0,0 +1,177 @@
+use std::{
+    collections::BTreeMap, fmt::{Display, Formatter}, str::FromStr,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+// Rules for the names of fields, shared by local writes and the sanitation of merged fields. Local writes
+// breaking them fail with InvalidFieldName. Merged fields can't be rejected or deleted without the members
+// diverging or fighting over the field, so with --sanitize-field-names they are detected after every merge,
+// counted, logged and left out of the listings instead, see HolyDiverDataHandler::sanitize_field_names.
+// Keys of automerge documents are always valid UTF-8, so there are no undecodable names to detect.
+
+pub const MAX_FIELD_NAME_LEN: usize = 1024;
+// characters of an invalid name shown in logs, errors and the report
+const SHOWN_CHARS: usize = 64;
+// fields kept in the quarantine report, further ones are only counted
+pub const MAX_QUARANTINED_FIELDS: usize = 1000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "rest", derive(utoipa::ToSchema))]
+#[serde(rename_all = "snake_case")]
+pub enum FieldNameRule {
+    Empty,
+    // longer than MAX_FIELD_NAME_LEN bytes
+    TooLong,
+    // contains a control character like a newline, which breaks the text protocol and log lines
+    ControlCharacter,
+}
+
+impl FieldNameRule {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            FieldNameRule::Empty => "empty",
+            FieldNameRule::TooLong => "too_long",
+            FieldNameRule::ControlCharacter => "control_character",
+        }
+    }
+}
+
+/// A field name breaking one of the rules. The name is escaped and shortened, see shown.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "rest", derive(utoipa::ToSchema))]
+pub struct InvalidFieldName {
+    pub field: String,
+    // length of the full name in bytes
+    pub length: usize,
+    pub rule: FieldNameRule,
+}
+
+impl Display for InvalidFieldName {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self.rule {
+            FieldNameRule::Empty => write!(f, "field names must not be empty"),
+            FieldNameRule::TooLong => write!(f, "field name {} is {} bytes long, at most {} bytes are allowed",
+                self.field, self.length, MAX_FIELD_NAME_LEN),
+            FieldNameRule::ControlCharacter => write!(f, "field name {} contains control characters", self.field),
+        }
+    }
+}
+
+pub fn check(field_name: &str) -> Result<(), InvalidFieldName> {
+    let rule = if field_name.is_empty() {
+        FieldNameRule::Empty
+    } else if field_name.len() > MAX_FIELD_NAME_LEN {
+        FieldNameRule::TooLong
+    } else if field_name.chars().any(char::is_control) {
+        FieldNameRule::ControlCharacter
+    } else {
+        return Ok(());
+    };
+    Err(InvalidFieldName { field: shown(field_name), length: field_name.len(), rule })
+}
+
+pub fn is_valid(field_name: &str) -> bool {
+    check(field_name).is_ok()
+}
+
+// the name with control characters escaped and cut after SHOWN_CHARS characters, safe to log
+pub fn shown(field_name: &str) -> String {
+    let mut shown: String = field_name.chars().take(SHOWN_CHARS).flat_map(char::escape_default).collect();
+    if field_name.chars().nth(SHOWN_CHARS).is_some() {
+        shown.push_str("...");
+    }
+    shown
+}
+
+/// What happens to merged fields breaking the rules, see --sanitize-field-names.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FieldSanitation {
+    // merged fields aren't checked
+    #[default]
+    Off,
+    // invalid fields are counted, logged and left out of the listings
+    Hide,
+    // like hide, and the fields are also recorded in the report of /debug/invalid-fields
+    Quarantine,
+}
+
+impl Display for FieldSanitation {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FieldSanitation::Off => write!(f, "off"),
+            FieldSanitation::Hide => write!(f, "hide"),
+            FieldSanitation::Quarantine => write!(f, "quarantine"),
+        }
+    }
+}
+
+impl FromStr for FieldSanitation {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "off" => Ok(FieldSanitation::Off),
+            "hide" => Ok(FieldSanitation::Hide),
+            "quarantine" => Ok(FieldSanitation::Quarantine),
+            other => Err(format!("unknown field sanitation {:?}, expected off, hide or quarantine", other)),
+        }
+    }
+}
+
+/// A merged field breaking the rules that is still in the document.
+#[derive(Debug, Clone, Serialize)]
+#[cfg_attr(feature = "rest", derive(utoipa::ToSchema))]
+pub struct QuarantinedField {
+    #[serde(flatten)]
+    pub name: InvalidFieldName,
+    // actor of the change that wrote the field, None for fields found on startup
+    pub actor: Option<String>,
+    #[cfg_attr(feature = "rest", schema(value_type = String))]
+    pub first_seen: DateTime<Utc>,
+}
+
+/// The invalid fields of the document, by their full name.
+#[derive(Debug, Default)]
+pub struct Quarantine {
+    fields: BTreeMap<String, QuarantinedField>,
+    // invalid fields not kept since the report was full
+    overflow: u64,
+}
+
+impl Quarantine {
+    // returns whether the field was not quarantined yet
+    pub fn insert(&mut self, field_name: &str, name: InvalidFieldName, actor: Option<String>) -> bool {
+        if self.fields.contains_key(field_name) {
+            return false;
+        }
+        if self.fields.len() >= MAX_QUARANTINED_FIELDS {
+            self.overflow += 1;
+            return true;
+        }
+        self.fields.insert(field_name.to_owned(), QuarantinedField { name, actor, first_seen: Utc::now() });
+        true
+    }
+
+    // the field was deleted from the document
+    pub fn remove(&mut self, field_name: &str) {
+        self.fields.remove(field_name);
+    }
+
+    pub fn report(&self) -> InvalidFieldReport {
+        InvalidFieldReport {
+            fields: self.fields.values().cloned().collect(),
+            overflow: self.overflow,
+        }
+    }
+}
+
+/// Merged fields breaking the field name rules, by escaped name.
+#[derive(Debug, Clone, Default, Serialize)]
+#[cfg_attr(feature = "rest", derive(utoipa::ToSchema))]
+pub struct InvalidFieldReport {
+    pub fields: Vec<QuarantinedField>,
+    // invalid fields left out since the report holds at most MAX_QUARANTINED_FIELDS
+    pub overflow: u64,
+}
@@ -1,44 +1,442 @@
 use std::{
-    net::SocketAddr,
-    sync::{Arc, Mutex}, collections::HashSet,
+    collections::{BTreeMap, HashMap, HashSet}, net::SocketAddr,
+    sync::{Arc, Mutex}, time::{Duration, Instant},
 };
 
-use rand::{rngs::StdRng, SeedableRng};
-use foca::{Foca, Notification, PostcardCodec, Timer};
-use tokio::{net::UdpSocket, sync::mpsc::{self, Sender}};
-use log::{info, error, trace};
-use bytes::{BufMut, Bytes, BytesMut};
+use rand::{rngs::StdRng, seq::SliceRandom, Rng, SeedableRng};
+use foca::{Foca, Notification, Timer};
+use tokio::{net::UdpSocket, sync::{mpsc::{self, Sender}, oneshot, watch}};
+use log::{debug, info, error, trace, warn, Level};
+use serde::Serialize;
+use bytes::Bytes;
+use uuid::Uuid;
 
-use super::{core::{AccumulatingRuntime, FocaRuntimeConfig}, broadcast::{Tag, GossipMessage, craft_broadcast, DataHandler}};
+use super::{core::{AccumulatingRuntime, FocaRuntimeConfig}, broadcast::{Broadcast, Tag, GossipMessage, MessageType, broadcast_size, craft_broadcast, node_config_message, state_checksum_message, leave_message, receive_direct, decode_limit, DataHandler, ReceiveOutcome, NODE_LABELS_VERSION}};
 use super::types::ID;
-use super::members::Members;
+use super::bump_history::{load_bump_history, save_bump_history};
+use super::members::{Members, NodeLabels};
+use super::build_info::{build_info, node_labels};
+use super::clock::{CLOCK, WireTimestamp};
+use super::codec::SwimCodec;
 use super::broadcast::Handler;
+use super::seen_ops::SeenOperations;
+use super::metrics;
+use super::log_throttle::{self, ErrorCategory};
+use super::envelope::{self, IncomingFrame, Kind, ENVELOPE_VERSION, MAX_DATAGRAM_SIZE, RECEIVE_BUFFER_SIZE};
+use super::capture::{CaptureWriter, Direction};
+use super::chaos::Fate;
+use super::startup::{StartupError, JOIN_TIMEOUT_EXIT_CODE};
+use super::ledger::{BroadcastLedger, LedgerStatus};
+use super::renewals::{RenewalStatus, RenewalTracker, RENEWAL_STORM_COUNT, RENEWAL_STORM_WINDOW};
+use super::events::{MemberEvent, MemberEventKind, MemberEventSender};
+use super::node_id::load_or_create_node_id;
+use super::probes::{PeerProbe, PeerProber};
+use super::inbound_limits::{Budget, InboundLimiter, PeerInboundRate};
+use super::known_peers::{load_known_peers, save_known_peers};
+use super::departures::{Departure, Departures, DownReason};
+use super::consistency::{ChecksumTracker, ConsistencyReport};
+use super::apply_latency::ApplyLatencies;
+use super::merge_queue::MergeQueue;
+use super::hops::HopTrail;
+use super::join_state::{JoinProgress, JoinState};
+use super::strict_identity::{load_or_create_session_token, StrictIdentity, Unverified};
+
+// how often the announce is repeated while a join deadline is pending
+const ANNOUNCE_RETRY_INTERVAL: Duration = Duration::from_secs(5);
+
+// remembered peers announced to per round, and the maximum random delay before each of these announces
+const KNOWN_PEER_ANNOUNCES: usize = 3;
+const KNOWN_PEER_JITTER: Duration = Duration::from_millis(1000);
 
 enum Input<T> {
     Event(Timer<T>),
-    Data(Bytes),
-    Announce(T),
+    // a datagram for foca with the address it was received from and the envelope version it is encoded in
+    Data(SocketAddr, u8, Bytes),
+    Direct(SocketAddr, u8, Bytes),
+    Announce(SocketAddr),
+    JoinFailed(String),
 }
 #[derive(Debug)]
 pub enum FocaCommand {
     SendBroadcast((Tag, GossipMessage)),
+    // sends the message to a single member only, bypassing foca's broadcast backlog
+    SendDirect(ID, Tag, GossipMessage),
     HandleTimer(Timer<ID>),
-    HandleData(Bytes),
-    Announce(ID),
+    // received datagrams with the envelope version they are encoded in
+    HandleData(SocketAddr, u8, Bytes),
+    HandleDirect(SocketAddr, u8, Bytes),
+    // announces to whichever identity is at the address, see AnnounceTargets
+    Announce(SocketAddr),
+    // the join deadline passed without another member up, see JoinState::Failed
+    JoinFailed(String),
+    // replies with the addresses of the active members, including this node
+    Members(oneshot::Sender<Vec<SocketAddr>>),
+    // replies with the labels of the active members that were received so far, including this node
+    MemberLabels(oneshot::Sender<BTreeMap<SocketAddr, NodeLabels>>),
+    // sets a label of this node and gossips its labels again
+    SetLabel(String, String),
+    Status(oneshot::Sender<FocaStatus>),
+    // replies with the members that went down within the last day and why, see departures
+    Departures(oneshot::Sender<BTreeMap<SocketAddr, Departure>>),
+    // replies with the unix millis when the operation id was first seen, None if it wasn't or was forgotten
+    SeenOperation(Uuid, oneshot::Sender<Option<i64>>),
+    // replies with the remembered operation ids and when they were first seen, newest first
+    SeenOperations(oneshot::Sender<Vec<(Uuid, i64)>>),
+    // forgets the operation id so that the operation is applied again, replies with the number forgotten
+    ForgetOperation(Uuid, oneshot::Sender<usize>),
+    // forgets all operation ids, replies with the number forgotten
+    ClearSeenOperations(oneshot::Sender<usize>),
+    // replies with the latency probes of the members, None if probing is disabled
+    Probes(oneshot::Sender<Option<BTreeMap<SocketAddr, PeerProbe>>>),
+    // replies with what the source addresses sent recently, see inbound_limits
+    InboundRates(oneshot::Sender<BTreeMap<SocketAddr, PeerInboundRate>>),
+    // replies with the members whose identity is not verified, see strict_identity
+    UnverifiedMembers(oneshot::Sender<BTreeMap<SocketAddr, Unverified>>),
+    // gossips the checksum of the values of this node, see consistency
+    BroadcastChecksum,
+    // replies with the local checksum and the last ones received from the members
+    Consistency(oneshot::Sender<ConsistencyReport>),
+    // replies with the address of the first member acknowledging the AckedOperation with this id
+    AwaitAck(Uuid, oneshot::Sender<SocketAddr>),
+    // replies with the document of the member answering the StateRequest with this id, empty if it doesn't fit
+    // into a datagram
+    AwaitState(Uuid, oneshot::Sender<Bytes>),
+    // sends the heads of this node to the members, which answer with the changes of their own it is missing
+    CatchUp,
+    // persists the state that should survive a restart, tells the members this node is leaving and stops
+    // handling further commands
+    Shutdown,
+}
+
+/// Snapshot of foca's state for /cluster/info.
+#[derive(Debug, Clone, Serialize)]
+#[cfg_attr(feature = "rest", derive(utoipa::ToSchema))]
+pub struct FocaStatus {
+    #[cfg_attr(feature = "rest", schema(value_type = String, example = "127.0.0.1:9000"))]
+    pub identity: SocketAddr,
+    // persisted id of the node, stays the same across restarts
+    pub node_id: Uuid,
+    #[cfg_attr(feature = "rest", schema(value_type = String, example = "2024-05-01T12:00:00.123"))]
+    pub startup_time: chrono::NaiveDateTime,
+    // whether another member was seen up since startup
+    pub joined: bool,
+    // how far joining the cluster got, see join_state
+    pub join: JoinState,
+    pub members: usize,
+    // membership updates foca still has to disseminate
+    pub updates_backlog: usize,
+    // custom broadcasts foca still has to transmit, including the ones relayed for other members
+    pub broadcast_backlog: usize,
+    // received broadcasts waiting to be merged, see merge_queue
+    pub pending_merges: usize,
+    pub broadcasts: LedgerStatus,
+    // renewals of the identity of this node, frequent ones hint at lost probes
+    pub identity_renewals: RenewalStatus,
+}
+
+// size of the datagram send_direct produces for the message
+pub fn direct_message_size(tag: &Tag, message: &GossipMessage) -> usize {
+    envelope::ENVELOPE_SIZE + broadcast_size(tag, message)
+}
+
+async fn send_direct(tx_send_data: &Sender<(SocketAddr, Bytes)>, destination: SocketAddr, tag: Tag, message: GossipMessage) {
+    let direct_message = envelope::wrap(Kind::Direct, &craft_broadcast(tag, message).data);
+    if direct_message.len() > MAX_DATAGRAM_SIZE {
+        error!("Dropping direct message to {} of {} bytes, it does not fit into a datagram", destination, direct_message.len());
+    } else {
+        let _ignored_send_result = tx_send_data.send((destination, direct_message)).await;
+    }
+}
+
+fn publish_member_event(member_events: &MemberEventSender, event: MemberEventKind, address: SocketAddr, members: &Members, reason: Option<DownReason>) {
+    // sending only fails without subscribers
+    let _ = member_events.send(MemberEvent {
+        timestamp: chrono::Utc::now(),
+        event,
+        address,
+        member_count: members.len(),
+        reason,
+    });
+}
+
+// Foca drops whatever it could not handle, e.g. datagrams of members using another codec. Received data fails
+// the same way for every datagram of such a member, so those failures are logged throttled.
+fn report_foca_error(operation: &str, error: foca::Error) {
+    metrics::FOCA_ERRORS.with_label_values(&[operation]).inc();
+    match operation {
+        "data" => log_throttle::report(ErrorCategory::DecodeFailure, Level::Warn,
+            format_args!("Foca could not handle {}: {}", operation, error)),
+        _ => warn!("Foca could not handle {}: {}", operation, error),
+    }
+}
+
+// Announces to the seeds again until another member is up. A node that has not joined within join_timeout
+// exits with JOIN_TIMEOUT_EXIT_CODE, so that its supervisor restarts it or alerts instead of it running
+// as a single node cluster, e.g. because of a mistyped seed address. The foca loop marks the join as failed
+// first, so that the last state logged and reported is why the node exits.
+fn spawn_join_deadline(tx_foca: Sender<Input<ID>>, seeds: Vec<SocketAddr>, join_timeout: Duration, mut join: watch::Receiver<JoinState>) {
+    tokio::spawn(async move {
+        let deadline = tokio::time::Instant::now() + join_timeout;
+        while join.borrow().is_pending() {
+            let retry_at = (tokio::time::Instant::now() + ANNOUNCE_RETRY_INTERVAL).min(deadline);
+            tokio::select! {
+                changed = join.changed() => {
+                    // foca was shut down
+                    if changed.is_err() {
+                        return;
+                    }
+                    continue;
+                },
+                _ = tokio::time::sleep_until(retry_at) => {},
+            }
+            if tokio::time::Instant::now() >= deadline {
+                let reason = format!("could not join the cluster via {:?} within {:?}", seeds, join_timeout);
+                if tx_foca.send(Input::JoinFailed(reason)).await.is_ok() {
+                    // the state may still turn to joined if a member came up meanwhile
+                    while join.borrow().is_pending() {
+                        if join.changed().await.is_err() {
+                            break;
+                        }
+                    }
+                    if join.borrow().is_joined() {
+                        return;
+                    }
+                }
+                error!("Could not join the cluster via {:?} within {:?}, exiting", seeds, join_timeout);
+                std::process::exit(JOIN_TIMEOUT_EXIT_CODE);
+            }
+            info!("Not joined yet, announcing to {:?} again", seeds);
+            for seed in &seeds {
+                if tx_foca.send(Input::Announce(*seed)).await.is_err() {
+                    return;
+                }
+            }
+        }
+    });
+}
+
+// Announces to a few of the remembered peers until another member is up, right away without a seed and
+// only once the seed did not let this node join within ANNOUNCE_RETRY_INTERVAL otherwise. Each announce
+// is delayed randomly, so that nodes restarting at the same time don't announce in lockstep.
+fn spawn_peer_reconnect(tx_foca: Sender<Input<ID>>, mut peers: Vec<SocketAddr>, has_seed: bool, mut join: watch::Receiver<JoinState>) {
+    tokio::spawn(async move {
+        let mut rng = StdRng::from_entropy();
+        if has_seed {
+            tokio::select! {
+                changed = join.changed() => if changed.is_err() { return },
+                _ = tokio::time::sleep(ANNOUNCE_RETRY_INTERVAL) => {},
+            }
+        }
+        if join.borrow().is_pending() {
+            info!("Announcing to the remembered peers {:?}", peers);
+        }
+        while join.borrow().is_pending() {
+            peers.shuffle(&mut rng);
+            for peer in peers.iter().take(KNOWN_PEER_ANNOUNCES) {
+                tokio::time::sleep(rng.gen_range(Duration::ZERO..=KNOWN_PEER_JITTER)).await;
+                if !join.borrow().is_pending() {
+                    break;
+                }
+                debug!("Announcing to remembered peer {}", peer);
+                if tx_foca.send(Input::Announce(*peer)).await.is_err() {
+                    return;
+                }
+            }
+            tokio::select! {
+                changed = join.changed() => if changed.is_err() { return },
+                _ = tokio::time::sleep(ANNOUNCE_RETRY_INTERVAL) => {},
+            }
+        }
+    });
+}
+
+// Asks the command loop to gossip the checksum of this node every interval
+fn spawn_checksum_broadcast(interval: Duration, foca_command_sender: Sender<FocaCommand>, mut shutdown: watch::Receiver<bool>) {
+    tokio::spawn(async move {
+        loop {
+            tokio::select! {
+                _ = tokio::time::sleep(interval) => {},
+                _ = shutdown.changed() => break,
+            }
+            if foca_command_sender.send(FocaCommand::BroadcastChecksum).await.is_err() {
+                break;
+            }
+        }
+    });
+}
+
+// Gossips the labels of this node, foca passes the broadcast to the members it talks to next
+// Applies the queued merges one after the other on the blocking pool, so that foca's loop never waits for
+// automerge. AckedOperations are acknowledged to their origin once merged, and the apply latencies recorded.
+fn spawn_merge_worker(queue: MergeQueue, data_handler: Arc<Mutex<dyn DataHandler + Send + Sync>>, latencies: ApplyLatencies, identity: SocketAddr,
+    tx_send_data: Sender<(SocketAddr, Bytes)>, foca_command_sender: Sender<FocaCommand>, mut shutdown: watch::Receiver<bool>) {
+    tokio::spawn(async move {
+        loop {
+            let merge = tokio::select! {
+                merge = queue.pop() => merge,
+                _ = shutdown.changed() => break,
+            };
+            let data_handler = data_handler.clone();
+            let (message_type, payload, span) = (merge.message_type, merge.payload, merge.span);
+            let result = tokio::task::spawn_blocking(move || {
+                let _span = span.entered();
+                data_handler.lock().unwrap().handle_message(message_type, payload)
+            }).await;
+            metrics::MERGE_QUEUE_SECONDS.observe(merge.enqueued.elapsed().as_secs_f64());
+            match result {
+                Ok(Ok(())) => {
+                    for origin in merge.origins {
+                        latencies.record(origin, WireTimestamp::now(), Instant::now());
+                    }
+                    for (origin, operation_id) in merge.acks.into_iter().filter(|(origin, _)| *origin != identity) {
+                        send_direct(&tx_send_data, origin, Tag::SyncOperation { operation_id: Uuid::new_v4() },
+                            GossipMessage::new(MessageType::Ack, operation_id.as_bytes().to_vec())).await;
+                    }
+                },
+                Ok(Err(e)) => log_throttle::report(ErrorCategory::MergeFailure, Level::Error,
+                    format_args!("Could not handle broadcast with id {}: {:#}", merge.operation_id, e)),
+                Err(e) => log_throttle::report(ErrorCategory::MergeFailure, Level::Error,
+                    format_args!("Merging broadcast with id {} failed: {}", merge.operation_id, e)),
+            }
+            if queue.take_catch_up() {
+                info!("Merges were dropped, asking the members for the changes this node is missing");
+                metrics::MERGE_CATCH_UPS.inc();
+                if foca_command_sender.send(FocaCommand::CatchUp).await.is_err() {
+                    break;
+                }
+            }
+        }
+    });
+}
+
+// foca decodes the broadcasts added to it right away, in the version craft_broadcast encoded them in
+fn add_broadcast(foca: &mut Foca<ID, SwimCodec, StdRng, Handler>, broadcast: &Broadcast) -> Result<(), foca::Error> {
+    envelope::with_wire_version(envelope::send_version(), || foca.add_broadcast(broadcast.as_ref()))
+}
+
+fn add_node_config(foca: &mut Foca<ID, SwimCodec, StdRng, Handler>, identity: &ID, labels: &NodeLabels) {
+    // the members can't tell the version of this node then, but don't drop its broadcasts either
+    if envelope::send_version() < NODE_LABELS_VERSION {
+        return;
+    }
+    let (tag, message) = node_config_message(identity.addr, labels);
+    if let Err(e) = add_broadcast(foca, &craft_broadcast(tag, message)) {
+        report_foca_error("node config", e);
+    }
+}
+
+// Pings the members every probe interval, the Pongs are recorded by the receive loop
+fn spawn_prober(prober: PeerProber, identity: SocketAddr, foca_command_sender: Sender<FocaCommand>,
+    tx_send_data: Sender<(SocketAddr, Bytes)>, mut shutdown: watch::Receiver<bool>) {
+    tokio::spawn(async move {
+        loop {
+            tokio::select! {
+                _ = tokio::time::sleep(prober.interval()) => {},
+                _ = shutdown.changed() => break,
+            }
+            let (reply, members) = oneshot::channel();
+            if foca_command_sender.send(FocaCommand::Members(reply)).await.is_err() {
+                break;
+            }
+            let members = match members.await {
+                Ok(members) => members,
+                Err(_) => break,
+            };
+            prober.retain(&members);
+            for member in members.into_iter().filter(|member| *member != identity) {
+                let ping = envelope::wrap(Kind::Ping, &prober.ping(member, Instant::now()));
+                if tx_send_data.send((member, ping)).await.is_err() {
+                    return;
+                }
+            }
+        }
+    });
 }
 
 pub async fn setup_foca(runtime_config: FocaRuntimeConfig, data_handler: Box<Arc<Mutex<dyn DataHandler + Send + Sync>>>) -> Result<Sender<FocaCommand>, anyhow::Error> {
+    info!("Starting {}", build_info());
     let rng = StdRng::from_entropy();
-    let broadcast_handler = Handler::new(HashSet::new(), data_handler);
-    let identity = runtime_config.identity;
+    let seen_ops_store = runtime_config.store.clone();
+    let runtime_settings = runtime_config.runtime_settings.clone();
+    let seen_ops_horizon = Duration::from_secs(runtime_settings.read().unwrap().seen_ops_horizon_secs);
+    let seen_ops = Arc::new(Mutex::new(SeenOperations::load(&*seen_ops_store,
+        seen_ops_horizon,
+        runtime_config.seen_ops_max_entries)));
+    // direct messages bypass foca, so they are handed to the data handler from the command loop
+    let direct_data_handler: Arc<Mutex<dyn DataHandler + Send + Sync>> = (*data_handler).clone();
+    let direct_seen_ops = seen_ops.clone();
+    let mut broadcast_handler = Handler::new(seen_ops.clone(), data_handler);
+    let merge_queue = MergeQueue::new(runtime_config.merge_queue_size);
+    broadcast_handler.set_merge_queue(merge_queue.clone());
+    let merge_data_handler = direct_data_handler.clone();
+    let pending_acks = broadcast_handler.pending_acks();
+    let member_labels = broadcast_handler.member_labels();
+    broadcast_handler.set_startup_reply_window(runtime_config.startup_reply_window);
+    broadcast_handler.set_decode_limit(decode_limit(runtime_config.foca_config.max_packet_size.get()));
+    let inbound = InboundLimiter::new(runtime_config.inbound_limits);
+    broadcast_handler.set_inbound_limiter(inbound.clone());
+    let receive_inbound = inbound.clone();
+    let checksums = ChecksumTracker::new(runtime_config.identity.addr);
+    broadcast_handler.set_checksum_tracker(checksums.clone());
+    let latencies = ApplyLatencies::new(runtime_config.identity.addr);
+    broadcast_handler.set_apply_latencies(latencies.clone());
+    let merge_latencies = latencies.clone();
+    let trace_hops = runtime_config.hop_tracing.is_some();
+    if let Some(hop_tracing) = runtime_config.hop_tracing {
+        broadcast_handler.set_hop_tracing(runtime_config.identity.addr, hop_tracing);
+    }
+    for (kind, handler) in runtime_config.message_handlers {
+        broadcast_handler.register(kind, handler)?;
+    }
+    let checksum_interval = runtime_config.checksum_interval;
+    let node_id = load_or_create_node_id(&*runtime_config.store)?;
+    let mut strict_identity = runtime_config.strict_identity
+        .map(|key| load_or_create_session_token(&*runtime_config.store).map(|token| StrictIdentity::new(key, token)))
+        .transpose()?;
+    let startup_time = chrono::Utc::now().naive_utc();
+    // a bump used before the restart would be refused by the members still remembering it
+    let bump_store = runtime_config.store.clone();
+    let identity = runtime_config.identity.with_renewals(runtime_config.bump_strategy, &load_bump_history(&*bump_store));
+    if let Err(e) = save_bump_history(&*bump_store, &identity.bump_history()) {
+        warn!("{:#}", e);
+    }
+    let identity_addr = identity.addr;
+    let member_events = runtime_config.member_events;
+    let member_count = runtime_config.member_count;
+    let backlog_warning_threshold = runtime_config.broadcast_backlog_warning;
     let announce_to = runtime_config.announce_to;
+    let join_timeout = runtime_config.join_timeout;
+    let prober = runtime_config.probe_interval.map(PeerProber::new);
+    let known_peers_store = runtime_config.store.clone();
+    let departures_store = runtime_config.store.clone();
+    let mut departures = Departures::load(&*runtime_config.store);
+    let known_peers: Vec<SocketAddr> = if runtime_config.forget_peers {
+        Vec::new()
+    } else {
+        load_known_peers(&*runtime_config.store).into_iter()
+            .filter(|peer| *peer != identity.addr && !announce_to.contains(peer))
+            .collect()
+    };
 
+    envelope::set_send_version(runtime_config.wire_version)?;
+    if runtime_config.wire_version < ENVELOPE_VERSION {
+        info!("Sending protocol version {} instead of {}, see --wire-version", runtime_config.wire_version, ENVELOPE_VERSION);
+    }
     let mut foca = Foca::with_custom_broadcast(identity.clone(),
     runtime_config.foca_config.clone(),
-    rng, PostcardCodec,
+    rng, runtime_config.swim_codec,
     broadcast_handler);
+    let mut labels = node_labels();
+    member_labels.update(identity.addr, CLOCK.now(), labels.clone());
+    add_node_config(&mut foca, &identity, &labels);
+
+    let socket = Arc::new(UdpSocket::bind(runtime_config.bind_addr).await
+        .map_err(|e| StartupError::udp_bind(runtime_config.bind_addr, e))?);
 
-    let socket = Arc::new(UdpSocket::bind(runtime_config.bind_addr).await?);
+    let capture = runtime_config.capture_dir.clone()
+        .map(|dir| CaptureWriter::new(dir, identity.clone(), runtime_config.capture_max_file_size))
+        .transpose()?
+        .map(CaptureWriter::shared);
 
     // We'll create a task responsible to sending data through the
     // socket.
@@ -46,6 +444,8 @@ pub async fn setup_foca(runtime_config: FocaRuntimeConfig, data_handler: Box<Arc
     let (tx_send_data, mut rx_send_data) = mpsc::channel::<(SocketAddr, Bytes)>(100);
     // The socket writing task
     let write_socket = Arc::clone(&socket);
+    let write_capture = capture.clone();
+    let write_faults = runtime_config.fault_injector.clone();
     tokio::spawn(async move {
         while let Some((dst, data)) = rx_send_data.recv().await {
             // A more reasonable implementation would do some more stuff
@@ -54,7 +454,25 @@ pub async fn setup_foca(runtime_config: FocaRuntimeConfig, data_handler: Box<Arc
             //  * encryption (shared key, AES most likely)
             //  * an envelope with tag+version+checksum to allow
             //    protocol evolution
+            if let Some(capture) = &write_capture {
+                capture.lock().unwrap().record(Direction::Outbound, dst, &data);
+            }
+            match write_faults.as_ref().map(|faults| faults.outbound(&dst)) {
+                Some(Fate::Drop) => continue,
+                // delayed datagrams may overtake each other, as they would on a real network
+                Some(Fate::Deliver(delay)) if !delay.is_zero() => {
+                    let write_socket = Arc::clone(&write_socket);
+                    tokio::spawn(async move {
+                        tokio::time::sleep(delay).await;
+                        let _ignored_send_result = write_socket.send_to(&data, &dst).await;
+                    });
+                    continue;
+                },
+                _ => {},
+            }
+            let timer = metrics::UDP_SEND_SECONDS.start_timer();
             let _ignored_send_result = write_socket.send_to(&data, &dst).await;
+            timer.observe_duration();
         }
     });
 
@@ -65,34 +483,292 @@ pub async fn setup_foca(runtime_config: FocaRuntimeConfig, data_handler: Box<Arc
     // instead.
     let mut runtime:AccumulatingRuntime<ID> = AccumulatingRuntime::new();
     let mut members = Members::new();
-    members.add_member(identity);
+    members.add_member(&identity);
+    let mut ledger = BroadcastLedger::new();
+    let mut renewals = RenewalTracker::new();
+    let mut ack_waiters: HashMap<Uuid, oneshot::Sender<SocketAddr>> = HashMap::new();
+    let mut state_waiters: HashMap<Uuid, oneshot::Sender<Bytes>> = HashMap::new();
+    // addresses of members that went down, so that they can be caught up once they are back
+    let mut down_members: HashSet<SocketAddr> = HashSet::new();
     let tx_foca_copy = tx_foca.clone();
+    let pong_sender = tx_send_data.clone();
+    let probe_sender = tx_send_data.clone();
+    let receive_prober = prober.clone();
+    let report_prober = prober.clone();
 
     let (foca_command_sender, mut foca_command_receiver) = mpsc::channel::<FocaCommand>(100);
+    // stops the tasks not driven by commands once foca is shut down, which also releases the socket
+    let (shutdown_sender, shutdown) = watch::channel(false);
+    // announces are retried while a join deadline is pending or remembered peers are announced to
+    let retry_interval = (!known_peers.is_empty() || (join_timeout.is_some() && !announce_to.is_empty()))
+        .then_some(ANNOUNCE_RETRY_INTERVAL);
+    let join_progress = JoinProgress::new(match announce_to.is_empty() && known_peers.is_empty() {
+        true => JoinState::NotConfigured,
+        false => JoinState::Announcing { attempt: 0, next_retry: None },
+    }, retry_interval);
+    let join = join_progress.watch();
+    let status_merge_queue = merge_queue.clone();
+    spawn_merge_worker(merge_queue, merge_data_handler, merge_latencies, identity.addr, tx_send_data.clone(), foca_command_sender.clone(), shutdown.clone());
 
+    // periodically persisting seen operation ids so that a restarted node recognizes
+    // broadcasts that are still being gossiped around
+    let periodic_seen_ops = seen_ops.clone();
+    let periodic_seen_ops_store = seen_ops_store.clone();
+    let mut flush_shutdown = shutdown.clone();
     tokio::spawn(async move {
+        loop {
+            // settings are read on every iteration so that changes apply without a restart
+            let (flush_interval, horizon) = {
+                let settings = runtime_settings.read().unwrap();
+                (Duration::from_secs(settings.seen_ops_flush_interval_secs), Duration::from_secs(settings.seen_ops_horizon_secs))
+            };
+            tokio::select! {
+                _ = tokio::time::sleep(flush_interval) => {},
+                _ = flush_shutdown.changed() => break,
+            }
+            let mut seen_ops = periodic_seen_ops.lock().unwrap();
+            seen_ops.set_horizon(horizon);
+            seen_ops.save(&*periodic_seen_ops_store);
+        }
+    });
 
+    tokio::spawn(async move {
+        // set on Shutdown, the members are told this node leaves once the loop stopped
+        let mut leaving = false;
         while let Some(foca_event) = foca_command_receiver.recv().await {
             match foca_event {
-                FocaCommand::SendBroadcast((tag, message)) => {    
+                FocaCommand::SendBroadcast((tag, message)) => {
+                    let tag = match tag {
+                        Tag::SyncOperation { operation_id } if trace_hops => Tag::TracedOperation {
+                            operation_id,
+                            trail: HopTrail::new(identity.addr),
+                        },
+                        tag => tag,
+                    };
                     let broadcast = craft_broadcast(tag, message);
-                    let _ignore_result = foca.add_broadcast(broadcast.as_ref());
+                    if add_broadcast(&mut foca, &broadcast).is_ok() {
+                        metrics::BROADCASTS_ADDED.inc();
+                        if ledger.record_added(&tag, Instant::now()) {
+                            metrics::BROADCASTS_INVALIDATED.inc();
+                        }
+                    }
                 },
                 FocaCommand::HandleTimer(timer) => {
-                    let _ignore_result = foca.handle_timer(timer, &mut runtime);
+                    if let Err(e) = foca.handle_timer(timer, &mut runtime) {
+                        report_foca_error("timer", e);
+                    }
                 },
-                FocaCommand::HandleData(data) => {
-                    let _ignore_result = foca.handle_data(&data, &mut runtime);
+                FocaCommand::SendDirect(destination, tag, message) => {
+                    send_direct(&tx_send_data, destination.addr, tag, message).await;
+                },
+                FocaCommand::HandleData(from, version, data) => {
+                    inbound.set_source(Some(from));
+                    let handled = envelope::with_wire_version(version, || foca.handle_data(&data, &mut runtime));
+                    inbound.set_source(None);
+                    if let Err(e) = handled {
+                        report_foca_error("data", e);
+                    }
+                    let acks = std::mem::take(&mut *pending_acks.lock().unwrap());
+                    for (origin, operation_id) in acks {
+                        // own operations come back through gossip as well
+                        if origin != identity.addr {
+                            send_direct(&tx_send_data, origin, Tag::SyncOperation { operation_id: Uuid::new_v4() },
+                                GossipMessage::new(MessageType::Ack, operation_id.as_bytes().to_vec())).await;
+                        }
+                    }
+                },
+                FocaCommand::HandleDirect(from, version, data) => {
+                    let received = envelope::with_wire_version(version, || receive_direct(&direct_seen_ops, &direct_data_handler, &data));
+                    match received {
+                        Ok((ReceiveOutcome::Failed(e), _)) => log_throttle::report(ErrorCategory::MergeFailure, Level::Error,
+                            format_args!("Could not apply direct message from {}: {}", from, e)),
+                        Ok((ReceiveOutcome::Acknowledged(operation_id), _)) => {
+                            // later acks of the same operation find no waiter anymore
+                            if let Some(waiter) = ack_waiters.remove(&operation_id) {
+                                debug!("Operation {} was acknowledged by {}", operation_id, from);
+                                let _ignore_result = waiter.send(from);
+                            }
+                        },
+                        Ok((ReceiveOutcome::Leaving(member), _)) => {
+                            info!("member {} is leaving", member);
+                            departures.announce_leave(member, Instant::now());
+                        },
+                        Ok((ReceiveOutcome::IdentityChallenge { nonce }, _)) => match strict_identity.as_ref().map(|strict| strict.prove(&nonce)) {
+                            Some(Some(proof)) => send_direct(&tx_send_data, from, Tag::SyncOperation { operation_id: Uuid::new_v4() },
+                                GossipMessage::new(MessageType::IdentityProof, proof)).await,
+                            Some(None) => warn!("Ignoring malformed identity challenge of {}", from),
+                            None => debug!("Ignoring the identity challenge of {}, this node runs without --strict-identity", from),
+                        },
+                        Ok((ReceiveOutcome::IdentityProof { proof }, _)) => {
+                            if let Some(strict) = strict_identity.as_mut() {
+                                if strict.verify(from, &proof).is_none() {
+                                    debug!("Ignoring identity proof of {}, no challenge is outstanding", from);
+                                }
+                            }
+                        },
+                        Ok((ReceiveOutcome::State(request_id, state), _)) => {
+                            if let Some(waiter) = state_waiters.remove(&request_id) {
+                                debug!("Received the state of {} for request {}", from, request_id);
+                                let _ignore_result = waiter.send(state);
+                            }
+                        },
+                        Ok((_, replies)) => {
+                            for reply in replies {
+                                send_direct(&tx_send_data, from, Tag::SyncOperation { operation_id: Uuid::new_v4() }, reply).await;
+                            }
+                        },
+                        Err(e) => log_throttle::report(ErrorCategory::DecodeFailure, Level::Error,
+                            format_args!("Could not handle direct message from {}: {}", from, e)),
+                    }
                 },
                 FocaCommand::Announce(destination) => {
-                    let _ignore_result = foca.announce(destination, &mut runtime);
+                    // a fresh bump, the seed may have renewed its identity since the last announce
+                    if let Err(e) = foca.announce(ID::new(destination), &mut runtime) {
+                        report_foca_error("announce", e);
+                    }
+                    join_progress.announced(destination);
+                },
+                FocaCommand::JoinFailed(reason) => {
+                    if join_progress.state().is_pending() {
+                        join_progress.failed(reason);
+                    }
+                },
+                FocaCommand::Members(reply) => {
+                    let _ignore_result = reply.send(members.sorted_addrs());
+                },
+                FocaCommand::MemberLabels(reply) => {
+                    let _ignore_result = reply.send(members.addrs()
+                        .filter_map(|addr| member_labels.get(addr).map(|labels| (*addr, labels)))
+                        .collect());
+                },
+                FocaCommand::SetLabel(key, value) => {
+                    if labels.get(&key) != Some(&value) {
+                        labels.insert(key, value);
+                        member_labels.update(identity.addr, CLOCK.now(), labels.clone());
+                        add_node_config(&mut foca, &identity, &labels);
+                    }
+                },
+                FocaCommand::Departures(reply) => {
+                    let _ignore_result = reply.send(departures.recent());
+                },
+                FocaCommand::SeenOperation(operation_id, reply) => {
+                    let _ignore_result = reply.send(seen_ops.lock().unwrap().seen_at(&operation_id));
+                },
+                FocaCommand::SeenOperations(reply) => {
+                    let _ignore_result = reply.send(seen_ops.lock().unwrap().entries());
+                },
+                FocaCommand::ForgetOperation(operation_id, reply) => {
+                    let forgotten = seen_ops.lock().unwrap().forget(&operation_id);
+                    if forgotten {
+                        warn!("Forgot seen operation {}, it is applied again when it arrives", operation_id);
+                    }
+                    let _ignore_result = reply.send(usize::from(forgotten));
+                },
+                FocaCommand::ClearSeenOperations(reply) => {
+                    let forgotten = seen_ops.lock().unwrap().clear();
+                    warn!("Forgot all {} seen operations, they are applied again when they arrive", forgotten);
+                    let _ignore_result = reply.send(forgotten);
+                },
+                FocaCommand::Probes(reply) => {
+                    let _ignore_result = reply.send(report_prober.as_ref().map(|prober| prober.report(Instant::now())));
+                },
+                FocaCommand::InboundRates(reply) => {
+                    let _ignore_result = reply.send(inbound.report(Instant::now()));
+                },
+                FocaCommand::UnverifiedMembers(reply) => {
+                    let _ignore_result = reply.send(strict_identity.as_ref().map(StrictIdentity::unverified).unwrap_or_default());
+                },
+                FocaCommand::BroadcastChecksum => {
+                    let checksum = direct_data_handler.lock().unwrap().state_checksum();
+                    let (tag, message) = state_checksum_message(identity.addr, &checksum);
+                    if let Err(e) = add_broadcast(&mut foca, &craft_broadcast(tag, message)) {
+                        report_foca_error("state checksum", e);
+                    }
+                },
+                FocaCommand::Consistency(reply) => {
+                    let checksum = direct_data_handler.lock().unwrap().state_checksum();
+                    let mut report = checksums.report(checksum_interval.is_some(), checksum);
+                    report.apply_latency = latencies.report(Instant::now());
+                    let _ignore_result = reply.send(report);
+                },
+                FocaCommand::CatchUp => {
+                    // the heads of this node, as it answers a HeadsRequest
+                    let heads = direct_data_handler.lock().unwrap().handle_direct(MessageType::HeadsRequest, Bytes::new());
+                    match heads {
+                        Ok(heads) => {
+                            for member in members.sorted_addrs().into_iter().filter(|addr| *addr != identity.addr) {
+                                for message in heads.iter().cloned() {
+                                    send_direct(&tx_send_data, member, Tag::SyncOperation { operation_id: Uuid::new_v4() }, message).await;
+                                }
+                            }
+                        },
+                        Err(e) => error!("Could not read the heads to catch up: {:#}", e),
+                    }
+                },
+                FocaCommand::AwaitAck(operation_id, reply) => {
+                    // waiters whose write already timed out are dropped
+                    ack_waiters.retain(|_, waiter| !waiter.is_closed());
+                    ack_waiters.insert(operation_id, reply);
+                },
+                FocaCommand::AwaitState(request_id, reply) => {
+                    state_waiters.retain(|_, waiter| !waiter.is_closed());
+                    state_waiters.insert(request_id, reply);
+                },
+                FocaCommand::Status(reply) => {
+                    let _ignore_result = reply.send(FocaStatus {
+                        identity: identity.addr,
+                        node_id,
+                        startup_time,
+                        joined: join_progress.state().is_joined(),
+                        join: join_progress.state(),
+                        members: foca.num_members(),
+                        updates_backlog: foca.updates_backlog(),
+                        broadcast_backlog: foca.custom_broadcast_backlog(),
+                        pending_merges: status_merge_queue.len(),
+                        broadcasts: ledger.status(Instant::now()),
+                        identity_renewals: renewals.status(),
+                    });
+                },
+                FocaCommand::Shutdown => {
+                    info!("Shutting down foca");
+                    seen_ops.lock().unwrap().save(&*seen_ops_store);
+                    let peers: Vec<SocketAddr> = members.sorted_addrs().into_iter()
+                        .filter(|addr| *addr != identity.addr)
+                        .collect();
+                    // the members as of the shutdown are the ones to announce to on the next start
+                    if !peers.is_empty() {
+                        if let Err(e) = save_known_peers(&*known_peers_store, &peers) {
+                            warn!("{:#}", e);
+                        }
+                    }
+                    // before foca gossips this node down, so that the members know it didn't fail
+                    for member in peers {
+                        let (tag, message) = leave_message(identity.addr);
+                        send_direct(&tx_send_data, member, tag, message).await;
+                    }
+                    let _ignored_send_error = shutdown_sender.send(true);
+                    leaving = true;
+                    break;
                 },
             }
 
+            let now = Instant::now();
+            let backlog = foca.custom_broadcast_backlog();
+            ledger.reconcile(backlog);
+            metrics::BROADCAST_BACKLOG.set(backlog as i64);
+            metrics::BROADCAST_OLDEST_PENDING_SECONDS.set(ledger.oldest_pending_age(now)
+                .map_or(0.0, |age| age.as_secs_f64()));
+            if ledger.should_warn(backlog, backlog_warning_threshold, now) {
+                warn!("The broadcast backlog has been above {} for a minute, currently {} broadcasts are pending", backlog_warning_threshold, backlog);
+            }
+            if let Some(strict) = strict_identity.as_mut() {
+                strict.expire(now);
+            }
+
             // First we submit everything that needs to go to the network
             while let Some((dst, data)) = runtime.to_send.pop() {
                 // ToSocketAddrs would be the fancy thing to use here
-                let _ignored_send_result = tx_send_data.send((dst.addr, data)).await;
+                let _ignored_send_result = tx_send_data.send((dst.addr, envelope::wrap(Kind::Foca, &data))).await;
             }
             // Then schedule what needs to be scheduled
             while let Some((delay, event)) = runtime.to_schedule.pop() {
@@ -123,11 +799,68 @@ pub async fn setup_foca(runtime_config: FocaRuntimeConfig, data_handler: Box<Arc
                 match notification {
                     Notification::MemberUp(id) => {
                         info!("member with id {:?} up", id);
-                        active_list_has_changed |= members.add_member(id);
+                        if down_members.remove(&id.addr) {
+                            // the member likely missed changes while it was down, asking for its heads
+                            // to send it the journaled changes it is missing
+                            info!("member {:?} is back, requesting its heads", id);
+                            send_direct(&tx_send_data, id.addr, Tag::SyncOperation { operation_id: Uuid::new_v4() },
+                                GossipMessage::new(MessageType::HeadsRequest, Vec::new())).await;
+                        }
+                        if id.addr != identity.addr {
+                            join_progress.member_up(id.addr);
+                            // every identity of a member proves it holds the token pinned for its address
+                            if let Some(strict) = strict_identity.as_mut() {
+                                let challenge = strict.challenge(&id, Instant::now());
+                                send_direct(&tx_send_data, id.addr, Tag::SyncOperation { operation_id: Uuid::new_v4() },
+                                    GossipMessage::new(MessageType::IdentityChallenge, challenge)).await;
+                            }
+                        }
+                        if departures.record_up(&id.addr) {
+                            if let Err(e) = departures.save(&*departures_store) {
+                                warn!("{:#}", e);
+                            }
+                        }
+                        if members.add_member(&id) {
+                            active_list_has_changed = true;
+                            publish_member_event(&member_events, MemberEventKind::Up, id.addr, &members, None);
+                            // the broadcast of the labels likely ran out of transmissions before the member came up
+                            if id.addr != identity.addr {
+                                add_node_config(&mut foca, &identity, &labels);
+                            }
+                        }
                     },
                     Notification::MemberDown(id) => {
                         info!("member with id {:?} down", id);
-                        active_list_has_changed |= members.remove_member(id);
+                        down_members.insert(id.addr);
+                        if members.remove_member(&id) {
+                            active_list_has_changed = true;
+                            if let Some(strict) = strict_identity.as_mut() {
+                                strict.member_gone(&id.addr);
+                            }
+                            let reason = departures.record_down(id.addr, Instant::now(), chrono::Utc::now());
+                            info!("member {} {}", id.addr, match reason {
+                                DownReason::Left => "left",
+                                _ => "failed",
+                            });
+                            if let Err(e) = departures.save(&*departures_store) {
+                                warn!("{:#}", e);
+                            }
+                            publish_member_event(&member_events, MemberEventKind::Down, id.addr, &members, Some(reason));
+                        }
+                    },
+                    Notification::Rejoin(id) => {
+                        info!("renewed identity to {:?} after being declared down", id);
+                        metrics::IDENTITY_RENEWALS.inc();
+                        if renewals.record(Instant::now(), chrono::Utc::now()) {
+                            warn!("The identity of this node was renewed more than {} times within {:?}, other members keep \
+                                declaring it down. Probes are likely lost or answered too late, consider raising the probe \
+                                period and timeouts of foca", RENEWAL_STORM_COUNT, RENEWAL_STORM_WINDOW);
+                        }
+                        // the renewals of the identity share its history
+                        if let Err(e) = save_bump_history(&*bump_store, &identity.bump_history()) {
+                            warn!("{:#}", e);
+                        }
+                        publish_member_event(&member_events, MemberEventKind::SelfRenewed, id.addr, &members, None);
                     },
                     Notification::Idle => {
                         info!("cluster empty");
@@ -140,53 +873,124 @@ pub async fn setup_foca(runtime_config: FocaRuntimeConfig, data_handler: Box<Arc
 
             if active_list_has_changed {
                 info!("New members list: {:?}", members);
+                member_count.set(members.len());
+                checksums.retain(&members.sorted_addrs());
+                latencies.retain(&members.sorted_addrs());
+                let peers: Vec<SocketAddr> = members.sorted_addrs().into_iter()
+                    .filter(|addr| *addr != identity.addr)
+                    .collect();
+                if !peers.is_empty() {
+                    if let Err(e) = save_known_peers(&*known_peers_store, &peers) {
+                        warn!("{:#}", e);
+                    }
+                }
+            }
+        }
+        if leaving {
+            // gossips this node down right away instead of letting the members suspect it first
+            if let Err(e) = foca.leave_cluster(&mut runtime) {
+                report_foca_error("leave", e);
+            }
+            while let Some((dst, data)) = runtime.to_send.pop() {
+                let _ignored_send_result = tx_send_data.send((dst.addr, envelope::wrap(Kind::Foca, &data))).await;
             }
         }
     });
 
+    if let Some(prober) = prober {
+        info!("Probing the latency of the members every {:?}", prober.interval());
+        spawn_prober(prober, identity_addr, foca_command_sender.clone(), probe_sender, shutdown.clone());
+    }
+
+    if let Some(interval) = checksum_interval {
+        info!("Gossiping the checksum of the state every {:?}", interval);
+        spawn_checksum_broadcast(interval, foca_command_sender.clone(), shutdown.clone());
+    }
+
     let foca_command_sender_clone = foca_command_sender.clone();
     tokio::spawn(async move {
         while let Some(input) = rx_foca.recv().await {
 
             let result = match input {
                 Input::Event(timer) => foca_command_sender_clone.send(FocaCommand::HandleTimer(timer)).await,
-                Input::Data(data) => foca_command_sender_clone.send(FocaCommand::HandleData(data)).await,
+                Input::Data(from, version, data) => foca_command_sender_clone.send(FocaCommand::HandleData(from, version, data)).await,
+                Input::Direct(from, version, data) => foca_command_sender_clone.send(FocaCommand::HandleDirect(from, version, data)).await,
                 Input::Announce(destination) => foca_command_sender_clone.send(FocaCommand::Announce(destination)).await,
+                Input::JoinFailed(reason) => foca_command_sender_clone.send(FocaCommand::JoinFailed(reason)).await,
             };
 
-            // Every public foca result yields `()` on success, so there's
-            // nothing to do with Ok
-            if let Err(error) = result {
-                // And we'd decide what to do with each error, but Foca
-                // is pretty tolerant so we just log them and pretend
-                // all is fine
-                error!("Ignored Error: {}", error);
+            // sending only fails once the command loop stopped on shutdown
+            if result.is_err() {
+                break;
             }
         }
     });
 
     // Foca is running, we can tell it to announce to our target
-    if let Some(dst) = announce_to {
-        let _ignored_send_error = tx_foca.send(Input::Announce(dst)).await;
+    if !known_peers.is_empty() {
+        spawn_peer_reconnect(tx_foca.clone(), known_peers, !announce_to.is_empty(), join.clone());
+    }
+    for dst in &announce_to {
+        let _ignored_send_error = tx_foca.send(Input::Announce(*dst)).await;
+    }
+    if let Some(join_timeout) = join_timeout.filter(|_| !announce_to.is_empty()) {
+        spawn_join_deadline(tx_foca.clone(), announce_to, join_timeout, join);
     }
 
+    let mut receive_shutdown = shutdown;
+    let read_faults = runtime_config.fault_injector.clone();
     tokio::spawn(async move {
-        let buf_len = runtime_config.foca_config.max_packet_size.get();
-        let mut recv_buf = vec![0u8; buf_len];
+        // direct messages are not bound to foca's max_packet_size
+        let mut recv_buf = vec![0u8; RECEIVE_BUFFER_SIZE];
         // And finally, we receive forever
-        let mut databuf = BytesMut::new();
         loop {
-            match socket.recv_from(&mut recv_buf).await {
-                Ok((len, _from_addr)) => {
-                // Accordinly, we would undo everything that's done prior to
-                // sending: decompress, decrypt, remove the envelope
-                databuf.put_slice(&recv_buf[..len]);
-                let data_to_send = databuf.split().freeze();
-                trace!("Data to send: {:?}", data_to_send);
+            let received = tokio::select! {
+                received = socket.recv_from(&mut recv_buf) => received,
+                _ = receive_shutdown.changed() => break,
+            };
+            match received {
+                Ok((len, from_addr)) => {
+                let datagram = &recv_buf[..len];
+                trace!("Received {} bytes from {}", len, from_addr);
+                if read_faults.as_ref().is_some_and(|faults| faults.drops_inbound(&from_addr)) {
+                    continue;
+                }
+                if let Some(capture) = &capture {
+                    capture.lock().unwrap().record(Direction::Inbound, from_addr, datagram);
+                }
+                // before anything is decoded, SWIM messages only take from this budget
+                if !receive_inbound.admit(from_addr, Budget::Packet, Instant::now()) {
+                    continue;
+                }
+                let input = match envelope::decode_datagram(datagram) {
+                    Ok(IncomingFrame { version, kind: Kind::Foca, payload }) => Some(Input::Data(from_addr, version, payload)),
+                    // direct messages are merged like broadcasts
+                    Ok(IncomingFrame { kind: Kind::Direct, .. }) if !receive_inbound.admit(from_addr, Budget::Broadcast, Instant::now()) => None,
+                    Ok(IncomingFrame { version, kind: Kind::Direct, payload }) => Some(Input::Direct(from_addr, version, payload)),
+                    // probes are answered right here so that they don't wait for foca, a full send queue drops the Pong
+                    Ok(IncomingFrame { kind: Kind::Ping, payload, .. }) => {
+                        let _ignored_send_result = pong_sender.try_send((from_addr, envelope::wrap(Kind::Pong, &payload)));
+                        None
+                    },
+                    Ok(IncomingFrame { kind: Kind::Pong, payload, .. }) => {
+                        if let Some(prober) = &receive_prober {
+                            prober.pong(from_addr, &payload, Instant::now());
+                        }
+                        None
+                    },
+                    Err(e) => {
+                        log_throttle::report(ErrorCategory::EnvelopeRejected, Level::Error,
+                            format_args!("Ignoring datagram from {}: {}", from_addr, e));
+                        None
+                    },
+                };
                 // And simply forward it to foca
-                let _ignored_send_error = tx_foca.send(Input::Data(data_to_send)).await;
+                if let Some(input) = input {
+                    let _ignored_send_error = tx_foca.send(input).await;
+                }
                 },
-                Err(e) => error!("got an error receiving: {}", e),
+                Err(e) => log_throttle::report(ErrorCategory::SocketReceive, Level::Error,
+                    format_args!("got an error receiving: {}", e)),
             }
         }
     });
@@ -0,0 +1,551 @@
+use std::{
+    collections::{BTreeMap, BTreeSet}, net::SocketAddr, ops::ControlFlow, sync::Arc, time::Duration,
+};
+use anyhow::{anyhow, Result};
+use automerge::ChangeHash;
+use bytes::Bytes;
+use log::{debug, error};
+use tokio::sync::{mpsc, oneshot};
+use uuid::Uuid;
+
+use super::{actors::ActorSummary, batch::{BatchOp, BatchSummary, Transaction}, broadcast::{GossipMessage, MessageKind}, consistency::ConsistencyReport, departures::Departure, core::{DocStats, FieldConflict, FieldMeta, FieldValues, HolyDiverController, StateSnapshot}, direct_sync::{SyncMode, SyncReport}, events::FieldChangeReceiver, field_names::InvalidFieldReport, foca::FocaStatus, inbound_limits::PeerInboundRate, leases::Lease, members::NodeLabels, persistence::PersistenceStatus, phases::{self, Phase, RequestPhases}, probes::PeerProbe, quotas::QuotaUsage, strict_identity::Unverified, structure::DocStructureError, tombstones::Tombstone, types::ID, validator::Validator};
+
+// The controller is owned by a task that handles the messages of its handles one after the other, so the REST
+// server, the text protocol and embedders share it without a lock of their own. Operations waiting for
+// something other than the controller, e.g. the ack of a write or the document of a member, get a receiver to
+// await after the reply. The data handler is still shared with foca and the background tasks behind its mutex,
+// but only the task of the controller locks it on behalf of requests, and it does so on the blocking pool: a
+// merge or a purge holding the lock for a while never blocks the runtime, foca included. Messages that only
+// ask foca are handled in tasks of their own, so that a round-trip to foca doesn't hold up the others.
+
+// messages queued for the controller before the handles have to wait
+pub const CONTROLLER_QUEUE_SIZE: usize = 1024;
+
+type Reply<T> = oneshot::Sender<T>;
+
+// builds the operations of ControllerHandle::transaction
+pub type TransactionBuilder = Box<dyn FnOnce(&mut Transaction) -> Result<()> + Send>;
+
+pub enum ControllerMsg {
+    Get { field: String, reply: Reply<Result<Option<String>, DocStructureError>> },
+    // the value with the version it was read at, for the ETag
+    GetVersioned { field: String, reply: Reply<(Result<Option<String>, DocStructureError>, String)> },
+    // None if the version can't be resolved
+    GetAt { field: String, version: String, reply: Reply<Option<Result<Option<String>>>> },
+    GetWithMeta { field: String, reply: Reply<Result<Option<FieldMeta>, DocStructureError>> },
+    GetMany { fields: Vec<String>, reply: Reply<Result<FieldValues, DocStructureError>> },
+    Keys { prefix: String, reply: Reply<Result<Vec<String>, DocStructureError>> },
+    // up to limit fields after the given one, as of heads
+    ReadFields { after: Option<String>, heads: Vec<ChangeHash>, limit: usize, reply: Reply<Result<Vec<(String, String)>, DocStructureError>> },
+    Snapshot { include_deleted: bool, meta: bool, reply: Reply<Result<StateSnapshot, DocStructureError>> },
+    ValuesReadable(Reply<Result<(), DocStructureError>>),
+    Tombstones(Reply<Result<BTreeMap<String, Tombstone>, DocStructureError>>),
+    Conflicts { field: String, reply: Reply<Result<Vec<FieldConflict>, DocStructureError>> },
+    Heads(Reply<Vec<ChangeHash>>),
+    Version(Reply<String>),
+    LocalFieldNames(Reply<BTreeSet<String>>),
+    Replicates { field: String, reply: Reply<bool> },
+    Stats(Reply<DocStats>),
+    PersistenceStatus(Reply<PersistenceStatus>),
+    Validator(Reply<Option<Arc<Validator>>>),
+    Actors(Reply<BTreeMap<String, ActorSummary>>),
+    InvalidFields(Reply<InvalidFieldReport>),
+    QuotaUsage(Reply<Vec<QuotaUsage>>),
+    Export(Reply<Bytes>),
+    Watch(Reply<FieldChangeReceiver>),
+    Set { field: String, value: String, reply: Reply<Result<()>> },
+    SetReplicated { field: String, value: String, reply: Reply<Result<Option<oneshot::Receiver<SocketAddr>>>> },
+    Delete { field: String, reply: Reply<Result<()>> },
+    // entries of the containers declared besides values, see containers
+    GetInContainer { container: String, field: String, reply: Reply<Result<Option<String>>> },
+    SetInContainer { container: String, field: String, value: String, reply: Reply<Result<()>> },
+    DeleteInContainer { container: String, field: String, reply: Reply<Result<()>> },
+    Revert { field: String, reply: Reply<Result<Option<Option<String>>>> },
+    Batch { ops: Vec<BatchOp>, reply: Reply<Result<BatchSummary>> },
+    Transaction { build: TransactionBuilder, reply: Reply<Result<BatchSummary>> },
+    Import { data: Bytes, reply: Reply<Result<()>> },
+    AcquireLease { name: String, ttl: Duration, reply: Reply<Result<Lease>> },
+    RenewLease { name: String, ttl: Duration, reply: Reply<Result<Lease>> },
+    ReleaseLease { name: String, reply: Reply<Result<()>> },
+    Status(Reply<Result<FocaStatus>>),
+    Members(Reply<Result<Vec<SocketAddr>>>),
+    MemberLabels(Reply<Result<BTreeMap<SocketAddr, NodeLabels>>>),
+    Departures(Reply<Result<BTreeMap<SocketAddr, Departure>>>),
+    SeenOperation { operation_id: Uuid, reply: Reply<Result<Option<i64>>> },
+    SeenOperations(Reply<Result<Vec<(Uuid, i64)>>>),
+    ForgetOperations { operation_id: Option<Uuid>, reply: Reply<Result<usize>> },
+    Probes(Reply<Result<Option<BTreeMap<SocketAddr, PeerProbe>>>>),
+    InboundRates(Reply<Result<BTreeMap<SocketAddr, PeerInboundRate>>>),
+    UnverifiedMembers(Reply<Result<BTreeMap<SocketAddr, Unverified>>>),
+    Consistency(Reply<Result<ConsistencyReport>>),
+    SyncWith { target: SocketAddr, mode: SyncMode, force: bool, reply: Reply<Result<SyncReport>> },
+    RequestPeerState { peer: SocketAddr, reply: Reply<Result<oneshot::Receiver<Bytes>>> },
+    SendTo { to: ID, message: GossipMessage, reply: Reply<Result<()>> },
+    BroadcastCustom { kind: MessageKind, payload: Bytes, reply: Reply<Result<()>> },
+    BroadcastCurrentState(Reply<Result<()>>),
+    AdvertiseRestPort { port: u16, reply: Reply<Result<()>> },
+    Shutdown(Reply<()>),
+}
+
+impl ControllerMsg {
+    // whether the message is answered by foca alone, without the data handler
+    fn only_asks_foca(&self) -> bool {
+        matches!(self, ControllerMsg::Status(_) | ControllerMsg::Members(_) | ControllerMsg::MemberLabels(_)
+            | ControllerMsg::Departures(_) | ControllerMsg::SeenOperation { .. } | ControllerMsg::SeenOperations(_)
+            | ControllerMsg::ForgetOperations { .. } | ControllerMsg::Probes(_) | ControllerMsg::InboundRates(_)
+            | ControllerMsg::UnverifiedMembers(_) | ControllerMsg::Consistency(_) | ControllerMsg::RequestPeerState { .. } | ControllerMsg::SendTo { .. }
+            | ControllerMsg::BroadcastCustom { .. } | ControllerMsg::AdvertiseRestPort { .. })
+    }
+}
+
+/// Cheap to clone, the methods are those of HolyDiverController and fail once its task is gone.
+#[derive(Clone)]
+pub struct ControllerHandle {
+    // with the phases of the REST request sending the message, if any
+    sender: mpsc::Sender<(ControllerMsg, Option<Arc<RequestPhases>>)>,
+}
+
+impl ControllerHandle {
+    // Must be called within the tokio runtime of the node, the controller is moved into a spawned task
+    // that runs until all handles are dropped.
+    pub fn spawn(controller: HolyDiverController) -> Self {
+        let (sender, receiver) = mpsc::channel(CONTROLLER_QUEUE_SIZE);
+        tokio::spawn(run(controller, receiver));
+        ControllerHandle { sender }
+    }
+
+    async fn request<T>(&self, msg: impl FnOnce(Reply<T>) -> ControllerMsg) -> Result<T> {
+        let (reply, response) = oneshot::channel();
+        phases::enter(Phase::LockAcquisition);
+        self.sender.send((msg(reply), phases::current())).await.map_err(|_| anyhow!("the controller is not running anymore"))?;
+        let response = response.await.map_err(|_| anyhow!("the controller is not running anymore"));
+        phases::enter(Phase::Handler);
+        response
+    }
+
+    pub async fn get_field(&self, field: String) -> Result<Option<String>> {
+        Ok(self.request(|reply| ControllerMsg::Get { field, reply }).await??)
+    }
+
+    pub async fn get_field_versioned(&self, field: String) -> Result<(Option<String>, String)> {
+        let (value, version) = self.request(|reply| ControllerMsg::GetVersioned { field, reply }).await?;
+        Ok((value?, version))
+    }
+
+    // the value as of a version token, None if the version can't be resolved (any more)
+    pub async fn get_field_at(&self, field: String, version: String) -> Result<Option<Result<Option<String>>>> {
+        self.request(|reply| ControllerMsg::GetAt { field, version, reply }).await
+    }
+
+    pub async fn get_field_with_meta(&self, field: String) -> Result<Option<FieldMeta>> {
+        Ok(self.request(|reply| ControllerMsg::GetWithMeta { field, reply }).await??)
+    }
+
+    pub async fn get_fields(&self, fields: Vec<String>) -> Result<FieldValues> {
+        Ok(self.request(|reply| ControllerMsg::GetMany { fields, reply }).await??)
+    }
+
+    pub async fn keys_with_prefix(&self, prefix: String) -> Result<Vec<String>> {
+        Ok(self.request(|reply| ControllerMsg::Keys { prefix, reply }).await??)
+    }
+
+    // Reads up to limit fields in order after the given one as of heads, see StateListingStream. The values are
+    // copied so that the controller isn't held while they are written.
+    pub async fn read_fields(&self, after: Option<String>, heads: Vec<ChangeHash>, limit: usize) -> Result<Vec<(String, String)>> {
+        Ok(self.request(|reply| ControllerMsg::ReadFields { after, heads, limit, reply }).await??)
+    }
+
+    pub async fn snapshot(&self, include_deleted: bool, meta: bool) -> Result<StateSnapshot> {
+        Ok(self.request(|reply| ControllerMsg::Snapshot { include_deleted, meta, reply }).await??)
+    }
+
+    pub async fn values_readable(&self) -> Result<()> {
+        Ok(self.request(ControllerMsg::ValuesReadable).await??)
+    }
+
+    pub async fn tombstones(&self) -> Result<BTreeMap<String, Tombstone>> {
+        Ok(self.request(ControllerMsg::Tombstones).await??)
+    }
+
+    pub async fn get_conflicts(&self, field: String) -> Result<Vec<FieldConflict>> {
+        Ok(self.request(|reply| ControllerMsg::Conflicts { field, reply }).await??)
+    }
+
+    pub async fn heads(&self) -> Result<Vec<ChangeHash>> {
+        self.request(ControllerMsg::Heads).await
+    }
+
+    pub async fn version(&self) -> Result<String> {
+        self.request(ControllerMsg::Version).await
+    }
+
+    pub async fn local_field_names(&self) -> Result<BTreeSet<String>> {
+        self.request(ControllerMsg::LocalFieldNames).await
+    }
+
+    pub async fn replicates(&self, field: String) -> Result<bool> {
+        self.request(|reply| ControllerMsg::Replicates { field, reply }).await
+    }
+
+    pub async fn stats(&self) -> Result<DocStats> {
+        self.request(ControllerMsg::Stats).await
+    }
+
+    pub async fn persistence_status(&self) -> Result<PersistenceStatus> {
+        self.request(ControllerMsg::PersistenceStatus).await
+    }
+
+    pub async fn validator(&self) -> Result<Option<Arc<Validator>>> {
+        self.request(ControllerMsg::Validator).await
+    }
+
+    pub async fn actors(&self) -> Result<BTreeMap<String, ActorSummary>> {
+        self.request(ControllerMsg::Actors).await
+    }
+
+    pub async fn invalid_fields(&self) -> Result<InvalidFieldReport> {
+        self.request(ControllerMsg::InvalidFields).await
+    }
+
+    pub async fn quota_usage(&self) -> Result<Vec<QuotaUsage>> {
+        self.request(ControllerMsg::QuotaUsage).await
+    }
+
+    pub async fn export(&self) -> Result<Bytes> {
+        self.request(ControllerMsg::Export).await
+    }
+
+    pub async fn subscribe(&self) -> Result<FieldChangeReceiver> {
+        self.request(ControllerMsg::Watch).await
+    }
+
+    pub async fn set_field(&self, field: String, value: String) -> Result<()> {
+        self.request(|reply| ControllerMsg::Set { field, value, reply }).await?
+    }
+
+    pub async fn set_field_replicated(&self, field: String, value: String) -> Result<Option<oneshot::Receiver<SocketAddr>>> {
+        self.request(|reply| ControllerMsg::SetReplicated { field, value, reply }).await?
+    }
+
+    pub async fn delete_field(&self, field: String) -> Result<()> {
+        self.request(|reply| ControllerMsg::Delete { field, reply }).await?
+    }
+
+    pub async fn get_container_field(&self, container: String, field: String) -> Result<Option<String>> {
+        self.request(|reply| ControllerMsg::GetInContainer { container, field, reply }).await?
+    }
+
+    pub async fn set_container_field(&self, container: String, field: String, value: String) -> Result<()> {
+        self.request(|reply| ControllerMsg::SetInContainer { container, field, value, reply }).await?
+    }
+
+    pub async fn delete_container_field(&self, container: String, field: String) -> Result<()> {
+        self.request(|reply| ControllerMsg::DeleteInContainer { container, field, reply }).await?
+    }
+
+    pub async fn revert_field(&self, field: String) -> Result<Option<Option<String>>> {
+        self.request(|reply| ControllerMsg::Revert { field, reply }).await?
+    }
+
+    pub async fn apply_batch(&self, ops: Vec<BatchOp>) -> Result<BatchSummary> {
+        self.request(|reply| ControllerMsg::Batch { ops, reply }).await?
+    }
+
+    // see HolyDiverController::transaction, build runs on the task of the controller
+    pub async fn transaction<F>(&self, build: F) -> Result<BatchSummary>
+    where F: FnOnce(&mut Transaction) -> Result<()> + Send + 'static {
+        self.request(|reply| ControllerMsg::Transaction { build: Box::new(build), reply }).await?
+    }
+
+    pub async fn import(&self, data: Bytes) -> Result<()> {
+        self.request(|reply| ControllerMsg::Import { data, reply }).await?
+    }
+
+    pub async fn acquire_lease(&self, name: String, ttl: Duration) -> Result<Lease> {
+        self.request(|reply| ControllerMsg::AcquireLease { name, ttl, reply }).await?
+    }
+
+    pub async fn renew_lease(&self, name: String, ttl: Duration) -> Result<Lease> {
+        self.request(|reply| ControllerMsg::RenewLease { name, ttl, reply }).await?
+    }
+
+    pub async fn release_lease(&self, name: String) -> Result<()> {
+        self.request(|reply| ControllerMsg::ReleaseLease { name, reply }).await?
+    }
+
+    pub async fn status(&self) -> Result<FocaStatus> {
+        self.request(ControllerMsg::Status).await?
+    }
+
+    pub async fn members(&self) -> Result<Vec<SocketAddr>> {
+        self.request(ControllerMsg::Members).await?
+    }
+
+    pub async fn member_labels(&self) -> Result<BTreeMap<SocketAddr, NodeLabels>> {
+        self.request(ControllerMsg::MemberLabels).await?
+    }
+
+    pub async fn departures(&self) -> Result<BTreeMap<SocketAddr, Departure>> {
+        self.request(ControllerMsg::Departures).await?
+    }
+
+    pub async fn seen_operation(&self, operation_id: Uuid) -> Result<Option<i64>> {
+        self.request(|reply| ControllerMsg::SeenOperation { operation_id, reply }).await?
+    }
+
+    pub async fn seen_operations(&self) -> Result<Vec<(Uuid, i64)>> {
+        self.request(ControllerMsg::SeenOperations).await?
+    }
+
+    pub async fn forget_operations(&self, operation_id: Option<Uuid>) -> Result<usize> {
+        self.request(|reply| ControllerMsg::ForgetOperations { operation_id, reply }).await?
+    }
+
+    pub async fn probes(&self) -> Result<Option<BTreeMap<SocketAddr, PeerProbe>>> {
+        self.request(ControllerMsg::Probes).await?
+    }
+
+    pub async fn inbound_rates(&self) -> Result<BTreeMap<SocketAddr, PeerInboundRate>> {
+        self.request(ControllerMsg::InboundRates).await?
+    }
+
+    pub async fn unverified_members(&self) -> Result<BTreeMap<SocketAddr, Unverified>> {
+        self.request(ControllerMsg::UnverifiedMembers).await?
+    }
+
+    pub async fn consistency(&self) -> Result<ConsistencyReport> {
+        self.request(ControllerMsg::Consistency).await?
+    }
+
+    pub async fn sync_with(&self, target: SocketAddr, mode: SyncMode, force: bool) -> Result<SyncReport> {
+        self.request(|reply| ControllerMsg::SyncWith { target, mode, force, reply }).await?
+    }
+
+    pub async fn request_peer_state(&self, peer: SocketAddr) -> Result<oneshot::Receiver<Bytes>> {
+        self.request(|reply| ControllerMsg::RequestPeerState { peer, reply }).await?
+    }
+
+    pub async fn send_to(&self, to: ID, message: GossipMessage) -> Result<()> {
+        self.request(|reply| ControllerMsg::SendTo { to, message, reply }).await?
+    }
+
+    pub async fn broadcast_custom(&self, kind: MessageKind, payload: impl Into<Bytes>) -> Result<()> {
+        let payload = payload.into();
+        self.request(|reply| ControllerMsg::BroadcastCustom { kind, payload, reply }).await?
+    }
+
+    pub async fn broadcast_current_state(&self) -> Result<()> {
+        self.request(ControllerMsg::BroadcastCurrentState).await?
+    }
+
+    pub async fn advertise_rest_port(&self, port: u16) -> Result<()> {
+        self.request(|reply| ControllerMsg::AdvertiseRestPort { port, reply }).await?
+    }
+
+    // see HolyDiverController::shutdown, nothing to shut down if the controller is gone already
+    pub async fn shutdown(&self) {
+        if let Err(e) = self.request(ControllerMsg::Shutdown).await {
+            debug!("Not shutting down: {}", e);
+        }
+    }
+}
+
+async fn run(controller: HolyDiverController, mut receiver: mpsc::Receiver<(ControllerMsg, Option<Arc<RequestPhases>>)>) {
+    while let Some((msg, request_phases)) = receiver.recv().await {
+        let only_asks_foca = msg.only_asks_foca();
+        // the controller is a few handles itself, every message gets its own
+        let mut controller = controller.clone();
+        let handled = async move {
+            match request_phases {
+                Some(request_phases) => phases::scope(request_phases, handle(&mut controller, msg)).await,
+                None => handle(&mut controller, msg).await,
+            }
+        };
+        if only_asks_foca {
+            tokio::spawn(handled);
+            continue;
+        }
+        let runtime = tokio::runtime::Handle::current();
+        if let Err(e) = tokio::task::spawn_blocking(move || runtime.block_on(handled)).await {
+            // the reply is dropped with the message, its requester gets an error
+            error!("Handling a message of the controller failed: {}", e);
+        }
+    }
+    debug!("All controller handles are dropped, stopping the controller");
+}
+
+// Replies whose requester is gone, e.g. because the client disconnected, are dropped. The operation is still
+// applied, just like it was before the requester could have been cancelled.
+async fn handle(controller: &mut HolyDiverController, msg: ControllerMsg) {
+    match msg {
+        ControllerMsg::Get { field, reply } => {
+            let _ = reply.send(controller.get_field(field));
+        },
+        ControllerMsg::GetVersioned { field, reply } => {
+            let _ = reply.send((controller.get_field(field), controller.version()));
+        },
+        ControllerMsg::GetAt { field, version, reply } => {
+            let value = controller.resolve_version(&version).map(|heads| controller.get_field_at(field, &heads));
+            let _ = reply.send(value);
+        },
+        ControllerMsg::GetWithMeta { field, reply } => {
+            let _ = reply.send(controller.get_field_with_meta(field));
+        },
+        ControllerMsg::GetMany { fields, reply } => {
+            let _ = reply.send(controller.get_fields(&fields));
+        },
+        ControllerMsg::Keys { prefix, reply } => {
+            let _ = reply.send(controller.keys_with_prefix(&prefix));
+        },
+        ControllerMsg::ReadFields { after, heads, limit, reply } => {
+            let mut fields = Vec::new();
+            let read = controller.for_each_field_at("", after.as_deref(), Some(&heads), |key, value| {
+                fields.push((key.to_owned(), value.to_owned()));
+                if fields.len() == limit { ControlFlow::Break(()) } else { ControlFlow::Continue(()) }
+            });
+            let _ = reply.send(read.map(|_| fields));
+        },
+        ControllerMsg::Snapshot { include_deleted, meta, reply } => {
+            let _ = reply.send(controller.snapshot(include_deleted, meta));
+        },
+        ControllerMsg::ValuesReadable(reply) => {
+            let _ = reply.send(controller.values_readable());
+        },
+        ControllerMsg::Tombstones(reply) => {
+            let _ = reply.send(controller.tombstones());
+        },
+        ControllerMsg::Conflicts { field, reply } => {
+            let _ = reply.send(controller.get_conflicts(field));
+        },
+        ControllerMsg::Heads(reply) => {
+            let _ = reply.send(controller.heads());
+        },
+        ControllerMsg::Version(reply) => {
+            let _ = reply.send(controller.version());
+        },
+        ControllerMsg::LocalFieldNames(reply) => {
+            let _ = reply.send(controller.local_field_names());
+        },
+        ControllerMsg::Replicates { field, reply } => {
+            let _ = reply.send(controller.replicates(&field));
+        },
+        ControllerMsg::Stats(reply) => {
+            let _ = reply.send(controller.stats());
+        },
+        ControllerMsg::PersistenceStatus(reply) => {
+            let _ = reply.send(controller.persistence_status());
+        },
+        ControllerMsg::Validator(reply) => {
+            let _ = reply.send(controller.validator());
+        },
+        ControllerMsg::Actors(reply) => {
+            let _ = reply.send(controller.actors());
+        },
+        ControllerMsg::InvalidFields(reply) => {
+            let _ = reply.send(controller.invalid_fields());
+        },
+        ControllerMsg::QuotaUsage(reply) => {
+            let _ = reply.send(controller.quota_usage());
+        },
+        ControllerMsg::Export(reply) => {
+            let _ = reply.send(controller.export());
+        },
+        ControllerMsg::Watch(reply) => {
+            let _ = reply.send(controller.subscribe());
+        },
+        ControllerMsg::Set { field, value, reply } => {
+            let _ = reply.send(controller.set_field(field, value).await);
+        },
+        ControllerMsg::SetReplicated { field, value, reply } => {
+            let _ = reply.send(controller.set_field_replicated(field, value).await);
+        },
+        ControllerMsg::Delete { field, reply } => {
+            let _ = reply.send(controller.delete_field(field).await);
+        },
+        ControllerMsg::GetInContainer { container, field, reply } => {
+            let _ = reply.send(controller.get_container_field(&container, &field));
+        },
+        ControllerMsg::SetInContainer { container, field, value, reply } => {
+            let _ = reply.send(controller.set_container_field(container, field, value).await);
+        },
+        ControllerMsg::DeleteInContainer { container, field, reply } => {
+            let _ = reply.send(controller.delete_container_field(container, field).await);
+        },
+        ControllerMsg::Revert { field, reply } => {
+            let _ = reply.send(controller.revert_field(field).await);
+        },
+        ControllerMsg::Batch { ops, reply } => {
+            let _ = reply.send(controller.apply_batch(ops).await);
+        },
+        ControllerMsg::Transaction { build, reply } => {
+            let _ = reply.send(controller.transaction(build).await);
+        },
+        ControllerMsg::Import { data, reply } => {
+            let _ = reply.send(controller.import(&data).await);
+        },
+        ControllerMsg::AcquireLease { name, ttl, reply } => {
+            let _ = reply.send(controller.acquire_lease(&name, ttl).await);
+        },
+        ControllerMsg::RenewLease { name, ttl, reply } => {
+            let _ = reply.send(controller.renew_lease(&name, ttl).await);
+        },
+        ControllerMsg::ReleaseLease { name, reply } => {
+            let _ = reply.send(controller.release_lease(&name).await);
+        },
+        ControllerMsg::Status(reply) => {
+            let _ = reply.send(controller.status().await);
+        },
+        ControllerMsg::Members(reply) => {
+            let _ = reply.send(controller.members().await);
+        },
+        ControllerMsg::MemberLabels(reply) => {
+            let _ = reply.send(controller.member_labels().await);
+        },
+        ControllerMsg::Departures(reply) => {
+            let _ = reply.send(controller.departures().await);
+        },
+        ControllerMsg::SeenOperation { operation_id, reply } => {
+            let _ = reply.send(controller.seen_operation(operation_id).await);
+        },
+        ControllerMsg::SeenOperations(reply) => {
+            let _ = reply.send(controller.seen_operations().await);
+        },
+        ControllerMsg::ForgetOperations { operation_id, reply } => {
+            let _ = reply.send(controller.forget_operations(operation_id).await);
+        },
+        ControllerMsg::Probes(reply) => {
+            let _ = reply.send(controller.probes().await);
+        },
+        ControllerMsg::InboundRates(reply) => {
+            let _ = reply.send(controller.inbound_rates().await);
+        },
+        ControllerMsg::UnverifiedMembers(reply) => {
+            let _ = reply.send(controller.unverified_members().await);
+        },
+        ControllerMsg::Consistency(reply) => {
+            let _ = reply.send(controller.consistency().await);
+        },
+        ControllerMsg::SyncWith { target, mode, force, reply } => {
+            let _ = reply.send(controller.sync_with(target, mode, force).await);
+        },
+        ControllerMsg::RequestPeerState { peer, reply } => {
+            let _ = reply.send(controller.request_peer_state(peer).await);
+        },
+        ControllerMsg::SendTo { to, message, reply } => {
+            let _ = reply.send(controller.send_to(to, message).await);
+        },
+        ControllerMsg::BroadcastCustom { kind, payload, reply } => {
+            let _ = reply.send(controller.broadcast_custom(kind, payload).await);
+        },
+        ControllerMsg::BroadcastCurrentState(reply) => {
+            let _ = reply.send(controller.broadcast_current_state().await);
+        },
+        ControllerMsg::AdvertiseRestPort { port, reply } => {
+            let _ = reply.send(controller.advertise_rest_port(port).await);
+        },
+        ControllerMsg::Shutdown(reply) => {
+            controller.shutdown().await;
+            let _ = reply.send(());
+        },
+    }
+}
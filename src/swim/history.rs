@@ -0,0 +1,228 @@
+use std::{
+    collections::HashSet, sync::{Arc, Mutex}, time::Duration,
+};
+use anyhow::Result;
+use automerge::{AutoCommit, ObjId, ObjType, ReadDoc, ROOT, ScalarValue, Value, transaction::Transactable};
+use bytes::Bytes;
+use log::{debug, error, info, warn};
+use serde::Serialize;
+
+use super::{core::HolyDiverDataHandler, metrics, structure};
+
+// The document keeps every change ever made, so its saved size grows with the number of writes while the values
+// stay the same size. Compacting it, i.e. rebuilding the current values into a document without history, isn't
+// done automatically yet. Until it is, the history is assessed periodically to tell when it would pay off.
+// The assessment works on the last cached save of the document: it is loaded and rebuilt into a scratch document
+// in a blocking task, the data handler is only locked to take the bytes and to record the result.
+
+pub const DEFAULT_HISTORY_CHECK_INTERVAL: Duration = Duration::from_secs(10 * 60);
+// compaction is recommended once the saved document is this many times the size of its values
+pub const COMPACTION_RATIO_THRESHOLD: f64 = 10.0;
+// smaller documents aren't worth compacting whatever their ratio
+pub const COMPACTION_MIN_SIZE: usize = 1024 * 1024;
+
+/// Size of the history of the document compared to its values, as of the last assessment.
+/// Sizes are in bytes, `assessed_at` is in unix millis.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[cfg_attr(feature = "rest", derive(utoipa::ToSchema))]
+pub struct HistoryAssessment {
+    pub assessed_at: i64,
+    pub changes: usize,
+    pub actors: usize,
+    pub serialized_size: usize,
+    /// Keys and values of all fields
+    pub values_size: usize,
+    /// serialized_size divided by values_size
+    pub ratio: f64,
+    /// Size of the document rebuilt from its current state without history
+    pub compacted_size: usize,
+    pub compaction_recommended: bool,
+}
+
+// the ratio of a document without values is its size, so that a history of deleted fields still counts
+pub fn history_ratio(serialized_size: usize, values_size: usize) -> f64 {
+    serialized_size as f64 / values_size.max(1) as f64
+}
+
+pub fn compaction_recommended(serialized_size: usize, ratio: f64) -> bool {
+    serialized_size >= COMPACTION_MIN_SIZE && ratio > COMPACTION_RATIO_THRESHOLD
+}
+
+// bytes of the keys and values of the values map
+pub fn values_size(doc: &AutoCommit) -> usize {
+    let Ok(values) = structure::values_map(doc) else {
+        return 0;
+    };
+    doc.map_range(&values, ..)
+        .map(|(key, value, _)| key.len() + match value {
+            Value::Scalar(scalar) => match scalar.as_ref() {
+                ScalarValue::Str(s) => s.len(),
+                other => other.to_string().len(),
+            },
+            Value::Object(_) => 0,
+        })
+        .sum()
+}
+
+// A new document with the current state of doc but none of its history, doc itself is left as it is.
+// Conflicting values are resolved to their winner.
+pub fn rebuild_without_history(doc: &AutoCommit) -> Result<AutoCommit> {
+    let mut scratch = AutoCommit::new();
+    copy_object(doc, &ROOT, ObjType::Map, &mut scratch, &ROOT)?;
+    Ok(scratch)
+}
+
+fn copy_object(doc: &AutoCommit, from: &ObjId, obj_type: ObjType, scratch: &mut AutoCommit, to: &ObjId) -> Result<()> {
+    match obj_type {
+        ObjType::Map | ObjType::Table => {
+            for key in doc.keys(from) {
+                match doc.get(from, key.as_str())? {
+                    Some((Value::Object(child_type), child)) => {
+                        let copy = scratch.put_object(to, key.as_str(), child_type)?;
+                        copy_object(doc, &child, child_type, scratch, &copy)?;
+                    },
+                    Some((Value::Scalar(scalar), _)) => scratch.put(to, key.as_str(), scalar.into_owned())?,
+                    None => {},
+                }
+            }
+        },
+        ObjType::List => {
+            for index in 0..doc.length(from) {
+                match doc.get(from, index)? {
+                    Some((Value::Object(child_type), child)) => {
+                        let copy = scratch.insert_object(to, index, child_type)?;
+                        copy_object(doc, &child, child_type, scratch, &copy)?;
+                    },
+                    Some((Value::Scalar(scalar), _)) => scratch.insert(to, index, scalar.into_owned())?,
+                    None => {},
+                }
+            }
+        },
+        ObjType::Text => scratch.splice_text(to, 0, 0, &doc.text(from)?)?,
+    }
+    Ok(())
+}
+
+// Assesses a saved document, expensive for large documents, see spawn_history_monitor
+pub fn assess_history(saved: &[u8]) -> Result<HistoryAssessment> {
+    let mut doc = AutoCommit::load(saved)?;
+    let values_size = values_size(&doc);
+    let compacted_size = rebuild_without_history(&doc)?.save().len();
+    let changes = doc.get_changes(&[])?;
+    let actors: HashSet<_> = changes.iter().map(|change| change.actor_id()).collect();
+    let ratio = history_ratio(saved.len(), values_size);
+    Ok(HistoryAssessment {
+        assessed_at: chrono::Utc::now().timestamp_millis(),
+        changes: changes.len(),
+        actors: actors.len(),
+        serialized_size: saved.len(),
+        values_size,
+        ratio,
+        compacted_size,
+        compaction_recommended: compaction_recommended(saved.len(), ratio),
+    })
+}
+
+// Periodically assesses the history of the document, logs a recommendation when compacting it would pay off and
+// records the assessment for /state/_stats. Needs to be called from within a tokio runtime.
+pub fn spawn_history_monitor(data_handler: Arc<Mutex<HolyDiverDataHandler>>, interval: Duration) {
+    tokio::spawn(async move {
+        // the last save the assessment was made for, the document didn't change if it is still cached
+        let mut assessed: Option<Bytes> = None;
+        loop {
+            tokio::time::sleep(interval).await;
+            let Some(saved) = data_handler.lock().unwrap().cached_save() else {
+                debug!("The document was not saved yet, skipping the history assessment");
+                continue;
+            };
+            if assessed.as_ref() == Some(&saved) {
+                continue;
+            }
+            let bytes = saved.clone();
+            let assessment = match tokio::task::spawn_blocking(move || assess_history(&bytes)).await {
+                Ok(Ok(assessment)) => assessment,
+                Ok(Err(e)) => {
+                    error!("Could not assess the history of the document: {:#}", e);
+                    continue;
+                },
+                Err(e) => {
+                    error!("The history assessment did not finish: {}", e);
+                    continue;
+                },
+            };
+            assessed = Some(saved);
+            report(&assessment);
+            data_handler.lock().unwrap().record_history_assessment(assessment);
+        }
+    });
+}
+
+fn report(assessment: &HistoryAssessment) {
+    metrics::DOC_HISTORY_RATIO.set(assessment.ratio);
+    metrics::DOC_COMPACTED_SIZE_BYTES.set(assessment.compacted_size as i64);
+    metrics::COMPACTION_RECOMMENDED.set(assessment.compaction_recommended as i64);
+    let summary = format!("changes={} actors={} serialized_size={} values_size={} ratio={:.1} compacted_size={}",
+        assessment.changes, assessment.actors, assessment.serialized_size, assessment.values_size, assessment.ratio,
+        assessment.compacted_size);
+    if assessment.compaction_recommended {
+        warn!("Compaction recommended, the history outweighs the values of the document: {}", summary);
+    } else {
+        info!("Assessed the history of the document: {}", summary);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::swim::structure::VALUES_KEY;
+
+    #[test]
+    fn a_rewritten_document_is_assessed_by_its_history_and_rebuilt_with_its_values_only() {
+        let mut doc = AutoCommit::new();
+        let values = doc.put_object(ROOT, VALUES_KEY, ObjType::Map).unwrap();
+        let list = doc.put_object(ROOT, "list", ObjType::List).unwrap();
+        doc.insert(&list, 0, "item").unwrap();
+        let text = doc.put_object(ROOT, "text", ObjType::Text).unwrap();
+        doc.splice_text(&text, 0, 0, "hello").unwrap();
+        for round in 0..300 {
+            for field in 0..10 {
+                doc.put(&values, format!("field{}", field), format!("{:04}", round)).unwrap();
+                doc.commit();
+            }
+        }
+        let saved = doc.save();
+        let heads = doc.get_heads();
+
+        let assessment = assess_history(&saved).unwrap();
+        assert_eq!(assessment.changes, doc.get_changes(&[]).unwrap().len());
+        assert_eq!(assessment.actors, 1);
+        assert_eq!(assessment.serialized_size, saved.len());
+        // 10 keys of 6 bytes and values of 4 bytes
+        assert_eq!(assessment.values_size, 100);
+        assert_eq!(assessment.ratio, saved.len() as f64 / 100.0);
+        assert!(assessment.compacted_size < saved.len() / 4, "{} of {} bytes", assessment.compacted_size, saved.len());
+        // too small to be worth it whatever the ratio
+        assert!(assessment.ratio > COMPACTION_RATIO_THRESHOLD);
+        assert!(!assessment.compaction_recommended);
+
+        let mut rebuilt = rebuild_without_history(&doc).unwrap();
+        assert_eq!(doc.get_heads(), heads);
+        assert_eq!(rebuilt.get_changes(&[]).unwrap().len(), 1);
+        let rebuilt_values = structure::values_map(&rebuilt).unwrap();
+        assert_eq!(values_size(&rebuilt), 100);
+        assert_eq!(rebuilt.get(&rebuilt_values, "field3").unwrap().unwrap().0.to_str(), Some("0299"));
+        let (_, rebuilt_list) = rebuilt.get(ROOT, "list").unwrap().unwrap();
+        assert_eq!(rebuilt.get(&rebuilt_list, 0).unwrap().unwrap().0.to_str(), Some("item"));
+        let (_, rebuilt_text) = rebuilt.get(ROOT, "text").unwrap().unwrap();
+        assert_eq!(rebuilt.text(&rebuilt_text).unwrap(), "hello");
+    }
+
+    #[test]
+    fn compaction_is_recommended_for_large_documents_beyond_the_ratio() {
+        assert!(compaction_recommended(COMPACTION_MIN_SIZE, COMPACTION_RATIO_THRESHOLD + 0.1));
+        assert!(!compaction_recommended(COMPACTION_MIN_SIZE, COMPACTION_RATIO_THRESHOLD));
+        assert!(!compaction_recommended(COMPACTION_MIN_SIZE - 1, 1000.0));
+        // documents without values compare their size to a single byte
+        assert_eq!(history_ratio(500, 0), 500.0);
+    }
+}
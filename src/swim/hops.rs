@@ -0,0 +1,68 @@
+use std::net::SocketAddr;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+// With --trace-hops the state broadcasts of a node are sent as TracedOperation instead of SyncOperation,
+// carrying a HopTrail that every member relaying them extends. Members log the trail of the operations
+// matching --trace-operation, which tells how far a broadcast travelled and via whom. Without the flag
+// broadcasts are sent as before and don't grow by a single byte. All members have to run a version that
+// knows TracedOperation, older ones drop the broadcasts as undecodable.
+
+// relays recorded in a trail, further ones are only counted
+pub const MAX_TRAIL_RELAYS: usize = 4;
+
+/// The path a traced broadcast took from its origin.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HopTrail {
+    pub origin: SocketAddr,
+    // members that received and relayed the broadcast so far
+    pub hops: u8,
+    // the first MAX_TRAIL_RELAYS of them, a fixed array keeps Tag Copy
+    relays: [Option<SocketAddr>; MAX_TRAIL_RELAYS],
+}
+
+impl HopTrail {
+    pub fn new(origin: SocketAddr) -> Self {
+        HopTrail {
+            origin,
+            hops: 0,
+            relays: [None; MAX_TRAIL_RELAYS],
+        }
+    }
+
+    // the trail as node received it
+    pub fn relayed_by(&self, node: SocketAddr) -> Self {
+        let mut trail = *self;
+        trail.hops = trail.hops.saturating_add(1);
+        if let Some(free) = trail.relays.iter_mut().find(|relay| relay.is_none()) {
+            *free = Some(node);
+        }
+        trail
+    }
+
+    pub fn relays(&self) -> impl Iterator<Item = SocketAddr> + '_ {
+        self.relays.iter().flatten().copied()
+    }
+}
+
+/// Settings of the hop tracing of a node, see Handler::set_hop_tracing.
+#[derive(Debug, Clone, Default)]
+pub struct HopTracing {
+    // trails of the operations whose hyphenated id starts with this are logged, see parse_operation_prefix
+    pub operation_prefix: Option<String>,
+}
+
+impl HopTracing {
+    pub fn logs(&self, operation_id: &Uuid) -> bool {
+        self.operation_prefix.as_ref()
+            .is_some_and(|prefix| operation_id.to_string().starts_with(prefix.as_str()))
+    }
+}
+
+// The start of an operation id as written by the logs and /debug/operations, lowercased
+pub fn parse_operation_prefix(input: &str) -> Result<String, String> {
+    if input.is_empty() || input.len() > 36 || !input.chars().all(|c| c.is_ascii_hexdigit() || c == '-') {
+        return Err(format!("{} is not the start of an operation id like 3f2a9c1b-7d3e-4e5f-8a9b-0c1d2e3f4a5b", input));
+    }
+    Ok(input.to_ascii_lowercase())
+}
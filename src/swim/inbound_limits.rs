@@ -0,0 +1,277 @@
+use std::{
+    collections::{BTreeMap, HashMap}, net::SocketAddr, sync::{Arc, Mutex}, time::{Duration, Instant},
+};
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+use super::metrics;
+
+// Per-peer limits of what is received over gossip, so that a misbehaving or malicious peer can't have all its
+// broadcasts decoded, merged and gossiped on. Every source address gets two token buckets: one for datagrams,
+// taken before anything is decoded, and one for the broadcasts and direct messages they carry, taken before an
+// item is decoded. The datagram budget should be well above the broadcast budget, SWIM messages only take from
+// the former, so that failure detection keeps working while the broadcasts of a peer are dropped. Dropped
+// broadcasts are not remembered as seen, the relays of other members still get them to this node.
+// The rates are measured without limits as well and reported by /members?probe=true.
+
+// source addresses tracked at once, the one seen longest ago is forgotten for a new one
+pub const MAX_TRACKED_PEERS: usize = 1024;
+// interval of the warnings about a peer being throttled
+const WARNING_INTERVAL: Duration = Duration::from_secs(10);
+// the rates are counted over windows of this length
+const RATE_WINDOW: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Budget {
+    // every datagram, including SWIM messages and probes
+    Packet,
+    // broadcasts and direct messages
+    Broadcast,
+}
+
+impl Budget {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Budget::Packet => "packet",
+            Budget::Broadcast => "broadcast",
+        }
+    }
+}
+
+/// Per second and peer, bursts of twice the rate are allowed. None leaves a budget unlimited.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct InboundLimits {
+    pub packets_per_sec: Option<u32>,
+    pub broadcasts_per_sec: Option<u32>,
+}
+
+struct TokenBucket {
+    rate: f64,
+    capacity: f64,
+    tokens: f64,
+    refilled: Instant,
+}
+
+impl TokenBucket {
+    fn new(rate: u32, now: Instant) -> Self {
+        let capacity = 2.0 * rate as f64;
+        TokenBucket { rate: rate as f64, capacity, tokens: capacity, refilled: now }
+    }
+
+    fn take(&mut self, now: Instant) -> bool {
+        let elapsed = now.saturating_duration_since(self.refilled).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.capacity);
+        self.refilled = now;
+        if self.tokens < 1.0 {
+            return false;
+        }
+        self.tokens -= 1.0;
+        true
+    }
+}
+
+struct RateMeter {
+    window_start: Instant,
+    count: u64,
+    // rate of the last full window
+    last_rate: f64,
+}
+
+impl RateMeter {
+    fn new(now: Instant) -> Self {
+        RateMeter { window_start: now, count: 0, last_rate: 0.0 }
+    }
+
+    fn record(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.window_start);
+        if elapsed >= RATE_WINDOW {
+            // a peer silent for more than a window had no rate in the last one
+            self.last_rate = match elapsed >= 2 * RATE_WINDOW {
+                true => 0.0,
+                false => self.count as f64 / elapsed.as_secs_f64(),
+            };
+            self.window_start = now;
+            self.count = 0;
+        }
+        self.count += 1;
+    }
+
+    fn rate(&self, now: Instant) -> f64 {
+        match now.saturating_duration_since(self.window_start) {
+            elapsed if elapsed >= 2 * RATE_WINDOW => 0.0,
+            elapsed if elapsed >= RATE_WINDOW => self.count as f64 / elapsed.as_secs_f64(),
+            _ => self.last_rate,
+        }
+    }
+}
+
+struct PeerInbound {
+    last_seen: Instant,
+    packets: Option<TokenBucket>,
+    broadcasts: Option<TokenBucket>,
+    packet_rate: RateMeter,
+    broadcast_rate: RateMeter,
+    dropped_packets: u64,
+    dropped_broadcasts: u64,
+    // drops since the last warning
+    unreported: u64,
+    warned: Option<Instant>,
+}
+
+impl PeerInbound {
+    fn new(limits: &InboundLimits, now: Instant) -> Self {
+        PeerInbound {
+            last_seen: now,
+            packets: limits.packets_per_sec.map(|rate| TokenBucket::new(rate, now)),
+            broadcasts: limits.broadcasts_per_sec.map(|rate| TokenBucket::new(rate, now)),
+            packet_rate: RateMeter::new(now),
+            broadcast_rate: RateMeter::new(now),
+            dropped_packets: 0,
+            dropped_broadcasts: 0,
+            unreported: 0,
+            warned: None,
+        }
+    }
+}
+
+/// What a source address sent recently, rates are per second.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "rest", derive(utoipa::ToSchema))]
+pub struct PeerInboundRate {
+    pub packets_per_sec: f64,
+    pub broadcasts_per_sec: f64,
+    pub dropped_packets: u64,
+    pub dropped_broadcasts: u64,
+}
+
+struct LimiterState {
+    limits: InboundLimits,
+    peers: HashMap<SocketAddr, PeerInbound>,
+    // sender of the datagram foca is handling, see set_source
+    source: Option<SocketAddr>,
+}
+
+/// The inbound budgets of all peers. Clones share the state.
+#[derive(Clone)]
+pub struct InboundLimiter {
+    state: Arc<Mutex<LimiterState>>,
+}
+
+impl InboundLimiter {
+    pub fn new(limits: InboundLimits) -> Self {
+        InboundLimiter {
+            state: Arc::new(Mutex::new(LimiterState { limits, peers: HashMap::new(), source: None })),
+        }
+    }
+
+    // Takes a token of the budget of peer, returns false if the message has to be dropped
+    pub fn admit(&self, peer: SocketAddr, budget: Budget, now: Instant) -> bool {
+        let mut state = self.state.lock().unwrap();
+        if !state.peers.contains_key(&peer) && state.peers.len() >= MAX_TRACKED_PEERS {
+            let oldest = state.peers.iter().min_by_key(|(_, inbound)| inbound.last_seen).map(|(addr, _)| *addr);
+            if let Some(oldest) = oldest {
+                state.peers.remove(&oldest);
+            }
+        }
+        let limits = state.limits;
+        let inbound = state.peers.entry(peer).or_insert_with(|| PeerInbound::new(&limits, now));
+        inbound.last_seen = now;
+        let admitted = match budget {
+            Budget::Packet => {
+                inbound.packet_rate.record(now);
+                inbound.packets.as_mut().is_none_or(|bucket| bucket.take(now))
+            },
+            Budget::Broadcast => {
+                inbound.broadcast_rate.record(now);
+                inbound.broadcasts.as_mut().is_none_or(|bucket| bucket.take(now))
+            },
+        };
+        if admitted {
+            return true;
+        }
+        metrics::INBOUND_DROPPED.with_label_values(&[budget.as_str()]).inc();
+        match budget {
+            Budget::Packet => inbound.dropped_packets += 1,
+            Budget::Broadcast => inbound.dropped_broadcasts += 1,
+        }
+        inbound.unreported += 1;
+        if inbound.warned.is_none_or(|warned| now.saturating_duration_since(warned) >= WARNING_INTERVAL) {
+            warn!("Throttling {}, dropped {} of its messages since the last warning ({} packets and {} broadcasts in total)",
+                peer, inbound.unreported, inbound.dropped_packets, inbound.dropped_broadcasts);
+            inbound.unreported = 0;
+            inbound.warned = Some(now);
+        }
+        false
+    }
+
+    // The sender of the datagram foca handles next, the broadcasts it carries take from its budget, see
+    // admit_from_source. None once it is handled, broadcasts added by this node have no budget.
+    pub fn set_source(&self, source: Option<SocketAddr>) {
+        self.state.lock().unwrap().source = source;
+    }
+
+    pub fn admit_from_source(&self, budget: Budget, now: Instant) -> bool {
+        let source = self.state.lock().unwrap().source;
+        match source {
+            Some(source) => self.admit(source, budget, now),
+            None => true,
+        }
+    }
+
+    pub fn report(&self, now: Instant) -> BTreeMap<SocketAddr, PeerInboundRate> {
+        self.state.lock().unwrap().peers.iter()
+            .map(|(addr, inbound)| (*addr, PeerInboundRate {
+                packets_per_sec: inbound.packet_rate.rate(now),
+                broadcasts_per_sec: inbound.broadcast_rate.rate(now),
+                dropped_packets: inbound.dropped_packets,
+                dropped_broadcasts: inbound.dropped_broadcasts,
+            }))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn peer(port: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], port))
+    }
+
+    #[test]
+    fn a_flooding_peer_loses_its_broadcasts_but_neither_its_packets_nor_the_budget_of_others() {
+        let limiter = InboundLimiter::new(InboundLimits { packets_per_sec: Some(1000), broadcasts_per_sec: Some(10) });
+        let start = Instant::now();
+        let flooder = peer(9002);
+
+        let admitted = (0..100).filter(|_| limiter.admit(flooder, Budget::Broadcast, start)).count();
+        // a burst of twice the rate
+        assert_eq!(admitted, 20);
+        assert!(limiter.admit(flooder, Budget::Packet, start));
+        assert!(limiter.admit(peer(9003), Budget::Broadcast, start));
+
+        // refilled at the rate
+        let later = start + Duration::from_secs(1);
+        let admitted = (0..100).filter(|_| limiter.admit(flooder, Budget::Broadcast, later)).count();
+        assert_eq!(admitted, 10);
+
+        let report = limiter.report(later);
+        assert_eq!(report[&flooder].dropped_broadcasts, 80 + 90);
+        assert_eq!(report[&flooder].dropped_packets, 0);
+        assert_eq!(report[&peer(9003)].dropped_broadcasts, 0);
+    }
+
+    #[test]
+    fn broadcasts_take_from_the_budget_of_the_datagram_sender_only() {
+        let limiter = InboundLimiter::new(InboundLimits { packets_per_sec: None, broadcasts_per_sec: Some(1) });
+        let now = Instant::now();
+        limiter.set_source(Some(peer(9002)));
+        assert!(limiter.admit_from_source(Budget::Broadcast, now));
+        assert!(limiter.admit_from_source(Budget::Broadcast, now));
+        assert!(!limiter.admit_from_source(Budget::Broadcast, now));
+        // broadcasts added by this node
+        limiter.set_source(None);
+        assert!(limiter.admit_from_source(Budget::Broadcast, now));
+        assert_eq!(limiter.report(now).len(), 1);
+    }
+}
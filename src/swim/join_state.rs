@@ -0,0 +1,148 @@
+use std::{fmt::{Display, Formatter}, net::SocketAddr, time::Duration};
+use chrono::{DateTime, Utc};
+use log::{error, info};
+use serde::Serialize;
+use tokio::sync::watch;
+
+// Progress of joining the cluster on startup. The foca loop owns the state: every announce it sends moves it
+// to Announcing with the attempt count, the first other member up to Joined, and a join deadline that passed to
+// Failed. Every transition is logged, /cluster/info and /healthz report the current state. The tasks retrying
+// the announces and enforcing the join deadline watch the state to know when to stop, see JoinProgress::watch.
+
+/// Where a node is in joining the cluster.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[cfg_attr(feature = "rest", derive(utoipa::ToSchema))]
+#[serde(tag = "state", rename_all = "snake_case")]
+pub enum JoinState {
+    // no seed and no remembered peer to announce to, the node waits for the others to announce to it
+    NotConfigured,
+    Announcing {
+        // announces sent so far, to seeds and remembered peers
+        attempt: u32,
+        // when the announce is repeated, None if it isn't
+        #[cfg_attr(feature = "rest", schema(value_type = Option<String>))]
+        next_retry: Option<DateTime<Utc>>,
+    },
+    Joined {
+        #[cfg_attr(feature = "rest", schema(value_type = String))]
+        at: DateTime<Utc>,
+        // the first other member seen up
+        #[cfg_attr(feature = "rest", schema(value_type = String, example = "127.0.0.1:9001"))]
+        via: SocketAddr,
+    },
+    Failed { reason: String },
+}
+
+impl JoinState {
+    pub fn is_joined(&self) -> bool {
+        matches!(self, JoinState::Joined { .. })
+    }
+
+    // whether the node still tries to join, announces are only retried then
+    pub fn is_pending(&self) -> bool {
+        matches!(self, JoinState::NotConfigured | JoinState::Announcing { .. })
+    }
+}
+
+impl Display for JoinState {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            JoinState::NotConfigured => write!(f, "waiting for members to announce to this node"),
+            JoinState::Announcing { attempt, next_retry: Some(next_retry) } =>
+                write!(f, "announcing, attempt {}, next retry at {}", attempt, next_retry.to_rfc3339()),
+            JoinState::Announcing { attempt, next_retry: None } => write!(f, "announcing, attempt {}, not retried", attempt),
+            JoinState::Joined { at, via } => write!(f, "joined via {} at {}", via, at.to_rfc3339()),
+            JoinState::Failed { reason } => write!(f, "failed: {}", reason),
+        }
+    }
+}
+
+/// The JoinState as updated by the foca loop, watched by the tasks announcing on its behalf.
+pub struct JoinProgress {
+    state: watch::Sender<JoinState>,
+    // how long after an announce it is repeated, None if nothing retries announces
+    retry_interval: Option<Duration>,
+}
+
+impl JoinProgress {
+    pub fn new(initial: JoinState, retry_interval: Option<Duration>) -> Self {
+        info!("Join state: {}", initial);
+        JoinProgress { state: watch::channel(initial).0, retry_interval }
+    }
+
+    pub fn watch(&self) -> watch::Receiver<JoinState> {
+        self.state.subscribe()
+    }
+
+    pub fn state(&self) -> JoinState {
+        self.state.borrow().clone()
+    }
+
+    // an announce to target was sent, announces after joining don't change the state
+    pub fn announced(&self, target: SocketAddr) {
+        let attempt = match &*self.state.borrow() {
+            JoinState::NotConfigured => 1,
+            JoinState::Announcing { attempt, .. } => attempt + 1,
+            JoinState::Joined { .. } | JoinState::Failed { .. } => return,
+        };
+        let next_retry = self.retry_interval
+            .and_then(|interval| chrono::Duration::from_std(interval).ok())
+            .map(|interval| Utc::now() + interval);
+        let state = JoinState::Announcing { attempt, next_retry };
+        info!("Announced to {}, join state: {}", target, state);
+        self.state.send_replace(state);
+    }
+
+    // another member is up, only the first one joins the node
+    pub fn member_up(&self, member: SocketAddr) {
+        if self.state.borrow().is_joined() {
+            return;
+        }
+        let state = JoinState::Joined { at: Utc::now(), via: member };
+        info!("Join state: {}", state);
+        self.state.send_replace(state);
+    }
+
+    pub fn failed(&self, reason: String) {
+        let state = JoinState::Failed { reason };
+        error!("Join state: {}", state);
+        self.state.send_replace(state);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn announces_are_counted_until_the_first_member_is_up() {
+        let seed = SocketAddr::from(([127, 0, 0, 1], 9001));
+        let progress = JoinProgress::new(JoinState::NotConfigured, Some(Duration::from_secs(5)));
+        let watch = progress.watch();
+        let before = Utc::now();
+        progress.announced(seed);
+        progress.announced(seed);
+        assert!(matches!(progress.state(), JoinState::Announcing { attempt: 2, next_retry: Some(next_retry) }
+            if next_retry >= before + chrono::Duration::seconds(5)));
+        assert_eq!(serde_json::to_value(progress.state()).unwrap()["state"], "announcing");
+
+        progress.member_up(seed);
+        // neither later members nor announces change the state once joined
+        progress.member_up(SocketAddr::from(([127, 0, 0, 1], 9002)));
+        progress.announced(seed);
+        assert!(matches!(&*watch.borrow(), JoinState::Joined { via, .. } if *via == seed));
+        assert!(!progress.state().is_pending());
+    }
+
+    #[test]
+    fn a_failed_join_is_no_longer_pending() {
+        let progress = JoinProgress::new(JoinState::NotConfigured, None);
+        progress.announced(SocketAddr::from(([127, 0, 0, 1], 9001)));
+        assert_eq!(progress.state(), JoinState::Announcing { attempt: 1, next_retry: None });
+
+        progress.failed("no seed answered".to_owned());
+        progress.announced(SocketAddr::from(([127, 0, 0, 1], 9001)));
+        assert_eq!(progress.state(), JoinState::Failed { reason: "no seed answered".to_owned() });
+        assert!(!progress.state().is_pending());
+    }
+}
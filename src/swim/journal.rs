@@ -0,0 +1,76 @@
+use std::{collections::VecDeque, time::Duration};
+use automerge::{AutoCommit, ChangeHash};
+
+/// Recent local changes kept so that members coming back after being down can be sent exactly
+/// the changes they missed instead of the whole document. At most `max_entries` changes that
+/// are not older than `max_age` are kept.
+pub struct ChangeJournal {
+    entries: VecDeque<JournalEntry>,
+    max_entries: usize,
+    max_age: Duration,
+}
+
+struct JournalEntry {
+    hash: ChangeHash,
+    change: Vec<u8>,
+    // unix millis
+    recorded_at: i64,
+}
+
+impl ChangeJournal {
+    pub fn new(max_entries: usize, max_age: Duration) -> Self {
+        ChangeJournal {
+            entries: VecDeque::new(),
+            max_entries,
+            max_age,
+        }
+    }
+
+    pub fn set_bounds(&mut self, max_entries: usize, max_age: Duration) {
+        self.max_entries = max_entries;
+        self.max_age = max_age;
+        self.prune();
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    // records the last local change of the document, needs to be called after the transaction was closed
+    pub fn record(&mut self, state: &mut AutoCommit) {
+        let change = match state.get_last_local_change() {
+            Some(change) => change,
+            None => return,
+        };
+        let hash = change.hash();
+        if self.entries.back().is_some_and(|e| e.hash == hash) {
+            return;
+        }
+        self.entries.push_back(JournalEntry {
+            hash,
+            change: change.raw_bytes().to_vec(),
+            recorded_at: chrono::Utc::now().timestamp_millis(),
+        });
+        self.prune();
+    }
+
+    // The encoded changes with the given hashes in the given order, or None if any of them
+    // is not (or no longer) part of the journal
+    pub fn changes(&mut self, hashes: &[ChangeHash]) -> Option<Vec<Vec<u8>>> {
+        self.prune();
+        hashes.iter()
+            .map(|hash| self.entries.iter().find(|e| &e.hash == hash).map(|e| e.change.clone()))
+            .collect()
+    }
+
+    fn prune(&mut self) {
+        let oldest_allowed = chrono::Utc::now().timestamp_millis() - self.max_age.as_millis() as i64;
+        while self.entries.front().is_some_and(|e| e.recorded_at < oldest_allowed) || self.entries.len() > self.max_entries {
+            self.entries.pop_front();
+        }
+    }
+}
@@ -0,0 +1,33 @@
+use std::net::SocketAddr;
+use anyhow::Result;
+use log::warn;
+
+use super::store::StateStore;
+
+// The addresses of the other members are remembered in <DATA_DIR>/known_peers.json whenever the
+// members change. After a restart of the whole cluster, e.g. a power cycle, a node without
+// --announce-to or whose seed is gone rejoins by announcing to the members it knew before, see
+// spawn_peer_reconnect. A node that ends up alone keeps the peers it knew last, so that a cluster
+// going down one node at a time still finds together again.
+
+pub const KNOWN_PEERS_KEY: &str = "known_peers.json";
+
+// an unreadable file is treated as empty and replaced once the members change
+pub fn load_known_peers(store: &dyn StateStore) -> Vec<SocketAddr> {
+    match store.load(KNOWN_PEERS_KEY) {
+        Ok(Some(data)) => serde_json::from_slice(&data).unwrap_or_else(|e| {
+            warn!("Ignoring invalid known peers {}: {}", store.describe(KNOWN_PEERS_KEY), e);
+            Vec::new()
+        }),
+        Ok(None) => Vec::new(),
+        Err(e) => {
+            warn!("Could not read known peers: {:#}", e);
+            Vec::new()
+        },
+    }
+}
+
+pub fn save_known_peers(store: &dyn StateStore, peers: &[SocketAddr]) -> Result<()> {
+    let data = serde_json::to_vec_pretty(peers)?;
+    store.save(KNOWN_PEERS_KEY, &data)
+}
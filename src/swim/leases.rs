@@ -0,0 +1,175 @@
+use std::time::Duration;
+use automerge::{AutoCommit, AutomergeError, ObjId, ObjType, ReadDoc, ROOT, ScalarValue, transaction::Transactable};
+use serde::{Deserialize, Serialize};
+
+// Named leases in the `leases` map of the document, each a map of holder and expires_at.
+// Acquiring checks the lease as currently known to this node and writes it if it is free, expired or
+// already held by the same holder. Nothing coordinates this with the other members: two nodes that
+// acquire the same lease before seeing each other's write both succeed, and after merging automerge
+// picks one of them as holder. Leases are therefore advisory only, good enough to mostly keep a job
+// on a single node but never to guard anything that must not run twice.
+
+/// A lease as stored in the document, `expires_at` is in unix millis.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(feature = "rest", derive(utoipa::ToSchema))]
+pub struct Lease {
+    pub name: String,
+    // identity address of the holding node
+    pub holder: String,
+    pub expires_at: i64,
+}
+
+impl Lease {
+    pub fn expired(&self, now: i64) -> bool {
+        self.expires_at <= now
+    }
+}
+
+#[derive(Debug)]
+pub enum LeaseError {
+    // held by another holder and not expired yet
+    Held(Lease),
+    // renewing or releasing a lease of another holder, None if the lease does not exist
+    NotHolder { name: String, lease: Option<Lease> },
+    Automerge(AutomergeError),
+}
+
+impl From<AutomergeError> for LeaseError {
+    fn from(error: AutomergeError) -> Self {
+        LeaseError::Automerge(error)
+    }
+}
+
+/// Compare-and-set operations on the leases of a document, times are unix millis.
+pub struct LeaseManager<'a> {
+    state: &'a mut AutoCommit,
+}
+
+impl<'a> LeaseManager<'a> {
+    pub fn new(state: &'a mut AutoCommit) -> Self {
+        LeaseManager { state }
+    }
+
+    fn leases_map(&self) -> Option<ObjId> {
+        match self.state.get(ROOT, "leases").ok()? {
+            Some((automerge::Value::Object(ObjType::Map), leases)) => Some(leases),
+            _ => None,
+        }
+    }
+
+    pub fn get(&self, name: &str) -> Option<Lease> {
+        let leases = self.leases_map()?;
+        let lease = match self.state.get(&leases, name).ok()? {
+            Some((automerge::Value::Object(ObjType::Map), lease)) => lease,
+            _ => return None,
+        };
+        Some(Lease {
+            name: name.to_owned(),
+            holder: self.state.get(&lease, "holder").ok()??.0.into_string().ok()?,
+            expires_at: self.state.get(&lease, "expires_at").ok()??.0.to_i64()?,
+        })
+    }
+
+    fn put(&mut self, name: &str, holder: &str, ttl: Duration, now: i64) -> Result<Lease, LeaseError> {
+        let leases = match self.leases_map() {
+            Some(leases) => leases,
+            // the map is created by the first lease
+            None => self.state.put_object(ROOT, "leases", ObjType::Map)?,
+        };
+        let lease = Lease {
+            name: name.to_owned(),
+            holder: holder.to_owned(),
+            expires_at: now + ttl.as_millis() as i64,
+        };
+        let lease_obj = self.state.put_object(&leases, name, ObjType::Map)?;
+        self.state.put(&lease_obj, "holder", lease.holder.as_str())?;
+        self.state.put(&lease_obj, "expires_at", ScalarValue::Timestamp(lease.expires_at))?;
+        Ok(lease)
+    }
+
+    // takes the lease if it is free, expired or already held by holder
+    pub fn acquire(&mut self, name: &str, holder: &str, ttl: Duration, now: i64) -> Result<Lease, LeaseError> {
+        match self.get(name) {
+            Some(lease) if lease.holder != holder && !lease.expired(now) => Err(LeaseError::Held(lease)),
+            _ => self.put(name, holder, ttl, now),
+        }
+    }
+
+    // extends a lease of holder, also after it expired as long as nobody else took it
+    pub fn renew(&mut self, name: &str, holder: &str, ttl: Duration, now: i64) -> Result<Lease, LeaseError> {
+        match self.get(name) {
+            Some(lease) if lease.holder == holder => self.put(name, holder, ttl, now),
+            lease => Err(LeaseError::NotHolder { name: name.to_owned(), lease }),
+        }
+    }
+
+    pub fn release(&mut self, name: &str, holder: &str) -> Result<(), LeaseError> {
+        match (self.get(name), self.leases_map()) {
+            (Some(lease), Some(leases)) if lease.holder == holder => Ok(self.state.delete(&leases, name)?),
+            (lease, _) => Err(LeaseError::NotHolder { name: name.to_owned(), lease }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TTL: Duration = Duration::from_secs(30);
+    const NOW: i64 = 1_700_000_000_000;
+
+    #[test]
+    fn a_held_lease_is_refused_to_others_until_it_expires() {
+        let mut state = AutoCommit::new();
+        let mut leases = LeaseManager::new(&mut state);
+        let held = leases.acquire("cron", "127.0.0.1:9001", TTL, NOW).unwrap();
+        assert_eq!(held.expires_at, NOW + 30_000);
+
+        match leases.acquire("cron", "127.0.0.1:9002", TTL, NOW + 29_999) {
+            Err(LeaseError::Held(lease)) => assert_eq!(lease, held),
+            other => panic!("expected the lease to be held, got {:?}", other),
+        }
+        // the holder may acquire it again
+        assert!(leases.acquire("cron", "127.0.0.1:9001", TTL, NOW + 1_000).is_ok());
+
+        let taken = leases.acquire("cron", "127.0.0.1:9002", TTL, NOW + 31_000).unwrap();
+        assert_eq!(taken.holder, "127.0.0.1:9002");
+        assert_eq!(leases.get("cron"), Some(taken));
+    }
+
+    #[test]
+    fn only_the_holder_renews_and_releases() {
+        let mut state = AutoCommit::new();
+        let mut leases = LeaseManager::new(&mut state);
+        let held = leases.acquire("cron", "127.0.0.1:9001", TTL, NOW).unwrap();
+
+        assert!(matches!(leases.renew("cron", "127.0.0.1:9002", TTL, NOW), Err(LeaseError::NotHolder { lease: Some(_), .. })));
+        match leases.release("cron", "127.0.0.1:9002") {
+            Err(LeaseError::NotHolder { name, lease }) => assert_eq!((name.as_str(), lease), ("cron", Some(held))),
+            other => panic!("expected a refused release, got {:?}", other),
+        }
+
+        // renewing after the expiry works as long as nobody took the lease
+        let renewed = leases.renew("cron", "127.0.0.1:9001", TTL, NOW + 60_000).unwrap();
+        assert_eq!(renewed.expires_at, NOW + 90_000);
+        leases.release("cron", "127.0.0.1:9001").unwrap();
+        assert_eq!(leases.get("cron"), None);
+        assert!(matches!(leases.release("cron", "127.0.0.1:9001"), Err(LeaseError::NotHolder { lease: None, .. })));
+    }
+
+    #[test]
+    fn concurrent_acquires_end_with_the_same_holder_on_both_sides() {
+        let mut a = AutoCommit::new();
+        LeaseManager::new(&mut a).acquire("cron", "127.0.0.1:9001", TTL, NOW).unwrap();
+        let mut b = a.fork();
+        // both see the lease expired and take it
+        LeaseManager::new(&mut a).acquire("cron", "127.0.0.1:9001", TTL, NOW + 40_000).unwrap();
+        LeaseManager::new(&mut b).acquire("cron", "127.0.0.1:9002", TTL, NOW + 40_000).unwrap();
+        a.merge(&mut b.clone()).unwrap();
+        b.merge(&mut a.clone()).unwrap();
+
+        let (lease_a, lease_b) = (LeaseManager::new(&mut a).get("cron"), LeaseManager::new(&mut b).get("cron"));
+        assert!(lease_a.is_some());
+        assert_eq!(lease_a, lease_b);
+    }
+}
@@ -0,0 +1,157 @@
+use std::{
+    collections::VecDeque, time::{Duration, Instant},
+};
+use serde::Serialize;
+use uuid::Uuid;
+
+use super::broadcast::Tag;
+
+// How long the backlog has to stay above the threshold before a warning is logged
+pub const BACKLOG_WARNING_DELAY: Duration = Duration::from_secs(60);
+
+/// Bookkeeping of the broadcasts this node hands to foca. Foca does not report when a broadcast
+/// is fully transmitted, so pending entries are trimmed oldest first to the size of foca's custom
+/// broadcast backlog. The backlog also contains broadcasts relayed for other members, so the age
+/// of the oldest pending item is an upper bound.
+#[derive(Debug, Default)]
+pub struct BroadcastLedger {
+    // operation id for broadcasts that foca may invalidate
+    pending: VecDeque<(Option<Uuid>, Instant)>,
+    added: u64,
+    invalidated: u64,
+    over_threshold_since: Option<Instant>,
+    warned: bool,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[cfg_attr(feature = "rest", derive(utoipa::ToSchema))]
+pub struct LedgerStatus {
+    pub added: u64,
+    // replaced by a broadcast with the same operation id before they were fully transmitted
+    pub invalidated: u64,
+    pub pending: usize,
+    pub oldest_pending_secs: Option<f64>,
+}
+
+impl BroadcastLedger {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // mirrors Broadcast::invalidates, a sync operation replaces a pending one with the same operation id.
+    // Returns whether a pending broadcast was invalidated.
+    pub fn record_added(&mut self, tag: &Tag, now: Instant) -> bool {
+        let operation_id = tag.sync_operation_id();
+        let position = operation_id
+            .and_then(|_| self.pending.iter().position(|(id, _)| *id == operation_id));
+        if let Some(position) = position {
+            self.pending.remove(position);
+            self.invalidated += 1;
+        }
+        self.pending.push_back((operation_id, now));
+        self.added += 1;
+        position.is_some()
+    }
+
+    // drops the oldest entries that can no longer be part of foca's backlog
+    pub fn reconcile(&mut self, backlog: usize) {
+        while self.pending.len() > backlog {
+            self.pending.pop_front();
+        }
+    }
+
+    pub fn oldest_pending_age(&self, now: Instant) -> Option<Duration> {
+        self.pending.front().map(|(_, added_at)| now.duration_since(*added_at))
+    }
+
+    // true once per episode of the backlog staying above the threshold for BACKLOG_WARNING_DELAY
+    pub fn should_warn(&mut self, backlog: usize, threshold: usize, now: Instant) -> bool {
+        if backlog <= threshold {
+            self.over_threshold_since = None;
+            self.warned = false;
+            return false;
+        }
+        let since = *self.over_threshold_since.get_or_insert(now);
+        if !self.warned && now.duration_since(since) >= BACKLOG_WARNING_DELAY {
+            self.warned = true;
+            return true;
+        }
+        false
+    }
+
+    pub fn status(&self, now: Instant) -> LedgerStatus {
+        LedgerStatus {
+            added: self.added,
+            invalidated: self.invalidated,
+            pending: self.pending.len(),
+            oldest_pending_secs: self.oldest_pending_age(now).map(|age| age.as_secs_f64()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+    use super::*;
+    use crate::swim::clock::HybridTimestamp;
+
+    fn sync_operation(operation_id: Uuid) -> Tag {
+        Tag::SyncOperation { operation_id }
+    }
+
+    #[test]
+    fn a_broadcast_of_the_same_operation_invalidates_the_pending_one() {
+        let start = Instant::now();
+        let mut ledger = BroadcastLedger::new();
+        let operation_id = Uuid::new_v4();
+        assert!(!ledger.record_added(&sync_operation(operation_id), start));
+        assert!(!ledger.record_added(&sync_operation(Uuid::new_v4()), start + Duration::from_secs(1)));
+        assert!(ledger.record_added(&sync_operation(operation_id), start + Duration::from_secs(2)));
+
+        let status = ledger.status(start + Duration::from_secs(3));
+        assert_eq!((status.added, status.invalidated, status.pending), (3, 1, 2));
+        // the invalidated one was the oldest
+        assert_eq!(status.oldest_pending_secs, Some(2.0));
+    }
+
+    #[test]
+    fn broadcasts_without_operation_id_are_never_invalidated_by_the_ledger() {
+        let now = Instant::now();
+        let mut ledger = BroadcastLedger::new();
+        let node_config = Tag::NodeConfig { node: SocketAddr::from(([127, 0, 0, 1], 9001)), version: HybridTimestamp::default() };
+        assert!(!ledger.record_added(&node_config, now));
+        assert!(!ledger.record_added(&node_config, now));
+        assert_eq!(ledger.status(now).pending, 2);
+    }
+
+    #[test]
+    fn transmitted_broadcasts_are_dropped_oldest_first() {
+        let start = Instant::now();
+        let mut ledger = BroadcastLedger::new();
+        for second in 0..3 {
+            ledger.record_added(&sync_operation(Uuid::new_v4()), start + Duration::from_secs(second));
+        }
+        ledger.reconcile(1);
+        let now = start + Duration::from_secs(5);
+        assert_eq!(ledger.status(now).pending, 1);
+        assert_eq!(ledger.oldest_pending_age(now), Some(Duration::from_secs(3)));
+        ledger.reconcile(0);
+        assert_eq!(ledger.status(now).oldest_pending_secs, None);
+        assert_eq!(ledger.status(now).added, 3);
+    }
+
+    #[test]
+    fn a_backlog_over_the_threshold_is_warned_about_once_after_a_minute() {
+        let start = Instant::now();
+        let mut ledger = BroadcastLedger::new();
+        assert!(!ledger.should_warn(20, 10, start));
+        assert!(!ledger.should_warn(20, 10, start + BACKLOG_WARNING_DELAY - Duration::from_secs(1)));
+        assert!(ledger.should_warn(20, 10, start + BACKLOG_WARNING_DELAY));
+        assert!(!ledger.should_warn(20, 10, start + BACKLOG_WARNING_DELAY * 2));
+        // dropping below the threshold starts a new episode
+        assert!(!ledger.should_warn(5, 10, start + BACKLOG_WARNING_DELAY * 2));
+        let again = start + BACKLOG_WARNING_DELAY * 3;
+        assert!(!ledger.should_warn(20, 10, again));
+        assert!(ledger.should_warn(20, 10, again + BACKLOG_WARNING_DELAY));
+    }
+}
@@ -0,0 +1,154 @@
+use std::{
+    collections::{BTreeMap, BTreeSet}, sync::Arc,
+};
+use anyhow::Result;
+use automerge::ChangeHash;
+use bytes::{BufMut, Bytes, BytesMut};
+use futures_util::Stream;
+
+use super::{auth::{Access, TokenScopes}, field_names, handle::ControllerHandle, tombstones::Tombstone};
+
+// GET /state written while iterating the document instead of from a map of all values, so that the
+// memory of a listing doesn't grow with the number of fields. The fields are read in chunks, the
+// controller only copies the fields of a chunk and is free while it is written. All chunks read the document as of the heads at
+// the start of the listing, so a listing is consistent even if the document changes in between.
+// The bytes are the same as those of serializing the listing with serde_json. A document that can't be read
+// any more ends the stream with an error, so that the connection is aborted instead of the body cut short.
+
+// Fields read per chunk. The range reads of automerge scan the map from its start instead of seeking to
+// the first field, so small chunks make large listings quadratic.
+pub const FIELDS_PER_CHUNK: usize = 10_000;
+
+#[derive(Clone, Copy)]
+enum Section {
+    Values,
+    Meta,
+}
+
+pub struct StateListingStream {
+    controller: ControllerHandle,
+    heads: Vec<ChangeHash>,
+    scopes: Option<Arc<TokenScopes>>,
+    deleted: Option<BTreeMap<String, Tombstone>>,
+    // names of the local fields if the meta section is listed
+    local_fields: Option<BTreeSet<String>>,
+    // leaves out fields whose names break the field name rules, see field_names
+    hide_invalid: bool,
+    section: Option<Section>,
+    // the last field read of the current section, None before its first chunk
+    after: Option<String>,
+    // whether a field was written to the current section, to separate the next one by a comma
+    written: bool,
+    started: bool,
+}
+
+impl StateListingStream {
+    pub fn new(controller: ControllerHandle, heads: Vec<ChangeHash>) -> Self {
+        StateListingStream {
+            controller,
+            heads,
+            scopes: None,
+            deleted: None,
+            local_fields: None,
+            hide_invalid: false,
+            section: Some(Section::Values),
+            after: None,
+            written: false,
+            started: false,
+        }
+    }
+
+    // leaves out the fields the scopes don't allow to read, the tombstones have to be filtered already
+    pub fn readable_by(mut self, scopes: Option<Arc<TokenScopes>>) -> Self {
+        self.scopes = scopes;
+        self
+    }
+
+    pub fn with_deleted(mut self, deleted: Option<BTreeMap<String, Tombstone>>) -> Self {
+        self.deleted = deleted;
+        self
+    }
+
+    pub fn with_meta(mut self, local_fields: Option<BTreeSet<String>>) -> Self {
+        self.local_fields = local_fields;
+        self
+    }
+
+    // the tombstones have to be filtered already
+    pub fn hiding_invalid(mut self, hide_invalid: bool) -> Self {
+        self.hide_invalid = hide_invalid;
+        self
+    }
+
+    // the next chunk of the listing, None once it is complete
+    async fn next_chunk(&mut self) -> Option<Result<Bytes>> {
+        let section = self.section?;
+        let mut chunk = BytesMut::new();
+        if !self.started {
+            chunk.put_slice(b"{\"values\":{");
+            self.started = true;
+        }
+        let fields = match self.controller.read_fields(self.after.clone(), self.heads.clone(), FIELDS_PER_CHUNK).await {
+            Ok(fields) => fields,
+            Err(e) => {
+                self.section = None;
+                return Some(Err(e));
+            },
+        };
+        for (key, value) in &fields {
+            if self.scopes.as_ref().is_some_and(|scopes| !scopes.allows(key, Access::Read)) {
+                continue;
+            }
+            if self.hide_invalid && !field_names::is_valid(key) {
+                continue;
+            }
+            if self.written {
+                chunk.put_u8(b',');
+            }
+            self.written = true;
+            write_json(&mut chunk, key);
+            chunk.put_u8(b':');
+            match section {
+                Section::Values => write_json(&mut chunk, value),
+                Section::Meta => {
+                    let replicated = !self.local_fields.as_ref().is_some_and(|local| local.contains(key));
+                    chunk.put_slice(if replicated { b"{\"replicated\":true}" } else { b"{\"replicated\":false}" });
+                },
+            }
+        }
+        if fields.len() == FIELDS_PER_CHUNK {
+            self.after = fields.last().map(|(key, _)| key.clone());
+            return Some(Ok(chunk.freeze()));
+        }
+        // the section is complete
+        chunk.put_u8(b'}');
+        self.after = None;
+        self.written = false;
+        self.section = match section {
+            Section::Values => {
+                if let Some(deleted) = &self.deleted {
+                    chunk.put_slice(b",\"deleted\":");
+                    chunk.put_slice(&serde_json::to_vec(deleted).expect("tombstones can be serialized"));
+                }
+                self.local_fields.is_some().then_some(Section::Meta)
+            },
+            Section::Meta => None,
+        };
+        match self.section {
+            Some(Section::Meta) => chunk.put_slice(b",\"meta\":{"),
+            _ => chunk.put_u8(b'}'),
+        }
+        Some(Ok(chunk.freeze()))
+    }
+
+    pub fn into_stream(self) -> impl Stream<Item = Result<Bytes>> {
+        futures_util::stream::unfold(self, |mut listing| async move {
+            let chunk = listing.next_chunk().await?;
+            Some((chunk, listing))
+        })
+    }
+}
+
+fn write_json(chunk: &mut BytesMut, s: &str) {
+    serde_json::to_writer(BufMut::writer(chunk), s).expect("strings can be serialized");
+}
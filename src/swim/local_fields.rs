@@ -0,0 +1,97 @@
+use std::collections::BTreeMap;
+use anyhow::Result;
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+use super::{pattern::glob_matches, store::SharedStateStore};
+
+// Fields matching one of the --no-replicate patterns are node-local scratch data. They are kept out of
+// the document, so they are never gossiped nor part of get_state(), and are stored as JSON in
+// <DATA_DIR>/local_fields.json instead. The data handler routes reads and writes by key, so they are
+// served by the same routes as replicated fields.
+// Fields stored here that no longer match a pattern, e.g. after the patterns changed between restarts,
+// are still read from here until the data handler migrates them, see set_no_replicate.
+
+/// A field only stored on this node, `modified_at` is in unix millis.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LocalField {
+    pub value: String,
+    pub modified_at: i64,
+}
+
+pub const LOCAL_FIELDS_KEY: &str = "local_fields.json";
+
+pub struct LocalFields {
+    store: SharedStateStore,
+    patterns: Vec<String>,
+    fields: BTreeMap<String, LocalField>,
+}
+
+impl LocalFields {
+    // without patterns until set_patterns, an unreadable file is treated as empty and replaced by the next write
+    pub fn load(store: SharedStateStore) -> Self {
+        let fields = match store.load(LOCAL_FIELDS_KEY) {
+            Ok(Some(data)) => serde_json::from_slice(&data).unwrap_or_else(|e| {
+                warn!("Ignoring invalid local fields {}: {}", store.describe(LOCAL_FIELDS_KEY), e);
+                BTreeMap::new()
+            }),
+            Ok(None) => BTreeMap::new(),
+            Err(e) => {
+                warn!("Could not read local fields: {:#}", e);
+                BTreeMap::new()
+            },
+        };
+        LocalFields { store, patterns: Vec::new(), fields }
+    }
+
+    pub fn set_patterns(&mut self, patterns: Vec<String>) {
+        self.patterns = patterns;
+    }
+
+    pub fn patterns(&self) -> &[String] {
+        &self.patterns
+    }
+
+    // whether writes of the field stay on this node
+    pub fn matches(&self, field_name: &str) -> bool {
+        self.patterns.iter().any(|pattern| glob_matches(pattern, field_name))
+    }
+
+    pub fn get(&self, field_name: &str) -> Option<&LocalField> {
+        self.fields.get(field_name)
+    }
+
+    pub fn contains(&self, field_name: &str) -> bool {
+        self.fields.contains_key(field_name)
+    }
+
+    pub fn fields(&self) -> &BTreeMap<String, LocalField> {
+        &self.fields
+    }
+
+    // stored fields that no longer match any pattern
+    pub fn unmatched(&self) -> Vec<String> {
+        self.fields.keys()
+            .filter(|field_name| !self.matches(field_name))
+            .cloned()
+            .collect()
+    }
+
+    pub fn insert(&mut self, field_name: &str, value: String, modified_at: i64) -> Result<()> {
+        self.fields.insert(field_name.to_owned(), LocalField { value, modified_at });
+        self.save()
+    }
+
+    pub fn remove(&mut self, field_name: &str) -> Result<Option<LocalField>> {
+        let removed = self.fields.remove(field_name);
+        if removed.is_some() {
+            self.save()?;
+        }
+        Ok(removed)
+    }
+
+    fn save(&self) -> Result<()> {
+        let data = serde_json::to_vec_pretty(&self.fields)?;
+        self.store.save(LOCAL_FIELDS_KEY, &data)
+    }
+}
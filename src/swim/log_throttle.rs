@@ -0,0 +1,97 @@
+use std::{
+    collections::HashMap, fmt::Arguments, sync::Mutex, time::{Duration, Instant},
+};
+use log::Level;
+use once_cell::sync::Lazy;
+
+use super::metrics;
+
+// During a network incident the gossip hot paths fail the same way thousands of times. Their errors are
+// counted by category in gossip_errors_total and logged through report, which logs the first error of a
+// category and every change of category right away, but a category repeating itself at most once per
+// LOG_INTERVAL, with the number of errors left out since its last line. Errors left out at the end of a
+// burst are only in the metric until the category fails again.
+
+pub const LOG_INTERVAL: Duration = Duration::from_secs(10);
+
+static THROTTLE: Lazy<ErrorThrottle> = Lazy::new(|| ErrorThrottle::new(LOG_INTERVAL));
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ErrorCategory {
+    // the socket failed to receive a datagram
+    SocketReceive,
+    // a datagram without the magic bytes or of another envelope version, see envelope
+    EnvelopeRejected,
+    // foca or the broadcast handler could not decode what a member sent
+    DecodeFailure,
+    // a received broadcast or direct message could not be applied to the document
+    MergeFailure,
+}
+
+impl ErrorCategory {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ErrorCategory::SocketReceive => "socket_receive",
+            ErrorCategory::EnvelopeRejected => "envelope_rejected",
+            ErrorCategory::DecodeFailure => "decode_failure",
+            ErrorCategory::MergeFailure => "merge_failure",
+        }
+    }
+}
+
+struct CategoryState {
+    logged: Instant,
+    // errors left out since logged
+    suppressed: u64,
+}
+
+#[derive(Default)]
+struct ThrottleState {
+    categories: HashMap<ErrorCategory, CategoryState>,
+    // category of the last error, logged or not
+    last: Option<ErrorCategory>,
+}
+
+/// Decides which errors of a category are logged, see log_throttle.
+pub struct ErrorThrottle {
+    interval: Duration,
+    state: Mutex<ThrottleState>,
+}
+
+impl ErrorThrottle {
+    pub fn new(interval: Duration) -> Self {
+        ErrorThrottle { interval, state: Mutex::new(ThrottleState::default()) }
+    }
+
+    // Some with the number of errors of the category left out since its last line if this one is logged
+    pub fn check(&self, category: ErrorCategory, now: Instant) -> Option<u64> {
+        let mut guard = self.state.lock().unwrap();
+        let state = &mut *guard;
+        let transition = state.last.replace(category) != Some(category);
+        let interval = self.interval;
+        match state.categories.get_mut(&category) {
+            Some(seen) if !transition && now.saturating_duration_since(seen.logged) < interval => {
+                seen.suppressed += 1;
+                None
+            },
+            Some(seen) => {
+                seen.logged = now;
+                Some(std::mem::take(&mut seen.suppressed))
+            },
+            None => {
+                state.categories.insert(category, CategoryState { logged: now, suppressed: 0 });
+                Some(0)
+            },
+        }
+    }
+}
+
+// Counts the error and logs message at level unless the category was logged within LOG_INTERVAL
+pub fn report(category: ErrorCategory, level: Level, message: Arguments) {
+    metrics::GOSSIP_ERRORS.with_label_values(&[category.as_str()]).inc();
+    match THROTTLE.check(category, Instant::now()) {
+        Some(0) => log::log!(level, "{}", message),
+        Some(suppressed) => log::log!(level, "{} (repeated {} times since the last report)", message, suppressed),
+        None => {},
+    }
+}
@@ -0,0 +1,126 @@
+use std::sync::{Arc, RwLock};
+
+use anyhow::{bail, Result};
+use log::{LevelFilter, Log, Metadata, Record};
+use once_cell::sync::OnceCell;
+
+// env_logger can't change its filter once installed, so the installed logger only delegates
+// to an env_logger instance that gets replaced whenever the filter changes.
+static LOGGER: OnceCell<Arc<RwLock<Reloadable>>> = OnceCell::new();
+
+struct Reloadable {
+    logger: env_logger::Logger,
+    filter: String,
+}
+
+struct ReloadableLogger(Arc<RwLock<Reloadable>>);
+
+impl Log for ReloadableLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.0.read().unwrap().logger.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        self.0.read().unwrap().logger.log(record)
+    }
+
+    fn flush(&self) {
+        self.0.read().unwrap().logger.flush()
+    }
+}
+
+// Replacement for env_logger::init() that allows changing the filter at runtime.
+// The initial filter is taken from RUST_LOG and defaults to `error` like env_logger does.
+pub fn init() {
+    let filter = std::env::var("RUST_LOG").unwrap_or_else(|_| "error".to_owned());
+    let reloadable = Arc::new(RwLock::new(Reloadable {
+        logger: build_logger(&filter),
+        filter,
+    }));
+    if LOGGER.set(reloadable.clone()).is_err() {
+        return;
+    }
+    log::set_max_level(reloadable.read().unwrap().logger.filter());
+    log::set_boxed_logger(Box::new(ReloadableLogger(reloadable)))
+        .expect("no other logger should be installed when using holydiver logging");
+}
+
+pub fn current_filter() -> Option<String> {
+    LOGGER.get().map(|l| l.read().unwrap().filter.to_owned())
+}
+
+// Applies a new filter in RUST_LOG syntax, e.g. `holydiver=debug,foca=trace`
+pub fn set_filter(filter: &str) -> Result<()> {
+    validate_filter(filter)?;
+    let reloadable = match LOGGER.get() {
+        Some(reloadable) => reloadable,
+        None => bail!("logging was not initialized with holydiver::swim::logging::init"),
+    };
+    let mut reloadable = reloadable.write().unwrap();
+    reloadable.logger = build_logger(filter);
+    reloadable.filter = filter.to_owned();
+    log::set_max_level(reloadable.logger.filter());
+    Ok(())
+}
+
+fn build_logger(filter: &str) -> env_logger::Logger {
+    env_logger::Builder::new().parse_filters(filter).build()
+}
+
+// env_logger silently ignores invalid directives, so they are checked up front
+fn validate_filter(filter: &str) -> Result<()> {
+    for directive in filter.split(',').map(str::trim).filter(|d| !d.is_empty()) {
+        let level = match directive.split_once('=') {
+            Some((module, level)) => {
+                if module.is_empty() {
+                    bail!("missing module name in {:?}", directive);
+                }
+                level
+            },
+            None => directive,
+        };
+        // a bare module name without level is valid and enables everything for it
+        if directive.contains('=') && level.parse::<LevelFilter>().is_err() {
+            bail!("invalid log level {:?} in {:?}", level, directive);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use log::Level;
+    use super::*;
+
+    // whether a line of level logged by target would be written by the installed logger
+    fn written(target: &str, level: Level) -> bool {
+        level <= log::max_level() && log::logger().enabled(&Metadata::builder().target(target).level(level).build())
+    }
+
+    // the only test touching the global logger, others would see its filter change
+    #[test]
+    fn debug_lines_are_written_once_the_filter_allows_them() {
+        std::env::set_var("RUST_LOG", "holydiver=info");
+        init();
+        assert_eq!(current_filter().as_deref(), Some("holydiver=info"));
+        assert!(written("holydiver::swim::core", Level::Info));
+        assert!(!written("holydiver::swim::core", Level::Debug));
+
+        set_filter("holydiver=debug,foca=warn").unwrap();
+        assert_eq!(current_filter().as_deref(), Some("holydiver=debug,foca=warn"));
+        assert!(written("holydiver::swim::core", Level::Debug));
+        assert!(!written("foca", Level::Info));
+
+        // a refused filter leaves the current one in place
+        assert!(set_filter("holydiver=loud").is_err());
+        assert!(written("holydiver::swim::core", Level::Debug));
+    }
+
+    #[test]
+    fn invalid_filters_are_refused() {
+        assert!(validate_filter("debug").is_ok());
+        assert!(validate_filter("holydiver, foca=trace").is_ok());
+        assert!(validate_filter("=debug").is_err());
+        assert!(validate_filter("holydiver=loud").is_err());
+    }
+}
@@ -0,0 +1,163 @@
+use std::{
+    fmt::{Display, Formatter}, net::SocketAddr,
+};
+use anyhow::{Context, Result};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use super::{node_id::{load_or_create_node_id, NODE_ID_KEY}, store::StateStore};
+
+// <DATA_DIR>/manifest.json records the node owning the dir by its identity and node id. A unit file copied
+// from another node can point a node at the dir of that node, which would merge the history written under
+// the other identity and reuse its actors, so a node refuses to start on a dir recorded for another identity
+// or node id. The identity is the address peers know the node by, i.e. --identity or --advertise-address,
+// the bind address can change freely. A dir without a manifest is claimed by the first node starting on it,
+// --adopt-data-dir takes over a dir recorded for another node and rewrites the manifest. A missing node id
+// is generated anew and so no longer matches the manifest either.
+
+pub const MANIFEST_KEY: &str = "manifest.json";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DataDirManifest {
+    pub identity: SocketAddr,
+    pub node_id: Uuid,
+}
+
+// Raised inside anyhow errors, callers can downcast to tell a dir of another node from other failures.
+#[derive(Debug)]
+pub struct DataDirOwnerError {
+    // where the manifest is kept
+    pub manifest: String,
+    pub recorded: DataDirManifest,
+    pub identity: SocketAddr,
+    // None if the dir has no valid node id and a new one would be generated
+    pub node_id: Option<Uuid>,
+}
+
+impl Display for DataDirOwnerError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let node_id = self.node_id.map_or_else(|| "a new node id".to_owned(), |node_id| format!("node id {}", node_id));
+        write!(f, "{} records identity {} with node id {} but this node is {} with {}, the data dir may belong to \
+            another node, refusing to start; check --data-dir and --identity or start with --adopt-data-dir to take \
+            the dir over", self.manifest, self.recorded.identity, self.recorded.node_id, self.identity, node_id)
+    }
+}
+
+impl std::error::Error for DataDirOwnerError {}
+
+// None if the dir has no manifest yet
+pub fn read_manifest(store: &dyn StateStore) -> Result<Option<DataDirManifest>> {
+    store.load(MANIFEST_KEY)?
+        .map(|data| serde_json::from_slice(&data)
+            .with_context(|| format!("invalid manifest {}", store.describe(MANIFEST_KEY))))
+        .transpose()
+}
+
+// The mismatch claim_data_dir would refuse to start on, without writing anything.
+pub fn check_data_dir_owner(store: &dyn StateStore, identity: SocketAddr) -> Result<Option<DataDirOwnerError>> {
+    let Some(recorded) = read_manifest(store)? else {
+        return Ok(None);
+    };
+    let node_id = store.load(NODE_ID_KEY)?
+        .and_then(|bytes| Uuid::parse_str(String::from_utf8_lossy(&bytes).trim()).ok());
+    Ok(mismatch(store, recorded, identity, node_id))
+}
+
+// Makes sure the dir belongs to this node before anything else reads it, records identity and the node id
+// in a dir without a manifest. With adopt a dir recorded for another node is taken over.
+pub fn claim_data_dir(store: &dyn StateStore, identity: SocketAddr, adopt: bool) -> Result<DataDirManifest> {
+    let recorded = match read_manifest(store) {
+        Err(e) if adopt => {
+            warn!("Replacing {:#}", e);
+            None
+        },
+        recorded => recorded.context("start with --adopt-data-dir to replace the manifest")?,
+    };
+    let node_id = load_or_create_node_id(store)?;
+    let manifest = DataDirManifest { identity, node_id };
+    match recorded {
+        Some(recorded) if recorded == manifest => return Ok(manifest),
+        Some(recorded) if adopt => warn!("Adopting the data dir of identity {} with node id {} as {} with node id {}",
+            recorded.identity, recorded.node_id, identity, node_id),
+        Some(recorded) => return Err(mismatch(store, recorded, identity, Some(node_id))
+            .expect("a manifest that differs is a mismatch")
+            .into()),
+        None => info!("Recording identity {} with node id {} in {}", identity, node_id, store.describe(MANIFEST_KEY)),
+    }
+    store.save(MANIFEST_KEY, &serde_json::to_vec_pretty(&manifest)?)?;
+    Ok(manifest)
+}
+
+fn mismatch(store: &dyn StateStore, recorded: DataDirManifest, identity: SocketAddr, node_id: Option<Uuid>) -> Option<DataDirOwnerError> {
+    (recorded.identity != identity || Some(recorded.node_id) != node_id).then(|| DataDirOwnerError {
+        manifest: store.describe(MANIFEST_KEY),
+        recorded,
+        identity,
+        node_id,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::TempDir;
+    use super::*;
+    use crate::swim::store::FileStore;
+
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], port))
+    }
+
+    fn refusal(result: Result<DataDirManifest>) -> DataDirOwnerError {
+        result.unwrap_err().downcast::<DataDirOwnerError>().expect("a refusal to start on the dir of another node")
+    }
+
+    #[test]
+    fn the_first_node_claims_the_dir_and_starts_on_it_again() {
+        let dir = TempDir::new().unwrap();
+        let store = FileStore::new(dir.path());
+        let claimed = claim_data_dir(&store, addr(9001), false).unwrap();
+        assert_eq!(read_manifest(&store).unwrap(), Some(claimed));
+
+        assert_eq!(claim_data_dir(&store, addr(9001), false).unwrap(), claimed);
+        assert!(check_data_dir_owner(&store, addr(9001)).unwrap().is_none());
+    }
+
+    #[test]
+    fn another_identity_is_refused_until_it_adopts_the_dir() {
+        let dir = TempDir::new().unwrap();
+        let store = FileStore::new(dir.path());
+        let claimed = claim_data_dir(&store, addr(9001), false).unwrap();
+
+        let refused = refusal(claim_data_dir(&store, addr(9002), false));
+        assert_eq!((refused.recorded, refused.identity, refused.node_id), (claimed, addr(9002), Some(claimed.node_id)));
+        assert!(check_data_dir_owner(&store, addr(9002)).unwrap().is_some());
+        assert_eq!(read_manifest(&store).unwrap(), Some(claimed));
+
+        let adopted = claim_data_dir(&store, addr(9002), true).unwrap();
+        assert_eq!(adopted, DataDirManifest { identity: addr(9002), node_id: claimed.node_id });
+        assert_eq!(read_manifest(&store).unwrap(), Some(adopted));
+        assert!(refusal(claim_data_dir(&store, addr(9001), false)).node_id.is_some());
+    }
+
+    #[test]
+    fn a_lost_node_id_no_longer_matches() {
+        let dir = TempDir::new().unwrap();
+        let store = FileStore::new(dir.path());
+        claim_data_dir(&store, addr(9001), false).unwrap();
+        std::fs::remove_file(store.path(NODE_ID_KEY)).unwrap();
+
+        assert_eq!(check_data_dir_owner(&store, addr(9001)).unwrap().map(|mismatch| mismatch.node_id), Some(None));
+        assert!(refusal(claim_data_dir(&store, addr(9001), false)).node_id.is_some());
+    }
+
+    #[test]
+    fn an_invalid_manifest_is_only_replaced_when_adopting() {
+        let dir = TempDir::new().unwrap();
+        let store = FileStore::new(dir.path());
+        store.save(MANIFEST_KEY, b"not json").unwrap();
+        assert!(claim_data_dir(&store, addr(9001), false).is_err());
+        let adopted = claim_data_dir(&store, addr(9001), true).unwrap();
+        assert_eq!(read_manifest(&store).unwrap(), Some(adopted));
+    }
+}
@@ -1,50 +1,110 @@
 use std::{
-    collections::HashMap, net::SocketAddr
+    collections::{BTreeMap, BTreeSet, HashMap}, net::SocketAddr, sync::{Arc, Mutex},
 };
 
-use crate::swim::types::ID;
+use log::{debug, warn};
 
+use crate::swim::{clock::HybridTimestamp, types::ID};
+
+// Labels members gossip about themselves with NodeConfig broadcasts, e.g. their version, see build_info
+pub type NodeLabels = BTreeMap<String, String>;
+
+// URL of the REST endpoint of a member, added once its server is bound, see bind_server_with_config
+pub const REST_URL_LABEL: &str = "rest_url";
+
+// The newest labels received per member with the time they were sent. Every member is the only writer
+// of its own labels, so the newest broadcast wins.
+#[derive(Debug, Default, Clone)]
+pub struct MemberLabels(Arc<Mutex<HashMap<SocketAddr, (HybridTimestamp, NodeLabels)>>>);
+
+impl MemberLabels {
+    // returns whether the labels are newer than the known ones
+    pub fn update(&self, node: SocketAddr, sent_at: HybridTimestamp, labels: NodeLabels) -> bool {
+        let mut known = self.0.lock().unwrap();
+        if known.get(&node).is_some_and(|(known_sent_at, _)| *known_sent_at >= sent_at) {
+            return false;
+        }
+        known.insert(node, (sent_at, labels));
+        true
+    }
+
+    pub fn get(&self, node: &SocketAddr) -> Option<NodeLabels> {
+        self.0.lock().unwrap().get(node).map(|(_, labels)| labels.clone())
+    }
+}
+
+// Foca can hold several identities of the same address at once, e.g. the old and the renewed identity of a
+// member that rejoined, so every address counts the identities that are up. Notifications of identities come
+// in any order after renewals, a MemberDown of an address that isn't up is logged and ignored instead of
+// letting the counter underflow. The addresses are kept sorted, so that they are listed and logged in a stable order.
 #[derive(Debug)]
-pub struct Members(HashMap<SocketAddr, u8>);
+pub struct Members(BTreeMap<SocketAddr, u8>);
+
+impl Default for Members {
+    fn default() -> Self {
+        Self::new()
+    }
+}
 
 impl Members {
     pub fn new() -> Self {
-        Self(HashMap::new())
+        Self(BTreeMap::new())
     }
 
     // A result of `true` means that the effective list of
     // cluster member addresses has changed
-    pub fn add_member(&mut self, member: ID) -> bool {
+    pub fn add_member(&mut self, member: &ID) -> bool {
         // Notice how we don't care at all about the `bump` part.
         // It's only useful for Foca.
         let counter = self.0.entry(member.addr).or_insert(0);
-
+        if *counter == u8::MAX {
+            warn!("Ignoring MemberUp of {:?}, {} identities of {} are up already", member, counter, member.addr);
+            return false;
+        }
         *counter += 1;
-
-        counter == &1
+        *counter == 1
     }
 
     // A result of `true` means that the effective list of
     // cluster member addresses has changed
-    pub fn remove_member(&mut self, member: ID) -> bool {
-        let effectively_down = if let Some(counter) = self.0.get_mut(&member.addr) {
-            *counter -= 1;
-
-            counter == &0
-        } else {
-            // Shouldn't happen
-            false
+    pub fn remove_member(&mut self, member: &ID) -> bool {
+        let Some(counter) = self.0.get_mut(&member.addr) else {
+            warn!("Ignoring MemberDown of {:?}, no identity of {} is up", member, member.addr);
+            return false;
         };
-
-        if effectively_down {
-            self.0.remove(&member.addr);
+        *counter -= 1;
+        if *counter > 0 {
+            debug!("{:?} is down, {} other identities of {} are still up", member, counter, member.addr);
+            return false;
         }
+        self.0.remove(&member.addr);
+        true
+    }
+
+    // the number of addresses with an identity that is up
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
 
-        effectively_down
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
     }
 
-    // prefixed with _ to prevent compiler warning not sure if this will be needed
-    pub fn _addrs(&self) -> impl Iterator<Item = &SocketAddr> {
+    pub fn contains(&self, addr: &SocketAddr) -> bool {
+        self.0.contains_key(addr)
+    }
+
+    pub fn addrs(&self) -> impl Iterator<Item = &SocketAddr> {
         self.0.keys()
     }
-}
\ No newline at end of file
+
+    // addresses in a stable order, e.g. for listing them over REST
+    pub fn sorted_addrs(&self) -> Vec<SocketAddr> {
+        self.addrs().copied().collect()
+    }
+
+    // the addresses as of now, to hand them to tasks outside of the foca loop
+    pub fn snapshot(&self) -> BTreeSet<SocketAddr> {
+        self.addrs().copied().collect()
+    }
+}
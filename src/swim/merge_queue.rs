@@ -0,0 +1,132 @@
+use std::{
+    collections::VecDeque, net::SocketAddr, sync::{Arc, Mutex}, time::Instant,
+};
+use bytes::Bytes;
+use log::warn;
+use tokio::sync::Notify;
+use uuid::Uuid;
+
+use super::{apply_latency::OperationOrigin, broadcast::MessageType, metrics};
+
+// Received broadcasts with changes of the document are merged by a worker of their own instead of inside
+// receive_item, so that foca keeps handling SWIM messages and timers while large documents are merged, see
+// spawn_merge_worker. receive_item only dedups and relays them and pushes them onto this queue.
+// The queue is bounded. When it is full, a FullSync replaces a queued FullSync of the same source, since both
+// are the whole document of a member. FullSyncs only name their origin if they are AckedOperations, otherwise
+// the member that passed them on stands in for it and may have relayed the document of another member.
+// Everything else that doesn't fit is dropped, but never silently: once the queue is drained, the members are
+// asked for the changes this node is missing, like a member that was down, see FocaCommand::CatchUp.
+
+pub const DEFAULT_MERGE_QUEUE_SIZE: usize = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Source {
+    // the member that broadcast the document
+    Origin(SocketAddr),
+    // the member foca received the broadcast from
+    Relay(SocketAddr),
+}
+
+pub struct QueuedMerge {
+    pub operation_id: Uuid,
+    pub message_type: MessageType,
+    pub payload: Bytes,
+    // continues the trace of the sender, see GossipMessage::apply_span
+    pub span: tracing::Span,
+    pub source: Option<Source>,
+    // origins and ids of the AckedOperations to acknowledge once merged, several after a FullSync replaced others
+    pub acks: Vec<(SocketAddr, Uuid)>,
+    // where and when the changes were written, see apply_latency, like acks several after a FullSync replaced others
+    pub origins: Vec<OperationOrigin>,
+    pub enqueued: Instant,
+}
+
+impl QueuedMerge {
+    fn replaces(&self, other: &QueuedMerge) -> bool {
+        self.message_type == MessageType::FullSync && other.message_type == MessageType::FullSync
+            && self.source.is_some() && self.source == other.source
+    }
+}
+
+#[derive(Default)]
+struct Pending {
+    merges: VecDeque<QueuedMerge>,
+    // set when a merge was dropped, the members are asked for their changes once the queue is drained
+    catch_up: bool,
+}
+
+#[derive(Clone)]
+pub struct MergeQueue {
+    pending: Arc<Mutex<Pending>>,
+    wake: Arc<Notify>,
+    capacity: usize,
+}
+
+impl MergeQueue {
+    pub fn new(capacity: usize) -> Self {
+        MergeQueue {
+            pending: Arc::default(),
+            wake: Arc::new(Notify::new()),
+            capacity: capacity.max(1),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.pending.lock().unwrap().merges.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // never blocks, a full queue coalesces or drops as described above
+    pub fn push(&self, mut merge: QueuedMerge) {
+        let mut guard = self.pending.lock().unwrap();
+        let pending = &mut *guard;
+        if pending.merges.len() < self.capacity {
+            pending.merges.push_back(merge);
+        } else if let Some(queued) = pending.merges.iter_mut().find(|queued| merge.replaces(queued)) {
+            if let Some(Source::Relay(relay)) = merge.source {
+                // the replaced document may have been of another member than the one relaying both
+                warn!("Merge queue is full, replacing a FullSync relayed by {} with a newer one", relay);
+                pending.catch_up = true;
+            }
+            merge.acks.append(&mut queued.acks);
+            merge.origins.append(&mut queued.origins);
+            *queued = merge;
+            metrics::MERGE_QUEUE_COALESCED.inc();
+        } else {
+            warn!("Merge queue is full, dropping {:?} of broadcast {}, the members will be asked for the missing changes",
+                merge.message_type, merge.operation_id);
+            metrics::MERGE_QUEUE_DROPPED.inc();
+            pending.catch_up = true;
+        }
+        metrics::MERGE_QUEUE_DEPTH.set(pending.merges.len() as i64);
+        drop(guard);
+        self.wake.notify_one();
+    }
+
+    // waits for the next merge
+    pub async fn pop(&self) -> QueuedMerge {
+        loop {
+            if let Some(merge) = self.try_pop() {
+                return merge;
+            }
+            self.wake.notified().await;
+        }
+    }
+
+    fn try_pop(&self) -> Option<QueuedMerge> {
+        let mut pending = self.pending.lock().unwrap();
+        let merge = pending.merges.pop_front();
+        metrics::MERGE_QUEUE_DEPTH.set(pending.merges.len() as i64);
+        merge
+    }
+
+    // whether merges were dropped and the members have to be asked for their changes now, true at most once
+    // per drop and only after the queue was drained
+    pub fn take_catch_up(&self) -> bool {
+        let mut pending = self.pending.lock().unwrap();
+        pending.merges.is_empty() && std::mem::take(&mut pending.catch_up)
+    }
+}
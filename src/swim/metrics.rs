@@ -0,0 +1,357 @@
+use once_cell::sync::Lazy;
+#[cfg(feature = "metrics")]
+use prometheus::{exponential_buckets, Encoder, Gauge, GaugeVec, Histogram, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge, Opts, Registry, TextEncoder};
+#[cfg(not(feature = "metrics"))]
+use noop::{exponential_buckets, Gauge, GaugeVec, Histogram, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge, Opts};
+
+// All holy-diver metrics are registered here and rendered by the /metrics endpoint.
+// The registry is process wide, so multiple nodes running in one process share their metrics.
+// Without the metrics feature they are the no-op stand-ins of the noop module and nothing is rendered.
+#[cfg(feature = "metrics")]
+pub static REGISTRY: Lazy<Registry> = Lazy::new(|| Registry::new_custom(Some("holydiver".to_owned()), None)
+    .expect("the registry prefix is valid"));
+
+#[cfg(feature = "metrics")]
+fn register<T: prometheus::core::Collector + Clone + 'static>(collector: T) -> T {
+    REGISTRY.register(Box::new(collector.clone())).expect("metrics are only registered once");
+    collector
+}
+
+#[cfg(not(feature = "metrics"))]
+fn register<T>(collector: T) -> T {
+    collector
+}
+
+pub static DOC_KEYS: Lazy<IntGauge> = Lazy::new(|| register(
+    IntGauge::new("doc_keys", "Number of keys in the values map").unwrap()));
+pub static DOC_SIZE_BYTES: Lazy<IntGauge> = Lazy::new(|| register(
+    IntGauge::new("doc_size_bytes", "Size of the last full save of the document").unwrap()));
+pub static DOC_INCREMENTAL_SIZE_BYTES: Lazy<IntGauge> = Lazy::new(|| register(
+    IntGauge::new("doc_incremental_size_bytes", "Size of the changes made since the last full save").unwrap()));
+pub static DOC_ESTIMATED_SIZE_BYTES: Lazy<IntGauge> = Lazy::new(|| register(
+    IntGauge::new("doc_estimated_size_bytes", "Estimated size of the document that local writes are checked against").unwrap()));
+pub static DOC_MAX_SIZE_BYTES: Lazy<IntGauge> = Lazy::new(|| register(
+    IntGauge::new("doc_max_size_bytes", "Maximum size of the document set with --max-doc-size, 0 without").unwrap()));
+pub static DOC_MAX_SIZE_EXCEEDED: Lazy<IntCounter> = Lazy::new(|| register(
+    IntCounter::new("doc_max_size_exceeded_total", "Merges of remote changes that grew the document beyond its maximum size").unwrap()));
+pub static DOC_STRUCTURE_ANOMALIES: Lazy<IntCounterVec> = Lazy::new(|| register(IntCounterVec::new(
+    Opts::new("doc_structure_anomalies_total", "Deviations from the expected document structure introduced by merges of remote changes"),
+    &["kind"]).unwrap()));
+pub static DOC_STRUCTURE_REPAIRS: Lazy<IntCounter> = Lazy::new(|| register(
+    IntCounter::new("doc_structure_repairs_total", "Local writes that recreated a missing values map").unwrap()));
+pub static DOC_CHANGES: Lazy<IntGauge> = Lazy::new(|| register(
+    IntGauge::new("doc_changes", "Number of changes in the document history").unwrap()));
+pub static DOC_ACTORS: Lazy<IntGauge> = Lazy::new(|| register(
+    IntGauge::new("doc_actors", "Number of distinct actors in the document history").unwrap()));
+pub static DOC_HISTORY_RATIO: Lazy<Gauge> = Lazy::new(|| register(
+    Gauge::new("doc_history_ratio", "Size of the saved document divided by the size of its values, as of the last history assessment").unwrap()));
+pub static DOC_COMPACTED_SIZE_BYTES: Lazy<IntGauge> = Lazy::new(|| register(
+    IntGauge::new("doc_compacted_size_bytes", "Estimated size of the document rebuilt without its history").unwrap()));
+pub static COMPACTION_RECOMMENDED: Lazy<IntGauge> = Lazy::new(|| register(
+    IntGauge::new("compaction_recommended", "1 if the last history assessment recommends compacting the document, 0 otherwise").unwrap()));
+pub static MERGE_CONFLICTS: Lazy<IntCounter> = Lazy::new(|| register(
+    IntCounter::new("merge_conflicts_total", "Fields that became conflicted through merges").unwrap()));
+pub static CONFLICT_POLICY_OVERWRITES: Lazy<IntCounter> = Lazy::new(|| register(
+    IntCounter::new("conflict_policy_overwrites_total", "Conflicted fields written again with the value their conflict policy prefers").unwrap()));
+pub static MERGE_QUEUE_DEPTH: Lazy<IntGauge> = Lazy::new(|| register(
+    IntGauge::new("merge_queue_depth", "Received broadcasts waiting to be merged into the local state").unwrap()));
+pub static MERGE_QUEUE_COALESCED: Lazy<IntCounter> = Lazy::new(|| register(
+    IntCounter::new("merge_queue_coalesced_total", "FullSyncs that replaced a queued FullSync of the same member because the merge queue was full").unwrap()));
+pub static MERGE_QUEUE_DROPPED: Lazy<IntCounter> = Lazy::new(|| register(
+    IntCounter::new("merge_queue_dropped_total", "Received broadcasts dropped because the merge queue was full").unwrap()));
+pub static MERGE_CATCH_UPS: Lazy<IntCounter> = Lazy::new(|| register(
+    IntCounter::new("merge_catch_ups_total", "Times the members were asked for missing changes after merges were dropped").unwrap()));
+pub static BROADCASTS_ADDED: Lazy<IntCounter> = Lazy::new(|| register(
+    IntCounter::new("broadcasts_added_total", "Broadcasts handed to foca by this node").unwrap()));
+pub static BROADCASTS_COALESCED: Lazy<IntCounter> = Lazy::new(|| register(
+    IntCounter::new("broadcasts_coalesced_total", "Broadcast requests merged into an already pending broadcast").unwrap()));
+pub static BROADCASTS_INVALIDATED: Lazy<IntCounter> = Lazy::new(|| register(
+    IntCounter::new("broadcasts_invalidated_total", "Broadcasts replaced before they were fully transmitted").unwrap()));
+pub static BROADCAST_BACKLOG: Lazy<IntGauge> = Lazy::new(|| register(
+    IntGauge::new("broadcast_backlog", "Custom broadcasts foca still has to transmit, including relayed ones").unwrap()));
+pub static BROADCAST_OLDEST_PENDING_SECONDS: Lazy<Gauge> = Lazy::new(|| register(
+    Gauge::new("broadcast_oldest_pending_seconds", "Upper bound of the age of the oldest pending broadcast of this node").unwrap()));
+
+pub static FOCA_ERRORS: Lazy<IntCounterVec> = Lazy::new(|| register(IntCounterVec::new(
+    Opts::new("foca_errors_total", "Errors returned by foca, e.g. datagrams that could not be decoded"),
+    &["operation"]).unwrap()));
+pub static STARTUP_REPLIES_SUPPRESSED: Lazy<IntCounter> = Lazy::new(|| register(
+    IntCounter::new("startup_replies_suppressed_total", "StartupMessages not answered since the node was answered within the reply window").unwrap()));
+pub static UNKNOWN_MESSAGES_DROPPED: Lazy<IntCounter> = Lazy::new(|| register(
+    IntCounter::new("unknown_messages_dropped_total", "Received broadcasts of custom message types without a registered handler").unwrap()));
+pub static CHAOS_DATAGRAMS_DROPPED: Lazy<IntCounterVec> = Lazy::new(|| register(IntCounterVec::new(
+    Opts::new("chaos_datagrams_dropped_total", "Datagrams dropped by the fault injector of --chaos"),
+    &["direction"]).unwrap()));
+pub static QUOTA_REJECTIONS: Lazy<IntCounterVec> = Lazy::new(|| register(IntCounterVec::new(
+    Opts::new("quota_rejections_total", "Local writes rejected since they would exceed the quota of a key pattern"),
+    &["pattern"]).unwrap()));
+pub static QUOTA_EXCEEDING_MERGES: Lazy<IntCounterVec> = Lazy::new(|| register(IntCounterVec::new(
+    Opts::new("quota_exceeding_merges_total", "Merges of remote changes that took the fields of a key pattern beyond its quota"),
+    &["pattern"]).unwrap()));
+pub static IDENTITY_RENEWALS: Lazy<IntCounter> = Lazy::new(|| register(
+    IntCounter::new("identity_renewals_total", "Renewals of the identity of this node after other members declared it down").unwrap()));
+pub static TOMBSTONES_PURGED: Lazy<IntCounter> = Lazy::new(|| register(
+    IntCounter::new("tombstones_purged_total", "Tombstones of deleted fields purged after the tombstone horizon").unwrap()));
+
+pub static PEER_RTT_SECONDS: Lazy<GaugeVec> = Lazy::new(|| register(GaugeVec::new(
+    Opts::new("peer_rtt_seconds", "Round trip times of the latency probes of a member, see --probe-interval"),
+    &["peer", "stat"]).unwrap()));
+
+pub static FIELD_OVERRIDES: Lazy<IntCounter> = Lazy::new(|| register(
+    IntCounter::new("field_overrides_total", "Recent local writes that lost against a remote write during a merge").unwrap()));
+
+pub static STATE_DIVERGENCES: Lazy<IntCounter> = Lazy::new(|| register(
+    IntCounter::new("state_divergences_total", "Members found to disagree with the state of this node by their checksums").unwrap()));
+
+pub static DIVERGED_PEERS: Lazy<IntGauge> = Lazy::new(|| register(
+    IntGauge::new("diverged_peers", "Members whose last checksums differed from the one of this node, see --checksum-interval").unwrap()));
+
+pub static AUDIT_RECORDS_WRITTEN: Lazy<IntCounter> = Lazy::new(|| register(
+    IntCounter::new("audit_records_written_total", "Field changes written to the audit log").unwrap()));
+pub static AUDIT_RECORDS_DROPPED: Lazy<IntCounter> = Lazy::new(|| register(
+    IntCounter::new("audit_records_dropped_total", "Field changes left out of the audit log because its queue was full").unwrap()));
+
+pub static SCHEMA_VIOLATIONS_MERGED: Lazy<IntCounter> = Lazy::new(|| register(
+    IntCounter::new("schema_violations_merged_total", "Merged remote values violating the schema").unwrap()));
+pub static INBOUND_DROPPED: Lazy<IntCounterVec> = Lazy::new(|| register(IntCounterVec::new(
+    Opts::new("inbound_dropped_total", "Received datagrams and broadcasts dropped since their sender exceeded its budget, see inbound_limits"),
+    &["budget"]).unwrap()));
+pub static INVALID_FIELD_NAMES_MERGED: Lazy<IntCounterVec> = Lazy::new(|| register(IntCounterVec::new(
+    Opts::new("invalid_field_names_merged_total", "Merged remote fields whose names break the field name rules, see --sanitize-field-names"),
+    &["rule"]).unwrap()));
+
+// latencies from 100µs up to ~3s
+fn latency_buckets() -> Vec<f64> {
+    exponential_buckets(0.0001, 2.0, 16).expect("bucket parameters are valid")
+}
+
+fn histogram(name: &str, help: &str) -> Histogram {
+    register(Histogram::with_opts(HistogramOpts::new(name, help).buckets(latency_buckets())).unwrap())
+}
+
+pub static SET_FIELD_BROADCAST_SECONDS: Lazy<Histogram> = Lazy::new(|| histogram(
+    "set_field_broadcast_seconds", "Time from a local write until its broadcast is enqueued"));
+pub static STORE_DATA_SECONDS: Lazy<HistogramVec> = Lazy::new(|| register(HistogramVec::new(
+    HistogramOpts::new("store_data_seconds", "Time it takes to write the state file").buckets(latency_buckets()),
+    &["outcome"]).unwrap()));
+pub static STATE_BYTES_WRITTEN: Lazy<IntCounter> = Lazy::new(|| register(
+    IntCounter::new("state_bytes_written_total", "Bytes written to the snapshot and the WAL").unwrap()));
+pub static PERSISTENCE_FAILURES: Lazy<IntGauge> = Lazy::new(|| register(
+    IntGauge::new("persistence_consecutive_failures", "Writes of the state that failed since the last successful one").unwrap()));
+pub static MERGE_SECONDS: Lazy<Histogram> = Lazy::new(|| histogram(
+    "merge_seconds", "Time it takes to merge remote changes into the local state"));
+pub static MERGE_QUEUE_SECONDS: Lazy<Histogram> = Lazy::new(|| histogram(
+    "merge_queue_seconds", "Time from receiving a broadcast until it is merged, including the wait in the merge queue"));
+pub static RECEIVE_ITEM_SECONDS: Lazy<Histogram> = Lazy::new(|| histogram(
+    "receive_item_seconds", "Time it takes to decode and apply a received broadcast"));
+pub static REQUEST_PHASE_SECONDS: Lazy<HistogramVec> = Lazy::new(|| register(HistogramVec::new(
+    HistogramOpts::new("request_phase_seconds", "Time REST requests spend in each phase, see phases").buckets(latency_buckets()),
+    &["phase"]).unwrap()));
+pub static REQUEST_TIMEOUTS: Lazy<IntCounterVec> = Lazy::new(|| register(IntCounterVec::new(
+    Opts::new("request_timeouts_total", "REST requests answered with a timeout, by the phase they were in"),
+    &["phase"]).unwrap()));
+pub static UDP_SEND_SECONDS: Lazy<Histogram> = Lazy::new(|| histogram(
+    "udp_send_seconds", "Duration of sending a single datagram"));
+pub static GOSSIP_ERRORS: Lazy<IntCounterVec> = Lazy::new(|| register(IntCounterVec::new(
+    Opts::new("gossip_errors_total", "Errors receiving, decoding and applying what members sent, logged throttled, see log_throttle"),
+    &["category"]).unwrap()));
+// replication takes longer than the operations above, from 1ms up to ~33s
+pub static APPLY_LATENCY_SECONDS: Lazy<HistogramVec> = Lazy::new(|| register(HistogramVec::new(
+    HistogramOpts::new("apply_latency_seconds", "Time from a write on its origin until this node applied it, see apply_latency")
+        .buckets(exponential_buckets(0.001, 2.0, 16).expect("bucket parameters are valid")),
+    &["origin"]).unwrap()));
+
+#[cfg(feature = "metrics")]
+pub fn render() -> String {
+    // metrics are registered on first use, forcing them here so they show up before the first event
+    Lazy::force(&DOC_KEYS);
+    Lazy::force(&DOC_SIZE_BYTES);
+    Lazy::force(&DOC_INCREMENTAL_SIZE_BYTES);
+    Lazy::force(&DOC_ESTIMATED_SIZE_BYTES);
+    Lazy::force(&DOC_MAX_SIZE_BYTES);
+    Lazy::force(&DOC_MAX_SIZE_EXCEEDED);
+    Lazy::force(&DOC_STRUCTURE_ANOMALIES);
+    Lazy::force(&DOC_STRUCTURE_REPAIRS);
+    Lazy::force(&DOC_CHANGES);
+    Lazy::force(&DOC_ACTORS);
+    Lazy::force(&DOC_HISTORY_RATIO);
+    Lazy::force(&DOC_COMPACTED_SIZE_BYTES);
+    Lazy::force(&COMPACTION_RECOMMENDED);
+    Lazy::force(&MERGE_CONFLICTS);
+    Lazy::force(&CONFLICT_POLICY_OVERWRITES);
+    Lazy::force(&MERGE_QUEUE_DEPTH);
+    Lazy::force(&MERGE_QUEUE_COALESCED);
+    Lazy::force(&MERGE_QUEUE_DROPPED);
+    Lazy::force(&MERGE_CATCH_UPS);
+    Lazy::force(&BROADCASTS_ADDED);
+    Lazy::force(&BROADCASTS_COALESCED);
+    Lazy::force(&BROADCASTS_INVALIDATED);
+    Lazy::force(&BROADCAST_BACKLOG);
+    Lazy::force(&BROADCAST_OLDEST_PENDING_SECONDS);
+    Lazy::force(&FOCA_ERRORS);
+    Lazy::force(&CHAOS_DATAGRAMS_DROPPED);
+    Lazy::force(&QUOTA_REJECTIONS);
+    Lazy::force(&QUOTA_EXCEEDING_MERGES);
+    Lazy::force(&STARTUP_REPLIES_SUPPRESSED);
+    Lazy::force(&UNKNOWN_MESSAGES_DROPPED);
+    Lazy::force(&IDENTITY_RENEWALS);
+    Lazy::force(&TOMBSTONES_PURGED);
+    Lazy::force(&PEER_RTT_SECONDS);
+    Lazy::force(&FIELD_OVERRIDES);
+    Lazy::force(&STATE_DIVERGENCES);
+    Lazy::force(&DIVERGED_PEERS);
+    Lazy::force(&SCHEMA_VIOLATIONS_MERGED);
+    Lazy::force(&INVALID_FIELD_NAMES_MERGED);
+    Lazy::force(&INBOUND_DROPPED);
+    Lazy::force(&AUDIT_RECORDS_WRITTEN);
+    Lazy::force(&AUDIT_RECORDS_DROPPED);
+    Lazy::force(&SET_FIELD_BROADCAST_SECONDS);
+    Lazy::force(&STORE_DATA_SECONDS);
+    Lazy::force(&STATE_BYTES_WRITTEN);
+    Lazy::force(&PERSISTENCE_FAILURES);
+    Lazy::force(&MERGE_SECONDS);
+    Lazy::force(&MERGE_QUEUE_SECONDS);
+    Lazy::force(&RECEIVE_ITEM_SECONDS);
+    Lazy::force(&REQUEST_PHASE_SECONDS);
+    Lazy::force(&REQUEST_TIMEOUTS);
+    Lazy::force(&UDP_SEND_SECONDS);
+    Lazy::force(&APPLY_LATENCY_SECONDS);
+    Lazy::force(&GOSSIP_ERRORS);
+    let mut buffer = Vec::new();
+    TextEncoder::new().encode(&REGISTRY.gather(), &mut buffer)
+        .expect("text encoding of metrics does not fail");
+    String::from_utf8(buffer).expect("prometheus text format is UTF-8")
+}
+
+#[cfg(not(feature = "metrics"))]
+pub fn render() -> String {
+    "# built without the metrics feature\n".to_owned()
+}
+
+// Stand-ins for the prometheus types used above, they take the same calls and record nothing
+#[cfg(not(feature = "metrics"))]
+mod noop {
+    use std::convert::Infallible;
+
+    type Result<T> = std::result::Result<T, Infallible>;
+
+    pub struct Opts;
+
+    impl Opts {
+        pub fn new(_name: &str, _help: &str) -> Self {
+            Opts
+        }
+    }
+
+    pub struct HistogramOpts;
+
+    impl HistogramOpts {
+        pub fn new(_name: &str, _help: &str) -> Self {
+            HistogramOpts
+        }
+
+        pub fn buckets(self, _buckets: Vec<f64>) -> Self {
+            self
+        }
+    }
+
+    pub fn exponential_buckets(_start: f64, _factor: f64, _count: usize) -> Result<Vec<f64>> {
+        Ok(Vec::new())
+    }
+
+    #[derive(Clone)]
+    pub struct IntCounter;
+
+    impl IntCounter {
+        pub fn new(_name: &str, _help: &str) -> Result<Self> {
+            Ok(IntCounter)
+        }
+
+        pub fn inc(&self) {}
+
+        pub fn inc_by(&self, _v: u64) {}
+    }
+
+    #[derive(Clone)]
+    pub struct IntGauge;
+
+    impl IntGauge {
+        pub fn new(_name: &str, _help: &str) -> Result<Self> {
+            Ok(IntGauge)
+        }
+
+        pub fn set(&self, _v: i64) {}
+
+        pub fn inc(&self) {}
+    }
+
+    #[derive(Clone)]
+    pub struct Gauge;
+
+    impl Gauge {
+        pub fn new(_name: &str, _help: &str) -> Result<Self> {
+            Ok(Gauge)
+        }
+
+        pub fn set(&self, _v: f64) {}
+    }
+
+    #[derive(Clone)]
+    pub struct Histogram;
+
+    impl Histogram {
+        pub fn with_opts(_opts: HistogramOpts) -> Result<Self> {
+            Ok(Histogram)
+        }
+
+        pub fn observe(&self, _v: f64) {}
+
+        pub fn start_timer(&self) -> HistogramTimer {
+            HistogramTimer
+        }
+    }
+
+    pub struct HistogramTimer;
+
+    impl HistogramTimer {
+        pub fn observe_duration(self) {}
+    }
+
+    // the labelled metrics hand out children of the unlabelled type
+    #[derive(Clone)]
+    pub struct MetricVec<T> {
+        child: T,
+    }
+
+    pub type IntCounterVec = MetricVec<IntCounter>;
+    pub type GaugeVec = MetricVec<Gauge>;
+    pub type HistogramVec = MetricVec<Histogram>;
+
+    impl MetricVec<IntCounter> {
+        pub fn new(_opts: Opts, _labels: &[&str]) -> Result<Self> {
+            Ok(MetricVec { child: IntCounter })
+        }
+    }
+
+    impl MetricVec<Gauge> {
+        pub fn new(_opts: Opts, _labels: &[&str]) -> Result<Self> {
+            Ok(MetricVec { child: Gauge })
+        }
+    }
+
+    impl MetricVec<Histogram> {
+        pub fn new(_opts: HistogramOpts, _labels: &[&str]) -> Result<Self> {
+            Ok(MetricVec { child: Histogram })
+        }
+    }
+
+    impl<T: Clone> MetricVec<T> {
+        pub fn with_label_values(&self, _values: &[&str]) -> T {
+            self.child.clone()
+        }
+
+        pub fn remove_label_values(&self, _values: &[&str]) -> Result<()> {
+            Ok(())
+        }
+    }
+}
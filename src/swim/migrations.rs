@@ -0,0 +1,231 @@
+use std::{
+    fmt::{Display, Formatter}, fs, path::{Path, PathBuf},
+};
+use anyhow::{anyhow, Context, Result};
+use log::info;
+
+use super::{
+    actors::ACTORS_KEY, backup::backup_dir, encryption::KeyError, known_peers::KNOWN_PEERS_KEY, local_fields::LOCAL_FIELDS_KEY, node_id::NODE_ID_KEY,
+    persistence::{load_persisted_state, STATE_KEY, WAL_KEY}, seen_ops::SEEN_OPS_KEY, store::{FileStore, write_atomically},
+};
+
+// The layout of a data dir is versioned by the integer in <data_dir>/FORMAT. At startup the registered
+// migrations are run one after the other until the dir has the format of this build, the FORMAT file is
+// rewritten after every step so that a migration interrupted by a crash continues where it stopped.
+// A dir written by a newer build is never touched, the node refuses to start instead. Migrations that
+// rewrite or remove files are marked destructive, the files of the dir are copied into
+// backups/format-<from>-<timestamp> before they run.
+//
+// Formats
+//
+// 0. the layout before FORMAT existed, the document in automerge.dat with or without a WAL
+// 1. the same files, versioned by FORMAT
+//
+pub const FORMAT_KEY: &str = "FORMAT";
+pub const CURRENT_FORMAT: u32 = 1;
+
+// files that make a dir without FORMAT a data dir of format 0 rather than a new one
+const LEGACY_KEYS: [&str; 7] = [STATE_KEY, WAL_KEY, SEEN_OPS_KEY, NODE_ID_KEY, ACTORS_KEY, LOCAL_FIELDS_KEY, KNOWN_PEERS_KEY];
+
+pub trait Migration: Send + Sync {
+    fn from(&self) -> u32;
+    fn to(&self) -> u32;
+    // for logs, e.g. "adopt data dirs written before FORMAT"
+    fn describe(&self) -> &str;
+    // destructive migrations rewrite or remove files, the dir is backed up before they run
+    fn destructive(&self) -> bool {
+        false
+    }
+    fn run(&self, dir: &Path) -> Result<()>;
+}
+
+// Raised inside anyhow errors, callers can downcast to tell a dir of a newer build from other failures.
+#[derive(Debug)]
+pub struct FutureFormatError {
+    pub dir: PathBuf,
+    pub found: u32,
+    pub supported: u32,
+}
+
+impl Display for FutureFormatError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "data dir {} has format {} but this build only supports formats up to {}, it was written by a newer \
+            version, refusing to start; run the newer version or restore a backup", self.dir.display(), self.found, self.supported)
+    }
+}
+
+impl std::error::Error for FutureFormatError {}
+
+/// What run did to a data dir.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MigrationReport {
+    pub from: u32,
+    pub to: u32,
+    // descriptions of the migrations that ran, in order
+    pub applied: Vec<String>,
+    // copies of the dir taken before destructive migrations
+    pub backups: Vec<PathBuf>,
+}
+
+pub struct Migrations {
+    migrations: Vec<Box<dyn Migration>>,
+    target: u32,
+}
+
+impl Migrations {
+    // a registry without migrations migrating to target
+    pub fn new(target: u32) -> Self {
+        Migrations {
+            migrations: Vec::new(),
+            target,
+        }
+    }
+
+    // the migrations of this build
+    pub fn builtin() -> Self {
+        let mut migrations = Self::new(CURRENT_FORMAT);
+        migrations.register(AdoptLegacyLayout);
+        migrations
+    }
+
+    pub fn register(&mut self, migration: impl Migration + 'static) -> &mut Self {
+        self.migrations.push(Box::new(migration));
+        self
+    }
+
+    pub fn target(&self) -> u32 {
+        self.target
+    }
+
+    // Migrations that would run for a dir at version, fails if the chain has a gap or version is newer than target.
+    pub fn plan(&self, dir: &Path, version: u32) -> Result<Vec<&dyn Migration>> {
+        if version > self.target {
+            return Err(FutureFormatError { dir: dir.to_owned(), found: version, supported: self.target }.into());
+        }
+        let mut plan = Vec::new();
+        let mut current = version;
+        while current < self.target {
+            let migration = self.migrations.iter()
+                .filter(|m| m.from() == current && m.to() > current && m.to() <= self.target)
+                .max_by_key(|m| m.to())
+                .ok_or_else(|| anyhow!("no migration of data dir {} from format {} towards format {}", dir.display(), current, self.target))?;
+            plan.push(migration.as_ref());
+            current = migration.to();
+        }
+        Ok(plan)
+    }
+
+    // Brings dir to the target format. A dir that doesn't exist or holds none of the files of a node yet
+    // is stamped with the target format without running anything.
+    pub fn run(&self, dir: &Path) -> Result<MigrationReport> {
+        let version = match detect_format(dir)? {
+            Some(version) => version,
+            None => {
+                write_format(dir, self.target)?;
+                return Ok(MigrationReport { from: self.target, to: self.target, ..Default::default() });
+            },
+        };
+        let plan = self.plan(dir, version)?;
+        let mut report = MigrationReport { from: version, to: version, ..Default::default() };
+        for migration in plan {
+            info!("Migrating data dir {} from format {} to {}: {}", dir.display(), migration.from(), migration.to(), migration.describe());
+            if migration.destructive() {
+                let backup = copy_data_dir(dir, migration.from())?;
+                info!("Backed up data dir {} to {}", dir.display(), backup.display());
+                report.backups.push(backup);
+            }
+            migration.run(dir)
+                .with_context(|| format!("could not migrate data dir {} from format {} to {}", dir.display(), migration.from(), migration.to()))?;
+            write_format(dir, migration.to())?;
+            report.to = migration.to();
+            report.applied.push(migration.describe().to_owned());
+        }
+        Ok(report)
+    }
+}
+
+// Runs the migrations of this build over dir, see Migrations::run.
+pub fn migrate_data_dir(dir: &Path) -> Result<MigrationReport> {
+    let report = Migrations::builtin().run(dir)?;
+    if !report.applied.is_empty() {
+        info!("Migrated data dir {} from format {} to {}", dir.display(), report.from, report.to);
+    }
+    Ok(report)
+}
+
+// None if dir has no FORMAT file
+pub fn read_format(dir: &Path) -> Result<Option<u32>> {
+    let path = dir.join(FORMAT_KEY);
+    let content = match fs::read_to_string(&path) {
+        Ok(content) => content,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e).with_context(|| format!("could not read {}", path.display())),
+    };
+    content.trim().parse::<u32>()
+        .map(Some)
+        .with_context(|| format!("{} does not hold a format version: {:?}", path.display(), content.trim()))
+}
+
+// The format of dir, 0 for a dir without FORMAT holding files of a node, None for a new dir
+pub fn detect_format(dir: &Path) -> Result<Option<u32>> {
+    match read_format(dir)? {
+        None if is_legacy_data_dir(dir) => Ok(Some(0)),
+        format => Ok(format),
+    }
+}
+
+pub fn write_format(dir: &Path, version: u32) -> Result<()> {
+    fs::create_dir_all(dir)
+        .with_context(|| format!("could not create data dir {}", dir.display()))?;
+    let path = dir.join(FORMAT_KEY);
+    write_atomically(&path, format!("{}\n", version).as_bytes())
+        .with_context(|| format!("could not write {}", path.display()))
+}
+
+fn is_legacy_data_dir(dir: &Path) -> bool {
+    LEGACY_KEYS.iter().any(|key| dir.join(key).exists())
+}
+
+// Copies the files directly in dir, so not the backups, into backups/format-<version>-<timestamp>.
+fn copy_data_dir(dir: &Path, version: u32) -> Result<PathBuf> {
+    let target = backup_dir(dir).join(format!("format-{}-{}", version, chrono::Utc::now().format("%Y%m%dT%H%M%S%.3fZ")));
+    fs::create_dir_all(&target)
+        .with_context(|| format!("could not create backup dir {}", target.display()))?;
+    let entries = fs::read_dir(dir)
+        .with_context(|| format!("could not list data dir {}", dir.display()))?;
+    for entry in entries {
+        let entry = entry?;
+        if entry.file_type()?.is_file() {
+            fs::copy(entry.path(), target.join(entry.file_name()))
+                .with_context(|| format!("could not back up {}", entry.path().display()))?;
+        }
+    }
+    Ok(target)
+}
+
+// Data dirs from before FORMAT already have the current layout, a bare automerge.dat is read as the
+// snapshot with an empty WAL. The document is only loaded, so that a dir that can't be read fails
+// the migration instead of being stamped with the current format.
+struct AdoptLegacyLayout;
+
+impl Migration for AdoptLegacyLayout {
+    fn from(&self) -> u32 {
+        0
+    }
+
+    fn to(&self) -> u32 {
+        1
+    }
+
+    fn describe(&self) -> &str {
+        "adopt data dirs written before FORMAT"
+    }
+
+    // encrypted state can't be checked without the key, it is checked when the node loads it
+    fn run(&self, dir: &Path) -> Result<()> {
+        match load_persisted_state(&FileStore::new(dir), None) {
+            Err(e) if e.downcast_ref::<KeyError>().is_none() => Err(e),
+            _ => Ok(()),
+        }
+    }
+}
@@ -1,6 +1,85 @@
+pub mod actors;
+pub mod apply_latency;
+pub mod audit;
+pub mod auth;
+pub mod backup;
+pub mod batch;
 pub mod broadcast;
+pub mod build_info;
+pub mod bump_history;
+pub mod capture;
+pub mod change_index;
+pub mod chaos;
+pub mod clock;
+#[cfg(feature = "client")]
+pub mod client;
+pub mod coalesce;
+pub mod codec;
+#[cfg(feature = "cli")]
+pub mod config_file;
+pub mod conflict_policy;
+pub mod consistency;
+pub mod containers;
+pub mod convert;
 pub mod core;
+pub mod departures;
+pub mod diff;
+pub mod direct_sync;
+pub mod duration;
+pub mod encryption;
+pub mod envelope;
+pub mod error;
+pub mod events;
+pub mod field_names;
+pub mod handle;
+pub mod history;
+pub mod hops;
+pub mod inbound_limits;
+pub mod journal;
+pub mod known_peers;
+pub mod leases;
+pub mod listing;
+pub mod local_fields;
+pub mod manifest;
+pub mod join_state;
+pub mod ledger;
+pub mod logging;
+pub mod log_throttle;
 pub mod members;
+pub mod merge_queue;
+pub mod migrations;
+pub mod pattern;
+pub mod metrics;
+pub mod node_id;
+pub mod persistence;
+pub mod phases;
+pub mod probes;
+pub mod quotas;
+pub mod profile;
+pub mod renewals;
 pub mod types;
+pub mod validator;
+pub mod runtime_settings;
+pub mod seed;
+pub mod seen_ops;
+#[cfg(feature = "rest")]
 pub mod server;
+pub mod settings;
+pub mod shutdown;
+pub mod size;
+pub mod store;
+pub mod structure;
+#[cfg(feature = "rest")]
+pub mod sse;
+pub mod startup;
+pub mod startup_replies;
+pub mod strict_identity;
+pub mod sync_states;
+pub mod telemetry;
+pub mod text;
+pub mod tombstones;
+pub mod touched_keys;
+#[cfg(feature = "ui")]
+pub mod ui;
+pub mod write_quorum;
 pub mod foca;
\ No newline at end of file
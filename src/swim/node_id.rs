@@ -0,0 +1,24 @@
+use anyhow::Result;
+use log::{info, warn};
+use uuid::Uuid;
+
+use super::store::StateStore;
+
+// Stable id of the node across restarts, stored as text in <DATA_DIR>/node_id. Unlike the identity
+// it does not change with the bind address, so members can tell a restarting node from a new one.
+
+pub const NODE_ID_KEY: &str = "node_id";
+
+// Reads the node id of the store, a missing or unreadable id is replaced by a new one
+pub fn load_or_create_node_id(store: &dyn StateStore) -> Result<Uuid> {
+    if let Some(bytes) = store.load(NODE_ID_KEY)? {
+        match Uuid::parse_str(String::from_utf8_lossy(&bytes).trim()) {
+            Ok(node_id) => return Ok(node_id),
+            Err(e) => warn!("Replacing invalid node id in {}: {}", store.describe(NODE_ID_KEY), e),
+        }
+    }
+    let node_id = Uuid::new_v4();
+    store.save(NODE_ID_KEY, node_id.to_string().as_bytes())?;
+    info!("Generated node id {}", node_id);
+    Ok(node_id)
+}
@@ -0,0 +1,25 @@
+// Glob style key patterns as used in configuration files and flags.
+// `*` matches any (possibly empty) sequence of characters, everything else matches literally.
+pub fn glob_matches(pattern: &str, key: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let key: Vec<char> = key.chars().collect();
+    // iterative matching with backtracking to the last `*`
+    let (mut p, mut k) = (0, 0);
+    let mut last_star: Option<(usize, usize)> = None;
+    while k < key.len() {
+        if p < pattern.len() && pattern[p] == '*' {
+            last_star = Some((p, k));
+            p += 1;
+        } else if p < pattern.len() && pattern[p] == key[k] {
+            p += 1;
+            k += 1;
+        } else if let Some((star_p, star_k)) = last_star {
+            p = star_p + 1;
+            k = star_k + 1;
+            last_star = Some((star_p, star_k + 1));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|c| *c == '*')
+}
@@ -0,0 +1,534 @@
+use std::{
+    fmt::{Display, Formatter}, sync::{Arc, Mutex, atomic::{AtomicBool, Ordering}}, time::{Duration, Instant},
+};
+use anyhow::{Context, Result};
+use automerge::AutoCommit;
+use bytes::Bytes;
+use log::{info, error, warn};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use tokio::sync::watch;
+
+use super::{core::HolyDiverDataHandler, encryption::{self, DataKey}, metrics, store::{SharedStateStore, StateStore}};
+
+// A store holds a full save of the document in automerge.dat and the changes made since then in
+// wal.dat. Every mutation appends its changes to the WAL, which is cheap compared to saving the
+// whole document. Once the WAL exceeds its configured size a new snapshot is written and the WAL
+// is truncated. With a data encryption key the snapshot and the payload of every WAL record are
+// encrypted, see encryption.
+//
+// Layout of wal.dat, a sequence of records of
+//
+// 0. payload length (u32 LE)
+// 1. CRC32 of the payload (u32 LE)
+// 2. payload, the raw bytes of the changes of one mutation
+//
+// automerge.dat.sha256 holds the SHA-256 of the snapshot as written, i.e. of the ciphertext with a key, in
+// the format of sha256sum. A snapshot not matching it was changed outside of holy-diver and is not loaded.
+// While a snapshot is replaced the file lists the checksums of both, so that a crash in between leaves a
+// snapshot matching one of them.
+//
+pub const STATE_KEY: &str = "automerge.dat";
+pub const WAL_KEY: &str = "wal.dat";
+pub const CHECKSUM_KEY: &str = "automerge.dat.sha256";
+// a snapshot not matching its checksum is kept here instead of being loaded
+pub const REJECTED_STATE_KEY: &str = "automerge.dat.rejected";
+const RECORD_HEADER_SIZE: usize = 8;
+
+// /healthz reports the node as unhealthy after this many writes failed in a row
+pub const DEFAULT_UNHEALTHY_AFTER_FAILURES: u32 = 3;
+
+// how often a failed write is retried while the node is idle
+const RETRY_INTERVAL: Duration = Duration::from_secs(5);
+
+pub fn encode_wal_record(payload: &[u8]) -> Vec<u8> {
+    let mut record = Vec::with_capacity(RECORD_HEADER_SIZE + payload.len());
+    record.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    record.extend_from_slice(&crc32fast::hash(payload).to_le_bytes());
+    record.extend_from_slice(payload);
+    record
+}
+
+/// Records of a WAL up to the first incomplete or corrupt one.
+pub struct WalContents {
+    pub records: Vec<Vec<u8>>,
+    pub size: usize,
+    // a record at the end was only partially written, e.g. because of a crash while appending
+    pub torn: bool,
+}
+
+pub fn decode_wal(bytes: &[u8]) -> WalContents {
+    let mut records = Vec::new();
+    let mut offset = 0;
+    while offset < bytes.len() {
+        let header = match bytes.get(offset..offset + RECORD_HEADER_SIZE) {
+            Some(header) => header,
+            None => break,
+        };
+        let len = u32::from_le_bytes(header[..4].try_into().unwrap()) as usize;
+        let crc = u32::from_le_bytes(header[4..].try_into().unwrap());
+        let payload = match bytes.get(offset + RECORD_HEADER_SIZE..offset + RECORD_HEADER_SIZE + len) {
+            Some(payload) if crc32fast::hash(payload) == crc => payload,
+            _ => break,
+        };
+        records.push(payload.to_vec());
+        offset += RECORD_HEADER_SIZE + len;
+    }
+    WalContents {
+        records,
+        size: bytes.len(),
+        torn: offset < bytes.len(),
+    }
+}
+
+/// Outcome of checking the snapshot against automerge.dat.sha256 on startup.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[cfg_attr(feature = "rest", derive(utoipa::ToSchema))]
+#[serde(rename_all = "snake_case")]
+pub enum SnapshotVerification {
+    Passed,
+    // written by a version without checksums, the next snapshot writes one
+    MissingChecksum,
+    // the snapshot was not loaded, see REJECTED_STATE_KEY
+    Mismatch,
+}
+
+// Raised inside anyhow errors by load_persisted_state for a snapshot not matching its checksum
+#[derive(Debug)]
+pub struct ChecksumMismatch(pub String);
+
+impl Display for ChecksumMismatch {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} does not match its checksum in {}, it was changed outside of holy-diver or is corrupted", self.0, CHECKSUM_KEY)
+    }
+}
+
+impl std::error::Error for ChecksumMismatch {}
+
+fn checksum_line(data: &[u8]) -> String {
+    let checksum: String = Sha256::digest(data).iter().map(|b| format!("{:02x}", b)).collect();
+    format!("{}  {}\n", checksum, STATE_KEY)
+}
+
+fn verify_snapshot(store: &dyn StateStore, data: &[u8]) -> Result<SnapshotVerification> {
+    let Some(checksums) = store.load(CHECKSUM_KEY)? else {
+        warn!("{} has no checksum yet, it is written with the next snapshot", store.describe(STATE_KEY));
+        return Ok(SnapshotVerification::MissingChecksum);
+    };
+    let expected = checksum_line(data);
+    let matches = String::from_utf8_lossy(&checksums).lines().any(|line| expected.trim_end() == line.trim_end());
+    Ok(if matches { SnapshotVerification::Passed } else { SnapshotVerification::Mismatch })
+}
+
+// Replaces the snapshot and its checksum, see CHECKSUM_KEY
+fn save_snapshot(store: &dyn StateStore, snapshot: &[u8]) -> Result<()> {
+    let current = match store.load(CHECKSUM_KEY)? {
+        Some(checksums) => String::from_utf8_lossy(&checksums).lines().next().map(|line| format!("{}\n", line)),
+        // snapshots written before there were checksums
+        None => store.load(STATE_KEY)?.map(|data| checksum_line(&data)),
+    };
+    let new = checksum_line(snapshot);
+    store.save(CHECKSUM_KEY, format!("{}{}", new, current.unwrap_or_default()).as_bytes())?;
+    store.save(STATE_KEY, snapshot)?;
+    store.save(CHECKSUM_KEY, new.as_bytes())
+}
+
+/// What was found in a store by load_persisted_state.
+#[derive(Debug, Default)]
+pub struct PersistedInfo {
+    pub snapshot_size: usize,
+    pub wal_size: usize,
+    pub wal_records: usize,
+    pub torn: bool,
+    // the snapshot or WAL records were not encrypted
+    pub unencrypted: bool,
+    // None without a snapshot
+    pub verification: Option<SnapshotVerification>,
+    // the document was repaired after loading, see read_state_from_disk
+    pub repaired: bool,
+}
+
+// whether a document was persisted in the store
+pub fn has_persisted_state(store: &dyn StateStore) -> bool {
+    store.contains(STATE_KEY) || store.contains(WAL_KEY)
+}
+
+// Loads the snapshot and applies the WAL on top of it, None if neither exists.
+// A WAL without a snapshot is applied to an empty document. Encrypted data needs key, plain data
+// is read with or without one. Fails with a ChecksumMismatch if the snapshot doesn't match its checksum.
+pub fn load_persisted_state(store: &dyn StateStore, key: Option<&DataKey>) -> Result<Option<(AutoCommit, PersistedInfo)>> {
+    let snapshot = store.load(STATE_KEY)?;
+    let wal = store.load(WAL_KEY)?;
+    let mut info = PersistedInfo::default();
+    let mut doc = match (snapshot, &wal) {
+        (Some(data), _) => {
+            info.snapshot_size = data.len();
+            info.verification = Some(verify_snapshot(store, &data)?);
+            if info.verification == Some(SnapshotVerification::Mismatch) {
+                return Err(ChecksumMismatch(store.describe(STATE_KEY)).into());
+            }
+            info.unencrypted = !encryption::is_sealed(&data);
+            let data = encryption::unseal(&data, key, &store.describe(STATE_KEY))?;
+            AutoCommit::load(&data)
+                .with_context(|| format!("{} does not contain a valid document", store.describe(STATE_KEY)))?
+        },
+        (None, Some(_)) => AutoCommit::new(),
+        (None, None) => return Ok(None),
+    };
+    if let Some(wal) = wal {
+        let wal = decode_wal(&wal);
+        info.wal_size = wal.size;
+        info.torn = wal.torn;
+        for record in &wal.records {
+            info.unencrypted |= !encryption::is_sealed(record);
+            let record = encryption::unseal(record, key, &store.describe(WAL_KEY))?;
+            // changes already contained in the snapshot are ignored by automerge
+            if let Err(e) = doc.load_incremental(&record) {
+                warn!("Ignoring the rest of {} after record {}: {}", store.describe(WAL_KEY), info.wal_records, e);
+                info.torn = true;
+                break;
+            }
+            info.wal_records += 1;
+        }
+        if info.torn {
+            warn!("{} ends with an incomplete record, it is dropped", store.describe(WAL_KEY));
+        }
+    }
+    Ok(Some((doc, info)))
+}
+
+/// Outcome of the recent writes of the state, a successful write resets the failures.
+#[derive(Debug, Clone, Default, Serialize)]
+#[cfg_attr(feature = "rest", derive(utoipa::ToSchema))]
+pub struct PersistenceStatus {
+    // writes that failed since the last successful one
+    pub consecutive_failures: u32,
+    // kept after recovering to tell what went wrong
+    pub last_error: Option<String>,
+    // unix millis
+    pub last_failure: Option<i64>,
+    // how the snapshot loaded on startup compared to its checksum, None without a snapshot
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub snapshot_verification: Option<SnapshotVerification>,
+}
+
+impl PersistenceStatus {
+    fn record_failure(&mut self, error: String) {
+        self.consecutive_failures += 1;
+        self.last_error = Some(error);
+        self.last_failure = Some(chrono::Utc::now().timestamp_millis());
+        metrics::PERSISTENCE_FAILURES.set(self.consecutive_failures as i64);
+    }
+}
+
+#[derive(Default)]
+struct PendingWrites {
+    // replaces the snapshot and truncates the WAL, appends requested before it are obsolete
+    snapshot: Option<Bytes>,
+    // WAL records to append after the snapshot, if any, in order
+    appends: Vec<Vec<u8>>,
+}
+
+/// Writes state to disk on a dedicated task so that mutations never wait for the disk.
+/// Only one write is in flight at a time. Snapshots requested in the meantime replace older ones
+/// and WAL records are appended in the order they were requested.
+pub struct StateWriter {
+    pending: Arc<Mutex<PendingWrites>>,
+    // set when a write failed, the WAL may be missing records until the next snapshot
+    failed: Arc<AtomicBool>,
+    status: Arc<Mutex<PersistenceStatus>>,
+    requested: watch::Sender<u64>,
+    written: watch::Receiver<u64>,
+    generation: u64,
+    // WAL records are encrypted when they are appended, snapshots by the writer task
+    key: Option<Arc<DataKey>>,
+}
+
+/// Resolves once the state that was current when the handle was created has been written.
+pub struct FlushHandle {
+    written: watch::Receiver<u64>,
+    generation: u64,
+}
+
+impl FlushHandle {
+    pub async fn wait(mut self) {
+        while *self.written.borrow_and_update() < self.generation {
+            if self.written.changed().await.is_err() {
+                // writer task is gone, nothing will be written anymore
+                return;
+            }
+        }
+    }
+}
+
+fn write_pending(store: &dyn StateStore, writes: PendingWrites, key: Option<&DataKey>) -> Result<usize> {
+    let mut written = 0;
+    if let Some(snapshot) = writes.snapshot {
+        let snapshot = encryption::seal(&snapshot, key);
+        save_snapshot(store, &snapshot)?;
+        // a crash before the truncation leaves records that are already part of the snapshot,
+        // applying them again on startup does no harm
+        store.save(WAL_KEY, &[])?;
+        written += snapshot.len();
+    }
+    if !writes.appends.is_empty() {
+        let records = writes.appends.concat();
+        store.append(WAL_KEY, &records)?;
+        written += records.len();
+    }
+    Ok(written)
+}
+
+impl StateWriter {
+    // needs to be called from within a tokio runtime since the writer task is spawned here
+    pub fn spawn(store: SharedStateStore, key: Option<Arc<DataKey>>) -> Self {
+        let pending = Arc::new(Mutex::new(PendingWrites::default()));
+        let (requested, mut requested_receiver) = watch::channel(0u64);
+        let (written_sender, written) = watch::channel(0u64);
+        let failed = Arc::new(AtomicBool::new(false));
+        let task_pending = pending.clone();
+        let task_failed = failed.clone();
+        let status = Arc::new(Mutex::new(PersistenceStatus::default()));
+        let task_status = status.clone();
+        let task_key = key.clone();
+        tokio::spawn(async move {
+            while requested_receiver.changed().await.is_ok() {
+                let generation = *requested_receiver.borrow_and_update();
+                let writes = std::mem::take(&mut *task_pending.lock().unwrap());
+                if writes.snapshot.is_none() && writes.appends.is_empty() {
+                    // already written along with an earlier generation
+                    let _ignored_send_error = written_sender.send(generation);
+                    continue;
+                }
+                let target = store.clone();
+                let location = store.describe(STATE_KEY);
+                let started = Instant::now();
+                let key = task_key.clone();
+                let outcome = match tokio::task::spawn_blocking(move || write_pending(&*target, writes, key.as_deref())).await {
+                    Ok(Ok(bytes)) => {
+                        info!("Wrote {} bytes of state to {}", bytes, location);
+                        metrics::STATE_BYTES_WRITTEN.inc_by(bytes as u64);
+                        let mut status = task_status.lock().unwrap();
+                        if status.consecutive_failures > 0 {
+                            info!("Writing state to {} works again after {} failed writes", location, status.consecutive_failures);
+                            status.consecutive_failures = 0;
+                            metrics::PERSISTENCE_FAILURES.set(0);
+                        }
+                        "ok"
+                    },
+                    Ok(Err(e)) => {
+                        error!("Could not write state to {}: {:#}", location, e);
+                        task_failed.store(true, Ordering::SeqCst);
+                        task_status.lock().unwrap().record_failure(format!("{:#}", e));
+                        "error"
+                    },
+                    Err(e) => {
+                        error!("Writing state to {} did not finish: {}", location, e);
+                        task_failed.store(true, Ordering::SeqCst);
+                        task_status.lock().unwrap().record_failure(e.to_string());
+                        "error"
+                    },
+                };
+                metrics::STORE_DATA_SECONDS.with_label_values(&[outcome]).observe(started.elapsed().as_secs_f64());
+                let _ignored_send_error = written_sender.send(generation);
+            }
+        });
+        StateWriter {
+            pending,
+            failed,
+            status,
+            requested,
+            written,
+            generation: 0,
+            key,
+        }
+    }
+
+    pub fn write_snapshot(&mut self, data: Bytes) {
+        {
+            let mut pending = self.pending.lock().unwrap();
+            pending.snapshot = Some(data);
+            pending.appends.clear();
+        }
+        self.failed.store(false, Ordering::SeqCst);
+        self.request();
+    }
+
+    // true if a write failed since the last snapshot, the next write should then be a snapshot
+    pub fn needs_snapshot(&self) -> bool {
+        self.failed.load(Ordering::SeqCst)
+    }
+
+    pub fn status(&self) -> PersistenceStatus {
+        self.status.lock().unwrap().clone()
+    }
+
+    // returns the size of the appended record
+    pub fn append(&mut self, payload: &[u8]) -> usize {
+        let record = encode_wal_record(&encryption::seal(payload, self.key.as_deref()));
+        let size = record.len();
+        self.pending.lock().unwrap().appends.push(record);
+        self.request();
+        size
+    }
+
+    fn request(&mut self) {
+        self.generation += 1;
+        let _ignored_send_error = self.requested.send(self.generation);
+    }
+
+    pub fn flush_handle(&self) -> FlushHandle {
+        FlushHandle {
+            written: self.written.clone(),
+            generation: self.generation,
+        }
+    }
+}
+
+// Retries failed writes without waiting for the next mutation, which may never come if writes are
+// rejected because of them, see HolyDiverDataHandler::set_strict_durability.
+// Needs to be called from within a tokio runtime.
+pub fn spawn_persistence_retry(data_handler: Arc<Mutex<HolyDiverDataHandler>>) {
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(RETRY_INTERVAL).await;
+            data_handler.lock().unwrap().retry_persist();
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use automerge::{transaction::Transactable, ReadDoc};
+    use super::*;
+    use crate::swim::store::FileStore;
+
+    const WRITE_DELAY: Duration = Duration::from_millis(500);
+    const TICK: Duration = Duration::from_millis(10);
+
+    // A FileStore taking WRITE_DELAY for every write, like a saturated disk
+    struct SlowStore(FileStore);
+
+    impl StateStore for SlowStore {
+        fn load(&self, key: &str) -> Result<Option<Vec<u8>>> {
+            self.0.load(key)
+        }
+
+        fn save(&self, key: &str, bytes: &[u8]) -> Result<()> {
+            std::thread::sleep(WRITE_DELAY);
+            self.0.save(key, bytes)
+        }
+
+        fn append(&self, key: &str, bytes: &[u8]) -> Result<()> {
+            std::thread::sleep(WRITE_DELAY);
+            self.0.append(key, bytes)
+        }
+
+        fn contains(&self, key: &str) -> bool {
+            self.0.contains(key)
+        }
+
+        fn describe(&self, key: &str) -> String {
+            self.0.describe(key)
+        }
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn timers_fire_on_time_while_a_slow_write_is_in_flight() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut writer = StateWriter::spawn(Arc::new(SlowStore(FileStore::new(dir.path()))), None);
+        let mut doc = AutoCommit::new();
+        doc.put(automerge::ROOT, "key", "value").unwrap();
+        writer.write_snapshot(Bytes::from(doc.save()));
+        let flushed = writer.flush_handle();
+
+        // stands in for the probe and gossip timers of foca, which share the executor with the writer
+        let mut timer = tokio::time::interval(TICK);
+        timer.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        timer.tick().await;
+        let started = Instant::now();
+        let mut latest = Duration::ZERO;
+        while started.elapsed() < WRITE_DELAY {
+            let expected = Instant::now() + TICK;
+            timer.tick().await;
+            latest = latest.max(Instant::now().saturating_duration_since(expected));
+        }
+        assert!(latest < Duration::from_millis(100), "a timer fired {:?} late", latest);
+
+        flushed.wait().await;
+        let (mut loaded, _) = load_persisted_state(&FileStore::new(dir.path()), None).unwrap().unwrap();
+        assert_eq!(loaded.get_heads(), doc.get_heads());
+    }
+
+    // a document with a snapshot of key=0 and the changes writing key=1, key=2 as WAL records
+    fn snapshot_and_records(store: &dyn StateStore) -> (AutoCommit, Vec<Vec<u8>>) {
+        let mut doc = AutoCommit::new();
+        doc.put(automerge::ROOT, "key", 0_i64).unwrap();
+        save_snapshot(store, &doc.save()).unwrap();
+        let records = (1..=2_i64)
+            .map(|value| {
+                doc.put(automerge::ROOT, "key", value).unwrap();
+                encode_wal_record(&doc.save_incremental())
+            })
+            .collect();
+        (doc, records)
+    }
+
+    fn key_of(doc: &AutoCommit) -> i64 {
+        match doc.get(automerge::ROOT, "key").unwrap() {
+            Some((automerge::Value::Scalar(scalar), _)) => scalar.to_i64().unwrap(),
+            other => panic!("unexpected value {:?}", other),
+        }
+    }
+
+    #[test]
+    fn a_torn_record_at_the_end_of_the_wal_is_dropped() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = FileStore::new(dir.path());
+        let (_, records) = snapshot_and_records(&store);
+        let torn = &records[1][..records[1].len() - 3];
+        store.save(WAL_KEY, &[records[0].as_slice(), torn].concat()).unwrap();
+
+        let (doc, info) = load_persisted_state(&store, None).unwrap().unwrap();
+        assert_eq!(key_of(&doc), 1);
+        assert_eq!(info.wal_records, 1);
+        assert!(info.torn);
+        assert_eq!(info.verification, Some(SnapshotVerification::Passed));
+    }
+
+    #[test]
+    fn a_snapshot_without_wal_is_loaded_on_its_own() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = FileStore::new(dir.path());
+        snapshot_and_records(&store);
+
+        let (doc, info) = load_persisted_state(&store, None).unwrap().unwrap();
+        assert_eq!(key_of(&doc), 0);
+        assert_eq!((info.wal_records, info.wal_size, info.torn), (0, 0, false));
+    }
+
+    #[test]
+    fn a_wal_without_snapshot_is_applied_to_an_empty_document() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = FileStore::new(dir.path());
+        let mut doc = AutoCommit::new();
+        let records: Vec<u8> = (1..=2_i64)
+            .flat_map(|value| {
+                doc.put(automerge::ROOT, "key", value).unwrap();
+                encode_wal_record(&doc.save_incremental())
+            })
+            .collect();
+        store.save(WAL_KEY, &records).unwrap();
+
+        let (mut loaded, info) = load_persisted_state(&store, None).unwrap().unwrap();
+        assert_eq!(key_of(&loaded), 2);
+        assert_eq!(loaded.get_heads(), doc.get_heads());
+        assert_eq!((info.snapshot_size, info.wal_records, info.verification), (0, 2, None));
+    }
+
+    #[test]
+    fn nothing_is_loaded_from_an_empty_store() {
+        let dir = tempfile::tempdir().unwrap();
+        assert!(load_persisted_state(&FileStore::new(dir.path()), None).unwrap().is_none());
+    }
+}
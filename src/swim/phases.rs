@@ -0,0 +1,171 @@
+use std::{
+    fmt::{Display, Formatter}, future::Future, sync::{Arc, Mutex}, time::{Duration, Instant},
+};
+use serde::{Deserialize, Serialize};
+
+use super::metrics;
+
+// Where the time of a REST request goes. The server runs every request within the scope of its RequestPhases,
+// ControllerHandle hands them to the controller task along with the request, and the controller marks the phases
+// it enters. Phases entered outside of a request, e.g. by merges of the foca task, aren't recorded anywhere.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[cfg_attr(feature = "rest", derive(utoipa::ToSchema))]
+#[serde(rename_all = "snake_case")]
+pub enum Phase {
+    // in the REST handler, e.g. parsing the request or waiting for an acknowledgement
+    Handler,
+    // waiting for the controller and the lock of the state
+    LockAcquisition,
+    // reading or changing the document
+    Apply,
+    // writing the change to the WAL or a snapshot
+    Persistence,
+    // handing the broadcast of the change to foca
+    BroadcastEnqueue,
+}
+
+const PHASES: [Phase; 5] = [Phase::Handler, Phase::LockAcquisition, Phase::Apply, Phase::Persistence, Phase::BroadcastEnqueue];
+
+impl Phase {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Phase::Handler => "handler",
+            Phase::LockAcquisition => "lock_acquisition",
+            Phase::Apply => "apply",
+            Phase::Persistence => "persistence",
+            Phase::BroadcastEnqueue => "broadcast_enqueue",
+        }
+    }
+}
+
+impl Display for Phase {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+#[derive(Debug)]
+struct PhaseState {
+    current: Phase,
+    entered: Instant,
+    // indexed like PHASES, without the time of the current phase
+    spent: [Duration; PHASES.len()],
+}
+
+/// The phases a single request went through so far.
+#[derive(Debug)]
+pub struct RequestPhases {
+    started: Instant,
+    state: Mutex<PhaseState>,
+}
+
+impl RequestPhases {
+    pub fn start() -> Arc<Self> {
+        let now = Instant::now();
+        Arc::new(RequestPhases {
+            started: now,
+            state: Mutex::new(PhaseState {
+                current: Phase::Handler,
+                entered: now,
+                spent: [Duration::ZERO; PHASES.len()],
+            }),
+        })
+    }
+
+    pub fn enter(&self, phase: Phase) {
+        let mut state = self.state.lock().unwrap();
+        if state.current == phase {
+            return;
+        }
+        let now = Instant::now();
+        let (current, spent) = (state.current as usize, now - state.entered);
+        state.spent[current] += spent;
+        state.current = phase;
+        state.entered = now;
+    }
+
+    pub fn current(&self) -> Phase {
+        self.state.lock().unwrap().current
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        self.started.elapsed()
+    }
+
+    // time spent in each phase so far including the current one, phases never entered are left out
+    pub fn breakdown(&self) -> Vec<(Phase, Duration)> {
+        let state = self.state.lock().unwrap();
+        let mut spent = state.spent;
+        spent[state.current as usize] += state.entered.elapsed();
+        PHASES.iter().copied().zip(spent)
+            .filter(|(phase, spent)| !spent.is_zero() || *phase == state.current)
+            .collect()
+    }
+
+    // records the breakdown of a finished request in the phase histograms
+    pub fn observe(&self) {
+        for (phase, spent) in self.breakdown() {
+            metrics::REQUEST_PHASE_SECONDS.with_label_values(&[phase.as_str()]).observe(spent.as_secs_f64());
+        }
+    }
+}
+
+// e.g. "lock_acquisition 1.204s, apply 3.1ms, persistence 12ms"
+pub fn format_breakdown(breakdown: &[(Phase, Duration)]) -> String {
+    breakdown.iter()
+        .map(|(phase, spent)| format!("{} {:?}", phase, spent))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+tokio::task_local! {
+    static CURRENT: Arc<RequestPhases>;
+}
+
+// runs future with phases as the phases of the current request
+pub async fn scope<F: Future>(phases: Arc<RequestPhases>, future: F) -> F::Output {
+    CURRENT.scope(phases, future).await
+}
+
+// the phases of the request the current task works on, if any
+pub fn current() -> Option<Arc<RequestPhases>> {
+    CURRENT.try_with(Arc::clone).ok()
+}
+
+pub fn enter(phase: Phase) {
+    let _ignore_result = CURRENT.try_with(|phases| phases.enter(phase));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const STEP: Duration = Duration::from_millis(20);
+
+    #[tokio::test]
+    async fn the_phases_entered_within_the_scope_of_a_request_are_timed() {
+        // outside of a request nothing is recorded
+        enter(Phase::Apply);
+        assert!(current().is_none());
+
+        let phases = RequestPhases::start();
+        scope(phases.clone(), async {
+            std::thread::sleep(STEP);
+            enter(Phase::LockAcquisition);
+            std::thread::sleep(2 * STEP);
+            enter(Phase::Apply);
+            // entering the current phase again keeps its time running
+            enter(Phase::Apply);
+            std::thread::sleep(STEP);
+            assert_eq!(current().unwrap().current(), Phase::Apply);
+        }).await;
+
+        let breakdown = phases.breakdown();
+        let entered: Vec<Phase> = breakdown.iter().map(|(phase, _)| *phase).collect();
+        assert_eq!(entered, vec![Phase::Handler, Phase::LockAcquisition, Phase::Apply]);
+        assert!(breakdown[0].1 >= STEP && breakdown[1].1 >= 2 * STEP && breakdown[2].1 >= STEP, "{:?}", breakdown);
+        assert!(phases.elapsed() >= breakdown.iter().map(|(_, spent)| *spent).sum::<Duration>());
+        assert!(format_breakdown(&breakdown).starts_with("handler "));
+    }
+}
@@ -0,0 +1,199 @@
+use std::{
+    collections::{BTreeMap, HashMap, VecDeque}, net::SocketAddr,
+    sync::{Arc, Mutex}, time::{Duration, Instant},
+};
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use super::metrics;
+
+// Optional latency probing: every probe interval a Ping envelope is sent straight to each member,
+// bypassing foca. Members answer with a Pong echoing the payload from their receive loop, so probes
+// never reach the SWIM state machine on either side. The payload is the send time relative to the
+// epoch of the prober, which gives the round trip time once the Pong arrives.
+// Members running versions without probing drop the unknown envelope kind and stay unknown.
+
+// round trip times kept per member for the statistics
+const PROBE_SAMPLES: usize = 100;
+
+// a member that answered before is unreachable once no Pong arrived for this many intervals
+const UNREACHABLE_INTERVALS: u32 = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "rest", derive(utoipa::ToSchema))]
+#[serde(rename_all = "snake_case")]
+pub enum Reachability {
+    // never answered a Ping, e.g. because it runs a version without probing
+    Unknown,
+    Reachable,
+    Unreachable,
+}
+
+/// Round trip times of the probes of a member in milliseconds, absent before the first Pong.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "rest", derive(utoipa::ToSchema))]
+pub struct PeerProbe {
+    pub reachability: Reachability,
+    pub sent: u64,
+    pub received: u64,
+    pub rtt_min_ms: Option<f64>,
+    pub rtt_avg_ms: Option<f64>,
+    pub rtt_p95_ms: Option<f64>,
+    #[cfg_attr(feature = "rest", schema(value_type = Option<String>))]
+    pub last_pong: Option<DateTime<Utc>>,
+}
+
+#[derive(Default)]
+struct PeerSamples {
+    sent: u64,
+    received: u64,
+    rtts: VecDeque<Duration>,
+    last_pong: Option<(Instant, DateTime<Utc>)>,
+}
+
+impl PeerSamples {
+    // min, avg and p95 of the recent round trip times
+    fn stats(&self) -> Option<(Duration, Duration, Duration)> {
+        let mut rtts: Vec<Duration> = self.rtts.iter().copied().collect();
+        rtts.sort();
+        let min = *rtts.first()?;
+        let avg = rtts.iter().sum::<Duration>() / rtts.len() as u32;
+        let p95 = rtts[(rtts.len() * 95).div_ceil(100) - 1];
+        Some((min, avg, p95))
+    }
+}
+
+fn millis(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
+
+#[derive(Clone)]
+pub struct PeerProber {
+    epoch: Instant,
+    interval: Duration,
+    peers: Arc<Mutex<HashMap<SocketAddr, PeerSamples>>>,
+}
+
+impl PeerProber {
+    pub fn new(interval: Duration) -> Self {
+        PeerProber {
+            epoch: Instant::now(),
+            interval,
+            peers: Arc::default(),
+        }
+    }
+
+    pub fn interval(&self) -> Duration {
+        self.interval
+    }
+
+    // the payload of the next Ping to peer
+    pub fn ping(&self, peer: SocketAddr, now: Instant) -> Bytes {
+        self.peers.lock().unwrap().entry(peer).or_default().sent += 1;
+        let sent_at = now.duration_since(self.epoch).as_nanos() as u64;
+        Bytes::copy_from_slice(&sent_at.to_be_bytes())
+    }
+
+    // Records the round trip time of a Pong. Pongs of members that were never pinged and
+    // malformed ones are ignored.
+    pub fn pong(&self, peer: SocketAddr, payload: &[u8], now: Instant) -> Option<Duration> {
+        let sent_at = u64::from_be_bytes(payload.try_into().ok()?);
+        let rtt = now.duration_since(self.epoch).checked_sub(Duration::from_nanos(sent_at))?;
+        let mut peers = self.peers.lock().unwrap();
+        let samples = peers.get_mut(&peer)?;
+        samples.received += 1;
+        samples.rtts.push_back(rtt);
+        if samples.rtts.len() > PROBE_SAMPLES {
+            samples.rtts.pop_front();
+        }
+        samples.last_pong = Some((now, Utc::now()));
+        if let Some((min, avg, p95)) = samples.stats() {
+            let peer = peer.to_string();
+            metrics::PEER_RTT_SECONDS.with_label_values(&[&peer, "min"]).set(min.as_secs_f64());
+            metrics::PEER_RTT_SECONDS.with_label_values(&[&peer, "avg"]).set(avg.as_secs_f64());
+            metrics::PEER_RTT_SECONDS.with_label_values(&[&peer, "p95"]).set(p95.as_secs_f64());
+        }
+        Some(rtt)
+    }
+
+    // forgets the members that are gone
+    pub fn retain(&self, members: &[SocketAddr]) {
+        self.peers.lock().unwrap().retain(|peer, _| {
+            let keep = members.contains(peer);
+            if !keep {
+                for stat in ["min", "avg", "p95"] {
+                    let _ = metrics::PEER_RTT_SECONDS.remove_label_values(&[&peer.to_string(), stat]);
+                }
+            }
+            keep
+        });
+    }
+
+    pub fn report(&self, now: Instant) -> BTreeMap<SocketAddr, PeerProbe> {
+        let unreachable_after = self.interval * UNREACHABLE_INTERVALS;
+        self.peers.lock().unwrap().iter()
+            .map(|(peer, samples)| {
+                let reachability = match samples.last_pong {
+                    None => Reachability::Unknown,
+                    Some((at, _)) if now.duration_since(at) <= unreachable_after => Reachability::Reachable,
+                    Some(_) => Reachability::Unreachable,
+                };
+                let stats = samples.stats();
+                (*peer, PeerProbe {
+                    reachability,
+                    sent: samples.sent,
+                    received: samples.received,
+                    rtt_min_ms: stats.map(|(min, _, _)| millis(min)),
+                    rtt_avg_ms: stats.map(|(_, avg, _)| millis(avg)),
+                    rtt_p95_ms: stats.map(|(_, _, p95)| millis(p95)),
+                    last_pong: samples.last_pong.map(|(_, at)| at),
+                })
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const INTERVAL: Duration = Duration::from_secs(1);
+
+    fn peer(port: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], port))
+    }
+
+    #[test]
+    fn a_member_is_reachable_until_it_misses_three_intervals_of_pongs() {
+        let prober = PeerProber::new(INTERVAL);
+        let start = Instant::now();
+        let (answering, silent) = (peer(9001), peer(9002));
+        let ping = prober.ping(answering, start);
+        prober.ping(silent, start);
+        assert_eq!(prober.pong(answering, &ping, start + Duration::from_millis(4)), Some(Duration::from_millis(4)));
+
+        let report = prober.report(start + INTERVAL);
+        assert_eq!(report[&answering].reachability, Reachability::Reachable);
+        assert_eq!((report[&answering].sent, report[&answering].received), (1, 1));
+        assert!(report[&answering].rtt_min_ms.is_some_and(|rtt| (rtt - 4.0).abs() < 1e-6));
+        assert_eq!(report[&silent].reachability, Reachability::Unknown);
+        assert_eq!(report[&silent].rtt_avg_ms, None);
+
+        let later = start + INTERVAL * (UNREACHABLE_INTERVALS + 1);
+        assert_eq!(prober.report(later)[&answering].reachability, Reachability::Unreachable);
+    }
+
+    #[test]
+    fn pongs_of_members_never_pinged_and_malformed_ones_are_ignored() {
+        let prober = PeerProber::new(INTERVAL);
+        let now = Instant::now();
+        let ping = prober.ping(peer(9001), now);
+        assert_eq!(prober.pong(peer(9002), &ping, now), None);
+        assert_eq!(prober.pong(peer(9001), b"short", now), None);
+        assert!(!prober.report(now).contains_key(&peer(9002)));
+
+        prober.retain(&[]);
+        assert!(prober.report(now).is_empty());
+    }
+}
@@ -0,0 +1,129 @@
+use std::{
+    fmt::{Display, Formatter}, str::FromStr,
+};
+use serde::{Deserialize, Serialize};
+
+// --profile picks the defaults of the settings that depend on the network between the members, so that
+// a cluster spread over data centers doesn't have to tune a dozen flags to stop flapping. Every flag given
+// explicitly still wins over the profile, NodeSettings::overridden lists those. Without a profile the
+// defaults are the ones holy-diver always had, see ProfileDefaults::standard.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Profile {
+    // all members on one host, e.g. tests or containers on a laptop
+    Local,
+    // members in the same data center
+    Lan,
+    // members in different regions with high and varying latency
+    Wan,
+}
+
+impl Profile {
+    pub fn defaults(profile: Option<Profile>) -> ProfileDefaults {
+        match profile {
+            None => ProfileDefaults::standard(),
+            Some(Profile::Local) => ProfileDefaults::local(),
+            Some(Profile::Lan) => ProfileDefaults::lan(),
+            Some(Profile::Wan) => ProfileDefaults::wan(),
+        }
+    }
+}
+
+impl Display for Profile {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Profile::Local => write!(f, "local"),
+            Profile::Lan => write!(f, "lan"),
+            Profile::Wan => write!(f, "wan"),
+        }
+    }
+}
+
+impl FromStr for Profile {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "local" => Ok(Profile::Local),
+            "lan" => Ok(Profile::Lan),
+            "wan" => Ok(Profile::Wan),
+            other => Err(format!("unknown profile {:?}, expected local, lan or wan", other)),
+        }
+    }
+}
+
+/// The settings a profile provides defaults for, named like the fields of NodeSettings.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct ProfileDefaults {
+    pub swim_probe_period_ms: u64,
+    pub swim_probe_rtt_ms: u64,
+    pub swim_suspect_timeout_ms: u64,
+    pub swim_max_transmissions: u8,
+    pub broadcast_interval_ms: u64,
+    pub checksum_interval_secs: Option<u64>,
+    pub seen_ops_flush_interval_secs: u64,
+    pub event_queue_size: usize,
+    pub broadcast_backlog_warning: usize,
+    pub write_concern_timeout_secs: u64,
+}
+
+impl ProfileDefaults {
+    // without --profile, foca's Config::simple with few transmissions
+    pub fn standard() -> Self {
+        ProfileDefaults {
+            swim_probe_period_ms: 1500,
+            swim_probe_rtt_ms: 500,
+            swim_suspect_timeout_ms: 3000,
+            swim_max_transmissions: 2,
+            broadcast_interval_ms: 100,
+            checksum_interval_secs: None,
+            seen_ops_flush_interval_secs: 30,
+            event_queue_size: 1024,
+            broadcast_backlog_warning: 100,
+            write_concern_timeout_secs: 5,
+        }
+    }
+
+    // fast failure detection and gossip, packets are hardly ever lost on a single host
+    pub fn local() -> Self {
+        ProfileDefaults {
+            swim_probe_period_ms: 500,
+            swim_probe_rtt_ms: 150,
+            swim_suspect_timeout_ms: 1500,
+            swim_max_transmissions: 2,
+            broadcast_interval_ms: 20,
+            write_concern_timeout_secs: 2,
+            ..Self::standard()
+        }
+    }
+
+    pub fn lan() -> Self {
+        ProfileDefaults {
+            swim_probe_period_ms: 1000,
+            swim_probe_rtt_ms: 300,
+            swim_suspect_timeout_ms: 4000,
+            swim_max_transmissions: 4,
+            checksum_interval_secs: Some(60),
+            broadcast_backlog_warning: 200,
+            ..Self::standard()
+        }
+    }
+
+    // Slow probes and a long suspicion so that latency spikes don't take members down, more
+    // transmissions against lost packets and larger queues for the longer round trips.
+    pub fn wan() -> Self {
+        ProfileDefaults {
+            swim_probe_period_ms: 5000,
+            swim_probe_rtt_ms: 1500,
+            swim_suspect_timeout_ms: 30_000,
+            swim_max_transmissions: 6,
+            broadcast_interval_ms: 500,
+            checksum_interval_secs: Some(300),
+            seen_ops_flush_interval_secs: 60,
+            event_queue_size: 4096,
+            broadcast_backlog_warning: 500,
+            write_concern_timeout_secs: 15,
+        }
+    }
+}
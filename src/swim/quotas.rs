@@ -0,0 +1,220 @@
+use std::fmt::{Display, Formatter};
+use anyhow::{anyhow, bail, Result};
+use serde::{Deserialize, Serialize};
+
+use super::{pattern::glob_matches, size::parse_size};
+
+// Limits on the fields of a namespace, declared with the scopes of --rest-auth-token, e.g.
+// `tokenA=rw:app1.*;max_keys=1000;max_bytes=256KB`. A quota belongs to the key pattern rather than to the
+// token, so it limits every local write of a matching field, whoever makes it. A field matching several
+// patterns with a quota counts towards each of them, its bytes are those of its key and its value.
+// Local writes that would exceed a quota are rejected, writes that add neither keys nor bytes are accepted
+// even over the quota so that a namespace can always be cleaned up. Merges of remote changes are never
+// rejected so that all nodes converge, a merge pushing a namespace over its quota is only flagged.
+// The usage is counted once from the state at startup and then follows every published change.
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Quota {
+    pub pattern: String,
+    pub max_keys: Option<usize>,
+    pub max_bytes: Option<u64>,
+}
+
+impl Quota {
+    // the first limit usage is beyond
+    fn exceeded_by(&self, usage: Usage) -> Option<(QuotaLimit, u64)> {
+        match (self.max_keys, self.max_bytes) {
+            (Some(max), _) if usage.keys > max => Some((QuotaLimit::MaxKeys, max as u64)),
+            (_, Some(max)) if usage.bytes > max => Some((QuotaLimit::MaxBytes, max)),
+            _ => None,
+        }
+    }
+}
+
+// Parses the `max_keys=N` and `max_bytes=SIZE` options following the pattern of a scope, None without options.
+pub fn parse_quota(pattern: &str, options: &[&str]) -> Result<Option<Quota>> {
+    if options.is_empty() {
+        return Ok(None);
+    }
+    let mut quota = Quota { pattern: pattern.to_owned(), max_keys: None, max_bytes: None };
+    for option in options {
+        match option.split_once('=').map(|(name, value)| (name.trim(), value.trim())) {
+            Some(("max_keys", keys)) => quota.max_keys = Some(keys.parse()
+                .map_err(|_| anyhow!("invalid max_keys {:?} in the scope of {}", keys, pattern))?),
+            Some(("max_bytes", bytes)) => quota.max_bytes = Some(parse_size(bytes)
+                .map_err(|e| anyhow!("{} in max_bytes of the scope of {}", e, pattern))?),
+            _ => bail!("unknown option {:?} in the scope of {}, expected max_keys=N or max_bytes=SIZE", option, pattern),
+        }
+    }
+    Ok(Some(quota))
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "rest", derive(utoipa::ToSchema))]
+pub struct Usage {
+    pub keys: usize,
+    pub bytes: u64,
+}
+
+impl Usage {
+    fn add(self, (keys, bytes): (i64, i64)) -> Usage {
+        Usage {
+            keys: (self.keys as i64 + keys).max(0) as usize,
+            bytes: (self.bytes as i64 + bytes).max(0) as u64,
+        }
+    }
+}
+
+// keys and bytes a change of a field from previous to value adds, None for absent or deleted values
+fn delta(field_name: &str, previous: Option<&str>, value: Option<&str>) -> (i64, i64) {
+    let bytes = |value: Option<&str>| value.map_or(0, |value| (field_name.len() + value.len()) as i64);
+    (value.is_some() as i64 - previous.is_some() as i64, bytes(value) - bytes(previous))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "rest", derive(utoipa::ToSchema))]
+#[serde(rename_all = "snake_case")]
+pub enum QuotaLimit {
+    MaxKeys,
+    MaxBytes,
+}
+
+impl Display for QuotaLimit {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            QuotaLimit::MaxKeys => write!(f, "max_keys"),
+            QuotaLimit::MaxBytes => write!(f, "max_bytes"),
+        }
+    }
+}
+
+/// A local write rejected by the quota of pattern.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "rest", derive(utoipa::ToSchema))]
+pub struct QuotaExceeded {
+    pub pattern: String,
+    pub limit: QuotaLimit,
+    pub max: u64,
+    // usage of the namespace now and as it would have been after the write
+    pub usage: Usage,
+    pub requested: Usage,
+}
+
+impl Display for QuotaExceeded {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let (now, requested) = match self.limit {
+            QuotaLimit::MaxKeys => (self.usage.keys as u64, self.requested.keys as u64),
+            QuotaLimit::MaxBytes => (self.usage.bytes, self.requested.bytes),
+        };
+        write!(f, "the write would take the fields matching {} from {} to {} beyond {}={}",
+            self.pattern, now, requested, self.limit, self.max)
+    }
+}
+
+/// Usage of a namespace with a quota, as listed by /admin/quotas.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[cfg_attr(feature = "rest", derive(utoipa::ToSchema))]
+pub struct QuotaUsage {
+    pub pattern: String,
+    pub max_keys: Option<usize>,
+    pub max_bytes: Option<u64>,
+    pub usage: Usage,
+    // set if merges of remote changes took the usage beyond the quota
+    pub over_quota: bool,
+    // merges since startup that took the namespace over its quota
+    pub merges_over_quota: u64,
+}
+
+struct Tracked {
+    quota: Quota,
+    usage: Usage,
+    merges_over_quota: u64,
+}
+
+#[derive(Default)]
+pub struct QuotaTracker {
+    tracked: Vec<Tracked>,
+}
+
+impl QuotaTracker {
+    pub fn new(quotas: Vec<Quota>) -> Self {
+        QuotaTracker {
+            tracked: quotas.into_iter()
+                .map(|quota| Tracked { quota, usage: Usage::default(), merges_over_quota: 0 })
+                .collect(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tracked.is_empty()
+    }
+
+    // forgets the usage before it is counted from scratch with record
+    pub fn reset_usage(&mut self) {
+        for tracked in &mut self.tracked {
+            tracked.usage = Usage::default();
+        }
+    }
+
+    // Checks local writes, given as field name, current value and new value, None for deletes. Every field
+    // must only be written once.
+    pub fn check(&self, writes: &[(&str, Option<&str>, Option<&str>)]) -> std::result::Result<(), QuotaExceeded> {
+        for tracked in &self.tracked {
+            let (keys, bytes) = writes.iter()
+                .filter(|(field_name, _, _)| glob_matches(&tracked.quota.pattern, field_name))
+                .map(|(field_name, previous, value)| delta(field_name, *previous, *value))
+                .fold((0, 0), |(keys, bytes), (k, b)| (keys + k, bytes + b));
+            let requested = tracked.usage.add((keys, bytes));
+            // only limits the writes add to are checked, deletes and shrinking writes always pass
+            let exceeded = match (tracked.quota.max_keys, tracked.quota.max_bytes) {
+                (Some(max), _) if keys > 0 && requested.keys > max => Some((QuotaLimit::MaxKeys, max as u64)),
+                (_, Some(max)) if bytes > 0 && requested.bytes > max => Some((QuotaLimit::MaxBytes, max)),
+                _ => None,
+            };
+            if let Some((limit, max)) = exceeded {
+                return Err(QuotaExceeded {
+                    pattern: tracked.quota.pattern.clone(),
+                    limit,
+                    max,
+                    usage: tracked.usage,
+                    requested,
+                });
+            }
+        }
+        Ok(())
+    }
+
+    // Counts a change of a field that was applied, returns the patterns it took over their quota.
+    pub fn record(&mut self, field_name: &str, previous: Option<&str>, value: Option<&str>) -> Vec<String> {
+        let delta = delta(field_name, previous, value);
+        let mut exceeded = Vec::new();
+        for tracked in self.tracked.iter_mut().filter(|tracked| glob_matches(&tracked.quota.pattern, field_name)) {
+            let was_over = tracked.quota.exceeded_by(tracked.usage).is_some();
+            tracked.usage = tracked.usage.add(delta);
+            if !was_over && tracked.quota.exceeded_by(tracked.usage).is_some() {
+                exceeded.push(tracked.quota.pattern.clone());
+            }
+        }
+        exceeded
+    }
+
+    // counts a merge that took the namespace of pattern over its quota
+    pub fn flag_merge(&mut self, pattern: &str) {
+        for tracked in self.tracked.iter_mut().filter(|tracked| tracked.quota.pattern == pattern) {
+            tracked.merges_over_quota += 1;
+        }
+    }
+
+    pub fn usage(&self) -> Vec<QuotaUsage> {
+        self.tracked.iter()
+            .map(|tracked| QuotaUsage {
+                pattern: tracked.quota.pattern.clone(),
+                max_keys: tracked.quota.max_keys,
+                max_bytes: tracked.quota.max_bytes,
+                usage: tracked.usage,
+                over_quota: tracked.quota.exceeded_by(tracked.usage).is_some(),
+                merges_over_quota: tracked.merges_over_quota,
+            })
+            .collect()
+    }
+}
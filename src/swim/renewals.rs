@@ -0,0 +1,108 @@
+use std::{
+    collections::VecDeque, time::{Duration, Instant},
+};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+
+// Foca renews the identity of this node, i.e. bumps it, whenever another member declared it down.
+// A few renewals happen after pauses or restarts of peers, frequent ones hint at lost probes.
+
+// more renewals than this within RENEWAL_STORM_WINDOW log a warning
+pub const RENEWAL_STORM_COUNT: usize = 3;
+pub const RENEWAL_STORM_WINDOW: Duration = Duration::from_secs(10 * 60);
+
+// timestamps of the last renewals reported in /cluster/info
+const RECENT_RENEWALS: usize = 10;
+
+#[derive(Debug, Default)]
+pub struct RenewalTracker {
+    total: u64,
+    recent: VecDeque<(Instant, DateTime<Utc>)>,
+    last_warning: Option<Instant>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[cfg_attr(feature = "rest", derive(utoipa::ToSchema))]
+pub struct RenewalStatus {
+    pub renewals: u64,
+    // oldest first
+    #[cfg_attr(feature = "rest", schema(value_type = Vec<String>))]
+    pub recent: Vec<DateTime<Utc>>,
+}
+
+impl RenewalTracker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Returns whether a warning is due, at most once per RENEWAL_STORM_WINDOW
+    pub fn record(&mut self, now: Instant, at: DateTime<Utc>) -> bool {
+        self.total += 1;
+        self.recent.push_back((now, at));
+        if self.recent.len() > RECENT_RENEWALS {
+            self.recent.pop_front();
+        }
+        let in_window = self.recent.iter()
+            .filter(|(renewed_at, _)| now.duration_since(*renewed_at) <= RENEWAL_STORM_WINDOW)
+            .count();
+        let warned_recently = self.last_warning
+            .is_some_and(|warned_at| now.duration_since(warned_at) < RENEWAL_STORM_WINDOW);
+        if in_window > RENEWAL_STORM_COUNT && !warned_recently {
+            self.last_warning = Some(now);
+            return true;
+        }
+        false
+    }
+
+    pub fn status(&self) -> RenewalStatus {
+        RenewalStatus {
+            renewals: self.total,
+            recent: self.recent.iter().map(|(_, at)| *at).collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // renews every second from start on, returns the renewals that warned
+    fn storm(tracker: &mut RenewalTracker, start: Instant, renewals: u32) -> Vec<u32> {
+        (0..renewals)
+            .filter(|renewal| tracker.record(start + Duration::from_secs(*renewal as u64), Utc::now()))
+            .collect()
+    }
+
+    #[test]
+    fn a_storm_warns_once_per_window() {
+        let mut tracker = RenewalTracker::new();
+        let start = Instant::now();
+        assert_eq!(storm(&mut tracker, start, 8), [RENEWAL_STORM_COUNT as u32]);
+
+        let later = start + RENEWAL_STORM_WINDOW + Duration::from_secs(60);
+        assert_eq!(storm(&mut tracker, later, 8), [RENEWAL_STORM_COUNT as u32]);
+    }
+
+    #[test]
+    fn spread_out_renewals_dont_warn() {
+        let mut tracker = RenewalTracker::new();
+        let start = Instant::now();
+        let spacing = RENEWAL_STORM_WINDOW / RENEWAL_STORM_COUNT as u32 + Duration::from_secs(1);
+        for renewal in 0..20 {
+            assert!(!tracker.record(start + spacing * renewal, Utc::now()));
+        }
+    }
+
+    #[test]
+    fn all_renewals_are_counted_and_the_last_ones_kept() {
+        let mut tracker = RenewalTracker::new();
+        let start = Instant::now();
+        let times: Vec<DateTime<Utc>> = (0..25).map(|minute| Utc::now() + chrono::Duration::minutes(minute)).collect();
+        for (renewal, at) in times.iter().enumerate() {
+            tracker.record(start + Duration::from_secs(renewal as u64), *at);
+        }
+        let status = tracker.status();
+        assert_eq!(status.renewals, 25);
+        assert_eq!(status.recent, times[25 - RECENT_RENEWALS..]);
+    }
+}
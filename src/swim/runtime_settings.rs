@@ -0,0 +1,68 @@
+use std::sync::{Arc, RwLock};
+
+use serde::Serialize;
+use serde_json::{Map, Value};
+
+/// Settings that can be changed while the node is running via PUT /admin/settings.
+/// They are local to this process and not replicated.
+#[derive(Debug, Clone, Serialize)]
+#[cfg_attr(feature = "rest", derive(utoipa::ToSchema))]
+pub struct RuntimeSettings {
+    // maximum number of keys a single batch read may ask for
+    pub max_batch_keys: usize,
+    // seen operation ids older than this are forgotten
+    pub seen_ops_horizon_secs: u64,
+    // how often the seen operation ids are written to the data dir
+    pub seen_ops_flush_interval_secs: u64,
+}
+
+pub type SharedRuntimeSettings = Arc<RwLock<RuntimeSettings>>;
+
+#[derive(Debug)]
+pub struct SettingError {
+    pub setting: String,
+    pub message: String,
+}
+
+impl Default for RuntimeSettings {
+    fn default() -> Self {
+        RuntimeSettings {
+            max_batch_keys: 100,
+            seen_ops_horizon_secs: 10 * 60,
+            seen_ops_flush_interval_secs: 30,
+        }
+    }
+}
+
+impl RuntimeSettings {
+    pub fn shared(self) -> SharedRuntimeSettings {
+        Arc::new(RwLock::new(self))
+    }
+
+    // Applies all given settings or none of them if any is unknown or invalid
+    pub fn apply(&mut self, changes: &Map<String, Value>) -> Result<(), SettingError> {
+        let mut updated = self.clone();
+        for (setting, value) in changes {
+            match setting.as_str() {
+                "max_batch_keys" => updated.max_batch_keys = positive(setting, value)? as usize,
+                "seen_ops_horizon_secs" => updated.seen_ops_horizon_secs = positive(setting, value)?,
+                "seen_ops_flush_interval_secs" => updated.seen_ops_flush_interval_secs = positive(setting, value)?,
+                _ => return Err(SettingError {
+                    setting: setting.to_owned(),
+                    message: "unknown setting or not changeable at runtime".to_owned(),
+                }),
+            }
+        }
+        *self = updated;
+        Ok(())
+    }
+}
+
+fn positive(setting: &str, value: &Value) -> Result<u64, SettingError> {
+    value.as_u64()
+        .filter(|v| *v > 0)
+        .ok_or_else(|| SettingError {
+            setting: setting.to_owned(),
+            message: format!("expected a positive integer, got {}", value),
+        })
+}
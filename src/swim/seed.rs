@@ -0,0 +1,149 @@
+use std::{fs, path::Path};
+
+use anyhow::{bail, Context, Result};
+use log::info;
+use serde_json::Value;
+
+use super::core::HolyDiverDataHandler;
+
+/// What apply_seed did with the values of a seed file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SeedOutcome {
+    Applied,
+    // forced over state loaded from the data dir, the merged state still has to be gossiped
+    AppliedOverExistingState,
+    Ignored,
+}
+
+// Reads a JSON object used to seed the values map of a new document.
+// Nested objects are flattened into dotted keys, e.g. {"db": {"host": "x"}} becomes "db.host" = "x".
+// Strings are stored as they are, every other scalar (and arrays) by its JSON representation.
+pub fn read_seed_file(path: &Path) -> Result<Vec<(String, String)>> {
+    let content = fs::read_to_string(path)
+        .with_context(|| format!("could not read seed file {}", path.display()))?;
+    let json: Value = serde_json::from_str(&content)
+        .with_context(|| format!("seed file {} is not valid JSON", path.display()))?;
+    seed_entries(&json)
+        .with_context(|| format!("seed file {} has an unexpected structure", path.display()))
+}
+
+pub fn seed_entries(json: &Value) -> Result<Vec<(String, String)>> {
+    let object = match json {
+        Value::Object(object) => object,
+        other => bail!("expected a JSON object at the top level, got {}", other),
+    };
+    let mut entries = Vec::new();
+    flatten("", object, &mut entries);
+    Ok(entries)
+}
+
+fn flatten(prefix: &str, object: &serde_json::Map<String, Value>, entries: &mut Vec<(String, String)>) {
+    for (key, value) in object {
+        let path = if prefix.is_empty() {
+            key.to_owned()
+        } else {
+            format!("{}.{}", prefix, key)
+        };
+        match value {
+            Value::Object(nested) => flatten(&path, nested, entries),
+            Value::String(s) => entries.push((path, s.to_owned())),
+            other => entries.push((path, other.to_string())),
+        }
+    }
+}
+
+// Writes the seed into a document that wasn't persisted before. Existing state is only written over with
+// force, the seed is then merged as regular writes and fields missing in it are kept.
+pub fn apply_seed(data_handler: &mut HolyDiverDataHandler, seed: Vec<(String, String)>, has_persisted_state: bool, force: bool) -> Result<SeedOutcome> {
+    if has_persisted_state && !force {
+        info!("Ignoring seed file since state already exists, use --seed-force to apply it anyway");
+        return Ok(SeedOutcome::Ignored);
+    }
+    info!("Applying {} seed values", seed.len());
+    data_handler.set_fields(seed)?;
+    Ok(if has_persisted_state { SeedOutcome::AppliedOverExistingState } else { SeedOutcome::Applied })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::SocketAddr, path::Path};
+    use serde_json::json;
+    use super::*;
+    use crate::swim::types::ID;
+
+    fn open(data_dir: &Path) -> HolyDiverDataHandler {
+        HolyDiverDataHandler::new(data_dir, ID::with_bump(SocketAddr::from(([127, 0, 0, 1], 9001)), 0))
+    }
+
+    fn value(data_handler: &HolyDiverDataHandler, field_name: &str) -> Option<String> {
+        data_handler.get_field(field_name.to_owned()).unwrap()
+    }
+
+    // a data dir with db.host written before the restart
+    async fn existing_state() -> tempfile::TempDir {
+        let dir = tempfile::tempdir().unwrap();
+        let mut data_handler = open(dir.path());
+        data_handler.set_field("db.host".to_owned(), "live".to_owned()).unwrap();
+        data_handler.set_field("db.user".to_owned(), "admin".to_owned()).unwrap();
+        data_handler.flush_handle().wait().await;
+        dir
+    }
+
+    fn seed() -> Vec<(String, String)> {
+        seed_entries(&json!({"db": {"host": "seeded", "port": 5432}, "debug": false})).unwrap()
+    }
+
+    #[test]
+    fn nested_objects_are_flattened_into_dotted_keys() {
+        let mut entries = seed();
+        entries.sort();
+        assert_eq!(entries, vec![
+            ("db.host".to_owned(), "seeded".to_owned()),
+            ("db.port".to_owned(), "5432".to_owned()),
+            ("debug".to_owned(), "false".to_owned()),
+        ]);
+    }
+
+    #[test]
+    fn malformed_seed_files_are_refused() {
+        let dir = tempfile::tempdir().unwrap();
+        let not_json = dir.path().join("not_json.json");
+        std::fs::write(&not_json, "{\"db\": ").unwrap();
+        assert!(format!("{:#}", read_seed_file(&not_json).unwrap_err()).contains("is not valid JSON"));
+        let not_an_object = dir.path().join("not_an_object.json");
+        std::fs::write(&not_an_object, "[1, 2]").unwrap();
+        assert!(format!("{:#}", read_seed_file(&not_an_object).unwrap_err()).contains("expected a JSON object"));
+    }
+
+    #[tokio::test]
+    async fn a_fresh_data_dir_is_seeded() {
+        let dir = tempfile::tempdir().unwrap();
+        let has_persisted_state = HolyDiverDataHandler::has_persisted_state(dir.path());
+        let mut data_handler = open(dir.path());
+        assert_eq!(apply_seed(&mut data_handler, seed(), has_persisted_state, false).unwrap(), SeedOutcome::Applied);
+        assert_eq!(value(&data_handler, "db.host").as_deref(), Some("seeded"));
+        assert_eq!(value(&data_handler, "db.port").as_deref(), Some("5432"));
+    }
+
+    #[tokio::test]
+    async fn existing_state_is_kept_without_force() {
+        let dir = existing_state().await;
+        let has_persisted_state = HolyDiverDataHandler::has_persisted_state(dir.path());
+        let mut data_handler = open(dir.path());
+        assert_eq!(apply_seed(&mut data_handler, seed(), has_persisted_state, false).unwrap(), SeedOutcome::Ignored);
+        assert_eq!(value(&data_handler, "db.host").as_deref(), Some("live"));
+        assert_eq!(value(&data_handler, "db.port"), None);
+    }
+
+    #[tokio::test]
+    async fn forced_seeds_are_merged_into_existing_state() {
+        let dir = existing_state().await;
+        let has_persisted_state = HolyDiverDataHandler::has_persisted_state(dir.path());
+        let mut data_handler = open(dir.path());
+        assert_eq!(apply_seed(&mut data_handler, seed(), has_persisted_state, true).unwrap(), SeedOutcome::AppliedOverExistingState);
+        assert_eq!(value(&data_handler, "db.host").as_deref(), Some("seeded"));
+        assert_eq!(value(&data_handler, "db.port").as_deref(), Some("5432"));
+        // fields the seed doesn't mention are not removed
+        assert_eq!(value(&data_handler, "db.user").as_deref(), Some("admin"));
+    }
+}
@@ -0,0 +1,187 @@
+use std::{
+    collections::HashMap, time::Duration,
+};
+use bincode::Options;
+use log::{info, warn, error};
+use uuid::Uuid;
+
+use super::store::StateStore;
+
+// Layout of seen_ops.bin:
+//
+// 0. Magic bytes "HDSO"
+// 1. Format version (u8)
+// 2. bincode encoded Vec<(Uuid, i64)>, operation id and unix millis when it was first seen
+//
+const MAGIC: &[u8; 4] = b"HDSO";
+const FORMAT_VERSION: u8 = 1;
+pub const SEEN_OPS_KEY: &str = "seen_ops.bin";
+
+/// Operation ids of SyncOperation broadcasts that were already acted on, together with the
+/// time they were first seen. Entries older than `horizon` are discarded and at most
+/// `max_entries` (the newest ones) are kept, which also caps the size of the persisted file.
+#[derive(Debug)]
+pub struct SeenOperations {
+    seen: HashMap<Uuid, i64>,
+    horizon: Duration,
+    max_entries: usize,
+}
+
+impl SeenOperations {
+    pub fn new(horizon: Duration, max_entries: usize) -> Self {
+        SeenOperations {
+            seen: HashMap::new(),
+            horizon,
+            max_entries,
+        }
+    }
+
+    pub fn contains(&self, operation_id: &Uuid) -> bool {
+        self.seen.contains_key(operation_id)
+    }
+
+    // unix millis when the operation id was first seen
+    pub fn seen_at(&self, operation_id: &Uuid) -> Option<i64> {
+        self.seen.get(operation_id).copied()
+    }
+
+    // operation ids with the unix millis when they were first seen, newest first
+    pub fn entries(&self) -> Vec<(Uuid, i64)> {
+        let mut entries: Vec<(Uuid, i64)> = self.seen.iter().map(|(id, seen_at)| (*id, *seen_at)).collect();
+        entries.sort_by_key(|(_, seen_at)| std::cmp::Reverse(*seen_at));
+        entries
+    }
+
+    // The operation is applied again when it arrives the next time, returns whether it was seen
+    pub fn forget(&mut self, operation_id: &Uuid) -> bool {
+        self.seen.remove(operation_id).is_some()
+    }
+
+    // forgets all operation ids and returns how many there were
+    pub fn clear(&mut self) -> usize {
+        let count = self.seen.len();
+        self.seen.clear();
+        count
+    }
+
+    // A result of `true` means that the operation id was not seen before
+    pub fn insert(&mut self, operation_id: Uuid) -> bool {
+        if self.seen.contains_key(&operation_id) {
+            return false;
+        }
+        self.seen.insert(operation_id, chrono::Utc::now().timestamp_millis());
+        if self.seen.len() > self.max_entries {
+            self.prune();
+        }
+        true
+    }
+
+    pub fn set_horizon(&mut self, horizon: Duration) {
+        self.horizon = horizon;
+    }
+
+    pub fn len(&self) -> usize {
+        self.seen.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.seen.is_empty()
+    }
+
+    // drops entries older than the horizon and the oldest entries exceeding max_entries
+    pub fn prune(&mut self) {
+        let oldest_allowed = chrono::Utc::now().timestamp_millis() - self.horizon.as_millis() as i64;
+        self.seen.retain(|_, seen_at| *seen_at >= oldest_allowed);
+        if self.seen.len() > self.max_entries {
+            let mut entries: Vec<(Uuid, i64)> = self.seen.drain().collect();
+            entries.sort_by_key(|(_, seen_at)| std::cmp::Reverse(*seen_at));
+            entries.truncate(self.max_entries);
+            self.seen.extend(entries);
+        }
+    }
+
+    // Loads previously persisted operation ids, a missing or undecodable file results in an empty set
+    pub fn load(store: &dyn StateStore, horizon: Duration, max_entries: usize) -> Self {
+        let mut seen_operations = SeenOperations::new(horizon, max_entries);
+        match store.load(SEEN_OPS_KEY).and_then(|bytes| bytes.map(|bytes| Self::decode(&bytes)).transpose()) {
+            Ok(Some(entries)) => {
+                seen_operations.seen.extend(entries);
+                seen_operations.prune();
+                info!("Loaded {} seen operation ids from {}", seen_operations.len(), store.describe(SEEN_OPS_KEY));
+            },
+            Ok(None) => {},
+            Err(e) => warn!("Could not load seen operation ids from {}, starting empty: {:#}", store.describe(SEEN_OPS_KEY), e),
+        }
+        seen_operations
+    }
+
+    pub fn save(&mut self, store: &dyn StateStore) {
+        self.prune();
+        let entries: Vec<(Uuid, i64)> = self.seen.iter().map(|(id, seen_at)| (*id, *seen_at)).collect();
+        let mut bytes = Vec::with_capacity(MAGIC.len() + 1 + entries.len() * 24);
+        bytes.extend_from_slice(MAGIC);
+        bytes.push(FORMAT_VERSION);
+        if let Err(e) = bincode::DefaultOptions::new().serialize_into(&mut bytes, &entries) {
+            error!("Could not serialize seen operation ids: {}", e);
+            return;
+        }
+        match store.save(SEEN_OPS_KEY, &bytes) {
+            Ok(_) => info!("Wrote {} seen operation ids ({} bytes) to {}", entries.len(), bytes.len(), store.describe(SEEN_OPS_KEY)),
+            Err(e) => error!("Could not write seen operation ids: {:#}", e),
+        }
+    }
+
+    fn decode(bytes: &[u8]) -> anyhow::Result<Vec<(Uuid, i64)>> {
+        if bytes.len() < MAGIC.len() + 1 || &bytes[..MAGIC.len()] != MAGIC {
+            anyhow::bail!("not a seen operations file");
+        }
+        let version = bytes[MAGIC.len()];
+        if version != FORMAT_VERSION {
+            anyhow::bail!("unsupported seen operations format version {}", version);
+        }
+        Ok(bincode::DefaultOptions::new().deserialize(&bytes[MAGIC.len() + 1..])?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::swim::store::FileStore;
+
+    const HORIZON: Duration = Duration::from_secs(60 * 60);
+
+    #[test]
+    fn a_restarted_node_recognizes_replayed_operations() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = FileStore::new(dir.path());
+        let replayed = Uuid::new_v4();
+        let mut before = SeenOperations::new(HORIZON, 100);
+        assert!(before.insert(replayed));
+        before.save(&store);
+
+        let mut after = SeenOperations::load(&store, HORIZON, 100);
+        assert!(after.contains(&replayed));
+        assert!(!after.insert(replayed));
+        assert!(after.insert(Uuid::new_v4()));
+    }
+
+    #[test]
+    fn only_the_newest_entries_are_kept() {
+        let mut seen = SeenOperations::new(HORIZON, 2);
+        let ids: Vec<Uuid> = (0..3).map(|_| Uuid::new_v4()).collect();
+        for (age, id) in ids.iter().enumerate() {
+            seen.seen.insert(*id, chrono::Utc::now().timestamp_millis() - 1000 * (3 - age as i64));
+        }
+        seen.prune();
+        assert!(!seen.contains(&ids[0]));
+        assert!(seen.contains(&ids[1]) && seen.contains(&ids[2]));
+    }
+
+    #[test]
+    fn an_unreadable_file_starts_empty() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = FileStore::new(dir.path());
+        store.save(SEEN_OPS_KEY, b"HDSO\x07garbage").unwrap();
+        assert!(SeenOperations::load(&store, HORIZON, 100).is_empty());
+    }
+}
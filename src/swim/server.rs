@@ -1,54 +1,1558 @@
-use std::sync::{Arc, Mutex};
+use std::collections::BTreeMap;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
 
+use actix_web::body::{BodySize, MessageBody};
+use actix_web::dev::{Payload, Server, ServiceRequest, ServiceResponse};
+use actix_web::http::{header, Method};
+use actix_web::middleware::{from_fn, Compress, Condition, Next};
+use actix_web::ResponseError;
 use actix_web::web::Data;
-use actix_web::{get, put, web, App, HttpRequest, HttpServer, HttpResponse};
+use actix_web::{delete, get, post, put, web, App, FromRequest, HttpMessage, HttpRequest, HttpServer, HttpResponse};
+use anyhow::Context;
 
-use log::info;
+use log::{info, warn};
 
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use utoipa::{IntoParams, OpenApi, ToSchema};
+use uuid::Uuid;
 
-use crate::swim::core::HolyDiverController;
+use crate::swim::actors::{ActorInfo, ActorSummary, ActorTag};
+use crate::swim::auth::{Access, AuthTokens, TokenScopes};
+use crate::swim::batch::{BatchOp, BatchSummary};
+use crate::swim::build_info::{self, BuildInfo};
+use crate::swim::chaos::{ChaosConfig, FaultInjector, LatencyRange};
+use crate::swim::consistency::{ConsistencyReport, PeerChecksum};
+use crate::swim::apply_latency::{ApplyLatencyReport, LatencySummary};
+use crate::swim::diff::{self, DifferentValues, HistoryRelation, SideValue, StateDiff};
+use crate::swim::broadcast::ACKED_OPERATION_VERSION;
+use crate::swim::envelope;
+use crate::swim::departures::{Departure, DownReason};
+use crate::swim::direct_sync::{SyncMode, SyncReport};
+use crate::swim::core::{DocStats, FieldConflict, FieldMeta, FieldValues};
+use crate::swim::handle::ControllerHandle;
+use crate::swim::history::HistoryAssessment;
+use crate::swim::foca::FocaStatus;
+use crate::swim::join_state::JoinState;
+use crate::swim::leases::Lease;
+use crate::swim::listing::StateListingStream;
+use crate::swim::field_names::{self, FieldNameRule, FieldSanitation, InvalidFieldName, InvalidFieldReport, QuarantinedField};
+use crate::swim::ledger::LedgerStatus;
+use crate::swim::persistence::{PersistenceStatus, SnapshotVerification, DEFAULT_UNHEALTHY_AFTER_FAILURES};
+use crate::swim::phases::{self, RequestPhases};
+use crate::swim::probes::{PeerProbe, Reachability};
+use crate::swim::inbound_limits::PeerInboundRate;
+use crate::swim::quotas::{QuotaExceeded, QuotaLimit, QuotaUsage, Usage};
+use crate::swim::renewals::RenewalStatus;
+use crate::swim::validator::{Constraint, ValueType};
+use crate::swim::{events, logging, metrics};
+use crate::swim::events::MemberEventSender;
+use crate::swim::sse::{self, EventSubscription};
+use crate::swim::shutdown::ShutdownCoordinator;
+use crate::swim::write_quorum::{WriteQuorum, WriteQuorumStatus};
+use crate::swim::startup::StartupError;
+use crate::swim::sync_states::SyncStateStats;
+use crate::swim::strict_identity::Unverified;
+use crate::swim::tombstones::Tombstone;
+use crate::swim::structure::DocStructureError;
+use crate::swim::runtime_settings::{RuntimeSettings, SharedRuntimeSettings};
+use crate::swim::error::{ErrorBody, ErrorEnvelope, HolyDiverError, json_error_handler};
 
-#[derive(Deserialize)]
+#[derive(Deserialize, ToSchema)]
 struct FieldUpdate {
     value: String,
 }
 
+#[derive(Deserialize, ToSchema)]
+struct LeaseRequest {
+    ttl_secs: u64,
+}
+
+#[derive(Deserialize, ToSchema)]
+struct SyncRequest {
+    #[schema(value_type = String, example = "10.0.0.7:9000")]
+    target: SocketAddr,
+    mode: SyncMode,
+    // also sync with addresses that are no active member
+    #[serde(default)]
+    force: bool,
+}
+
+#[derive(Deserialize, ToSchema)]
+struct FieldsRequest {
+    keys: Vec<String>,
+}
+
+#[derive(Deserialize, Serialize, ToSchema)]
+struct LogLevel {
+    // env_logger style filter, e.g. info,holydiver=debug
+    #[schema(example = "info,holydiver=debug")]
+    filter: String,
+}
+
+#[derive(Serialize, ToSchema)]
+struct CurrentLogLevel {
+    // None if the filter was never set
+    filter: Option<String>,
+}
+
+#[derive(Serialize, ToSchema)]
+struct OperationSighting {
+    #[schema(value_type = String)]
+    operation_id: Uuid,
+    seen: bool,
+    // when the operation was first received or sent, None if it wasn't or was forgotten after --seen-ops-horizon
+    #[schema(value_type = Option<String>)]
+    first_seen: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+#[derive(Serialize, ToSchema)]
+struct SeenOperationList {
+    count: usize,
+    // newest first
+    operations: Vec<SeenOperation>,
+}
+
+#[derive(Serialize, ToSchema)]
+struct SeenOperation {
+    #[schema(value_type = String)]
+    operation_id: Uuid,
+    #[schema(value_type = String)]
+    first_seen: chrono::DateTime<chrono::Utc>,
+}
+
+#[derive(Serialize, ToSchema)]
+struct ForgottenOperations {
+    forgotten: usize,
+}
+
+#[derive(Serialize, ToSchema)]
+struct KeyList {
+    keys: Vec<String>,
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct KeysQuery {
+    /// Only list fields starting with this prefix.
+    #[serde(default)]
+    prefix: String,
+    /// Also list merged fields whose names break the field name rules, only hidden with --sanitize-field-names.
+    #[serde(default)]
+    include_invalid: bool,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+enum WriteConcern {
+    // answered once the write is applied locally
+    #[default]
+    Local,
+    // answered once another member acknowledged applying the write, or after the write concern timeout
+    Replicated,
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct WriteQuery {
+    /// replicated waits until another member applied the write.
+    #[serde(default)]
+    write_concern: WriteConcern,
+}
+
+/// Returned with 202 when a replicated write was not acknowledged in time. The write is applied
+/// locally and still gossiped.
+#[derive(Serialize, ToSchema)]
+struct WriteWarning {
+    warning: String,
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct StateQuery {
+    /// Also list the tombstones of deleted fields.
+    #[serde(default)]
+    include_deleted: bool,
+    /// Also list whether each field is replicated.
+    #[serde(default)]
+    meta: bool,
+    /// Read all sections at once under a single lock and return the version they were read at as ETag, instead
+    /// of streaming the fields in chunks.
+    #[serde(default)]
+    consistent: bool,
+    /// Also list merged fields whose names break the field name rules, only hidden with --sanitize-field-names.
+    #[serde(default)]
+    include_invalid: bool,
+}
+
+/// All fields with their values, with `include_deleted` also the tombstones of deleted fields
+/// that were not purged yet and with `meta` whether the fields are replicated.
+// written field by field by StateListingStream, serialized as a whole only for consistent listings
+#[derive(Serialize, ToSchema)]
+struct StateListing {
+    values: BTreeMap<String, String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    deleted: Option<BTreeMap<String, Tombstone>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    meta: Option<BTreeMap<String, ListedFieldMeta>>,
+}
+
+/// Fields matching a no-replicate pattern are only stored on the node answering.
+#[derive(Serialize, ToSchema)]
+struct ListedFieldMeta {
+    replicated: bool,
+}
+
+#[derive(Serialize, ToSchema)]
+struct VersionInfo {
+    version: String,
+}
+
+/// Addresses of the active cluster members, including the node answering, with the labels the
+/// members gossiped about themselves, e.g. their version.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct MemberList {
+    #[schema(value_type = Vec<String>, example = json!(["127.0.0.1:9000", "127.0.0.1:9001"]))]
+    pub members: Vec<SocketAddr>,
+    pub count: usize,
+    // members whose labels were not received yet are missing, e.g. ones running older versions
+    #[serde(default)]
+    #[schema(value_type = Object, example = json!({"127.0.0.1:9000": {"version": "0.1.0", "git_commit": "4f2a9c1b7d3e", "protocol_version": "3", "gossip_message_version": "2"}}))]
+    pub labels: BTreeMap<SocketAddr, BTreeMap<String, String>>,
+    // members that went down within the last day, with whether they left or failed
+    #[serde(default)]
+    #[schema(value_type = Object, example = json!({"127.0.0.1:9002": {"timestamp": "2024-05-01T12:00:00Z", "reason": "left"}}))]
+    pub departures: BTreeMap<SocketAddr, Departure>,
+    // with --strict-identity, the members that did not prove their identity yet or failed to, see strict_identity
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    #[schema(value_type = Object, example = json!({"127.0.0.1:9001": "token_mismatch"}))]
+    pub unverified: BTreeMap<SocketAddr, Unverified>,
+    // with probe=true, the latency probes of the other members
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schema(value_type = Option<Object>)]
+    pub probes: Option<BTreeMap<SocketAddr, PeerProbe>>,
+    // with probe=true, what each source address sent recently and how much of it was dropped, see inbound_limits
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schema(value_type = Option<Object>)]
+    pub inbound: Option<BTreeMap<SocketAddr, PeerInboundRate>>,
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct MembersQuery {
+    /// Also list the round trip times of the latency probes, requires --probe-interval, and the inbound rates of the peers.
+    #[serde(default)]
+    probe: bool,
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct DiffQuery {
+    /// Address of the member to compare this node with.
+    #[param(value_type = String, example = "10.0.0.7:9000")]
+    peer: SocketAddr,
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct EventsQuery {
+    /// Comma separated event types to stream, field and/or member. All types if absent.
+    types: Option<String>,
+    /// Also stream changes of merged fields whose names break the field name rules, only hidden with --sanitize-field-names.
+    #[serde(default)]
+    include_invalid: bool,
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct FieldQuery {
+    /// Return the value with its metadata as JSON.
+    #[serde(default)]
+    meta: bool,
+    /// Version token as returned by /state/_version or the ETag header, to read the value at that version.
+    at: Option<String>,
+}
+
+// Generated from the handler annotations below, every handler registered in host_server_with_config
+// has to be listed in paths.
+#[derive(OpenApi)]
+#[openapi(
+    info(title = "holy-diver", description = "REST API of a holy-diver node"),
+    paths(hello, get_health, get_build_info, get_version, get_state, get_keys, get_stats, get_schema, get_members, get_cluster_info, get_consistency, get_events, get_metrics,
+        get_field, get_fields, apply_batch, update_field, get_conflicts, revert_field, delete_field, get_container_field, update_container_field, delete_container_field, export_state, import_state,
+        get_log_level, update_log_level, get_settings, update_settings, sync_with, get_openapi, get_actors, get_operation, get_seen_ops, forget_seen_op, get_invalid_fields, clear_seen_ops, get_diff,
+        acquire_lease, renew_lease, release_lease, get_chaos, update_chaos, get_quotas),
+    components(schemas(ErrorEnvelope, ErrorBody, FieldUpdate, FieldsRequest, BatchOp, BatchSummary, LogLevel, CurrentLogLevel, KeyList, StateListing, ListedFieldMeta, Tombstone, Lease, LeaseRequest, WriteConcern, WriteWarning, VersionInfo, BuildInfo, MemberList, Departure, DownReason, PeerProbe, PeerInboundRate, Reachability,
+        DocStats, HistoryAssessment, PersistenceStatus, SnapshotVerification, Health, FieldMeta, FieldValues, FieldConflict, ActorInfo, ActorTag, ActorSummary, OperationSighting, InvalidFieldReport, QuarantinedField, InvalidFieldName, FieldNameRule, SeenOperationList, SeenOperation, ForgottenOperations, FocaStatus, LedgerStatus, RenewalStatus, Constraint, ValueType, RuntimeSettings, SyncRequest, SyncMode, SyncReport, ConsistencyReport, PeerChecksum, ApplyLatencyReport, LatencySummary, WriteQuorumStatus, JoinState, SyncStateStats, Unverified,
+        ChaosConfig, LatencyRange, QuotaUsage, Usage, QuotaExceeded, QuotaLimit, StateDiff, SideValue, DifferentValues, HistoryRelation)),
+    tags(
+        (name = "state", description = "Reading and writing fields of the replicated document"),
+        (name = "cluster", description = "Members and gossip of the cluster"),
+        (name = "admin", description = "Settings of this node"),
+        (name = "leases", description = "Advisory named leases, eventually consistent like all state"),
+    ),
+)]
+struct ApiDoc;
+
+/// The OpenAPI 3 description of the REST API as served by /openapi.json.
+pub fn openapi() -> utoipa::openapi::OpenApi {
+    ApiDoc::openapi()
+}
+
+pub struct ServerConfig {
+    // 0 binds any free port, see bind_server_with_config
+    pub port: u16,
+    // the bound port is written to this file, e.g. <DATA_DIR>/rest.port, so that tools find nodes bound to port 0
+    pub port_file: Option<PathBuf>,
+    // settings changeable via /admin/settings, including the maximum number of keys per batch read
+    pub runtime_settings: SharedRuntimeSettings,
+    // maximum size of JSON request bodies in bytes
+    pub max_json_body: usize,
+    // maximum size of documents uploaded to /import in bytes
+    pub max_import_body: usize,
+    // member events of foca, the same sender has to be passed to FocaRuntimeConfig
+    pub member_events: MemberEventSender,
+    // interval of keep-alive comments on idle /events connections
+    pub sse_heartbeat: Duration,
+    // serves a Swagger UI for /openapi.json at /swagger-ui
+    pub enable_swagger: bool,
+    // how long writes with write_concern=replicated wait for an acknowledgement
+    pub write_concern_timeout: Duration,
+    // /healthz reports the node as unhealthy after this many failed writes of the state in a row
+    pub unhealthy_after_failures: u32,
+    // scopes by bearer token, the API is open to everyone if empty, see auth
+    pub auth_tokens: AuthTokens,
+    // faults injected into the transport, /admin/chaos is only served if set, see --chaos
+    pub fault_injector: Option<Arc<FaultInjector>>,
+    // compresses large responses for clients sending Accept-Encoding, see select_compression
+    pub compression: bool,
+    // serves the /debug endpoints changing the state of the node, e.g. DELETE /debug/seen-ops/{id}
+    pub debug_endpoints: bool,
+    // requests taking longer are answered with a RequestTimeout, see route_timeout
+    pub read_timeout: Duration,
+    pub write_timeout: Duration,
+    // requests taking longer are logged with the time of each phase
+    pub slow_request_threshold: Duration,
+    // merged fields breaking the field name rules are left out of listings unless asked for, see field_names
+    pub field_sanitation: FieldSanitation,
+    // writes are answered with 503 once the node drains, see shutdown
+    pub shutdown: ShutdownCoordinator,
+    // the quorum of the data handler, /healthz fails while it isn't met, see write_quorum
+    pub write_quorum: Option<WriteQuorum>,
+    // stop the server on SIGTERM and SIGINT, off if the embedder stops it through the handle of the Server
+    pub handle_signals: bool,
+    // requests in flight are waited for this long once the server stops
+    pub shutdown_timeout: Duration,
+}
+
+pub const REST_PORT_FILE: &str = "rest.port";
+pub const DEFAULT_WRITE_CONCERN_TIMEOUT: Duration = Duration::from_secs(5);
+pub const DEFAULT_READ_TIMEOUT: Duration = Duration::from_secs(2);
+pub const DEFAULT_WRITE_TIMEOUT: Duration = Duration::from_secs(5);
+pub const DEFAULT_SLOW_REQUEST_THRESHOLD: Duration = Duration::from_secs(1);
+// the default of actix
+pub const DEFAULT_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(30);
+
+impl ServerConfig {
+    pub fn new(port: u16) -> Self {
+        ServerConfig {
+            port,
+            port_file: None,
+            runtime_settings: RuntimeSettings::default().shared(),
+            max_json_body: 256 * 1024,
+            max_import_body: 64 * 1024 * 1024,
+            member_events: events::member_channel(events::DEFAULT_QUEUE_SIZE),
+            sse_heartbeat: sse::DEFAULT_HEARTBEAT,
+            enable_swagger: false,
+            write_concern_timeout: DEFAULT_WRITE_CONCERN_TIMEOUT,
+            unhealthy_after_failures: DEFAULT_UNHEALTHY_AFTER_FAILURES,
+            auth_tokens: AuthTokens::default(),
+            fault_injector: None,
+            compression: true,
+            debug_endpoints: false,
+            read_timeout: DEFAULT_READ_TIMEOUT,
+            write_timeout: DEFAULT_WRITE_TIMEOUT,
+            slow_request_threshold: DEFAULT_SLOW_REQUEST_THRESHOLD,
+            field_sanitation: FieldSanitation::Off,
+            shutdown: ShutdownCoordinator::new(),
+            write_quorum: None,
+            handle_signals: true,
+            shutdown_timeout: DEFAULT_SHUTDOWN_TIMEOUT,
+        }
+    }
+
+    // whether a listing leaves out the fields breaking the field name rules, see field_names
+    fn hides_invalid_fields(&self, include_invalid: bool) -> bool {
+        self.field_sanitation != FieldSanitation::Off && !include_invalid
+    }
+}
+
+// served without a token, so that health checks and the API description don't need one
+const PUBLIC_PATHS: [&str; 4] = ["/hello", "/healthz", "/openapi.json", "/swagger-ui"];
+// need a token with the admin scope
+const ADMIN_PATHS: [&str; 4] = ["/admin/", "/debug/", "/export", "/import"];
+
+// Rejects requests without a configured bearer token and attaches the scopes of the token for the
+// handlers, which check them against the fields they access. Does nothing if no token is configured.
+async fn authenticate(req: ServiceRequest, next: Next<impl MessageBody>) -> Result<ServiceResponse<impl MessageBody>, actix_web::Error> {
+    let config = req.app_data::<web::Data<Arc<ServerConfig>>>()
+        .expect("the server config is registered as app data")
+        .clone();
+    if config.auth_tokens.is_empty() || PUBLIC_PATHS.contains(&req.path()) {
+        return next.call(req).await;
+    }
+    let scopes = req.headers().get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .and_then(|token| config.auth_tokens.get(token.trim()))
+        .ok_or(HolyDiverError::Unauthorized)?;
+    if ADMIN_PATHS.iter().any(|path| req.path().starts_with(path)) {
+        scopes.check_admin(req.path()).map_err(HolyDiverError::Forbidden)?;
+    }
+    req.extensions_mut().insert(scopes);
+    next.call(req).await
+}
+
+const REQUEST_ID_HEADER: &str = "x-request-id";
+
+// Runs the handlers with the X-Request-Id of the request, or a new id if it has none, so that the field
+// changes made by the request carry it, see events::with_request_id. The id is returned in the response.
+async fn assign_request_id(req: ServiceRequest, next: Next<impl MessageBody>) -> Result<ServiceResponse<impl MessageBody>, actix_web::Error> {
+    let request_id = req.headers().get(REQUEST_ID_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .map(str::trim)
+        .filter(|value| !value.is_empty() && value.len() <= 128)
+        .map(str::to_owned)
+        .unwrap_or_else(|| Uuid::new_v4().to_string());
+    let mut response = events::with_request_id(request_id.clone(), next.call(req)).await?;
+    if let Ok(value) = header::HeaderValue::from_str(&request_id) {
+        response.headers_mut().insert(header::HeaderName::from_static(REQUEST_ID_HEADER), value);
+    }
+    Ok(response)
+}
+
+// streamed or transferring whole documents, these take as long as they take
+const UNTIMED_PATHS: [&str; 3] = ["/events", "/export", "/import"];
+
+// How long a request may take, None if it isn't limited. Requests waiting for a member on purpose get that wait
+// on top of the timeout.
+fn route_timeout(req: &ServiceRequest, config: &ServerConfig) -> Option<Duration> {
+    if UNTIMED_PATHS.contains(&req.path()) {
+        return None;
+    }
+    let reads = matches!(*req.method(), Method::GET | Method::HEAD);
+    Some(match req.path() {
+        "/debug/diff" if reads => config.read_timeout + PEER_STATE_TIMEOUT,
+        _ if reads => config.read_timeout,
+        _ if req.query_string().contains("write_concern=replicated") => config.write_timeout + config.write_concern_timeout,
+        _ => config.write_timeout,
+    })
+}
+
+// Answers writes with 503 and Retry-After while the node drains, see shutdown. Reads are still served, as are
+// batch reads, which are POSTed.
+async fn reject_draining_writes(req: ServiceRequest, next: Next<impl MessageBody>) -> Result<ServiceResponse<impl MessageBody>, actix_web::Error> {
+    let config = req.app_data::<web::Data<Arc<ServerConfig>>>()
+        .expect("the server config is registered as app data");
+    let reads = matches!(*req.method(), Method::GET | Method::HEAD | Method::OPTIONS) || req.path() == "/state/get";
+    if !reads && config.shutdown.is_draining() {
+        return Err(HolyDiverError::ShuttingDown.into());
+    }
+    next.call(req).await
+}
+
+// Runs the handlers within the phases of the request, see phases. Requests exceeding the timeout of their
+// route are answered with a RequestTimeout naming the phase they were in, the controller still finishes them.
+// Requests exceeding the slow request threshold are logged with the time they spent in each phase.
+async fn time_request(req: ServiceRequest, next: Next<impl MessageBody>) -> Result<ServiceResponse<impl MessageBody>, actix_web::Error> {
+    let config = req.app_data::<web::Data<Arc<ServerConfig>>>()
+        .expect("the server config is registered as app data")
+        .clone();
+    let timeout = route_timeout(&req, &config);
+    let route = format!("{} {}", req.method(), req.path());
+    let request_phases = RequestPhases::start();
+    let response = phases::scope(request_phases.clone(), next.call(req));
+    let response = match timeout {
+        Some(timeout) => match actix_web::rt::time::timeout(timeout, response).await {
+            Ok(response) => response,
+            Err(_) => {
+                let stage = request_phases.current();
+                warn!("{} timed out after {:?} in the {} phase: {}", route, timeout, stage,
+                    phases::format_breakdown(&request_phases.breakdown()));
+                metrics::REQUEST_TIMEOUTS.with_label_values(&[stage.as_str()]).inc();
+                return Err(HolyDiverError::RequestTimeout { stage, timeout }.into());
+            },
+        },
+        None => response.await,
+    };
+    request_phases.observe();
+    if request_phases.elapsed() >= config.slow_request_threshold {
+        warn!("Slow request {} took {:?}: {}", route, request_phases.elapsed(), phases::format_breakdown(&request_phases.breakdown()));
+    }
+    response
+}
+
+// Responses of fewer bytes are sent as they are, compressing them saves less than it costs
+pub const COMPRESSION_MIN_SIZE: u64 = 1024;
+// the content types of responses that shrink when compressed, e.g. GET /state and /export
+const COMPRESSIBLE_TYPES: [&str; 3] = ["application/json", "application/octet-stream", "text/plain"];
+
+// Leaves Compress only the responses that are worth compressing: those of COMPRESSIBLE_TYPES with at least
+// COMPRESSION_MIN_SIZE bytes, or streamed without a known size like GET /state. Compress encodes the chunks
+// of a stream as they are written, so the listing isn't buffered either. Other responses are marked as
+// identity encoded, which Compress leaves alone, above all /events, whose events have to reach the
+// subscribers one by one. ETags are the same for every encoding.
+async fn select_compression(req: ServiceRequest, next: Next<impl MessageBody>) -> Result<ServiceResponse<impl MessageBody>, actix_web::Error> {
+    // without Accept-Encoding nothing is compressed anyway
+    let negotiated = req.headers().contains_key(header::ACCEPT_ENCODING);
+    let mut response = next.call(req).await?;
+    if negotiated && !response.headers().contains_key(header::CONTENT_ENCODING) && !compresses_well(&response) {
+        response.headers_mut().insert(header::CONTENT_ENCODING, header::HeaderValue::from_static("identity"));
+    }
+    Ok(response)
+}
+
+fn compresses_well(response: &ServiceResponse<impl MessageBody>) -> bool {
+    let compressible = response.headers().get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.split(';').next())
+        .is_some_and(|content_type| COMPRESSIBLE_TYPES.contains(&content_type.trim()));
+    compressible && match response.response().body().size() {
+        BodySize::Sized(size) => size >= COMPRESSION_MIN_SIZE,
+        BodySize::Stream => true,
+        BodySize::None => false,
+    }
+}
+
+// Scopes of the bearer token of a request, None if the API is open
+struct Permissions(Option<Arc<TokenScopes>>);
+
+impl FromRequest for Permissions {
+    type Error = std::convert::Infallible;
+    type Future = std::future::Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _payload: &mut Payload) -> Self::Future {
+        std::future::ready(Ok(Permissions(req.extensions().get::<Arc<TokenScopes>>().cloned())))
+    }
+}
+
+impl Permissions {
+    fn allows(&self, key: &str, access: Access) -> bool {
+        self.0.as_ref().is_none_or(|scopes| scopes.allows(key, access))
+    }
+
+    fn check(&self, key: &str, access: Access) -> Result<(), HolyDiverError> {
+        match &self.0 {
+            Some(scopes) => scopes.check(key, access).map_err(HolyDiverError::Forbidden),
+            None => Ok(()),
+        }
+    }
+
+    // all or nothing, so that a batch is rejected as a whole
+    fn check_all<'a>(&self, keys: impl IntoIterator<Item = &'a str>, access: Access) -> Result<(), HolyDiverError> {
+        keys.into_iter().try_for_each(|key| self.check(key, access))
+    }
+}
+
+/// Liveness check
+#[utoipa::path(responses((status = 200, description = "The server is up", body = String, content_type = "text/plain")))]
 #[get("/hello")]
 async fn hello(req:HttpRequest) -> &'static str {
     info!("REQ: {:?}", req);
     "Hello world!\r\n"
 }
 
+#[derive(Serialize, ToSchema)]
+struct Health {
+    healthy: bool,
+    persistence: PersistenceStatus,
+    // only with --min-members-for-writes
+    #[serde(skip_serializing_if = "Option::is_none")]
+    write_quorum: Option<WriteQuorumStatus>,
+    // how far joining the cluster got, for information only, missing if foca did not answer
+    #[serde(skip_serializing_if = "Option::is_none")]
+    join: Option<JoinState>,
+}
+
+/// Health check, fails while the state can't be written to disk or too few members are up for writes
+///
+/// The node is unhealthy once unhealthy_after_failures writes of the state failed in a row and healthy
+/// again after the next successful write. With `--min-members-for-writes` it is also unhealthy while fewer
+/// members are up, including itself. The join state is reported alongside but does not affect the health,
+/// a node waiting for the first member to announce to it is healthy.
+#[utoipa::path(tag = "admin", responses(
+    (status = 200, description = "The node is healthy", body = Health),
+    (status = 503, description = "Writing the state failed repeatedly or too few members are up for writes", body = Health),
+))]
+#[get("/healthz")]
+async fn get_health(config:web::Data<Arc<ServerConfig>>
+    , controller:web::Data<ControllerHandle>) -> Result<HttpResponse, HolyDiverError> {
+    let persistence = controller.persistence_status().await?;
+    let write_quorum = config.write_quorum.as_ref().map(WriteQuorum::status);
+    let healthy = persistence.consecutive_failures < config.unhealthy_after_failures
+        && write_quorum.as_ref().is_none_or(|status| status.writable);
+    let join = controller.status().await.ok().map(|status| status.join);
+    let mut response = if healthy { HttpResponse::Ok() } else { HttpResponse::ServiceUnavailable() };
+    Ok(response.json(Health { healthy, persistence, write_quorum, join }))
+}
+
+/// Version, commit and build time of this node, with the protocol and automerge versions it uses
+#[utoipa::path(tag = "admin", responses((status = 200, body = BuildInfo)))]
+#[get("/version")]
+async fn get_build_info() -> HttpResponse {
+    HttpResponse::Ok().json(build_info::build_info())
+}
+
+/// Version token of the current document, also returned as ETag
+#[utoipa::path(tag = "state", responses((status = 200, body = VersionInfo)))]
+#[get("/state/_version")]
+async fn get_version(controller:web::Data<ControllerHandle>) -> Result<HttpResponse, HolyDiverError> {
+    let version = controller.version().await?;
+    Ok(HttpResponse::Ok()
+    .insert_header((header::ETAG, format!("\"{}\"", version)))
+    .json(VersionInfo { version }))
+}
+
+/// All fields
+///
+/// The values of a listing are read at the version of the document when the request arrived, so changes merged
+/// while it is streamed don't show up, not even partly. Tombstones and local fields are read separately though,
+/// and local fields, which aren't versioned, are read as they are when their chunk is written. With `consistent`
+/// all sections are read at once, the response is not streamed and its ETag is the version it was read at. The
+/// ETag can be passed as `at` to `GET /state/{field}` to read further fields at the same version.
+#[utoipa::path(tag = "state", params(StateQuery), responses((status = 200, body = StateListing)))]
+#[get("/state")]
+async fn get_state(query:web::Query<StateQuery>
+    , config:web::Data<Arc<ServerConfig>>
+    , permissions: Permissions
+    , controller:web::Data<ControllerHandle>) -> Result<HttpResponse, HolyDiverError> {
+    let hide_invalid = config.hides_invalid_fields(query.include_invalid);
+    if query.consistent {
+        return get_consistent_state(&query, hide_invalid, &permissions, &controller).await;
+    }
+    // checked before the status is sent, the stream can only abort
+    controller.values_readable().await?;
+    let deleted = match query.include_deleted {
+        true => {
+            let mut tombstones = controller.tombstones().await?;
+            tombstones.retain(|key, _| permissions.allows(key, Access::Read) && !(hide_invalid && !field_names::is_valid(key)));
+            Some(tombstones)
+        },
+        false => None,
+    };
+    let local_fields = match query.meta {
+        true => Some(controller.local_field_names().await?),
+        false => None,
+    };
+    let heads = controller.heads().await?;
+    // fields the token can't read are left out instead of failing the whole listing
+    let listing = StateListingStream::new(controller.get_ref().clone(), heads)
+        .readable_by(permissions.0)
+        .with_deleted(deleted)
+        .with_meta(local_fields)
+        .hiding_invalid(hide_invalid);
+    Ok(HttpResponse::Ok()
+    .content_type("application/json")
+    .streaming(listing.into_stream()))
+}
+
+async fn get_consistent_state(query: &StateQuery, hide_invalid: bool, permissions: &Permissions, controller: &ControllerHandle) -> Result<HttpResponse, HolyDiverError> {
+    let mut snapshot = controller.snapshot(query.include_deleted, query.meta).await?;
+    // fields the token can't read are left out instead of failing the whole listing
+    snapshot.values.retain(|key, _| permissions.allows(key, Access::Read) && !(hide_invalid && !field_names::is_valid(key)));
+    if let Some(deleted) = &mut snapshot.deleted {
+        deleted.retain(|key, _| permissions.allows(key, Access::Read) && !(hide_invalid && !field_names::is_valid(key)));
+    }
+    let meta = snapshot.local_fields.map(|local_fields| snapshot.values.keys()
+        .map(|key| (key.clone(), ListedFieldMeta { replicated: !local_fields.contains(key) }))
+        .collect());
+    Ok(HttpResponse::Ok()
+    .insert_header((header::ETAG, format!("\"{}\"", snapshot.version)))
+    .json(StateListing { values: snapshot.values, deleted: snapshot.deleted, meta }))
+}
+
+/// Sorted names of the fields
+#[utoipa::path(tag = "state", params(KeysQuery), responses((status = 200, body = KeyList)))]
+#[get("/state/_keys")]
+async fn get_keys(query:web::Query<KeysQuery>
+    , config:web::Data<Arc<ServerConfig>>
+    , permissions: Permissions
+    , controller:web::Data<ControllerHandle>) -> Result<HttpResponse, HolyDiverError> {
+    let hide_invalid = config.hides_invalid_fields(query.include_invalid);
+    let mut keys = controller.keys_with_prefix(query.prefix.clone()).await?;
+    keys.retain(|key| permissions.allows(key, Access::Read) && !(hide_invalid && !field_names::is_valid(key)));
+    Ok(HttpResponse::Ok().json(KeyList { keys }))
+}
+
+/// Statistics about the replicated document
+#[utoipa::path(tag = "state", responses((status = 200, body = DocStats)))]
+#[get("/state/_stats")]
+async fn get_stats(controller:web::Data<ControllerHandle>) -> Result<HttpResponse, HolyDiverError> {
+    let stats = controller.stats().await?;
+    Ok(HttpResponse::Ok().json(stats))
+}
+
+/// Value constraints by key pattern, empty without a schema file
+#[utoipa::path(tag = "state", responses((status = 200, body = BTreeMap<String, Constraint>)))]
+#[get("/schema")]
+async fn get_schema(controller:web::Data<ControllerHandle>) -> Result<HttpResponse, HolyDiverError> {
+    let rules = controller.validator().await?
+        .map(|v| v.rules())
+        .unwrap_or_default();
+    Ok(HttpResponse::Ok().json(rules))
+}
+
+/// Active cluster members
+///
+/// The ETag changes whenever the member list changes, so that clients can poll with If-None-Match.
+/// With `--strict-identity` the members that did not prove their identity are listed as unverified.
+/// Responses with probes change with every probe and carry no ETag.
+#[utoipa::path(tag = "cluster", params(MembersQuery), responses(
+    (status = 200, body = MemberList),
+    (status = 304, description = "The member list still matches If-None-Match"),
+    (status = 400, description = "Probes were requested but probing is disabled", body = ErrorEnvelope),
+    (status = 503, description = "Foca's command queue is full", body = ErrorEnvelope),
+))]
+#[get("/members")]
+async fn get_members(req:HttpRequest
+    , query:web::Query<MembersQuery>
+    , controller:web::Data<ControllerHandle>) -> Result<HttpResponse, HolyDiverError> {
+    let (members, labels, departures) = (controller.members().await?, controller.member_labels().await?, controller.departures().await?);
+    let unverified = controller.unverified_members().await?;
+    if query.probe {
+        let probes = controller.probes().await?
+            .ok_or_else(|| HolyDiverError::InvalidQuery("probing is disabled, start the node with --probe-interval".to_owned()))?;
+        let inbound = controller.inbound_rates().await?;
+        return Ok(HttpResponse::Ok().json(MemberList { count: members.len(), members, labels, departures, unverified, probes: Some(probes), inbound: Some(inbound) }));
+    }
+    let mut hasher = DefaultHasher::new();
+    members.hash(&mut hasher);
+    labels.hash(&mut hasher);
+    departures.hash(&mut hasher);
+    unverified.hash(&mut hasher);
+    let etag = format!("\"{:016x}\"", hasher.finish());
+    let not_modified = req.headers().get(header::IF_NONE_MATCH)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v == etag);
+    if not_modified {
+        return Ok(HttpResponse::NotModified().insert_header((header::ETAG, etag)).finish());
+    }
+    Ok(HttpResponse::Ok()
+    .insert_header((header::ETAG, etag))
+    .json(MemberList { count: members.len(), members, labels, departures, unverified, probes: None, inbound: None }))
+}
+
+/// Broadcast queue depth and bookkeeping of the broadcasts this node handed to foca
+#[utoipa::path(tag = "cluster", responses(
+    (status = 200, body = FocaStatus),
+    (status = 503, description = "Foca's command queue is full", body = ErrorEnvelope),
+))]
+#[get("/cluster/info")]
+async fn get_cluster_info(controller:web::Data<ControllerHandle>) -> Result<HttpResponse, HolyDiverError> {
+    let status = controller.status().await?;
+    Ok(HttpResponse::Ok().json(status))
+}
+
+/// Checksum of the values of this node and the last ones gossiped by the members
+///
+/// Members whose checksum differed twice in a row are reported as diverged. Requires --checksum-interval
+/// on the members, a node without it still receives and compares the checksums of the others.
+#[utoipa::path(tag = "cluster", responses(
+    (status = 200, body = ConsistencyReport),
+    (status = 503, description = "Foca's command queue is full", body = ErrorEnvelope),
+))]
+#[get("/cluster/consistency")]
+async fn get_consistency(controller:web::Data<ControllerHandle>) -> Result<HttpResponse, HolyDiverError> {
+    let report = controller.consistency().await?;
+    Ok(HttpResponse::Ok().json(report))
+}
+
+/// Server-Sent Events stream of field and member events
+#[utoipa::path(tag = "cluster", params(EventsQuery), responses(
+    (status = 200, description = "field, member and lost_events records", body = String, content_type = "text/event-stream"),
+    (status = 400, description = "Unknown event type", body = ErrorEnvelope),
+))]
+#[get("/events")]
+async fn get_events(query:web::Query<EventsQuery>
+    , config:web::Data<Arc<ServerConfig>>
+    , permissions: Permissions
+    , controller:web::Data<ControllerHandle>) -> Result<HttpResponse, HolyDiverError> {
+    let (mut fields, mut members) = (query.types.is_none(), query.types.is_none());
+    for event_type in query.types.iter().flat_map(|t| t.split(',')) {
+        match event_type.trim() {
+            "field" => fields = true,
+            "member" => members = true,
+            other => return Err(HolyDiverError::InvalidQuery(format!("unknown event type {}, expected field or member", other))),
+        }
+    }
+    let field_events = if fields { Some(controller.subscribe().await?) } else { None };
+    let member_events = members.then(|| config.member_events.subscribe());
+    let mut subscription = EventSubscription::new(field_events, member_events, config.sse_heartbeat);
+    if let Some(scopes) = permissions.0 {
+        subscription = subscription.readable_by(scopes);
+    }
+    if config.hides_invalid_fields(query.include_invalid) {
+        subscription = subscription.hiding_invalid_fields();
+    }
+    Ok(HttpResponse::Ok()
+    .content_type("text/event-stream")
+    .insert_header((header::CACHE_CONTROL, "no-cache"))
+    .streaming(subscription.into_stream()))
+}
+
+/// Actor ids of the document with the address of their writer and their number of changes
+///
+/// Writers are only known for actor ids seen in merged changes or used by this node.
+#[utoipa::path(tag = "cluster", responses((status = 200, body = BTreeMap<String, ActorSummary>)))]
+#[get("/debug/actors")]
+async fn get_actors(controller:web::Data<ControllerHandle>) -> Result<HttpResponse, HolyDiverError> {
+    let actors = controller.actors().await?;
+    Ok(HttpResponse::Ok().json(actors))
+}
+
+/// Whether this node has seen the operation with this id and when
+///
+/// Ids are remembered for --seen-ops-horizon, so an unseen id may also have been forgotten. Together with the
+/// hop trails of --trace-hops this tells how far a broadcast travelled.
+#[utoipa::path(tag = "cluster", params(("id" = String, Path, description = "Id of the operation")), responses((status = 200, body = OperationSighting)))]
+#[get("/debug/operations/{id}")]
+async fn get_operation(id:web::Path<Uuid>
+    , controller:web::Data<ControllerHandle>) -> Result<HttpResponse, HolyDiverError> {
+    let operation_id = id.into_inner();
+    let first_seen = controller.seen_operation(operation_id).await?
+        .and_then(chrono::DateTime::from_timestamp_millis);
+    Ok(HttpResponse::Ok().json(OperationSighting { operation_id, seen: first_seen.is_some(), first_seen }))
+}
+
+/// Operation ids this node remembers as applied, with when they were first seen
+///
+/// Broadcasts of these operations are ignored when they arrive again. Ids are forgotten after --seen-ops-horizon.
+#[utoipa::path(tag = "cluster", responses((status = 200, body = SeenOperationList)))]
+#[get("/debug/seen-ops")]
+async fn get_seen_ops(controller:web::Data<ControllerHandle>) -> Result<HttpResponse, HolyDiverError> {
+    let operations: Vec<SeenOperation> = controller.seen_operations().await?.into_iter()
+        .filter_map(|(operation_id, seen_at)| chrono::DateTime::from_timestamp_millis(seen_at)
+            .map(|first_seen| SeenOperation { operation_id, first_seen }))
+        .collect();
+    Ok(HttpResponse::Ok().json(SeenOperationList { count: operations.len(), operations }))
+}
+
+/// Forgets an operation id, so that the operation is applied again the next time it arrives
+///
+/// Only served with --debug-endpoints. `forgotten` is 0 if the id wasn't remembered.
+#[utoipa::path(tag = "admin", params(("id" = String, Path, description = "Id of the operation")), responses(
+    (status = 200, body = ForgottenOperations),
+    (status = 404, description = "The node was not started with --debug-endpoints", body = ErrorEnvelope),
+))]
+#[delete("/debug/seen-ops/{id}")]
+async fn forget_seen_op(req: HttpRequest, id:web::Path<Uuid>
+    , config:web::Data<Arc<ServerConfig>>
+    , controller:web::Data<ControllerHandle>) -> Result<HttpResponse, HolyDiverError> {
+    if !config.debug_endpoints {
+        return Err(HolyDiverError::RouteNotFound(req.path().to_owned()));
+    }
+    let forgotten = controller.forget_operations(Some(id.into_inner())).await?;
+    Ok(HttpResponse::Ok().json(ForgottenOperations { forgotten }))
+}
+
+/// Forgets all operation ids, so that every operation is applied again the next time it arrives
+///
+/// Only served with --debug-endpoints.
+#[utoipa::path(tag = "admin", responses(
+    (status = 200, body = ForgottenOperations),
+    (status = 404, description = "The node was not started with --debug-endpoints", body = ErrorEnvelope),
+))]
+#[post("/debug/seen-ops/clear")]
+async fn clear_seen_ops(req: HttpRequest
+    , config:web::Data<Arc<ServerConfig>>
+    , controller:web::Data<ControllerHandle>) -> Result<HttpResponse, HolyDiverError> {
+    if !config.debug_endpoints {
+        return Err(HolyDiverError::RouteNotFound(req.path().to_owned()));
+    }
+    let forgotten = controller.forget_operations(None).await?;
+    Ok(HttpResponse::Ok().json(ForgottenOperations { forgotten }))
+}
+
+/// Merged fields whose names break the field name rules, e.g. too long or with control characters
+///
+/// Only served with --sanitize-field-names=quarantine. The fields stay in the document, deleting them would fight
+/// the members writing them. Names are escaped and shortened, `length` is the length of the full name.
+#[utoipa::path(tag = "cluster", responses(
+    (status = 200, body = InvalidFieldReport),
+    (status = 404, description = "The node was not started with --sanitize-field-names=quarantine", body = ErrorEnvelope),
+))]
+#[get("/debug/invalid-fields")]
+async fn get_invalid_fields(req: HttpRequest
+    , config:web::Data<Arc<ServerConfig>>
+    , controller:web::Data<ControllerHandle>) -> Result<HttpResponse, HolyDiverError> {
+    if config.field_sanitation != FieldSanitation::Quarantine {
+        return Err(HolyDiverError::RouteNotFound(req.path().to_owned()));
+    }
+    let report = controller.invalid_fields().await?;
+    Ok(HttpResponse::Ok().json(report))
+}
+
+// how long /debug/diff waits for the document of the member
+const PEER_STATE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Differences between the fields of this node (a) and of a member (b)
+///
+/// The member is asked for its document with a direct message, so only documents that fit into a datagram
+/// can be compared. Larger ones can be compared from their exports with the diff command of the CLI.
+#[utoipa::path(tag = "cluster", params(DiffQuery), responses(
+    (status = 200, body = StateDiff),
+    (status = 404, description = "The peer is no active member", body = ErrorEnvelope),
+    (status = 504, description = "The member did not send its document in time or it does not fit into a datagram", body = ErrorEnvelope),
+))]
+#[get("/debug/diff")]
+async fn get_diff(query:web::Query<DiffQuery>
+    , controller:web::Data<ControllerHandle>) -> Result<HttpResponse, HolyDiverError> {
+    let peer = query.peer;
+    // the controller isn't held while waiting for the member
+    let peer_state = controller.request_peer_state(peer).await?;
+    let unavailable = |reason: String| HolyDiverError::PeerUnavailable { member: peer.to_string(), reason };
+    let peer_state = match actix_web::rt::time::timeout(PEER_STATE_TIMEOUT, peer_state).await {
+        Ok(Ok(state)) if state.is_empty() => return Err(unavailable("its document does not fit into a datagram".to_owned())),
+        Ok(Ok(state)) => state,
+        Ok(Err(_)) => return Err(HolyDiverError::Internal(anyhow::anyhow!("foca is not running anymore"))),
+        Err(_) => return Err(unavailable(format!("it did not send its document within {:?}", PEER_STATE_TIMEOUT))),
+    };
+    let local_state = controller.export().await?;
+    let diff = diff::diff_saved(&local_state, &peer_state)?;
+    Ok(HttpResponse::Ok().json(diff))
+}
+
+fn lease_ttl(request: &LeaseRequest) -> Result<Duration, HolyDiverError> {
+    match request.ttl_secs {
+        0 => Err(HolyDiverError::InvalidJson("ttl_secs must be at least 1".to_owned())),
+        ttl_secs => Ok(Duration::from_secs(ttl_secs)),
+    }
+}
+
+/// Acquires a lease for this node
+///
+/// Succeeds if the lease is free, expired or already held by this node. Leases are only advisory:
+/// nodes that acquire the same lease before they see each other's write both succeed.
+#[utoipa::path(tag = "leases", params(("name" = String, Path, description = "Name of the lease")), request_body = LeaseRequest, responses(
+    (status = 200, body = Lease),
+    (status = 409, description = "Another node holds the lease", body = ErrorEnvelope),
+))]
+#[post("/lease/{name}")]
+async fn acquire_lease(name:web::Path<String>
+    , web::Json(request): web::Json<LeaseRequest>
+    , permissions: Permissions
+    , controller:web::Data<ControllerHandle>) -> Result<HttpResponse, HolyDiverError> {
+    permissions.check(&name, Access::ReadWrite)?;
+    let lease = controller.acquire_lease(name.to_string(), lease_ttl(&request)?).await?;
+    Ok(HttpResponse::Ok().json(lease))
+}
+
+/// Extends a lease held by this node
+#[utoipa::path(tag = "leases", params(("name" = String, Path, description = "Name of the lease")), request_body = LeaseRequest, responses(
+    (status = 200, body = Lease),
+    (status = 409, description = "This node does not hold the lease", body = ErrorEnvelope),
+))]
+#[put("/lease/{name}/renew")]
+async fn renew_lease(name:web::Path<String>
+    , web::Json(request): web::Json<LeaseRequest>
+    , permissions: Permissions
+    , controller:web::Data<ControllerHandle>) -> Result<HttpResponse, HolyDiverError> {
+    permissions.check(&name, Access::ReadWrite)?;
+    let lease = controller.renew_lease(name.to_string(), lease_ttl(&request)?).await?;
+    Ok(HttpResponse::Ok().json(lease))
+}
+
+/// Releases a lease held by this node
+#[utoipa::path(tag = "leases", params(("name" = String, Path, description = "Name of the lease")), responses(
+    (status = 200, description = "The lease was released"),
+    (status = 409, description = "This node does not hold the lease", body = ErrorEnvelope),
+))]
+#[delete("/lease/{name}")]
+async fn release_lease(name:web::Path<String>
+    , permissions: Permissions
+    , controller:web::Data<ControllerHandle>) -> Result<HttpResponse, HolyDiverError> {
+    permissions.check(&name, Access::ReadWrite)?;
+    controller.release_lease(name.to_string()).await?;
+    Ok(HttpResponse::Ok().finish())
+}
+
+/// Prometheus metrics
+#[utoipa::path(responses((status = 200, body = String, content_type = "text/plain; version=0.0.4")))]
+#[get("/metrics")]
+async fn get_metrics(controller:web::Data<ControllerHandle>) -> Result<HttpResponse, HolyDiverError> {
+    // refreshes the document gauges
+    controller.stats().await?;
+    Ok(HttpResponse::Ok()
+    .content_type("text/plain; version=0.0.4")
+    .body(metrics::render()))
+}
+
+/// Value of a field
+///
+/// Returned as `field: value` text unless `meta` is set.
+#[utoipa::path(tag = "state", params(("field" = String, Path, description = "Name of the field"), FieldQuery), responses(
+    (status = 200, description = "The value as text, N/A if the field does not exist, or with its metadata as JSON if meta is set",
+        content(("text/plain" = String), ("application/json" = FieldMeta))),
+    (status = 404, description = "The field does not exist", body = ErrorEnvelope),
+    (status = 410, description = "The version can no longer be resolved", body = ErrorEnvelope),
+))]
 #[get("/state/{field}")]
+#[tracing::instrument(skip_all, fields(field = %field))]
 async fn get_field(field:web::Path<String>
-    , controller:web::Data<Arc<Mutex<HolyDiverController>>>) -> HttpResponse {
-    let field_value = controller.lock().unwrap().get_field(field.to_string());
+    , query:web::Query<FieldQuery>
+    , permissions: Permissions
+    , controller:web::Data<ControllerHandle>) -> Result<HttpResponse, HolyDiverError> {
+    permissions.check(&field, Access::Read)?;
+    if let Some(version) = &query.at {
+        // accept the token as it appears in the quoted ETag header as well
+        let version = version.trim_matches('"');
+        let field_value = match controller.get_field_at(field.to_string(), version.to_owned()).await? {
+            Some(field_value) => field_value,
+            None => return Err(HolyDiverError::VersionGone(version.to_owned())),
+        };
+        return match field_value {
+            Ok(Some(field_value)) => Ok(HttpResponse::Ok().body(format!("{}: {}", field, field_value))),
+            Ok(None) => Err(HolyDiverError::FieldNotFound(field.to_string())),
+            Err(e) if e.is::<DocStructureError>() => Err(e.into()),
+            // the heads are not (or no longer) part of the document
+            Err(_) => Err(HolyDiverError::VersionGone(version.to_owned())),
+        };
+    }
+    if query.meta {
+        let field_meta = controller.get_field_with_meta(field.to_string()).await?;
+        info!("Got field meta: {:?}", field_meta);
+        return match field_meta {
+            Some(field_meta) => Ok(HttpResponse::Ok().json(field_meta)),
+            None => Err(HolyDiverError::FieldNotFound(field.to_string())),
+        };
+    }
+    let (field_value, version) = controller.get_field_versioned(field.to_string()).await?;
     info!("Got field value: {:?}", field_value);
-    HttpResponse::Ok().body(format!("{}: {}", field, field_value.unwrap_or("N/A".to_owned())))
+    Ok(HttpResponse::Ok()
+    .insert_header((header::ETAG, format!("\"{}\"", version)))
+    .body(format!("{}: {}", field, field_value.unwrap_or("N/A".to_owned()))))
+}
+
+/// Values of several fields at once
+#[utoipa::path(tag = "state", request_body = FieldsRequest, responses(
+    (status = 200, body = FieldValues),
+    (status = 400, description = "More keys than max_batch_keys or an invalid body", body = ErrorEnvelope),
+))]
+#[post("/state/get")]
+#[tracing::instrument(skip_all)]
+async fn get_fields(web::Json(request): web::Json<FieldsRequest>
+    , config:web::Data<Arc<ServerConfig>>
+    , permissions: Permissions
+    , controller:web::Data<ControllerHandle>) -> Result<HttpResponse, HolyDiverError> {
+    let max_batch_keys = config.runtime_settings.read().unwrap().max_batch_keys;
+    if request.keys.len() > max_batch_keys {
+        return Err(HolyDiverError::TooManyKeys { requested: request.keys.len(), max: max_batch_keys });
+    }
+    permissions.check_all(request.keys.iter().map(String::as_str), Access::Read)?;
+    let field_values = controller.get_fields(request.keys).await?;
+    Ok(HttpResponse::Ok().json(field_values))
+}
+
+/// Applies several sets, deletes and increments at once
+///
+/// The operations are applied in order as a single change and gossiped with a single broadcast.
+/// Either all of them are applied or none.
+#[utoipa::path(tag = "state", request_body = Vec<BatchOp>, responses(
+    (status = 200, body = BatchSummary),
+    (status = 400, description = "More operations than max_batch_keys or an invalid body", body = ErrorEnvelope),
+    (status = 413, description = "The body is too large", body = ErrorEnvelope),
+    (status = 422, description = "A value violates the schema or an incremented field is no integer", body = ErrorEnvelope),
+    (status = 503, description = "The batch is applied locally but the broadcast queue is full, or rejected while fewer members than --min-members-for-writes are up", body = ErrorEnvelope),
+    (status = 429, description = "The write would exceed the max_keys quota of a key pattern", body = ErrorEnvelope),
+    (status = 507, description = "Rejected with --strict-durability while the state can't be written to disk, or when the write would grow the document beyond --max-doc-size or exceed the max_bytes quota of a key pattern", body = ErrorEnvelope),
+))]
+#[post("/state/batch")]
+#[tracing::instrument(skip_all, fields(ops = ops.len()))]
+async fn apply_batch(web::Json(ops): web::Json<Vec<BatchOp>>
+    , config:web::Data<Arc<ServerConfig>>
+    , permissions: Permissions
+    , controller:web::Data<ControllerHandle>) -> Result<HttpResponse, HolyDiverError> {
+    let max_batch_keys = config.runtime_settings.read().unwrap().max_batch_keys;
+    if ops.len() > max_batch_keys {
+        return Err(HolyDiverError::TooManyKeys { requested: ops.len(), max: max_batch_keys });
+    }
+    permissions.check_all(ops.iter().map(BatchOp::key), Access::ReadWrite)?;
+    let summary = controller.apply_batch(ops).await?;
+    info!("Applied {} batched operations", summary.applied);
+    Ok(HttpResponse::Ok().json(summary))
 }
 
+/// Sets a field and gossips the change
+///
+/// With write_concern=replicated the response waits until another member acknowledged applying the write.
+#[utoipa::path(tag = "state", params(("field" = String, Path, description = "Name of the field"), WriteQuery), request_body = FieldUpdate, responses(
+    (status = 200, description = "The field was written, and acknowledged by another member for write_concern=replicated"),
+    (status = 202, description = "The field was written but no member acknowledged it within the write concern timeout, or it is not replicated", body = WriteWarning),
+    (status = 400, description = "Invalid body", body = ErrorEnvelope),
+    (status = 413, description = "The body is too large", body = ErrorEnvelope),
+    (status = 422, description = "The value violates the schema", body = ErrorEnvelope),
+    (status = 503, description = "The write is applied locally but the broadcast queue is full, or rejected while fewer members than --min-members-for-writes are up", body = ErrorEnvelope),
+    (status = 429, description = "The write would exceed the max_keys quota of a key pattern", body = ErrorEnvelope),
+    (status = 507, description = "Rejected with --strict-durability while the state can't be written to disk, or when the write would grow the document beyond --max-doc-size or exceed the max_bytes quota of a key pattern", body = ErrorEnvelope),
+))]
 #[put("/state/{field}")]
+#[tracing::instrument(skip_all, fields(field = %field))]
 async fn update_field(field:web::Path<String>
+    , query:web::Query<WriteQuery>
     , web::Json(update): web::Json<FieldUpdate>
-    , controller:web::Data<Arc<Mutex<HolyDiverController>>>) -> HttpResponse {
+    , config:web::Data<Arc<ServerConfig>>
+    , permissions: Permissions
+    , controller:web::Data<ControllerHandle>) -> Result<HttpResponse, HolyDiverError> {
+    permissions.check(&field, Access::ReadWrite)?;
         //TODO somehow get foca or another handler here to be able to publish a broadcast
         // would be better to just make a trait for every component and then figure out how to pass things around
-    controller.lock().unwrap().set_field(field.to_string(), update.value).await.unwrap();
-    HttpResponse::Ok().finish()
+    if query.write_concern == WriteConcern::Local {
+        controller.set_field(field.to_string(), update.value).await?;
+        return Ok(HttpResponse::Ok().finish());
+    }
+    if !controller.replicates(field.to_string()).await? {
+        controller.set_field(field.to_string(), update.value).await?;
+        return Ok(HttpResponse::Accepted().json(WriteWarning {
+            warning: "the field matches a no-replicate pattern and is only stored on this node".to_owned(),
+        }));
+    }
+    if envelope::send_version() < ACKED_OPERATION_VERSION {
+        controller.set_field(field.to_string(), update.value).await?;
+        return Ok(HttpResponse::Accepted().json(WriteWarning {
+            warning: format!("members of protocol version {} can't acknowledge writes, the write is gossiped without waiting, see --wire-version",
+                envelope::send_version()),
+        }));
+    }
+    let ack = controller.set_field_replicated(field.to_string(), update.value).await?;
+    let warning = match ack {
+        Some(ack) => match tokio::time::timeout(config.write_concern_timeout, ack).await {
+            Ok(Ok(member)) => {
+                info!("Write of {} was acknowledged by {}", field, member);
+                return Ok(HttpResponse::Ok().finish());
+            },
+            Ok(Err(_)) => "foca stopped before the write was acknowledged".to_owned(),
+            Err(_) => format!("no member acknowledged the write within {:?}", config.write_concern_timeout),
+        },
+        None => "the write exceeded the gossip budget and is only replicated by anti-entropy".to_owned(),
+    };
+    warn!("Write of {} is not confirmed to be replicated: {}", field, warning);
+    Ok(HttpResponse::Accepted().json(WriteWarning { warning }))
+}
+
+/// Concurrently written values of a field, none for a deleted field
+#[utoipa::path(tag = "state", params(("field" = String, Path, description = "Name of the field")), responses(
+    (status = 200, body = Vec<FieldConflict>),
+))]
+#[get("/state/{field}/conflicts")]
+async fn get_conflicts(field:web::Path<String>
+    , permissions: Permissions
+    , controller:web::Data<ControllerHandle>) -> Result<HttpResponse, HolyDiverError> {
+    permissions.check(&field, Access::Read)?;
+    let conflicts = controller.get_conflicts(field.to_string()).await?;
+    Ok(HttpResponse::Ok().json(conflicts))
+}
+
+/// Sets a field back to its previous value
+#[utoipa::path(tag = "state", params(("field" = String, Path, description = "Name of the field")), responses(
+    (status = 200, description = "The field was reverted"),
+    (status = 409, description = "There is no previous value", body = ErrorEnvelope),
+    (status = 422, description = "The previous value violates the schema", body = ErrorEnvelope),
+    (status = 503, description = "The write is applied locally but the broadcast queue is full, or rejected while fewer members than --min-members-for-writes are up", body = ErrorEnvelope),
+    (status = 429, description = "The write would exceed the max_keys quota of a key pattern", body = ErrorEnvelope),
+    (status = 507, description = "Rejected with --strict-durability while the state can't be written to disk, or when the write would grow the document beyond --max-doc-size or exceed the max_bytes quota of a key pattern", body = ErrorEnvelope),
+))]
+#[post("/state/{field}/revert")]
+#[tracing::instrument(skip_all, fields(field = %field))]
+async fn revert_field(field:web::Path<String>
+    , permissions: Permissions
+    , controller:web::Data<ControllerHandle>) -> Result<HttpResponse, HolyDiverError> {
+    permissions.check(&field, Access::ReadWrite)?;
+    match controller.revert_field(field.to_string()).await? {
+        Some(previous_value) => {
+            info!("Reverted field {} to {:?}", field, previous_value);
+            Ok(HttpResponse::Ok().finish())
+        },
+        None => Err(HolyDiverError::NoPreviousValue(field.to_string())),
+    }
+}
+
+/// Deletes a field and gossips the change
+#[utoipa::path(tag = "state", params(("field" = String, Path, description = "Name of the field")), responses(
+    (status = 200, description = "The field was deleted"),
+    (status = 503, description = "The write is applied locally but the broadcast queue is full, or rejected while fewer members than --min-members-for-writes are up", body = ErrorEnvelope),
+    (status = 507, description = "Rejected with --strict-durability while the state can't be written to disk", body = ErrorEnvelope),
+))]
+#[delete("/state/{field}")]
+#[tracing::instrument(skip_all, fields(field = %field))]
+async fn delete_field(field:web::Path<String>
+    , permissions: Permissions
+    , controller:web::Data<ControllerHandle>) -> Result<HttpResponse, HolyDiverError> {
+    permissions.check(&field, Access::ReadWrite)?;
+    controller.delete_field(field.to_string()).await?;
+    Ok(HttpResponse::Ok().finish())
+}
+
+/// Entry of a declared container
+///
+/// Entries of maps are addressed by key, entries of lists by index. The container values is the map of /state.
+/// Returned as `field: value` text like /state/{field}.
+#[utoipa::path(tag = "state", params(("name" = String, Path, description = "Name of the container, see --container"),
+    ("field" = String, Path, description = "Key of the entry, or its index in lists")), responses(
+    (status = 200, description = "The value as text, N/A if the entry does not exist", body = String, content_type = "text/plain"),
+    (status = 400, description = "The entry of a list is not addressed by index", body = ErrorEnvelope),
+    (status = 404, description = "The container is not declared", body = ErrorEnvelope),
+))]
+#[get("/container/{name}/state/{field}")]
+#[tracing::instrument(skip_all, fields(container = %path.0, field = %path.1))]
+async fn get_container_field(path:web::Path<(String, String)>
+    , permissions: Permissions
+    , controller:web::Data<ControllerHandle>) -> Result<HttpResponse, HolyDiverError> {
+    let (container, field) = path.into_inner();
+    permissions.check(&field, Access::Read)?;
+    let field_value = controller.get_container_field(container, field.clone()).await?;
+    Ok(HttpResponse::Ok().body(format!("{}: {}", field, field_value.unwrap_or("N/A".to_owned()))))
+}
+
+/// Sets an entry of a declared container and gossips the change
+///
+/// The index right after the last entry of a list appends an entry. Writes to containers other than values
+/// are not acknowledged, see write_concern of /state/{field}.
+#[utoipa::path(tag = "state", params(("name" = String, Path, description = "Name of the container, see --container"),
+    ("field" = String, Path, description = "Key of the entry, or its index in lists")), request_body = FieldUpdate, responses(
+    (status = 200, description = "The entry was written"),
+    (status = 400, description = "Invalid body, key or index", body = ErrorEnvelope),
+    (status = 404, description = "The container is not declared", body = ErrorEnvelope),
+    (status = 503, description = "The write is applied locally but the broadcast queue is full, or rejected while fewer members than --min-members-for-writes are up", body = ErrorEnvelope),
+    (status = 507, description = "Rejected with --strict-durability while the state can't be written to disk, or when the write would grow the document beyond --max-doc-size", body = ErrorEnvelope),
+))]
+#[put("/container/{name}/state/{field}")]
+#[tracing::instrument(skip_all, fields(container = %path.0, field = %path.1))]
+async fn update_container_field(path:web::Path<(String, String)>
+    , web::Json(update): web::Json<FieldUpdate>
+    , permissions: Permissions
+    , controller:web::Data<ControllerHandle>) -> Result<HttpResponse, HolyDiverError> {
+    let (container, field) = path.into_inner();
+    permissions.check(&field, Access::ReadWrite)?;
+    controller.set_container_field(container, field, update.value).await?;
+    Ok(HttpResponse::Ok().finish())
+}
+
+/// Deletes an entry of a declared container and gossips the change
+///
+/// The following entries of a list move up by one.
+#[utoipa::path(tag = "state", params(("name" = String, Path, description = "Name of the container, see --container"),
+    ("field" = String, Path, description = "Key of the entry, or its index in lists")), responses(
+    (status = 200, description = "The entry was deleted"),
+    (status = 400, description = "The entry of a list is not addressed by index", body = ErrorEnvelope),
+    (status = 404, description = "The container is not declared or the list has no entry at the index", body = ErrorEnvelope),
+    (status = 503, description = "The write is applied locally but the broadcast queue is full, or rejected while fewer members than --min-members-for-writes are up", body = ErrorEnvelope),
+    (status = 507, description = "Rejected with --strict-durability while the state can't be written to disk", body = ErrorEnvelope),
+))]
+#[delete("/container/{name}/state/{field}")]
+#[tracing::instrument(skip_all, fields(container = %path.0, field = %path.1))]
+async fn delete_container_field(path:web::Path<(String, String)>
+    , permissions: Permissions
+    , controller:web::Data<ControllerHandle>) -> Result<HttpResponse, HolyDiverError> {
+    let (container, field) = path.into_inner();
+    permissions.check(&field, Access::ReadWrite)?;
+    controller.delete_container_field(container, field).await?;
+    Ok(HttpResponse::Ok().finish())
+}
+
+/// The whole document as saved automerge bytes
+#[utoipa::path(tag = "state", responses((status = 200, body = Vec<u8>, content_type = "application/octet-stream")))]
+#[get("/export")]
+async fn export_state(controller:web::Data<ControllerHandle>) -> Result<HttpResponse, HolyDiverError> {
+    let state = controller.export().await?;
+    Ok(HttpResponse::Ok()
+    .content_type("application/octet-stream")
+    .body(state))
+}
+
+/// Merges a document exported by any node and gossips the result
+#[utoipa::path(tag = "state", request_body(content = Vec<u8>, content_type = "application/octet-stream"), responses(
+    (status = 200, description = "The document was merged"),
+    (status = 400, description = "Not a valid automerge document", body = ErrorEnvelope),
+    (status = 413, description = "The document is too large", body = ErrorEnvelope),
+    (status = 503, description = "The write is applied locally but the broadcast queue is full, or rejected while fewer members than --min-members-for-writes are up", body = ErrorEnvelope),
+    (status = 507, description = "Rejected with --strict-durability while the state can't be written to disk, or when the write would grow the document beyond --max-doc-size", body = ErrorEnvelope),
+))]
+#[post("/import")]
+#[tracing::instrument(skip_all)]
+async fn import_state(body: web::Bytes
+    , controller:web::Data<ControllerHandle>) -> Result<HttpResponse, HolyDiverError> {
+    controller.import(body.clone()).await?;
+    info!("Imported document of {} bytes", body.len());
+    Ok(HttpResponse::Ok().finish())
+}
+
+/// Current log filter
+#[utoipa::path(tag = "admin", responses((status = 200, body = CurrentLogLevel)))]
+#[get("/admin/log-level")]
+async fn get_log_level() -> HttpResponse {
+    HttpResponse::Ok().json(CurrentLogLevel { filter: logging::current_filter() })
+}
+
+/// Changes the log filter without a restart
+#[utoipa::path(tag = "admin", request_body = LogLevel, responses(
+    (status = 200, body = LogLevel),
+    (status = 400, description = "Invalid filter", body = ErrorEnvelope),
+))]
+#[put("/admin/log-level")]
+async fn update_log_level(web::Json(update): web::Json<LogLevel>) -> Result<HttpResponse, HolyDiverError> {
+    logging::set_filter(&update.filter).map_err(|e| HolyDiverError::InvalidSetting {
+        setting: "filter".to_owned(),
+        message: e.to_string(),
+    })?;
+    warn!("Log filter changed to {}", update.filter);
+    Ok(HttpResponse::Ok().json(update))
+}
+
+/// Settings that can be changed while running
+#[utoipa::path(tag = "admin", responses((status = 200, body = RuntimeSettings)))]
+#[get("/admin/settings")]
+async fn get_settings(config:web::Data<Arc<ServerConfig>>) -> HttpResponse {
+    let settings = config.runtime_settings.read().unwrap().clone();
+    HttpResponse::Ok().json(settings)
+}
+
+/// Changes some of the runtime settings, unknown settings are rejected
+#[utoipa::path(tag = "admin", request_body(content = Object, description = "Settings to change with their new values"), responses(
+    (status = 200, body = RuntimeSettings),
+    (status = 400, description = "Unknown setting or invalid value", body = ErrorEnvelope),
+))]
+#[put("/admin/settings")]
+async fn update_settings(web::Json(changes): web::Json<Map<String, Value>>
+    , config:web::Data<Arc<ServerConfig>>) -> Result<HttpResponse, HolyDiverError> {
+    let settings = {
+        let mut settings = config.runtime_settings.write().unwrap();
+        settings.apply(&changes).map_err(|e| HolyDiverError::InvalidSetting {
+            setting: e.setting,
+            message: e.message,
+        })?;
+        settings.clone()
+    };
+    warn!("Runtime settings changed to {:?}", settings);
+    Ok(HttpResponse::Ok().json(settings))
+}
+
+/// Network faults currently injected by this node, only served with --chaos
+#[utoipa::path(tag = "admin", responses(
+    (status = 200, body = ChaosConfig),
+    (status = 404, description = "The node was not started with --chaos", body = ErrorEnvelope),
+))]
+#[get("/admin/chaos")]
+async fn get_chaos(req: HttpRequest, config:web::Data<Arc<ServerConfig>>) -> Result<HttpResponse, HolyDiverError> {
+    let faults = config.fault_injector.as_ref().ok_or_else(|| HolyDiverError::RouteNotFound(req.path().to_owned()))?;
+    Ok(HttpResponse::Ok().json(faults.config()))
+}
+
+/// Replaces the injected network faults, an empty object heals the node
+///
+/// For testing only: partitions, drops and latency apply to the datagrams of this node, to partition a
+/// cluster the same partitions have to be set on every member.
+#[utoipa::path(tag = "admin", request_body = ChaosConfig, responses(
+    (status = 200, body = ChaosConfig),
+    (status = 400, description = "Invalid probabilities, latencies or partitions", body = ErrorEnvelope),
+    (status = 404, description = "The node was not started with --chaos", body = ErrorEnvelope),
+))]
+#[put("/admin/chaos")]
+async fn update_chaos(req: HttpRequest, web::Json(chaos): web::Json<ChaosConfig>
+    , config:web::Data<Arc<ServerConfig>>) -> Result<HttpResponse, HolyDiverError> {
+    let faults = config.fault_injector.as_ref().ok_or_else(|| HolyDiverError::RouteNotFound(req.path().to_owned()))?;
+    faults.set(chaos).map_err(|message| HolyDiverError::InvalidSetting {
+        setting: "chaos".to_owned(),
+        message,
+    })?;
+    Ok(HttpResponse::Ok().json(faults.config()))
+}
+
+/// Usage of the key patterns with a quota
+///
+/// Quotas are declared with the scopes of --rest-auth-token. `over_quota` is set when merges of remote
+/// changes took a pattern beyond its quota, local writes adding to it are rejected until it shrinks.
+#[utoipa::path(tag = "admin", responses((status = 200, body = Vec<QuotaUsage>)))]
+#[get("/admin/quotas")]
+async fn get_quotas(controller:web::Data<ControllerHandle>) -> Result<HttpResponse, HolyDiverError> {
+    let usage = controller.quota_usage().await?;
+    Ok(HttpResponse::Ok().json(usage))
+}
+
+/// Syncs this node with a single member right away
+///
+/// `full` sends the whole document, `changes` asks the member for its heads and then sends it the
+/// journaled changes it is missing. `session` runs an automerge sync session with the member, resumed from
+/// the sync state of the last one. The sync is only enqueued, the member applies it asynchronously.
+#[utoipa::path(tag = "admin", request_body = SyncRequest, responses(
+    (status = 200, description = "Whether the sync was enqueued, full syncs of documents larger than a datagram are not", body = SyncReport),
+    (status = 404, description = "The target is no active member and force is not set", body = ErrorEnvelope),
+))]
+#[post("/admin/sync")]
+async fn sync_with(web::Json(request): web::Json<SyncRequest>
+    , controller:web::Data<ControllerHandle>) -> Result<HttpResponse, HolyDiverError> {
+    let report = controller.sync_with(request.target, request.mode, request.force).await?;
+    Ok(HttpResponse::Ok().json(report))
+}
+
+/// This document
+#[utoipa::path(responses((status = 200, description = "OpenAPI 3 description of the REST API", body = Object)))]
+#[get("/openapi.json")]
+async fn get_openapi() -> HttpResponse {
+    HttpResponse::Ok().json(openapi())
+}
+
+// Swagger UI page for /openapi.json, the assets are loaded from a CDN by the browser
+#[get("/swagger-ui")]
+async fn swagger_ui() -> HttpResponse {
+    HttpResponse::Ok()
+    .content_type("text/html; charset=utf-8")
+    .body(SWAGGER_UI_PAGE)
+}
+
+const SWAGGER_UI_PAGE: &str = r##"<!DOCTYPE html>
+<html>
+<head>
+  <title>holy-diver API</title>
+  <link rel="stylesheet" href="https://unpkg.com/swagger-ui-dist@5/swagger-ui.css">
+</head>
+<body>
+  <div id="swagger-ui"></div>
+  <script src="https://unpkg.com/swagger-ui-dist@5/swagger-ui-bundle.js"></script>
+  <script>SwaggerUIBundle({ url: "/openapi.json", dom_id: "#swagger-ui" });</script>
+</body>
+</html>
+"##;
+
+#[cfg(feature = "ui")]
+fn configure_ui(cfg: &mut web::ServiceConfig) {
+    crate::swim::ui::configure(cfg);
+}
+
+#[cfg(not(feature = "ui"))]
+fn configure_ui(_cfg: &mut web::ServiceConfig) {}
+
+async fn route_not_found(req: HttpRequest) -> HttpResponse {
+    HolyDiverError::RouteNotFound(req.path().to_owned()).error_response()
 }
 // same as fn host_server<T: HolyDiverController + Send + Sync>(port:u16, controller:&T)
-pub async fn host_server(port: u16, controller: Arc<Mutex<HolyDiverController>>) -> std::io::Result<()> {
-    HttpServer::new(move || {
+pub async fn host_server(port: u16, controller: ControllerHandle) -> anyhow::Result<()> {
+    host_server_with_config(ServerConfig::new(port), controller).await
+}
+
+// Foca is shut down if the REST server can't be started, so that no node is left running without its API.
+pub async fn host_server_with_config(config: ServerConfig, controller: ControllerHandle) -> anyhow::Result<()> {
+    let (_, server) = bind_server_with_config(config, controller).await?;
+    server.await?;
+    Ok(())
+}
+
+// Binds the REST server without running it yet, the returned address has the port actually bound, e.g. the free
+// one picked for port 0. The port is written to the port file of the config and gossiped to the members as the
+// rest_url label of this node. Has to be called from within an actix system, the server runs once awaited.
+pub async fn bind_server_with_config(config: ServerConfig, controller: ControllerHandle) -> anyhow::Result<(SocketAddr, Server)> {
+    let port = config.port;
+    let port_file = config.port_file.clone();
+    let config = Arc::new(config);
+    let (handle_signals, shutdown_timeout) = (config.handle_signals, config.shutdown_timeout);
+    let server_controller = controller.clone();
+    let server = HttpServer::new(move || {
         App::new()
-        .app_data(Data::new(controller.clone()))
+        .wrap(from_fn(time_request))
+        .wrap(from_fn(reject_draining_writes))
+        .wrap(from_fn(authenticate))
+        .wrap(from_fn(assign_request_id))
+        .wrap(Condition::new(config.compression, from_fn(select_compression)))
+        .wrap(Condition::new(config.compression, Compress::default()))
+        .app_data(Data::new(server_controller.clone()))
+        .app_data(Data::new(config.clone()))
+        .app_data(web::JsonConfig::default()
+            .limit(config.max_json_body)
+            .error_handler(json_error_handler))
+        .app_data(web::PayloadConfig::new(config.max_import_body))
         .service(hello)
+        .service(get_health)
+        .service(get_build_info)
+        .service(get_openapi)
+        .configure(|cfg| {
+            if config.enable_swagger {
+                cfg.service(swagger_ui);
+            }
+        })
+        .configure(configure_ui)
+        .service(get_fields)
+        .service(apply_batch)
+        .service(get_version)
+        .service(get_state)
+        .service(get_keys)
+        .service(get_stats)
+        .service(get_metrics)
+        .service(get_members)
+        .service(get_cluster_info)
+        .service(get_consistency)
+        .service(get_actors)
+        .service(get_operation)
+        .service(get_seen_ops)
+        .service(forget_seen_op)
+        .service(clear_seen_ops)
+        .service(get_invalid_fields)
+        .service(get_diff)
+        .service(acquire_lease)
+        .service(renew_lease)
+        .service(release_lease)
+        .service(get_events)
+        .service(get_schema)
+        .service(export_state)
+        .service(import_state)
+        .service(get_log_level)
+        .service(update_log_level)
+        .service(get_settings)
+        .service(update_settings)
+        .service(get_chaos)
+        .service(update_chaos)
+        .service(get_quotas)
+        .service(sync_with)
         .service(get_field)
         .service(update_field)
+        .service(delete_field)
+        .service(revert_field)
+        .service(get_conflicts)
+        .service(get_container_field)
+        .service(update_container_field)
+        .service(delete_container_field)
+        .default_service(web::route().to(route_not_found))
     })
-    .bind(("127.0.0.1", port))?
-    .run()
-    .await
+    .shutdown_timeout(shutdown_timeout.as_secs());
+    let server = match handle_signals {
+        true => server,
+        false => server.disable_signals(),
+    };
+    let server = server.bind(("127.0.0.1", port));
+    let server = match server {
+        Ok(server) => server,
+        Err(e) => {
+            controller.shutdown().await;
+            return Err(StartupError::rest_bind(port, e).into());
+        },
+    };
+    let addr = server.addrs()[0];
+    info!("REST endpoint listening on {}", addr);
+    let advertised = async {
+        if let Some(port_file) = &port_file {
+            std::fs::write(port_file, addr.port().to_string())
+                .with_context(|| format!("could not write the REST port to {}", port_file.display()))?;
+        }
+        controller.advertise_rest_port(addr.port()).await
+    };
+    if let Err(e) = advertised.await {
+        controller.shutdown().await;
+        return Err(e);
+    }
+    Ok((addr, server.run()))
 }
 
 // pub struct HolyDiverRestController {
@@ -83,4 +1587,38 @@ pub async fn host_server(port: u16, controller: Arc<Mutex<HolyDiverController>>)
 //         // self.foca.add_broadcast(broadcast_msg.as_ref())?;
 //         Ok(())
 //     }
-// }
\ No newline at end of file
+// }
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // the routes declared with the route macros of this file, as (method, path)
+    fn declared_routes() -> Vec<(String, String)> {
+        include_str!("server.rs").lines()
+            .filter_map(|line| {
+                let (method, rest) = line.strip_prefix("#[")?.split_once("(\"")?;
+                let path = rest.strip_suffix("\")]")?;
+                ["get", "put", "post", "delete", "patch"].contains(&method).then(|| (method.to_owned(), path.to_owned()))
+            })
+            // the Swagger UI is not part of the API
+            .filter(|(_, path)| path != "/swagger-ui")
+            .collect()
+    }
+
+    #[test]
+    fn the_description_is_openapi_3_and_lists_every_route() {
+        let json = serde_json::to_string(&openapi()).unwrap();
+        let parsed: utoipa::openapi::OpenApi = serde_json::from_str(&json).unwrap();
+        let description: serde_json::Value = serde_json::from_str(&json).unwrap();
+        assert!(description["openapi"].as_str().unwrap().starts_with("3."));
+        assert!(description["components"]["schemas"]["ErrorEnvelope"].is_object());
+
+        let routes = declared_routes();
+        assert!(routes.len() > 40, "only found {} routes", routes.len());
+        let missing: Vec<&(String, String)> = routes.iter()
+            .filter(|(method, path)| description["paths"][path][method].is_null())
+            .collect();
+        assert!(missing.is_empty(), "missing in the description: {:?}", missing);
+        assert_eq!(parsed.paths.paths.len(), description["paths"].as_object().unwrap().len());
+    }
+}
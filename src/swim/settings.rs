@@ -0,0 +1,432 @@
+use std::{
+    fs, net::{SocketAddr, ToSocketAddrs}, num::NonZeroU8, path::{Path, PathBuf}, sync::Arc, time::Duration,
+};
+use anyhow::{anyhow, Context, Result};
+use foca::Config;
+use serde::{Serialize, Serializer};
+
+use super::{audit::{AuditConfig, FsyncPolicy}, auth::{self, AuthTokens}, codec::SwimCodec, conflict_policy::ConflictPolicies, containers::DocSchema, encryption::DataKey, field_names::FieldSanitation, manifest::check_data_dir_owner, migrations::{detect_format, Migrations}, persistence::load_persisted_state, store::FileStore, profile::Profile, runtime_settings::RuntimeSettings, seed::read_seed_file, strict_identity::ClusterKey, types::BumpStrategy, validator::Validator};
+
+/// Everything a node is started with, as given on the command line.
+#[derive(Debug, Clone, Serialize)]
+pub struct NodeSettings {
+    // the defaults of the settings depending on the network, see profile
+    pub profile: Option<Profile>,
+    // settings given explicitly instead of taken from the profile
+    pub overridden: Vec<String>,
+    pub bind_address: String,
+    // defaults to the bind address
+    pub identity: Option<String>,
+    pub announce_to: Option<String>,
+    pub data_dir: PathBuf,
+    pub rest_port: u16,
+    // port of the line based text protocol, not served if None
+    pub text_port: Option<u16>,
+    pub text_idle_timeout_secs: u64,
+    pub seed_file: Option<PathBuf>,
+    pub seed_force: bool,
+    pub schema_file: Option<PathBuf>,
+    // NAME[:TYPE] of the containers of the document besides values, see containers
+    pub containers: Vec<String>,
+    // glob patterns of the fields kept on this node, see local_fields
+    pub no_replicate: Vec<String>,
+    // PATTERN=POLICY of the built-in conflict policies, see conflict_policy
+    pub conflict_policies: Vec<String>,
+    // TOKEN=SCOPES of the REST API, see auth. Only the scopes are serialized
+    #[serde(serialize_with = "redacted")]
+    pub rest_auth_tokens: Vec<String>,
+    pub gossip_budget_percent: usize,
+    pub backup_interval_secs: Option<u64>,
+    pub backup_keep: usize,
+    pub journal_max_entries: usize,
+    pub journal_max_age_secs: u64,
+    pub capture_dir: Option<PathBuf>,
+    pub capture_max_file_size: u64,
+    // append-only log of the field changes applied on this node, see audit
+    pub audit_log: Option<PathBuf>,
+    pub audit_log_max_size: u64,
+    pub audit_log_keep: usize,
+    pub audit_fsync: FsyncPolicy,
+    pub audit_queue_size: usize,
+    // a merge replacing a local write younger than this emits an Overridden event
+    pub override_window_secs: u64,
+    pub log_overrides: bool,
+    // what happens to merged fields whose names break the field name rules, see field_names
+    pub sanitize_field_names: FieldSanitation,
+    // size of the WAL after which a new snapshot is written
+    pub wal_max_size: usize,
+    // reject local writes while the state can't be written
+    pub strict_durability: bool,
+    // local writes growing the document beyond this many bytes are rejected
+    pub max_doc_size: Option<usize>,
+    // local writes are rejected while fewer members are up, this node included, see write_quorum
+    pub min_members_for_writes: usize,
+    // the snapshot and WAL are encrypted with the key in this file, see encryption. Only the path is serialized
+    pub data_encryption_key_file: Option<PathBuf>,
+    // members coming up prove their identity with the key in this file, see strict_identity. Only the path is serialized
+    pub cluster_key_file: Option<PathBuf>,
+    pub strict_identity: bool,
+    // failed writes of the state in a row after which /healthz reports the node as unhealthy
+    pub unhealthy_after_failures: u32,
+    // events a subscriber of /events can fall behind before losing the oldest ones
+    pub event_queue_size: usize,
+    pub sse_heartbeat_secs: u64,
+    // how long writes with write_concern=replicated wait for an acknowledgement
+    pub write_concern_timeout_secs: u64,
+    // REST requests taking longer are answered with 503, see phases
+    pub rest_read_timeout_ms: u64,
+    pub rest_write_timeout_ms: u64,
+    // REST requests taking longer are logged with the time of each phase
+    pub slow_request_ms: u64,
+    pub enable_swagger: bool,
+    // compress large REST responses for clients accepting it
+    pub rest_compression: bool,
+    // serve /admin/chaos to inject network faults, see chaos
+    pub chaos: bool,
+    // seed of the injected faults for configs without one, random if None
+    pub chaos_seed: Option<u64>,
+    // serve the /debug endpoints changing the node, e.g. forgetting seen operations
+    pub debug_endpoints: bool,
+    // minimum time between two state broadcasts of local changes
+    pub broadcast_interval_ms: u64,
+    // pending broadcasts above which a warning is logged after a minute
+    pub broadcast_backlog_warning: usize,
+    // deleted fields keep a tombstone for this long, see tombstones
+    pub tombstone_horizon_secs: u64,
+    // interval of the history assessments, see history
+    pub history_check_interval_secs: u64,
+    // minimum time between two full state replies to the StartupMessages of the same node
+    pub startup_reply_window_secs: u64,
+    // received broadcasts waiting to be merged before further ones are coalesced or dropped
+    pub merge_queue_size: usize,
+    // per-peer datagrams and broadcasts received per second before further ones are dropped, see inbound_limits
+    pub peer_packet_rate: Option<u32>,
+    pub peer_broadcast_rate: Option<u32>,
+    pub swim_codec: SwimCodec,
+    // envelope version of the datagrams sent, older than the current one during rolling upgrades, see envelope
+    pub wire_version: u8,
+    // how the identity picks its bump when foca renews it
+    pub identity_bump: BumpStrategy,
+    // broadcasts carry hop trails, see hops
+    pub trace_hops: bool,
+    // the hop trails of operations whose id starts with this are logged
+    pub trace_operation: Option<String>,
+    // exit if no other member is up this long after announcing, disabled if None
+    pub join_timeout_secs: Option<u64>,
+    // latency probes of the members are sent this often, disabled if None
+    pub probe_interval_secs: Option<u64>,
+    // don't announce to the members remembered in known_peers.json
+    pub forget_peers: bool,
+    // take over a data dir whose manifest records another identity or node id
+    pub adopt_data_dir: bool,
+    // the checksum of the values is gossiped this often, disabled if None
+    pub checksum_interval_secs: Option<u64>,
+    // failure detection of foca, see foca::Config
+    pub swim_probe_period_ms: u64,
+    pub swim_probe_rtt_ms: u64,
+    pub swim_suspect_timeout_ms: u64,
+    // how often a single broadcast is sent
+    pub swim_max_transmissions: u8,
+    pub runtime: RuntimeSettings,
+}
+
+fn redacted<S: Serializer>(specs: &[String], serializer: S) -> std::result::Result<S::Ok, S::Error> {
+    serializer.collect_seq(specs.iter().map(|spec| auth::redact(spec)))
+}
+
+/// Outcome of validating NodeSettings without starting anything.
+#[derive(Debug, Serialize)]
+pub struct CheckReport {
+    pub settings: NodeSettings,
+    pub bind_addr: Option<SocketAddr>,
+    pub identity: Option<SocketAddr>,
+    pub announce_to: Option<SocketAddr>,
+    // size of the persisted document in bytes, if there is one
+    pub persisted_state_size: Option<usize>,
+    pub problems: Vec<String>,
+    // configurations that work but are likely not intended
+    pub warnings: Vec<String>,
+}
+
+impl CheckReport {
+    pub fn passed(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
+
+// Resolves `host:port`, host names are allowed
+pub fn resolve_address(address: &str) -> Result<SocketAddr> {
+    address.to_socket_addrs()
+        .with_context(|| format!("could not resolve {}", address))?
+        .next()
+        .ok_or_else(|| anyhow!("{} did not resolve to any address", address))
+}
+
+impl NodeSettings {
+    pub fn bind_addr(&self) -> Result<SocketAddr> {
+        self.bind_address.parse()
+            .with_context(|| format!("could not parse bind address as SocketAddr '{}'", self.bind_address))
+    }
+
+    pub fn identity_addr(&self) -> Result<SocketAddr> {
+        match &self.identity {
+            Some(identity) => identity.parse()
+                .with_context(|| format!("could not parse identity as SocketAddr '{}'", identity)),
+            None => self.bind_addr(),
+        }
+    }
+
+    pub fn announce_to_addr(&self) -> Result<Option<SocketAddr>> {
+        self.announce_to.as_deref()
+            .map(resolve_address)
+            .transpose()
+    }
+
+    // The settings of instance `index` of a local cluster, see `serve --instances`. Instance i binds to
+    // base_port + i on the host of the bind address, keeps its state in <DATA_DIR>/node-i and serves REST
+    // and the text protocol on their ports + i. All instances but the first announce to the first one.
+    pub fn for_instance(&self, index: u16, base_port: u16) -> Result<NodeSettings> {
+        let offset = |port: u16, what: &str| port.checked_add(index)
+            .ok_or_else(|| anyhow!("the {} port of instance {} exceeds {}", what, index, u16::MAX));
+        let with_port = |addr: SocketAddr, port: u16| SocketAddr::new(addr.ip(), port).to_string();
+        let first_identity = with_port(self.identity_addr()?, base_port);
+        let announce_to = match index {
+            0 => self.announce_to.clone(),
+            _ => Some(first_identity),
+        };
+        let port = offset(base_port, "SWIM")?;
+        let identity = match self.identity {
+            Some(_) => Some(with_port(self.identity_addr()?, port)),
+            None => None,
+        };
+        Ok(NodeSettings {
+            bind_address: with_port(self.bind_addr()?, port),
+            identity,
+            announce_to,
+            data_dir: self.data_dir.join(format!("node-{}", index)),
+            // every instance picks a free port of its own
+            rest_port: match self.rest_port {
+                0 => 0,
+                port => offset(port, "REST")?,
+            },
+            text_port: self.text_port.map(|port| offset(port, "text protocol")).transpose()?,
+            capture_dir: self.capture_dir.as_ref().map(|dir| dir.join(format!("node-{}", index))),
+            audit_log: self.audit_log.as_ref().map(|path| {
+                let dir = path.parent().unwrap_or(Path::new("")).join(format!("node-{}", index));
+                dir.join(path.file_name().unwrap_or_default())
+            }),
+            ..self.clone()
+        })
+    }
+
+    pub fn data_key(&self) -> Result<Option<Arc<DataKey>>> {
+        self.data_encryption_key_file.as_ref()
+            .map(|path| DataKey::from_file(path).map(Arc::new))
+            .transpose()
+    }
+
+    // the key members prove their identity with, None without --strict-identity
+    pub fn cluster_key(&self) -> Result<Option<ClusterKey>> {
+        if !self.strict_identity {
+            return Ok(None);
+        }
+        let path = self.cluster_key_file.as_ref().ok_or_else(|| anyhow!("--strict-identity needs a --cluster-key-file"))?;
+        ClusterKey::from_file(path).map(Some)
+    }
+
+    pub fn audit_config(&self) -> Option<AuditConfig> {
+        self.audit_log.as_ref().map(|path| AuditConfig {
+            path: path.clone(),
+            max_file_size: self.audit_log_max_size,
+            keep: self.audit_log_keep,
+            fsync: self.audit_fsync,
+            queue_size: self.audit_queue_size,
+        })
+    }
+
+    pub fn foca_config(&self) -> Config {
+        let mut config = Config::simple();
+        config.probe_period = Duration::from_millis(self.swim_probe_period_ms);
+        config.probe_rtt = Duration::from_millis(self.swim_probe_rtt_ms);
+        config.suspect_to_down_after = Duration::from_millis(self.swim_suspect_timeout_ms);
+        // With this setting you can suspend (^Z) one process,
+        // wait for it the member to be declared down then resume
+        // it (fg) and foca should recover by itself
+        config.notify_down_members = true;
+        config.max_transmissions = NonZeroU8::new(self.swim_max_transmissions).unwrap_or(NonZeroU8::MIN);
+        config
+    }
+}
+
+// Checks everything that can be checked without binding sockets or joining the cluster.
+// All problems are collected instead of stopping at the first one.
+pub fn validate(settings: &NodeSettings) -> CheckReport {
+    let mut problems = Vec::new();
+    let mut record = |result: Result<()>| if let Err(e) = result {
+        problems.push(format!("{:#}", e));
+    };
+    let bind_addr = settings.bind_addr().map_err(|e| record(Err(e))).ok();
+    let identity = settings.identity_addr().map_err(|e| record(Err(e))).ok();
+    let announce_to = settings.announce_to_addr().map_err(|e| record(Err(e))).ok().flatten();
+    record(check_data_dir_writable(settings));
+    let pending_migrations = check_data_format(settings).map_err(|e| record(Err(e))).ok().unwrap_or_default();
+    let data_key = settings.data_key().map_err(|e| record(Err(e))).ok().flatten();
+    let persisted_state_size = check_persisted_state(settings, data_key.as_deref()).map_err(|e| record(Err(e))).ok().flatten();
+    let foreign_owner = identity
+        .and_then(|identity| check_data_dir_owner(&FileStore::new(&settings.data_dir), identity).map_err(|e| record(Err(e))).ok())
+        .flatten();
+    if let Some(owner) = foreign_owner.as_ref().filter(|_| !settings.adopt_data_dir) {
+        record(Err(anyhow!("{}", owner)));
+    }
+    if let Some(seed_file) = &settings.seed_file {
+        record(read_seed_file(seed_file).map(|_| ()));
+    }
+    if let Some(schema_file) = &settings.schema_file {
+        record(Validator::from_file(schema_file).map(|_| ()));
+    }
+    record(DocSchema::parse(&settings.containers).map(|_| ()));
+    record(ConflictPolicies::parse(&settings.conflict_policies).map(|_| ()));
+    if settings.gossip_budget_percent == 0 || settings.gossip_budget_percent > 100 {
+        record(Err(anyhow!("gossip budget percent must be between 1 and 100, got {}", settings.gossip_budget_percent)));
+    }
+    if settings.text_port == Some(settings.rest_port) {
+        record(Err(anyhow!("the text protocol and REST can't both use port {}", settings.rest_port)));
+    }
+    if settings.text_port.is_some() && settings.text_idle_timeout_secs == 0 {
+        record(Err(anyhow!("the text protocol idle timeout must be at least one second")));
+    }
+    if settings.sse_heartbeat_secs == 0 {
+        record(Err(anyhow!("the /events heartbeat interval must be at least one second")));
+    }
+    if settings.write_concern_timeout_secs == 0 {
+        record(Err(anyhow!("the write concern timeout must be at least one second")));
+    }
+    if settings.rest_read_timeout_ms == 0 || settings.rest_write_timeout_ms == 0 {
+        record(Err(anyhow!("the REST read and write timeouts must be at least a millisecond")));
+    }
+    if settings.tombstone_horizon_secs == 0 {
+        record(Err(anyhow!("the tombstone horizon must be at least one second")));
+    }
+    if settings.history_check_interval_secs == 0 {
+        record(Err(anyhow!("the history check interval must be at least one second")));
+    }
+    if settings.join_timeout_secs == Some(0) {
+        record(Err(anyhow!("the join timeout must be at least one second")));
+    }
+    if settings.probe_interval_secs == Some(0) {
+        record(Err(anyhow!("the probe interval must be at least one second")));
+    }
+    if settings.checksum_interval_secs == Some(0) {
+        record(Err(anyhow!("the checksum interval must be at least one second")));
+    }
+    if settings.swim_probe_rtt_ms == 0 || settings.swim_probe_rtt_ms >= settings.swim_probe_period_ms {
+        record(Err(anyhow!("the SWIM probe round trip time must be positive and shorter than the probe period of {}ms, got {}ms",
+            settings.swim_probe_period_ms, settings.swim_probe_rtt_ms)));
+    }
+    if settings.swim_suspect_timeout_ms == 0 {
+        record(Err(anyhow!("the SWIM suspect timeout must be positive")));
+    }
+    if let Some(audit_log) = settings.audit_log.as_ref().filter(|path| path.file_name().is_none()) {
+        record(Err(anyhow!("the audit log has to be a file, got {}", audit_log.display())));
+    }
+    if settings.max_doc_size == Some(0) {
+        record(Err(anyhow!("the maximum document size must be at least one byte")));
+    }
+    if settings.min_members_for_writes == 0 {
+        record(Err(anyhow!("writes need at least one member, the node itself")));
+    }
+    if settings.merge_queue_size == 0 {
+        record(Err(anyhow!("the merge queue has to hold at least one broadcast")));
+    }
+    if settings.swim_max_transmissions == 0 {
+        record(Err(anyhow!("SWIM broadcasts have to be transmitted at least once")));
+    }
+    record(AuthTokens::parse(&settings.rest_auth_tokens).map(|_| ()));
+    record(settings.cluster_key().map(|_| ()));
+    let mut warnings = match (identity, &announce_to) {
+        (Some(identity), announce_to) => identity_warnings(identity, announce_to.as_ref()),
+        (None, _) => Vec::new(),
+    };
+    if settings.cluster_key_file.is_some() && !settings.strict_identity {
+        warnings.push("the cluster key has no effect without --strict-identity".to_owned());
+    }
+    if settings.join_timeout_secs.is_some() && settings.announce_to.is_none() {
+        warnings.push("the join timeout has no effect without --announce-to".to_owned());
+    }
+    if settings.min_members_for_writes > 1 && settings.announce_to.is_none() {
+        warnings.push(format!("without --announce-to writes are rejected until {} members joined this node", settings.min_members_for_writes));
+    }
+    if !settings.rest_auth_tokens.is_empty() && settings.text_port.is_some() {
+        warnings.push("the text protocol has no authentication, --rest-auth-token only protects the REST API".to_owned());
+    }
+    if settings.no_replicate.iter().any(|pattern| pattern.chars().all(|c| c == '*')) {
+        warnings.push("a --no-replicate pattern matches all fields, none of them are replicated".to_owned());
+    }
+    if let (Some(packets), Some(broadcasts)) = (settings.peer_packet_rate, settings.peer_broadcast_rate) {
+        if packets <= broadcasts {
+            warnings.push("--peer-packet-rate is not above --peer-broadcast-rate, a flood of broadcasts can starve failure detection".to_owned());
+        }
+    }
+    if !pending_migrations.is_empty() {
+        warnings.push(format!("the data dir will be migrated at startup: {}", pending_migrations.join(", ")));
+    }
+    if let Some(owner) = foreign_owner.filter(|_| settings.adopt_data_dir) {
+        warnings.push(format!("the data dir of identity {} with node id {} will be adopted", owner.recorded.identity, owner.recorded.node_id));
+    }
+    if let (Some(size), Some(max)) = (persisted_state_size, settings.max_doc_size) {
+        if size > max {
+            warnings.push(format!("the persisted document of {} bytes exceeds --max-doc-size, local writes will be rejected", size));
+        }
+    }
+    CheckReport {
+        settings: settings.clone(),
+        bind_addr,
+        identity,
+        announce_to,
+        persisted_state_size,
+        problems,
+        warnings,
+    }
+}
+
+// The identity is the address peers use to reach this node, so it has to be dialable from the
+// other members. Binding to 0.0.0.0 is fine, but then the identity has to be set explicitly.
+pub fn identity_warnings(identity: SocketAddr, announce_to: Option<&SocketAddr>) -> Vec<String> {
+    let mut warnings = Vec::new();
+    if identity.ip().is_unspecified() {
+        warnings.push(format!("identity {} is an unspecified address that peers can't reach, \
+            set --advertise-address to the address peers should use", identity));
+    } else if identity.ip().is_loopback() && announce_to.is_some_and(|a| !a.ip().is_loopback()) {
+        warnings.push(format!("identity {} is a loopback address but announcing to the remote address {}, \
+            set --advertise-address to the address peers should use", identity, announce_to.unwrap()));
+    }
+    warnings
+}
+
+fn check_data_dir_writable(settings: &NodeSettings) -> Result<()> {
+    let data_dir = &settings.data_dir;
+    fs::create_dir_all(data_dir)
+        .with_context(|| format!("could not create data dir {}", data_dir.display()))?;
+    let probe = data_dir.join(".write-check");
+    fs::write(&probe, b"")
+        .with_context(|| format!("data dir {} is not writable", data_dir.display()))?;
+    fs::remove_file(&probe)
+        .with_context(|| format!("could not remove {}", probe.display()))
+}
+
+// Migrations the startup would run, without running them. Fails for a dir of a newer version.
+fn check_data_format(settings: &NodeSettings) -> Result<Vec<String>> {
+    let version = match detect_format(&settings.data_dir)? {
+        Some(version) => version,
+        None => return Ok(Vec::new()),
+    };
+    Ok(Migrations::builtin().plan(&settings.data_dir, version)?
+        .iter()
+        .map(|migration| migration.describe().to_owned())
+        .collect())
+}
+
+fn check_persisted_state(settings: &NodeSettings, key: Option<&DataKey>) -> Result<Option<usize>> {
+    Ok(load_persisted_state(&FileStore::new(&settings.data_dir), key)?
+        .map(|(_, info)| info.snapshot_size + info.wal_size))
+}
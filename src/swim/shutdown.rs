@@ -0,0 +1,87 @@
+use std::{sync::{atomic::{AtomicBool, Ordering}, Arc}, time::Duration};
+use anyhow::Result;
+use log::{error, info, warn};
+
+// Stopping a node on SIGTERM or SIGINT, e.g. by a container runtime that kills the process a few seconds later.
+// The node drains first: REST writes are answered with 503 and Retry-After, so that clients retry them on
+// another member, while reads and requests in flight are still served. Then the REST server stops, pending
+// changes are broadcast, the members are told about the leave, and the state, the seen operations and the known
+// peers are written. A node not done within the deadline exits with FORCED_EXIT_CODE, so that supervisors can
+// tell a clean stop from a forced one. SIGHUP calls the reload hook instead, e.g. to reread the log level.
+
+pub const DEFAULT_SHUTDOWN_DEADLINE: Duration = Duration::from_secs(8);
+// sysexits EX_TEMPFAIL, a clean stop exits with 0
+pub const FORCED_EXIT_CODE: i32 = 75;
+// Retry-After of the writes rejected while draining, by then another member should be asked
+pub const DRAIN_RETRY_AFTER_SECS: u64 = 5;
+
+/// Tells the parts of a node that it is shutting down. Clones share the state.
+#[derive(Debug, Clone, Default)]
+pub struct ShutdownCoordinator {
+    draining: Arc<AtomicBool>,
+}
+
+impl ShutdownCoordinator {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // starts draining, returns false if the node was draining already
+    pub fn begin(&self) -> bool {
+        !self.draining.swap(true, Ordering::SeqCst)
+    }
+
+    pub fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::SeqCst)
+    }
+}
+
+// Exits the process with FORCED_EXIT_CODE after the deadline. The timer runs on a thread of its own, so that it
+// fires even if the runtime is stuck, e.g. in a blocking write of the state.
+pub fn exit_after(deadline: Duration) {
+    std::thread::spawn(move || {
+        std::thread::sleep(deadline);
+        error!("Shutdown did not finish within {:?}, exiting with {}", deadline, FORCED_EXIT_CODE);
+        log::logger().flush();
+        std::process::exit(FORCED_EXIT_CODE);
+    });
+}
+
+// resolves with the name of the first SIGTERM or SIGINT received
+#[cfg(unix)]
+pub async fn stop_signal() -> Result<&'static str> {
+    use tokio::signal::unix::{signal, SignalKind};
+    let mut terminate = signal(SignalKind::terminate())?;
+    let mut interrupt = signal(SignalKind::interrupt())?;
+    Ok(tokio::select! {
+        _ = terminate.recv() => "SIGTERM",
+        _ = interrupt.recv() => "SIGINT",
+    })
+}
+
+#[cfg(not(unix))]
+pub async fn stop_signal() -> Result<&'static str> {
+    tokio::signal::ctrl_c().await?;
+    Ok("Ctrl-C")
+}
+
+// Calls reload on every SIGHUP, failures are logged and the node keeps running with what it had
+#[cfg(unix)]
+pub fn spawn_reload_on_hangup(reload: impl Fn() -> Result<()> + Send + 'static) -> Result<()> {
+    use tokio::signal::unix::{signal, SignalKind};
+    let mut hangup = signal(SignalKind::hangup())?;
+    tokio::spawn(async move {
+        while hangup.recv().await.is_some() {
+            info!("Received SIGHUP, reloading");
+            if let Err(e) = reload() {
+                warn!("Could not reload: {:#}", e);
+            }
+        }
+    });
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn spawn_reload_on_hangup(_reload: impl Fn() -> Result<()> + Send + 'static) -> Result<()> {
+    Ok(())
+}
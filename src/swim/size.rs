@@ -0,0 +1,20 @@
+// Parses sizes like `4096`, `512KB`, `4MB` or `1GB`, plain numbers are bytes. Units are powers of 1024
+// and case insensitive, `K`, `M` and `G` work as well. The error is a String so this can be used as a
+// clap value parser.
+pub fn parse_size(input: &str) -> Result<u64, String> {
+    let input = input.trim();
+    let upper = input.to_ascii_uppercase();
+    let without_b = upper.strip_suffix('B').unwrap_or(&upper);
+    let (number, unit) = match without_b.char_indices().last() {
+        Some((i, 'K')) => (&without_b[..i], 1 << 10),
+        Some((i, 'M')) => (&without_b[..i], 1 << 20),
+        Some((i, 'G')) => (&without_b[..i], 1 << 30),
+        Some(_) => (without_b, 1),
+        None => return Err("size must not be empty".to_owned()),
+    };
+    number.trim()
+        .parse::<u64>()
+        .ok()
+        .and_then(|n| n.checked_mul(unit))
+        .ok_or_else(|| format!("invalid size {:?}, expected e.g. 4096, 512KB, 4MB or 1GB", input))
+}
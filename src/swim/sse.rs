@@ -0,0 +1,102 @@
+use std::{convert::Infallible, sync::Arc, time::Duration};
+
+use actix_web::rt::time::{interval_at, Instant, Interval};
+use bytes::Bytes;
+use futures_util::Stream;
+use serde::Serialize;
+use tokio::sync::broadcast::{error::RecvError, Receiver};
+
+use super::{auth::{Access, TokenScopes}, events::{FieldChangeReceiver, MemberEventReceiver}, field_names};
+
+// Server-Sent Events stream of field and member events for /events.
+// Each subscription reads its own broadcast receivers, a client that falls behind by more than
+// the queue size of a channel loses the oldest events and gets a lost_events record instead.
+
+pub const DEFAULT_HEARTBEAT: Duration = Duration::from_secs(15);
+
+pub struct EventSubscription {
+    fields: Option<FieldChangeReceiver>,
+    members: Option<MemberEventReceiver>,
+    heartbeat: Interval,
+    // changes of fields these scopes can't read are skipped, all are streamed if None
+    scopes: Option<Arc<TokenScopes>>,
+    // changes of fields whose names break the field name rules are skipped, see field_names
+    hide_invalid: bool,
+}
+
+fn record<T: Serialize>(event: &str, data: &T) -> Bytes {
+    let data = serde_json::to_string(data).expect("events serialize to JSON");
+    Bytes::from(format!("event: {}\ndata: {}\n\n", event, data))
+}
+
+fn lost_events(event: &str, count: u64) -> Bytes {
+    record("lost_events", &serde_json::json!({ "type": event, "count": count }))
+}
+
+// waits forever on absent or closed channels so that the other sources keep working
+async fn recv<T: Clone>(receiver: &mut Option<Receiver<T>>) -> Result<T, u64> {
+    loop {
+        match receiver {
+            Some(rx) => match rx.recv().await {
+                Ok(event) => return Ok(event),
+                Err(RecvError::Lagged(count)) => return Err(count),
+                Err(RecvError::Closed) => *receiver = None,
+            },
+            None => std::future::pending::<()>().await,
+        }
+    }
+}
+
+impl EventSubscription {
+    // must be created within the actix runtime, the heartbeat uses its timer
+    pub fn new(fields: Option<FieldChangeReceiver>, members: Option<MemberEventReceiver>, heartbeat: Duration) -> Self {
+        // intervals panic on a zero period
+        let heartbeat = heartbeat.max(Duration::from_millis(1));
+        EventSubscription {
+            fields,
+            members,
+            heartbeat: interval_at(Instant::now() + heartbeat, heartbeat),
+            scopes: None,
+            hide_invalid: false,
+        }
+    }
+
+    pub fn readable_by(mut self, scopes: Arc<TokenScopes>) -> Self {
+        self.scopes = Some(scopes);
+        self
+    }
+
+    pub fn hiding_invalid_fields(mut self) -> Self {
+        self.hide_invalid = true;
+        self
+    }
+
+    fn readable(&self, field: &str) -> bool {
+        self.scopes.as_ref().is_none_or(|scopes| scopes.allows(field, Access::Read))
+            && !(self.hide_invalid && !field_names::is_valid(field))
+    }
+
+    async fn next_record(&mut self) -> Bytes {
+        loop {
+            return tokio::select! {
+                change = recv(&mut self.fields) => match change {
+                    Ok(change) if !self.readable(&change.field) => continue,
+                    Ok(change) => record("field", &change),
+                    Err(count) => lost_events("field", count),
+                },
+                event = recv(&mut self.members) => match event {
+                    Ok(event) => record("member", &event),
+                    Err(count) => lost_events("member", count),
+                },
+                _ = self.heartbeat.tick() => Bytes::from_static(b": keep-alive\n\n"),
+            };
+        }
+    }
+
+    pub fn into_stream(self) -> impl Stream<Item = Result<Bytes, Infallible>> {
+        futures_util::stream::unfold(self, |mut subscription| async move {
+            let record = subscription.next_record().await;
+            Some((Ok(record), subscription))
+        })
+    }
+}
@@ -0,0 +1,54 @@
+use std::{
+    fmt::{Display, Formatter}, io, net::SocketAddr,
+};
+
+// exit code of a node that could not join the cluster within --join-timeout
+pub const JOIN_TIMEOUT_EXIT_CODE: i32 = 3;
+
+// Failures while bringing a node up that have a likely cause worth telling the operator about.
+// They are raised inside anyhow errors, callers can downcast to tell them apart.
+#[derive(Debug)]
+pub enum StartupError {
+    UdpAddressInUse(SocketAddr),
+    UdpBind { addr: SocketAddr, source: io::Error },
+    RestAddressInUse(u16),
+    RestBind { port: u16, source: io::Error },
+}
+
+impl StartupError {
+    pub fn udp_bind(addr: SocketAddr, source: io::Error) -> Self {
+        match source.kind() {
+            io::ErrorKind::AddrInUse => StartupError::UdpAddressInUse(addr),
+            _ => StartupError::UdpBind { addr, source },
+        }
+    }
+
+    pub fn rest_bind(port: u16, source: io::Error) -> Self {
+        match source.kind() {
+            io::ErrorKind::AddrInUse => StartupError::RestAddressInUse(port),
+            _ => StartupError::RestBind { port, source },
+        }
+    }
+}
+
+impl Display for StartupError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            StartupError::UdpAddressInUse(addr) => write!(f, "another instance appears to be bound to {}, \
+                if you intend to run two nodes use different --bind-address values", addr),
+            StartupError::UdpBind { addr, source } => write!(f, "could not bind to {}: {}", addr, source),
+            StartupError::RestAddressInUse(port) => write!(f, "another instance appears to serve REST on port {}, \
+                if you intend to run two nodes use different --port values", port),
+            StartupError::RestBind { port, source } => write!(f, "could not serve REST on port {}: {}", port, source),
+        }
+    }
+}
+
+impl std::error::Error for StartupError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            StartupError::UdpBind { source, .. } | StartupError::RestBind { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
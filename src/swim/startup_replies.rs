@@ -0,0 +1,92 @@
+use std::{
+    collections::{HashMap, VecDeque}, time::{Duration, Instant},
+};
+use uuid::Uuid;
+
+use super::clock::WireTimestamp;
+
+// Members answer a StartupMessage with a full state broadcast. A node in a crash loop announces
+// itself on every boot, so besides ignoring repeated (node_id, startup_time) pairs the replies are
+// limited to one per node id and window.
+
+pub const DEFAULT_STARTUP_REPLY_WINDOW: Duration = Duration::from_secs(60);
+
+// upper bound of remembered (node_id, startup_time) pairs, the oldest ones are forgotten first
+const MAX_SEEN_STARTUPS: usize = 10_000;
+
+#[derive(Debug)]
+pub struct StartupReplies {
+    window: Duration,
+    seen: VecDeque<(Uuid, WireTimestamp)>,
+    last_reply: HashMap<Uuid, Instant>,
+}
+
+/// How a received StartupMessage is handled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StartupReply {
+    Reply,
+    // the same startup was announced before
+    Duplicate,
+    // the node was already answered within the window
+    RateLimited,
+}
+
+impl StartupReplies {
+    pub fn new(window: Duration) -> Self {
+        StartupReplies {
+            window,
+            seen: VecDeque::new(),
+            last_reply: HashMap::new(),
+        }
+    }
+
+    pub fn set_window(&mut self, window: Duration) {
+        self.window = window;
+    }
+
+    pub fn check(&mut self, node_id: Uuid, startup_time: WireTimestamp, now: Instant) -> StartupReply {
+        if self.seen.contains(&(node_id, startup_time)) {
+            return StartupReply::Duplicate;
+        }
+        if self.seen.len() >= MAX_SEEN_STARTUPS {
+            self.seen.pop_front();
+        }
+        self.seen.push_back((node_id, startup_time));
+        let window = self.window;
+        self.last_reply.retain(|_, replied_at| now.duration_since(*replied_at) < window);
+        if self.last_reply.contains_key(&node_id) {
+            return StartupReply::RateLimited;
+        }
+        self.last_reply.insert(node_id, now);
+        StartupReply::Reply
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn a_crash_loop_is_answered_once_per_window() {
+        let mut replies = StartupReplies::new(Duration::from_secs(60));
+        let node_id = Uuid::new_v4();
+        let start = Instant::now();
+        let answers: Vec<StartupReply> = (0..3)
+            .map(|boot| replies.check(node_id, WireTimestamp::from_millis(boot), start + Duration::from_secs(boot as u64 * 10)))
+            .collect();
+        assert_eq!(answers, [StartupReply::Reply, StartupReply::RateLimited, StartupReply::RateLimited]);
+
+        assert_eq!(replies.check(node_id, WireTimestamp::from_millis(3), start + Duration::from_secs(61)), StartupReply::Reply);
+    }
+
+    #[test]
+    fn a_repeated_startup_is_a_duplicate_even_after_the_window() {
+        let mut replies = StartupReplies::new(Duration::from_secs(60));
+        let node_id = Uuid::new_v4();
+        let start = Instant::now();
+        assert_eq!(replies.check(node_id, WireTimestamp::from_millis(1), start), StartupReply::Reply);
+        assert_eq!(replies.check(node_id, WireTimestamp::from_millis(1), start + Duration::from_secs(120)), StartupReply::Duplicate);
+        // other nodes aren't limited by it
+        assert_eq!(replies.check(Uuid::new_v4(), WireTimestamp::from_millis(1), start), StartupReply::Reply);
+    }
+}
@@ -0,0 +1,99 @@
+use std::{
+    fs, io::Write, path::{Path, PathBuf}, sync::Arc,
+};
+use anyhow::{Context, Result};
+
+// Everything a node persists goes through a StateStore as bytes by key: the document and its WAL, see
+// persistence, and the small auxiliary files like the seen operation ids, the node id, the actor table,
+// the local fields and the known peers. Each user keeps its own format, a store only has to keep the
+// bytes. FileStore keeps every key in a file of the data dir named like the key, which is the layout
+// data dirs always had.
+
+pub trait StateStore: Send + Sync {
+    // None if nothing was saved under key yet
+    fn load(&self, key: &str) -> Result<Option<Vec<u8>>>;
+    // replaces the bytes of key, a crash leaves either the old or the new bytes
+    fn save(&self, key: &str, bytes: &[u8]) -> Result<()>;
+    // appends to the bytes of key, which are created if missing, durable once this returns
+    fn append(&self, key: &str, bytes: &[u8]) -> Result<()>;
+    fn contains(&self, key: &str) -> bool;
+    // where key is kept, for logs and errors
+    fn describe(&self, key: &str) -> String;
+}
+
+pub type SharedStateStore = Arc<dyn StateStore>;
+
+pub struct FileStore {
+    dir: PathBuf,
+}
+
+impl FileStore {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        FileStore { dir: dir.into() }
+    }
+
+    pub fn shared(dir: impl Into<PathBuf>) -> SharedStateStore {
+        Arc::new(Self::new(dir))
+    }
+
+    pub fn path(&self, key: &str) -> PathBuf {
+        self.dir.join(key)
+    }
+
+    fn create_dir(&self) -> Result<()> {
+        fs::create_dir_all(&self.dir)
+            .with_context(|| format!("could not create data dir {}", self.dir.display()))
+    }
+}
+
+impl StateStore for FileStore {
+    fn load(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        let path = self.path(key);
+        match fs::read(&path) {
+            Ok(bytes) => Ok(Some(bytes)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e).with_context(|| format!("could not read {}", path.display())),
+        }
+    }
+
+    fn save(&self, key: &str, bytes: &[u8]) -> Result<()> {
+        self.create_dir()?;
+        let path = self.path(key);
+        write_atomically(&path, bytes)
+            .with_context(|| format!("could not write {}", path.display()))
+    }
+
+    fn append(&self, key: &str, bytes: &[u8]) -> Result<()> {
+        self.create_dir()?;
+        let path = self.path(key);
+        let mut file = fs::OpenOptions::new()
+            .append(true)
+            .create(true)
+            .open(&path)
+            .with_context(|| format!("could not open {}", path.display()))?;
+        file.write_all(bytes)
+            .and_then(|_| file.sync_data())
+            .with_context(|| format!("could not append to {}", path.display()))
+    }
+
+    fn contains(&self, key: &str) -> bool {
+        self.path(key).exists()
+    }
+
+    fn describe(&self, key: &str) -> String {
+        self.path(key).display().to_string()
+    }
+}
+
+// writes to a temporary file first so that readers never observe a half-written file
+pub fn write_atomically(path: &Path, data: &[u8]) -> std::io::Result<()> {
+    let tmp_path = path.with_extension("tmp");
+    let mut file = fs::OpenOptions::new()
+        .write(true)
+        .truncate(true)
+        .create(true)
+        .open(&tmp_path)?;
+    file.write_all(data)?;
+    file.sync_all()?;
+    fs::rename(&tmp_path, path)
+}
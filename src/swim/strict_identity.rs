@@ -0,0 +1,270 @@
+use std::{
+    collections::{BTreeMap, HashMap}, fmt::{Debug, Display, Formatter}, fs, net::SocketAddr, path::Path, time::{Duration, Instant},
+};
+use anyhow::{anyhow, Context, Result};
+use bytes::{BufMut, Bytes, BytesMut};
+use hmac::{Hmac, Mac};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use zeroize::Zeroizing;
+
+use super::{store::StateStore, types::ID};
+
+// With --strict-identity a member coming up again at an address, e.g. with a renewed identity or after a restart,
+// only counts as the same member once it proved it. Foca takes any identity of an address as the next one of that
+// member (has_same_prefix), so without it a process bound to the address of a member takes over its place.
+//
+// Every node keeps a random session token in <DATA_DIR>/session_token, created when it first starts. Each member
+// coming up is sent an IdentityChallenge with a random nonce, which it answers with an IdentityProof holding its
+// token and an HMAC-SHA256 over nonce and token, keyed with the key of --cluster-key-file. The token of the first
+// proof of an address is pinned, the proofs of later identities at the address have to carry the same one.
+// Members that sent a wrong signature or another token or didn't answer within CHALLENGE_TIMEOUT are logged and
+// reported as unverified by /members until a later proof passes. The token is sent with the proof instead of
+// being part of the ID, whose encoding stays the same for members of older versions.
+
+pub const SESSION_TOKEN_KEY: &str = "session_token";
+pub const TOKEN_SIZE: usize = 16;
+const NONCE_SIZE: usize = 16;
+const SIGNATURE_SIZE: usize = 32;
+// keys shorter than this are refused, e.g. a file holding a placeholder
+const MIN_KEY_SIZE: usize = 16;
+// a member that didn't answer its challenge within this time is unverified
+pub const CHALLENGE_TIMEOUT: Duration = Duration::from_secs(5);
+// signatures of proofs can't be replayed for other protocols using the same key
+const PROOF_CONTEXT: &[u8] = b"holy-diver identity proof";
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Key of --cluster-key-file, shared by all members. Never shown in logs or errors, zeroized when dropped.
+#[derive(Clone)]
+pub struct ClusterKey(Zeroizing<Vec<u8>>);
+
+impl ClusterKey {
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        if bytes.len() < MIN_KEY_SIZE {
+            return Err(anyhow!("a cluster key has to have at least {} bytes, got {}", MIN_KEY_SIZE, bytes.len()));
+        }
+        Ok(ClusterKey(Zeroizing::new(bytes.to_vec())))
+    }
+
+    // The file holds any secret of at least MIN_KEY_SIZE bytes, e.g. as written by `openssl rand -base64 32`.
+    // Whitespace around it is ignored, so that a trailing newline doesn't make members disagree.
+    pub fn from_file(path: &Path) -> Result<Self> {
+        let content = Zeroizing::new(fs::read(path)
+            .with_context(|| format!("could not read the cluster key file {}", path.display()))?);
+        Self::from_bytes(content.trim_ascii())
+            .with_context(|| format!("the cluster key file {} holds no usable key", path.display()))
+    }
+
+    fn sign(&self, nonce: &[u8], token: &[u8]) -> HmacSha256 {
+        let mut mac = HmacSha256::new_from_slice(&self.0).expect("HMAC takes keys of any size");
+        mac.update(PROOF_CONTEXT);
+        mac.update(nonce);
+        mac.update(token);
+        mac
+    }
+}
+
+impl Debug for ClusterKey {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "ClusterKey(<redacted>)")
+    }
+}
+
+// Reads the session token of the store, a missing or invalid token is replaced by a new one. The members that
+// pinned the old one report this node as unverified then.
+pub fn load_or_create_session_token(store: &dyn StateStore) -> Result<[u8; TOKEN_SIZE]> {
+    if let Some(bytes) = store.load(SESSION_TOKEN_KEY)? {
+        match <[u8; TOKEN_SIZE]>::try_from(bytes.as_slice()) {
+            Ok(token) => return Ok(token),
+            Err(_) => warn!("Replacing invalid session token in {}, members that pinned the old one will report \
+                this node as unverified", store.describe(SESSION_TOKEN_KEY)),
+        }
+    }
+    let token: [u8; TOKEN_SIZE] = rand::random();
+    store.save(SESSION_TOKEN_KEY, &token)?;
+    info!("Generated the session token of this node");
+    Ok(token)
+}
+
+/// Why the identity of a member is not verified.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[cfg_attr(feature = "rest", derive(utoipa::ToSchema))]
+#[serde(rename_all = "snake_case")]
+pub enum Unverified {
+    // came up again at an address whose token is pinned, the proof is still outstanding
+    Pending,
+    // did not answer the challenge in time, e.g. because it runs without --strict-identity
+    NoProof,
+    // the proof is not signed with the cluster key
+    InvalidSignature,
+    // the proof is signed but carries another token than the one pinned for the address
+    TokenMismatch,
+}
+
+impl Display for Unverified {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Unverified::Pending => write!(f, "its proof is pending"),
+            Unverified::NoProof => write!(f, "it sent no proof within {:?}", CHALLENGE_TIMEOUT),
+            Unverified::InvalidSignature => write!(f, "its proof is not signed with the cluster key"),
+            Unverified::TokenMismatch => write!(f, "its proof carries another session token than the one pinned for the address"),
+        }
+    }
+}
+
+pub struct StrictIdentity {
+    key: ClusterKey,
+    token: [u8; TOKEN_SIZE],
+    // the token of the first passing proof of every address
+    pinned: HashMap<SocketAddr, [u8; TOKEN_SIZE]>,
+    // the nonce of the last challenge sent to an address, until its proof arrives
+    challenges: HashMap<SocketAddr, ([u8; NONCE_SIZE], Instant)>,
+    unverified: BTreeMap<SocketAddr, Unverified>,
+}
+
+impl StrictIdentity {
+    pub fn new(key: ClusterKey, token: [u8; TOKEN_SIZE]) -> Self {
+        StrictIdentity { key, token, pinned: HashMap::new(), challenges: HashMap::new(), unverified: BTreeMap::new() }
+    }
+
+    // The payload of the IdentityChallenge to send to a member that came up. A member coming up at a pinned
+    // address is pending until its proof arrives.
+    pub fn challenge(&mut self, member: &ID, now: Instant) -> Bytes {
+        let nonce: [u8; NONCE_SIZE] = rand::random();
+        self.challenges.insert(member.addr, (nonce, now));
+        if self.pinned.contains_key(&member.addr) {
+            self.unverified.insert(member.addr, Unverified::Pending);
+        }
+        Bytes::copy_from_slice(&nonce)
+    }
+
+    // The payload of the IdentityProof answering the challenge, None for a malformed one
+    pub fn prove(&self, challenge: &[u8]) -> Option<Bytes> {
+        let nonce: [u8; NONCE_SIZE] = challenge.try_into().ok()?;
+        let mut proof = BytesMut::with_capacity(TOKEN_SIZE + SIGNATURE_SIZE);
+        proof.put_slice(&self.token);
+        proof.put_slice(&self.key.sign(&nonce, &self.token).finalize().into_bytes());
+        Some(proof.freeze())
+    }
+
+    // Checks the proof sent by the member at addr, pinning its token if it is the first one of the address.
+    // Proofs without an outstanding challenge are ignored and return None.
+    pub fn verify(&mut self, addr: SocketAddr, proof: &[u8]) -> Option<Result<(), Unverified>> {
+        let (nonce, _) = self.challenges.remove(&addr)?;
+        let verified = match (proof.get(..TOKEN_SIZE), proof.get(TOKEN_SIZE..).filter(|signature| signature.len() == SIGNATURE_SIZE)) {
+            (Some(token), Some(signature)) if self.key.sign(&nonce, token).verify_slice(signature).is_ok() => {
+                let token: [u8; TOKEN_SIZE] = token.try_into().expect("sliced to the token size");
+                match *self.pinned.entry(addr).or_insert(token) == token {
+                    true => Ok(()),
+                    false => Err(Unverified::TokenMismatch),
+                }
+            },
+            _ => Err(Unverified::InvalidSignature),
+        };
+        match verified {
+            Ok(()) => {
+                if self.unverified.remove(&addr).is_some() {
+                    info!("The identity of member {} is verified again", addr);
+                }
+            },
+            Err(reason) => {
+                warn!("Could not verify the identity of member {}, {}", addr, reason);
+                self.unverified.insert(addr, reason);
+            },
+        }
+        Some(verified)
+    }
+
+    // Marks the members whose challenge is older than CHALLENGE_TIMEOUT as unverified
+    pub fn expire(&mut self, now: Instant) {
+        let expired: Vec<SocketAddr> = self.challenges.iter()
+            .filter(|(_, (_, sent_at))| now.duration_since(*sent_at) > CHALLENGE_TIMEOUT)
+            .map(|(addr, _)| *addr)
+            .collect();
+        for addr in expired {
+            self.challenges.remove(&addr);
+            warn!("Could not verify the identity of member {}, {}", addr, Unverified::NoProof);
+            self.unverified.insert(addr, Unverified::NoProof);
+        }
+    }
+
+    // Forgets the checks of a member that is down, its token stays pinned for when it comes back
+    pub fn member_gone(&mut self, addr: &SocketAddr) {
+        self.challenges.remove(addr);
+        self.unverified.remove(addr);
+    }
+
+    pub fn unverified(&self) -> BTreeMap<SocketAddr, Unverified> {
+        self.unverified.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key() -> ClusterKey {
+        ClusterKey::from_bytes(b"the key of the cluster").unwrap()
+    }
+
+    fn id_at(port: u16, bump: u16) -> ID {
+        ID::with_bump(SocketAddr::from(([127, 0, 0, 1], port)), bump)
+    }
+
+    // the proof the member answers a challenge of verifier with
+    fn answer(verifier: &mut StrictIdentity, member: &StrictIdentity, id: &ID) -> Option<Result<(), Unverified>> {
+        let challenge = verifier.challenge(id, Instant::now());
+        let proof = member.prove(&challenge).unwrap();
+        verifier.verify(id.addr, &proof)
+    }
+
+    #[test]
+    fn a_renewed_identity_with_the_pinned_token_is_verified() {
+        let mut verifier = StrictIdentity::new(key(), rand::random());
+        let member = StrictIdentity::new(key(), rand::random());
+        assert_eq!(answer(&mut verifier, &member, &id_at(9001, 1)), Some(Ok(())));
+
+        // pending until the proof of the renewal arrived
+        let renewed = id_at(9001, 2);
+        let challenge = verifier.challenge(&renewed, Instant::now());
+        assert_eq!(verifier.unverified().get(&renewed.addr), Some(&Unverified::Pending));
+        assert_eq!(verifier.verify(renewed.addr, &member.prove(&challenge).unwrap()), Some(Ok(())));
+        assert!(verifier.unverified().is_empty());
+        // a proof without a challenge is ignored
+        assert_eq!(verifier.verify(renewed.addr, &member.prove(&challenge).unwrap()), None);
+    }
+
+    #[test]
+    fn another_token_at_a_pinned_address_is_flagged() {
+        let mut verifier = StrictIdentity::new(key(), rand::random());
+        let member = StrictIdentity::new(key(), rand::random());
+        assert_eq!(answer(&mut verifier, &member, &id_at(9001, 1)), Some(Ok(())));
+
+        // another process at the address, with the cluster key but without the token of the member
+        let squatter = StrictIdentity::new(key(), rand::random());
+        assert_eq!(answer(&mut verifier, &squatter, &id_at(9001, 2)), Some(Err(Unverified::TokenMismatch)));
+        assert_eq!(verifier.unverified().get(&id_at(9001, 2).addr), Some(&Unverified::TokenMismatch));
+        // without the cluster key it can't sign at all
+        let outsider = StrictIdentity::new(ClusterKey::from_bytes(b"some other cluster key").unwrap(), member.token);
+        assert_eq!(answer(&mut verifier, &outsider, &id_at(9001, 3)), Some(Err(Unverified::InvalidSignature)));
+
+        // the member itself is verified again once it is back
+        assert_eq!(answer(&mut verifier, &member, &id_at(9001, 4)), Some(Ok(())));
+        assert!(verifier.unverified().is_empty());
+    }
+
+    #[test]
+    fn unanswered_challenges_expire() {
+        let mut verifier = StrictIdentity::new(key(), rand::random());
+        let sent_at = Instant::now();
+        verifier.challenge(&id_at(9001, 1), sent_at);
+        verifier.expire(sent_at + CHALLENGE_TIMEOUT);
+        assert!(verifier.unverified().is_empty());
+        verifier.expire(sent_at + CHALLENGE_TIMEOUT + Duration::from_millis(1));
+        assert_eq!(verifier.unverified().get(&id_at(9001, 1).addr), Some(&Unverified::NoProof));
+        verifier.member_gone(&id_at(9001, 1).addr);
+        assert!(verifier.unverified().is_empty());
+    }
+}
@@ -0,0 +1,225 @@
+use std::fmt::{Display, Formatter};
+use automerge::{AutoCommit, AutomergeError, ChangeHash, ObjId, ObjType, ReadDoc, ROOT, transaction::Transactable};
+use log::warn;
+
+use super::{containers::DocSchema, metrics};
+
+// The fields live in the map `values` in the root of the document, next to the maps `values_meta` and
+// `leases` and the containers declared with --container, see containers. Peers running modified code or a
+// misused seed file can gossip documents of another shape, which merge like any other change, so the
+// structure is never assumed. Reads fail with a DocStructureError if the values map is gone, writes
+// recreate it. A map that lost a concurrent put of another value is still read and written, so the fields
+// it holds stay available. Every merge writing to the root is checked for anomalies, which are logged and
+// counted but left in the document.
+
+pub const VALUES_KEY: &str = "values";
+pub const VALUES_META_KEY: &str = "values_meta";
+pub const LEASES_KEY: &str = "leases";
+
+// keys in the root of the document this build writes, all of them maps
+const KNOWN_KEYS: [&str; 3] = [VALUES_KEY, VALUES_META_KEY, LEASES_KEY];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DocStructureError {
+    MissingValues,
+    // the value in place of the values map, e.g. "a str"
+    ValuesNotAMap(String),
+    // automerge failed to read the document
+    Unreadable(String),
+}
+
+impl Display for DocStructureError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DocStructureError::MissingValues => write!(f, "the document has no values map"),
+            DocStructureError::ValuesNotAMap(found) => write!(f, "the values of the document are {} instead of a map", found),
+            DocStructureError::Unreadable(reason) => write!(f, "the document could not be read: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for DocStructureError {}
+
+impl From<AutomergeError> for DocStructureError {
+    fn from(error: AutomergeError) -> Self {
+        DocStructureError::Unreadable(error.to_string())
+    }
+}
+
+/// A deviation from the expected structure found after a merge.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Anomaly {
+    MissingValues,
+    // a known key of the root holding something else than a map
+    NotAMap { key: String, found: String },
+    // the map of a known key conflicts with another value written concurrently
+    Conflicted(String),
+    // a declared container holding something else than its type, see containers
+    WrongContainerType { key: String, found: String },
+    UnexpectedKey(String),
+}
+
+impl Anomaly {
+    // label of the metric
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Anomaly::MissingValues => "missing_values",
+            Anomaly::NotAMap { .. } => "not_a_map",
+            Anomaly::Conflicted(_) => "conflicted",
+            Anomaly::WrongContainerType { .. } => "wrong_container_type",
+            Anomaly::UnexpectedKey(_) => "unexpected_key",
+        }
+    }
+}
+
+impl Display for Anomaly {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Anomaly::MissingValues => write!(f, "the values map is missing"),
+            Anomaly::NotAMap { key, found } => write!(f, "{} is {} instead of a map", key, found),
+            Anomaly::Conflicted(key) => write!(f, "the map {} conflicts with another value written concurrently", key),
+            Anomaly::WrongContainerType { key, found } => write!(f, "the container {} is {} instead of its declared type", key, found),
+            Anomaly::UnexpectedKey(key) => write!(f, "unexpected key {} in the root", key),
+        }
+    }
+}
+
+// The values map for reads. Prefers the winning value of the key but falls back to a map among
+// the conflicting values.
+pub fn values_map(doc: &AutoCommit) -> Result<ObjId, DocStructureError> {
+    values_map_in(doc, None)
+}
+
+// the values map as of heads
+pub fn values_map_at(doc: &AutoCommit, heads: &[ChangeHash]) -> Result<ObjId, DocStructureError> {
+    values_map_in(doc, Some(heads))
+}
+
+fn values_map_in(doc: &AutoCommit, heads: Option<&[ChangeHash]>) -> Result<ObjId, DocStructureError> {
+    let all = get_all_in(doc, VALUES_KEY, heads);
+    if let Some((_, values)) = all.iter().rev().find(|(value, _)| matches!(value, automerge::Value::Object(ObjType::Map))) {
+        return Ok(values.clone());
+    }
+    match all.last() {
+        Some((value, _)) => Err(DocStructureError::ValuesNotAMap(describe(value))),
+        None => Err(DocStructureError::MissingValues),
+    }
+}
+
+// The values map for writes, recreated if it is missing. The fields of a values map that was replaced
+// are not recovered.
+pub fn values_map_mut(doc: &mut AutoCommit) -> Result<ObjId, AutomergeError> {
+    match values_map(doc) {
+        Ok(values) => Ok(values),
+        Err(e) => {
+            warn!("Recreating the values map on write: {}", e);
+            metrics::DOC_STRUCTURE_REPAIRS.inc();
+            doc.put_object(ROOT, VALUES_KEY, ObjType::Map)
+        },
+    }
+}
+
+// the deviations of the root from the expected structure, the containers of schema besides values are expected as well
+pub fn check_structure(doc: &AutoCommit, schema: &DocSchema) -> Vec<Anomaly> {
+    check_structure_in(doc, schema, None)
+}
+
+// the deviations as of heads
+pub fn check_structure_at(doc: &AutoCommit, schema: &DocSchema, heads: &[ChangeHash]) -> Vec<Anomaly> {
+    check_structure_in(doc, schema, Some(heads))
+}
+
+fn check_structure_in(doc: &AutoCommit, schema: &DocSchema, heads: Option<&[ChangeHash]>) -> Vec<Anomaly> {
+    let mut anomalies = Vec::new();
+    for key in KNOWN_KEYS {
+        let all = get_all_in(doc, key, heads);
+        let is_map = |value: &automerge::Value| matches!(value, automerge::Value::Object(ObjType::Map));
+        match all.last() {
+            None if key == VALUES_KEY => anomalies.push(Anomaly::MissingValues),
+            None => {},
+            Some((value, _)) if !is_map(value) => anomalies.push(Anomaly::NotAMap { key: key.to_owned(), found: describe(value) }),
+            // maps created concurrently by nodes that started on their own are no anomaly
+            Some(_) if all.iter().any(|(value, _)| !is_map(value)) => anomalies.push(Anomaly::Conflicted(key.to_owned())),
+            Some(_) => {},
+        }
+    }
+    for container in schema.containers().iter().filter(|container| container.name != VALUES_KEY) {
+        let all = get_all_in(doc, container.name.as_str(), heads);
+        let is_kind = |value: &automerge::Value| matches!(value, automerge::Value::Object(obj_type) if *obj_type == container.kind.obj_type());
+        match all.last() {
+            Some((value, _)) if !is_kind(value) => anomalies.push(Anomaly::WrongContainerType { key: container.name.clone(), found: describe(value) }),
+            Some(_) if all.iter().any(|(value, _)| !is_kind(value)) => anomalies.push(Anomaly::Conflicted(container.name.clone())),
+            _ => {},
+        }
+    }
+    let keys: Vec<String> = match heads {
+        Some(heads) => doc.keys_at(ROOT, heads).collect(),
+        None => doc.keys(ROOT).collect(),
+    };
+    anomalies.extend(keys.into_iter()
+        .filter(|key| !KNOWN_KEYS.contains(&key.as_str()) && !schema.is_declared(key))
+        .map(Anomaly::UnexpectedKey));
+    anomalies
+}
+
+// the values of key in the root, as of heads if given
+fn get_all_in<'a>(doc: &'a AutoCommit, key: &str, heads: Option<&[ChangeHash]>) -> Vec<(automerge::Value<'a>, ObjId)> {
+    match heads {
+        Some(heads) => doc.get_all_at(ROOT, key, heads),
+        None => doc.get_all(ROOT, key),
+    }.unwrap_or_default()
+}
+
+pub fn describe(value: &automerge::Value) -> String {
+    match value {
+        automerge::Value::Object(obj_type) => format!("a {:?}", obj_type).to_lowercase(),
+        automerge::Value::Scalar(scalar) => format!("the scalar {}", scalar),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn with_values() -> AutoCommit {
+        let mut doc = AutoCommit::new();
+        doc.put_object(ROOT, VALUES_KEY, ObjType::Map).unwrap();
+        doc
+    }
+
+    #[test]
+    fn a_well_formed_document_has_no_anomalies() {
+        let mut doc = with_values();
+        doc.put_object(ROOT, LEASES_KEY, ObjType::Map).unwrap();
+        assert!(check_structure(&doc, &DocSchema::default()).is_empty());
+    }
+
+    #[test]
+    fn anomalies_are_found() {
+        let mut doc = AutoCommit::new();
+        assert_eq!(check_structure(&doc, &DocSchema::default()), [Anomaly::MissingValues]);
+        assert_eq!(values_map(&doc), Err(DocStructureError::MissingValues));
+
+        doc.put(ROOT, VALUES_KEY, "oops").unwrap();
+        doc.put(ROOT, VALUES_META_KEY, 1).unwrap();
+        doc.put(ROOT, "stray", true).unwrap();
+        let kinds: Vec<&str> = check_structure(&doc, &DocSchema::default()).iter().map(Anomaly::kind).collect();
+        assert_eq!(kinds, ["not_a_map", "not_a_map", "unexpected_key"]);
+        assert!(matches!(values_map(&doc), Err(DocStructureError::ValuesNotAMap(_))));
+    }
+
+    #[test]
+    fn a_map_conflicting_with_a_scalar_is_still_used() {
+        let mut doc = with_values();
+        let values = values_map(&doc).unwrap();
+        doc.put(&values, "color", "red").unwrap();
+        let mut other = AutoCommit::new();
+        other.put(ROOT, VALUES_KEY, "oops").unwrap();
+        doc.merge(&mut other).unwrap();
+
+        assert_eq!(values_map(&doc), Ok(values));
+        // which of both wins depends on the actor ids
+        let kinds: Vec<&str> = check_structure(&doc, &DocSchema::default()).iter().map(Anomaly::kind).collect();
+        assert!(kinds == ["conflicted"] || kinds == ["not_a_map"], "{:?}", kinds);
+    }
+}
@@ -0,0 +1,233 @@
+use std::{
+    collections::{BTreeMap, HashMap}, net::SocketAddr,
+};
+use anyhow::Result;
+use automerge::sync::State;
+use base64::{engine::general_purpose::STANDARD, Engine};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use super::{clock::WireTimestamp, store::SharedStateStore};
+
+// The automerge sync states of SyncMode::Session, one per peer node id, stored as JSON in
+// <DATA_DIR>/sync_states.json. A session with a member resumes from the state of the last session with it, so
+// the bloom filters of both sides only cover the changes since the heads they shared then instead of the whole
+// history. Automerge only keeps these shared heads in the encoded state, what the peer has and needs is learned
+// again during the session. A member that comes back with another node id, e.g. with a new data dir, gets a new
+// state, and so does one whose state can't be decoded. Beyond the cap the least recently used states are evicted.
+// The messages of a session carry the node id and address of their sender, see SyncPayload.
+
+pub const SYNC_STATES_KEY: &str = "sync_states.json";
+pub const DEFAULT_MAX_SYNC_STATES: usize = 256;
+
+/// Payload of a SyncMessage, bincode encoded.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SyncPayload {
+    pub node_id: Uuid,
+    pub addr: SocketAddr,
+    // the first message of a session, the receiver resumes from its stored state for the sender
+    pub first: bool,
+    // sync::Message::encode
+    pub message: Vec<u8>,
+}
+
+/// The sync states kept for the sessions with other members.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[cfg_attr(feature = "rest", derive(utoipa::ToSchema))]
+pub struct SyncStateStats {
+    pub states: usize,
+    // of the encoded states
+    pub bytes: usize,
+    pub max_states: usize,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct StoredState {
+    // where the peer synced from last, to find its state when a session with the address is started
+    addr: SocketAddr,
+    // State::encode in base64
+    state: String,
+    last_used: i64,
+}
+
+// a session in progress with the member at an address
+struct Session {
+    // None until the first message of the member arrived if its node id was unknown when the session started
+    peer: Option<Uuid>,
+    state: State,
+    last_message: i64,
+}
+
+pub struct SyncStates {
+    store: SharedStateStore,
+    max_states: usize,
+    stored: BTreeMap<Uuid, StoredState>,
+    sessions: HashMap<SocketAddr, Session>,
+}
+
+impl SyncStates {
+    // an unreadable file is replaced, the sessions then start from scratch
+    pub fn load(store: SharedStateStore, max_states: usize) -> Self {
+        let stored = match store.load(SYNC_STATES_KEY) {
+            Ok(Some(data)) => serde_json::from_slice(&data).unwrap_or_else(|e| {
+                warn!("Replacing invalid sync states {}: {}", store.describe(SYNC_STATES_KEY), e);
+                BTreeMap::new()
+            }),
+            Ok(None) => BTreeMap::new(),
+            Err(e) => {
+                warn!("Could not read sync states: {:#}", e);
+                BTreeMap::new()
+            },
+        };
+        SyncStates { store, max_states, stored, sessions: HashMap::new() }
+    }
+
+    // the stored state of peer, a new one if there is none or it can't be decoded
+    fn resume(&self, peer: Uuid) -> State {
+        let Some(stored) = self.stored.get(&peer) else {
+            return State::new();
+        };
+        match STANDARD.decode(&stored.state).map_err(anyhow::Error::from)
+            .and_then(|bytes| State::decode(&bytes).map_err(|e| anyhow::anyhow!("{}", e))) {
+            Ok(state) => state,
+            Err(e) => {
+                warn!("Starting over with the sync state of {}, it can't be decoded: {:#}", peer, e);
+                State::new()
+            },
+        }
+    }
+
+    // Starts a session with the member at addr, resumed from the state of the node that synced from there last.
+    // Returns that node as well, to be kept with the state.
+    pub fn start(&mut self, addr: SocketAddr) -> (Option<Uuid>, State) {
+        self.sessions.remove(&addr);
+        let peer = self.stored.iter().find(|(_, stored)| stored.addr == addr).map(|(peer, _)| *peer);
+        (peer, peer.map_or_else(State::new, |peer| self.resume(peer)))
+    }
+
+    // The state for a message of peer at addr, first if it started a session. A session started with another
+    // node id at the address is started over, the member was replaced.
+    pub fn take(&mut self, peer: Uuid, addr: SocketAddr, first: bool) -> State {
+        match self.sessions.remove(&addr) {
+            Some(Session { peer: Some(expected), .. }) if !first && expected != peer => {
+                info!("{} synced as node {} before and now as {}, starting over", addr, expected, peer);
+                self.resume(peer)
+            },
+            Some(session) if !first => session.state,
+            _ => self.resume(peer),
+        }
+    }
+
+    // Keeps the state of the session with the member at addr for its next message unless the session is done.
+    // The state is stored for peer, replacing those of other nodes that synced from addr before, and only
+    // written if it changed.
+    pub fn keep(&mut self, peer: Option<Uuid>, addr: SocketAddr, state: State, done: bool, now: WireTimestamp) -> Result<()> {
+        let encoded = STANDARD.encode(state.encode());
+        if !done {
+            // sessions whose member stopped answering are never done
+            if self.sessions.len() >= self.max_states {
+                if let Some(oldest) = self.sessions.iter().min_by_key(|(_, session)| session.last_message).map(|(addr, _)| *addr) {
+                    self.sessions.remove(&oldest);
+                }
+            }
+            self.sessions.insert(addr, Session { peer, state, last_message: now.millis() });
+        }
+        let Some(peer) = peer else {
+            return Ok(());
+        };
+        let replaced: Vec<Uuid> = self.stored.iter()
+            .filter(|(node, stored)| **node != peer && stored.addr == addr)
+            .map(|(node, _)| *node)
+            .collect();
+        let changed = !replaced.is_empty()
+            || self.stored.get(&peer).is_none_or(|stored| stored.addr != addr || stored.state != encoded);
+        for node in replaced {
+            self.stored.remove(&node);
+        }
+        self.stored.insert(peer, StoredState { addr, state: encoded, last_used: now.millis() });
+        while self.stored.len() > self.max_states {
+            let Some(evicted) = self.stored.iter().min_by_key(|(_, stored)| stored.last_used).map(|(node, _)| *node) else {
+                break;
+            };
+            info!("Evicting the sync state of {}, more than {} are kept", evicted, self.max_states);
+            self.stored.remove(&evicted);
+        }
+        if !changed {
+            return Ok(());
+        }
+        let data = serde_json::to_vec_pretty(&self.stored)?;
+        self.store.save(SYNC_STATES_KEY, &data)
+    }
+
+    pub fn stats(&self) -> SyncStateStats {
+        SyncStateStats {
+            states: self.stored.len(),
+            bytes: self.stored.values().map(|stored| STANDARD.decode(&stored.state).map_or(0, |state| state.len())).sum(),
+            max_states: self.max_states,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::TempDir;
+    use super::*;
+    use crate::swim::store::FileStore;
+
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], port))
+    }
+
+    fn at(millis: i64) -> WireTimestamp {
+        WireTimestamp::from_millis(millis)
+    }
+
+    #[test]
+    fn states_are_kept_by_node_id_and_evicted_beyond_the_cap() {
+        let dir = TempDir::new().unwrap();
+        let mut states = SyncStates::load(FileStore::shared(dir.path()), 2);
+        let (first, second, third) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+        for (millis, (peer, port)) in [(first, 9001), (second, 9002), (third, 9003)].into_iter().enumerate() {
+            let state = states.take(peer, addr(port), true);
+            states.keep(Some(peer), addr(port), state, true, at(millis as i64)).unwrap();
+        }
+        assert_eq!(states.stats().states, 2);
+
+        // the least recently used one was evicted, the others are found by their address after a restart
+        let mut states = SyncStates::load(FileStore::shared(dir.path()), 2);
+        assert!(!states.stored.contains_key(&first));
+        assert_eq!(states.start(addr(9002)).0, Some(second));
+        assert_eq!(states.start(addr(9001)).0, None);
+    }
+
+    #[test]
+    fn a_member_with_another_node_id_replaces_the_state_of_its_address() {
+        let dir = TempDir::new().unwrap();
+        let mut states = SyncStates::load(FileStore::shared(dir.path()), 8);
+        let (before, after) = (Uuid::new_v4(), Uuid::new_v4());
+        let state = states.take(before, addr(9001), true);
+        states.keep(Some(before), addr(9001), state, true, at(0)).unwrap();
+
+        let (expected, state) = states.start(addr(9001));
+        assert_eq!(expected, Some(before));
+        states.keep(expected, addr(9001), state, false, at(1)).unwrap();
+        let state = states.take(after, addr(9001), false);
+        states.keep(Some(after), addr(9001), state, true, at(2)).unwrap();
+        assert_eq!(states.stored.keys().copied().collect::<Vec<_>>(), vec![after]);
+        assert!(states.sessions.is_empty());
+    }
+
+    #[test]
+    fn undecodable_states_are_started_over() {
+        let dir = TempDir::new().unwrap();
+        let store = FileStore::shared(dir.path());
+        let peer = Uuid::new_v4();
+        let stored = BTreeMap::from([(peer, StoredState { addr: addr(9001), state: STANDARD.encode(b"garbage"), last_used: 0 })]);
+        store.save(SYNC_STATES_KEY, &serde_json::to_vec(&stored).unwrap()).unwrap();
+
+        let mut states = SyncStates::load(store, 8);
+        let (_, state) = states.start(addr(9001));
+        assert_eq!(state.encode(), State::new().encode());
+    }
+}
@@ -0,0 +1,58 @@
+// Trace context propagation between nodes. Spans are only exported when the crate is built
+// with the otlp feature and init_otlp was called, otherwise all of this is a no-op and
+// logging works as before.
+
+#[cfg(feature = "otlp")]
+mod otlp {
+    use std::collections::HashMap;
+
+    use anyhow::Result;
+    use opentelemetry::{global, KeyValue};
+    use opentelemetry_otlp::WithExportConfig;
+    use opentelemetry_sdk::{propagation::TraceContextPropagator, trace, Resource};
+    use tracing_opentelemetry::OpenTelemetrySpanExt;
+    use tracing_subscriber::layer::SubscriberExt;
+
+    const TRACEPARENT: &str = "traceparent";
+
+    // Exports all spans to an OTLP/HTTP collector, e.g. http://localhost:4318 for Jaeger
+    pub fn init_otlp(endpoint: &str) -> Result<()> {
+        global::set_text_map_propagator(TraceContextPropagator::new());
+        let tracer = opentelemetry_otlp::new_pipeline()
+            .tracing()
+            .with_exporter(opentelemetry_otlp::new_exporter().http().with_endpoint(endpoint))
+            .with_trace_config(trace::config()
+                .with_resource(Resource::new(vec![KeyValue::new("service.name", "holy-diver")])))
+            .install_simple()?;
+        let subscriber = tracing_subscriber::registry()
+            .with(tracing_opentelemetry::layer().with_tracer(tracer));
+        tracing::subscriber::set_global_default(subscriber)?;
+        Ok(())
+    }
+
+    pub fn current_trace_context() -> Option<String> {
+        let context = tracing::Span::current().context();
+        let mut carrier = HashMap::new();
+        global::get_text_map_propagator(|p| p.inject_context(&context, &mut carrier));
+        carrier.remove(TRACEPARENT)
+    }
+
+    pub fn set_remote_parent(span: &tracing::Span, trace_context: &str) {
+        let carrier = HashMap::from([(TRACEPARENT.to_owned(), trace_context.to_owned())]);
+        let context = global::get_text_map_propagator(|p| p.extract(&carrier));
+        span.set_parent(context);
+    }
+}
+
+#[cfg(feature = "otlp")]
+pub use otlp::{current_trace_context, init_otlp, set_remote_parent};
+
+// W3C traceparent of the current span, None if spans are not exported
+#[cfg(not(feature = "otlp"))]
+pub fn current_trace_context() -> Option<String> {
+    None
+}
+
+// continues the trace of another node in the given span
+#[cfg(not(feature = "otlp"))]
+pub fn set_remote_parent(_span: &tracing::Span, _trace_context: &str) {}
@@ -0,0 +1,258 @@
+use std::{net::SocketAddr, sync::Arc, time::Duration};
+
+use anyhow::{Context, Result};
+use log::{debug, info, warn};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::{TcpListener, TcpStream},
+    sync::Semaphore,
+};
+
+use super::{error::HolyDiverError, field_names, handle::ControllerHandle};
+
+// Line based protocol for clients that can't comfortably speak HTTP, usable with netcat:
+//
+//   GET key          -> OK value | NIL
+//   SET key value    -> OK          (the value is the rest of the line and may contain spaces)
+//   DEL key          -> OK
+//   KEYS prefix      -> OK key1 key2 ...
+//   MEMBERS          -> OK 127.0.0.1:9000 127.0.0.1:9001 ...
+//
+// Failures are answered with ERR message. Backslashes, line feeds and carriage returns in values
+// and messages are escaped as \\, \n and \r, SET values are unescaped the same way.
+
+pub struct TextServerConfig {
+    pub port: u16,
+    // connections without a complete command for this long are closed
+    pub idle_timeout: Duration,
+    // longest accepted command line in bytes, longer lines close the connection
+    pub max_line_length: usize,
+    // further clients are rejected with an error
+    pub max_connections: usize,
+    // KEYS leaves out merged fields whose names break the field name rules, e.g. names containing line feeds
+    // would break the reply, see field_names
+    pub hide_invalid_fields: bool,
+}
+
+impl TextServerConfig {
+    pub fn new(port: u16) -> Self {
+        TextServerConfig {
+            port,
+            idle_timeout: Duration::from_secs(60),
+            max_line_length: 64 * 1024,
+            max_connections: 64,
+            hide_invalid_fields: false,
+        }
+    }
+}
+
+pub struct TextServer {
+    listener: TcpListener,
+    config: Arc<TextServerConfig>,
+    connections: Arc<Semaphore>,
+}
+
+#[derive(Debug)]
+enum Command {
+    Get(String),
+    Set(String, String),
+    Del(String),
+    Keys(String),
+    Members,
+}
+
+enum Reply {
+    Ok(Option<String>),
+    Nil,
+    Err(String),
+}
+
+impl Reply {
+    fn to_line(&self) -> String {
+        match self {
+            Reply::Ok(Some(value)) => format!("OK {}\n", escape(value)),
+            Reply::Ok(None) => "OK\n".to_owned(),
+            Reply::Nil => "NIL\n".to_owned(),
+            Reply::Err(message) => format!("ERR {}\n", escape(message)),
+        }
+    }
+}
+
+fn escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+fn unescape(value: &str) -> std::result::Result<String, String> {
+    let mut unescaped = String::with_capacity(value.len());
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            unescaped.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('\\') => unescaped.push('\\'),
+            Some('n') => unescaped.push('\n'),
+            Some('r') => unescaped.push('\r'),
+            Some(other) => return Err(format!("unknown escape sequence \\{}", other)),
+            None => return Err("value ends with a single backslash".to_owned()),
+        }
+    }
+    Ok(unescaped)
+}
+
+fn key(argument: &str) -> std::result::Result<String, String> {
+    match argument {
+        "" => Err("missing key".to_owned()),
+        key if key.contains(' ') => Err("keys must not contain spaces".to_owned()),
+        key => Ok(key.to_owned()),
+    }
+}
+
+fn parse(line: &str) -> std::result::Result<Command, String> {
+    let (command, argument) = line.split_once(' ').unwrap_or((line, ""));
+    match command.to_ascii_uppercase().as_str() {
+        "GET" => key(argument).map(Command::Get),
+        "DEL" => key(argument).map(Command::Del),
+        "SET" => {
+            let (field, value) = argument.split_once(' ').ok_or_else(|| "missing value".to_owned())?;
+            Ok(Command::Set(key(field)?, unescape(value)?))
+        },
+        "KEYS" => Ok(Command::Keys(argument.to_owned())),
+        "MEMBERS" if argument.is_empty() => Ok(Command::Members),
+        "MEMBERS" => Err("MEMBERS takes no arguments".to_owned()),
+        "" => Err("empty command".to_owned()),
+        other => Err(format!("unknown command {}", other)),
+    }
+}
+
+// the messages of HolyDiverError, e.g. for schema violations, are meant for clients
+fn error_reply(error: anyhow::Error) -> Reply {
+    Reply::Err(HolyDiverError::from(error).to_string())
+}
+
+async fn execute(command: Command, controller: &ControllerHandle, config: &TextServerConfig) -> Reply {
+    match command {
+        Command::Get(field) => match controller.get_field(field).await {
+            Ok(Some(value)) => Reply::Ok(Some(value)),
+            Ok(None) => Reply::Nil,
+            Err(e) => error_reply(e),
+        },
+        Command::Set(field, value) => match controller.set_field(field, value).await {
+            Ok(()) => Reply::Ok(None),
+            Err(e) => error_reply(e),
+        },
+        Command::Del(field) => match controller.delete_field(field).await {
+            Ok(()) => Reply::Ok(None),
+            Err(e) => error_reply(e),
+        },
+        Command::Keys(prefix) => match controller.keys_with_prefix(prefix).await {
+            Ok(mut keys) => {
+                if config.hide_invalid_fields {
+                    keys.retain(|key| field_names::is_valid(key));
+                }
+                Reply::Ok(Some(keys.join(" ")))
+            },
+            Err(e) => error_reply(e),
+        },
+        Command::Members => match controller.members().await {
+            Ok(members) => Reply::Ok(Some(members.iter().map(|m| m.to_string()).collect::<Vec<_>>().join(" "))),
+            Err(e) => error_reply(e),
+        },
+    }
+}
+
+async fn handle_connection(stream: TcpStream, controller: ControllerHandle, config: Arc<TextServerConfig>) -> Result<()> {
+    let (read, mut write) = stream.into_split();
+    let mut reader = BufReader::new(read);
+    let mut line = Vec::new();
+    loop {
+        line.clear();
+        // reading at most one byte more than allowed bounds the buffer for lines without a line feed
+        let limit = config.max_line_length as u64 + 1;
+        let read = tokio::time::timeout(config.idle_timeout, (&mut reader).take(limit).read_until(b'\n', &mut line)).await;
+        let read = match read {
+            Ok(read) => read?,
+            Err(_) => {
+                write.write_all(Reply::Err("idle timeout".to_owned()).to_line().as_bytes()).await?;
+                return Ok(());
+            },
+        };
+        if read == 0 {
+            return Ok(());
+        }
+        if line.last() == Some(&b'\n') {
+            line.pop();
+        } else if line.len() > config.max_line_length {
+            // the rest of the line can't be told apart from the next command, so the connection is closed
+            write.write_all(Reply::Err(format!("line exceeds {} bytes", config.max_line_length)).to_line().as_bytes()).await?;
+            return Ok(());
+        }
+        if line.last() == Some(&b'\r') {
+            line.pop();
+        }
+        let reply = match std::str::from_utf8(&line) {
+            Ok(command) => match parse(command) {
+                Ok(command) => execute(command, &controller, &config).await,
+                Err(message) => Reply::Err(message),
+            },
+            Err(_) => Reply::Err("commands must be UTF-8".to_owned()),
+        };
+        write.write_all(reply.to_line().as_bytes()).await?;
+    }
+}
+
+impl TextServer {
+    pub async fn bind(config: TextServerConfig) -> Result<Self> {
+        let listener = TcpListener::bind(("127.0.0.1", config.port)).await
+            .with_context(|| format!("could not serve the text protocol on port {}", config.port))?;
+        Ok(TextServer {
+            listener,
+            connections: Arc::new(Semaphore::new(config.max_connections)),
+            config: Arc::new(config),
+        })
+    }
+
+    // the address actually bound, e.g. the free port picked for port 0
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(self.listener.local_addr()?)
+    }
+
+    // accepts clients until the listener fails, every client is handled by its own task
+    pub async fn run(self, controller: ControllerHandle) {
+        info!("Serving the text protocol on port {}", self.config.port);
+        loop {
+            let (mut stream, peer) = match self.listener.accept().await {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    warn!("Could not accept text protocol connection: {}", e);
+                    continue;
+                },
+            };
+            let permit = match self.connections.clone().try_acquire_owned() {
+                Ok(permit) => permit,
+                Err(_) => {
+                    let _ignored_write_error = stream.write_all(Reply::Err("too many connections".to_owned()).to_line().as_bytes()).await;
+                    continue;
+                },
+            };
+            let controller = controller.clone();
+            let config = self.config.clone();
+            tokio::spawn(async move {
+                if let Err(e) = handle_connection(stream, controller, config).await {
+                    debug!("Text protocol connection of {} failed: {}", peer, e);
+                }
+                drop(permit);
+            });
+        }
+    }
+}
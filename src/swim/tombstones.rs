@@ -0,0 +1,207 @@
+use std::{
+    collections::BTreeMap, sync::{Arc, Mutex}, time::Duration,
+};
+use automerge::{AutoCommit, AutomergeError, ChangeHash, ObjId, ObjType, Prop, ReadDoc, ROOT};
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+
+use super::core::HolyDiverDataHandler;
+
+// A delete removes the value and marks the meta entry of the field in values_meta as deleted.
+// Automerge lets a write that is concurrent with a delete win, so a node that was partitioned while
+// the field was deleted would resurrect it with its older value. Reads therefore compare the wall-clock
+// timestamps of the tombstone and of the meta entry written with the current value and treat the field
+// as absent if the delete is newer.
+// Tombstones are purged once they are older than the horizon. A concurrent write that only arrives
+// after the purge resurrects the field again, so the horizon has to exceed the longest time a node
+// may be partitioned or offline.
+
+pub const DEFAULT_TOMBSTONE_HORIZON: Duration = Duration::from_secs(24 * 60 * 60);
+
+// purges run at least this often, shorter horizons are checked once per horizon
+const MAX_PURGE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+// tombstones purged under one lock of the data handler, writes and merges get in between batches
+const PURGE_BATCH_SIZE: usize = 500;
+
+/// A deleted field as recorded in `values_meta`, `deleted_at` is in unix millis.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "rest", derive(utoipa::ToSchema))]
+pub struct Tombstone {
+    pub actor: Option<String>,
+    pub deleted_at: i64,
+}
+
+// one of the meta entries of a field, there are several after concurrent writes
+struct MetaEntry {
+    actor: Option<String>,
+    timestamp: Option<i64>,
+    deleted: bool,
+}
+
+// The meta entries only qualify the values, an unreadable entry counts as a missing one.
+
+pub fn values_meta_map(state: &AutoCommit) -> Option<ObjId> {
+    values_meta_map_in(state, None)
+}
+
+fn values_meta_map_in(state: &AutoCommit, heads: Option<&[ChangeHash]>) -> Option<ObjId> {
+    match get_in(state, &ROOT, "values_meta", heads) {
+        Some((automerge::Value::Object(ObjType::Map), values_meta)) => Some(values_meta),
+        _ => None,
+    }
+}
+
+// the value of prop in obj, as of heads if given
+fn get_in<'a, P: Into<Prop>>(state: &'a AutoCommit, obj: &ObjId, prop: P, heads: Option<&[ChangeHash]>) -> Option<(automerge::Value<'a>, ObjId)> {
+    match heads {
+        Some(heads) => state.get_at(obj, prop, heads),
+        None => state.get(obj, prop),
+    }.ok().flatten()
+}
+
+fn meta_entries(state: &AutoCommit, values_meta: &ObjId, field_name: &str) -> Vec<MetaEntry> {
+    meta_entries_in(state, values_meta, field_name, None)
+}
+
+fn meta_entries_in(state: &AutoCommit, values_meta: &ObjId, field_name: &str, heads: Option<&[ChangeHash]>) -> Vec<MetaEntry> {
+    let all = match heads {
+        Some(heads) => state.get_all_at(values_meta, field_name, heads),
+        None => state.get_all(values_meta, field_name),
+    };
+    all.unwrap_or_default().into_iter()
+        .filter_map(|(value, id)| match value {
+            automerge::Value::Object(ObjType::Map) => Some(id),
+            _ => None,
+        })
+        .map(|field_meta| MetaEntry {
+            actor: get_in(state, &field_meta, "actor", heads).and_then(|(v, _)| v.into_string().ok()),
+            timestamp: get_in(state, &field_meta, "timestamp", heads).and_then(|(v, _)| v.to_i64()),
+            deleted: get_in(state, &field_meta, "deleted", heads).and_then(|(v, _)| v.to_bool()).unwrap_or(false),
+        })
+        .collect()
+}
+
+fn newest_tombstone(entries: &[MetaEntry]) -> Option<Tombstone> {
+    entries.iter()
+        .filter(|entry| entry.deleted)
+        .filter_map(|entry| entry.timestamp.map(|deleted_at| Tombstone { actor: entry.actor.clone(), deleted_at }))
+        .max_by_key(|tombstone| tombstone.deleted_at)
+}
+
+// Whether the value held by the op value_id was deleted by a concurrent delete with a newer timestamp.
+// Values without a meta entry of their writer, e.g. of nodes that don't maintain values_meta, are never hidden.
+fn hidden_by(entries: &[MetaEntry], value_id: &ObjId) -> bool {
+    let actor = match value_id {
+        ObjId::Id(_, actor, _) => actor.to_string(),
+        ObjId::Root => return false,
+    };
+    let deleted_at = match newest_tombstone(entries) {
+        Some(tombstone) => tombstone.deleted_at,
+        None => return false,
+    };
+    entries.iter()
+        .filter(|entry| !entry.deleted && entry.actor.as_deref() == Some(actor.as_str()))
+        .filter_map(|entry| entry.timestamp)
+        .max()
+        .is_some_and(|written_at| deleted_at > written_at)
+}
+
+pub fn is_hidden(state: &AutoCommit, values_meta: Option<&ObjId>, field_name: &str, value_id: &ObjId) -> bool {
+    match values_meta {
+        Some(values_meta) => hidden_by(&meta_entries(state, values_meta, field_name), value_id),
+        None => false,
+    }
+}
+
+// the current value of the field unless a tombstone hides it
+pub fn visible_value<'a>(state: &'a AutoCommit, values: &ObjId, field_name: &str) -> Result<Option<(automerge::Value<'a>, ObjId)>, AutomergeError> {
+    let values_meta = values_meta_map(state);
+    Ok(state.get(values, field_name)?
+        .filter(|(_, id)| !is_hidden(state, values_meta.as_ref(), field_name, id)))
+}
+
+// the value of the field as of heads unless a tombstone of then hides it
+pub fn visible_value_at<'a>(state: &'a AutoCommit, values: &ObjId, field_name: &str, heads: &[ChangeHash]) -> Result<Option<(automerge::Value<'a>, ObjId)>, AutomergeError> {
+    let values_meta = values_meta_map_in(state, Some(heads));
+    Ok(state.get_at(values, field_name, heads)?
+        .filter(|(_, id)| !values_meta.as_ref()
+            .is_some_and(|values_meta| hidden_by(&meta_entries_in(state, values_meta, field_name, Some(heads)), id))))
+}
+
+// the id of the op holding the visible value of the field as of heads
+pub fn visible_id_at(state: &AutoCommit, values: &ObjId, field_name: &str, heads: &[ChangeHash]) -> Option<ObjId> {
+    visible_value_at(state, values, field_name, heads).ok().flatten().map(|(_, id)| id)
+}
+
+// the newest tombstones of all fields without a visible value
+pub fn tombstones(state: &AutoCommit, values: &ObjId) -> BTreeMap<String, Tombstone> {
+    let values_meta = match values_meta_map(state) {
+        Some(values_meta) => values_meta,
+        None => return BTreeMap::new(),
+    };
+    state.keys(&values_meta)
+        .filter_map(|field_name| {
+            let entries = meta_entries(state, &values_meta, &field_name);
+            // fields whose value can't be read are left out rather than reported as deleted
+            let visible = state.get(values, field_name.as_str()).ok()?
+                .is_some_and(|(_, id)| !hidden_by(&entries, &id));
+            if visible {
+                return None;
+            }
+            newest_tombstone(&entries).map(|tombstone| (field_name, tombstone))
+        })
+        .collect()
+}
+
+// Up to limit fields whose newest meta entry is a tombstone from before cutoff (unix millis), with whether
+// a value hidden by the tombstone is left in the values map.
+pub fn expired_tombstones(state: &AutoCommit, values: &ObjId, cutoff: i64, limit: usize) -> Vec<(String, bool)> {
+    let values_meta = match values_meta_map(state) {
+        Some(values_meta) => values_meta,
+        None => return Vec::new(),
+    };
+    state.keys(&values_meta)
+        .filter_map(|field_name| {
+            let entries = meta_entries(state, &values_meta, &field_name);
+            let newest = entries.iter().max_by_key(|entry| entry.timestamp)?;
+            if !newest.deleted || newest.timestamp.is_none_or(|deleted_at| deleted_at >= cutoff) {
+                return None;
+            }
+            let hidden_value = state.get(values, field_name.as_str()).ok()?
+                .is_some_and(|(_, id)| hidden_by(&entries, &id));
+            Some((field_name, hidden_value))
+        })
+        .take(limit)
+        .collect()
+}
+
+// Periodically purges the tombstones older than horizon. Every node purges on its own, the deletes
+// of the same meta entries by several nodes merge without conflicts.
+// The scans and the persisting of the purges run on the blocking pool in batches of PURGE_BATCH_SIZE.
+// Needs to be called from within a tokio runtime.
+pub fn spawn_tombstone_gc(data_handler: Arc<Mutex<HolyDiverDataHandler>>, horizon: Duration) {
+    let interval = horizon.min(MAX_PURGE_INTERVAL);
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(interval).await;
+            let data_handler = data_handler.clone();
+            let result = tokio::task::spawn_blocking(move || {
+                let mut purged = 0;
+                loop {
+                    let batch = data_handler.lock().unwrap().purge_tombstones(horizon, PURGE_BATCH_SIZE)?;
+                    purged += batch;
+                    if batch < PURGE_BATCH_SIZE {
+                        return Ok::<usize, anyhow::Error>(purged);
+                    }
+                }
+            }).await;
+            match result {
+                Ok(Ok(0)) => {},
+                Ok(Ok(purged)) => info!("Purged {} tombstones older than {:?}", purged, horizon),
+                Ok(Err(e)) => error!("Could not purge tombstones: {:#}", e),
+                Err(e) => error!("Tombstone purge did not finish: {}", e),
+            }
+        }
+    });
+}
@@ -0,0 +1,32 @@
+use std::collections::{BTreeSet, HashMap};
+use automerge::{Change, ObjId};
+
+// The map keys the ops of changes write, by the object they write to, so that a merge only looks at those keys
+// instead of scanning the document, see HolyDiverDataHandler::apply_remote. automerge doesn't export the types
+// Change::decode expands the ops into, their JSON form is read instead. It names objects like ObjId displays them.
+
+#[derive(Debug, Default)]
+pub struct TouchedKeys(HashMap<String, BTreeSet<String>>);
+
+impl TouchedKeys {
+    // None if one of the changes can't be expanded
+    pub fn of<'a>(changes: impl IntoIterator<Item = &'a Change>) -> Option<Self> {
+        let mut touched = TouchedKeys::default();
+        for change in changes {
+            let expanded = serde_json::to_value(change.decode()).ok()?;
+            for op in expanded.get("ops")?.as_array()? {
+                // list ops name an elemId instead of a key
+                let (Some(obj), Some(key)) = (op.get("obj").and_then(|obj| obj.as_str()), op.get("key").and_then(|key| key.as_str())) else {
+                    continue;
+                };
+                touched.0.entry(obj.to_owned()).or_default().insert(key.to_owned());
+            }
+        }
+        Some(touched)
+    }
+
+    // the keys written in obj
+    pub fn keys(&self, obj: &ObjId) -> impl Iterator<Item = &String> {
+        self.0.get(&obj.to_string()).into_iter().flatten()
+    }
+}
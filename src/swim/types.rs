@@ -1,13 +1,138 @@
-use std::net::SocketAddr;
-use serde::{Serialize, Deserialize};
+use std::{
+    cmp::Ordering, collections::VecDeque, fmt::{Display, Formatter}, hash::{Hash, Hasher}, net::{IpAddr, SocketAddr},
+    str::FromStr, sync::{Arc, Mutex}, time::{SystemTime, UNIX_EPOCH},
+};
+use serde::{de::{self, SeqAccess, Unexpected, Visitor}, ser::SerializeTuple, Deserialize, Deserializer, Serialize, Serializer};
 use foca::Identity;
 use rand;
 
-#[derive(Clone, PartialEq, Eq, Deserialize, Serialize)]
+use super::envelope;
+
+// IDs are part of nearly every SWIM message, so they are encoded compactly instead of with the serde
+// representation of SocketAddr: a tag telling IPv4 from IPv6, the octets of the address and the port and
+// bump as two big endian bytes each, 9 bytes for IPv4 and 21 bytes for IPv6 in both postcard and bincode.
+// Datagrams of envelope versions before COMPACT_ID_VERSION encode them as LegacyId, see envelope.
+const IPV4_TAG: u8 = 4;
+const IPV6_TAG: u8 = 6;
+const COMPACT_ID_VERSION: u8 = 3;
+
+// bumps of this node remembered to not renew to one of them again, also across restarts, see bump_history
+pub const BUMP_HORIZON: usize = 1024;
+
+/// How the identity of this node picks its next bump when foca renews it. Foca refuses identities it has
+/// seen before, so a renewal must not come back to a bump the members may still remember.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum BumpStrategy {
+    // the bump after the current one
+    #[default]
+    Sequential,
+    // a random bump, not one of the history
+    RandomNonRepeating,
+    // the seconds since the epoch, truncated to 16 bits
+    Timestamp,
+}
+
+impl Display for BumpStrategy {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BumpStrategy::Sequential => write!(f, "sequential"),
+            BumpStrategy::RandomNonRepeating => write!(f, "random-nonrepeating"),
+            BumpStrategy::Timestamp => write!(f, "timestamp"),
+        }
+    }
+}
+
+impl FromStr for BumpStrategy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "sequential" => Ok(BumpStrategy::Sequential),
+            "random-nonrepeating" => Ok(BumpStrategy::RandomNonRepeating),
+            "timestamp" => Ok(BumpStrategy::Timestamp),
+            other => Err(format!("unknown bump strategy {:?}, expected sequential, random-nonrepeating or timestamp", other)),
+        }
+    }
+}
+
+impl BumpStrategy {
+    // the candidate for the bump after current, renew skips the ones in the history
+    fn next(&self, current: u16) -> u16 {
+        match self {
+            BumpStrategy::Sequential => current.wrapping_add(1),
+            BumpStrategy::RandomNonRepeating => rand::random(),
+            BumpStrategy::Timestamp => {
+                let secs = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+                secs as u16
+            },
+        }
+    }
+}
+
+// The bumps an identity and its renewals used, shared by all of them. Oldest first, at most BUMP_HORIZON.
+#[derive(Debug)]
+struct Renewals {
+    strategy: BumpStrategy,
+    used: Mutex<VecDeque<u16>>,
+}
+
+impl Renewals {
+    // the next bump that is not in the history, which records it
+    fn next_bump(&self, current: u16) -> u16 {
+        let mut used = self.used.lock().unwrap();
+        let mut bump = self.strategy.next(current);
+        // the history holds far fewer than 2^16 bumps, so a free one is found
+        while used.contains(&bump) {
+            bump = match self.strategy {
+                BumpStrategy::RandomNonRepeating => rand::random(),
+                _ => bump.wrapping_add(1),
+            };
+        }
+        used.push_back(bump);
+        if used.len() > BUMP_HORIZON {
+            used.pop_front();
+        }
+        bump
+    }
+}
+
+// Equal, ordered and hashed by address and then by bump, e.g. for tie-breaks that have to come out the
+// same on every member. The renewal history of the own identity is not part of it.
+#[derive(Clone)]
 pub struct ID {
     pub addr: SocketAddr,
     // An extra field to allow fast rejoin
     pub bump: u16,
+    // only set on the identity of this node, see with_renewals
+    renewals: Option<Arc<Renewals>>,
+}
+
+impl PartialEq for ID {
+    fn eq(&self, other: &Self) -> bool {
+        (self.addr, self.bump) == (other.addr, other.bump)
+    }
+}
+
+impl Eq for ID {}
+
+impl PartialOrd for ID {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for ID {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (self.addr, self.bump).cmp(&(other.addr, other.bump))
+    }
+}
+
+impl Hash for ID {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.addr.hash(state);
+        self.bump.hash(state);
+    }
 }
 
 
@@ -25,11 +150,107 @@ impl std::fmt::Debug for ID {
 
 impl ID {
     pub fn new(addr: SocketAddr) -> Self {
+        Self::with_bump(addr, rand::random())
+    }
+
+    pub fn with_bump(addr: SocketAddr, bump: u16) -> Self {
         Self {
             addr,
-            bump: rand::random(),
+            bump,
+            renewals: None,
         }
     }
+
+    // This identity renewing with strategy, never to one of the bumps in history, oldest first. The bump is
+    // replaced by the next one of the strategy if it is in the history.
+    pub fn with_renewals(&self, strategy: BumpStrategy, history: &[u16]) -> Self {
+        let start = history.len().saturating_sub(BUMP_HORIZON);
+        let renewals = Renewals {
+            strategy,
+            used: Mutex::new(history[start..].iter().copied().collect()),
+        };
+        let bump = match history.contains(&self.bump) {
+            true => renewals.next_bump(self.bump),
+            false => {
+                let mut used = renewals.used.lock().unwrap();
+                used.push_back(self.bump);
+                if used.len() > BUMP_HORIZON {
+                    used.pop_front();
+                }
+                self.bump
+            },
+        };
+        Self {
+            addr: self.addr,
+            bump,
+            renewals: Some(Arc::new(renewals)),
+        }
+    }
+
+    // the bumps this identity and its renewals used, oldest first, empty without with_renewals
+    pub fn bump_history(&self) -> Vec<u16> {
+        self.renewals.as_ref()
+            .map_or_else(Vec::new, |renewals| renewals.used.lock().unwrap().iter().copied().collect())
+    }
+}
+
+// the derived encoding of IDs up to envelope version 2
+#[derive(Serialize, Deserialize)]
+struct LegacyId {
+    addr: SocketAddr,
+    bump: u16,
+}
+
+impl Serialize for ID {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        if envelope::wire_version() < COMPACT_ID_VERSION {
+            return LegacyId { addr: self.addr, bump: self.bump }.serialize(serializer);
+        }
+        let mut tuple = serializer.serialize_tuple(4)?;
+        match self.addr.ip() {
+            IpAddr::V4(ip) => {
+                tuple.serialize_element(&IPV4_TAG)?;
+                tuple.serialize_element(&ip.octets())?;
+            },
+            IpAddr::V6(ip) => {
+                tuple.serialize_element(&IPV6_TAG)?;
+                tuple.serialize_element(&ip.octets())?;
+            },
+        }
+        tuple.serialize_element(&self.addr.port().to_be_bytes())?;
+        tuple.serialize_element(&self.bump.to_be_bytes())?;
+        tuple.end()
+    }
+}
+
+impl<'de> Deserialize<'de> for ID {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        if envelope::wire_version() < COMPACT_ID_VERSION {
+            return LegacyId::deserialize(deserializer).map(|legacy| ID::with_bump(legacy.addr, legacy.bump));
+        }
+        deserializer.deserialize_tuple(4, IdVisitor)
+    }
+}
+
+struct IdVisitor;
+
+impl<'de> Visitor<'de> for IdVisitor {
+    type Value = ID;
+
+    fn expecting(&self, formatter: &mut Formatter) -> std::fmt::Result {
+        formatter.write_str("an address tag followed by the octets of the address, the port and the bump")
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<ID, A::Error> {
+        let ip = match seq.next_element::<u8>()?.ok_or_else(|| de::Error::invalid_length(0, &self))? {
+            IPV4_TAG => IpAddr::from(seq.next_element::<[u8; 4]>()?.ok_or_else(|| de::Error::invalid_length(1, &self))?),
+            IPV6_TAG => IpAddr::from(seq.next_element::<[u8; 16]>()?.ok_or_else(|| de::Error::invalid_length(1, &self))?),
+            tag => return Err(de::Error::invalid_value(Unexpected::Unsigned(tag.into()), &"address tag 4 or 6")),
+        };
+        let port = seq.next_element::<[u8; 2]>()?.ok_or_else(|| de::Error::invalid_length(2, &self))?;
+        let bump = seq.next_element::<[u8; 2]>()?.ok_or_else(|| de::Error::invalid_length(3, &self))?;
+        Ok(ID::with_bump(SocketAddr::new(ip, u16::from_be_bytes(port)), u16::from_be_bytes(bump)))
+    }
 }
 
 impl Identity for ID {
@@ -43,11 +264,122 @@ impl Identity for ID {
 
     // And by implementing `renew` we enable automatic rejoining:
     // when another member declares us as down, Foca immediatelly
-    // switches to this new identity and rejoins the cluster for us
+    // switches to this new identity and rejoins the cluster for us.
+    // Without renewals, e.g. in embedders, the next bump is taken
     fn renew(&self) -> Option<Self> {
+        let bump = match &self.renewals {
+            Some(renewals) => renewals.next_bump(self.bump),
+            None => self.bump.wrapping_add(1),
+        };
         Some(Self {
             addr: self.addr,
-            bump: self.bump.wrapping_add(1),
+            bump,
+            renewals: self.renewals.clone(),
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use bincode::Options;
+    use super::*;
+
+    fn id(addr: &str, bump: u16) -> ID {
+        ID::with_bump(addr.parse().unwrap(), bump)
+    }
+
+    fn bincode_encode(version: u8, id: &ID) -> Vec<u8> {
+        envelope::with_wire_version(version, || bincode::DefaultOptions::new().serialize(id).unwrap())
+    }
+
+    fn bincode_decode(version: u8, bytes: &[u8]) -> ID {
+        envelope::with_wire_version(version, || bincode::DefaultOptions::new().deserialize(bytes).unwrap())
+    }
+
+    fn postcard_encode(id: &ID) -> Vec<u8> {
+        let mut buf = [0u8; 64];
+        postcard::to_slice(id, &mut buf).unwrap().to_vec()
+    }
+
+    #[test]
+    fn compact_ids_keep_their_encoding_in_both_codecs() {
+        let v4 = id("127.0.0.1:9001", 513);
+        let v4_golden: [u8; 9] = [IPV4_TAG, 127, 0, 0, 1, 0x23, 0x29, 0x02, 0x01];
+        let v6 = id("[2001:db8::1]:443", 1);
+        let mut v6_golden = vec![IPV6_TAG, 0x20, 0x01, 0x0d, 0xb8];
+        v6_golden.extend_from_slice(&[0; 11]);
+        v6_golden.extend_from_slice(&[1, 0x01, 0xbb, 0x00, 0x01]);
+
+        for (id, golden) in [(v4, v4_golden.to_vec()), (v6, v6_golden)] {
+            assert_eq!(bincode_encode(COMPACT_ID_VERSION, &id), golden);
+            assert_eq!(postcard_encode(&id), golden);
+            let decoded = bincode_decode(COMPACT_ID_VERSION, &golden);
+            assert_eq!((decoded.addr, decoded.bump), (id.addr, id.bump));
+            let decoded: ID = postcard::from_bytes(&golden).unwrap();
+            assert_eq!((decoded.addr, decoded.bump), (id.addr, id.bump));
+        }
+    }
+
+    #[test]
+    fn ids_of_envelope_version_2_keep_the_derived_encoding() {
+        let id = id("127.0.0.1:9001", 513);
+        // variant V4 of SocketAddr, the octets and the port and bump as varints
+        let golden: [u8; 11] = [0, 127, 0, 0, 1, 251, 0x29, 0x23, 251, 0x01, 0x02];
+        assert_eq!(bincode_encode(2, &id), golden);
+        assert_eq!(bincode_decode(2, &golden), id);
+    }
+
+    #[test]
+    fn an_unknown_address_tag_is_refused() {
+        let result: Result<ID, _> = bincode::DefaultOptions::new().deserialize(&[5u8, 127, 0, 0, 1, 0x23, 0x29, 0, 1]);
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn ids_are_ordered_by_address_then_bump() {
+        let mut ids = vec![id("127.0.0.2:9000", 0), id("127.0.0.1:9001", 0), id("127.0.0.1:9000", 7), id("127.0.0.1:9000", 3)];
+        ids.sort();
+        let sorted: Vec<(String, u16)> = ids.iter().map(|id| (id.addr.to_string(), id.bump)).collect();
+        assert_eq!(sorted, [
+            ("127.0.0.1:9000".to_owned(), 3),
+            ("127.0.0.1:9000".to_owned(), 7),
+            ("127.0.0.1:9001".to_owned(), 0),
+            ("127.0.0.2:9000".to_owned(), 0),
+        ]);
+        // the renewal history is no part of equality
+        let renewing = id("127.0.0.1:9000", 3).with_renewals(BumpStrategy::Sequential, &[]);
+        assert_eq!(renewing, id("127.0.0.1:9000", 3));
+    }
+
+    // renews from an identity with an empty history, returns the bumps in the order they were used
+    fn renewals(strategy: BumpStrategy, count: usize) -> Vec<u16> {
+        let mut identity = id("127.0.0.1:9000", 0).with_renewals(strategy, &[]);
+        let mut bumps = vec![identity.bump];
+        for _ in 0..count {
+            identity = identity.renew().unwrap();
+            bumps.push(identity.bump);
+        }
+        bumps
+    }
+
+    #[test]
+    fn renewals_dont_repeat_a_bump_within_the_horizon() {
+        for strategy in [BumpStrategy::Sequential, BumpStrategy::RandomNonRepeating, BumpStrategy::Timestamp] {
+            let bumps = renewals(strategy, 5000);
+            for window in bumps.windows(BUMP_HORIZON) {
+                let distinct: std::collections::HashSet<&u16> = window.iter().collect();
+                assert_eq!(distinct.len(), window.len(), "{} repeated a bump", strategy);
+            }
+        }
+    }
+
+    #[test]
+    fn a_restarted_identity_avoids_the_bumps_of_its_history() {
+        let before_restart = renewals(BumpStrategy::Sequential, 10);
+        let restarted = id("127.0.0.1:9000", before_restart[3]).with_renewals(BumpStrategy::Sequential, &before_restart);
+        assert!(!before_restart.contains(&restarted.bump));
+        let renewed = restarted.renew().unwrap();
+        assert!(!before_restart.contains(&renewed.bump));
+        assert_eq!(renewed.bump_history().len(), before_restart.len() + 2);
+    }
+}
@@ -0,0 +1,68 @@
+use actix_web::{http::header, web, HttpRequest, HttpResponse};
+use once_cell::sync::Lazy;
+use sha2::{Digest, Sha256};
+
+use super::error::HolyDiverError;
+
+// The built-in UI at /ui, a single page using the REST API of the node serving it. Its assets in the ui
+// dir of the crate are compiled into the binary, there is no build step. Like every other route the UI
+// is only served to requests with a valid bearer token if --rest-auth-token is set.
+
+struct Asset {
+    name: &'static str,
+    content_type: &'static str,
+    body: &'static [u8],
+}
+
+const INDEX: &str = "index.html";
+
+const ASSETS: [Asset; 3] = [
+    Asset { name: INDEX, content_type: "text/html; charset=utf-8", body: include_bytes!("../../ui/index.html") },
+    Asset { name: "app.js", content_type: "text/javascript; charset=utf-8", body: include_bytes!("../../ui/app.js") },
+    Asset { name: "style.css", content_type: "text/css; charset=utf-8", body: include_bytes!("../../ui/style.css") },
+];
+
+// by asset, derived from the content so that they change with every build changing an asset
+static ETAGS: Lazy<Vec<String>> = Lazy::new(|| ASSETS.iter()
+    .map(|asset| format!("\"{}\"", hex(&Sha256::digest(asset.body)[..8])))
+    .collect());
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+// Browsers revalidate the assets on every load, which is answered with 304 as long as they didn't
+// change, so that a node running a new version never serves a cached page of the old one.
+fn serve(req: &HttpRequest, name: &str) -> HttpResponse {
+    let index = match ASSETS.iter().position(|asset| asset.name == name) {
+        Some(index) => index,
+        None => return actix_web::ResponseError::error_response(&HolyDiverError::RouteNotFound(req.path().to_owned())),
+    };
+    let asset = &ASSETS[index];
+    let etag = &ETAGS[index];
+    let not_modified = req.headers().get(header::IF_NONE_MATCH)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.split(',').any(|candidate| candidate.trim() == etag));
+    let mut response = if not_modified { HttpResponse::NotModified() } else { HttpResponse::Ok() };
+    response
+        .insert_header((header::ETAG, etag.as_str()))
+        .insert_header((header::CACHE_CONTROL, "no-cache"))
+        .insert_header((header::X_CONTENT_TYPE_OPTIONS, "nosniff"));
+    if not_modified {
+        return response.finish();
+    }
+    response.content_type(asset.content_type).body(asset.body)
+}
+
+async fn index(req: HttpRequest) -> HttpResponse {
+    serve(&req, INDEX)
+}
+
+async fn asset(req: HttpRequest, name: web::Path<String>) -> HttpResponse {
+    serve(&req, &name)
+}
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(web::resource(["/ui", "/ui/"]).route(web::get().to(index)))
+        .service(web::resource("/ui/{name}").route(web::get().to(asset)));
+}
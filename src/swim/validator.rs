@@ -0,0 +1,202 @@
+use std::{collections::BTreeMap, fmt::{Display, Formatter}, fs, path::Path};
+
+use anyhow::{Context, Result};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+
+use super::pattern::glob_matches;
+
+/// Constraints for the values of all keys matching a pattern. Every given constraint has to hold.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[cfg_attr(feature = "rest", derive(utoipa::ToSchema))]
+#[serde(deny_unknown_fields)]
+pub struct Constraint {
+    #[serde(rename = "type", skip_serializing_if = "Option::is_none")]
+    pub value_type: Option<ValueType>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub regex: Option<String>,
+    // numeric bounds, only applicable to numeric values
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub min: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max: Option<f64>,
+    #[serde(rename = "enum", skip_serializing_if = "Option::is_none")]
+    pub allowed: Option<Vec<String>>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[cfg_attr(feature = "rest", derive(utoipa::ToSchema))]
+#[serde(rename_all = "lowercase")]
+pub enum ValueType {
+    String,
+    Int,
+    Bool,
+}
+
+#[derive(Debug)]
+struct Rule {
+    pattern: String,
+    constraint: Constraint,
+    regex: Option<Regex>,
+}
+
+/// A violated constraint of a field value.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Violation {
+    pub field: String,
+    pub pattern: String,
+    pub constraint: String,
+    pub message: String,
+}
+
+impl Display for Violation {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} violates {} of {}: {}", self.field, self.constraint, self.pattern, self.message)
+    }
+}
+
+/// Validates field values against rules loaded from a schema file of the form
+/// `{"<key pattern>": {"type": "int", "min": 0, "max": 10}, "mode": {"enum": ["a", "b"]}}`.
+#[derive(Debug, Default)]
+pub struct Validator {
+    rules: Vec<Rule>,
+}
+
+impl Validator {
+    pub fn from_file(path: &Path) -> Result<Self> {
+        let content = fs::read_to_string(path)
+            .with_context(|| format!("could not read schema file {}", path.display()))?;
+        let constraints: BTreeMap<String, Constraint> = serde_json::from_str(&content)
+            .with_context(|| format!("schema file {} is invalid", path.display()))?;
+        Self::new(constraints)
+    }
+
+    pub fn new(constraints: BTreeMap<String, Constraint>) -> Result<Self> {
+        let rules = constraints.into_iter()
+            .map(|(pattern, constraint)| {
+                let regex = constraint.regex.as_deref()
+                    .map(Regex::new)
+                    .transpose()
+                    .with_context(|| format!("invalid regex for {}", pattern))?;
+                Ok(Rule { pattern, constraint, regex })
+            })
+            .collect::<Result<Vec<Rule>>>()?;
+        Ok(Validator { rules })
+    }
+
+    pub fn rules(&self) -> BTreeMap<String, Constraint> {
+        self.rules.iter()
+            .map(|r| (r.pattern.to_owned(), r.constraint.clone()))
+            .collect()
+    }
+
+    pub fn validate(&self, field: &str, value: &str) -> std::result::Result<(), Violation> {
+        for rule in self.rules.iter().filter(|r| glob_matches(&r.pattern, field)) {
+            rule.check(value).map_err(|(constraint, message)| Violation {
+                field: field.to_owned(),
+                pattern: rule.pattern.to_owned(),
+                constraint: constraint.to_owned(),
+                message,
+            })?;
+        }
+        Ok(())
+    }
+}
+
+impl Rule {
+    fn check(&self, value: &str) -> std::result::Result<(), (&'static str, String)> {
+        let constraint = &self.constraint;
+        match constraint.value_type {
+            Some(ValueType::Int) if value.parse::<i64>().is_err() =>
+                return Err(("type", format!("{:?} is not an integer", value))),
+            Some(ValueType::Bool) if value.parse::<bool>().is_err() =>
+                return Err(("type", format!("{:?} is not a boolean", value))),
+            _ => {},
+        }
+        if let Some(regex) = &self.regex {
+            if !regex.is_match(value) {
+                return Err(("regex", format!("{:?} does not match {}", value, regex.as_str())));
+            }
+        }
+        if constraint.min.is_some() || constraint.max.is_some() {
+            let number = value.parse::<f64>()
+                .map_err(|_| ("type", format!("{:?} is not a number", value)))?;
+            if let Some(min) = constraint.min.filter(|min| number < *min) {
+                return Err(("min", format!("{} is less than {}", number, min)));
+            }
+            if let Some(max) = constraint.max.filter(|max| number > *max) {
+                return Err(("max", format!("{} is greater than {}", number, max)));
+            }
+        }
+        if let Some(allowed) = &constraint.allowed {
+            if !allowed.iter().any(|a| a == value) {
+                return Err(("enum", format!("{:?} is not one of {:?}", value, allowed)));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use super::*;
+
+    fn validator(schema: serde_json::Value) -> Validator {
+        Validator::new(serde_json::from_value(schema).unwrap()).unwrap()
+    }
+
+    // the constraint a value violates, None if it is valid
+    fn violated(validator: &Validator, field: &str, value: &str) -> Option<String> {
+        validator.validate(field, value).err().map(|violation| violation.constraint)
+    }
+
+    #[test]
+    fn values_have_to_be_of_the_type() {
+        let validator = validator(json!({"port": {"type": "int"}, "debug": {"type": "bool"}, "name": {"type": "string"}}));
+        assert_eq!(violated(&validator, "port", "8080"), None);
+        assert_eq!(violated(&validator, "port", "80.5").as_deref(), Some("type"));
+        assert_eq!(violated(&validator, "debug", "true"), None);
+        assert_eq!(violated(&validator, "debug", "yes").as_deref(), Some("type"));
+        assert_eq!(violated(&validator, "name", "42"), None);
+    }
+
+    #[test]
+    fn values_have_to_match_the_regex() {
+        let validator = validator(json!({"host": {"regex": "^[a-z]+\\.example\\.com$"}}));
+        assert_eq!(violated(&validator, "host", "db.example.com"), None);
+        assert_eq!(violated(&validator, "host", "db.example.org").as_deref(), Some("regex"));
+    }
+
+    #[test]
+    fn numbers_have_to_be_within_the_bounds() {
+        let validator = validator(json!({"workers": {"min": 1, "max": 16}}));
+        assert_eq!(violated(&validator, "workers", "1"), None);
+        assert_eq!(violated(&validator, "workers", "16"), None);
+        assert_eq!(violated(&validator, "workers", "0").as_deref(), Some("min"));
+        assert_eq!(violated(&validator, "workers", "16.5").as_deref(), Some("max"));
+        assert_eq!(violated(&validator, "workers", "many").as_deref(), Some("type"));
+    }
+
+    #[test]
+    fn values_have_to_be_one_of_the_enum() {
+        let validator = validator(json!({"mode": {"enum": ["active", "standby"]}}));
+        assert_eq!(violated(&validator, "mode", "standby"), None);
+        assert_eq!(violated(&validator, "mode", "Active").as_deref(), Some("enum"));
+    }
+
+    #[test]
+    fn rules_apply_to_the_keys_matching_their_pattern() {
+        let validator = validator(json!({"limits.*": {"type": "int"}}));
+        let violation = validator.validate("limits.connections", "unlimited").unwrap_err();
+        assert_eq!(violation.field, "limits.connections");
+        assert_eq!(violation.pattern, "limits.*");
+        assert!(validator.validate("other", "unlimited").is_ok());
+    }
+
+    #[test]
+    fn invalid_schemas_are_refused() {
+        assert!(Validator::new(serde_json::from_value(json!({"host": {"regex": "("}})).unwrap()).is_err());
+        assert!(serde_json::from_value::<BTreeMap<String, Constraint>>(json!({"host": {"maximum": 3}})).is_err());
+    }
+}
@@ -0,0 +1,74 @@
+use std::sync::{atomic::{AtomicUsize, Ordering}, Arc};
+use serde::{Deserialize, Serialize};
+
+use super::error::{HolyDiverError, ReadOnlyReason};
+
+// Rejecting local writes while the node sees fewer members than --min-members-for-writes, for deployments where
+// a write accepted in isolation is worse than none: it would collide with what the rest of the cluster wrote in
+// the meantime. The foca loop keeps the number of active members, this node included, in a MemberCount that the
+// data handler checks every local write against, like strict durability, so REST, the text protocol and
+// embedders are gated alike. Merges are always applied and reads keep working. /healthz fails meanwhile. The
+// count follows failure detection, a node notices that it is isolated once the others are declared down.
+
+pub const DEFAULT_MIN_MEMBERS_FOR_WRITES: usize = 1;
+
+/// The number of active members including this node, as of the last change of the members. Clones share the count.
+#[derive(Debug, Clone)]
+pub struct MemberCount(Arc<AtomicUsize>);
+
+impl Default for MemberCount {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MemberCount {
+    // a node is its own first member
+    pub fn new() -> Self {
+        MemberCount(Arc::new(AtomicUsize::new(1)))
+    }
+
+    pub fn set(&self, members: usize) {
+        self.0.store(members, Ordering::SeqCst);
+    }
+
+    pub fn get(&self) -> usize {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+
+/// Whether the node has enough members to accept writes.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "rest", derive(utoipa::ToSchema))]
+pub struct WriteQuorumStatus {
+    pub members: usize,
+    pub min_members_for_writes: usize,
+    pub writable: bool,
+}
+
+/// Local writes need at least min active members, see write_quorum.
+#[derive(Debug, Clone)]
+pub struct WriteQuorum {
+    min: usize,
+    members: MemberCount,
+}
+
+impl WriteQuorum {
+    // members has to be the count passed to FocaRuntimeConfig
+    pub fn new(min: usize, members: MemberCount) -> Self {
+        WriteQuorum { min, members }
+    }
+
+    pub fn status(&self) -> WriteQuorumStatus {
+        let members = self.members.get();
+        WriteQuorumStatus { members, min_members_for_writes: self.min, writable: members >= self.min }
+    }
+
+    pub fn check(&self) -> Result<(), HolyDiverError> {
+        let members = self.members.get();
+        if members < self.min {
+            return Err(HolyDiverError::ReadOnly(ReadOnlyReason::InsufficientMembers { members, min: self.min }));
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,124 @@
+use std::{num::NonZeroU8, path::PathBuf, net::SocketAddr, str::FromStr, sync::{Arc, Mutex}};
+
+use log::warn;
+use tokio::sync::{broadcast::error::RecvError, oneshot};
+
+use foca::Config;
+use crate::swim::{events::{ChangeKind, FieldChange}, foca::setup_foca, types::ID, core::{FocaRuntimeConfig, HolyDiverDataHandler, HolyDiverController, gossip_budget, DEFAULT_GOSSIP_BUDGET_PERCENT}, handle::ControllerHandle, coalesce::{BroadcastCoalescer, DEFAULT_BROADCAST_INTERVAL}, tombstones::{spawn_tombstone_gc, DEFAULT_TOMBSTONE_HORIZON}, persistence::spawn_persistence_retry, history::{spawn_history_monitor, DEFAULT_HISTORY_CHECK_INTERVAL}, migrations::migrate_data_dir, manifest::claim_data_dir, store::FileStore};
+
+use wasm_bindgen::prelude::*;
+
+#[wasm_bindgen]
+pub struct HolyDiverHolder {
+    controller: ControllerHandle,
+    // read directly by the synchronous methods, which can't await the controller
+    data_handler: Arc<Mutex<HolyDiverDataHandler>>,
+}
+
+#[wasm_bindgen]
+impl HolyDiverHolder {
+    /// All fields as a plain object of strings, including the local ones.
+    pub fn get_state_js(&self) -> Result<JsValue, JsValue> {
+        let fields = self.data_handler.lock().unwrap().get_all_fields()
+            .map_err(|e| JsValue::from_str(&e.to_string()))?;
+        let json = serde_json::to_string(&fields).map_err(|e| JsValue::from_str(&e.to_string()))?;
+        js_sys::JSON::parse(&json)
+    }
+
+    /// Calls `callback` with `{field, value, remote}` for every change of a field, `value` is null for
+    /// deletes and `remote` tells merged changes from local writes. Returns a function that unsubscribes.
+    /// The subscription only holds the receiving end of the change channel, not the node.
+    pub fn on_change(&self, callback: js_sys::Function) -> js_sys::Function {
+        let mut changes = self.data_handler.lock().unwrap().subscribe();
+        let (unsubscribe, mut unsubscribed) = oneshot::channel::<()>();
+        wasm_bindgen_futures::spawn_local(async move {
+            loop {
+                let change = tokio::select! {
+                    change = changes.recv() => change,
+                    _ = &mut unsubscribed => break,
+                };
+                match change {
+                    // followed by the Updated or Deleted change of the merge
+                    Ok(change) if change.kind == ChangeKind::Overridden => {},
+                    Ok(change) => {
+                        if let Err(e) = change_to_js(&change).and_then(|change| callback.call1(&JsValue::NULL, &change)) {
+                            warn!("on_change callback failed for {}: {:?}", change.field, e);
+                        }
+                    },
+                    Err(RecvError::Lagged(missed)) => warn!("on_change callback missed {} changes", missed),
+                    Err(RecvError::Closed) => break,
+                }
+            }
+        });
+        Closure::once_into_js(move || {
+            let _ = unsubscribe.send(());
+        }).unchecked_into()
+    }
+}
+
+fn change_to_js(change: &FieldChange) -> Result<JsValue, JsValue> {
+    let json = serde_json::json!({
+        "field": change.field,
+        "value": change.winning_value,
+        "remote": change.remote,
+    });
+    js_sys::JSON::parse(&json.to_string())
+}
+
+#[wasm_bindgen]
+pub async fn init(data_dir_path: &str, bind_address: &str) -> HolyDiverHolder {
+    let foca_config = {
+        let mut c = Config::simple();
+        // With this setting you can suspend (^Z) one process,
+        // wait for it the member to be declared down then resume
+        // it (fg) and foca should recover by itself
+        c.notify_down_members = true;
+        // limits the number of broadcasts of a single message
+        c.max_transmissions = NonZeroU8::new(2).unwrap();
+        c
+    };
+    let mut data_dir = PathBuf::new();
+    data_dir.push(data_dir_path);
+    let bind_addr = SocketAddr::from_str(bind_address).unwrap();
+    let identity = ID::new(bind_addr);
+    let announce_to: Vec<SocketAddr> = Vec::new();
+    let runtime_config = FocaRuntimeConfig::new(identity.clone(), data_dir, bind_addr, announce_to, foca_config);
+    migrate_data_dir(&runtime_config.data_dir).unwrap();
+    claim_data_dir(&FileStore::new(&runtime_config.data_dir), identity.addr, false).unwrap();
+    let data_handler = Arc::from(Mutex::from(HolyDiverDataHandler::new(&runtime_config.data_dir, identity.clone())));
+    spawn_tombstone_gc(data_handler.clone(), DEFAULT_TOMBSTONE_HORIZON);
+    spawn_persistence_retry(data_handler.clone());
+    spawn_history_monitor(data_handler.clone(), DEFAULT_HISTORY_CHECK_INTERVAL);
+    let gossip_budget = gossip_budget(&runtime_config.foca_config, DEFAULT_GOSSIP_BUDGET_PERCENT);
+    let foca_command_sender = setup_foca(runtime_config, Box::new(data_handler.clone())).await.unwrap();
+    let broadcasts = BroadcastCoalescer::spawn(foca_command_sender.clone(), data_handler.clone(), gossip_budget, DEFAULT_BROADCAST_INTERVAL);
+    let controller = ControllerHandle::spawn(HolyDiverController {
+        data_handler: data_handler.clone(),
+        foca_command_sender,
+        broadcasts,
+    });
+    HolyDiverHolder {
+        controller,
+        data_handler,
+    }
+}
+
+// a rejected write, e.g. with too few members up, is thrown as an error with its message
+#[wasm_bindgen]
+pub async fn set_field(holder: HolyDiverHolder, field_name: String, field_value: String) -> Result<(), JsValue> {
+    holder.controller.set_field(field_name, field_value).await
+        .map_err(|e| JsValue::from_str(&format!("{:#}", e)))
+}
+
+// synchronous like before the controller had a task of its own, reads don't need to wait for it
+#[wasm_bindgen]
+pub fn get_field(holder: HolyDiverHolder, field_name: String) -> String {
+    match holder.data_handler.lock().unwrap().get_field(field_name) {
+        Ok(Some(field_value)) => field_value,
+        Ok(None) => "N/A".to_owned(),
+        Err(e) => {
+            warn!("Could not read a field: {}", e);
+            "N/A".to_owned()
+        },
+    }
+}
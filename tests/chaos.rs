@@ -0,0 +1,59 @@
+mod common;
+
+use common::Node;
+use holydiver::swim::chaos::ChaosConfig;
+
+#[tokio::test]
+async fn both_sides_of_a_healed_partition_converge() {
+    let (a, a_faults) = Node::start_with_faults(&[], 1).await;
+    let (b, b_faults) = Node::start_with_faults(&[&a], 2).await;
+    let (c, c_faults) = Node::start_with_faults(&[&a], 3).await;
+    a.wait_for_members(3).await;
+    a.set("before", "0").await;
+    c.wait_for_field("before", "0").await;
+
+    let partitioned = ChaosConfig {
+        partitions: vec![vec![a.addr], vec![b.addr, c.addr]],
+        ..ChaosConfig::default()
+    };
+    for faults in [&a_faults, &b_faults, &c_faults] {
+        faults.set(partitioned.clone()).unwrap();
+    }
+    a.wait_for_members(1).await;
+    b.wait_for_members(2).await;
+    a.set("left", "a").await;
+    b.set("right", "b").await;
+    c.wait_for_field("right", "b").await;
+    assert_eq!(c.field("left"), None);
+    assert_eq!(a.field("right"), None);
+
+    for faults in [&a_faults, &b_faults, &c_faults] {
+        faults.set(ChaosConfig::default()).unwrap();
+    }
+    a.wait_for_members(3).await;
+    for node in [&a, &b, &c] {
+        node.wait_for_field("left", "a").await;
+        node.wait_for_field("right", "b").await;
+    }
+}
+
+#[tokio::test]
+async fn writes_cross_a_lossy_link() {
+    let (a, a_faults) = Node::start_with_faults(&[], 4).await;
+    let (b, b_faults) = Node::start_with_faults(&[&a], 5).await;
+    a.wait_for_members(2).await;
+
+    // a third of the datagrams in either direction is lost, broadcasts among them
+    let lossy = ChaosConfig {
+        drop_probability: 0.3,
+        ..ChaosConfig::default()
+    };
+    a_faults.set(lossy.clone()).unwrap();
+    b_faults.set(lossy).unwrap();
+    for i in 0..20 {
+        a.set(&format!("key{}", i), &i.to_string()).await;
+    }
+    for i in 0..20 {
+        b.wait_for_field(&format!("key{}", i), &i.to_string()).await;
+    }
+}
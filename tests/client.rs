@@ -0,0 +1,105 @@
+#![cfg(feature = "client")]
+
+mod common;
+
+use futures_util::StreamExt;
+
+use common::Node;
+use holydiver::swim::{
+    client::{ClientConfig, Event, EventType, HolyDiverClient},
+    error::HolyDiverError,
+    events::ChangeKind,
+    quotas::{Quota, QuotaLimit},
+    shutdown::ShutdownCoordinator,
+};
+
+// without retries, so that rejections are reported right away
+fn client(base_url: &str) -> HolyDiverClient {
+    HolyDiverClient::new(ClientConfig { retries: 0, ..ClientConfig::new(base_url) }).unwrap()
+}
+
+#[actix_web::test]
+async fn fields_are_written_read_listed_and_deleted() {
+    let node = Node::start(&[]).await;
+    let client = client(&node.serve().await);
+
+    client.set_field("db.host", "primary").await.unwrap();
+    client.set_field("db.port", "5432").await.unwrap();
+    client.set_field("debug", "false").await.unwrap();
+    assert_eq!(client.get_field("db.host").await.unwrap().as_deref(), Some("primary"));
+    assert_eq!(node.field("db.host").as_deref(), Some("primary"));
+    assert_eq!(client.list("db.").await.unwrap(), vec!["db.host".to_owned(), "db.port".to_owned()]);
+    let read = client.get_fields(&["db.port".to_owned(), "missing".to_owned()]).await.unwrap();
+    assert_eq!(read.values.get("db.port").map(String::as_str), Some("5432"));
+    assert_eq!(read.missing, vec!["missing".to_owned()]);
+
+    client.delete_field("db.host").await.unwrap();
+    // the 404 of a missing field is no error
+    assert_eq!(client.get_field("db.host").await.unwrap(), None);
+    assert_eq!(client.list("db.").await.unwrap(), vec!["db.port".to_owned()]);
+
+    let members = client.members().await.unwrap();
+    assert_eq!(members.members, vec![node.addr]);
+    assert!(!client.export().await.unwrap().is_empty());
+}
+
+#[actix_web::test]
+async fn field_changes_are_watched() {
+    let node = Node::start(&[]).await;
+    let client = client(&node.serve().await);
+    let mut events = Box::pin(client.watch(&[EventType::Field]).await.unwrap());
+    client.set_field("color", "red").await.unwrap();
+
+    let event = tokio::time::timeout(common::TIMEOUT, events.next()).await.unwrap().unwrap().unwrap();
+    match event {
+        Event::Field(change) => {
+            assert_eq!(change.kind, ChangeKind::Updated);
+            assert_eq!(change.field, "color");
+            assert_eq!(change.winning_value.as_deref(), Some("red"));
+        },
+        other => panic!("unexpected event {:?}", other),
+    }
+}
+
+#[actix_web::test]
+async fn unknown_routes_are_reported_as_not_found() {
+    let node = Node::start(&[]).await;
+    let client = client(&format!("{}/not-the-api", node.serve().await));
+    match client.members().await {
+        Err(HolyDiverError::RouteNotFound(path)) => assert_eq!(path, "/not-the-api/members"),
+        other => panic!("expected a RouteNotFound, got {:?}", other.map(|m| m.members)),
+    }
+}
+
+#[actix_web::test]
+async fn writes_over_the_quota_are_rejected_with_429() {
+    let node = Node::start(&[]).await;
+    node.data_handler.lock().unwrap().set_quotas(vec![Quota { pattern: "limited.*".to_owned(), max_keys: Some(1), max_bytes: None }]);
+    let client = client(&node.serve().await);
+
+    client.set_field("limited.one", "1").await.unwrap();
+    match client.set_field("limited.two", "2").await {
+        Err(HolyDiverError::QuotaExceeded(exceeded)) => {
+            assert_eq!(exceeded.pattern, "limited.*");
+            assert_eq!(exceeded.limit, QuotaLimit::MaxKeys);
+            assert_eq!(exceeded.max, 1);
+        },
+        other => panic!("expected a QuotaExceeded, got {:?}", other),
+    }
+    assert_eq!(node.field("limited.two"), None);
+}
+
+#[actix_web::test]
+async fn writes_to_a_draining_node_are_rejected_with_503() {
+    let node = Node::start(&[]).await;
+    let shutdown = ShutdownCoordinator::new();
+    let server_shutdown = shutdown.clone();
+    let client = client(&node.serve_with(|config| config.shutdown = server_shutdown).await);
+    client.set_field("color", "red").await.unwrap();
+
+    shutdown.begin();
+    assert!(matches!(client.set_field("color", "blue").await, Err(HolyDiverError::ShuttingDown)));
+    assert!(matches!(client.delete_field("color").await, Err(HolyDiverError::ShuttingDown)));
+    // reads are still answered
+    assert_eq!(client.get_field("color").await.unwrap().as_deref(), Some("red"));
+}
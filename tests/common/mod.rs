@@ -0,0 +1,160 @@
+// Nodes of a cluster running in the test process, each on its own UDP port of 127.0.0.1 with a temporary data dir.
+// They are wired like the holy-diver binary does it, with the default settings unless a test changes them.
+// Every test binary uses only some of the helpers.
+#![allow(dead_code)]
+
+use std::{
+    future::Future, net::{SocketAddr, UdpSocket}, num::NonZeroUsize, sync::{Arc, Mutex}, time::{Duration, Instant},
+};
+use foca::{Config, PeriodicParams};
+use tempfile::TempDir;
+
+use holydiver::swim::{
+    chaos::FaultInjector,
+    coalesce::{BroadcastCoalescer, DEFAULT_BROADCAST_INTERVAL},
+    core::{gossip_budget, FocaRuntimeConfig, HolyDiverController, HolyDiverDataHandler, DEFAULT_GOSSIP_BUDGET_PERCENT},
+    foca::setup_foca,
+    handle::ControllerHandle,
+    types::ID,
+};
+
+// how long eventually waits, a few rounds of probes and gossip of foca's simple config
+pub const TIMEOUT: Duration = Duration::from_secs(20);
+
+pub struct Node {
+    pub addr: SocketAddr,
+    pub data_handler: Arc<Mutex<HolyDiverDataHandler>>,
+    pub controller: ControllerHandle,
+    data_dir: TempDir,
+}
+
+// a node that was shut down, its data dir is kept for starting it again
+pub struct StoppedNode {
+    pub addr: SocketAddr,
+    data_dir: TempDir,
+}
+
+fn free_addr() -> SocketAddr {
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    socket.local_addr().unwrap()
+}
+
+pub fn foca_config() -> Config {
+    let mut config = Config::simple();
+    config.notify_down_members = true;
+    config
+}
+
+impl Node {
+    // a node announcing to seeds
+    pub async fn start(seeds: &[&Node]) -> Node {
+        Self::start_with(seeds, |_| {}).await
+    }
+
+    // same as start, with the runtime config changed by configure before foca is set up
+    pub async fn start_with(seeds: &[&Node], configure: impl FnOnce(&mut FocaRuntimeConfig)) -> Node {
+        let seeds = seeds.iter().map(|seed| seed.addr).collect();
+        launch(free_addr(), TempDir::new().unwrap(), seeds, configure).await
+    }
+
+    // A node whose datagrams pass a fault injector seeded with seed. Members declared down are announced to
+    // every second, so that nodes cut off for a while find the others again.
+    pub async fn start_with_faults(seeds: &[&Node], seed: u64) -> (Node, Arc<FaultInjector>) {
+        let mut injector = None;
+        let node = Self::start_with(seeds, |config| {
+            let faults = FaultInjector::shared(config.identity.addr, Some(seed));
+            config.fault_injector = Some(faults.clone());
+            config.foca_config.periodic_announce_to_down_members = Some(PeriodicParams {
+                frequency: Duration::from_secs(1),
+                num_members: NonZeroUsize::new(2).unwrap(),
+            });
+            injector = Some(faults);
+        }).await;
+        (node, injector.unwrap())
+    }
+
+    pub fn field(&self, field_name: &str) -> Option<String> {
+        self.data_handler.lock().unwrap().get_field(field_name.to_owned()).unwrap()
+    }
+
+    pub async fn set(&self, field_name: &str, value: &str) {
+        self.controller.set_field(field_name.to_owned(), value.to_owned()).await.unwrap();
+    }
+
+    // waits until foca counts members active members, including this node
+    pub async fn wait_for_members(&self, members: usize) {
+        eventually_async(&format!("{} has {} members", self.addr, members), || async {
+            self.controller.members().await.map_or(false, |m| m.len() == members)
+        }).await;
+    }
+
+    // waits until the field has the value on this node
+    pub async fn wait_for_field(&self, field_name: &str, value: &str) {
+        eventually(&format!("{} has {}={}", self.addr, field_name, value), || self.field(field_name).as_deref() == Some(value)).await;
+    }
+
+    // shuts the node down like on SIGTERM, the members are told it leaves
+    pub async fn stop(self) -> StoppedNode {
+        self.controller.shutdown().await;
+        // the socket is closed once the tasks of foca noticed the shutdown
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        StoppedNode { addr: self.addr, data_dir: self.data_dir }
+    }
+
+    // serves the REST API on a free port, returns its base URL. Needs to be called within an actix system.
+    #[cfg(feature = "rest")]
+    pub async fn serve(&self) -> String {
+        self.serve_with(|_| {}).await
+    }
+
+    // same as serve, with the server config changed by configure
+    #[cfg(feature = "rest")]
+    pub async fn serve_with(&self, configure: impl FnOnce(&mut holydiver::swim::server::ServerConfig)) -> String {
+        use holydiver::swim::server::{bind_server_with_config, ServerConfig};
+        let mut config = ServerConfig {
+            handle_signals: false,
+            ..ServerConfig::new(0)
+        };
+        configure(&mut config);
+        let (addr, server) = bind_server_with_config(config, self.controller.clone()).await.unwrap();
+        actix_web::rt::spawn(server);
+        format!("http://{}", addr)
+    }
+}
+
+impl StoppedNode {
+    // starts the node again with the same address and data dir
+    pub async fn start(self, seeds: &[&Node]) -> Node {
+        let seeds = seeds.iter().map(|seed| seed.addr).collect();
+        launch(self.addr, self.data_dir, seeds, |_| {}).await
+    }
+}
+
+async fn launch(addr: SocketAddr, data_dir: TempDir, seeds: Vec<SocketAddr>, configure: impl FnOnce(&mut FocaRuntimeConfig)) -> Node {
+    let identity = ID::new(addr);
+    let mut runtime_config = FocaRuntimeConfig::new(identity.clone(), data_dir.path().to_owned(), addr, seeds, foca_config());
+    configure(&mut runtime_config);
+    let data_handler = Arc::new(Mutex::new(HolyDiverDataHandler::new(data_dir.path(), identity)));
+    let gossip_budget = gossip_budget(&runtime_config.foca_config, DEFAULT_GOSSIP_BUDGET_PERCENT);
+    let foca_command_sender = setup_foca(runtime_config, Box::new(data_handler.clone())).await.unwrap();
+    let broadcasts = BroadcastCoalescer::spawn(foca_command_sender.clone(), data_handler.clone(), gossip_budget, DEFAULT_BROADCAST_INTERVAL);
+    let controller = ControllerHandle::spawn(HolyDiverController {
+        foca_command_sender,
+        data_handler: data_handler.clone(),
+        broadcasts,
+    });
+    Node { addr, data_handler, controller, data_dir }
+}
+
+// waits until condition holds, fails the test after TIMEOUT
+pub async fn eventually(what: &str, mut condition: impl FnMut() -> bool) {
+    eventually_async(what, || std::future::ready(condition())).await
+}
+
+pub async fn eventually_async<F: Future<Output = bool>>(what: &str, mut condition: impl FnMut() -> F) {
+    let deadline = Instant::now() + TIMEOUT;
+    while !condition().await {
+        assert!(Instant::now() < deadline, "timed out waiting until {}", what);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+}
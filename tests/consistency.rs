@@ -0,0 +1,34 @@
+mod common;
+
+use std::time::Duration;
+use common::{eventually_async, Node};
+use holydiver::swim::direct_sync::SyncMode;
+
+const CHECKSUM_INTERVAL: Duration = Duration::from_millis(200);
+
+async fn diverged(node: &Node, peer: &Node) -> bool {
+    let report = node.controller.consistency().await.unwrap();
+    report.peers.get(&peer.addr).map_or(false, |checksum| checksum.diverged)
+}
+
+#[tokio::test]
+async fn a_member_missing_a_write_is_reported_until_it_is_synced() {
+    let a = Node::start_with(&[], |config| config.checksum_interval = Some(CHECKSUM_INTERVAL)).await;
+    let b = Node::start_with(&[&a], |config| config.checksum_interval = Some(CHECKSUM_INTERVAL)).await;
+    b.wait_for_members(2).await;
+    eventually_async("b received a matching checksum of a", || async {
+        let report = b.controller.consistency().await.unwrap();
+        report.enabled && report.peers.get(&a.addr).map_or(false, |checksum| checksum.consecutive_mismatches == 0)
+    }).await;
+
+    // written without a broadcast, b never gets it by gossip
+    a.data_handler.lock().unwrap().set_field("unseen".to_owned(), "1".to_owned()).unwrap();
+    eventually_async("b reports a as diverged", || diverged(&b, &a)).await;
+    eventually_async("a reports b as diverged", || diverged(&a, &b)).await;
+    assert_eq!(b.controller.consistency().await.unwrap().diverged_peers, 1);
+
+    a.controller.sync_with(b.addr, SyncMode::Full, false).await.unwrap();
+    b.wait_for_field("unseen", "1").await;
+    eventually_async("b agrees with a again", || async { !diverged(&b, &a).await }).await;
+    eventually_async("a agrees with b again", || async { !diverged(&a, &b).await }).await;
+}
@@ -0,0 +1,29 @@
+// reqwest comes with the client feature
+#![cfg(feature = "client")]
+
+mod common;
+
+use common::Node;
+
+#[actix_web::test]
+async fn a_consistent_listing_can_be_continued_at_its_version() {
+    let a = Node::start(&[]).await;
+    let url = a.serve().await;
+    a.set("a", "1").await;
+    a.set("b", "1").await;
+    a.set("c", "1").await;
+    a.controller.delete_field("c".to_owned()).await.unwrap();
+
+    let response = reqwest::get(format!("{}/state?consistent=true&include_deleted=true&meta=true", url)).await.unwrap();
+    let version = response.headers()["etag"].to_str().unwrap().trim_matches('"').to_owned();
+    let listing: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(listing["values"], serde_json::json!({"a": "1", "b": "1"}));
+    assert!(listing["deleted"]["c"].is_object());
+    assert_eq!(listing["meta"]["a"]["replicated"], true);
+
+    a.set("a", "2").await;
+    let at_version = reqwest::get(format!("{}/state/a?at={}", url, version)).await.unwrap().text().await.unwrap();
+    assert_eq!(at_version, "a: 1");
+    let now = reqwest::get(format!("{}/state/a", url)).await.unwrap().text().await.unwrap();
+    assert_eq!(now, "a: 2");
+}
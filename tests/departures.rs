@@ -0,0 +1,24 @@
+mod common;
+
+use common::{eventually_async, Node};
+use holydiver::swim::{chaos::ChaosConfig, departures::DownReason};
+
+#[tokio::test]
+async fn members_tell_a_graceful_leave_from_a_failure() {
+    let a = Node::start(&[]).await;
+    let b = Node::start(&[&a]).await;
+    let (c, c_faults) = Node::start_with_faults(&[&a], 1).await;
+    a.wait_for_members(3).await;
+
+    let b = b.stop().await;
+    eventually_async("a reports that b left", || async {
+        a.controller.departures().await.unwrap().get(&b.addr).is_some_and(|departure| departure.reason == DownReason::Left)
+    }).await;
+
+    // c stops answering without saying goodbye
+    c_faults.set(ChaosConfig { blackhole: true, ..ChaosConfig::default() }).unwrap();
+    eventually_async("a reports that c failed", || async {
+        a.controller.departures().await.unwrap().get(&c.addr).is_some_and(|departure| departure.reason == DownReason::Failed)
+    }).await;
+    a.wait_for_members(1).await;
+}
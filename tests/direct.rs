@@ -0,0 +1,23 @@
+mod common;
+
+use std::time::Duration;
+use common::Node;
+use holydiver::swim::{broadcast::{GossipMessage, MessageType}, types::ID};
+
+#[tokio::test]
+async fn a_direct_message_reaches_its_target_only() {
+    let a = Node::start(&[]).await;
+    let b = Node::start(&[&a]).await;
+    let c = Node::start(&[&a]).await;
+    a.wait_for_members(3).await;
+
+    // written without a broadcast, only the direct message carries it
+    a.data_handler.lock().unwrap().set_field("direct".to_owned(), "b only".to_owned()).unwrap();
+    let document = a.controller.export().await.unwrap();
+    a.controller.send_to(ID::new(b.addr), GossipMessage::new(MessageType::FullSync, document)).await.unwrap();
+
+    b.wait_for_field("direct", "b only").await;
+    // b applied it without passing it on
+    tokio::time::sleep(Duration::from_secs(1)).await;
+    assert_eq!(c.field("direct"), None);
+}
@@ -0,0 +1,40 @@
+mod common;
+
+use std::time::Duration;
+use common::Node;
+use holydiver::swim::{chaos::ChaosConfig, join_state::JoinState};
+
+#[tokio::test]
+async fn a_node_with_a_join_deadline_announces_again_until_the_seed_answers() {
+    let (seed, seed_faults) = Node::start_with_faults(&[], 1).await;
+    seed_faults.set(ChaosConfig { blackhole: true, ..ChaosConfig::default() }).unwrap();
+    let node = Node::start_with(&[&seed], |config| config.join_timeout = Some(Duration::from_secs(120))).await;
+
+    tokio::time::sleep(Duration::from_secs(1)).await;
+    let status = node.controller.status().await.unwrap();
+    assert!(!status.joined);
+    assert!(matches!(status.join, JoinState::Announcing { attempt: 1, next_retry: Some(_) }));
+
+    // the first announce was lost, a retry reaches the seed
+    seed_faults.set(ChaosConfig::default()).unwrap();
+    node.wait_for_members(2).await;
+    let status = node.controller.status().await.unwrap();
+    assert!(status.joined);
+    assert!(matches!(status.join, JoinState::Joined { via, .. } if via == seed.addr));
+}
+
+#[tokio::test]
+async fn a_node_joins_a_seed_that_came_back_with_another_identity() {
+    let seed = Node::start(&[]).await.stop().await;
+    let node = Node::start_with(&[], |config| {
+        config.announce_to = vec![seed.addr];
+        config.join_timeout = Some(Duration::from_secs(120));
+    }).await;
+    tokio::time::sleep(Duration::from_secs(1)).await;
+    assert!(!node.controller.status().await.unwrap().joined);
+
+    // same address, a new bump
+    let seed = seed.start(&[]).await;
+    node.wait_for_members(2).await;
+    seed.wait_for_members(2).await;
+}
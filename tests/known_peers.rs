@@ -0,0 +1,20 @@
+mod common;
+
+use common::Node;
+
+#[tokio::test]
+async fn restarted_nodes_without_seeds_find_the_peers_they_knew() {
+    let a = Node::start(&[]).await;
+    let b = Node::start(&[&a]).await;
+    a.wait_for_members(2).await;
+    b.wait_for_members(2).await;
+
+    // like a power cycle of the whole cluster, b is gone while a restarts
+    let b = b.stop().await;
+    let a = a.stop().await;
+    let a = a.start(&[]).await;
+    let b = b.start(&[]).await;
+
+    a.wait_for_members(2).await;
+    b.wait_for_members(2).await;
+}
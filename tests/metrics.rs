@@ -0,0 +1,35 @@
+// reqwest comes with the client feature
+#![cfg(all(feature = "metrics", feature = "client"))]
+
+mod common;
+
+use common::Node;
+
+// the count of a histogram, summed over its labels
+fn histogram_count(metrics: &str, family: &str) -> u64 {
+    let count = format!("holydiver_{}_count", family);
+    metrics.lines()
+        .filter(|line| line.split(|c| c == ' ' || c == '{').next() == Some(count.as_str()))
+        .filter_map(|line| line.rsplit(' ').next()?.parse::<u64>().ok())
+        .sum()
+}
+
+#[actix_web::test]
+async fn latency_histograms_are_scraped_after_writes_were_replicated() {
+    let a = Node::start(&[]).await;
+    let b = Node::start(&[&a]).await;
+    a.wait_for_members(2).await;
+    for i in 0..3 {
+        a.set(&format!("key{}", i), "value").await;
+    }
+    b.wait_for_field("key2", "value").await;
+
+    // the registry is shared by both nodes of the process
+    let metrics = reqwest::get(format!("{}/metrics", a.serve().await)).await.unwrap()
+        .text().await.unwrap();
+    for family in ["set_field_broadcast_seconds", "store_data_seconds", "merge_seconds", "receive_item_seconds", "udp_send_seconds"] {
+        assert!(metrics.contains(&format!("# TYPE holydiver_{} histogram", family)), "{} is missing", family);
+        assert!(histogram_count(&metrics, family) > 0, "{} has not recorded anything", family);
+    }
+    assert!(metrics.contains("holydiver_store_data_seconds_count{outcome=\"ok\"}"));
+}
@@ -0,0 +1,22 @@
+mod common;
+
+use common::Node;
+use holydiver::swim::chaos::ChaosConfig;
+
+#[tokio::test]
+async fn a_node_declared_down_rejoins_with_a_renewed_identity() {
+    let (a, _) = Node::start_with_faults(&[], 1).await;
+    let (b, b_faults) = Node::start_with_faults(&[&a], 2).await;
+    a.wait_for_members(2).await;
+
+    // a keeps the identity b had when it was declared down
+    b_faults.set(ChaosConfig { blackhole: true, ..ChaosConfig::default() }).unwrap();
+    a.wait_for_members(1).await;
+    b_faults.set(ChaosConfig::default()).unwrap();
+
+    a.wait_for_members(2).await;
+    b.wait_for_members(2).await;
+    assert!(b.controller.status().await.unwrap().identity_renewals.renewals >= 1);
+    b.set("after", "rejoin").await;
+    a.wait_for_field("after", "rejoin").await;
+}
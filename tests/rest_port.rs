@@ -0,0 +1,26 @@
+// reqwest comes with the client feature
+#![cfg(feature = "client")]
+
+mod common;
+
+use common::{eventually_async, Node};
+use holydiver::swim::members::REST_URL_LABEL;
+
+#[actix_web::test]
+async fn a_rest_endpoint_on_a_free_port_is_written_down_and_gossiped() {
+    let a = Node::start(&[]).await;
+    let b = Node::start(&[&a]).await;
+    a.wait_for_members(2).await;
+
+    let dir = tempfile::tempdir().unwrap();
+    let port_file = dir.path().join("rest.port");
+    let url = b.serve_with(|config| config.port_file = Some(port_file.clone())).await;
+    let port = std::fs::read_to_string(&port_file).unwrap();
+    assert_eq!(url, format!("http://127.0.0.1:{}", port));
+    assert!(reqwest::get(format!("{}/hello", url)).await.unwrap().status().is_success());
+
+    eventually_async("a has the REST URL of b", || async {
+        a.controller.member_labels().await.unwrap().get(&b.addr)
+            .is_some_and(|labels| labels.get(REST_URL_LABEL) == Some(&url))
+    }).await;
+}
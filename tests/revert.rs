@@ -0,0 +1,19 @@
+mod common;
+
+use common::Node;
+
+#[tokio::test]
+async fn a_revert_restores_the_previous_value_on_every_member() {
+    let a = Node::start(&[]).await;
+    let b = Node::start(&[&a]).await;
+    a.wait_for_members(2).await;
+
+    a.set("color", "v1").await;
+    a.set("color", "v2").await;
+    b.wait_for_field("color", "v2").await;
+
+    let reverted = a.controller.revert_field("color".to_owned()).await.unwrap();
+    assert_eq!(reverted, Some(Some("v1".to_owned())));
+    assert_eq!(a.field("color").as_deref(), Some("v1"));
+    b.wait_for_field("color", "v1").await;
+}
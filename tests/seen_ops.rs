@@ -0,0 +1,47 @@
+// reqwest comes with the client feature
+#![cfg(feature = "client")]
+
+mod common;
+
+use common::Node;
+use serde_json::Value;
+
+async fn seen_ids(url: &str) -> Vec<String> {
+    let list: Value = reqwest::get(format!("{}/debug/seen-ops", url)).await.unwrap().json().await.unwrap();
+    list["operations"].as_array().unwrap().iter()
+        .map(|operation| operation["operation_id"].as_str().unwrap().to_owned())
+        .collect()
+}
+
+#[actix_web::test]
+async fn seen_operations_are_listed_and_forgotten_with_debug_endpoints_only() {
+    let a = Node::start(&[]).await;
+    let b = Node::start(&[&a]).await;
+    b.wait_for_members(2).await;
+    a.set("color", "red").await;
+    b.wait_for_field("color", "red").await;
+    let url = b.serve().await;
+    let debug_url = b.serve_with(|config| config.debug_endpoints = true).await;
+    let client = reqwest::Client::new();
+
+    let seen = seen_ids(&url).await;
+    assert!(!seen.is_empty());
+    let id = &seen[0];
+    let sighting: Value = reqwest::get(format!("{}/debug/operations/{}", url, id)).await.unwrap().json().await.unwrap();
+    assert_eq!(sighting["seen"], true);
+
+    let forget = |base: &str| client.delete(format!("{}/debug/seen-ops/{}", base, id)).send();
+    assert_eq!(forget(&url).await.unwrap().status(), reqwest::StatusCode::NOT_FOUND);
+    let forgotten: Value = forget(&debug_url).await.unwrap().json().await.unwrap();
+    assert_eq!(forgotten["forgotten"], 1);
+    let forgotten: Value = forget(&debug_url).await.unwrap().json().await.unwrap();
+    assert_eq!(forgotten["forgotten"], 0);
+    let sighting: Value = reqwest::get(format!("{}/debug/operations/{}", url, id)).await.unwrap().json().await.unwrap();
+    assert_eq!(sighting["seen"], false);
+
+    let clear = |base: &str| client.post(format!("{}/debug/seen-ops/clear", base)).send();
+    assert_eq!(clear(&url).await.unwrap().status(), reqwest::StatusCode::NOT_FOUND);
+    assert!(clear(&debug_url).await.unwrap().status().is_success());
+    let remaining = seen_ids(&url).await;
+    assert!(seen.iter().all(|id| !remaining.contains(id)), "{:?} are still remembered", remaining);
+}
@@ -0,0 +1,41 @@
+mod common;
+
+use std::time::Duration;
+use common::Node;
+
+const WRITERS: usize = 8;
+const WRITES: usize = 50;
+// far more than the writes take, a controller stuck behind the data handler or foca misses it
+const DEADLINE: Duration = Duration::from_secs(10);
+
+// Writers on both nodes while each merges the gossip of the other, asking foca for the members after every write
+#[tokio::test]
+async fn concurrent_writes_and_merges_finish_in_time() {
+    let a = Node::start(&[]).await;
+    let b = Node::start(&[&a]).await;
+    a.wait_for_members(2).await;
+
+    let mut writers = Vec::new();
+    for (node, name) in [(&a, "a"), (&b, "b")] {
+        for writer in 0..WRITERS {
+            let controller = node.controller.clone();
+            writers.push(tokio::spawn(async move {
+                for i in 0..WRITES {
+                    controller.set_field(format!("{}.{}", name, writer), i.to_string()).await.unwrap();
+                    assert_eq!(controller.members().await.unwrap().len(), 2);
+                }
+            }));
+        }
+    }
+    tokio::time::timeout(DEADLINE, async {
+        for writer in writers {
+            writer.await.unwrap();
+        }
+    }).await.expect("the writes did not finish before the deadline");
+
+    let last = (WRITES - 1).to_string();
+    for writer in 0..WRITERS {
+        a.wait_for_field(&format!("b.{}", writer), &last).await;
+        b.wait_for_field(&format!("a.{}", writer), &last).await;
+    }
+}
@@ -0,0 +1,55 @@
+mod common;
+
+use std::net::SocketAddr;
+use common::{eventually, Node};
+use holydiver::swim::{direct_sync::SyncMode, error::HolyDiverError};
+
+#[tokio::test]
+async fn a_sync_brings_a_single_member_up_to_date() {
+    let a = Node::start(&[]).await;
+    let b = Node::start(&[&a]).await;
+    let c = Node::start(&[&a]).await;
+    a.wait_for_members(3).await;
+
+    // written without a broadcast, only the syncs carry them
+    a.data_handler.lock().unwrap().set_field("full".to_owned(), "1".to_owned()).unwrap();
+    let report = a.controller.sync_with(b.addr, SyncMode::Full, false).await.unwrap();
+    assert!(report.enqueued);
+    assert_eq!((report.target, report.mode), (b.addr, SyncMode::Full));
+    assert!(report.bytes > 0);
+    b.wait_for_field("full", "1").await;
+
+    // c sends its heads and is sent the changes it misses
+    a.data_handler.lock().unwrap().set_field("changes".to_owned(), "2".to_owned()).unwrap();
+    assert!(a.controller.sync_with(c.addr, SyncMode::Changes, false).await.unwrap().enqueued);
+    c.wait_for_field("full", "1").await;
+    c.wait_for_field("changes", "2").await;
+}
+
+#[tokio::test]
+async fn a_sync_session_brings_both_members_up_to_date() {
+    let a = Node::start(&[]).await;
+    let b = Node::start(&[&a]).await;
+    a.wait_for_members(2).await;
+
+    a.data_handler.lock().unwrap().set_field("from_a".to_owned(), "1".to_owned()).unwrap();
+    b.data_handler.lock().unwrap().set_field("from_b".to_owned(), "2".to_owned()).unwrap();
+    assert!(a.controller.sync_with(b.addr, SyncMode::Session, false).await.unwrap().enqueued);
+    b.wait_for_field("from_a", "1").await;
+    a.wait_for_field("from_b", "2").await;
+    // each keeps the state of the session for the other
+    for node in [&a, &b] {
+        eventually("the sync state is kept", || node.data_handler.lock().unwrap().stats().sync_states.states == 1).await;
+    }
+}
+
+#[tokio::test]
+async fn a_sync_with_an_address_that_is_no_member_needs_force() {
+    let a = Node::start(&[]).await;
+    let stranger = SocketAddr::from(([127, 0, 0, 1], 9));
+
+    let rejected = a.controller.sync_with(stranger, SyncMode::Full, false).await.unwrap_err();
+    assert!(matches!(rejected.downcast_ref::<HolyDiverError>(), Some(HolyDiverError::UnknownMember(member)) if *member == stranger.to_string()),
+        "{:#}", rejected);
+    assert!(a.controller.sync_with(stranger, SyncMode::Full, true).await.unwrap().enqueued);
+}
@@ -0,0 +1,85 @@
+// the REST side is driven by HolyDiverClient
+#![cfg(feature = "client")]
+
+mod common;
+
+use std::net::SocketAddr;
+use tokio::{io::{AsyncBufReadExt, AsyncWriteExt, BufReader}, net::{tcp::OwnedReadHalf, TcpStream}};
+
+use common::Node;
+use holydiver::swim::{client::{ClientConfig, HolyDiverClient}, text::{TextServer, TextServerConfig}};
+
+struct TextClient {
+    reader: BufReader<OwnedReadHalf>,
+    writer: tokio::net::tcp::OwnedWriteHalf,
+}
+
+impl TextClient {
+    async fn connect(addr: SocketAddr) -> Self {
+        let (read, writer) = TcpStream::connect(addr).await.unwrap().into_split();
+        TextClient { reader: BufReader::new(read), writer }
+    }
+
+    // sends a command line, returns the reply without its line feed
+    async fn send(&mut self, command: &str) -> String {
+        self.writer.write_all(format!("{}\n", command).as_bytes()).await.unwrap();
+        self.reply().await
+    }
+
+    async fn reply(&mut self) -> String {
+        let mut reply = String::new();
+        self.reader.read_line(&mut reply).await.unwrap();
+        reply.trim_end_matches('\n').to_owned()
+    }
+}
+
+// a node serving REST and the text protocol
+async fn start(config: TextServerConfig) -> (Node, HolyDiverClient, SocketAddr) {
+    let node = Node::start(&[]).await;
+    let rest = HolyDiverClient::new(ClientConfig::new(&node.serve().await)).unwrap();
+    let text_server = TextServer::bind(config).await.unwrap();
+    let text_addr = text_server.local_addr().unwrap();
+    tokio::spawn(text_server.run(node.controller.clone()));
+    (node, rest, text_addr)
+}
+
+#[actix_web::test]
+async fn values_written_with_either_protocol_are_read_with_the_other() {
+    let (node, rest, text_addr) = start(TextServerConfig::new(0)).await;
+    let mut text = TextClient::connect(text_addr).await;
+
+    rest.set_field("from-rest", "written over HTTP").await.unwrap();
+    assert_eq!(text.send("GET from-rest").await, "OK written over HTTP");
+
+    assert_eq!(text.send("SET from-text two\\nlines").await, "OK");
+    assert_eq!(rest.get_field("from-text").await.unwrap().as_deref(), Some("two\nlines"));
+    assert_eq!(text.send("GET from-text").await, "OK two\\nlines");
+
+    assert_eq!(text.send("KEYS from-").await, "OK from-rest from-text");
+    assert_eq!(text.send("MEMBERS").await, format!("OK {}", node.addr));
+
+    assert_eq!(text.send("DEL from-rest").await, "OK");
+    assert_eq!(rest.get_field("from-rest").await.unwrap(), None);
+    assert_eq!(text.send("GET from-rest").await, "NIL");
+}
+
+#[actix_web::test]
+async fn malformed_commands_are_answered_with_errors() {
+    let (_node, _rest, text_addr) = start(TextServerConfig::new(0)).await;
+    let mut text = TextClient::connect(text_addr).await;
+    assert_eq!(text.send("FROB key").await, "ERR unknown command FROB");
+    assert_eq!(text.send("GET").await, "ERR missing key");
+    assert_eq!(text.send("SET key").await, "ERR missing value");
+    assert_eq!(text.send("SET key trailing\\").await, "ERR value ends with a single backslash");
+    assert_eq!(text.send("MEMBERS now").await, "ERR MEMBERS takes no arguments");
+    // the connection is still usable
+    assert_eq!(text.send("GET key").await, "NIL");
+}
+
+#[actix_web::test]
+async fn overlong_lines_close_the_connection() {
+    let (_node, _rest, text_addr) = start(TextServerConfig { max_line_length: 16, ..TextServerConfig::new(0) }).await;
+    let mut text = TextClient::connect(text_addr).await;
+    assert_eq!(text.send(&format!("SET key {}", "x".repeat(100))).await, "ERR line exceeds 16 bytes");
+    assert_eq!(text.reply().await, "");
+}
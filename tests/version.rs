@@ -0,0 +1,25 @@
+// reqwest comes with the client feature
+#![cfg(feature = "client")]
+
+mod common;
+
+use common::{eventually_async, Node};
+use holydiver::swim::build_info::{GIT_COMMIT, VERSION};
+
+#[actix_web::test]
+async fn the_build_of_a_node_is_served_and_gossiped_to_the_members() {
+    let a = Node::start(&[]).await;
+    let b = Node::start(&[&a]).await;
+    a.wait_for_members(2).await;
+
+    let version: serde_json::Value = reqwest::get(format!("{}/version", a.serve().await)).await.unwrap()
+        .json().await.unwrap();
+    assert_eq!(version["version"], VERSION);
+    assert_eq!(version["git_commit"], GIT_COMMIT);
+    assert!(version["protocol_version"].as_u64().is_some());
+
+    eventually_async("a has the labels of b", || async {
+        a.controller.member_labels().await.unwrap().get(&b.addr)
+            .is_some_and(|labels| labels.get("version").map(String::as_str) == Some(VERSION))
+    }).await;
+}
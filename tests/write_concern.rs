@@ -0,0 +1,24 @@
+mod common;
+
+use std::time::Duration;
+use common::{Node, TIMEOUT};
+
+#[tokio::test]
+async fn a_replicated_write_is_acknowledged_by_the_member_applying_it() {
+    let a = Node::start(&[]).await;
+    let b = Node::start(&[&a]).await;
+    a.wait_for_members(2).await;
+
+    let ack = a.controller.set_field_replicated("color".to_owned(), "red".to_owned()).await.unwrap()
+        .expect("the write fits into a broadcast");
+    let acked_by = tokio::time::timeout(TIMEOUT, ack).await.expect("no acknowledgement").unwrap();
+    assert_eq!(acked_by, b.addr);
+    assert_eq!(b.field("color").as_deref(), Some("red"));
+
+    // without another member nobody acknowledges
+    b.stop().await;
+    a.wait_for_members(1).await;
+    let ack = a.controller.set_field_replicated("color".to_owned(), "blue".to_owned()).await.unwrap().unwrap();
+    assert!(tokio::time::timeout(Duration::from_secs(2), ack).await.is_err());
+    assert_eq!(a.field("color").as_deref(), Some("blue"));
+}
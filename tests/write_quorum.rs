@@ -0,0 +1,28 @@
+mod common;
+
+use common::{eventually, Node};
+use holydiver::swim::{error::{HolyDiverError, ReadOnlyReason}, write_quorum::WriteQuorum};
+
+#[tokio::test]
+async fn writes_are_rejected_while_too_few_members_are_up() {
+    let mut member_count = None;
+    let a = Node::start_with(&[], |config| member_count = Some(config.member_count.clone())).await;
+    a.data_handler.lock().unwrap().set_write_quorum(Some(WriteQuorum::new(2, member_count.unwrap())));
+    let write = |value: &str| a.data_handler.lock().unwrap().set_field("color".to_owned(), value.to_owned());
+
+    let rejected = write("red").unwrap_err();
+    assert!(matches!(rejected.downcast_ref::<HolyDiverError>(),
+        Some(HolyDiverError::ReadOnly(ReadOnlyReason::InsufficientMembers { members: 1, min: 2 }))), "{:#}", rejected);
+
+    let b = Node::start(&[&a]).await;
+    a.wait_for_members(2).await;
+    eventually("a accepts writes", || write("red").is_ok()).await;
+    a.set("color", "blue").await;
+    b.wait_for_field("color", "blue").await;
+
+    // once b is gone writes are rejected again, reads keep working
+    b.stop().await;
+    a.wait_for_members(1).await;
+    eventually("a rejects writes", || write("green").is_err()).await;
+    assert_eq!(a.field("color").as_deref(), Some("blue"));
+}
@@ -0,0 +1,183 @@
+"use strict";
+
+// Single page UI of a holy-diver node, using the REST API of the node serving it.
+
+const MAX_CHANGES = 200;
+
+const $ = (id) => document.getElementById(id);
+
+function showError(message) {
+  const error = $("error");
+  error.textContent = message;
+  error.hidden = false;
+  clearTimeout(showError.timer);
+  showError.timer = setTimeout(() => { error.hidden = true; }, 5000);
+}
+
+// fetches a route, failing with the message of the error envelope
+async function api(method, path, body) {
+  const options = { method, headers: {} };
+  if (body !== undefined) {
+    options.headers["Content-Type"] = "application/json";
+    options.body = JSON.stringify(body);
+  }
+  const response = await fetch(path, options);
+  if (!response.ok) {
+    let message = `${method} ${path} failed with ${response.status}`;
+    try {
+      message = (await response.json()).error.message;
+    } catch (_) {}
+    throw new Error(message);
+  }
+  const text = await response.text();
+  return text ? JSON.parse(text) : null;
+}
+
+const fieldPath = (field) => `/state/${encodeURIComponent(field)}`;
+
+function fieldRow(field, value, replicated) {
+  const row = document.createElement("tr");
+  row.dataset.field = field;
+  row.classList.toggle("local", !replicated);
+  const name = document.createElement("td");
+  name.textContent = field;
+  const valueCell = document.createElement("td");
+  const input = document.createElement("input");
+  input.value = value;
+  input.addEventListener("keydown", (event) => {
+    if (event.key === "Enter") {
+      setField(field, input.value);
+    }
+  });
+  valueCell.append(input);
+  const actions = document.createElement("td");
+  const save = document.createElement("button");
+  save.textContent = "Save";
+  save.addEventListener("click", () => setField(field, input.value));
+  const remove = document.createElement("button");
+  remove.textContent = "Delete";
+  remove.addEventListener("click", () => deleteField(field));
+  actions.append(save, remove);
+  row.append(name, valueCell, actions);
+  return row;
+}
+
+function updateFieldCount() {
+  $("field-count").textContent = $("fields").children.length;
+}
+
+async function loadFields() {
+  const listing = await api("GET", "/state?meta=true");
+  const rows = Object.entries(listing.values)
+    .map(([field, value]) => fieldRow(field, value, listing.meta?.[field]?.replicated ?? true));
+  $("fields").replaceChildren(...rows);
+  updateFieldCount();
+}
+
+// applies a field event without reloading all fields
+function applyChange(change) {
+  const existing = $("fields").querySelector(`tr[data-field="${CSS.escape(change.field)}"]`);
+  if (change.winning_value === null || change.winning_value === undefined) {
+    existing?.remove();
+  } else if (existing) {
+    const input = existing.querySelector("input");
+    if (document.activeElement !== input) {
+      input.value = change.winning_value;
+    }
+    existing.classList.remove("flash");
+    void existing.offsetWidth;
+    existing.classList.add("flash");
+  } else {
+    const row = fieldRow(change.field, change.winning_value, true);
+    row.classList.add("flash");
+    const next = [...$("fields").children].find((r) => r.dataset.field > change.field);
+    $("fields").insertBefore(row, next ?? null);
+  }
+  updateFieldCount();
+}
+
+function logChange(text, remote) {
+  const item = document.createElement("li");
+  item.textContent = `${new Date().toLocaleTimeString()} ${text}`;
+  item.classList.toggle("remote", remote);
+  $("changes").prepend(item);
+  while ($("changes").children.length > MAX_CHANGES) {
+    $("changes").lastChild.remove();
+  }
+}
+
+async function setField(field, value) {
+  try {
+    await api("PUT", fieldPath(field), { value });
+  } catch (e) {
+    showError(e.message);
+  }
+}
+
+async function deleteField(field) {
+  try {
+    await api("DELETE", fieldPath(field));
+  } catch (e) {
+    showError(e.message);
+  }
+}
+
+async function loadMembers() {
+  const list = await api("GET", "/members");
+  $("member-count").textContent = list.count;
+  $("members").replaceChildren(...list.members.map((member) => {
+    const item = document.createElement("li");
+    const version = list.labels?.[member]?.version;
+    item.textContent = version ? `${member} (${version})` : member;
+    return item;
+  }));
+}
+
+async function loadNode() {
+  const version = await api("GET", "/version");
+  $("node").textContent = `${location.host} · ${version.version} (${version.git_commit})`;
+}
+
+function subscribe() {
+  const events = new EventSource("/events?types=field,member");
+  events.onopen = () => {
+    $("connection").textContent = "live";
+    $("connection").className = "status live";
+  };
+  events.onerror = () => {
+    $("connection").textContent = "reconnecting";
+    $("connection").className = "status down";
+  };
+  events.addEventListener("field", (event) => {
+    const change = JSON.parse(event.data);
+    if (change.kind === "overridden") {
+      logChange(`${change.field}: local write ${JSON.stringify(change.local_value)} was overridden`, true);
+      return;
+    }
+    applyChange(change);
+    const value = change.winning_value === null ? "deleted" : `= ${JSON.stringify(change.winning_value)}`;
+    logChange(`${change.field} ${value}${change.remote ? " (remote)" : ""}`, change.remote);
+  });
+  events.addEventListener("member", (event) => {
+    const member = JSON.parse(event.data);
+    logChange(`member ${member.address} ${member.event}`, true);
+    loadMembers().catch((e) => showError(e.message));
+  });
+  // events were lost, so the listing may be stale
+  events.addEventListener("lost_events", () => {
+    loadFields().catch((e) => showError(e.message));
+    loadMembers().catch((e) => showError(e.message));
+  });
+}
+
+$("new-field").addEventListener("submit", async (event) => {
+  event.preventDefault();
+  await setField($("new-key").value, $("new-value").value);
+  $("new-key").value = "";
+  $("new-value").value = "";
+});
+
+subscribe();
+Promise.all([loadNode(), loadFields(), loadMembers()]).catch((e) => showError(e.message));
+// member events are only sent for members joining and leaving, labels change on upgrades
+setInterval(() => loadMembers().catch(() => {}), 10000);